@@ -0,0 +1,126 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, Request},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use downloader::auth::{
+    axum::Authorization, cache::TokenCache, repository::TokenRepository,
+    Permission,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+fn eddsa_repository() -> TokenRepository {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+    TokenRepository::new(
+        Algorithm::EdDSA,
+        EncodingKey::from_ed_der(pkcs8.as_ref()),
+        DecodingKey::from_ed_der(pair.public_key().as_ref()),
+        Duration::from_secs(3600),
+        Duration::from_secs(3600),
+        b"secret".to_vec(),
+    )
+}
+
+fn hs256_repository() -> TokenRepository {
+    TokenRepository::new(
+        Algorithm::HS256,
+        EncodingKey::from_secret(b"key"),
+        DecodingKey::from_secret(b"key"),
+        Duration::from_secs(3600),
+        Duration::from_secs(3600),
+        b"secret".to_vec(),
+    )
+}
+
+fn user_token(repo: &TokenRepository) -> String {
+    repo.generate_user_token(
+        Uuid::new_v4(),
+        Permission::UNPRIVILEGED,
+        "bench".into(),
+    )
+    .unwrap()
+}
+
+fn decode_token(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_token");
+
+    let cache = || TokenCache::new(1024, Duration::from_secs(60));
+
+    for (name, repo) in [
+        ("eddsa", eddsa_repository()),
+        ("eddsa_cached", eddsa_repository().with_cache(cache())),
+        ("hs256", hs256_repository()),
+    ] {
+        let token = user_token(&repo);
+        group.bench_function(name, |b| {
+            b.iter(|| repo.decode_token(&token).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn extractor(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let repo = Arc::new(eddsa_repository());
+    let token = user_token(&repo);
+
+    let header_parts = || -> Parts {
+        Request::builder()
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .extension(repo.clone())
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let query_parts = || -> Parts {
+        Request::builder()
+            .uri(format!("/api/file/{}?token={token}", Uuid::nil()))
+            .extension(repo.clone())
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+
+    let mut group = c.benchmark_group("authorization");
+
+    group.bench_function("header", |b| {
+        b.to_async(&rt).iter_batched(
+            header_parts,
+            |mut parts| async move {
+                Authorization::from_request_parts(&mut parts, &())
+                    .await
+                    .unwrap()
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("query", |b| {
+        b.to_async(&rt).iter_batched(
+            query_parts,
+            |mut parts| async move {
+                Authorization::from_request_parts(&mut parts, &())
+                    .await
+                    .unwrap()
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode_token, extractor);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+use bytes::Bytes;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, Criterion, Throughput,
+};
+use downloader::utils::crypto::{ConcurrentHasher, HashStream};
+use futures_util::{stream, StreamExt};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    runtime::Runtime,
+};
+
+const SIZE: usize = 256 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn chunks() -> Vec<Bytes> {
+    let mut data = vec![0u8; SIZE];
+    rand::thread_rng().fill_bytes(&mut data);
+
+    let data = Bytes::from(data);
+    (0..SIZE)
+        .step_by(CHUNK_SIZE)
+        .map(|i| data.slice(i..i + CHUNK_SIZE))
+        .collect()
+}
+
+fn writer() -> BufWriter<File> {
+    let file = File::from_std(tempfile::tempfile().unwrap());
+    BufWriter::with_capacity(1024 * 1024, file)
+}
+
+/// Writing the data of an upload to a file while hashing it, as done by
+/// stores.
+fn store_hashing(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let chunks = chunks();
+
+    let mut group = c.benchmark_group("store_hashing");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+
+    group.bench_function("inline", |b| {
+        b.to_async(&rt).iter_batched(
+            || (chunks.clone(), writer()),
+            |(chunks, mut file)| async move {
+                let mut stream = HashStream::<_, Sha256>::new(
+                    stream::iter(chunks).map(Ok::<_, std::io::Error>),
+                );
+                while let Some(chunk) = stream.next().await {
+                    file.write_all(&chunk.unwrap()).await.unwrap();
+                }
+                file.flush().await.unwrap();
+                stream.hash()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("concurrent", |b| {
+        b.to_async(&rt).iter_batched(
+            || (chunks.clone(), writer()),
+            |(chunks, mut file)| async move {
+                let mut hasher = ConcurrentHasher::<Sha256>::new();
+                for chunk in chunks {
+                    hasher.update(chunk.clone()).await;
+                    file.write_all(&chunk).await.unwrap();
+                }
+                file.flush().await.unwrap();
+                hasher.finish().await.unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, store_hashing);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+#![no_main]
+
+use std::net::SocketAddr;
+
+use downloader::{
+    config::Config,
+    utils::serde::{deserialize_socket_addr, ResolvedFile, ResolvedPath},
+};
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Fields {
+    #[serde(deserialize_with = "deserialize_socket_addr")]
+    addr: SocketAddr,
+    file: Option<ResolvedFile>,
+    path: Option<ResolvedPath>,
+}
+
+fuzz_target!(|data: &str| {
+    let _ = toml::from_str::<Config>(data);
+    let _ = serde_json::from_str::<Config>(data);
+    let _ = toml::from_str::<Fields>(data);
+
+    if let Ok(fields) = serde_json::from_str::<Fields>(data) {
+        // Any accepted address must survive a serialization roundtrip.
+        let json = serde_json::json!({ "addr": fields.addr });
+        let back: Fields = serde_json::from_value(json).unwrap();
+        assert_eq!(back.addr, fields.addr);
+    }
+});
@@ -0,0 +1,40 @@
+#![no_main]
+
+use axum::{
+    body::Body,
+    extract::{FromRequest, Multipart, Request},
+    http::header,
+};
+use downloader::storage::routes::extract_multipart_file;
+use futures_util::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+
+const BOUNDARY: &str = "fuzzboundary";
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: std::sync::OnceLock<Runtime> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| Builder::new_current_thread().build().unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let req = Request::builder()
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .body(Body::from(data.to_vec()))
+        .unwrap();
+
+    runtime().block_on(async move {
+        let Ok(mut multipart) = Multipart::from_request(req, &()).await else {
+            return;
+        };
+
+        if let Ok((mut stream, _, _)) =
+            extract_multipart_file(&mut multipart).await
+        {
+            while let Some(Ok(_)) = stream.next().await {}
+        }
+    });
+});
@@ -0,0 +1,12 @@
+-- Add up migration script here
+
+CREATE TABLE api_key (
+    id blob PRIMARY KEY,
+    user_id blob NOT NULL,
+    created_at integer NOT NULL,
+    name text NOT NULL,
+    permission integer NOT NULL,
+    key_hash blob NOT NULL
+) STRICT;
+
+CREATE INDEX api_key_user_id_idx ON api_key(user_id);
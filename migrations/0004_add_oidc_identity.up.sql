@@ -0,0 +1,11 @@
+-- Add up migration script here
+
+CREATE TABLE oidc_identity (
+    issuer text NOT NULL,
+    subject text NOT NULL,
+    user_id blob NOT NULL,
+    created_at integer NOT NULL,
+    PRIMARY KEY (issuer, subject)
+) STRICT;
+
+CREATE INDEX oidc_identity_user_id_idx ON oidc_identity(user_id);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS totp_recovery_code;
+DROP TABLE IF EXISTS user_totp;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+
+CREATE TABLE user_totp (
+    user_id blob PRIMARY KEY,
+    created_at integer NOT NULL,
+    secret blob NOT NULL,
+    enabled integer NOT NULL,
+    last_step integer NOT NULL
+) STRICT;
+
+CREATE TABLE totp_recovery_code (
+    user_id blob NOT NULL,
+    code_hash blob NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+) STRICT;
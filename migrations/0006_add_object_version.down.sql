@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+DROP TRIGGER IF EXISTS object_version_delete;
+DROP TRIGGER IF EXISTS object_version_transfer;
+DROP TRIGGER IF EXISTS object_version_update;
+DROP TRIGGER IF EXISTS object_version_insert;
+DROP TABLE IF EXISTS object_version;
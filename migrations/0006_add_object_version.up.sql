@@ -0,0 +1,36 @@
+-- Add up migration script here
+
+-- Change counter of the objects owned by each user, bumped by the triggers
+-- below on every change so clients can cheaply tell if a listing changed.
+CREATE TABLE object_version (
+    user_id blob PRIMARY KEY,
+    version integer NOT NULL
+) STRICT;
+
+INSERT INTO object_version (user_id, version)
+SELECT DISTINCT user_id, 1 FROM object;
+
+CREATE TRIGGER object_version_insert AFTER INSERT ON object
+BEGIN
+    INSERT INTO object_version (user_id, version) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET version = version + 1;
+END;
+
+CREATE TRIGGER object_version_update AFTER UPDATE ON object
+BEGIN
+    INSERT INTO object_version (user_id, version) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET version = version + 1;
+END;
+
+CREATE TRIGGER object_version_transfer AFTER UPDATE OF user_id ON object
+WHEN OLD.user_id != NEW.user_id
+BEGIN
+    INSERT INTO object_version (user_id, version) VALUES (OLD.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET version = version + 1;
+END;
+
+CREATE TRIGGER object_version_delete AFTER DELETE ON object
+BEGIN
+    INSERT INTO object_version (user_id, version) VALUES (OLD.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET version = version + 1;
+END;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+-- Objects deleted less than the undelete window ago, whose data is kept
+-- until they are purged.
+CREATE TABLE deleted_object (
+    id blob PRIMARY KEY,
+    user_id blob NOT NULL,
+    created_at integer NOT NULL,
+    updated_at integer NOT NULL,
+    name text NOT NULL,
+    mime_type text NOT NULL,
+    size integer NOT NULL,
+    checksum_256 blob NOT NULL,
+    deleted_at integer NOT NULL
+) STRICT;
+
+CREATE INDEX deleted_object_deleted_at_idx ON deleted_object(deleted_at);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+
+-- Nonces of presigned urls not used yet, removed once used or expired.
+CREATE TABLE presign_nonce (
+    nonce text PRIMARY KEY,
+    object_id blob NOT NULL,
+    expires_at integer NOT NULL
+) STRICT;
+
+CREATE INDEX presign_nonce_expires_at_idx ON presign_nonce(expires_at);
@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+CREATE TABLE job (
+    id blob PRIMARY KEY,
+    user_id blob,
+    created_at integer NOT NULL,
+    updated_at integer NOT NULL,
+    kind text NOT NULL,
+    state integer NOT NULL,
+    progress integer NOT NULL,
+    total integer,
+    -- JSON encoded result of succeeded jobs
+    result text,
+    error text
+) STRICT;
+
+CREATE INDEX job_user_id_idx ON job(user_id);
+CREATE INDEX job_state_idx ON job(state);
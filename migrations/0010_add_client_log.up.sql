@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+CREATE TABLE client_log (
+    id blob PRIMARY KEY,
+    created_at integer NOT NULL,
+    user_id blob,
+    -- Request id of the failed request, as reported by the client
+    request_id text,
+    source text NOT NULL,
+    message text NOT NULL,
+    -- JSON encoded details
+    details text,
+    user_agent text,
+    ip text
+) STRICT;
+
+CREATE INDEX client_log_created_at_idx ON client_log(created_at);
+CREATE INDEX client_log_request_id_idx ON client_log(request_id);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+
+-- Public ids of objects when ids are exposed as slugs. Entries are kept
+-- after the objects are removed, so a slug never points to another one.
+CREATE TABLE object_slug (
+    slug text PRIMARY KEY,
+    object_id blob NOT NULL UNIQUE
+) STRICT;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+
+-- Where the current data of each object came from. Entries are replaced on
+-- each data write and kept after the objects are removed.
+CREATE TABLE object_provenance (
+    object_id blob PRIMARY KEY,
+    recorded_at integer NOT NULL,
+    ip text,
+    user_agent text,
+    -- One of `user`, `api_key`, `file`, `server` or `presigned`
+    token_type text NOT NULL,
+    -- Id of the api key, when uploaded with one
+    token_id text,
+    server_version text NOT NULL
+) STRICT;
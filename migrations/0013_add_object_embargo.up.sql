@@ -0,0 +1,8 @@
+-- Add up migration script here
+
+-- Instants before which objects can not be downloaded through shares.
+-- Entries are kept after the objects are removed, like their slugs.
+CREATE TABLE object_embargo (
+    object_id blob PRIMARY KEY,
+    available_from integer NOT NULL
+) STRICT;
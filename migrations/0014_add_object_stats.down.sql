@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS object_download_day;
+DROP TABLE IF EXISTS object_stats;
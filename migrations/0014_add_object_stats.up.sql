@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+-- Downloads of each object, written in batches. Entries are kept after the
+-- objects are removed, like their slugs.
+CREATE TABLE object_stats (
+    object_id blob PRIMARY KEY,
+    downloads integer NOT NULL,
+    last_accessed_at integer NOT NULL
+) STRICT;
+
+-- Downloads of each object per day, counted since the unix epoch. Days older
+-- than a year are removed as new ones are written.
+CREATE TABLE object_download_day (
+    object_id blob NOT NULL,
+    day integer NOT NULL,
+    downloads integer NOT NULL,
+    PRIMARY KEY (object_id, day)
+) STRICT;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+
+-- Downloads of all the objects per hour, counted since the unix epoch. Hours
+-- older than a week are removed as new ones are written.
+CREATE TABLE download_hour (
+    hour integer PRIMARY KEY,
+    downloads integer NOT NULL
+) STRICT;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS object_download_day_day_idx;
+DROP TABLE IF EXISTS download_rollup;
+DROP TABLE IF EXISTS download_event;
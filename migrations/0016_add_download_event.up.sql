@@ -0,0 +1,25 @@
+-- Add up migration script here
+
+-- Each download, without anything about who made it. They are rolled up into
+-- object_download_day and download_hour in the background, and removed once
+-- rolled up and older than the configured retention.
+CREATE TABLE download_event (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    object_id blob NOT NULL,
+    downloaded_at integer NOT NULL
+) STRICT;
+
+CREATE INDEX download_event_object_idx
+    ON download_event (object_id, downloaded_at);
+CREATE INDEX download_event_downloaded_at_idx
+    ON download_event (downloaded_at);
+
+-- The last event rolled up, the later ones are counted from the events.
+CREATE TABLE download_rollup (
+    id integer PRIMARY KEY CHECK (id = 0),
+    last_event_id integer NOT NULL
+) STRICT;
+
+INSERT INTO download_rollup (id, last_event_id) VALUES (0, 0);
+
+CREATE INDEX object_download_day_day_idx ON object_download_day (day);
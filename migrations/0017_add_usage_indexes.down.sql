@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS deleted_object_user_id_size_idx;
+DROP INDEX IF EXISTS object_user_id_size_idx;
+CREATE INDEX IF NOT EXISTS object_user_id_idx ON object(user_id);
@@ -0,0 +1,6 @@
+-- Add up migration script here
+
+-- Cover the sizes so the usage of users is summed from the indexes alone.
+DROP INDEX IF EXISTS object_user_id_idx;
+CREATE INDEX object_user_id_size_idx ON object(user_id, size);
+CREATE INDEX deleted_object_user_id_size_idx ON deleted_object(user_id, size);
@@ -0,0 +1,11 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS object_namespace_idx;
+DROP INDEX IF EXISTS user_namespace_username_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS user_username_idx ON user(username);
+
+ALTER TABLE deleted_object DROP COLUMN namespace;
+ALTER TABLE object DROP COLUMN namespace;
+ALTER TABLE user DROP COLUMN namespace;
+
+DROP TABLE IF EXISTS namespace;
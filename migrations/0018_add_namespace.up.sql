@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+CREATE TABLE namespace (
+    name text PRIMARY KEY,
+    created_at integer NOT NULL
+) STRICT;
+
+INSERT INTO namespace (name, created_at)
+VALUES ('default', CAST(strftime('%s', 'now') AS integer) * 1000);
+
+ALTER TABLE user ADD COLUMN namespace text NOT NULL DEFAULT 'default';
+ALTER TABLE object ADD COLUMN namespace text NOT NULL DEFAULT 'default';
+ALTER TABLE deleted_object ADD COLUMN namespace text NOT NULL DEFAULT 'default';
+
+-- Usernames are only unique within their namespace.
+DROP INDEX IF EXISTS user_username_idx;
+CREATE UNIQUE INDEX user_namespace_username_idx ON user(namespace, username);
+CREATE INDEX object_namespace_idx ON object(namespace);
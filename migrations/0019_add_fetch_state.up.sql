@@ -0,0 +1,19 @@
+-- Add up migration script here
+
+-- What is needed to resume the background fetch of a job after a restart
+CREATE TABLE fetch_state (
+    job_id blob PRIMARY KEY,
+    user_id blob NOT NULL,
+    namespace text NOT NULL,
+    url text NOT NULL,
+    -- Requested name, or the one of the remote file once known
+    name text,
+    mime_type text,
+    -- JSON encoded provenance of the request
+    provenance text NOT NULL,
+    fetched integer NOT NULL,
+    total integer,
+    etag text,
+    last_modified text,
+    updated_at integer NOT NULL
+) STRICT;
@@ -0,0 +1,10 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS group_object_object_id_idx;
+DROP TABLE IF EXISTS group_object;
+
+DROP INDEX IF EXISTS group_member_user_id_idx;
+DROP TABLE IF EXISTS group_member;
+
+DROP INDEX IF EXISTS group_namespace_name_idx;
+DROP TABLE IF EXISTS "group";
@@ -0,0 +1,32 @@
+-- Add up migration script here
+
+CREATE TABLE "group" (
+    id blob PRIMARY KEY,
+    created_at integer NOT NULL,
+    updated_at integer NOT NULL,
+    namespace text NOT NULL,
+    name text NOT NULL,
+    permission integer NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX group_namespace_name_idx ON "group"(namespace, name);
+
+-- Members get the permission of their groups on top of their own.
+CREATE TABLE group_member (
+    group_id blob NOT NULL,
+    user_id blob NOT NULL,
+    created_at integer NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+) STRICT;
+
+CREATE INDEX group_member_user_id_idx ON group_member(user_id);
+
+-- Objects the members of a group can read, besides their own.
+CREATE TABLE group_object (
+    group_id blob NOT NULL,
+    object_id blob NOT NULL,
+    created_at integer NOT NULL,
+    PRIMARY KEY (group_id, object_id)
+) STRICT;
+
+CREATE INDEX group_object_object_id_idx ON group_object(object_id);
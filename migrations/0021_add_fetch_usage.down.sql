@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+ALTER TABLE fetch_state DROP COLUMN folder;
+
+DROP TABLE IF EXISTS fetch_usage;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+
+-- Bytes fetched by each user per day, counted since the unix epoch
+CREATE TABLE fetch_usage (
+    user_id blob NOT NULL,
+    day integer NOT NULL,
+    bytes integer NOT NULL,
+    PRIMARY KEY (user_id, day)
+) STRICT;
+
+-- Folder the remote file is fetched into when it names the object
+ALTER TABLE fetch_state ADD COLUMN folder text;
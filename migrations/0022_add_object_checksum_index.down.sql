@@ -0,0 +1,3 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS object_user_id_checksum_idx;
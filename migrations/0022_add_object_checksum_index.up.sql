@@ -0,0 +1,4 @@
+-- Add up migration script here
+
+-- Finds the objects of a user with the checksum of an upload
+CREATE INDEX object_user_id_checksum_idx ON object(user_id, checksum_256);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS object_meta;
+
+ALTER TABLE deleted_object DROP COLUMN description;
+ALTER TABLE object DROP COLUMN description;
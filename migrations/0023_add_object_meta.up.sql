@@ -0,0 +1,13 @@
+-- Add up migration script here
+
+ALTER TABLE object ADD COLUMN description text;
+ALTER TABLE deleted_object ADD COLUMN description text;
+
+-- Custom key-value metadata of objects. Entries are kept after the objects
+-- are removed, like their provenance, so restored objects keep them.
+CREATE TABLE object_meta (
+    object_id blob NOT NULL,
+    key text NOT NULL,
+    value text NOT NULL,
+    PRIMARY KEY (object_id, key)
+) STRICT;
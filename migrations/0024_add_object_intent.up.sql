@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+-- Objects being created, recorded before their data is stored and marked
+-- committed once it is, so creations interrupted by a crash can be rolled
+-- back or completed on startup. Removed along with the object insert.
+CREATE TABLE object_intent (
+    id blob PRIMARY KEY,
+    user_id blob NOT NULL,
+    namespace text NOT NULL,
+    name text NOT NULL,
+    mime_type text NOT NULL,
+    -- 0 while the data is stored, 1 once in place
+    state integer NOT NULL,
+    -- Set once committed
+    size integer,
+    checksum_256 blob,
+    created_at integer NOT NULL
+) STRICT;
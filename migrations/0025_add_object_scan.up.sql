@@ -0,0 +1,12 @@
+-- Add up migration script here
+
+-- Results of malware scans of the data of objects, only valid while the
+-- object keeps the scanned checksum. Kept after the objects are removed,
+-- like their metadata, so restored objects keep them.
+CREATE TABLE object_scan (
+    object_id blob PRIMARY KEY,
+    checksum_256 blob NOT NULL,
+    status integer NOT NULL,
+    signature text,
+    scanned_at integer NOT NULL
+) STRICT;
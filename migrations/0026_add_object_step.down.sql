@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS object_step_state_idx;
+DROP TABLE IF EXISTS object_step;
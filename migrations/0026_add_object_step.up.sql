@@ -0,0 +1,16 @@
+-- Add up migration script here
+
+-- States of the post-processing steps run on the data of objects, replaced
+-- every time new data is stored.
+CREATE TABLE object_step (
+    object_id blob NOT NULL,
+    step text NOT NULL,
+    position integer NOT NULL,
+    state integer NOT NULL,
+    result text,
+    error text,
+    updated_at integer NOT NULL,
+    PRIMARY KEY (object_id, step)
+) STRICT;
+
+CREATE INDEX object_step_state_idx ON object_step(state);
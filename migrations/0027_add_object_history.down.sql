@@ -0,0 +1,6 @@
+-- Add down migration script here
+
+DROP INDEX IF EXISTS object_history_object_id_idx;
+DROP TABLE IF EXISTS object_history;
+
+ALTER TABLE object DROP COLUMN updated_by;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+
+-- User that last updated the info of each object, null if never updated or
+-- last updated with a file or server token.
+ALTER TABLE object ADD COLUMN updated_by blob;
+
+-- Updates of the info of objects and who made them. Entries are kept after
+-- the objects are removed, like their provenance.
+CREATE TABLE object_history (
+    object_id blob NOT NULL,
+    changed_at integer NOT NULL,
+    -- User of the token, null for file and server tokens
+    user_id blob,
+    -- One of `user`, `api_key`, `file` or `server`
+    token_type text NOT NULL,
+    -- Id of the api key, when updated with one
+    token_id text,
+    previous_name text NOT NULL,
+    name text NOT NULL,
+    -- Comma separated fields changed, among `name`, `mime_type`,
+    -- `description` and `metadata`
+    fields text NOT NULL
+) STRICT;
+
+CREATE INDEX object_history_object_id_idx ON object_history(object_id);
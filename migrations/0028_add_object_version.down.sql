@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+ALTER TABLE deleted_object DROP COLUMN version;
+ALTER TABLE object DROP COLUMN version;
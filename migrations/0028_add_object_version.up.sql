@@ -0,0 +1,7 @@
+-- Add up migration script here
+
+-- Incremented by every update of each object, so clients can make their
+-- updates conditional on the version they last read. Kept while deleted,
+-- so restored objects keep it.
+ALTER TABLE object ADD COLUMN version integer NOT NULL DEFAULT 0;
+ALTER TABLE deleted_object ADD COLUMN version integer NOT NULL DEFAULT 0;
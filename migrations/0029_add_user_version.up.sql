@@ -0,0 +1,5 @@
+-- Add up migration script here
+
+-- Incremented by every update of each user, so concurrent updates by
+-- different admins can be made conditional on the version they last read.
+ALTER TABLE user ADD COLUMN version integer NOT NULL DEFAULT 0;
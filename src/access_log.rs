@@ -0,0 +1,365 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::config::LoggingConfig;
+
+const CURRENT_FILE: &str = "access.log";
+const ROTATED_PREFIX: &str = "access-";
+const ROTATED_SUFFIX: &str = ".log";
+
+/// Entries waiting to be written, newer ones are dropped while full.
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Debug, Default, Serialize)]
+struct AccessEntry {
+    time: DateTime<Utc>,
+    request_id: Option<String>,
+    method: String,
+    path: String,
+    client_ip: Option<String>,
+    status: Option<u16>,
+    user_id: Option<String>,
+    bytes: Option<u64>,
+    latency_ms: Option<f64>,
+}
+
+impl Visit for AccessEntry {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "request_id" => self.request_id = Some(value.to_owned()),
+            "method" => self.method = value.to_owned(),
+            "path" => self.path = value.to_owned(),
+            "client_ip" => self.client_ip = Some(value.to_owned()),
+            "user_id" => self.user_id = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "status" => self.status = u16::try_from(value).ok(),
+            "bytes" => self.bytes = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if let Ok(value) = u64::try_from(value) {
+            self.record_u64(field, value);
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "latency_ms" {
+            self.latency_ms = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        // Fields recorded with `%` are formatted with `Display`
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// Writes a line to the access log once each request span closes, see
+/// `server::CustomMakeSpan`.
+pub struct AccessLogLayer {
+    tx: SyncSender<AccessEntry>,
+}
+
+impl AccessLogLayer {
+    /// Starts the thread writing the access log to `dir`.
+    pub fn new(dir: PathBuf, cfg: &LoggingConfig) -> io::Result<Self> {
+        let file = RotatingFile::open(dir, cfg)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+
+        thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || write_entries(rx, file))?;
+
+        Ok(Self { tx })
+    }
+
+    /// Whether the layer needs the span or event, used to filter it.
+    pub fn wants(meta: &Metadata<'_>) -> bool {
+        meta.is_span()
+            && meta.name() == "request"
+            && meta.fields().field("status").is_some()
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &Attributes<'_>,
+        id: &Id,
+        ctx: Context<'_, S>,
+    ) {
+        if !Self::wants(attrs.metadata()) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut entry = AccessEntry {
+            time: Utc::now(),
+            ..Default::default()
+        };
+        attrs.record(&mut entry);
+        span.extensions_mut().insert(entry);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(entry) = extensions.get_mut::<AccessEntry>() {
+            values.record(entry);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let entry = span.extensions_mut().remove::<AccessEntry>();
+        if let Some(entry) = entry {
+            // Requests never wait for the log to be written
+            let _ = self.tx.try_send(entry);
+        }
+    }
+}
+
+fn write_entries(rx: Receiver<AccessEntry>, mut file: RotatingFile) {
+    while let Ok(entry) = rx.recv() {
+        let mut res = file.write(&entry);
+        // Flushed once all the pending entries are written
+        while let Ok(entry) = rx.try_recv() {
+            res = res.and_then(|_| file.write(&entry));
+        }
+
+        if let Err(error) = res.and_then(|_| file.flush()) {
+            tracing::error!(%error, "failed to write access log");
+        }
+    }
+}
+
+struct RotatingFile {
+    dir: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened_at: SystemTime,
+    max_size: u64,
+    max_age: Duration,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(dir: PathBuf, cfg: &LoggingConfig) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (file, size, opened_at) = open_current(&dir)?;
+
+        Ok(Self {
+            dir,
+            file,
+            size,
+            opened_at,
+            max_size: cfg.max_size,
+            max_age: cfg.max_age,
+            keep: cfg.keep,
+        })
+    }
+
+    fn write(&mut self, entry: &AccessEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let too_large = self.max_size != 0
+            && self.size != 0
+            && self.size + line.len() as u64 > self.max_size;
+        let too_old = !self.max_age.is_zero()
+            && self.opened_at.elapsed().unwrap_or_default() >= self.max_age;
+        if too_large || too_old {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let name = format!(
+            "{ROTATED_PREFIX}{}{ROTATED_SUFFIX}",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        );
+        fs::rename(self.dir.join(CURRENT_FILE), self.dir.join(name))?;
+
+        (self.file, self.size, self.opened_at) = open_current(&self.dir)?;
+        prune_rotated(&self.dir, self.keep)
+    }
+}
+
+fn open_current(dir: &Path) -> io::Result<(BufWriter<File>, u64, SystemTime)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(CURRENT_FILE))?;
+
+    let meta = file.metadata()?;
+    let opened_at = meta.created().unwrap_or_else(|_| SystemTime::now());
+
+    Ok((BufWriter::new(file), meta.len(), opened_at))
+}
+
+fn prune_rotated(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(ROTATED_PREFIX) && name.ends_with(ROTATED_SUFFIX) {
+            rotated.push(entry.path());
+        }
+    }
+
+    // Names sort by their timestamp
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(keep);
+    for path in &rotated[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracing::field::{display, Empty};
+    use tracing_subscriber::{
+        filter::filter_fn, layer::SubscriberExt, Layer, Registry,
+    };
+    use uuid::Uuid;
+
+    use crate::config::LoggingConfig;
+
+    use super::*;
+
+    fn config(max_size: u64, keep: usize) -> LoggingConfig {
+        LoggingConfig {
+            access_log: true,
+            max_size,
+            max_age: Duration::ZERO,
+            keep,
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file =
+            RotatingFile::open(dir.path().to_owned(), &config(256, 2)).unwrap();
+
+        let entry = AccessEntry {
+            method: "GET".into(),
+            path: "/api/file".into(),
+            ..Default::default()
+        };
+        for _ in 0..20 {
+            file.write(&entry).unwrap();
+        }
+        file.flush().unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+
+        assert_eq!(names.len(), 3, "{names:?}");
+        assert_eq!(names[2], CURRENT_FILE);
+        for name in &names {
+            let size = fs::metadata(dir.path().join(name)).unwrap().len();
+            assert!(size <= 256, "`{name}` not rotated at {size} bytes");
+        }
+    }
+
+    #[test]
+    fn test_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer =
+            AccessLogLayer::new(dir.path().to_owned(), &config(0, 1)).unwrap();
+        let subscriber = Registry::default()
+            .with(layer.with_filter(filter_fn(AccessLogLayer::wants)));
+
+        let user_id = Uuid::new_v4();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "request",
+                request_id = "abc",
+                method = %"PUT",
+                path = %"/api/file/1/data",
+                user_id = Empty,
+                object_id = Empty,
+                bytes = Empty,
+                status = Empty,
+                latency_ms = Empty,
+            );
+            span.record("user_id", display(user_id));
+            span.record("bytes", 42);
+            span.record("status", 200);
+            span.record("latency_ms", 1.5);
+
+            tracing::info_span!("other").in_scope(|| {});
+        });
+
+        let path = dir.path().join(CURRENT_FILE);
+        for _ in 0..100 {
+            let log = fs::read_to_string(&path).unwrap();
+            if log.is_empty() {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+
+            assert_eq!(log.lines().count(), 1, "{log}");
+            let entry: serde_json::Value = serde_json::from_str(&log).unwrap();
+            assert_eq!(entry["request_id"], "abc");
+            assert_eq!(entry["method"], "PUT");
+            assert_eq!(entry["path"], "/api/file/1/data");
+            assert_eq!(entry["status"], 200);
+            assert_eq!(entry["user_id"], user_id.to_string());
+            assert_eq!(entry["bytes"], 42);
+            assert_eq!(entry["latency_ms"], 1.5);
+            return;
+        }
+        panic!("access log was not written");
+    }
+}
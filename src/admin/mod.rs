@@ -0,0 +1,132 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    storage::{
+        cache::CacheUsage,
+        manager::{DirUsage, StorageUsage},
+        provenance::Provenance,
+        sweep::TempSweeps,
+        Object,
+    },
+    utils::retry::BusyRetries,
+};
+
+pub mod routes;
+pub mod stats;
+pub mod transfer;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("no local owner for objects of user `{0}`")]
+    OwnerNotFound(Uuid),
+    #[error("data of object `{0}` does not match its checksum")]
+    ChecksumMismatch(Uuid),
+    #[error("invalid export ids: {0}")]
+    InvalidExportIds(&'static str),
+}
+
+impl AdminError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::InvalidArchive(..) => StatusCode::BAD_REQUEST,
+            AdminError::OwnerNotFound(..) => StatusCode::BAD_REQUEST,
+            AdminError::ChecksumMismatch(..) => StatusCode::BAD_REQUEST,
+            AdminError::InvalidExportIds(..) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    AdminError {
+        InvalidArchive(..) => 1, "the backup archive is malformed";
+        OwnerNotFound(..) => 2, "no local user owns the imported objects";
+        ChecksumMismatch(..) => 3,
+            "the data of an imported object does not match its checksum";
+        InvalidExportIds(..) => 4,
+            "the ids of the objects to export are invalid";
+    }
+}
+
+/// Where the storage space goes, for diagnosing a full disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageReport {
+    #[serde(flatten)]
+    pub usage: StorageUsage,
+    /// Deleted objects whose data is kept until the undelete window ends.
+    pub trash: DirUsage,
+    pub object_cache: Option<CacheUsage>,
+    pub largest_objects: Vec<Object>,
+    /// Queries retried since startup because the database was locked.
+    pub database_busy: BusyRetries,
+    /// Incomplete objects removed since startup.
+    pub temp_sweeps: TempSweeps,
+}
+
+/// Totals of the objects stored and of the recent activity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub computed_at: DateTime<Utc>,
+    pub objects: DirUsage,
+    /// Usage by the part of the mime type before the slash, the largest
+    /// first.
+    pub mime_families: Vec<MimeFamilyUsage>,
+    /// The users whose objects use the most space, the largest first.
+    pub top_users: Vec<UserUsage>,
+    pub last_24h: RecentActivity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MimeFamilyUsage {
+    pub family: String,
+    #[serde(flatten)]
+    pub usage: DirUsage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub user_id: Uuid,
+    /// None if the user no longer exists.
+    pub username: Option<String>,
+    #[serde(flatten)]
+    pub usage: DirUsage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentActivity {
+    /// Objects created, with their current size.
+    pub uploads: DirUsage,
+    /// None if downloads are not counted.
+    pub downloads: Option<u64>,
+}
+
+/// Metadata of the objects in an export archive, the first entry of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub exported_at: DateTime<Utc>,
+    pub objects: Vec<ExportedObject>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedObject {
+    pub object: Object,
+    /// Username of the owner, used to find it in the importing instance.
+    pub owner: Option<String>,
+    /// Kept by the importing instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub imported: Vec<Uuid>,
+    /// Objects whose id is already used in this instance.
+    pub skipped: Vec<Uuid>,
+    /// Objects of the manifest without data in the archive.
+    pub missing: Vec<Uuid>,
+}
@@ -0,0 +1,746 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use axum::{
+    extract::{Path, Request},
+    http::{header, StatusCode},
+    response::Response,
+    routing, Extension, Router,
+};
+use chrono::{TimeDelta, Utc};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use sqlx::Sqlite;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Token},
+    errors::DownloaderError,
+    job::{queue::JobQueue, Job},
+    maintenance::Maintenance,
+    storage::{
+        manager::Manager,
+        provenance::{Provenance, ProvenanceRepository},
+        repository::{ObjectRepository, RepositoryError},
+        stats::DownloadStats,
+        sweep::SweepCounters,
+        WriteLocks,
+    },
+    timeout::transfer,
+    user::{repository::UserRepository, UserError},
+    utils::{
+        extractors::{Body, Query},
+        retry::busy_retries,
+    },
+};
+
+use super::{
+    stats::StatsCache,
+    transfer::{export_archive, import_archive},
+    AdminError, ExportManifest, ExportedObject, ImportReport, MimeFamilyUsage,
+    RecentActivity, ServerStats, StorageReport, UserUsage,
+};
+
+const DEFAULT_LARGEST_LIMIT: u32 = 10;
+const TOP_USERS_LIMIT: u32 = 10;
+const MAX_EXPORT_IDS: usize = 1000;
+
+pub fn admin_routes<S, M>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    M: Manager,
+{
+    router
+        .route("/storage", routing::get(get_storage_report::<M>))
+        .route("/stats", routing::get(get_server_stats))
+        .route("/export", transfer(routing::get(export_objects::<M>)))
+        .route("/import", transfer(routing::post(import_objects::<M>)))
+        .route("/provenance/:id", routing::get(get_provenance))
+        .route("/maintenance", routing::post(post_maintenance))
+}
+
+/// The admin endpoints are server-wide, so the administrators of the other
+/// namespaces are not allowed in.
+fn require_admin(token: &Token) -> Result<(), DownloaderError> {
+    if !token.is_server_admin() {
+        return Err(AuthError::AccessDenied.into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageReportQuery {
+    /// Amount of the largest objects to report.
+    pub limit: Option<u32>,
+}
+
+pub async fn get_storage_report<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(sweeps): Extension<Arc<SweepCounters>>,
+    Query(query): Query<StorageReportQuery>,
+) -> Result<Body<StorageReport>, DownloaderError> {
+    require_admin(&token)?;
+
+    let usage = manager.usage().await?;
+    let trash = repo.get_trash_usage().await?;
+    let largest_objects = repo
+        .get_largest(query.limit.unwrap_or(DEFAULT_LARGEST_LIMIT))
+        .await?;
+
+    Ok(Body(StorageReport {
+        usage,
+        trash,
+        object_cache: repo.cache_usage(),
+        largest_objects,
+        database_busy: busy_retries(),
+        temp_sweeps: sweeps.get(),
+    }))
+}
+
+/// Totals of the stored objects and of the activity of the last day,
+/// computed again once the cached ones get old.
+pub async fn get_server_stats(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(users): Extension<UserRepository<Sqlite>>,
+    download_stats: Option<Extension<DownloadStats>>,
+    cache: Option<Extension<Arc<StatsCache>>>,
+) -> Result<Body<ServerStats>, DownloaderError> {
+    require_admin(&token)?;
+
+    if let Some(stats) = cache.as_ref().and_then(|Extension(c)| c.get()) {
+        return Ok(Body(stats));
+    }
+
+    let now = Utc::now();
+    let day_ago = now - TimeDelta::hours(24);
+
+    let mime_families = repo
+        .get_usage_by_mime_family()
+        .await?
+        .into_iter()
+        .map(|(family, usage)| MimeFamilyUsage { family, usage })
+        .collect();
+
+    let mut top_users = Vec::new();
+    for (user_id, usage) in repo.get_top_users(TOP_USERS_LIMIT).await? {
+        let username = match users.get(user_id).await {
+            Ok(user) => Some(user.username),
+            Err(UserError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        top_users.push(UserUsage {
+            user_id,
+            username,
+            usage,
+        });
+    }
+
+    let downloads = match &download_stats {
+        Some(Extension(stats)) => {
+            Some(stats.get_downloads_since(day_ago).await?)
+        }
+        None => None,
+    };
+
+    let stats = ServerStats {
+        computed_at: now,
+        objects: repo.get_usage(None).await?,
+        mime_families,
+        top_users,
+        last_24h: RecentActivity {
+            uploads: repo.get_usage(Some(day_ago)).await?,
+            downloads,
+        },
+    };
+
+    if let Some(Extension(cache)) = &cache {
+        cache.insert(&stats);
+    }
+    Ok(Body(stats))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportQuery {
+    /// Comma separated ids of the objects to export.
+    pub ids: String,
+}
+
+/// Streams a tar archive with the objects and their metadata, to be
+/// imported by another instance.
+pub async fn export_objects<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(users): Extension<UserRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    provenances: Option<Extension<ProvenanceRepository<Sqlite>>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, DownloaderError> {
+    require_admin(&token)?;
+
+    let ids = query
+        .ids
+        .split(',')
+        .map(|id| id.trim().parse::<Uuid>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| AdminError::InvalidExportIds("malformed id"))?;
+    if ids.len() > MAX_EXPORT_IDS {
+        return Err(AdminError::InvalidExportIds("too many ids").into());
+    }
+
+    let mut usernames = HashMap::new();
+    let mut objects = Vec::with_capacity(ids.len());
+    for id in ids {
+        let object = repo.get(id).await?;
+
+        let owner = match usernames.get(&object.user_id) {
+            Some(owner) => Option::clone(owner),
+            None => {
+                let owner = match users.get(object.user_id).await {
+                    Ok(user) => Some(user.username),
+                    Err(UserError::NotFound) => None,
+                    Err(error) => return Err(error.into()),
+                };
+                usernames.insert(object.user_id, owner.clone());
+                owner
+            }
+        };
+
+        let provenance = match &provenances {
+            Some(Extension(provenances)) => match provenances.get(id).await {
+                Ok(provenance) => Some(provenance),
+                Err(RepositoryError::NotFound(..)) => None,
+                Err(error) => return Err(error.into()),
+            },
+            None => None,
+        };
+
+        objects.push(ExportedObject {
+            object,
+            owner,
+            provenance,
+        });
+    }
+
+    let manifest = ExportManifest {
+        exported_at: Utc::now(),
+        objects,
+    };
+    let stream = export_archive(manager, manifest).map_err(|error| {
+        DownloaderError::Other(
+            error.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })?;
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"downloader-export.tar\"",
+        )
+        .body(axum::body::Body::from_stream(stream))
+        .map_err(DownloaderError::from)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportQuery {
+    /// Owner of the objects whose owner is not found by id or username.
+    pub owner: Option<Uuid>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn import_objects<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(users): Extension<UserRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(locks): Extension<Arc<WriteLocks>>,
+    provenances: Option<Extension<ProvenanceRepository<Sqlite>>>,
+    Query(query): Query<ImportQuery>,
+    req: Request,
+) -> Result<Body<ImportReport>, DownloaderError> {
+    require_admin(&token)?;
+
+    let reader = StreamReader::new(
+        req.into_body().into_data_stream().map_err(io::Error::other),
+    );
+
+    import_archive(
+        reader,
+        &repo,
+        &users,
+        provenances.as_ref().map(|Extension(p)| p),
+        manager.as_ref(),
+        &locks,
+        query.owner,
+    )
+    .await
+    .map(Body)
+}
+
+/// Where the current data of an object came from.
+pub async fn get_provenance(
+    Authorization(token): Authorization,
+    Extension(provenances): Extension<ProvenanceRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<Provenance>, DownloaderError> {
+    require_admin(&token)?;
+
+    Ok(Body(provenances.get(id).await?))
+}
+
+/// Queues a database maintenance run, outside of the scheduled windows.
+pub async fn post_maintenance(
+    Authorization(token): Authorization,
+    Extension(maintenance): Extension<Maintenance>,
+    Extension(jobs): Extension<Arc<JobQueue>>,
+) -> Result<(StatusCode, Body<Job>), DownloaderError> {
+    require_admin(&token)?;
+
+    let user_id = match &token {
+        Token::User(user_token) => Some(user_token.user_id),
+        _ => None,
+    };
+    let job = maintenance.enqueue(&jobs, user_id).await?;
+
+    Ok((StatusCode::ACCEPTED, Body(job)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{
+            header, request::Builder as RequestBuilder, Request, StatusCode,
+        },
+        response::Response,
+        Extension, Router,
+    };
+    use futures_util::stream;
+    use sqlx::{migrate, Sqlite, SqlitePool};
+    use test_log::test;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        admin::{stats::StatsCache, ImportReport, ServerStats, StorageReport},
+        auth::{
+            repository::{
+                tests::repository as token_repository, TokenRepository,
+            },
+            Permission,
+        },
+        config::StorageConfig,
+        storage::{
+            cache::ObjectCache,
+            manager::{DirUsage, Manager, ObjectManager},
+            repository::ObjectRepository,
+            sweep::{sweep_incomplete, SweepCounters},
+            ObjectData, WriteLocks,
+        },
+        user::{
+            password::PasswordHasher, repository::UserRepository, DeletePolicy,
+            UserData,
+        },
+        utils::serde::ResolvedPath,
+    };
+
+    use super::admin_routes;
+
+    #[test(tokio::test)]
+    async fn test_storage_report() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = ResolvedPath::new(dir.path().to_string_lossy().into_owned())
+            .unwrap();
+        let manager = Arc::new(ObjectManager::new(&StorageConfig {
+            state_dir: path.clone(),
+            data_dir: path.clone(),
+            temp_dir: path,
+            on_user_delete: DeletePolicy::Block,
+            object_cache_size: 8,
+            object_cache_ttl: Duration::from_secs(30),
+            upload_timeout: Duration::ZERO,
+            upload_min_rate: 0,
+            upload_rate_window: Duration::ZERO,
+            wait_for_writes: true,
+            undelete_window: Duration::ZERO,
+            fetch: None,
+            expose_ids: Default::default(),
+            stats_flush_interval: Duration::ZERO,
+            stats_rollup_interval: Duration::ZERO,
+            download_retention: Duration::ZERO,
+            names: Default::default(),
+            temp_sweep_interval: Duration::ZERO,
+            temp_max_age: Duration::ZERO,
+            data_layout: Default::default(),
+            io: Default::default(),
+            cache: None,
+            backend: Default::default(),
+            post_process: Vec::new(),
+            upload_concurrency: Default::default(),
+        }));
+        let repo = ObjectRepository::new(db)
+            .with_cache(ObjectCache::new(8, Duration::from_secs(30)));
+        let token_repo = Arc::new(token_repository());
+
+        let mut ids = Vec::new();
+        for content in [b"small".as_slice(), b"the largest one"] {
+            let id = Uuid::new_v4();
+            let stream = stream::iter([Ok(content.into())]);
+            let (size, checksum_256) =
+                manager.store(id, stream, None, None).await.unwrap();
+            let data = ObjectData {
+                name: "file".into(),
+                mime_type: "text/plain".into(),
+                size,
+                checksum_256,
+            };
+            repo.create(id, Uuid::new_v4(), data).await.unwrap();
+            ids.push(id);
+        }
+        repo.trash(ids[0]).await.unwrap();
+
+        let sweeps = Arc::new(SweepCounters::default());
+        sweep_incomplete(manager.as_ref(), &sweeps, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let router = admin_routes::<_, ObjectManager>(Router::new())
+            .layer(Extension(repo))
+            .layer(Extension(manager))
+            .layer(Extension(sweeps))
+            .layer(Extension(token_repo.clone()));
+
+        let request = |permission| {
+            let token = token_repo
+                .generate_user_token(Uuid::new_v4(), permission, "admin".into())
+                .unwrap();
+            Request::get("/storage")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = router
+            .clone()
+            .oneshot(request(Permission::UNPRIVILEGED))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = router.oneshot(request(Permission::ADMIN)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let report: StorageReport = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            report.usage.data,
+            DirUsage {
+                files: 2,
+                bytes: 20
+            }
+        );
+        assert_eq!(report.usage.orphaned, DirUsage::default());
+        assert_eq!(report.trash, DirUsage { files: 1, bytes: 5 });
+        assert_eq!(report.object_cache.unwrap().capacity, 8);
+        let largest: Vec<_> =
+            report.largest_objects.iter().map(|obj| obj.id).collect();
+        assert_eq!(largest, [ids[1]]);
+        assert_eq!(report.temp_sweeps.runs, 1);
+    }
+
+    struct Instance {
+        router: Router,
+        repo: ObjectRepository<Sqlite>,
+        users: UserRepository<Sqlite>,
+        manager: Arc<ObjectManager>,
+        token_repo: Arc<TokenRepository>,
+        _dir: TempDir,
+    }
+
+    impl Instance {
+        async fn new() -> Self {
+            let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+            migrate!().run(&db).await.unwrap();
+
+            let dir = tempfile::tempdir().unwrap();
+            let path =
+                ResolvedPath::new(dir.path().to_string_lossy().into_owned())
+                    .unwrap();
+            let manager = Arc::new(ObjectManager::new(&StorageConfig {
+                state_dir: path.clone(),
+                data_dir: path.clone(),
+                temp_dir: path,
+                on_user_delete: DeletePolicy::Block,
+                object_cache_size: 0,
+                object_cache_ttl: Duration::ZERO,
+                upload_timeout: Duration::ZERO,
+                upload_min_rate: 0,
+                upload_rate_window: Duration::ZERO,
+                wait_for_writes: true,
+                undelete_window: Duration::ZERO,
+                fetch: None,
+                expose_ids: Default::default(),
+                stats_flush_interval: Duration::ZERO,
+                stats_rollup_interval: Duration::ZERO,
+                download_retention: Duration::ZERO,
+                names: Default::default(),
+                temp_sweep_interval: Duration::ZERO,
+                temp_max_age: Duration::ZERO,
+                data_layout: Default::default(),
+                io: Default::default(),
+                cache: None,
+                backend: Default::default(),
+                post_process: Vec::new(),
+                upload_concurrency: Default::default(),
+            }));
+            let repo = ObjectRepository::new(db.clone());
+            let users = UserRepository::new(db, PasswordHasher::bcrypt(4));
+            let token_repo = Arc::new(token_repository());
+
+            let router = admin_routes::<_, ObjectManager>(Router::new())
+                .layer(Extension(repo.clone()))
+                .layer(Extension(users.clone()))
+                .layer(Extension(manager.clone()))
+                .layer(Extension(Arc::new(WriteLocks::new(true))))
+                .layer(Extension(token_repo.clone()));
+
+            Self {
+                router,
+                repo,
+                users,
+                manager,
+                token_repo,
+                _dir: dir,
+            }
+        }
+
+        async fn create_user(&self, username: &str) -> Uuid {
+            let data = UserData {
+                username: username.into(),
+                password: "password".into(),
+            };
+            self.users
+                .create(Permission::UNPRIVILEGED, data)
+                .await
+                .unwrap()
+                .id
+        }
+
+        async fn request(&self, req: RequestBuilder, body: Body) -> Response {
+            let token = self
+                .token_repo
+                .generate_user_token(
+                    Uuid::new_v4(),
+                    Permission::ADMIN,
+                    "admin".into(),
+                )
+                .unwrap();
+            let req = req
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(body)
+                .unwrap();
+            self.router.clone().oneshot(req).await.unwrap()
+        }
+
+        async fn import(&self, archive: &[u8], query: &str) -> Response {
+            let uri = format!("/import{query}");
+            self.request(Request::post(uri), Body::from(archive.to_vec()))
+                .await
+        }
+    }
+
+    async fn import_report(res: Response) -> ImportReport {
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(status, StatusCode::OK, "{body:?}");
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_server_stats() {
+        let mut instance = Instance::new().await;
+        let alice = instance.create_user("alice").await;
+        instance.router = instance.router.clone().layer(Extension(Arc::new(
+            StatsCache::new(Duration::from_secs(60)),
+        )));
+
+        let files = [
+            (alice, "image/png", 700),
+            (alice, "text/plain", 20),
+            (Uuid::new_v4(), "image/jpeg", 300),
+            (Uuid::new_v4(), "invalid", 5),
+        ];
+        for (owner, mime_type, size) in files {
+            let data = ObjectData {
+                name: "file".into(),
+                mime_type: mime_type.into(),
+                size,
+                checksum_256: [0; 32],
+            };
+            instance
+                .repo
+                .create(Uuid::new_v4(), owner, data)
+                .await
+                .unwrap();
+        }
+
+        let get_stats = || async {
+            let res = instance
+                .request(Request::get("/stats"), Body::empty())
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<ServerStats>(&body).unwrap()
+        };
+        let stats = get_stats().await;
+
+        assert_eq!(
+            stats.objects,
+            DirUsage {
+                files: 4,
+                bytes: 1025
+            }
+        );
+        assert_eq!(stats.last_24h.uploads, stats.objects);
+        assert_eq!(stats.last_24h.downloads, None);
+        let families: Vec<_> = stats
+            .mime_families
+            .iter()
+            .map(|f| (f.family.as_str(), f.usage.files, f.usage.bytes))
+            .collect();
+        assert_eq!(
+            families,
+            [("image", 2, 1000), ("text", 1, 20), ("invalid", 1, 5)],
+        );
+        assert_eq!(stats.top_users.len(), 3);
+        assert_eq!(stats.top_users[0].user_id, alice);
+        assert_eq!(stats.top_users[0].username.as_deref(), Some("alice"));
+        assert_eq!(stats.top_users[0].usage.bytes, 720);
+        assert_eq!(stats.top_users[1].username, None);
+
+        // Served from the cache until it gets old
+        let data = ObjectData {
+            name: "file".into(),
+            mime_type: "text/plain".into(),
+            size: 1,
+            checksum_256: [0; 32],
+        };
+        instance
+            .repo
+            .create(Uuid::new_v4(), alice, data)
+            .await
+            .unwrap();
+        assert_eq!(get_stats().await, stats);
+    }
+
+    #[test(tokio::test)]
+    async fn test_export_import() {
+        let src = Instance::new().await;
+        let alice = src.create_user("alice").await;
+        let bob = src.create_user("bob").await;
+
+        let mut ids = Vec::new();
+        for (owner, content) in [(alice, b"data".as_slice()), (bob, &[7; 700])]
+        {
+            let id = Uuid::new_v4();
+            let stream = stream::iter([Ok(content.to_vec().into())]);
+            let (size, checksum_256) =
+                src.manager.store(id, stream, None, None).await.unwrap();
+            let data = ObjectData {
+                name: "file".into(),
+                mime_type: "text/plain".into(),
+                size,
+                checksum_256,
+            };
+            src.repo.create(id, owner, data).await.unwrap();
+            ids.push(id);
+        }
+
+        let uri = format!("/export?ids={},{}", ids[0], ids[1]);
+        let res = src.request(Request::get(uri), Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let archive = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+        // Only alice exists in the destination, under another id
+        let dst = Instance::new().await;
+        let dst_alice = dst.create_user("alice").await;
+        let res = dst.import(&archive, "").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(dst.repo.get_all(10, 0).await.unwrap().is_empty());
+
+        let fallback = dst.create_user("fallback").await;
+        let report = import_report(
+            dst.import(&archive, &format!("?owner={fallback}")).await,
+        )
+        .await;
+        assert_eq!(report.imported, ids);
+        assert!(report.skipped.is_empty() && report.missing.is_empty());
+
+        for (id, owner) in ids.iter().zip([dst_alice, fallback]) {
+            let src_obj = src.repo.get(*id).await.unwrap();
+            let dst_obj = dst.repo.get(*id).await.unwrap();
+            assert_eq!(dst_obj.user_id, owner);
+            assert_eq!(dst_obj.data, src_obj.data);
+            assert_eq!(dst_obj.created_at, src_obj.created_at);
+
+            let mut data = Vec::new();
+            let mut reader = dst.manager.fetch(*id).await.unwrap();
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.len() as u64, src_obj.data.size);
+        }
+
+        let report = import_report(dst.import(&archive, "").await).await;
+        assert!(report.imported.is_empty());
+        assert_eq!(report.skipped, ids);
+    }
+
+    #[test(tokio::test)]
+    async fn test_import_checksum_mismatch() {
+        let src = Instance::new().await;
+        let owner = src.create_user("owner").await;
+
+        let id = Uuid::new_v4();
+        let stream = stream::iter([Ok(b"original".to_vec().into())]);
+        let (size, checksum_256) =
+            src.manager.store(id, stream, None, None).await.unwrap();
+        let data = ObjectData {
+            name: "file".into(),
+            mime_type: "text/plain".into(),
+            size,
+            checksum_256,
+        };
+        src.repo.create(id, owner, data).await.unwrap();
+
+        let uri = format!("/export?ids={id}");
+        let res = src.request(Request::get(uri), Body::empty()).await;
+        let mut archive = to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec();
+
+        let pos = archive.windows(8).position(|w| w == b"original").unwrap();
+        archive[pos] = b'O';
+
+        let dst = Instance::new().await;
+        dst.create_user("owner").await;
+        let res = dst.import(&archive, "").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(dst.repo.get_all(10, 0).await.unwrap().is_empty());
+        assert!(dst.manager.fetch(id).await.is_err());
+    }
+}
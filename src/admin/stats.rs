@@ -0,0 +1,34 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::ServerStats;
+
+/// Keeps the last [`ServerStats`] for `ttl`, as computing them scans all
+/// the objects.
+pub struct StatsCache {
+    entry: Mutex<Option<(Instant, ServerStats)>>,
+    ttl: Duration,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entry: Mutex::new(None),
+            ttl,
+        }
+    }
+
+    pub fn get(&self) -> Option<ServerStats> {
+        let entry = self.entry.lock().unwrap();
+        entry
+            .as_ref()
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    pub fn insert(&self, stats: &ServerStats) {
+        *self.entry.lock().unwrap() = Some((Instant::now(), stats.clone()));
+    }
+}
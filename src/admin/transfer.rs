@@ -0,0 +1,360 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{future::ready, stream, Stream, StreamExt, TryStreamExt};
+use sqlx::Sqlite;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{
+    errors::DownloaderError,
+    storage::{
+        manager::Manager, provenance::ProvenanceRepository,
+        repository::ObjectRepository, WriteLocks,
+    },
+    user::{repository::UserRepository, UserError},
+};
+
+use super::{AdminError, ExportManifest, ImportReport};
+
+/// Name of the manifest, the first entry of export archives.
+pub const MANIFEST_ENTRY: &str = "manifest.json";
+/// Directory of the object data entries, named by the object id.
+const OBJECTS_DIR: &str = "objects/";
+
+const BLOCK_SIZE: usize = 512;
+const MAX_MANIFEST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Streams an uncompressed tar archive with the manifest followed by the
+/// data of each object in it.
+pub fn export_archive<M: Manager>(
+    manager: Arc<M>,
+    manifest: ExportManifest,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>> + Send + 'static> {
+    let json = serde_json::to_vec_pretty(&manifest)?;
+
+    let mut head =
+        entry_header(MANIFEST_ENTRY, json.len() as u64, manifest.exported_at)?
+            .to_vec();
+    head.extend_from_slice(&json);
+    head.resize(head.len() + padding(json.len() as u64), 0);
+
+    let objects = stream::iter(manifest.objects)
+        .then(move |exported| {
+            let manager = manager.clone();
+            async move {
+                let obj = exported.object;
+                let size = obj.data.size;
+
+                let header = entry_header(
+                    &format!("{OBJECTS_DIR}{}", obj.id),
+                    size,
+                    obj.updated_at,
+                )?;
+                let reader = manager.fetch(obj.id).await.map_err(|error| {
+                    tracing::error!(
+                        %error,
+                        id = %obj.id,
+                        "failed to fetch exported object data",
+                    );
+                    io::Error::other(error)
+                })?;
+
+                // The header was already sent with the expected size, so
+                // shorter data can only abort the archive
+                let written = Arc::new(AtomicU64::new(0));
+                let counter = written.clone();
+                let data = ReaderStream::new(reader.take(size)).inspect_ok(
+                    move |chunk| {
+                        counter
+                            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                    },
+                );
+                let tail = stream::once(async move {
+                    if written.load(Ordering::Relaxed) != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("data of object `{}` is truncated", obj.id),
+                        ));
+                    }
+                    Ok(Bytes::from(vec![0; padding(size)]))
+                });
+
+                Ok::<_, io::Error>(
+                    stream::once(ready(Ok(Bytes::copy_from_slice(&header))))
+                        .chain(data)
+                        .chain(tail),
+                )
+            }
+        })
+        .try_flatten();
+
+    Ok(stream::once(ready(Ok(Bytes::from(head))))
+        .chain(objects)
+        .chain(stream::once(ready(Ok(Bytes::from_static(
+            &[0; BLOCK_SIZE * 2],
+        ))))))
+}
+
+/// Creates the objects of an archive produced by [`export_archive`],
+/// keeping their ids. Objects whose id is taken are skipped.
+///
+/// Owners are matched by id, then by username, falling back to
+/// `default_owner`. Objects imported before an error are kept.
+pub async fn import_archive<M: Manager>(
+    mut reader: impl AsyncRead + Send + Unpin,
+    repo: &ObjectRepository<Sqlite>,
+    users: &UserRepository<Sqlite>,
+    provenances: Option<&ProvenanceRepository<Sqlite>>,
+    manager: &M,
+    locks: &WriteLocks,
+    default_owner: Option<Uuid>,
+) -> Result<ImportReport, DownloaderError> {
+    let (path, size) = read_header(&mut reader)
+        .await?
+        .ok_or_else(|| invalid("empty archive"))?;
+    if path != MANIFEST_ENTRY {
+        return Err(
+            invalid(format!("expected `{MANIFEST_ENTRY}` first")).into()
+        );
+    }
+    if size > MAX_MANIFEST_SIZE {
+        return Err(invalid("manifest too large").into());
+    }
+
+    let mut buf = vec![0; size as usize];
+    read_exact(&mut reader, &mut buf).await?;
+    skip_padding(&mut reader, size).await?;
+    let manifest: ExportManifest = serde_json::from_slice(&buf)
+        .map_err(|e| invalid(format!("invalid manifest: {e}")))?;
+
+    let mut owners = HashMap::new();
+    let mut pending = HashMap::new();
+    let mut provenance_of = HashMap::new();
+    let mut report = ImportReport::default();
+
+    for exported in manifest.objects {
+        let mut obj = exported.object;
+
+        if repo.exists(obj.id).await? || pending.contains_key(&obj.id) {
+            report.skipped.push(obj.id);
+            continue;
+        }
+
+        let owner = match owners.entry(obj.user_id) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let owner = find_owner(
+                    users,
+                    obj.user_id,
+                    exported.owner.as_deref(),
+                    default_owner,
+                )
+                .await?;
+                *entry.insert(owner)
+            }
+        };
+        obj.user_id = owner;
+        if let Some(p) = exported.provenance {
+            provenance_of.insert(obj.id, p);
+        }
+        pending.insert(obj.id, obj);
+    }
+
+    while let Some((path, size)) = read_header(&mut reader).await? {
+        let obj = path
+            .strip_prefix(OBJECTS_DIR)
+            .and_then(|id| id.parse::<Uuid>().ok())
+            .and_then(|id| pending.remove(&id));
+
+        let Some(obj) = obj else {
+            skip_entry(&mut reader, size).await?;
+            continue;
+        };
+
+        if size != obj.data.size {
+            return Err(invalid(format!(
+                "size of object `{}` does not match the manifest",
+                obj.id,
+            ))
+            .into());
+        }
+
+        let _guard = locks.acquire(obj.id).await?;
+        // Storing must not overwrite the data of an object created since
+        if repo.exists(obj.id).await? {
+            skip_entry(&mut reader, size).await?;
+            report.skipped.push(obj.id);
+            continue;
+        }
+
+        let stream = ReaderStream::new((&mut reader).take(size));
+        let (stored, checksum_256) =
+            manager.store(obj.id, stream, None, Some(size)).await?;
+
+        if stored != size || checksum_256 != obj.data.checksum_256 {
+            let _ = manager.delete(obj.id).await;
+            return Err(AdminError::ChecksumMismatch(obj.id).into());
+        }
+        if let Err(error) = repo.import(&obj).await {
+            let _ = manager.delete(obj.id).await;
+            return Err(error.into());
+        }
+        skip_padding(&mut reader, size).await?;
+
+        if let (Some(provenances), Some(p)) =
+            (provenances, provenance_of.remove(&obj.id))
+        {
+            // The data is already stored, see `Uploader::record`
+            if let Err(error) = provenances.set(obj.id, &p).await {
+                tracing::error!(
+                    %error,
+                    id = %obj.id,
+                    "failed to import object provenance",
+                );
+            }
+        }
+
+        tracing::info!(id = %obj.id, "imported object");
+        report.imported.push(obj.id);
+    }
+
+    report.missing = pending.into_keys().collect();
+    report.missing.sort();
+    Ok(report)
+}
+
+async fn find_owner(
+    users: &UserRepository<Sqlite>,
+    user_id: Uuid,
+    username: Option<&str>,
+    default_owner: Option<Uuid>,
+) -> Result<Uuid, DownloaderError> {
+    match users.get(user_id).await {
+        Ok(user) => return Ok(user.id),
+        Err(UserError::NotFound) => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    if let Some(username) = username {
+        match users.get_by_username(username).await {
+            Ok(user) => return Ok(user.id),
+            Err(UserError::NotFound) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    match default_owner {
+        Some(owner) => match users.get(owner).await {
+            Ok(user) => Ok(user.id),
+            Err(UserError::NotFound) => {
+                Err(AdminError::OwnerNotFound(user_id).into())
+            }
+            Err(error) => Err(error.into()),
+        },
+        None => Err(AdminError::OwnerNotFound(user_id).into()),
+    }
+}
+
+fn entry_header(
+    path: &str,
+    size: u64,
+    mtime: DateTime<Utc>,
+) -> io::Result<[u8; BLOCK_SIZE]> {
+    let mut header = tar::Header::new_gnu();
+    header.set_path(path)?;
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime.timestamp().max(0) as u64);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    Ok(*header.as_bytes())
+}
+
+/// Reads the next entry header, returning its path and size, or `None` at
+/// the end of the archive.
+async fn read_header(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<(String, u64)>, AdminError> {
+    let mut block = [0; BLOCK_SIZE];
+    read_exact(reader, &mut block).await?;
+
+    if block.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+
+    let header = tar::Header::from_byte_slice(&block);
+    let cksum = header.cksum().map_err(|e| invalid(e.to_string()))?;
+    let sum = block[..148]
+        .iter()
+        .chain(&[b' '; 8])
+        .chain(&block[156..])
+        .fold(0u32, |sum, &b| sum + b as u32);
+    if cksum != sum {
+        return Err(invalid("corrupted entry header"));
+    }
+    if !header.entry_type().is_file() {
+        return Err(invalid("unsupported entry type"));
+    }
+
+    let path = header.path().map_err(|e| invalid(e.to_string()))?;
+    let path = path
+        .to_str()
+        .ok_or_else(|| invalid("entry path is not utf-8"))?
+        .to_owned();
+    let size = header.entry_size().map_err(|e| invalid(e.to_string()))?;
+
+    Ok(Some((path, size)))
+}
+
+async fn skip_entry(
+    reader: &mut (impl AsyncRead + Unpin),
+    size: u64,
+) -> Result<(), AdminError> {
+    let read = tokio::io::copy(&mut reader.take(size), &mut tokio::io::sink())
+        .await
+        .map_err(|e| invalid(e.to_string()))?;
+    if read != size {
+        return Err(invalid("unexpected end of archive"));
+    }
+    skip_padding(reader, size).await
+}
+
+async fn skip_padding(
+    reader: &mut (impl AsyncRead + Unpin),
+    size: u64,
+) -> Result<(), AdminError> {
+    let mut buf = [0; BLOCK_SIZE];
+    read_exact(reader, &mut buf[..padding(size)]).await
+}
+
+async fn read_exact(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> Result<(), AdminError> {
+    reader.read_exact(buf).await.map(|_| ()).map_err(|error| {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            invalid("unexpected end of archive")
+        } else {
+            invalid(error.to_string())
+        }
+    })
+}
+
+#[inline]
+fn padding(size: u64) -> usize {
+    (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE
+}
+
+fn invalid(msg: impl Into<String>) -> AdminError {
+    AdminError::InvalidArchive(msg.into())
+}
@@ -0,0 +1,182 @@
+//! The router of the server with the `Extension`s of its routes, built by
+//! the binary and by the tests alike.
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{middleware, routing, Extension, Router};
+use sqlx::Sqlite;
+
+use crate::{
+    admin::{routes::admin_routes, stats::StatsCache},
+    auth::{
+        keys::ApiKeyRepository, lockout::LoginLimiter, oidc::OidcClient,
+        presign::PresignRepository, repository::TokenRepository,
+        routes::auth_routes, totp::TotpRepository,
+    },
+    branding::{html_errors, Branding},
+    client_log::{
+        limiter::ReportLimiter, repository::ClientLogRepository,
+        routes::client_log_routes,
+    },
+    errors::get_error_catalog,
+    group::{repository::GroupRepository, routes::group_routes},
+    job::{queue::JobQueue, routes::job_routes},
+    maintenance::Maintenance,
+    namespace::{
+        repository::NamespaceRepository, route_namespaces,
+        routes::namespace_routes, scope_namespace,
+    },
+    proxy::TrustedProxies,
+    server::layer_root_router,
+    storage::{
+        embargo::EmbargoRepository,
+        fetch::RemoteFetcher,
+        fetch_job::FetchJobs,
+        fetch_quota::FetchQuota,
+        history::HistoryRepository,
+        manager::Manager,
+        meta::MetaRepository,
+        pipeline::{Pipeline, StepRepository},
+        progress::Transfers,
+        provenance::ProvenanceRepository,
+        repository::ObjectRepository,
+        routes::file_routes,
+        scan::Scanner,
+        share::share_routes,
+        slots::UploadSlots,
+        slug::ObjectIds,
+        stats::DownloadStats,
+        sweep::SweepCounters,
+        ws::ws_routes,
+        UndeleteWindow, UploadLimits, WriteLocks,
+    },
+    timeout::{timeout_requests, RequestTimeouts},
+    user::{repository::UserRepository, routes::user_routes, DeletePolicy},
+};
+
+/// What the routes of [`router`] are given, the optional features being
+/// disabled when `None`.
+pub struct AppState<M> {
+    pub obj_repo: ObjectRepository<Sqlite>,
+    pub object_ids: ObjectIds,
+    pub provenance_repo: ProvenanceRepository<Sqlite>,
+    pub embargo_repo: EmbargoRepository<Sqlite>,
+    pub step_repo: StepRepository<Sqlite>,
+    pub meta_repo: MetaRepository<Sqlite>,
+    pub history_repo: HistoryRepository<Sqlite>,
+    pub download_stats: DownloadStats,
+    pub manager: Arc<M>,
+    pub jobs: Arc<JobQueue>,
+    pub fetch_jobs: FetchJobs,
+    pub maintenance: Maintenance,
+    pub sweeps: Arc<SweepCounters>,
+    pub undelete_window: UndeleteWindow,
+    pub on_user_delete: DeletePolicy,
+    pub upload_limits: UploadLimits,
+    pub upload_slots: Arc<UploadSlots>,
+    pub write_locks: Arc<WriteLocks>,
+    pub stats_cache: Arc<StatsCache>,
+    pub user_repo: UserRepository<Sqlite>,
+    pub group_repo: GroupRepository<Sqlite>,
+    pub key_repo: ApiKeyRepository<Sqlite>,
+    pub client_log_repo: ClientLogRepository<Sqlite>,
+    pub report_limiter: Arc<ReportLimiter>,
+    pub presign_repo: PresignRepository<Sqlite>,
+    pub totp_repo: TotpRepository<Sqlite>,
+    pub login_limiter: Arc<LoginLimiter>,
+    pub token_repo: Arc<TokenRepository>,
+    pub namespace_repo: NamespaceRepository<Sqlite>,
+    pub trusted_proxies: TrustedProxies,
+    pub timeouts: RequestTimeouts,
+    /// Served instead of the embedded frontend.
+    pub frontend_dir: Option<PathBuf>,
+    pub oidc: Option<Arc<OidcClient>>,
+    pub branding: Option<Arc<Branding>>,
+    pub fetcher: Option<(Arc<RemoteFetcher>, FetchQuota)>,
+    pub scanner: Option<Scanner>,
+    pub pipeline: Option<Pipeline>,
+}
+
+/// Builds the whole router of the server, the namespace prefixes
+/// included.
+pub fn router<M: Manager>(state: AppState<M>) -> Router {
+    let namespaced = Router::new()
+        .nest("/api/file", file_routes::<_, M>(Router::new()))
+        .nest("/api/auth", auth_routes(Router::new()))
+        .nest("/api/user", user_routes::<_, M>(Router::new()))
+        .nest("/api/ws", ws_routes::<_, M>(Router::new()))
+        .nest("/api/jobs", job_routes(Router::new()))
+        .nest("/api/client-logs", client_log_routes(Router::new()))
+        .nest("/api/namespaces", namespace_routes(Router::new()))
+        .nest("/api/groups", group_routes(Router::new()))
+        .layer(middleware::from_fn(scope_namespace));
+
+    let mut app = layer_root_router(
+        namespaced
+            .nest("/s", share_routes(Router::new()))
+            .nest("/api/admin", admin_routes::<_, M>(Router::new()))
+            .route("/api/errors", routing::get(get_error_catalog))
+            .layer(middleware::from_fn_with_state(
+                state.timeouts,
+                timeout_requests,
+            )),
+        state.frontend_dir.as_deref(),
+    )
+    .layer(Extension(state.obj_repo))
+    .layer(Extension(state.object_ids))
+    .layer(Extension(state.provenance_repo))
+    .layer(Extension(state.embargo_repo))
+    .layer(Extension(state.step_repo))
+    .layer(Extension(state.meta_repo))
+    .layer(Extension(state.history_repo))
+    .layer(Extension(state.download_stats))
+    .layer(Extension(state.manager))
+    .layer(Extension(state.jobs))
+    .layer(Extension(state.fetch_jobs))
+    .layer(Extension(state.maintenance))
+    .layer(Extension(state.sweeps))
+    .layer(Extension(state.undelete_window))
+    .layer(Extension(state.on_user_delete))
+    .layer(Extension(state.upload_limits))
+    .layer(Extension(state.upload_slots))
+    .layer(Extension(state.write_locks))
+    .layer(Extension(Arc::new(Transfers::new())))
+    .layer(Extension(state.stats_cache))
+    .layer(Extension(state.user_repo))
+    .layer(Extension(state.group_repo))
+    .layer(Extension(state.key_repo))
+    .layer(Extension(state.client_log_repo))
+    .layer(Extension(state.report_limiter))
+    .layer(Extension(state.presign_repo))
+    .layer(Extension(state.totp_repo))
+    .layer(Extension(state.login_limiter))
+    .layer(Extension(state.token_repo))
+    .layer(Extension(state.namespace_repo.clone()))
+    .layer(Extension(state.trusted_proxies));
+
+    if let Some(oidc) = state.oidc {
+        app = app.layer(Extension(oidc));
+    }
+    if let Some(branding) = state.branding {
+        app = app
+            .layer(middleware::from_fn(html_errors))
+            .layer(Extension(branding));
+    }
+    if let Some((fetcher, quota)) = state.fetcher {
+        app = app.layer(Extension(fetcher)).layer(Extension(quota));
+    }
+    if let Some(scanner) = state.scanner {
+        app = app.layer(Extension(scanner));
+    }
+    if let Some(pipeline) = state.pipeline {
+        app = app.layer(Extension(pipeline));
+    }
+
+    // The namespace prefix must be rewritten before the routing
+    Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn_with_state(
+            state.namespace_repo,
+            route_namespaces,
+        ))
+}
@@ -6,10 +6,14 @@ use axum::{
     http::{header, request::Parts, StatusCode},
 };
 use serde::Deserialize;
+use sqlx::Sqlite;
+use tracing::field::display;
 
-use crate::{auth::AuthError, errors::DownloaderError};
+use crate::{
+    auth::AuthError, errors::DownloaderError, namespace::RequestNamespace,
+};
 
-use super::{repository::TokenRepository, Token};
+use super::{keys::ApiKeyRepository, repository::TokenRepository, Token};
 
 #[derive(Deserialize)]
 struct AuthorizationQuery {
@@ -26,62 +30,103 @@ impl<S: Send + Sync> FromRequestParts<S> for Authorization {
         parts: &mut Parts,
         _state: &S,
     ) -> Result<Self, Self::Rejection> {
-        let auth_header = parts.headers.get(header::AUTHORIZATION);
+        let token = authenticate(parts).await?;
 
-        let (strategy, token) = if let Some(auth_header) = auth_header {
-            let s = auth_header
-                .to_str()
-                .map_err(|_| AuthError::InvalidAuthHeader)?
-                .split(' ')
-                .collect::<Vec<_>>();
+        if let Token::User(user_token) = &token {
+            let RequestNamespace(namespace) = RequestNamespace::of(parts);
+            if user_token.namespace != namespace {
+                return Err(AuthError::NamespaceMismatch(
+                    user_token.namespace.clone(),
+                )
+                .into());
+            }
+        }
 
-            if s.len() != 2 {
-                return Err(AuthError::InvalidAuthHeader.into());
+        // Traced along with the request, see `server::CustomMakeSpan`
+        let span = tracing::Span::current();
+        match &token {
+            Token::User(user_token) => {
+                span.record("user_id", display(user_token.user_id));
             }
+            Token::File(file_token) => {
+                span.record("object_id", display(file_token.file_id));
+            }
+            Token::Server => {}
+        }
 
-            (s[0], s[1].to_owned())
-        } else {
-            let token = Query::<AuthorizationQuery>::try_from_uri(&parts.uri)
-                .map_err(|_| AuthError::AuthorizationRequired)?
-                .0
-                .token;
+        Ok(Authorization(token))
+    }
+}
 
-            ("Bearer", token)
-        };
+async fn authenticate(parts: &Parts) -> Result<Token, DownloaderError> {
+    let auth_header = parts.headers.get(header::AUTHORIZATION);
 
-        let repo = parts.extensions.get::<Arc<TokenRepository>>().ok_or_else(
-            || {
-                DownloaderError::Other(
-                    format!(
-                        "Extension of type `{}` was not found. \
-                        Perhaps you forgot to add it? See `axum::Extension`.",
-                        std::any::type_name::<Arc<TokenRepository>>()
-                    ),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )
-            },
-        )?;
-
-        match strategy {
-            "Bearer" => repo.decode_token(&token),
-            "Secret" => repo.verify_srv_key(&token).and_then(|ok| {
-                if ok {
-                    Ok(Token::Server)
-                } else {
-                    Err(AuthError::InvalidToken)
-                }
-            }),
-            s => {
-                return Err(AuthError::InvalidAuthStrategy(
-                    s.to_owned(),
-                    &["Bearer", "Secret"],
-                )
-                .into())
+    let (strategy, token) = if let Some(auth_header) = auth_header {
+        let s = auth_header
+            .to_str()
+            .map_err(|_| AuthError::InvalidAuthHeader)?
+            .split(' ')
+            .collect::<Vec<_>>();
+
+        if s.len() != 2 {
+            return Err(AuthError::InvalidAuthHeader.into());
+        }
+
+        (s[0], s[1].to_owned())
+    } else {
+        let token = Query::<AuthorizationQuery>::try_from_uri(&parts.uri)
+            .map_err(|_| AuthError::AuthorizationRequired)?
+            .0
+            .token;
+
+        ("Bearer", token)
+    };
+
+    if strategy == "ApiKey" {
+        let repo = get_extension::<ApiKeyRepository<Sqlite>>(parts)?;
+
+        return repo
+            .authenticate(&token)
+            .await
+            .map(Token::User)
+            .map_err(DownloaderError::Auth);
+    }
+
+    let repo = get_extension::<Arc<TokenRepository>>(parts)?;
+
+    match strategy {
+        "Bearer" => repo.decode_token(&token),
+        "Secret" => repo.verify_srv_key(&token).and_then(|ok| {
+            if ok {
+                Ok(Token::Server)
+            } else {
+                Err(AuthError::InvalidToken)
             }
+        }),
+        s => {
+            return Err(AuthError::InvalidAuthStrategy(
+                s.to_owned(),
+                &["Bearer", "Secret", "ApiKey"],
+            )
+            .into())
         }
-        .map(Authorization)
-        .map_err(DownloaderError::Auth)
     }
+    .map_err(DownloaderError::Auth)
+}
+
+fn get_extension<T: Clone + Send + Sync + 'static>(
+    parts: &Parts,
+) -> Result<T, DownloaderError> {
+    parts.extensions.get::<T>().cloned().ok_or_else(|| {
+        DownloaderError::Other(
+            format!(
+                "Extension of type `{}` was not found. \
+                Perhaps you forgot to add it? See `axum::Extension`.",
+                std::any::type_name::<T>()
+            ),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })
 }
 
 #[cfg(test)]
@@ -92,11 +137,18 @@ mod tests {
         extract::FromRequestParts,
         http::{header, request::Builder, Request},
     };
+    use sqlx::{migrate, SqlitePool};
     use test_log::test;
     use uuid::Uuid;
 
-    use crate::auth::{
-        axum::Authorization, repository::tests::repository, Permission, Token,
+    use crate::{
+        auth::{
+            axum::Authorization, keys::ApiKeyRepository,
+            repository::tests::repository, Permission, Token,
+        },
+        user::{
+            password::PasswordHasher, repository::UserRepository, UserData,
+        },
     };
 
     async fn test_requests_insertions<F: FnOnce(Builder, String) -> Builder>(
@@ -173,4 +225,48 @@ mod tests {
             _ => panic!("expected server token, but got {token:?}"),
         }
     }
+
+    #[test(tokio::test)]
+    async fn test_header_api_key() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let user = UserRepository::new(db.clone(), PasswordHasher::bcrypt(4))
+            .create(
+                Permission::ADMIN,
+                UserData {
+                    username: Uuid::new_v4().to_string(),
+                    password: Uuid::new_v4().to_string(),
+                },
+            )
+            .await
+            .unwrap();
+
+        let key_repo = ApiKeyRepository::new(db);
+        let (_, key) = key_repo
+            .create(user.id, "test", Permission::SHARE)
+            .await
+            .unwrap();
+
+        let mut parts = Request::builder()
+            .extension(key_repo)
+            .header(header::AUTHORIZATION, format!("ApiKey {key}"))
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+
+        let token = Authorization::from_request_parts(&mut parts, &())
+            .await
+            .expect("Failed to extract api key")
+            .0;
+
+        match token {
+            Token::User(token) => {
+                assert_eq!(token.user_id, user.id);
+                assert_eq!(token.permission, Permission::SHARE);
+            }
+            _ => panic!("expected user token, but got {token:?}"),
+        }
+    }
 }
@@ -0,0 +1,62 @@
+use std::{sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use schnellru::{ByLength, LruMap};
+
+use super::Token;
+
+struct CachedToken {
+    token: Token,
+    expires_at: DateTime<Utc>,
+}
+
+/// A bounded LRU cache of decoded tokens, keyed by the encoded token.
+///
+/// Entries are kept until the token expires or for at most `ttl`. Nothing
+/// is dropped early: issued tokens can not be revoked, changing the
+/// password or the permission of a user or deleting it only affects the
+/// tokens issued afterwards, cached or not. API keys are checked against the
+/// database on every request and never cached, so revoking one takes effect
+/// right away.
+pub struct TokenCache {
+    map: Mutex<LruMap<String, CachedToken>>,
+    ttl: Duration,
+}
+
+impl TokenCache {
+    pub fn new(capacity: u32, ttl: Duration) -> Self {
+        Self {
+            map: Mutex::new(LruMap::new(ByLength::new(capacity))),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, encoded: &str) -> Option<Token> {
+        let mut map = self.map.lock().unwrap();
+
+        let entry = map.get(encoded)?;
+        if entry.expires_at <= Utc::now() {
+            map.remove(encoded);
+            return None;
+        }
+
+        Some(entry.token.clone())
+    }
+
+    pub fn insert(&self, encoded: &str, token: &Token) {
+        let expiration = match token {
+            Token::User(t) => t.expiration,
+            Token::File(t) => t.expiration,
+            Token::Server => return,
+        };
+        let expires_at = (Utc::now() + self.ttl).min(expiration);
+
+        self.map.lock().unwrap().insert(
+            encoded.to_owned(),
+            CachedToken {
+                token: token.clone(),
+                expires_at,
+            },
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
+    Pool, Row, Type,
+};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::group::{union_permissions, MEMBER_PERMISSIONS_QUERY};
+
+use super::{AuthError, Permission, UserToken};
+
+const SECRET_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub name: String,
+    pub permission: Permission,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for ApiKey
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let id: Vec<u8> = row.try_get("id")?;
+        let id: [u8; 16] = id.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `id` uuid out of range".into())
+        })?;
+        let id = Uuid::from_bytes(id);
+
+        let user_id: Vec<u8> = row.try_get("user_id")?;
+        let user_id: [u8; 16] = user_id.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `user_id` uuid out of range".into())
+        })?;
+        let user_id = Uuid::from_bytes(user_id);
+
+        let created_at: i64 = row.try_get("created_at")?;
+        let created_at = DateTime::from_timestamp_millis(created_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `created_at` field gone wrong".into(),
+                )
+            })?;
+
+        let name: String = row.try_get("name")?;
+
+        let permission = decode_permission(row.try_get("permission")?)?;
+
+        Ok(Self {
+            id,
+            user_id,
+            created_at,
+            name,
+            permission,
+        })
+    }
+}
+
+fn decode_permission(permission: i64) -> Result<Permission, sqlx::Error> {
+    let permission: u8 = permission.try_into().map_err(|_| {
+        sqlx::Error::Decode("parse `permission` u8 out of range".into())
+    })?;
+    Permission::from_bits(permission).ok_or_else(|| {
+        sqlx::Error::Decode("parse `permission` invalid bitflags".into())
+    })
+}
+
+/// An [`ApiKey`] joined with the information of the user that owns it.
+struct ApiKeyWithOwner {
+    key: ApiKey,
+    key_hash: Vec<u8>,
+    username: String,
+    namespace: String,
+    user_permission: Permission,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for ApiKeyWithOwner
+where
+    ApiKey: FromRow<'r, R>,
+
+    &'r str: ColumnIndex<R>,
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let key = ApiKey::from_row(row)?;
+        let key_hash = row.try_get("key_hash")?;
+        let username = row.try_get("username")?;
+        let namespace = row.try_get("namespace")?;
+        let user_permission =
+            decode_permission(row.try_get("user_permission")?)?;
+
+        Ok(Self {
+            key,
+            key_hash,
+            username,
+            namespace,
+            user_permission,
+        })
+    }
+}
+
+pub struct ApiKeyRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for ApiKeyRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> ApiKeyRepository<DB> {
+    pub fn new(db: Pool<DB>) -> ApiKeyRepository<DB> {
+        ApiKeyRepository { db }
+    }
+}
+
+impl<DB> ApiKeyRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> ApiKey: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'r> &'r str: ColumnIndex<DB::Row>,
+    for<'r> Vec<u8>: Decode<'r, DB>,
+    Vec<u8>: Type<DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> String: Decode<'r, DB>,
+    String: Type<DB>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    /// Creates a new key, returning it along with the secret string used to
+    /// authenticate with it. The secret is not stored and can not be
+    /// retrieved again.
+    pub async fn create(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        permission: Permission,
+    ) -> Result<(ApiKey, String), AuthError> {
+        let id = Uuid::new_v4();
+        let now_ms = Utc::now().timestamp_millis();
+
+        let mut secret = [0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let key_hash = Sha256::digest(secret);
+
+        let key = sqlx::query_as(
+            "INSERT INTO api_key \
+            (id, user_id, created_at, name, permission, key_hash) \
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(user_id.into_bytes().as_slice())
+        .bind(now_ms)
+        .bind(name)
+        .bind(permission.bits() as i64)
+        .bind(key_hash.as_slice())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while creating api key");
+            AuthError::Sqlx(error)
+        })?;
+
+        let secret = format!(
+            "{}.{}",
+            id.simple(),
+            BASE64_URL_SAFE_NO_PAD.encode(secret),
+        );
+
+        Ok((key, secret))
+    }
+
+    pub async fn get_by_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<ApiKey>, AuthError> {
+        sqlx::query_as(
+            "SELECT * FROM api_key WHERE user_id = $1 ORDER BY created_at",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving user api keys",
+            );
+            AuthError::Sqlx(error)
+        })
+    }
+
+    /// Verifies the secret string of a key, returning a token that acts on
+    /// behalf of its owner. The permission of the token never exceeds the
+    /// current permission of the owner, including the one of its groups.
+    pub async fn authenticate(
+        &self,
+        secret: &str,
+    ) -> Result<UserToken, AuthError> {
+        let (id, secret) =
+            secret.split_once('.').ok_or(AuthError::InvalidToken)?;
+        let id = Uuid::try_parse(id).map_err(|_| AuthError::InvalidToken)?;
+        let secret = BASE64_URL_SAFE_NO_PAD
+            .decode(secret)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let key: ApiKeyWithOwner = sqlx::query_as(
+            "SELECT api_key.*, user.username, user.namespace, \
+            user.permission AS user_permission \
+            FROM api_key JOIN user ON user.id = api_key.user_id \
+            WHERE api_key.id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while fetching api key");
+            AuthError::Sqlx(error)
+        })?
+        .ok_or(AuthError::InvalidToken)?;
+
+        // Compared in constant time, so the time taken says nothing about
+        // how much of the hash matched
+        let hash = Sha256::digest(secret);
+        if !bool::from(hash.as_slice().ct_eq(&key.key_hash)) {
+            return Err(AuthError::InvalidToken);
+        }
+
+        let group_permissions = sqlx::query_as(MEMBER_PERMISSIONS_QUERY)
+            .bind(key.key.user_id.into_bytes().as_slice())
+            .fetch_all(&self.db)
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, "got sqlx error while fetching groups");
+                AuthError::Sqlx(error)
+            })?;
+        let user_permission =
+            key.user_permission | union_permissions(group_permissions);
+
+        Ok(UserToken {
+            user_id: key.key.user_id,
+            created_at: key.key.created_at,
+            expiration: DateTime::<Utc>::MAX_UTC,
+            issuer: format!("key/{}", key.key.id),
+            permission: key.key.permission.intersection(user_permission),
+            username: key.username,
+            namespace: key.namespace,
+        })
+    }
+
+    pub async fn delete(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<ApiKey, AuthError> {
+        sqlx::query_as(
+            "DELETE FROM api_key WHERE id = $1 AND user_id = $2 RETURNING *",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while deleting api key");
+            AuthError::Sqlx(error)
+        })?
+        .ok_or(AuthError::ApiKeyNotFound(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, Sqlite, SqlitePool};
+    use test_log::test;
+    use uuid::Uuid;
+
+    use crate::{
+        auth::{AuthError, Permission},
+        group::{repository::GroupRepository, GroupData},
+        user::{
+            password::PasswordHasher, repository::UserRepository, User,
+            UserData,
+        },
+    };
+
+    use super::ApiKeyRepository;
+
+    async fn repository() -> (ApiKeyRepository<Sqlite>, UserRepository<Sqlite>)
+    {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        (
+            ApiKeyRepository::new(db.clone()),
+            UserRepository::new(db, PasswordHasher::bcrypt(4)),
+        )
+    }
+
+    async fn create_user(
+        repo: &UserRepository<Sqlite>,
+        permission: Permission,
+    ) -> User {
+        let data = UserData {
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        };
+        repo.create(permission, data).await.unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_authenticate() {
+        let (repo, user_repo) = repository().await;
+        let user = create_user(&user_repo, Permission::ADMIN).await;
+
+        let (key, secret) = repo
+            .create(user.id, "ci", Permission::UNPRIVILEGED)
+            .await
+            .unwrap();
+
+        let token = repo
+            .authenticate(&secret)
+            .await
+            .expect("failed to authenticate with created key");
+
+        assert_eq!(token.user_id, user.id);
+        assert_eq!(token.username, user.username);
+        assert_eq!(token.permission, Permission::UNPRIVILEGED);
+        assert_eq!(token.issuer, format!("key/{}", key.id));
+
+        let (id, _) = secret.split_once('.').unwrap();
+        let forged = format!("{id}.{}", "A".repeat(43));
+
+        let res = repo.authenticate(&forged).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidToken)),
+            "expected invalid token error while using a forged secret",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_authenticate_owner_permission() {
+        let (repo, user_repo) = repository().await;
+        let user = create_user(&user_repo, Permission::ADMIN).await;
+
+        let (_, secret) =
+            repo.create(user.id, "ci", Permission::ADMIN).await.unwrap();
+
+        user_repo
+            .update_permission(user.id, Permission::SHARE, None)
+            .await
+            .unwrap();
+
+        let token = repo.authenticate(&secret).await.unwrap();
+        assert_eq!(
+            token.permission,
+            Permission::SHARE,
+            "key permission must not exceed the owner one",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_authenticate_group_permission() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ApiKeyRepository::new(db.clone());
+        let groups = GroupRepository::new(db.clone());
+        let user_repo = UserRepository::new(db, PasswordHasher::bcrypt(4));
+
+        let user = create_user(&user_repo, Permission::SHARE).await;
+        let (_, secret) =
+            repo.create(user.id, "ci", Permission::ADMIN).await.unwrap();
+
+        let group = groups
+            .create(&GroupData {
+                name: "readers".into(),
+                permission: Permission::READ_ALL,
+            })
+            .await
+            .unwrap();
+        groups.add_member(group.id, user.id).await.unwrap();
+
+        let token = repo.authenticate(&secret).await.unwrap();
+        assert_eq!(token.permission, Permission::SHARE | Permission::READ_ALL);
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_by_user_and_delete() {
+        let (repo, user_repo) = repository().await;
+        let user = create_user(&user_repo, Permission::ADMIN).await;
+        let other = create_user(&user_repo, Permission::ADMIN).await;
+
+        let (key1, secret) = repo
+            .create(user.id, "one", Permission::SHARE)
+            .await
+            .unwrap();
+        let (key2, _) = repo
+            .create(user.id, "two", Permission::SHARE)
+            .await
+            .unwrap();
+        repo.create(other.id, "three", Permission::SHARE)
+            .await
+            .unwrap();
+
+        let keys = repo.get_by_user(user.id).await.unwrap();
+        assert_eq!(keys, vec![key1.clone(), key2]);
+
+        let res = repo.delete(key1.id, other.id).await;
+        assert!(
+            matches!(res, Err(AuthError::ApiKeyNotFound(..))),
+            "keys must only be deleted by their owners",
+        );
+
+        let deleted = repo.delete(key1.id, user.id).await.unwrap();
+        assert_eq!(deleted, key1);
+
+        let res = repo.authenticate(&secret).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidToken)),
+            "expected invalid token error while using a revoked key",
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use schnellru::{ByLength, LruMap};
+
+use super::AuthError;
+
+/// Usernames and addresses tracked at most, the ones that failed least
+/// recently being forgotten first, so a spray of distinct usernames or
+/// addresses can not grow the map without bound.
+const MAX_ENTRIES: u32 = 16384;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LoginKey {
+    Username(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed login attempts per username and per client address,
+/// locking them out with an exponential backoff once too many attempts
+/// fail in a row.
+pub struct LoginLimiter {
+    max_failures: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+    failures: Mutex<LruMap<LoginKey, Failures>>,
+}
+
+impl LoginLimiter {
+    pub fn new(
+        max_failures: u32,
+        lockout: Duration,
+        max_lockout: Duration,
+    ) -> Self {
+        Self {
+            max_failures,
+            lockout,
+            max_lockout,
+            failures: Mutex::new(LruMap::new(ByLength::new(MAX_ENTRIES))),
+        }
+    }
+
+    /// Fails with [`AuthError::LoginLocked`] if the username or the
+    /// address are locked out.
+    pub fn check(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), AuthError> {
+        self.check_at(&keys(username, ip), Instant::now())
+    }
+
+    pub fn record_failure(&self, username: &str, ip: Option<IpAddr>) {
+        self.record_failure_at(&keys(username, ip), Instant::now())
+    }
+
+    pub fn record_success(&self, username: &str, ip: Option<IpAddr>) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys(username, ip) {
+            failures.remove(&key);
+        }
+    }
+
+    fn check_at(
+        &self,
+        keys: &[LoginKey],
+        now: Instant,
+    ) -> Result<(), AuthError> {
+        let failures = self.failures.lock().unwrap();
+
+        let retry_after = keys
+            .iter()
+            .filter_map(|key| failures.peek(key)?.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now);
+
+        match retry_after {
+            Some(retry_after) => Err(AuthError::LoginLocked(retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    fn record_failure_at(&self, keys: &[LoginKey], now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+
+        for key in keys {
+            let Some(entry) =
+                failures.get_or_insert(key.clone(), || Failures {
+                    count: 0,
+                    last_failure: now,
+                    locked_until: None,
+                })
+            else {
+                continue;
+            };
+
+            // Failures are forgotten once enough time passes without new ones
+            if now.saturating_duration_since(entry.last_failure)
+                >= self.max_lockout
+            {
+                entry.count = 0;
+            }
+
+            entry.count += 1;
+            entry.last_failure = now;
+
+            if entry.count >= self.max_failures {
+                let exp = (entry.count - self.max_failures).min(16);
+                let lockout =
+                    self.lockout.saturating_mul(1 << exp).min(self.max_lockout);
+                entry.locked_until = Some(now + lockout);
+            }
+        }
+    }
+}
+
+fn keys(username: &str, ip: Option<IpAddr>) -> Vec<LoginKey> {
+    let mut keys = vec![LoginKey::Username(username.to_owned())];
+    if let Some(ip) = ip {
+        keys.push(LoginKey::Ip(ip));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use crate::auth::AuthError;
+
+    use super::{keys, LoginLimiter, MAX_ENTRIES};
+
+    const LOCKOUT: Duration = Duration::from_secs(10);
+    const MAX_LOCKOUT: Duration = Duration::from_secs(60);
+
+    fn limiter() -> LoginLimiter {
+        LoginLimiter::new(3, LOCKOUT, MAX_LOCKOUT)
+    }
+
+    fn retry_after(res: Result<(), AuthError>) -> Duration {
+        match res {
+            Err(AuthError::LoginLocked(retry_after)) => retry_after,
+            res => panic!("expected login to be locked, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_lockout() {
+        let limiter = limiter();
+        let keys = keys("user", None);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            limiter.record_failure_at(&keys, now);
+            limiter.check_at(&keys, now).unwrap();
+        }
+
+        limiter.record_failure_at(&keys, now);
+        assert_eq!(retry_after(limiter.check_at(&keys, now)), LOCKOUT);
+        limiter.check_at(&keys, now + LOCKOUT).unwrap();
+
+        // Backoff doubles with every failure, up to the maximum lockout
+        let mut expected = LOCKOUT;
+        for _ in 0..4 {
+            limiter.record_failure_at(&keys, now);
+            expected = (expected * 2).min(MAX_LOCKOUT);
+            assert_eq!(retry_after(limiter.check_at(&keys, now)), expected);
+        }
+        assert_eq!(expected, MAX_LOCKOUT);
+
+        limiter.record_success("user", None);
+        limiter.check_at(&keys, now).unwrap();
+    }
+
+    #[test]
+    fn test_lockout_ip() {
+        let limiter = limiter();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let now = Instant::now();
+
+        for i in 0..3 {
+            let keys = keys(&format!("user-{i}"), Some(ip));
+            limiter.record_failure_at(&keys, now);
+        }
+
+        let res = limiter.check_at(&keys("other", Some(ip)), now);
+        assert_eq!(retry_after(res), LOCKOUT, "address was not locked");
+        limiter.check_at(&keys("other", None), now).unwrap();
+    }
+
+    #[test]
+    fn test_failures_expire() {
+        let limiter = limiter();
+        let keys = keys("user", None);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            limiter.record_failure_at(&keys, now);
+        }
+        limiter.record_failure_at(&keys, now + MAX_LOCKOUT);
+        limiter.check_at(&keys, now + MAX_LOCKOUT).unwrap();
+    }
+
+    #[test]
+    fn test_failures_bounded() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for i in 0..MAX_ENTRIES + 1000 {
+            let ip = IpAddr::V4(Ipv4Addr::from(i));
+            limiter
+                .record_failure_at(&keys(&format!("user-{i}"), Some(ip)), now);
+            assert!(
+                limiter.failures.lock().unwrap().len() <= MAX_ENTRIES as usize
+            );
+        }
+
+        // The most recent failures are kept
+        let last = MAX_ENTRIES + 999;
+        let keys = keys(&format!("user-{last}"), None);
+        let failures = limiter.failures.lock().unwrap();
+        assert_eq!(failures.peek(&keys[0]).map(|f| f.count), Some(1));
+    }
+}
@@ -6,9 +6,17 @@ use chrono::{DateTime, Utc};
 use serde::{de::Unexpected, Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::namespace::{default_namespace, DEFAULT_NAMESPACE};
+
 pub mod axum;
+pub mod cache;
+pub mod keys;
+pub mod lockout;
+pub mod oidc;
+pub mod presign;
 pub mod repository;
 pub mod routes;
+pub mod totp;
 
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
@@ -37,6 +45,43 @@ pub enum AuthError {
     AccessDenied,
     #[error("you can not create a token with a permission higher than yours")]
     HigherPermissionRequired,
+
+    #[error("api key `{0}` not found")]
+    ApiKeyNotFound(Uuid),
+    #[error("sqlx error: {0}")]
+    Sqlx(sqlx::Error),
+
+    #[error("oidc login failed: {0}")]
+    Oidc(String),
+    #[error("the oidc login state is invalid or expired")]
+    InvalidOidcState,
+    #[error("oidc login is not enabled")]
+    OidcDisabled,
+
+    #[error("a two-factor authentication code is required")]
+    TotpRequired,
+    #[error("the provided two-factor authentication code is invalid")]
+    InvalidTotpCode,
+    #[error("two-factor authentication is already enabled")]
+    TotpAlreadyEnabled,
+    #[error("two-factor authentication is not enrolled")]
+    TotpNotEnrolled,
+
+    #[error("too many failed login attempts, try again in {}s", .0.as_secs())]
+    LoginLocked(Duration),
+
+    #[error("the presigned url is invalid or expired")]
+    InvalidPresignedUrl,
+    #[error("the presigned url was already used")]
+    PresignedUrlUsed,
+    #[error("the upload does not satisfy the presigned policy: {0}")]
+    PolicyViolation(String),
+    #[error("invalid presign request: {0}")]
+    InvalidPresignRequest(&'static str),
+    #[error("the file is not available until {}", .0.to_rfc3339())]
+    Embargoed(DateTime<Utc>),
+    #[error("the token belongs to namespace `{0}`")]
+    NamespaceMismatch(String),
 }
 
 impl AuthError {
@@ -53,23 +98,62 @@ impl AuthError {
             | AuthError::InvalidAuthStrategy(..) => StatusCode::BAD_REQUEST,
             AuthError::AccessDenied => StatusCode::FORBIDDEN,
             AuthError::HigherPermissionRequired => StatusCode::FORBIDDEN,
+            AuthError::ApiKeyNotFound(..) => StatusCode::NOT_FOUND,
+            AuthError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::Oidc(..) => StatusCode::BAD_GATEWAY,
+            AuthError::InvalidOidcState => StatusCode::BAD_REQUEST,
+            AuthError::OidcDisabled => StatusCode::NOT_FOUND,
+            AuthError::TotpRequired | AuthError::InvalidTotpCode => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::TotpAlreadyEnabled => StatusCode::CONFLICT,
+            AuthError::TotpNotEnrolled => StatusCode::BAD_REQUEST,
+            AuthError::LoginLocked(..) => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::InvalidPresignedUrl => StatusCode::FORBIDDEN,
+            AuthError::PresignedUrlUsed => StatusCode::GONE,
+            AuthError::PolicyViolation(..) => StatusCode::FORBIDDEN,
+            AuthError::InvalidPresignRequest(..) => StatusCode::BAD_REQUEST,
+            AuthError::Embargoed(..) => StatusCode::FORBIDDEN,
+            AuthError::NamespaceMismatch(..) => StatusCode::FORBIDDEN,
         }
     }
+}
 
-    #[inline]
-    pub fn custom_code(&self) -> u8 {
-        match self {
-            AuthError::GenerateTokenFailed => 1,
-            AuthError::TokenExpirationTooLong { .. } => 2,
-            AuthError::InvalidToken => 3,
-            AuthError::ExpiredToken => 4,
-            AuthError::ImatureToken => 5,
-            AuthError::AuthorizationRequired => 6,
-            AuthError::InvalidAuthHeader => 7,
-            AuthError::InvalidAuthStrategy(..) => 8,
-            AuthError::AccessDenied => 9,
-            AuthError::HigherPermissionRequired => 10,
-        }
+crate::errors::error_codes! {
+    AuthError {
+        GenerateTokenFailed => 1, "failed to generate token";
+        TokenExpirationTooLong { .. } => 2,
+            "the requested token expiration is too long";
+        InvalidToken => 3, "the provided token is invalid";
+        ExpiredToken => 4, "the provided token is expired";
+        ImatureToken => 5, "the provided token must be used in the future";
+        AuthorizationRequired => 6,
+            "authorization is required but none was provided";
+        InvalidAuthHeader => 7, "the Authorization header is invalid";
+        InvalidAuthStrategy(..) => 8,
+            "the authorization strategy is not supported";
+        AccessDenied => 9, "access denied to the requested entity";
+        HigherPermissionRequired => 10,
+            "tokens can not have a higher permission than their creator";
+        ApiKeyNotFound(..) => 11, "api key not found";
+        Sqlx(..) => 12, "database error";
+        Oidc(..) => 13, "oidc login failed";
+        InvalidOidcState => 14, "the oidc login state is invalid or expired";
+        OidcDisabled => 15, "oidc login is not enabled";
+        TotpRequired => 16, "a two-factor authentication code is required";
+        InvalidTotpCode => 17, "the two-factor authentication code is invalid";
+        TotpAlreadyEnabled => 18,
+            "two-factor authentication is already enabled";
+        TotpNotEnrolled => 19, "two-factor authentication is not enrolled";
+        LoginLocked(..) => 20,
+            "too many failed login attempts, see the `retry-after` header";
+        InvalidPresignedUrl => 21, "the presigned url is invalid or expired";
+        PresignedUrlUsed => 22, "the presigned url was already used";
+        PolicyViolation(..) => 23,
+            "the upload does not satisfy the presigned policy";
+        InvalidPresignRequest(..) => 24, "the presign request is invalid";
+        Embargoed(..) => 25, "the file is not available yet";
+        NamespaceMismatch(..) => 26, "the token belongs to another namespace";
     }
 }
 
@@ -98,6 +182,9 @@ pub struct UserToken {
     #[serde(rename = "perm")]
     pub permission: Permission,
     pub username: String,
+    /// The namespace of the user, the only one the token is accepted in.
+    #[serde(rename = "ns", default = "default_namespace")]
+    pub namespace: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,6 +203,23 @@ pub struct FileToken {
     // Custom information
     #[serde(rename = "perm")]
     pub permission: Permission,
+    #[serde(rename = "scp", default = "default_file_scope")]
+    pub scope: FileScope,
+}
+
+impl FileToken {
+    /// Whether the token grants `scope` over the file `id`. The permission
+    /// bits must be checked separately.
+    #[inline]
+    pub fn allows(&self, id: Uuid, scope: FileScope) -> bool {
+        self.file_id == id && self.scope.contains(scope)
+    }
+}
+
+/// Tokens issued before scopes existed may only download the file.
+#[inline]
+fn default_file_scope() -> FileScope {
+    FileScope::DOWNLOAD
 }
 
 impl Token {
@@ -128,6 +232,20 @@ impl Token {
         }
     }
 
+    /// Whether the token may manage the whole server, which only the
+    /// administrators of the default namespace may.
+    #[inline]
+    pub fn is_server_admin(&self) -> bool {
+        match self {
+            Token::User(user_token)
+                if user_token.namespace != DEFAULT_NAMESPACE =>
+            {
+                false
+            }
+            _ => self.permission().contains(Permission::ADMIN),
+        }
+    }
+
     #[inline]
     pub fn can_share(&self) -> bool {
         self.permission().contains(Permission::SHARE)
@@ -190,6 +308,64 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The operations a file token may perform over its file, enforced on
+    /// top of the permission bits.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FileScope: u8 {
+        const DOWNLOAD = 1;
+        const UPDATE = 1 << 1;
+        const DELETE = 1 << 2;
+        const METADATA = 1 << 3;
+
+        const READ = Self::DOWNLOAD.bits();
+        const READ_WRITE = Self::DOWNLOAD.bits()
+        | Self::UPDATE.bits()
+        | Self::DELETE.bits()
+        | Self::METADATA.bits();
+    }
+}
+
+impl FileScope {
+    /// The scope granted when none is requested, matching what the
+    /// permission bits alone used to allow.
+    #[inline]
+    pub fn for_permission(permission: Permission) -> Self {
+        if permission.contains(Permission::WRITE_OWNED) {
+            Self::READ_WRITE
+        } else {
+            Self::READ
+        }
+    }
+}
+
+impl Serialize for FileScope {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.bits().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileScope {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bits = u8::deserialize(deserializer)?;
+
+        FileScope::from_bits(bits).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                Unexpected::Unsigned(bits.into()),
+                &"a valid set of file scope bits",
+            )
+        })
+    }
+}
+
 impl Serialize for Permission {
     #[inline]
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -216,3 +392,175 @@ impl<'de> Deserialize<'de> for Permission {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    use super::{FileScope, FileToken, Permission, Token, UserToken};
+
+    fn permission() -> impl Strategy<Value = Permission> {
+        any::<u8>().prop_map(Permission::from_bits_truncate)
+    }
+
+    fn scope() -> impl Strategy<Value = FileScope> {
+        any::<u8>().prop_map(FileScope::from_bits_truncate)
+    }
+
+    /// JWT timestamps only have second precision.
+    fn timestamp() -> impl Strategy<Value = DateTime<Utc>> {
+        (0i64..4_102_444_800)
+            .prop_map(|secs| DateTime::from_timestamp(secs, 0).unwrap())
+    }
+
+    fn uuid() -> impl Strategy<Value = Uuid> {
+        any::<u128>().prop_map(Uuid::from_u128)
+    }
+
+    /// Tokens that can be decoded from a JWT.
+    fn jwt_token() -> impl Strategy<Value = Token> {
+        prop_oneof![
+            (
+                uuid(),
+                timestamp(),
+                timestamp(),
+                ".*",
+                permission(),
+                ".*",
+                "[a-z0-9-]{1,32}"
+            )
+                .prop_map(
+                    |(user_id, iat, exp, iss, permission, username, ns)| {
+                        Token::User(UserToken {
+                            user_id,
+                            created_at: iat,
+                            expiration: exp,
+                            issuer: iss,
+                            permission,
+                            username,
+                            namespace: ns,
+                        })
+                    }
+                ),
+            (
+                uuid(),
+                timestamp(),
+                timestamp(),
+                ".*",
+                permission(),
+                scope()
+            )
+                .prop_map(
+                    |(file_id, iat, exp, iss, permission, scope)| {
+                        Token::File(FileToken {
+                            file_id,
+                            created_at: iat,
+                            expiration: exp,
+                            issuer: iss,
+                            permission,
+                            scope,
+                        })
+                    }
+                ),
+        ]
+    }
+
+    fn token() -> impl Strategy<Value = Token> {
+        prop_oneof![jwt_token(), Just(Token::Server)]
+    }
+
+    fn token_eq(a: &Token, b: &Token) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn test_permission_bits(bits in any::<u8>()) {
+            let res = serde_json::from_str::<Permission>(&bits.to_string());
+
+            match Permission::from_bits(bits) {
+                Some(perm) => {
+                    let perm2 = res.expect("valid bits must be accepted");
+                    prop_assert_eq!(perm, perm2);
+                    prop_assert_eq!(perm2.bits(), bits);
+                }
+                None => {
+                    prop_assert!(res.is_err(), "unknown bits must be rejected");
+                    prop_assert_ne!(bits & !Permission::all().bits(), 0);
+                }
+            }
+        }
+
+        #[test]
+        fn test_permission_roundtrip(perm in permission()) {
+            let json = serde_json::to_string(&perm).unwrap();
+            let perm2: Permission = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(perm, perm2);
+        }
+
+        #[test]
+        fn test_permission_invariants(perm in permission()) {
+            prop_assert!(Permission::ADMIN.contains(perm));
+            prop_assert!(perm.contains(Permission::SINGLE_FILE_R));
+
+            let token = Token::File(FileToken {
+                file_id: Uuid::nil(),
+                created_at: DateTime::UNIX_EPOCH,
+                expiration: DateTime::UNIX_EPOCH,
+                issuer: String::new(),
+                permission: perm,
+                scope: FileScope::READ,
+            });
+            prop_assert_eq!(
+                token.can_write_owned(),
+                perm.intersects(Permission::WRITE_OWNED | Permission::WRITE_ALL),
+            );
+        }
+
+        #[test]
+        fn test_file_scope_default(file_id in uuid(), perm in permission()) {
+            let json = serde_json::json!({
+                "type": "FILE",
+                "sub": file_id,
+                "iat": 0,
+                "exp": 0,
+                "iss": "",
+                "perm": perm,
+            });
+
+            let Token::File(token) = serde_json::from_value(json).unwrap() else {
+                panic!("decoded wrong token type");
+            };
+            prop_assert!(token.allows(file_id, FileScope::DOWNLOAD));
+            prop_assert!(!token.allows(file_id, FileScope::UPDATE));
+            prop_assert!(!token.allows(Uuid::nil(), FileScope::DOWNLOAD));
+        }
+
+        #[test]
+        fn test_token_roundtrip(token in token()) {
+            let json = serde_json::to_string(&token).unwrap();
+            let token2: Token = serde_json::from_str(&json).unwrap();
+            prop_assert!(token_eq(&token, &token2), "{json}");
+        }
+
+        /// Tokens carrying claims unknown to this server are rejected,
+        /// instead of having them silently ignored.
+        #[test]
+        fn test_token_unknown_claims(
+            token in jwt_token(),
+            claim in "[a-z_]{1,12}",
+            value in any::<i64>(),
+        ) {
+            let mut json = serde_json::to_value(&token).unwrap();
+            let map = json.as_object_mut().unwrap();
+            prop_assume!(!map.contains_key(&claim));
+
+            map.insert(claim, value.into());
+
+            let res = serde_json::from_value::<Token>(json);
+            prop_assert!(res.is_err(), "unknown claim accepted");
+        }
+    }
+}
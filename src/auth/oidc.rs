@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use rand::RngCore;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+use crate::config::OidcConfig;
+
+use super::{AuthError, Permission};
+
+/// How long a login started with [`OidcClient::authorize_url`] stays valid.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+/// Upper bound of logins waiting for the provider callback.
+const MAX_PENDING_LOGINS: usize = 4096;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+struct PendingLogin {
+    nonce: String,
+    expires_at: Instant,
+}
+
+/// An user authenticated by the identity provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub username: String,
+}
+
+/// Client of the OpenID Connect authorization code flow.
+pub struct OidcClient {
+    cfg: OidcConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl OidcClient {
+    pub fn new(cfg: OidcConfig) -> Self {
+        Self {
+            cfg,
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn default_permission(&self) -> Permission {
+        self.cfg.default_permission
+    }
+
+    /// Starts a new login, returning the provider url the user must be
+    /// redirected to.
+    pub async fn authorize_url(&self) -> Result<Url, AuthError> {
+        let metadata = self.metadata().await?;
+
+        let state = random_string();
+        let nonce = random_string();
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let now = Instant::now();
+            pending.retain(|_, login| login.expires_at > now);
+
+            if pending.len() >= MAX_PENDING_LOGINS {
+                return Err(AuthError::Oidc("too many pending logins".into()));
+            }
+            pending.insert(
+                state.clone(),
+                PendingLogin {
+                    nonce: nonce.clone(),
+                    expires_at: now + LOGIN_TIMEOUT,
+                },
+            );
+        }
+
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|e| oidc_error("invalid authorization endpoint", e))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.cfg.client_id)
+            .append_pair("redirect_uri", &self.cfg.redirect_url)
+            .append_pair("scope", &self.cfg.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce);
+
+        Ok(url)
+    }
+
+    /// Finishes a login started with [`OidcClient::authorize_url`],
+    /// exchanging the authorization `code` for the user identity.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        state: &str,
+    ) -> Result<OidcIdentity, AuthError> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.expires_at > Instant::now())
+            .ok_or(AuthError::InvalidOidcState)?;
+
+        let metadata = self.metadata().await?;
+
+        let res: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.cfg.redirect_url),
+                ("client_id", &self.cfg.client_id),
+                ("client_secret", &self.cfg.client_secret),
+            ])
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| oidc_error("token request failed", e))?
+            .json()
+            .await
+            .map_err(|e| oidc_error("invalid token response", e))?;
+
+        let claims = self.verify_id_token(metadata, &res.id_token).await?;
+        if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+            return Err(AuthError::Oidc("id token nonce mismatch".into()));
+        }
+
+        let username = claims
+            .preferred_username
+            .or(claims.email)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| claims.sub.clone());
+
+        Ok(OidcIdentity {
+            issuer: metadata.issuer.clone(),
+            subject: claims.sub,
+            username,
+        })
+    }
+
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        token: &str,
+    ) -> Result<IdTokenClaims, AuthError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| oidc_error("invalid id token", e))?;
+
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| oidc_error("jwks request failed", e))?
+            .json()
+            .await
+            .map_err(|e| oidc_error("invalid jwks response", e))?;
+
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| {
+            AuthError::Oidc("id token signing key not found".into())
+        })?;
+
+        let key = DecodingKey::from_jwk(jwk)
+            .map_err(|e| oidc_error("invalid jwk", e))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.cfg.client_id]);
+
+        jsonwebtoken::decode(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| oidc_error("id token validation failed", e))
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, AuthError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.cfg.issuer.trim_end_matches('/'),
+                );
+
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| oidc_error("discovery request failed", e))?
+                    .json()
+                    .await
+                    .map_err(|e| oidc_error("invalid discovery response", e))?;
+
+                if metadata.issuer.trim_end_matches('/')
+                    != self.cfg.issuer.trim_end_matches('/')
+                {
+                    return Err(AuthError::Oidc(format!(
+                        "discovered issuer `{}` does not match `{}`",
+                        metadata.issuer, self.cfg.issuer,
+                    )));
+                }
+
+                Ok(metadata)
+            })
+            .await
+    }
+}
+
+fn random_string() -> String {
+    let mut buf = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut buf);
+    BASE64_URL_SAFE_NO_PAD.encode(buf)
+}
+
+fn oidc_error(msg: &str, error: impl std::fmt::Display) -> AuthError {
+    tracing::error!(%error, "{msg}");
+    AuthError::Oidc(format!("{msg}: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::State, routing, Json, Router};
+    use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use reqwest::Url;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::{json, Value};
+    use test_log::test;
+    use tokio::net::TcpListener;
+
+    use crate::{
+        auth::{AuthError, Permission},
+        config::OidcConfig,
+    };
+
+    use super::OidcClient;
+
+    const CLIENT_ID: &str = "downloader";
+
+    #[derive(Clone)]
+    struct Provider {
+        issuer: String,
+        key: Arc<EncodingKey>,
+        jwk: Value,
+        nonce: Arc<Mutex<String>>,
+    }
+
+    async fn discovery(State(p): State<Provider>) -> Json<Value> {
+        Json(json!({
+            "issuer": p.issuer,
+            "authorization_endpoint": format!("{}/authorize", p.issuer),
+            "token_endpoint": format!("{}/token", p.issuer),
+            "jwks_uri": format!("{}/jwks", p.issuer),
+        }))
+    }
+
+    async fn jwks(State(p): State<Provider>) -> Json<Value> {
+        Json(json!({ "keys": [p.jwk] }))
+    }
+
+    async fn token(State(p): State<Provider>) -> Json<Value> {
+        let now = chrono::Utc::now().timestamp();
+        let claims = json!({
+            "iss": p.issuer,
+            "aud": CLIENT_ID,
+            "sub": "subject-1",
+            "iat": now,
+            "exp": now + 60,
+            "nonce": *p.nonce.lock().unwrap(),
+            "preferred_username": "oidc-user",
+        });
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("key-1".into());
+        let id_token = jsonwebtoken::encode(&header, &claims, &p.key).unwrap();
+
+        Json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+    }
+
+    async fn provider() -> (Provider, OidcClient) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let provider = Provider {
+            issuer: issuer.clone(),
+            key: Arc::new(EncodingKey::from_ed_der(pkcs8.as_ref())),
+            jwk: json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "kid": "key-1",
+                "x": BASE64_URL_SAFE_NO_PAD.encode(pair.public_key()),
+            }),
+            nonce: Arc::new(Mutex::new(String::new())),
+        };
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", routing::get(discovery))
+            .route("/jwks", routing::get(jwks))
+            .route("/token", routing::post(token))
+            .with_state(provider.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = OidcClient::new(OidcConfig {
+            issuer,
+            client_id: CLIENT_ID.into(),
+            client_secret: "secret".into(),
+            redirect_url: "http://localhost/api/auth/oidc/callback".into(),
+            scopes: vec!["openid".into()],
+            default_permission: Permission::UNPRIVILEGED,
+        });
+
+        (provider, client)
+    }
+
+    fn query_param(url: &Url, key: &str) -> String {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+            .unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_exchange() {
+        let (provider, client) = provider().await;
+
+        let url = client.authorize_url().await.unwrap();
+        assert_eq!(query_param(&url, "client_id"), CLIENT_ID);
+        let state = query_param(&url, "state");
+        *provider.nonce.lock().unwrap() = query_param(&url, "nonce");
+
+        let identity = client.exchange("code", &state).await.unwrap();
+        assert_eq!(identity.issuer, provider.issuer);
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.username, "oidc-user");
+
+        let res = client.exchange("code", &state).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidOidcState)),
+            "login state must not be reusable",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_exchange_nonce_mismatch() {
+        let (provider, client) = provider().await;
+
+        let url = client.authorize_url().await.unwrap();
+        *provider.nonce.lock().unwrap() = "other".into();
+
+        let res = client.exchange("code", &query_param(&url, "state")).await;
+        assert!(matches!(res, Err(AuthError::Oidc(..))));
+    }
+}
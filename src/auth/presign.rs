@@ -0,0 +1,516 @@
+use std::time::Duration;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use crate::{storage::embargo::check_available, utils::retry::retry_busy};
+
+use super::AuthError;
+
+const NONCE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresignAction {
+    Download,
+    Upload,
+    /// Creates a new object with the presigned id.
+    Create,
+}
+
+impl PresignAction {
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            PresignAction::Download => "download",
+            PresignAction::Upload => "upload",
+            PresignAction::Create => "create",
+        }
+    }
+}
+
+/// Constraints of a presigned upload, signed along with the url and
+/// checked when the upload is received.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadPolicy {
+    /// Owner of the objects created with the url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Allowed mime types, any when empty. Entries like `image/*` match
+    /// all the subtypes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mime_types: Vec<String>,
+    /// Created objects are named `{folder}/{name}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+}
+
+impl UploadPolicy {
+    /// Checks what is known of an upload before receiving its data.
+    pub fn check(
+        &self,
+        mime_type: &str,
+        size: Option<u64>,
+    ) -> Result<(), AuthError> {
+        if let (Some(max), Some(size)) = (self.max_size, size) {
+            if size > max {
+                return Err(AuthError::PolicyViolation(format!(
+                    "size of {size} bytes exceeds the maximum of {max}",
+                )));
+            }
+        }
+
+        let essence = mime_type.split(';').next().unwrap_or("").trim();
+        let allowed = self.mime_types.is_empty()
+            || self.mime_types.iter().any(|allowed| {
+                match allowed.strip_suffix("/*") {
+                    Some(ty) => essence
+                        .split_once('/')
+                        .is_some_and(|(t, _)| t.eq_ignore_ascii_case(ty)),
+                    None => essence.eq_ignore_ascii_case(allowed),
+                }
+            });
+        if !allowed {
+            return Err(AuthError::PolicyViolation(format!(
+                "mime type `{essence}` is not allowed",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The name of an object created with `name` as the file name.
+    pub fn object_name(&self, name: &str) -> Result<String, AuthError> {
+        if name.is_empty() || name.contains('/') || name == ".." {
+            return Err(AuthError::PolicyViolation(format!(
+                "invalid file name `{name}`",
+            )));
+        }
+
+        Ok(match &self.folder {
+            Some(folder) => format!("{}/{name}", folder.trim_end_matches('/')),
+            None => name.to_owned(),
+        })
+    }
+
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("policy is serializable");
+        BASE64_URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let json = BASE64_URL_SAFE_NO_PAD.decode(s).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+/// The query parameters of a presigned url.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PresignedQuery {
+    pub action: PresignAction,
+    /// Unix timestamp in seconds.
+    pub expires: i64,
+    pub nonce: String,
+    /// The encoded [`UploadPolicy`], if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Unix timestamp in seconds before which the url can not be used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available_from: Option<i64>,
+    pub signature: String,
+    /// Serves downloaded files to be displayed instead of saved.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inline: bool,
+}
+
+impl PresignedQuery {
+    /// Encodes the parameters as an url query string.
+    pub fn to_query_string(&self) -> String {
+        // All the values are url safe
+        let mut query = format!(
+            "action={}&expires={}&nonce={}",
+            self.action.as_str(),
+            self.expires,
+            self.nonce,
+        );
+        if let Some(policy) = &self.policy {
+            query.push_str("&policy=");
+            query.push_str(policy);
+        }
+        if let Some(available_from) = self.available_from {
+            query.push_str(&format!("&available_from={available_from}"));
+        }
+        if self.inline {
+            query.push_str("&inline=true");
+        }
+        query.push_str("&signature=");
+        query.push_str(&self.signature);
+        query
+    }
+}
+
+/// Issues single-use urls that grant access to an object without a bearer
+/// token, signed with a key derived from the server secret.
+pub struct PresignRepository<DB: Database> {
+    db: Pool<DB>,
+    key: [u8; 32],
+    max_duration: Duration,
+}
+
+impl<DB: Database> Clone for PresignRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            key: self.key,
+            max_duration: self.max_duration,
+        }
+    }
+}
+
+impl<DB: Database> PresignRepository<DB> {
+    pub fn new(
+        db: Pool<DB>,
+        secret: &[u8],
+        max_duration: Duration,
+    ) -> PresignRepository<DB> {
+        // Not using the secret directly keeps the signatures from being
+        // valid for anything else
+        let key = Hmac::<Sha256>::new_from_slice(secret)
+            .expect("hmac accepts keys of any size")
+            .chain_update(b"downloader presigned url")
+            .finalize()
+            .into_bytes()
+            .into();
+
+        PresignRepository {
+            db,
+            key,
+            max_duration,
+        }
+    }
+
+    /// The signature of `query` over the object `id`, all the fields but
+    /// the signature itself signed.
+    fn mac(&self, id: Uuid, query: &PresignedQuery) -> Hmac<Sha256> {
+        let mut msg = format!(
+            "{id}:{}:{}:{}",
+            query.action.as_str(),
+            query.expires,
+            query.nonce,
+        );
+        if let Some(policy) = &query.policy {
+            msg.push(':');
+            msg.push_str(policy);
+        }
+        // Named, as encoded policies may look like numbers
+        if let Some(available_from) = query.available_from {
+            msg.push_str(&format!(":available_from={available_from}"));
+        }
+        if query.inline {
+            msg.push_str(":inline");
+        }
+
+        Hmac::<Sha256>::new_from_slice(&self.key)
+            .expect("hmac accepts keys of any size")
+            .chain_update(msg.as_bytes())
+    }
+}
+
+impl<DB> PresignRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    /// Presigns `action` over the object `id` for `duration`, restricted by
+    /// `policy` if any. The url can not be used before `available_from`,
+    /// and serves the file to be displayed if `inline`.
+    pub async fn create(
+        &self,
+        id: Uuid,
+        action: PresignAction,
+        duration: Duration,
+        policy: Option<&UploadPolicy>,
+        available_from: Option<DateTime<Utc>>,
+        inline: bool,
+    ) -> Result<PresignedQuery, AuthError> {
+        if duration > self.max_duration {
+            return Err(AuthError::TokenExpirationTooLong {
+                got: duration,
+                max: self.max_duration,
+            });
+        }
+
+        let now = Utc::now();
+        let expires = now.timestamp() + duration.as_secs() as i64;
+
+        let available_from = available_from.map(|at| at.timestamp());
+        if available_from.is_some_and(|at| at >= expires) {
+            return Err(AuthError::InvalidPresignRequest(
+                "the url would expire before becoming available",
+            ));
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = BASE64_URL_SAFE_NO_PAD.encode(nonce);
+
+        retry_busy(|| {
+            sqlx::query("DELETE FROM presign_nonce WHERE expires_at <= $1")
+                .bind(now.timestamp_millis())
+                .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "INSERT INTO presign_nonce (nonce, object_id, expires_at) \
+                VALUES ($1, $2, $3)",
+            )
+            .bind(nonce.as_str())
+            .bind(id_bytes.as_slice())
+            .bind(expires * 1000)
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        let mut query = PresignedQuery {
+            action,
+            expires,
+            nonce,
+            policy: policy.map(UploadPolicy::encode),
+            available_from,
+            signature: String::new(),
+            inline,
+        };
+        let signature = self.mac(id, &query).finalize().into_bytes();
+        query.signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
+
+        Ok(query)
+    }
+
+    /// Verifies a presigned url for `action` over the object `id` without
+    /// using it, returning its policy.
+    pub fn verify(
+        &self,
+        id: Uuid,
+        action: PresignAction,
+        query: &PresignedQuery,
+    ) -> Result<Option<UploadPolicy>, AuthError> {
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(&query.signature)
+            .map_err(|_| AuthError::InvalidPresignedUrl)?;
+
+        if query.action != action {
+            return Err(AuthError::InvalidPresignedUrl);
+        }
+
+        self.mac(id, query)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidPresignedUrl)?;
+
+        if DateTime::from_timestamp(query.expires, 0)
+            .is_none_or(|e| e <= Utc::now())
+        {
+            return Err(AuthError::InvalidPresignedUrl);
+        }
+        if let Some(at) = query.available_from {
+            let at = DateTime::from_timestamp(at, 0)
+                .ok_or(AuthError::InvalidPresignedUrl)?;
+            check_available(Some(at))?;
+        }
+
+        query
+            .policy
+            .as_deref()
+            .map(|policy| {
+                UploadPolicy::decode(policy)
+                    .ok_or(AuthError::InvalidPresignedUrl)
+            })
+            .transpose()
+    }
+
+    /// Verifies a presigned url for `action` over the object `id`, which
+    /// can not be used again afterwards, returning its policy.
+    pub async fn consume(
+        &self,
+        id: Uuid,
+        action: PresignAction,
+        query: &PresignedQuery,
+    ) -> Result<Option<UploadPolicy>, AuthError> {
+        let policy = self.verify(id, action, query)?;
+        let now = Utc::now();
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as::<_, (i64,)>(
+                "DELETE FROM presign_nonce \
+                WHERE nonce = $1 AND object_id = $2 AND expires_at > $3 \
+                RETURNING expires_at",
+            )
+            .bind(query.nonce.as_str())
+            .bind(id_bytes.as_slice())
+            .bind(now.timestamp_millis())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?
+        .ok_or(AuthError::PresignedUrlUsed)?;
+
+        Ok(policy)
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> AuthError {
+    tracing::error!(%error, "got sqlx error while handling presigned url");
+    AuthError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeDelta, Utc};
+    use sqlx::{migrate, Sqlite, SqlitePool};
+    use test_log::test;
+    use uuid::Uuid;
+
+    use crate::auth::AuthError;
+
+    use super::{PresignAction, PresignRepository, UploadPolicy};
+
+    async fn repository() -> PresignRepository<Sqlite> {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        PresignRepository::new(db, b"secret", Duration::from_secs(3600))
+    }
+
+    #[test(tokio::test)]
+    async fn test_consume() {
+        let repo = repository().await;
+        let id = Uuid::new_v4();
+        let action = PresignAction::Download;
+
+        let query = repo
+            .create(id, action, Duration::from_secs(60), None, None, false)
+            .await
+            .unwrap();
+
+        let res = repo.consume(id, PresignAction::Upload, &query).await;
+        assert!(matches!(res, Err(AuthError::InvalidPresignedUrl)));
+        let res = repo.consume(Uuid::new_v4(), action, &query).await;
+        assert!(matches!(res, Err(AuthError::InvalidPresignedUrl)));
+
+        let mut tampered = query.clone();
+        tampered.expires += 3600;
+        let res = repo.consume(id, action, &tampered).await;
+        assert!(matches!(res, Err(AuthError::InvalidPresignedUrl)));
+
+        let mut tampered = query.clone();
+        tampered.inline = true;
+        let res = repo.consume(id, action, &tampered).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidPresignedUrl)),
+            "inline must be part of the signature",
+        );
+
+        repo.consume(id, action, &query).await.unwrap();
+        let res = repo.consume(id, action, &query).await;
+        assert!(
+            matches!(res, Err(AuthError::PresignedUrlUsed)),
+            "presigned url must be single use",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_available_from() {
+        let repo = repository().await;
+        let id = Uuid::new_v4();
+        let action = PresignAction::Download;
+        let duration = Duration::from_secs(60);
+
+        let at = Utc::now() + TimeDelta::seconds(30);
+        let query = repo
+            .create(id, action, duration, None, Some(at), false)
+            .await
+            .unwrap();
+        assert!(query.to_query_string().contains("&available_from="));
+
+        let res = repo.consume(id, action, &query).await;
+        assert!(matches!(res, Err(AuthError::Embargoed(..))));
+
+        let mut tampered = query.clone();
+        tampered.available_from = None;
+        let res = repo.consume(id, action, &tampered).await;
+        assert!(matches!(res, Err(AuthError::InvalidPresignedUrl)));
+
+        let at = Utc::now() + TimeDelta::seconds(120);
+        let res = repo
+            .create(id, action, duration, None, Some(at), false)
+            .await;
+        assert!(matches!(res, Err(AuthError::InvalidPresignRequest(..))));
+    }
+
+    #[test(tokio::test)]
+    async fn test_create_too_long() {
+        let repo = repository().await;
+
+        let res = repo
+            .create(
+                Uuid::new_v4(),
+                PresignAction::Upload,
+                Duration::from_secs(3601),
+                None,
+                None,
+                false,
+            )
+            .await;
+        assert!(matches!(res, Err(AuthError::TokenExpirationTooLong { .. })));
+    }
+
+    #[test]
+    fn test_upload_policy() {
+        let policy = UploadPolicy {
+            max_size: Some(10),
+            mime_types: vec!["image/*".into(), "text/plain".into()],
+            folder: Some("uploads".into()),
+            ..Default::default()
+        };
+
+        policy.check("image/png", Some(10)).unwrap();
+        policy.check("text/plain; charset=utf-8", None).unwrap();
+        assert!(policy.check("image/png", Some(11)).is_err());
+        assert!(policy.check("text/html", None).is_err());
+        assert!(policy.check("imagex/png", None).is_err());
+
+        assert_eq!(policy.object_name("a.png").unwrap(), "uploads/a.png");
+        assert!(policy.object_name("../a.png").is_err());
+        assert!(policy.object_name("").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use base64::Engine;
 use chrono::Utc;
@@ -8,18 +8,34 @@ use jsonwebtoken::{
 };
 use uuid::Uuid;
 
-use super::{AuthError, FileToken, Permission, Token, UserToken};
+use crate::{
+    config::TokenDurationConfig, namespace::default_namespace, user::User,
+};
+
+use super::{
+    cache::TokenCache, AuthError, FileScope, FileToken, Permission, Token,
+    UserToken,
+};
 
 pub struct TokenRepository {
     enc_key: EncodingKey,
     dec_key: DecodingKey,
+    /// Keys of retired signing keys, by key id.
+    retired_keys: HashMap<String, DecodingKey>,
     header: Header,
     validation: Validation,
 
     user_token_duration: Duration,
-    max_token_duration: Duration,
+    max_file_token_duration: Duration,
+    admin_token_duration: Option<Duration>,
+    unprivileged_token_duration: Option<Duration>,
+    remember_me_duration: Option<Duration>,
+    /// Token durations of some users, by id.
+    user_durations: HashMap<Uuid, Duration>,
 
     srv_secret: Vec<u8>,
+
+    cache: Option<TokenCache>,
 }
 
 impl TokenRepository {
@@ -28,37 +44,141 @@ impl TokenRepository {
         enc_key: EncodingKey,
         dec_key: DecodingKey,
         user_token_duration: Duration,
-        max_token_duration: Duration,
+        max_file_token_duration: Duration,
         srv_secret: Vec<u8>,
     ) -> Self {
         Self {
             enc_key,
             dec_key,
+            retired_keys: HashMap::new(),
             header: Header::new(algo),
             validation: Validation::new(algo),
             user_token_duration,
-            max_token_duration,
+            max_file_token_duration,
+            admin_token_duration: None,
+            unprivileged_token_duration: None,
+            remember_me_duration: None,
+            user_durations: HashMap::new(),
             srv_secret,
+            cache: None,
         }
     }
+
+    /// Sets the `kid` header of issued tokens, identifying the key they
+    /// were signed with once it gets retired.
+    pub fn with_key_id(mut self, kid: String) -> Self {
+        self.header.kid = Some(kid);
+        self
+    }
+
+    /// Keeps accepting tokens signed by a retired key until they expire.
+    pub fn with_retired_key(mut self, kid: String, key: DecodingKey) -> Self {
+        self.retired_keys.insert(kid, key);
+        self
+    }
+
+    /// Overrides the duration of the tokens issued to some users.
+    pub fn with_durations(mut self, cfg: &TokenDurationConfig) -> Self {
+        self.admin_token_duration = cfg.admin;
+        self.unprivileged_token_duration = cfg.unprivileged;
+        self.remember_me_duration = cfg.remember_me;
+        self.user_durations = cfg
+            .users
+            .iter()
+            .map(|user| (user.user_id, user.duration))
+            .collect();
+        self
+    }
+
+    /// Caches successfully decoded tokens, skipping the signature
+    /// verification of tokens seen recently.
+    pub fn with_cache(mut self, cache: TokenCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
 }
 
 impl TokenRepository {
+    /// Lifetime of the tokens issued to the user `user_id`. The duration
+    /// of the user is preferred to the one of the `permission` class, which
+    /// also bounds the one of `remember_me` logins.
+    pub fn user_token_duration(
+        &self,
+        user_id: Uuid,
+        permission: Permission,
+        remember_me: bool,
+    ) -> Duration {
+        if let Some(&duration) = self.user_durations.get(&user_id) {
+            return duration;
+        }
+
+        let class_duration = if Permission::UNPRIVILEGED.contains(permission) {
+            self.unprivileged_token_duration
+        } else {
+            self.admin_token_duration
+        };
+
+        let remember_me = self.remember_me_duration.filter(|_| remember_me);
+        match (remember_me, class_duration) {
+            (Some(remember_me), Some(class)) => remember_me.min(class),
+            (Some(remember_me), None) => remember_me,
+            (None, class) => class.unwrap_or(self.user_token_duration),
+        }
+    }
+
+    /// Issues a token of a user of the default namespace.
     pub fn generate_user_token(
         &self,
         user_id: Uuid,
         permission: Permission,
         username: String,
+    ) -> Result<String, AuthError> {
+        self.generate_token(
+            user_id,
+            permission,
+            username,
+            default_namespace(),
+            false,
+        )
+    }
+
+    /// Issues a token of `user` in its namespace, with the longer duration
+    /// of remembered logins if `remember_me`.
+    pub fn generate_login_token(
+        &self,
+        user: &User,
+        permission: Permission,
+        remember_me: bool,
+    ) -> Result<String, AuthError> {
+        self.generate_token(
+            user.id,
+            permission,
+            user.username.clone(),
+            user.namespace.clone(),
+            remember_me,
+        )
+    }
+
+    fn generate_token(
+        &self,
+        user_id: Uuid,
+        permission: Permission,
+        username: String,
+        namespace: String,
+        remember_me: bool,
     ) -> Result<String, AuthError> {
         let now = Utc::now();
+        let duration =
+            self.user_token_duration(user_id, permission, remember_me);
 
         let claims = Token::User(UserToken {
             user_id,
             created_at: now,
-            expiration: now + self.user_token_duration,
+            expiration: now + duration,
             issuer: "SRV".into(),
             permission,
             username,
+            namespace,
         });
 
         jsonwebtoken::encode(&self.header, &claims, &self.enc_key)
@@ -71,11 +191,12 @@ impl TokenRepository {
         expiration: Duration,
         issuer: String,
         permission: Permission,
+        scope: FileScope,
     ) -> Result<String, AuthError> {
-        if expiration > self.max_token_duration {
+        if expiration > self.max_file_token_duration {
             return Err(AuthError::TokenExpirationTooLong {
                 got: expiration,
-                max: self.max_token_duration,
+                max: self.max_file_token_duration,
             });
         }
 
@@ -87,6 +208,7 @@ impl TokenRepository {
             expiration: now + expiration,
             issuer,
             permission,
+            scope,
         });
 
         jsonwebtoken::encode(&self.header, &claims, &self.enc_key).map_err(
@@ -98,13 +220,57 @@ impl TokenRepository {
     }
 
     pub fn decode_token(&self, token: &str) -> Result<Token, AuthError> {
-        jsonwebtoken::decode(token, &self.dec_key, &self.validation)
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.get(token)) {
+            return Ok(cached);
+        }
+
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let claims = match header.kid {
+            Some(kid) if Some(&kid) == self.header.kid.as_ref() => {
+                self.decode_with(token, &self.dec_key)
+            }
+            Some(kid) => match self.retired_keys.get(&kid) {
+                Some(key) => self.decode_with(token, key),
+                None => Err(AuthError::InvalidToken),
+            },
+            // Tokens issued before key ids were configured
+            None => self.retired_keys.values().fold(
+                self.decode_with(token, &self.dec_key),
+                |res, key| match res {
+                    Err(AuthError::InvalidToken) => {
+                        self.decode_with(token, key)
+                    }
+                    res => res,
+                },
+            ),
+        }?;
+
+        // Server tokens are only obtained with the server secret key and are
+        // never issued as JWTs
+        if let Token::Server = claims {
+            return Err(AuthError::InvalidToken);
+        }
+
+        if let Some(cache) = &self.cache {
+            cache.insert(token, &claims);
+        }
+        Ok(claims)
+    }
+
+    fn decode_with(
+        &self,
+        token: &str,
+        key: &DecodingKey,
+    ) -> Result<Token, AuthError> {
+        jsonwebtoken::decode(token, key, &self.validation)
+            .map(|data| data.claims)
             .map_err(|error| match error.kind() {
                 JwtErrorKind::ExpiredSignature => AuthError::ExpiredToken,
                 JwtErrorKind::ImmatureSignature => AuthError::ImatureToken,
                 _ => AuthError::InvalidToken,
             })
-            .map(|v| v.claims)
     }
 
     pub fn verify_srv_key(&self, token: &str) -> Result<bool, AuthError> {
@@ -131,14 +297,19 @@ pub mod tests {
     use std::time::Duration;
 
     use base64::Engine;
+    use chrono::Utc;
     use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
     use rand::RngCore;
     use test_log::test;
     use uuid::Uuid;
 
-    use crate::auth::{Permission, Token};
+    use crate::{
+        auth::{AuthError, FileScope, Permission, Token},
+        config::{TokenDurationConfig, UserTokenDuration},
+        user::User,
+    };
 
-    use super::TokenRepository;
+    use super::{TokenCache, TokenRepository};
 
     const USER_TOKEN_DURATION: Duration = Duration::from_secs(1);
 
@@ -206,6 +377,27 @@ pub mod tests {
         assert_eq!(data.username, username);
     }
 
+    #[test]
+    fn test_decode_server_token() {
+        let repo = repository();
+
+        let tk = jsonwebtoken::encode(
+            &repo.header,
+            &serde_json::json!({
+                "type": "SERVER",
+                "exp": Utc::now().timestamp() + 3600,
+            }),
+            &repo.enc_key,
+        )
+        .unwrap();
+
+        let res = repo.decode_token(&tk);
+        assert!(
+            matches!(res, Err(AuthError::InvalidToken)),
+            "server tokens must never be decoded from a JWT",
+        );
+    }
+
     #[test]
     fn test_create_file_token() {
         let repo = repository();
@@ -214,6 +406,7 @@ pub mod tests {
         let expiration = Duration::from_secs(327);
         let issuer = format!("user/{}", Uuid::new_v4());
         let permission = Permission::ADMIN;
+        let scope = FileScope::DOWNLOAD | FileScope::METADATA;
 
         let tk = repo
             .generate_file_token(
@@ -221,6 +414,7 @@ pub mod tests {
                 expiration,
                 issuer.clone(),
                 permission,
+                scope,
             )
             .unwrap();
 
@@ -239,6 +433,134 @@ pub mod tests {
             expiration.as_secs() as i64
         );
         assert_eq!(data.permission, permission);
+        assert_eq!(data.scope, scope);
         assert_eq!(data.file_id, file_id);
     }
+
+    #[test]
+    fn test_token_durations() {
+        let user_id = Uuid::new_v4();
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let repo = repository().with_durations(&TokenDurationConfig {
+            admin: Some(hours(1)),
+            unprivileged: None,
+            remember_me: Some(hours(24 * 7)),
+            users: vec![UserTokenDuration {
+                user_id,
+                duration: hours(24 * 30),
+            }],
+        });
+        let duration = |user_id, permission, remember_me| {
+            repo.user_token_duration(user_id, permission, remember_me)
+        };
+
+        let other = Uuid::new_v4();
+        assert_eq!(
+            duration(other, Permission::UNPRIVILEGED, false),
+            USER_TOKEN_DURATION,
+        );
+        assert_eq!(duration(other, Permission::SHARE, true), hours(24 * 7));
+        assert_eq!(duration(other, Permission::ADMIN, false), hours(1));
+        assert_eq!(duration(other, Permission::ADMIN, true), hours(1));
+        assert_eq!(duration(user_id, Permission::ADMIN, false), hours(24 * 30));
+
+        let user = User {
+            id: other,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            permission: Permission::UNPRIVILEGED,
+            username: rand_string(),
+            namespace: "team-a".into(),
+            version: 0,
+        };
+        let tk = repo
+            .generate_login_token(&user, Permission::UNPRIVILEGED, true)
+            .unwrap();
+        let Token::User(data) = repo.decode_token(&tk).unwrap() else {
+            panic!("decoded wrong token type");
+        };
+        assert_eq!(data.namespace, "team-a");
+        assert_eq!(
+            (data.expiration - data.created_at).num_seconds(),
+            hours(24 * 7).as_secs() as i64,
+        );
+    }
+
+    #[test]
+    fn test_cached_token() {
+        let repo = repository()
+            .with_cache(TokenCache::new(2, Duration::from_secs(3600)));
+        let cache = repo.cache.as_ref().unwrap();
+
+        let tokens: Vec<_> = (0..3)
+            .map(|_| {
+                repo.generate_user_token(
+                    Uuid::new_v4(),
+                    Permission::UNPRIVILEGED,
+                    rand_string(),
+                )
+                .unwrap()
+            })
+            .collect();
+
+        for tk in &tokens {
+            assert!(cache.get(tk).is_none());
+            repo.decode_token(tk).unwrap();
+            assert!(cache.get(tk).is_some(), "decoded token was not cached");
+        }
+        assert!(cache.get(&tokens[0]).is_none(), "cache is not bounded");
+
+        assert!(repo.decode_token("invalid").is_err());
+        assert!(cache.get("invalid").is_none());
+
+        // Entries never outlive the token expiration
+        std::thread::sleep(USER_TOKEN_DURATION + Duration::from_millis(1100));
+        assert!(cache.get(&tokens[2]).is_none());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let (old_key, new_key) = (rand_vec(64), rand_vec(64));
+        let repo = |key: &[u8]| {
+            TokenRepository::new(
+                Algorithm::HS256,
+                EncodingKey::from_secret(key),
+                DecodingKey::from_secret(key),
+                USER_TOKEN_DURATION,
+                USER_TOKEN_DURATION,
+                rand_vec(16),
+            )
+        };
+        let generate = |repo: &TokenRepository| {
+            repo.generate_user_token(
+                Uuid::new_v4(),
+                Permission::UNPRIVILEGED,
+                rand_string(),
+            )
+            .unwrap()
+        };
+
+        let legacy_token = generate(&repo(&old_key));
+        let old_token = generate(&repo(&old_key).with_key_id("old".into()));
+
+        let rotated = repo(&new_key)
+            .with_key_id("new".into())
+            .with_retired_key("old".into(), DecodingKey::from_secret(&old_key));
+        let new_token = generate(&rotated);
+
+        let header = jsonwebtoken::decode_header(&new_token).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("new"));
+
+        for tk in [&legacy_token, &old_token, &new_token] {
+            rotated.decode_token(tk).expect("failed to decode token");
+        }
+
+        // Once the old key is dropped its tokens are rejected
+        let dropped = repo(&new_key).with_key_id("new".into());
+        dropped.decode_token(&new_token).unwrap();
+        for tk in [&legacy_token, &old_token] {
+            let res = dropped.decode_token(tk);
+            assert!(matches!(res, Err(AuthError::InvalidToken)));
+        }
+    }
 }
@@ -1,20 +1,33 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
-use axum::{extract::Path, routing, Extension, Router};
+use axum::{
+    extract::{Path, Query},
+    response::Redirect,
+    routing, Extension, Router,
+};
 use serde::{Deserialize, Serialize};
 use sqlx::Sqlite;
 use uuid::Uuid;
 
 use crate::{
     errors::DownloaderError,
-    storage::{repository::ObjectRepository, Object},
-    user::{repository::UserRepository, User, UserData},
-    utils::extractors::Json,
+    proxy::ClientIp,
+    storage::{
+        repository::ObjectRepository,
+        slug::{ObjectId, ObjectIds, PublicObject},
+    },
+    user::{repository::UserRepository, User, UserData, UserError},
+    utils::extractors::Body,
 };
 
 use super::{
-    axum::Authorization, repository::TokenRepository, AuthError, Permission,
-    Token,
+    axum::Authorization,
+    keys::{ApiKey, ApiKeyRepository},
+    lockout::LoginLimiter,
+    oidc::OidcClient,
+    repository::TokenRepository,
+    totp::{TotpEnrollment, TotpRepository},
+    AuthError, FileScope, Permission, Token,
 };
 
 pub fn auth_routes<S>(router: Router<S>) -> Router<S>
@@ -27,6 +40,15 @@ where
         .route("/signup", routing::post(post_signup))
         .route("/token/:id", routing::post(post_file_token))
         .route("/password", routing::put(update_self_password))
+        .route("/keys", routing::get(get_api_keys))
+        .route("/keys", routing::post(post_api_key))
+        .route("/keys/:id", routing::delete(delete_api_key))
+        .route("/oidc/login", routing::get(get_oidc_login))
+        .route("/oidc/callback", routing::get(get_oidc_callback))
+        .route("/2fa", routing::get(get_totp_status))
+        .route("/2fa", routing::delete(delete_totp))
+        .route("/2fa/enroll", routing::post(post_totp_enroll))
+        .route("/2fa/confirm", routing::post(post_totp_confirm))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
@@ -35,6 +57,10 @@ pub struct LoginRequestData {
     pub username: String,
     pub password: String,
     pub permission: Option<Permission>,
+    pub totp_code: Option<String>,
+    /// Issues a longer lived token, if configured. Only for login.
+    #[serde(default)]
+    pub remember_me: bool,
 }
 
 impl LoginRequestData {
@@ -60,12 +86,14 @@ pub struct LoginResponseData {
 #[serde(deny_unknown_fields)]
 pub struct FileTokenRequestData {
     pub permission: Option<Permission>,
+    /// Defaults to what the permission allows.
+    pub scope: Option<FileScope>,
     pub duration: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct FileTokenResponseData {
-    pub file: Object,
+    pub file: PublicObject,
     pub token: String,
 }
 
@@ -74,78 +102,127 @@ pub struct UpdatePasswordRequestData {
     pub username: String,
     pub old_password: String,
     pub new_password: String,
+    pub totp_code: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyRequestData {
+    pub name: String,
+    pub permission: Option<Permission>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApiKeyResponseData {
+    pub key: ApiKey,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TotpCodeRequestData {
+    pub totp_code: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TotpConfirmResponseData {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TotpStatusResponseData {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
 
 pub async fn get_self(
     Authorization(token): Authorization,
-) -> Result<Json<Token>, DownloaderError> {
-    Ok(Json(token))
+) -> Result<Body<Token>, DownloaderError> {
+    Ok(Body(token))
 }
 
 pub async fn post_login(
     Extension(token_repo): Extension<Arc<TokenRepository>>,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
-    Json(data): Json<LoginRequestData>,
-) -> Result<Json<LoginResponseData>, DownloaderError> {
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+    Extension(limiter): Extension<Arc<LoginLimiter>>,
+    ClientIp(ip): ClientIp,
+    Body(data): Body<LoginRequestData>,
+) -> Result<Body<LoginResponseData>, DownloaderError> {
+    let totp_code = data.totp_code.clone();
+    let remember_me = data.remember_me;
     let (data, permission) = data.split();
-    let user = user_repo.authenticate(data).await?;
+
+    let (user, max_permission) = authenticate(
+        &limiter,
+        &user_repo,
+        &totp_repo,
+        data,
+        totp_code.as_deref(),
+        ip,
+    )
+    .await?;
 
     let permission = if let Some(permission) = permission {
-        if !user.permission.contains(permission) {
+        if !max_permission.contains(permission) {
             return Err(AuthError::HigherPermissionRequired.into());
         }
         permission
     } else {
-        user.permission
+        max_permission
     };
 
-    let token = token_repo.generate_user_token(
-        user.id,
-        permission,
-        user.username.clone(),
-    )?;
+    let token =
+        token_repo.generate_login_token(&user, permission, remember_me)?;
 
-    Ok(Json(LoginResponseData { token, user }))
+    Ok(Body(LoginResponseData { token, user }))
 }
 
 pub async fn post_signup(
     Authorization(token): Authorization,
     Extension(token_repo): Extension<Arc<TokenRepository>>,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
-    Json(data): Json<LoginRequestData>,
-) -> Result<Json<LoginResponseData>, DownloaderError> {
+    Body(data): Body<LoginRequestData>,
+) -> Result<Body<LoginResponseData>, DownloaderError> {
     if !token.can_write_users() {
         return Err(AuthError::AccessDenied.into());
     }
 
     let (data, permission) = data.split();
-    let permission = permission.unwrap_or_else(|| match token {
+    let permission = permission.unwrap_or(match token {
         Token::Server => Permission::ADMIN,
         _ => Permission::UNPRIVILEGED,
     });
 
     let user = user_repo.create(permission, data).await?;
-    let token = token_repo.generate_user_token(
-        user.id,
-        permission,
-        user.username.clone(),
-    )?;
+    let token = token_repo.generate_login_token(&user, permission, false)?;
 
-    Ok(Json(LoginResponseData { user, token }))
+    Ok(Body(LoginResponseData { user, token }))
 }
 
 pub async fn post_file_token(
     Authorization(token): Authorization,
     Extension(token_repo): Extension<Arc<TokenRepository>>,
     Extension(obj_repo): Extension<ObjectRepository<Sqlite>>,
-    Path(id): Path<Uuid>,
-    Json(data): Json<FileTokenRequestData>,
-) -> Result<Json<FileTokenResponseData>, DownloaderError> {
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+    Body(data): Body<FileTokenRequestData>,
+) -> Result<Body<FileTokenResponseData>, DownloaderError> {
     if !token.can_share() {
         return Err(AuthError::AccessDenied.into());
     }
 
     let permission = data.permission.unwrap_or(Permission::SINGLE_FILE_R);
+    let scope = data
+        .scope
+        .unwrap_or_else(|| FileScope::for_permission(permission));
     let duration = data
         .duration
         .map(Duration::from_secs)
@@ -178,32 +255,245 @@ pub async fn post_file_token(
     }
 
     let token = token_repo
-        .generate_file_token(file.id, duration, issuer, permission)?;
+        .generate_file_token(file.id, duration, issuer, permission, scope)?;
 
-    Ok(Json(FileTokenResponseData { file, token }))
+    Ok(Body(FileTokenResponseData {
+        file: ids.expose(file).await?,
+        token,
+    }))
 }
 
 pub async fn update_self_password(
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
     Extension(token_repo): Extension<Arc<TokenRepository>>,
-    Json(data): Json<UpdatePasswordRequestData>,
-) -> Result<Json<LoginResponseData>, DownloaderError> {
-    let mut user = user_repo
-        .authenticate(UserData {
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+    Extension(limiter): Extension<Arc<LoginLimiter>>,
+    ClientIp(ip): ClientIp,
+    Body(data): Body<UpdatePasswordRequestData>,
+) -> Result<Body<LoginResponseData>, DownloaderError> {
+    let (mut user, permission) = authenticate(
+        &limiter,
+        &user_repo,
+        &totp_repo,
+        UserData {
             username: data.username,
             password: data.old_password,
-        })
-        .await?;
+        },
+        data.totp_code.as_deref(),
+        ip,
+    )
+    .await?;
 
     user = user_repo
-        .update_password(user.id, data.new_password)
+        .update_password(user.id, data.new_password, None)
         .await?;
 
-    let token = token_repo.generate_user_token(
-        user.id,
-        user.permission,
-        user.username.clone(),
-    )?;
+    let token = token_repo.generate_login_token(&user, permission, false)?;
+
+    Ok(Body(LoginResponseData { user, token }))
+}
+
+pub async fn get_api_keys(
+    Authorization(token): Authorization,
+    Extension(key_repo): Extension<ApiKeyRepository<Sqlite>>,
+) -> Result<Body<Vec<ApiKey>>, DownloaderError> {
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let keys = key_repo.get_by_user(user_id).await?;
+    Ok(Body(keys))
+}
+
+pub async fn post_api_key(
+    Authorization(token): Authorization,
+    Extension(key_repo): Extension<ApiKeyRepository<Sqlite>>,
+    Body(data): Body<ApiKeyRequestData>,
+) -> Result<Body<ApiKeyResponseData>, DownloaderError> {
+    let permission = data.permission.unwrap_or(token.permission());
+    if !token.permission().contains(permission) {
+        return Err(AuthError::HigherPermissionRequired.into());
+    }
+
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let (key, token) = key_repo.create(user_id, &data.name, permission).await?;
+    Ok(Body(ApiKeyResponseData { key, token }))
+}
+
+pub async fn delete_api_key(
+    Authorization(token): Authorization,
+    Extension(key_repo): Extension<ApiKeyRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<ApiKey>, DownloaderError> {
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let key = key_repo.delete(id, user_id).await?;
+    Ok(Body(key))
+}
+
+pub async fn get_oidc_login(
+    oidc: Option<Extension<Arc<OidcClient>>>,
+) -> Result<Redirect, DownloaderError> {
+    let Extension(oidc) = oidc.ok_or(AuthError::OidcDisabled)?;
+
+    let url = oidc.authorize_url().await?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+pub async fn get_oidc_callback(
+    oidc: Option<Extension<Arc<OidcClient>>>,
+    Extension(token_repo): Extension<Arc<TokenRepository>>,
+    Extension(user_repo): Extension<UserRepository<Sqlite>>,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<Body<LoginResponseData>, DownloaderError> {
+    let Extension(oidc) = oidc.ok_or(AuthError::OidcDisabled)?;
+
+    let code = match (query.code, query.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(AuthError::Oidc(format!(
+                "provider returned error `{}`: {}",
+                error.unwrap_or_default(),
+                query.error_description.unwrap_or_default(),
+            ))
+            .into())
+        }
+    };
+
+    let identity = oidc.exchange(&code, &query.state).await?;
+    let user = user_repo
+        .get_or_create_oidc(
+            &identity.issuer,
+            &identity.subject,
+            identity.username,
+            oidc.default_permission(),
+        )
+        .await?;
+
+    let permission = user_repo.get_effective_permission(&user).await?;
+    let token = token_repo.generate_login_token(&user, permission, false)?;
+
+    Ok(Body(LoginResponseData { user, token }))
+}
+
+/// Authenticates the user credentials and second factor, returning the
+/// highest permission a token issued to it may have. Failed attempts are
+/// accounted by `limiter`.
+async fn authenticate(
+    limiter: &LoginLimiter,
+    user_repo: &UserRepository<Sqlite>,
+    totp_repo: &TotpRepository<Sqlite>,
+    data: UserData,
+    totp_code: Option<&str>,
+    ip: Option<IpAddr>,
+) -> Result<(User, Permission), DownloaderError> {
+    let username = data.username.clone();
+    limiter.check(&username, ip)?;
+
+    let res = async {
+        let user = user_repo.authenticate(data).await?;
+        let permission =
+            second_factor(user_repo, totp_repo, &user, totp_code).await?;
+        Ok((user, permission))
+    }
+    .await;
+
+    match &res {
+        Ok(..) => limiter.record_success(&username, ip),
+        Err(DownloaderError::User(
+            UserError::NotFound | UserError::PasswordMismatch,
+        ))
+        | Err(DownloaderError::Auth(AuthError::InvalidTotpCode)) => {
+            tracing::info!(%username, ?ip, "failed login attempt");
+            limiter.record_failure(&username, ip)
+        }
+        Err(..) => {}
+    }
+
+    res
+}
+
+/// Checks the second authentication factor of `user`, returning the highest
+/// permission a token issued to it may have.
+async fn second_factor(
+    user_repo: &UserRepository<Sqlite>,
+    totp_repo: &TotpRepository<Sqlite>,
+    user: &User,
+    totp_code: Option<&str>,
+) -> Result<Permission, DownloaderError> {
+    if totp_repo.is_enabled(user.id).await? {
+        let code = totp_code.ok_or(AuthError::TotpRequired)?;
+        totp_repo.verify(user.id, code).await?;
+    } else if totp_repo.required() {
+        // Only allows the user to enroll two-factor authentication.
+        return Ok(Permission::empty());
+    }
+
+    Ok(user_repo.get_effective_permission(user).await?)
+}
+
+pub async fn get_totp_status(
+    Authorization(token): Authorization,
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+) -> Result<Body<TotpStatusResponseData>, DownloaderError> {
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let enabled = totp_repo.is_enabled(user_id).await?;
+    Ok(Body(TotpStatusResponseData { enabled }))
+}
+
+pub async fn post_totp_enroll(
+    Authorization(token): Authorization,
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+) -> Result<Body<TotpEnrollment>, DownloaderError> {
+    let user_token = match token {
+        Token::User(user_token) => user_token,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let enrollment = totp_repo
+        .enroll(user_token.user_id, &user_token.username)
+        .await?;
+    Ok(Body(enrollment))
+}
+
+pub async fn post_totp_confirm(
+    Authorization(token): Authorization,
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+    Body(data): Body<TotpCodeRequestData>,
+) -> Result<Body<TotpConfirmResponseData>, DownloaderError> {
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let recovery_codes = totp_repo.confirm(user_id, &data.totp_code).await?;
+    Ok(Body(TotpConfirmResponseData { recovery_codes }))
+}
+
+pub async fn delete_totp(
+    Authorization(token): Authorization,
+    Extension(totp_repo): Extension<TotpRepository<Sqlite>>,
+    Body(data): Body<TotpCodeRequestData>,
+) -> Result<Body<TotpStatusResponseData>, DownloaderError> {
+    let user_id = match token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    totp_repo.verify(user_id, &data.totp_code).await?;
+    totp_repo.disable(user_id).await?;
 
-    Ok(Json(LoginResponseData { user, token }))
+    Ok(Body(TotpStatusResponseData { enabled: false }))
 }
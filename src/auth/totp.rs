@@ -0,0 +1,414 @@
+use chrono::Utc;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
+    Pool, Row, Type,
+};
+use totp_rs::{Algorithm, TOTP};
+use uuid::Uuid;
+
+use super::AuthError;
+
+const SECRET_LEN: usize = 20;
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TotpEnrollment {
+    /// The base32 encoded secret.
+    pub secret: String,
+    /// The `otpauth://` uri of the secret, usually rendered as a QR code.
+    pub uri: String,
+}
+
+struct UserTotp {
+    secret: Vec<u8>,
+    enabled: bool,
+    last_step: i64,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for UserTotp
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let secret = row.try_get("secret")?;
+        let enabled: i64 = row.try_get("enabled")?;
+        let last_step = row.try_get("last_step")?;
+
+        Ok(Self {
+            secret,
+            enabled: enabled != 0,
+            last_step,
+        })
+    }
+}
+
+pub struct TotpRepository<DB: Database> {
+    db: Pool<DB>,
+    issuer: String,
+    required: bool,
+}
+
+impl<DB: Database> Clone for TotpRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            issuer: self.issuer.clone(),
+            required: self.required,
+        }
+    }
+}
+
+impl<DB: Database> TotpRepository<DB> {
+    pub fn new(
+        db: Pool<DB>,
+        issuer: String,
+        required: bool,
+    ) -> TotpRepository<DB> {
+        TotpRepository {
+            db,
+            issuer,
+            required,
+        }
+    }
+
+    /// Whether users without two-factor authentication enrolled are only
+    /// allowed to enroll it.
+    #[inline]
+    pub fn required(&self) -> bool {
+        self.required
+    }
+}
+
+impl<DB> TotpRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+
+    for<'r> &'r str: ColumnIndex<DB::Row>,
+    for<'r> Vec<u8>: Decode<'r, DB>,
+    Vec<u8>: Type<DB>,
+    for<'r> i64: Decode<'r, DB>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    /// Generates a new secret for the user, replacing any previous enrollment
+    /// that was not confirmed yet.
+    pub async fn enroll(
+        &self,
+        user_id: Uuid,
+        username: &str,
+    ) -> Result<TotpEnrollment, AuthError> {
+        let mut secret = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        sqlx::query_as::<_, (Vec<u8>,)>(
+            "INSERT INTO user_totp \
+            (user_id, created_at, secret, enabled, last_step) \
+            VALUES ($1, $2, $3, 0, 0) \
+            ON CONFLICT (user_id) DO UPDATE SET \
+            created_at = excluded.created_at, secret = excluded.secret \
+            WHERE user_totp.enabled = 0 RETURNING user_id",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(Utc::now().timestamp_millis())
+        .bind(secret.as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?
+        .ok_or(AuthError::TotpAlreadyEnabled)?;
+
+        let totp = generator(secret, self.issuer.clone(), username.to_owned());
+        Ok(TotpEnrollment {
+            secret: totp.get_secret_base32(),
+            uri: totp.get_url(),
+        })
+    }
+
+    /// Enables the enrolled secret once the user proves to own it, returning
+    /// a fresh set of single use recovery codes.
+    pub async fn confirm(
+        &self,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<Vec<String>, AuthError> {
+        let mut tx = self.db.begin().await.map_err(sqlx_error)?;
+
+        let totp: UserTotp =
+            sqlx::query_as("SELECT * FROM user_totp WHERE user_id = $1")
+                .bind(user_id.into_bytes().as_slice())
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(sqlx_error)?
+                .ok_or(AuthError::TotpNotEnrolled)?;
+
+        if totp.enabled {
+            return Err(AuthError::TotpAlreadyEnabled);
+        }
+
+        let step = check_code(&totp, code).ok_or(AuthError::InvalidTotpCode)?;
+
+        sqlx::query(
+            "UPDATE user_totp SET enabled = 1, last_step = $1 \
+            WHERE user_id = $2",
+        )
+        .bind(step)
+        .bind(user_id.into_bytes().as_slice())
+        .execute(&mut *tx)
+        .await
+        .map_err(sqlx_error)?;
+
+        sqlx::query("DELETE FROM totp_recovery_code WHERE user_id = $1")
+            .bind(user_id.into_bytes().as_slice())
+            .execute(&mut *tx)
+            .await
+            .map_err(sqlx_error)?;
+
+        let mut codes = Vec::with_capacity(RECOVERY_CODES);
+        for _ in 0..RECOVERY_CODES {
+            let mut code = [0u8; RECOVERY_CODE_LEN];
+            rand::thread_rng().fill_bytes(&mut code);
+            let code = hex::encode(code);
+
+            sqlx::query(
+                "INSERT INTO totp_recovery_code (user_id, code_hash) \
+                VALUES ($1, $2)",
+            )
+            .bind(user_id.into_bytes().as_slice())
+            .bind(Sha256::digest(&code).as_slice())
+            .execute(&mut *tx)
+            .await
+            .map_err(sqlx_error)?;
+
+            codes.push(code);
+        }
+
+        tx.commit().await.map_err(sqlx_error)?;
+
+        Ok(codes)
+    }
+
+    pub async fn is_enabled(&self, user_id: Uuid) -> Result<bool, AuthError> {
+        Ok(self.get_enabled(user_id).await?.is_some())
+    }
+
+    /// Verifies a code generated by the user authenticator or one of its
+    /// recovery codes. Both are accepted only once.
+    pub async fn verify(
+        &self,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<(), AuthError> {
+        let totp = self
+            .get_enabled(user_id)
+            .await?
+            .ok_or(AuthError::TotpNotEnrolled)?;
+
+        if let Some(step) = check_code(&totp, code) {
+            // Compare and set, so concurrent logins can not reuse the code.
+            return sqlx::query_as::<_, (Vec<u8>,)>(
+                "UPDATE user_totp SET last_step = $1 \
+                WHERE user_id = $2 AND last_step < $1 RETURNING user_id",
+            )
+            .bind(step)
+            .bind(user_id.into_bytes().as_slice())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(sqlx_error)?
+            .map(|_| ())
+            .ok_or(AuthError::InvalidTotpCode);
+        }
+
+        let code = code.trim().to_ascii_lowercase();
+        sqlx::query_as::<_, (Vec<u8>,)>(
+            "DELETE FROM totp_recovery_code \
+            WHERE user_id = $1 AND code_hash = $2 RETURNING user_id",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(Sha256::digest(&code).as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?
+        .ok_or(AuthError::InvalidTotpCode)?;
+
+        tracing::info!(%user_id, "totp recovery code used");
+        Ok(())
+    }
+
+    pub async fn disable(&self, user_id: Uuid) -> Result<(), AuthError> {
+        let mut tx = self.db.begin().await.map_err(sqlx_error)?;
+
+        sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+            .bind(user_id.into_bytes().as_slice())
+            .execute(&mut *tx)
+            .await
+            .map_err(sqlx_error)?;
+
+        sqlx::query("DELETE FROM totp_recovery_code WHERE user_id = $1")
+            .bind(user_id.into_bytes().as_slice())
+            .execute(&mut *tx)
+            .await
+            .map_err(sqlx_error)?;
+
+        tx.commit().await.map_err(sqlx_error)
+    }
+
+    async fn get_enabled(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<UserTotp>, AuthError> {
+        sqlx::query_as(
+            "SELECT * FROM user_totp WHERE user_id = $1 AND enabled = 1",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)
+    }
+}
+
+/// Returns the time step the code was generated for, if it is valid and
+/// was not used before.
+fn check_code(totp: &UserTotp, code: &str) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let generator =
+        generator(totp.secret.clone(), String::new(), String::new());
+    let now = Utc::now().timestamp() as u64;
+
+    // Accept the previous and the next step to tolerate clock skew.
+    [now.saturating_sub(STEP), now, now + STEP]
+        .into_iter()
+        .find(|time| generator.generate(*time) == code)
+        .map(|time| (time / STEP) as i64)
+        .filter(|step| *step > totp.last_step)
+}
+
+fn generator(secret: Vec<u8>, issuer: String, username: String) -> TOTP {
+    TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP,
+        secret,
+        Some(issuer),
+        username,
+    )
+}
+
+fn sqlx_error(error: sqlx::Error) -> AuthError {
+    tracing::error!(%error, "got sqlx error while handling totp");
+    AuthError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, Sqlite, SqlitePool};
+    use test_log::test;
+    use totp_rs::Secret;
+    use uuid::Uuid;
+
+    use crate::auth::AuthError;
+
+    use super::{generator, TotpEnrollment, TotpRepository};
+
+    async fn repository() -> TotpRepository<Sqlite> {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        TotpRepository::new(db, "downloader".into(), false)
+    }
+
+    fn generate(enrollment: &TotpEnrollment, offset: i64) -> String {
+        let secret = Secret::Encoded(enrollment.secret.clone());
+        let time = chrono::Utc::now().timestamp() + offset;
+
+        generator(secret.to_bytes().unwrap(), String::new(), String::new())
+            .generate(time as u64)
+    }
+
+    #[test(tokio::test)]
+    async fn test_enroll_confirm() {
+        let repo = repository().await;
+        let user_id = Uuid::new_v4();
+
+        let enrollment = repo.enroll(user_id, "user").await.unwrap();
+        assert!(enrollment.uri.starts_with("otpauth://totp/downloader:user"));
+        assert!(!repo.is_enabled(user_id).await.unwrap());
+
+        let res = repo.verify(user_id, &generate(&enrollment, 0)).await;
+        assert!(matches!(res, Err(AuthError::TotpNotEnrolled)));
+
+        let res = repo.confirm(user_id, "000000x").await;
+        assert!(matches!(res, Err(AuthError::InvalidTotpCode)));
+
+        let codes = repo
+            .confirm(user_id, &generate(&enrollment, -30))
+            .await
+            .unwrap();
+        assert_eq!(codes.len(), super::RECOVERY_CODES);
+        assert!(repo.is_enabled(user_id).await.unwrap());
+
+        let res = repo.enroll(user_id, "user").await;
+        assert!(matches!(res, Err(AuthError::TotpAlreadyEnabled)));
+    }
+
+    #[test(tokio::test)]
+    async fn test_verify() {
+        let repo = repository().await;
+        let user_id = Uuid::new_v4();
+
+        let enrollment = repo.enroll(user_id, "user").await.unwrap();
+        let codes = repo
+            .confirm(user_id, &generate(&enrollment, -30))
+            .await
+            .unwrap();
+
+        let code = generate(&enrollment, 0);
+        repo.verify(user_id, &code).await.unwrap();
+
+        let res = repo.verify(user_id, &code).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidTotpCode)),
+            "totp code must not be accepted twice",
+        );
+
+        repo.verify(user_id, &codes[0]).await.unwrap();
+        let res = repo.verify(user_id, &codes[0]).await;
+        assert!(
+            matches!(res, Err(AuthError::InvalidTotpCode)),
+            "recovery code must not be accepted twice",
+        );
+        repo.verify(user_id, &codes[1].to_uppercase())
+            .await
+            .unwrap();
+
+        repo.disable(user_id).await.unwrap();
+        assert!(!repo.is_enabled(user_id).await.unwrap());
+        let res = repo.verify(user_id, &codes[2]).await;
+        assert!(matches!(res, Err(AuthError::TotpNotEnrolled)));
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    config::BackupConfig,
+    errors::DownloaderError,
+    job::{queue::JobQueue, JobKind},
+    storage::{
+        manager::{Manager, ObjectError},
+        Object,
+    },
+};
+
+/// Name of the database snapshot inside the backup archive.
+pub const DATABASE_ENTRY: &str = "files.sqlite";
+/// Name of the object manifest inside the backup archive.
+pub const MANIFEST_ENTRY: &str = "manifest.json";
+
+const BACKUP_PREFIX: &str = "downloader-";
+const BACKUP_SUFFIX: &str = ".tar.gz";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("invalid backup: missing `{0}`")]
+    MissingEntry(&'static str),
+    #[error("invalid backup: database integrity check failed: {0}")]
+    Corrupted(String),
+}
+
+/// The objects known by the database snapshot of a backup. Object data
+/// is not part of backups, so the manifest tells what must be present in
+/// the data directory after a restore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub created_at: DateTime<Utc>,
+    pub objects: Vec<Object>,
+}
+
+/// Summary of a finished backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub path: PathBuf,
+    pub objects: usize,
+    pub size: u64,
+}
+
+/// Writes a gzipped tarball with a snapshot of the database and the
+/// object manifest to `path`, replacing it atomically.
+pub async fn create_backup(
+    db: &SqlitePool,
+    path: &Path,
+) -> Result<BackupInfo, BackupError> {
+    let snapshot = sibling(path, ".sqlite.tmp");
+    let archive = sibling(path, ".tmp");
+
+    let res = write_backup(db, path, &snapshot, &archive).await;
+
+    let _ = tokio::fs::remove_file(&snapshot).await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&archive).await;
+    }
+    res
+}
+
+async fn write_backup(
+    db: &SqlitePool,
+    path: &Path,
+    snapshot: &Path,
+    archive: &Path,
+) -> Result<BackupInfo, BackupError> {
+    // VACUUM INTO fails if the file already exists
+    match tokio::fs::remove_file(snapshot).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            return Err(error.into())
+        }
+        _ => {}
+    }
+
+    sqlx::query("VACUUM INTO $1")
+        .bind(snapshot.to_string_lossy().into_owned())
+        .execute(db)
+        .await?;
+
+    // Read from the snapshot, so the manifest matches it exactly
+    let snapshot_db = SqlitePool::connect_with(
+        SqliteConnectOptions::new()
+            .filename(snapshot)
+            .read_only(true)
+            .immutable(true),
+    )
+    .await?;
+    let objects: Result<Vec<Object>, _> =
+        sqlx::query_as("SELECT * FROM object")
+            .fetch_all(&snapshot_db)
+            .await;
+    snapshot_db.close().await;
+
+    let manifest = BackupManifest {
+        created_at: Utc::now(),
+        objects: objects?,
+    };
+    let objects = manifest.objects.len();
+
+    let (snapshot, tmp) = (snapshot.to_owned(), archive.to_owned());
+    let size = spawn_blocking(move || -> Result<u64, BackupError> {
+        let file = File::create(&tmp)?;
+        let mut builder =
+            tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+        builder.append_path_with_name(&snapshot, DATABASE_ENTRY)?;
+
+        let mtime = manifest.created_at.timestamp().max(0) as u64;
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        builder.append_data(
+            &mut header,
+            MANIFEST_ENTRY,
+            manifest.as_slice(),
+        )?;
+
+        let file = builder.into_inner()?.finish()?;
+        file.sync_all()?;
+        Ok(file.metadata()?.len())
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    tokio::fs::rename(&archive, path).await?;
+
+    Ok(BackupInfo {
+        path: path.to_owned(),
+        objects,
+        size,
+    })
+}
+
+/// Replaces the database at `db_path` with the snapshot of the backup at
+/// `path`, returning its manifest. Must not run while the server is using
+/// the database.
+///
+/// The replaced database is kept with the `.pre-restore` extension.
+pub async fn restore_backup(
+    path: &Path,
+    db_path: &Path,
+) -> Result<BackupManifest, BackupError> {
+    let snapshot = sibling(db_path, ".restore.tmp");
+
+    let res = extract_backup(path, &snapshot).await;
+    let manifest = match res {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            let _ = tokio::fs::remove_file(&snapshot).await;
+            return Err(error);
+        }
+    };
+
+    match tokio::fs::rename(db_path, sibling(db_path, ".pre-restore")).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            let _ = tokio::fs::remove_file(&snapshot).await;
+            return Err(error.into());
+        }
+        _ => {}
+    }
+    // Journals of the replaced database must not be applied to the new one
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let _ = tokio::fs::remove_file(sibling(db_path, suffix)).await;
+    }
+    tokio::fs::rename(&snapshot, db_path).await?;
+
+    Ok(manifest)
+}
+
+async fn extract_backup(
+    path: &Path,
+    snapshot: &Path,
+) -> Result<BackupManifest, BackupError> {
+    let path = path.to_owned();
+    let out = snapshot.to_owned();
+
+    let manifest = spawn_blocking(move || -> Result<_, BackupError> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+        let mut manifest = None;
+        let mut found_db = false;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+
+            if entry_path == Path::new(DATABASE_ENTRY) {
+                io::copy(&mut entry, &mut File::create(&out)?)?;
+                found_db = true;
+            } else if entry_path == Path::new(MANIFEST_ENTRY) {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                manifest = Some(serde_json::from_slice(&buf)?);
+            }
+        }
+
+        if !found_db {
+            return Err(BackupError::MissingEntry(DATABASE_ENTRY));
+        }
+        manifest.ok_or(BackupError::MissingEntry(MANIFEST_ENTRY))
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    // Refuses snapshots that are not valid databases
+    let db = SqlitePool::connect_with(
+        SqliteConnectOptions::new()
+            .filename(snapshot)
+            .read_only(true)
+            .immutable(true),
+    )
+    .await?;
+    let (check,): (String,) = sqlx::query_as("PRAGMA integrity_check")
+        .fetch_one(&db)
+        .await?;
+    db.close().await;
+
+    if check != "ok" {
+        return Err(BackupError::Corrupted(check));
+    }
+
+    Ok(manifest)
+}
+
+/// Ids of the objects in `manifest` whose data is missing.
+pub async fn missing_objects<M: Manager>(
+    manager: &M,
+    manifest: &BackupManifest,
+) -> Result<Vec<uuid::Uuid>, ObjectError> {
+    let mut missing = Vec::new();
+    for obj in &manifest.objects {
+        match manager.fetch(obj.id).await {
+            Ok(..) => {}
+            Err(ObjectError::NotFound) => missing.push(obj.id),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(missing)
+}
+
+/// Periodically queues backups into `cfg.dir`, keeping the latest
+/// `cfg.keep` ones.
+pub async fn run_backups(
+    db: SqlitePool,
+    jobs: Arc<JobQueue>,
+    cfg: BackupConfig,
+) {
+    let mut interval = tokio::time::interval(cfg.interval);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let db = db.clone();
+        let cfg = cfg.clone();
+        let res = jobs
+            .enqueue(JobKind::Backup, None, |_| async move {
+                let name = format!(
+                    "{BACKUP_PREFIX}{}{BACKUP_SUFFIX}",
+                    Utc::now().format("%Y%m%dT%H%M%SZ"),
+                );
+                let info = create_backup(&db, &cfg.dir.join(name))
+                    .await
+                    .map_err(backup_error)?;
+                prune_backups(Path::new(cfg.dir.as_str()), cfg.keep)
+                    .await
+                    .map_err(backup_error)?;
+
+                tracing::info!(
+                    path = %info.path.display(),
+                    objects = info.objects,
+                    size = info.size,
+                    "created backup",
+                );
+                Ok(info)
+            })
+            .await;
+
+        if let Err(error) = res {
+            tracing::error!(%error, "failed to queue backup");
+        }
+    }
+}
+
+/// Removes all but the `keep` most recent backups in `dir`.
+async fn prune_backups(dir: &Path, keep: usize) -> Result<(), BackupError> {
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) {
+            backups.push(entry.path());
+        }
+    }
+
+    // Names sort by their timestamp
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for path in &backups[..excess] {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+fn backup_error(error: BackupError) -> DownloaderError {
+    tracing::error!(%error, "failed to create backup");
+    DownloaderError::Other(error.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sqlx::{
+        migrate,
+        sqlite::{SqliteConnectOptions, SqlitePool},
+    };
+    use test_log::test;
+    use uuid::Uuid;
+
+    use crate::storage::{repository::ObjectRepository, ObjectData};
+
+    use super::{create_backup, prune_backups, restore_backup, BackupError};
+
+    /// `VACUUM INTO` snapshots of in-memory databases are in-memory too, so
+    /// the database must be a file.
+    async fn repository(
+        dir: &Path,
+    ) -> (SqlitePool, ObjectRepository<sqlx::Sqlite>) {
+        let db = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(dir.join("source.sqlite"))
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        (db.clone(), ObjectRepository::new(db))
+    }
+
+    fn data(name: &str) -> ObjectData {
+        ObjectData {
+            name: name.into(),
+            mime_type: mime::TEXT_PLAIN.to_string(),
+            size: 4,
+            checksum_256: [7; 32],
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_backup_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, repo) = repository(dir.path()).await;
+
+        let mut objects = Vec::new();
+        for name in ["a.txt", "b.txt"] {
+            let obj = repo
+                .create(Uuid::new_v4(), Uuid::new_v4(), data(name))
+                .await
+                .unwrap();
+            objects.push(obj);
+        }
+
+        let path = dir.path().join("backup.tar.gz");
+        let info = create_backup(&db, &path).await.unwrap();
+        assert_eq!(info.objects, 2);
+        assert_eq!(info.size, std::fs::metadata(&path).unwrap().len());
+
+        // Changes after the backup must be rolled back by the restore
+        repo.create(Uuid::new_v4(), Uuid::new_v4(), data("c.txt"))
+            .await
+            .unwrap();
+
+        let db_path = dir.path().join("files.sqlite");
+        std::fs::write(&db_path, b"old database").unwrap();
+
+        let manifest = restore_backup(&path, &db_path).await.unwrap();
+        assert_eq!(manifest.objects, objects);
+        assert_eq!(
+            std::fs::read(dir.path().join("files.sqlite.pre-restore")).unwrap(),
+            b"old database",
+        );
+
+        let restored = SqlitePool::connect(&format!(
+            "sqlite:{}",
+            db_path.to_string_lossy()
+        ))
+        .await
+        .unwrap();
+        let restored = ObjectRepository::new(restored);
+        let all = restored.get_all(10, 0).await.unwrap();
+        assert_eq!(all, objects);
+    }
+
+    #[test(tokio::test)]
+    async fn test_restore_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.tar.gz");
+        std::fs::write(&path, b"not a backup").unwrap();
+
+        let db_path = dir.path().join("files.sqlite");
+        std::fs::write(&db_path, b"database").unwrap();
+
+        let res = restore_backup(&path, &db_path).await;
+        assert!(matches!(res, Err(BackupError::Io(..))), "{res:?}");
+        assert_eq!(std::fs::read(&db_path).unwrap(), b"database");
+        assert!(!dir.path().join("files.sqlite.restore.tmp").exists());
+    }
+
+    #[test(tokio::test)]
+    async fn test_prune_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "downloader-20240101T000000Z.tar.gz",
+            "downloader-20240102T000000Z.tar.gz",
+            "downloader-20240103T000000Z.tar.gz",
+            "other.tar.gz",
+        ] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        prune_backups(dir.path(), 2).await.unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "downloader-20240102T000000Z.tar.gz",
+                "downloader-20240103T000000Z.tar.gz",
+                "other.tar.gz",
+            ],
+        );
+    }
+}
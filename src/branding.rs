@@ -0,0 +1,369 @@
+use std::{fs, io, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::BrandingConfig,
+    errors::ErrorDetails,
+    utils::{extractors::accepts, fmt::fmt_size},
+};
+
+const ERROR_TEMPLATE: &str = "error.html";
+const NOT_FOUND_TEMPLATE: &str = "not_found.html";
+const EMBARGO_TEMPLATE: &str = "embargo.html";
+const SHARE_TEMPLATE: &str = "share.html";
+
+/// Used for the pages without a template in the templates directory.
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}} - {{product_name}}</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 0; color: #222; }
+main { max-width: 36rem; margin: 15vh auto; padding: 0 1.5rem; }
+img { max-height: 3rem; }
+small { color: #777; }
+</style>
+</head>
+<body>
+<main>
+{{logo}}
+<h1>{{status}} {{title}}</h1>
+<p>{{message}}</p>
+<small>{{product_name}} {{request_id}}</small>
+</main>
+</body>
+</html>
+"#;
+
+const DEFAULT_SHARE_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>{{name}} - {{product_name}}</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 0; color: #222; }
+main { max-width: 36rem; margin: 15vh auto; padding: 0 1.5rem; }
+img { max-height: 3rem; }
+h1 { overflow-wrap: anywhere; }
+a.download { display: inline-block; padding: .6rem 1.2rem; border-radius: .4rem;
+  background: #2563eb; color: #fff; text-decoration: none; }
+small { color: #777; }
+</style>
+</head>
+<body>
+<main>
+{{logo}}
+<h1>{{name}}</h1>
+<p>{{size}} &middot; {{mime_type}} &middot; shared by {{uploader}}</p>
+<p><a class="download" href="{{download_url}}">Download</a></p>
+<small>The link can be used once and expires at {{expires_at}}</small>
+</main>
+</body>
+</html>
+"#;
+
+/// Pages shown to browsers instead of the json errors, with the name and
+/// logo of the product. Templates may use `{{product_name}}`, `{{logo}}`,
+/// `{{logo_url}}`, `{{status}}`, `{{title}}`, `{{message}}`,
+/// `{{available_from}}` and `{{request_id}}`.
+///
+/// The landing page of share links may use `{{name}}`, `{{size}}`,
+/// `{{mime_type}}`, `{{uploader}}`, `{{download_url}}` and `{{expires_at}}`
+/// instead of the error ones.
+pub struct Branding {
+    product_name: String,
+    logo_url: Option<String>,
+    error: String,
+    not_found: String,
+    embargo: String,
+    share: String,
+}
+
+/// The details of a shared file shown in its landing page.
+pub struct SharePage<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub mime_type: &'a str,
+    /// Username of the owner, if it still exists.
+    pub uploader: Option<&'a str>,
+    pub download_url: &'a str,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Branding {
+    /// Reads the templates of the pages, if any.
+    pub fn new(cfg: &BrandingConfig) -> io::Result<Self> {
+        let template = |name: &str, default: &str| match &cfg.templates_dir {
+            Some(dir) => match fs::read_to_string(dir.join(name)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Ok(default.to_owned())
+                }
+                res => res,
+            },
+            None => Ok(default.to_owned()),
+        };
+
+        Ok(Self {
+            product_name: cfg.product_name.clone(),
+            logo_url: cfg.logo_url.clone(),
+            error: template(ERROR_TEMPLATE, DEFAULT_TEMPLATE)?,
+            not_found: template(NOT_FOUND_TEMPLATE, DEFAULT_TEMPLATE)?,
+            embargo: template(EMBARGO_TEMPLATE, DEFAULT_TEMPLATE)?,
+            share: template(SHARE_TEMPLATE, DEFAULT_SHARE_TEMPLATE)?,
+        })
+    }
+
+    pub fn render_share(&self, page: &SharePage<'_>) -> String {
+        let (logo_url, logo) = self.logo();
+
+        fill(&self.share, |key| {
+            Some(match key {
+                "product_name" => escape(&self.product_name),
+                "logo" => logo.clone(),
+                "logo_url" => logo_url.clone().unwrap_or_default(),
+                "name" => escape(page.name),
+                "size" => fmt_size(page.size),
+                "mime_type" => escape(page.mime_type),
+                "uploader" => escape(page.uploader.unwrap_or("unknown")),
+                "download_url" => escape(page.download_url),
+                "expires_at" => page.expires_at.to_rfc3339(),
+                _ => return None,
+            })
+        })
+    }
+
+    /// The escaped url of the logo and its `img` tag, empty without one.
+    fn logo(&self) -> (Option<String>, String) {
+        let logo_url = self.logo_url.as_deref().map(escape);
+        let logo = logo_url
+            .as_ref()
+            .map(|url| format!(r#"<img src="{url}" alt="">"#))
+            .unwrap_or_default();
+        (logo_url, logo)
+    }
+
+    pub fn render(
+        &self,
+        status: StatusCode,
+        details: &ErrorDetails,
+        request_id: Option<&str>,
+    ) -> String {
+        let template = if details.available_from.is_some() {
+            &self.embargo
+        } else if status == StatusCode::NOT_FOUND {
+            &self.not_found
+        } else {
+            &self.error
+        };
+
+        let (logo_url, logo) = self.logo();
+        let available_from = details
+            .available_from
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_default();
+
+        fill(template, |key| {
+            Some(match key {
+                "product_name" => escape(&self.product_name),
+                "logo" => logo.clone(),
+                "logo_url" => logo_url.clone().unwrap_or_default(),
+                "status" => status.as_u16().to_string(),
+                "title" => escape(status.canonical_reason().unwrap_or("Error")),
+                "message" => escape(&details.message),
+                "available_from" => available_from.clone(),
+                "request_id" => escape(request_id.unwrap_or_default()),
+                _ => return None,
+            })
+        })
+    }
+}
+
+/// Replaces the `{{key}}` placeholders of `template`, leaving the unknown
+/// ones as they are.
+fn fill(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find("}}") else {
+            break;
+        };
+        match value(rest[2..end].trim()) {
+            Some(v) => out.push_str(&v),
+            None => out.push_str(&rest[..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders error responses as pages for browsers, that is requests
+/// accepting `text/html`, once the [`Branding`] extension is present.
+pub async fn html_errors(req: Request, next: Next) -> Response {
+    let branding = req.extensions().get::<Arc<Branding>>().cloned();
+    let wants_html = matches!(*req.method(), Method::GET | Method::HEAD)
+        && accepts(req.headers(), "text/html");
+
+    let res = next.run(req).await;
+
+    let Some(branding) = branding.filter(|_| wants_html) else {
+        return res;
+    };
+    let Some(details) = res.extensions().get::<ErrorDetails>() else {
+        return res;
+    };
+
+    let request_id = res
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok());
+    let page = branding.render(res.status(), details, request_id);
+
+    let (mut parts, _) = res.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Response::from_parts(parts, Body::from(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::to_bytes, http::Request, middleware, routing, Extension, Router,
+    };
+    use chrono::TimeDelta;
+    use test_log::test;
+    use tower::ServiceExt;
+
+    use crate::{
+        auth::AuthError, errors::DownloaderError, utils::serde::ResolvedPath,
+    };
+
+    use super::*;
+
+    fn config(templates_dir: Option<&tempfile::TempDir>) -> BrandingConfig {
+        BrandingConfig {
+            product_name: "Acme <Files>".into(),
+            logo_url: Some("https://example.com/logo.png?a=1&b=2".into()),
+            templates_dir: templates_dir.map(|dir| {
+                ResolvedPath::new(dir.path().to_string_lossy().into_owned())
+                    .unwrap()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let value = |key: &str| (key == "a").then(|| "1".to_owned());
+        assert_eq!(fill("{{a}}-{{ a }}-{{b}}-{{a", value), "1-1-{{b}}-{{a");
+        assert_eq!(
+            escape(r#"<a href="x">&'"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_html_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(EMBARGO_TEMPLATE),
+            "{{product_name}} opens at {{available_from}}",
+        )
+        .unwrap();
+        let branding = Branding::new(&config(Some(&dir))).unwrap();
+
+        let at = Utc::now() + TimeDelta::hours(1);
+        let router = Router::new()
+            .route(
+                "/embargoed",
+                routing::get(move || async move {
+                    Err::<(), _>(DownloaderError::from(AuthError::Embargoed(
+                        at,
+                    )))
+                }),
+            )
+            .route(
+                "/denied",
+                routing::get(|| async {
+                    Err::<(), _>(DownloaderError::from(AuthError::AccessDenied))
+                }),
+            )
+            .layer(middleware::from_fn(html_errors))
+            .layer(Extension(Arc::new(branding)));
+
+        let request = |uri: &str, accept: &str| {
+            let req = Request::get(uri)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            let router = router.clone();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                let status = res.status();
+                let content_type = res.headers()[header::CONTENT_TYPE].clone();
+                let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (
+                    status,
+                    content_type,
+                    String::from_utf8(body.to_vec()).unwrap(),
+                )
+            }
+        };
+
+        let html = "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8";
+        let (status, content_type, body) = request("/embargoed", html).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(content_type.to_str().unwrap().starts_with("text/html"));
+        assert_eq!(
+            body,
+            format!("Acme &lt;Files&gt; opens at {}", at.to_rfc3339()),
+        );
+
+        let (status, _, body) = request("/denied", html).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("<h1>403 Forbidden</h1>"), "{body}");
+        assert!(
+            body.contains(r#"src="https://example.com/logo.png?a=1&amp;b=2""#)
+        );
+
+        let (_, content_type, body) =
+            request("/denied", "application/json").await;
+        assert_eq!(content_type, "application/json");
+        assert!(body.starts_with('{'));
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+use super::ClientLogError;
+
+/// Entries are pruned once the map grows past this size.
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportKey {
+    User(Uuid),
+    Ip(IpAddr),
+    /// Clients without user nor address share the same limit.
+    Anonymous,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// Limits reports to `max_size` bytes, and the ones submitted by each
+/// client to `limit` per `window`.
+pub struct ReportLimiter {
+    max_size: usize,
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<ReportKey, Window>>,
+}
+
+impl ReportLimiter {
+    pub fn new(max_size: usize, limit: u32, window: Duration) -> Self {
+        Self {
+            max_size,
+            limit,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Counts a new report of `key`, failing with
+    /// [`ClientLogError::RateLimited`] if it is over the limit.
+    pub fn check(&self, key: ReportKey) -> Result<(), ClientLogError> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        key: ReportKey,
+        now: Instant,
+    ) -> Result<(), ClientLogError> {
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() >= PRUNE_THRESHOLD {
+            let window = self.window;
+            windows
+                .retain(|_, w| now.saturating_duration_since(w.start) < window);
+        }
+
+        let entry = windows.entry(key).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.saturating_duration_since(entry.start) >= self.window {
+            *entry = Window {
+                start: now,
+                count: 0,
+            };
+        }
+
+        if entry.count >= self.limit {
+            let retry_after = (entry.start + self.window) - now;
+            return Err(ClientLogError::RateLimited(retry_after));
+        }
+        entry.count += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use crate::client_log::ClientLogError;
+
+    use super::{ReportKey, ReportLimiter};
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_limit() {
+        let limiter = ReportLimiter::new(1024, 2, WINDOW);
+        let key = ReportKey::User(Uuid::new_v4());
+        let now = Instant::now();
+
+        limiter.check_at(key, now).unwrap();
+        limiter.check_at(key, now).unwrap();
+
+        let later = now + Duration::from_secs(15);
+        match limiter.check_at(key, later) {
+            Err(ClientLogError::RateLimited(retry_after)) => {
+                assert_eq!(retry_after, WINDOW - Duration::from_secs(15))
+            }
+            res => panic!("expected report to be limited, got {res:?}"),
+        }
+        limiter.check_at(ReportKey::Anonymous, later).unwrap();
+
+        limiter.check_at(key, now + WINDOW).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Sqlite, Type};
+use uuid::Uuid;
+
+use self::repository::ClientLogRepository;
+
+pub mod limiter;
+pub mod repository;
+pub mod routes;
+
+/// Upper bound of the time reports outlive the retention.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientLogError {
+    #[error("the report exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("too many reports, retry in {}s", .0.as_secs().max(1))]
+    RateLimited(Duration),
+    #[error("invalid report: {0}")]
+    InvalidReport(&'static str),
+    #[error("sqlx error: {0}")]
+    Sqlx(sqlx::Error),
+}
+
+impl ClientLogError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ClientLogError::TooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            ClientLogError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            ClientLogError::InvalidReport(..) => StatusCode::BAD_REQUEST,
+            ClientLogError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    ClientLogError {
+        TooLarge(..) => 1, "the report exceeds the maximum size";
+        RateLimited(..) => 2,
+            "too many reports, see the `retry-after` header";
+        InvalidReport(..) => 3, "the report is invalid";
+        Sqlx(..) => 4, "database error";
+    }
+}
+
+/// An error report submitted by a client, kept for operator review.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientLog {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// The authenticated user that submitted the report, if any.
+    pub user_id: Option<Uuid>,
+    /// The `x-request-id` of the request that failed.
+    pub request_id: Option<String>,
+    /// The kind of client, like `web` or `cli`.
+    pub source: String,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Data of a new [`ClientLog`], besides what is taken from the request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientLogData {
+    pub request_id: Option<String>,
+    pub source: String,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+}
+
+impl ClientLogData {
+    const MAX_REQUEST_ID_LEN: usize = 128;
+    const MAX_SOURCE_LEN: usize = 32;
+
+    pub fn validate(&self) -> Result<(), ClientLogError> {
+        if self.message.trim().is_empty() {
+            return Err(ClientLogError::InvalidReport("empty message"));
+        }
+        if self.source.is_empty()
+            || self.source.len() > Self::MAX_SOURCE_LEN
+            || !self
+                .source
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        {
+            return Err(ClientLogError::InvalidReport("invalid source"));
+        }
+        if self.request_id.as_ref().is_some_and(|id| {
+            id.is_empty()
+                || id.len() > Self::MAX_REQUEST_ID_LEN
+                || !id.bytes().all(|c| c.is_ascii_graphic())
+        }) {
+            return Err(ClientLogError::InvalidReport("invalid request id"));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for ClientLog
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+    Option<Vec<u8>>: Decode<'r, R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let id: Vec<u8> = row.try_get("id")?;
+        let id: [u8; 16] = id.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `id` uuid out of range".into())
+        })?;
+        let id = Uuid::from_bytes(id);
+
+        let created_at: i64 = row.try_get("created_at")?;
+        let created_at = DateTime::from_timestamp_millis(created_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `created_at` field gone wrong".into(),
+                )
+            })?;
+
+        let user_id: Option<Vec<u8>> = row.try_get("user_id")?;
+        let user_id = user_id
+            .map(|user_id| {
+                let user_id: [u8; 16] = user_id.try_into().map_err(|_| {
+                    sqlx::Error::Decode(
+                        "parse `user_id` uuid out of range".into(),
+                    )
+                })?;
+                Ok::<_, sqlx::Error>(Uuid::from_bytes(user_id))
+            })
+            .transpose()?;
+
+        let details: Option<String> = row.try_get("details")?;
+        let details = details
+            .map(|details| {
+                serde_json::from_str(&details).map_err(|err| {
+                    sqlx::Error::Decode(
+                        format!("parse `details`: {err}").into(),
+                    )
+                })
+            })
+            .transpose()?;
+
+        Ok(ClientLog {
+            id,
+            created_at,
+            user_id,
+            request_id: row.try_get("request_id")?,
+            source: row.try_get("source")?,
+            message: row.try_get("message")?,
+            details,
+            user_agent: row.try_get("user_agent")?,
+            ip: row.try_get("ip")?,
+        })
+    }
+}
+
+/// Periodically removes the reports older than `retention`.
+pub async fn run_prune(repo: ClientLogRepository<Sqlite>, retention: Duration) {
+    let mut interval = tokio::time::interval(retention.min(PRUNE_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let Some(before) = TimeDelta::from_std(retention)
+            .ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+        else {
+            continue;
+        };
+
+        match repo.delete_before(before).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "pruned client logs"),
+            Err(error) => {
+                tracing::error!(%error, "failed to prune client logs")
+            }
+        }
+    }
+}
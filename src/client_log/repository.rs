@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use super::{ClientLog, ClientLogData, ClientLogError};
+
+pub struct ClientLogRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for ClientLogRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> ClientLogRepository<DB> {
+    pub fn new(db: Pool<DB>) -> ClientLogRepository<DB> {
+        ClientLogRepository { db }
+    }
+}
+
+impl<DB> ClientLogRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> ClientLog: FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+    for<'e> Option<&'e [u8]>: Encode<'e, DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+    for<'e> Option<String>: Encode<'e, DB>,
+    String: Type<DB>,
+{
+    pub async fn create(
+        &self,
+        user_id: Option<Uuid>,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+        data: ClientLogData,
+    ) -> Result<ClientLog, ClientLogError> {
+        let user_id = user_id.map(|id| id.into_bytes());
+
+        sqlx::query_as(
+            "INSERT INTO client_log \
+            (id, created_at, user_id, request_id, source, message, details, \
+            user_agent, ip) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+            RETURNING *",
+        )
+        .bind(Uuid::new_v4().into_bytes().as_slice())
+        .bind(Utc::now().timestamp_millis())
+        .bind(user_id.as_ref().map(|id| id.as_slice()))
+        .bind(data.request_id.as_deref())
+        .bind(data.source.as_str())
+        .bind(data.message.as_str())
+        .bind(data.details.map(|details| details.to_string()))
+        .bind(user_agent)
+        .bind(ip)
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while creating client log");
+            ClientLogError::Sqlx(error)
+        })
+    }
+
+    /// Most recent reports first, only the ones of `request_id` if given.
+    pub async fn get_all(
+        &self,
+        request_id: Option<&str>,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<ClientLog>, ClientLogError> {
+        sqlx::query_as(
+            "SELECT * FROM client_log \
+            WHERE $1 IS NULL OR request_id = $1 \
+            ORDER BY created_at DESC, rowid DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(request_id)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving client logs",
+            );
+            ClientLogError::Sqlx(error)
+        })
+    }
+
+    pub async fn delete_before(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<usize, ClientLogError> {
+        let ids: Vec<(Vec<u8>,)> = sqlx::query_as(
+            "DELETE FROM client_log WHERE created_at < $1 RETURNING id",
+        )
+        .bind(before.timestamp_millis())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while deleting client logs",
+            );
+            ClientLogError::Sqlx(error)
+        })?;
+
+        Ok(ids.len())
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use axum::{
+    body::to_bytes,
+    http::{header, HeaderMap, StatusCode},
+    routing, Extension, Router,
+};
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Permission, Token},
+    errors::DownloaderError,
+    proxy::ClientIp,
+    utils::extractors::{Body, Envelope, Page, Paged, Query},
+};
+
+use super::{
+    limiter::{ReportKey, ReportLimiter},
+    repository::ClientLogRepository,
+    ClientLog, ClientLogData, ClientLogError,
+};
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 200;
+const MAX_USER_AGENT_LEN: usize = 256;
+
+pub fn client_log_routes<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.route("/", routing::post(post_client_log).get(get_client_logs))
+}
+
+/// Stores an error report of a client. Authentication is optional, as
+/// failing to authenticate is often what is being reported.
+pub async fn post_client_log(
+    authorization: Option<Authorization>,
+    ClientIp(ip): ClientIp,
+    Extension(repo): Extension<ClientLogRepository<Sqlite>>,
+    Extension(limiter): Extension<Arc<ReportLimiter>>,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> Result<(StatusCode, Body<ClientLog>), DownloaderError> {
+    let user_id = match authorization {
+        Some(Authorization(Token::User(token))) => Some(token.user_id),
+        _ => None,
+    };
+    let key = match (user_id, ip) {
+        (Some(user_id), _) => ReportKey::User(user_id),
+        (None, Some(ip)) => ReportKey::Ip(ip),
+        (None, None) => ReportKey::Anonymous,
+    };
+    limiter.check(key)?;
+
+    let max_size = limiter.max_size();
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_size) {
+        return Err(ClientLogError::TooLarge(max_size).into());
+    }
+
+    let body = to_bytes(body, max_size)
+        .await
+        .map_err(|_| ClientLogError::TooLarge(max_size))?;
+    let data: ClientLogData = serde_json::from_slice(&body).map_err(|e| {
+        DownloaderError::Other(
+            format!("invalid report: {e}"),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+    data.validate()?;
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| match v.char_indices().nth(MAX_USER_AGENT_LEN) {
+            Some((i, _)) => &v[..i],
+            None => v,
+        });
+
+    let log = repo
+        .create(
+            user_id,
+            user_agent,
+            ip.map(|ip| ip.to_string()).as_deref(),
+            data,
+        )
+        .await?;
+
+    tracing::warn!(
+        id = %log.id,
+        source = log.source,
+        request_id = log.request_id,
+        message = log.message,
+        "received client error report",
+    );
+
+    Ok((StatusCode::CREATED, Body(log)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientLogQuery {
+    pub request_id: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+pub async fn get_client_logs(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ClientLogRepository<Sqlite>>,
+    envelope: Envelope,
+    Query(query): Query<ClientLogQuery>,
+) -> Result<Paged<ClientLog>, DownloaderError> {
+    if !token.permission().contains(Permission::ADMIN) {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    let logs = repo
+        .get_all(query.request_id.as_deref(), limit, offset)
+        .await?;
+
+    Ok(envelope.wrap(Page::new(logs, limit, offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        Extension, Router,
+    };
+    use serde_json::json;
+    use sqlx::{migrate, SqlitePool};
+    use test_log::test;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::{
+        auth::{repository::tests::repository as token_repository, Permission},
+        client_log::{
+            limiter::ReportLimiter, repository::ClientLogRepository, ClientLog,
+        },
+    };
+
+    use super::client_log_routes;
+
+    async fn router(rate_limit: u32) -> (Router, String) {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let token_repo = Arc::new(token_repository());
+        let admin_token = token_repo
+            .generate_user_token(Uuid::new_v4(), Permission::ADMIN, "a".into())
+            .unwrap();
+
+        let router = client_log_routes(Router::new())
+            .layer(Extension(ClientLogRepository::new(db)))
+            .layer(Extension(Arc::new(ReportLimiter::new(
+                256,
+                rate_limit,
+                Duration::from_secs(60),
+            ))))
+            .layer(Extension(token_repo));
+
+        (router, admin_token)
+    }
+
+    fn report(body: impl Into<Body>) -> Request<Body> {
+        Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::USER_AGENT, "downloader-cli/1.0")
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_client_logs() {
+        let (router, admin_token) = router(10).await;
+
+        for request_id in ["req-1", "req-2"] {
+            let body = json!({
+                "request_id": request_id,
+                "source": "cli",
+                "message": "upload failed",
+                "details": { "status": 500 },
+            });
+            let res = router
+                .clone()
+                .oneshot(report(body.to_string()))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+
+        let res = router
+            .clone()
+            .oneshot(report(r#"{"source": "cli", "message": " "}"#))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let message = "a".repeat(512);
+        let body = json!({ "source": "web", "message": message });
+        let res = router
+            .clone()
+            .oneshot(report(body.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = router
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = router
+            .oneshot(
+                Request::get("/?request_id=req-2")
+                    .header(
+                        header::AUTHORIZATION,
+                        format!("Bearer {admin_token}"),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let logs: Vec<ClientLog> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].request_id.as_deref(), Some("req-2"));
+        assert_eq!(logs[0].user_agent.as_deref(), Some("downloader-cli/1.0"));
+        assert_eq!(logs[0].details, Some(json!({ "status": 500 })));
+    }
+
+    #[test(tokio::test)]
+    async fn test_client_logs_rate_limit() {
+        let (router, _) = router(1).await;
+        let body = r#"{"source": "web", "message": "failed"}"#;
+
+        let res = router.clone().oneshot(report(body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = router.oneshot(report(body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+    }
+}
@@ -1,21 +1,38 @@
 use std::{
     fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     time::Duration,
 };
 
-use clap::Parser;
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+use ipnet::IpNet;
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::utils::serde::{
-    base64, deserialize_socket_addr, duration_secs, ResolvedFile, ResolvedPath,
+use crate::{
+    auth::Permission,
+    database::Synchronous,
+    storage::{
+        backend::BackendKind, manager::DataLayout, name::NameStrictness,
+        pipeline::StepKind, scan::ScanAction, slug::IdExposure,
+    },
+    user::{password::PasswordAlgorithm, DeletePolicy},
+    utils::serde::{
+        base64, deserialize_ip_nets, deserialize_socket_addr, duration_secs,
+        ResolvedFile, ResolvedPath,
+    },
 };
 
 pub const DEFAULT_HTTP_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080);
 pub const DEFAULT_TCP_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 7777);
-pub const DEFAULT_TEMP_DIR: &'static str = "/tmp/downloader";
+pub const DEFAULT_REDIRECT_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 80);
+pub const DEFAULT_TEMP_DIR: &str = "/tmp/downloader";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -31,16 +48,35 @@ pub struct Args {
         default_value_t = String::from("/etc/downloader/config.toml"),
     )]
     pub config_path: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Writes a snapshot of the database and the object manifest to a
+    /// tarball at `path`.
+    Backup { path: PathBuf },
+    /// Replaces the database with the one of the backup at `path`. The
+    /// server must not be running.
+    Restore { path: PathBuf },
+    /// Moves the data of all the objects to the `data_layout` of the config.
+    /// The server must not be running.
+    MigrateLayout,
 }
 
 pub fn load(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
     let file = fs::read_to_string(path)?;
 
-    if path.ends_with(".json") {
-        serde_json::from_str(&file).map_err(Into::into)
+    let cfg: Config = if path.ends_with(".json") {
+        serde_json::from_str(&file)?
     } else {
-        toml::from_str(&file).map_err(Into::into)
-    }
+        toml::from_str(&file)?
+    };
+
+    cfg.validate()?;
+    Ok(cfg)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,7 +84,99 @@ pub struct Config {
     pub net: NetConfig,
     pub ssl: SslConfig,
     pub storage: StorageConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    pub backup: Option<BackupConfig>,
+    /// Enables scheduled database maintenance when present.
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Enables scanning the data of objects for malware when present.
+    pub scanning: Option<ScanningConfig>,
+    #[serde(default)]
+    pub client_logs: ClientLogConfig,
+    /// Enables exporting traces when present.
+    pub telemetry: Option<TelemetryConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Renders errors as pages for browsers when present.
+    pub branding: Option<BrandingConfig>,
+}
+
+impl Config {
+    /// Checks constraints between values that can not be expressed by the
+    /// types alone.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.net.enable_http && self.net.unix_socket.is_none() {
+            return Err(
+                "`net.enable_http` or `net.unix_socket` must be set".into()
+            );
+        }
+        if cfg!(not(unix)) && self.net.unix_socket.is_some() {
+            return Err(
+                "`net.unix_socket` is not supported on this platform".into()
+            );
+        }
+        if self.ssl.redirect_http && !self.ssl.enable {
+            return Err("`ssl.redirect_http` requires `ssl.enable`".into());
+        }
+        if self.net.unix_socket_mode > 0o777 {
+            return Err("`net.unix_socket_mode` must be at most 0o777".into());
+        }
+        if self.auth.user_token_duration.is_zero() {
+            return Err("`auth.user_token_duration` must not be zero".into());
+        }
+        if self.auth.max_file_token_duration.is_zero() {
+            return Err(
+                "`auth.max_file_token_duration` must not be zero".into()
+            );
+        }
+        let durations = &self.auth.token_durations;
+        let overrides = [
+            durations.admin,
+            durations.unprivileged,
+            durations.remember_me,
+        ];
+        if overrides.iter().flatten().any(Duration::is_zero)
+            || durations.users.iter().any(|user| user.duration.is_zero())
+        {
+            return Err("`auth.token_durations` must not be zero".into());
+        }
+        if let Some(backup) = &self.backup {
+            if backup.interval.is_zero() {
+                return Err("`backup.interval` must not be zero".into());
+            }
+            if backup.keep == 0 {
+                return Err("`backup.keep` must not be zero".into());
+            }
+        }
+        if let Some(maintenance) = &self.maintenance {
+            if maintenance.interval.is_zero() {
+                return Err("`maintenance.interval` must not be zero".into());
+            }
+        }
+        if self.logging.access_log && self.logging.keep == 0 {
+            return Err("`logging.keep` must not be zero".into());
+        }
+        if self.storage.backend == BackendKind::Memory
+            && !self.database.sqlite.in_memory
+        {
+            // The database would still list the objects after a restart
+            return Err("`storage.backend = \"memory\"` requires \
+                `database.sqlite.in_memory`"
+                .into());
+        }
+        if let Some(telemetry) = &self.telemetry {
+            if !(0.0..=1.0).contains(&telemetry.sample_ratio) {
+                return Err(
+                    "`telemetry.sample_ratio` must be between 0 and 1".into()
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +196,47 @@ pub struct NetConfig {
         deserialize_with = "deserialize_socket_addr"
     )]
     pub tpc_addr: SocketAddr,
+
+    /// Directory the frontend is served from instead of the embedded one.
+    #[serde(default)]
+    pub frontend_dir: Option<ResolvedPath>,
+
+    /// Peers whose `Forwarded` and `X-Forwarded-For` headers are trusted to
+    /// tell the address of the client.
+    #[serde(default, deserialize_with = "deserialize_ip_nets")]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Path of a unix socket also listened on for http connections, whose
+    /// peers are trusted like `trusted_proxies`.
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions the unix socket is created with.
+    #[serde(default = "default_unix_socket_mode")]
+    pub unix_socket_mode: u32,
+
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+}
+
+/// Limits on how long requests take to be responded to. Responses streamed
+/// after that, like downloads, are not limited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutConfig {
+    /// Limit of api requests, disabled when zero.
+    #[serde(with = "duration_secs", default = "default_api_timeout")]
+    pub api: Duration,
+    /// Limit of the requests streaming uploads and downloads, disabled when
+    /// zero.
+    #[serde(with = "duration_secs", default)]
+    pub transfer: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            api: default_api_timeout(),
+            transfer: Duration::ZERO,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,6 +245,18 @@ pub struct SslConfig {
     pub enable: bool,
     pub cert: Option<ResolvedFile>,
     pub key: Option<ResolvedFile>,
+
+    /// Redirects plain http requests on `redirect_addr` to https.
+    #[serde(default = "default_false")]
+    pub redirect_http: bool,
+    #[serde(
+        default = "default_redirect_addr",
+        deserialize_with = "deserialize_socket_addr"
+    )]
+    pub redirect_addr: SocketAddr,
+    /// Directory ACME http-01 challenges are served from by the redirect
+    /// listener, as written by `certbot certonly --webroot`.
+    pub acme_webroot: Option<ResolvedPath>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,22 +265,533 @@ pub struct StorageConfig {
     pub data_dir: ResolvedPath,
     #[serde(default = "default_temp_dir")]
     pub temp_dir: ResolvedPath,
+    #[serde(default)]
+    pub on_user_delete: DeletePolicy,
+
+    /// Amount of object metadata entries kept in memory, disabled when zero.
+    #[serde(default)]
+    pub object_cache_size: u32,
+    #[serde(with = "duration_secs", default = "default_object_cache_ttl")]
+    pub object_cache_ttl: Duration,
+
+    /// Maximum duration of an upload, unlimited when zero.
+    #[serde(with = "duration_secs", default = "default_upload_timeout")]
+    pub upload_timeout: Duration,
+    /// Uploads slower than this many bytes per second over a whole
+    /// `upload_rate_window` are aborted, disabled when zero.
+    #[serde(default = "default_upload_min_rate")]
+    pub upload_min_rate: u64,
+    #[serde(with = "duration_secs", default = "default_upload_rate_window")]
+    pub upload_rate_window: Duration,
+    /// Whether writes of an object wait for the one in progress to finish,
+    /// otherwise they fail with a conflict.
+    #[serde(default = "default_true")]
+    pub wait_for_writes: bool,
+    /// How long deleted objects can be restored before their data is
+    /// removed, disabled when zero.
+    #[serde(with = "duration_secs", default)]
+    pub undelete_window: Duration,
+    /// Enables fetching files from remote urls when present.
+    #[serde(default)]
+    pub fetch: Option<FetchConfig>,
+    /// Whether objects are identified by their uuids or by slugs mapped to
+    /// them, keeping the uuids internal.
+    #[serde(default)]
+    pub expose_ids: IdExposure,
+    /// How often the download counters of objects are written to the
+    /// database, at least every second.
+    #[serde(with = "duration_secs", default = "default_stats_flush_interval")]
+    pub stats_flush_interval: Duration,
+    /// How often the written downloads are rolled up into the downloads
+    /// per day and per hour, at least every second.
+    #[serde(with = "duration_secs", default = "default_stats_rollup_interval")]
+    pub stats_rollup_interval: Duration,
+    /// How long the downloads are kept one by one after being rolled up,
+    /// removed right away when zero.
+    #[serde(with = "duration_secs", default = "default_download_retention")]
+    pub download_retention: Duration,
+    /// Validation of the names given to objects when uploaded or renamed.
+    #[serde(default)]
+    pub names: NameConfig,
+    /// How often the incomplete objects left behind by interrupted uploads
+    /// are removed, only on startup when zero.
+    #[serde(with = "duration_secs", default = "default_temp_sweep_interval")]
+    pub temp_sweep_interval: Duration,
+    /// How long incomplete objects must have been left untouched before
+    /// being removed.
+    #[serde(with = "duration_secs", default = "default_temp_max_age")]
+    pub temp_max_age: Duration,
+    /// How the object data is laid out in `data_dir`.
+    #[serde(default)]
+    pub data_layout: DataLayout,
+    /// Hints given to the filesystem when reading and writing object data.
+    #[serde(default)]
+    pub io: IoConfig,
+    /// Keeps the data of small objects in memory when present.
+    #[serde(default)]
+    pub cache: Option<DataCacheConfig>,
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Steps run on the data of objects once stored, in order.
+    #[serde(default)]
+    pub post_process: Vec<StepKind>,
+    #[serde(default)]
+    pub upload_concurrency: UploadConcurrencyConfig,
+}
+
+/// Caps on the uploads stored at once, so parallel writes do not thrash the
+/// disks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadConcurrencyConfig {
+    /// Uploads of all users, unlimited when zero.
+    #[serde(default)]
+    pub global: usize,
+    /// Uploads of each user, unlimited when zero. Uploads authorized by
+    /// file or server tokens, or to presigned urls of existing files, only
+    /// count towards `global`.
+    #[serde(default)]
+    pub per_user: usize,
+    /// How long uploads wait for a slot before failing, not at all when
+    /// zero.
+    #[serde(with = "duration_secs", default)]
+    pub queue_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataCacheConfig {
+    /// Total bytes of object data kept in memory.
+    #[serde(default = "default_data_cache_max_size")]
+    pub max_size: u64,
+    /// Objects larger than this are always read from the disk.
+    #[serde(default = "default_data_cache_max_object_size")]
+    pub max_object_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IoConfig {
+    /// Whether the space of uploads of known size is allocated before
+    /// writing them, reducing fragmentation.
+    #[serde(default = "default_true")]
+    pub preallocate: bool,
+    /// Whether downloads are read with a larger readahead.
+    #[serde(default = "default_true")]
+    pub sequential_reads: bool,
+    /// Objects at least this large are written to disk and dropped from the
+    /// page cache once stored, so they do not evict hotter data, disabled
+    /// when zero.
+    #[serde(default = "default_drop_cache_size")]
+    pub drop_cache_size: u64,
+    /// Bytes of uploads buffered before being written to the file.
+    #[serde(default = "default_write_buffer_size")]
+    pub write_buffer_size: usize,
+    /// How often the buffered bytes of uploads are written even if the
+    /// buffer is not full, checked as data arrives, only when full if zero.
+    #[serde(with = "duration_secs", default)]
+    pub flush_interval: Duration,
+    /// Consecutive upload chunks smaller than this are merged before being
+    /// hashed and buffered, disabled when zero.
+    #[serde(default = "default_coalesce_size")]
+    pub coalesce_size: usize,
+}
+
+impl Default for IoConfig {
+    fn default() -> Self {
+        Self {
+            preallocate: true,
+            sequential_reads: true,
+            drop_cache_size: default_drop_cache_size(),
+            write_buffer_size: default_write_buffer_size(),
+            flush_interval: Duration::ZERO,
+            coalesce_size: default_coalesce_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameConfig {
+    /// Maximum length in bytes of a name, folders included, unlimited when
+    /// zero.
+    #[serde(default = "default_name_max_length")]
+    pub max_length: usize,
+    #[serde(default)]
+    pub strictness: NameStrictness,
+    /// Turns backslashes into slashes and removes the empty segments of the
+    /// names before validating them.
+    #[serde(default = "default_false")]
+    pub normalize_separators: bool,
+}
+
+impl Default for NameConfig {
+    fn default() -> Self {
+        Self {
+            max_length: default_name_max_length(),
+            strictness: NameStrictness::default(),
+            normalize_separators: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchConfig {
+    /// Hosts files can be fetched from, any when empty. Entries starting
+    /// with `*.` match all the subdomains.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Allows fetching from loopback, private and link local addresses.
+    #[serde(default = "default_false")]
+    pub allow_private: bool,
+    #[serde(default = "default_fetch_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_fetch_max_redirects")]
+    pub max_redirects: usize,
+    #[serde(with = "duration_secs", default = "default_fetch_connect_timeout")]
+    pub connect_timeout: Duration,
+    /// Fetches each user can have in progress at once, any when zero.
+    #[serde(default)]
+    pub max_jobs_per_user: usize,
+    /// Bytes each user can fetch per day, unlimited when zero.
+    #[serde(default)]
+    pub daily_max_bytes_per_user: u64,
+    /// Folders files can be fetched into, any when empty. `{username}` is
+    /// replaced by the name of the user fetching.
+    #[serde(default)]
+    pub allowed_folders: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqliteConfig {
+    /// Whether the write-ahead log is used, letting reads run along with a
+    /// write.
+    #[serde(default = "default_true")]
+    pub wal: bool,
+    /// How long queries wait for a locked database before failing.
+    #[serde(with = "duration_secs", default = "default_sqlite_busy_timeout")]
+    pub busy_timeout: Duration,
+    #[serde(default)]
+    pub synchronous: Synchronous,
+    /// Connections open at once at most.
+    #[serde(default = "default_sqlite_max_connections")]
+    pub max_connections: u32,
+    /// How often the query planner statistics are refreshed and the
+    /// write-ahead log is checkpointed, never when zero.
+    #[serde(
+        with = "duration_secs",
+        default = "default_sqlite_optimize_interval"
+    )]
+    pub optimize_interval: Duration,
+    /// Whether the database is kept in memory instead of the state dir,
+    /// losing it on restart like the `memory` storage backend.
+    #[serde(default)]
+    pub in_memory: bool,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            wal: true,
+            busy_timeout: default_sqlite_busy_timeout(),
+            synchronous: Synchronous::default(),
+            max_connections: default_sqlite_max_connections(),
+            optimize_interval: default_sqlite_optimize_interval(),
+            in_memory: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobConfig {
+    /// Amount of background jobs run at the same time.
+    #[serde(default = "default_job_workers")]
+    pub workers: usize,
+    /// How long finished jobs are kept, forever when zero.
+    #[serde(with = "duration_secs", default = "default_job_retention")]
+    pub retention: Duration,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_job_workers(),
+            retention: default_job_retention(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientLogConfig {
+    /// Maximum size in bytes of a report.
+    #[serde(default = "default_client_log_max_size")]
+    pub max_size: usize,
+    /// Reports accepted from each client per minute.
+    #[serde(default = "default_client_log_rate_limit")]
+    pub rate_limit: u32,
+    /// How long reports are kept, forever when zero.
+    #[serde(with = "duration_secs", default = "default_client_log_retention")]
+    pub retention: Duration,
+}
+
+impl Default for ClientLogConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_client_log_max_size(),
+            rate_limit: default_client_log_rate_limit(),
+            retention: default_client_log_retention(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Directory periodic backups are written to.
+    pub dir: ResolvedPath,
+    #[serde(with = "duration_secs", default = "default_backup_interval")]
+    pub interval: Duration,
+    /// Amount of backups kept in `dir`, older ones are removed.
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// Minimum time between scheduled runs.
+    #[serde(with = "duration_secs", default = "default_maintenance_interval")]
+    pub interval: Duration,
+    /// Times of the day, in UTC, scheduled runs may start at. Any time when
+    /// empty.
+    #[serde(default)]
+    pub windows: Vec<MaintenanceWindow>,
+    /// Free pages returned to the filesystem by each run, all when zero.
+    #[serde(default)]
+    pub vacuum_pages: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanningConfig {
+    /// Address of clamd, either the path of its unix socket or `host:port`.
+    pub clamd: String,
+    /// What is done to infected objects.
+    #[serde(default)]
+    pub action: ScanAction,
+    /// Time a single scan may take before it is retried.
+    #[serde(with = "duration_secs", default = "default_scan_timeout")]
+    pub timeout: Duration,
+    /// Time between checks for objects not scanned yet.
+    #[serde(with = "duration_secs", default = "default_scan_interval")]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub start: NaiveTime,
+    /// Before `start` for windows that cross midnight.
+    pub end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// Writes a line for each request to `logs/access.log` in the state
+    /// directory.
+    #[serde(default = "default_false")]
+    pub access_log: bool,
+    /// Size in bytes the access log is rotated at, disabled when zero.
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    /// Age the access log is rotated at, disabled when zero.
+    #[serde(with = "duration_secs", default = "default_log_max_age")]
+    pub max_age: Duration,
+    /// Amount of rotated access logs kept, older ones are removed.
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            access_log: false,
+            max_size: default_log_max_size(),
+            max_age: default_log_max_age(),
+            keep: default_log_keep(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP endpoint the traces are sent to, usually ending with
+    /// `/v1/traces`.
+    pub endpoint: String,
+    /// Fraction of the requests traced.
+    #[serde(default = "default_telemetry_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "default_telemetry_service_name")]
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrandingConfig {
+    #[serde(default = "default_product_name")]
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    /// Directory with `error.html`, `not_found.html`, `embargo.html` and
+    /// `share.html` templates replacing the default pages.
+    pub templates_dir: Option<ResolvedPath>,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        Self {
+            product_name: default_product_name(),
+            logo_url: None,
+            templates_dir: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthConfig {
-    pub token_cert: ResolvedFile,
+    #[serde(default = "default_token_algorithm")]
+    pub token_algorithm: Algorithm,
+    /// Certificate of `token_key`, not used by HMAC algorithms.
+    pub token_cert: Option<ResolvedFile>,
+    /// The private key or, for HMAC algorithms, the secret.
     pub token_key: ResolvedFile,
-    #[serde(with = "duration_secs", default = "default_token_duration")]
-    pub token_duration: Duration,
-    #[serde(with = "duration_secs", default = "default_max_token_duration")]
-    pub max_token_duration: Duration,
+    /// Certificates of retired signing keys, whose tokens are still
+    /// accepted until they expire.
+    #[serde(default)]
+    pub retired_token_certs: Vec<ResolvedFile>,
+    /// Lifetime of the tokens issued on login.
+    #[serde(
+        alias = "token_duration",
+        with = "duration_secs",
+        default = "default_user_token_duration"
+    )]
+    pub user_token_duration: Duration,
+    /// Upper bound of the lifetime requested for file tokens.
+    #[serde(
+        alias = "max_token_duration",
+        with = "duration_secs",
+        default = "default_max_file_token_duration"
+    )]
+    pub max_file_token_duration: Duration,
+    /// Overrides of `user_token_duration`.
+    #[serde(default)]
+    pub token_durations: TokenDurationConfig,
 
     #[serde(with = "base64")]
     pub secret_key: Vec<u8>,
 
+    /// Algorithm used to hash new passwords, existing hashes of other
+    /// algorithms are replaced on login.
+    #[serde(default)]
+    pub password_algorithm: PasswordAlgorithm,
+    /// Cost of the bcrypt algorithm.
     #[serde(default = "default_password_hash_cost")]
     pub password_hash_cost: u32,
+    /// Memory cost of the argon2id algorithm in KiB.
+    #[serde(default = "default_argon2_memory_cost")]
+    pub argon2_memory_cost: u32,
+    #[serde(default = "default_argon2_time_cost")]
+    pub argon2_time_cost: u32,
+    #[serde(default = "default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+
+    /// Failed logins in a row before a username or address gets locked.
+    #[serde(default = "default_login_max_failures")]
+    pub login_max_failures: u32,
+    /// Duration of the first lockout, doubled on every further failure.
+    #[serde(with = "duration_secs", default = "default_login_lockout")]
+    pub login_lockout: Duration,
+    #[serde(with = "duration_secs", default = "default_login_max_lockout")]
+    pub login_max_lockout: Duration,
+
+    /// Amount of decoded tokens kept in memory, disabled when zero.
+    #[serde(default)]
+    pub token_cache_size: u32,
+    /// How long a decoded token is kept at most, tokens being valid until
+    /// they expire either way.
+    #[serde(with = "duration_secs", default = "default_token_cache_ttl")]
+    pub token_cache_ttl: Duration,
+
+    pub oidc: Option<OidcConfig>,
+
+    #[serde(default)]
+    pub totp: TotpConfig,
+}
+
+/// Lifetimes of the tokens issued on login to some users. A duration of the
+/// user takes precedence over the one of its permission class.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenDurationConfig {
+    /// Tokens with permissions beyond the unprivileged ones.
+    #[serde(with = "duration_secs::option", default)]
+    pub admin: Option<Duration>,
+    /// Tokens with at most the unprivileged permissions.
+    #[serde(with = "duration_secs::option", default)]
+    pub unprivileged: Option<Duration>,
+    /// Tokens of logins with `remember_me`, bounded by the duration of
+    /// their class if any. The flag is ignored when missing.
+    #[serde(with = "duration_secs::option", default)]
+    pub remember_me: Option<Duration>,
+    #[serde(default)]
+    pub users: Vec<UserTokenDuration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserTokenDuration {
+    pub user_id: Uuid,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpConfig {
+    /// Name of the service shown by authenticator apps.
+    #[serde(default = "default_totp_issuer")]
+    pub issuer: String,
+    /// Requires every user to enroll two-factor authentication. Users that
+    /// did not enroll yet only get tokens able to enroll it.
+    #[serde(default = "default_false")]
+    pub required: bool,
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        Self {
+            issuer: default_totp_issuer(),
+            required: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// The public url of the `/api/auth/oidc/callback` route, registered
+    /// in the identity provider.
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Permission given to users provisioned on their first login.
+    #[serde(default = "default_oidc_permission")]
+    pub default_permission: Permission,
 }
 
 const fn default_false() -> bool {
@@ -118,19 +810,343 @@ const fn default_tcp_addr() -> SocketAddr {
     DEFAULT_TCP_ADDR
 }
 
-const fn default_token_duration() -> Duration {
+const fn default_redirect_addr() -> SocketAddr {
+    DEFAULT_REDIRECT_ADDR
+}
+
+const fn default_api_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_unix_socket_mode() -> u32 {
+    0o660
+}
+
+const fn default_token_algorithm() -> Algorithm {
+    Algorithm::EdDSA
+}
+
+const fn default_user_token_duration() -> Duration {
     Duration::from_secs(3600)
 }
 
-const fn default_max_token_duration() -> Duration {
+const fn default_max_file_token_duration() -> Duration {
     Duration::from_secs(7 * 24 * 3600)
 }
 
+const fn default_login_max_failures() -> u32 {
+    5
+}
+
+const fn default_login_lockout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_login_max_lockout() -> Duration {
+    Duration::from_secs(3600)
+}
+
+const fn default_token_cache_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_object_cache_ttl() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_stats_flush_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_stats_rollup_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+const fn default_download_retention() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_temp_sweep_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+const fn default_temp_max_age() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_drop_cache_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+const fn default_write_buffer_size() -> usize {
+    1024 * 1024
+}
+
+const fn default_coalesce_size() -> usize {
+    64 * 1024
+}
+
+const fn default_data_cache_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+const fn default_data_cache_max_object_size() -> u64 {
+    1024 * 1024
+}
+
+const fn default_sqlite_busy_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_sqlite_max_connections() -> u32 {
+    10
+}
+
+const fn default_sqlite_optimize_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+const fn default_name_max_length() -> usize {
+    255
+}
+
+const fn default_upload_timeout() -> Duration {
+    Duration::from_secs(3600)
+}
+
+const fn default_upload_min_rate() -> u64 {
+    1024
+}
+
+const fn default_upload_rate_window() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_fetch_max_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+const fn default_fetch_max_redirects() -> usize {
+    5
+}
+
+const fn default_fetch_connect_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_job_workers() -> usize {
+    4
+}
+
+const fn default_job_retention() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+const fn default_client_log_max_size() -> usize {
+    16 * 1024
+}
+
+const fn default_client_log_rate_limit() -> u32 {
+    10
+}
+
+const fn default_client_log_retention() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+const fn default_backup_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_backup_keep() -> usize {
+    7
+}
+
+const fn default_maintenance_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_scan_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_scan_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_log_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+const fn default_log_max_age() -> Duration {
+    Duration::from_secs(86400)
+}
+
+const fn default_log_keep() -> usize {
+    7
+}
+
+const fn default_telemetry_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_telemetry_service_name() -> String {
+    "downloader".into()
+}
+
+fn default_product_name() -> String {
+    "Downloader".into()
+}
+
 const fn default_password_hash_cost() -> u32 {
     bcrypt::DEFAULT_COST
 }
 
+const fn default_argon2_memory_cost() -> u32 {
+    argon2::Params::DEFAULT_M_COST
+}
+
+const fn default_argon2_time_cost() -> u32 {
+    argon2::Params::DEFAULT_T_COST
+}
+
+const fn default_argon2_parallelism() -> u32 {
+    argon2::Params::DEFAULT_P_COST
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".into(), "profile".into(), "email".into()]
+}
+
+fn default_totp_issuer() -> String {
+    "downloader".into()
+}
+
+const fn default_oidc_permission() -> Permission {
+    Permission::UNPRIVILEGED
+}
+
 fn default_temp_dir() -> ResolvedPath {
     ResolvedPath::new(DEFAULT_TEMP_DIR.into())
         .expect("failed to parse default temp path into ResolvedPath")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::Config;
+    use crate::storage::backend::BackendKind;
+
+    fn config(auth: &str) -> Result<Config, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap();
+        let key = dir.path().join("key");
+        std::fs::write(&key, "secret").unwrap();
+
+        let cfg = format!(
+            r#"
+            [net]
+            [ssl]
+            [storage]
+            state_dir = "{dir_path}"
+            data_dir = "{dir_path}"
+            temp_dir = "{dir_path}"
+            [auth]
+            token_algorithm = "HS256"
+            token_key = "{key}"
+            secret_key = "c2VjcmV0"
+            {auth}
+            "#,
+            key = key.to_str().unwrap(),
+        );
+
+        let cfg: Config = toml::from_str(&cfg).map_err(|e| e.to_string())?;
+        cfg.validate().map(|_| cfg)
+    }
+
+    #[test]
+    fn test_token_durations() {
+        let cfg = config("").unwrap();
+        assert_eq!(cfg.auth.user_token_duration, Duration::from_secs(3600));
+
+        let cfg =
+            config("user_token_duration = 60\nmax_file_token_duration = 120")
+                .unwrap();
+        assert_eq!(cfg.auth.user_token_duration, Duration::from_secs(60));
+        assert_eq!(cfg.auth.max_file_token_duration, Duration::from_secs(120));
+
+        // Names used by older configurations
+        let cfg =
+            config("token_duration = 30\nmax_token_duration = 90").unwrap();
+        assert_eq!(cfg.auth.user_token_duration, Duration::from_secs(30));
+        assert_eq!(cfg.auth.max_file_token_duration, Duration::from_secs(90));
+
+        assert!(config("user_token_duration = 0").is_err());
+        assert!(config("max_file_token_duration = 0").is_err());
+
+        let cfg = config(
+            "[auth.token_durations]\nadmin = 600\nremember_me = 86400\n\
+            [[auth.token_durations.users]]\n\
+            user_id = \"67e55044-10b1-426f-9247-bb680e5fe0c8\"\n\
+            duration = 2592000",
+        )
+        .unwrap();
+        let durations = cfg.auth.token_durations;
+        assert_eq!(durations.admin, Some(Duration::from_secs(600)));
+        assert_eq!(durations.unprivileged, None);
+        assert_eq!(durations.remember_me, Some(Duration::from_secs(86400)));
+        assert_eq!(durations.users[0].duration, Duration::from_secs(2592000));
+
+        assert!(config("[auth.token_durations]\nadmin = 0").is_err());
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let telemetry = |s: &str| {
+            config(&format!("[telemetry]\nendpoint = \"http://otel\"\n{s}"))
+        };
+
+        let cfg = telemetry("").unwrap().telemetry.unwrap();
+        assert_eq!(cfg.sample_ratio, 1.0);
+        assert_eq!(cfg.service_name, "downloader");
+
+        assert!(telemetry("sample_ratio = 0.25").is_ok());
+        assert!(telemetry("sample_ratio = 1.5").is_err());
+        assert!(telemetry("sample_ratio = -1.0").is_err());
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        let mut cfg = config("").unwrap();
+        cfg.storage.backend = BackendKind::Memory;
+        assert!(cfg.validate().is_err());
+
+        cfg.database.sqlite.in_memory = true;
+        cfg.validate().unwrap();
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let maintenance = |s: &str| config(&format!("[maintenance]\n{s}"));
+
+        let cfg = maintenance("").unwrap().maintenance.unwrap();
+        assert_eq!(cfg.interval, Duration::from_secs(86400));
+        assert!(cfg.windows.is_empty());
+
+        let cfg = maintenance(
+            "windows = [{ start = \"23:00:00\", end = \"02:30:00\" }]",
+        )
+        .unwrap()
+        .maintenance
+        .unwrap();
+        let window = cfg.windows[0];
+        assert!(window.contains(NaiveTime::from_hms_opt(1, 0, 0).unwrap()));
+        assert!(!window.contains(NaiveTime::from_hms_opt(3, 0, 0).unwrap()));
+
+        assert!(maintenance("interval = 0").is_err());
+        assert!(maintenance("windows = [{ start = \"25:00:00\" }]").is_err());
+    }
+}
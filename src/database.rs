@@ -0,0 +1,163 @@
+use std::{path::Path, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions,
+        SqliteSynchronous,
+    },
+    SqlitePool,
+};
+
+use crate::config::SqliteConfig;
+
+/// How often SQLite waits for writes to reach the disk, trading durability
+/// on power loss for write speed.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Synchronous {
+    Off,
+    /// Safe from corruption in WAL mode, but may lose the last commits on
+    /// power loss.
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl From<Synchronous> for SqliteSynchronous {
+    fn from(value: Synchronous) -> Self {
+        match value {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+/// Opens the database at `path`, or in memory when `cfg` says so, with the
+/// settings of `cfg` applied to every connection of the pool.
+pub async fn open(
+    path: &Path,
+    cfg: &SqliteConfig,
+) -> Result<SqlitePool, sqlx::Error> {
+    let journal_mode = if cfg.wal {
+        SqliteJournalMode::Wal
+    } else {
+        SqliteJournalMode::Delete
+    };
+
+    let options = match cfg.in_memory {
+        true => SqliteConnectOptions::from_str("sqlite::memory:")?,
+        false => SqliteConnectOptions::new().filename(path),
+    }
+    .journal_mode(journal_mode)
+    .busy_timeout(cfg.busy_timeout)
+    .synchronous(cfg.synchronous.into());
+
+    let mut pool =
+        SqlitePoolOptions::new().max_connections(cfg.max_connections);
+    if cfg.in_memory {
+        // The database is gone once its last connection is closed
+        pool = pool
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    }
+    pool.connect_with(options).await
+}
+
+/// Refreshes the query planner statistics that need it and writes back the
+/// write-ahead log without waiting for readers, both cheap enough to run
+/// often, unlike a full maintenance run.
+pub async fn optimize(db: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    sqlx::query("PRAGMA optimize").execute(&mut *conn).await?;
+    sqlx::query("PRAGMA wal_checkpoint(PASSIVE)")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Runs [`optimize`] every `interval`.
+pub async fn run_optimize(db: SqlitePool, interval: Duration) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut interval = tokio::time::interval_at(start, interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(error) = optimize(&db).await {
+            tracing::error!(%error, "failed to optimize the database");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("files.sqlite");
+        std::fs::File::create(&path).unwrap();
+
+        let cfg = SqliteConfig {
+            wal: true,
+            busy_timeout: Duration::from_secs(2),
+            synchronous: Synchronous::Normal,
+            max_connections: 3,
+            optimize_interval: Duration::ZERO,
+            in_memory: false,
+        };
+        let db = open(&path, &cfg).await.unwrap();
+
+        let (mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+        let (timeout,): (i64,) = sqlx::query_as("PRAGMA busy_timeout")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(timeout, 2000);
+        let (synchronous,): (i64,) = sqlx::query_as("PRAGMA synchronous")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(synchronous, 1);
+        assert_eq!(db.options().get_max_connections(), 3);
+
+        optimize(&db).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("files.sqlite");
+
+        let cfg = SqliteConfig {
+            in_memory: true,
+            ..Default::default()
+        };
+        let db = open(&path, &cfg).await.unwrap();
+        assert!(!path.exists());
+
+        // Every connection of the pool sees the same database
+        let mut conn = db.acquire().await.unwrap();
+        sqlx::query("CREATE TABLE test (id INTEGER)")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let mut other = db.acquire().await.unwrap();
+        sqlx::query("SELECT * FROM test")
+            .fetch_all(&mut *other)
+            .await
+            .unwrap();
+    }
+}
@@ -1,17 +1,76 @@
 use axum::{
     body::Body,
-    extract::multipart::MultipartError,
-    http::{header, StatusCode},
+    extract::{multipart::MultipartError, Request},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
 };
+use chrono::{DateTime, Utc};
 use serde::Serialize;
 
 use crate::{
+    admin::AdminError,
     auth::AuthError,
-    storage::{manager::ObjectError, repository::RepositoryError},
+    client_log::ClientLogError,
+    group::GroupError,
+    job::JobError,
+    locale::current_locale,
+    namespace::NamespaceError,
+    server::current_request_id,
+    storage::{
+        fetch::FetchError, manager::ObjectError, name::NameError,
+        repository::RepositoryError, scan::ScanError,
+    },
     user::UserError,
+    utils::extractors::{self, accepts},
 };
 
+/// Media type of RFC 7807 problem details, see [`problem_errors`].
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Code of a variant of an error enum, see [`error_codes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode {
+    pub code: u8,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Implements `custom_code` of an error enum from a table of its variants,
+/// also kept in its `CODES` for the [`error_catalog`]. Codes are part of the
+/// api, so existing ones must never be reassigned.
+macro_rules! error_codes {
+    (
+        $error:ident {
+            $(
+                $variant:ident $(($($tuple:tt)*))? $({$($fields:tt)*})?
+                    => $code:literal, $description:literal;
+            )*
+        }
+    ) => {
+        impl $error {
+            pub const CODES: &'static [$crate::errors::ErrorCode] = &[$(
+                $crate::errors::ErrorCode {
+                    code: $code,
+                    name: stringify!($variant),
+                    description: $description,
+                },
+            )*];
+
+            #[inline]
+            pub fn custom_code(&self) -> u8 {
+                match self {
+                    $(
+                        $error::$variant $(($($tuple)*))? $({$($fields)*})?
+                            => $code,
+                    )*
+                }
+            }
+        }
+    };
+}
+pub(crate) use error_codes;
+
 #[derive(Debug, thiserror::Error)]
 pub enum DownloaderError {
     #[error("Repository error: {0}")]
@@ -22,6 +81,22 @@ pub enum DownloaderError {
     User(#[from] UserError),
     #[error("Auth error: {0}")]
     Auth(#[from] AuthError),
+    #[error("Fetch error: {0}")]
+    Fetch(#[from] FetchError),
+    #[error("Job error: {0}")]
+    Job(#[from] JobError),
+    #[error("Client log error: {0}")]
+    ClientLog(#[from] ClientLogError),
+    #[error("Admin error: {0}")]
+    Admin(#[from] AdminError),
+    #[error("Namespace error: {0}")]
+    Namespace(#[from] NamespaceError),
+    #[error("Group error: {0}")]
+    Group(#[from] GroupError),
+    #[error("Name error: {0}")]
+    Name(#[from] NameError),
+    #[error("Scan error: {0}")]
+    Scan(#[from] ScanError),
 
     #[error("Http error: {0}")]
     Http(#[from] HttpError),
@@ -43,6 +118,14 @@ impl DownloaderError {
             DownloaderError::Object(e) => e.status_code(),
             DownloaderError::User(e) => e.status_code(),
             DownloaderError::Auth(e) => e.status_code(),
+            DownloaderError::Fetch(e) => e.status_code(),
+            DownloaderError::Job(e) => e.status_code(),
+            DownloaderError::ClientLog(e) => e.status_code(),
+            DownloaderError::Admin(e) => e.status_code(),
+            DownloaderError::Namespace(e) => e.status_code(),
+            DownloaderError::Group(e) => e.status_code(),
+            DownloaderError::Name(e) => e.status_code(),
+            DownloaderError::Scan(e) => e.status_code(),
             DownloaderError::Http(e) => e.status_code(),
             DownloaderError::AxumHttp(..) => StatusCode::INTERNAL_SERVER_ERROR,
             DownloaderError::Multipart(e) => e.status(),
@@ -56,6 +139,14 @@ impl DownloaderError {
             DownloaderError::Object(e) => e.custom_code(),
             DownloaderError::User(e) => e.custom_code(),
             DownloaderError::Auth(e) => e.custom_code(),
+            DownloaderError::Fetch(e) => e.custom_code(),
+            DownloaderError::Job(e) => e.custom_code(),
+            DownloaderError::ClientLog(e) => e.custom_code(),
+            DownloaderError::Admin(e) => e.custom_code(),
+            DownloaderError::Namespace(e) => e.custom_code(),
+            DownloaderError::Group(e) => e.custom_code(),
+            DownloaderError::Name(e) => e.custom_code(),
+            DownloaderError::Scan(e) => e.custom_code(),
             DownloaderError::Http(e) => e.custom_code(),
             DownloaderError::AxumHttp(..) => 0,
             DownloaderError::Multipart(..) => 0,
@@ -67,6 +158,14 @@ impl DownloaderError {
             DownloaderError::Object(..) => 2,
             DownloaderError::User(..) => 3,
             DownloaderError::Auth(..) => 4,
+            DownloaderError::Fetch(..) => 5,
+            DownloaderError::Job(..) => 6,
+            DownloaderError::ClientLog(..) => 7,
+            DownloaderError::Admin(..) => 8,
+            DownloaderError::Namespace(..) => 9,
+            DownloaderError::Group(..) => 10,
+            DownloaderError::Name(..) => 11,
+            DownloaderError::Scan(..) => 12,
             DownloaderError::Http(..) => 99,
             DownloaderError::AxumHttp(..) => 100,
             DownloaderError::Multipart(..) => 101,
@@ -77,6 +176,83 @@ impl DownloaderError {
     }
 }
 
+/// Categories of [`DownloaderError::custom_code`], the thousands of the
+/// codes, along with the codes of their variants.
+const CATEGORIES: &[(u32, &str, &[ErrorCode])] = &[
+    (
+        0,
+        "other",
+        &[ErrorCode {
+            code: 0,
+            name: "Other",
+            description: "unclassified error, see the message",
+        }],
+    ),
+    (1, "repository", RepositoryError::CODES),
+    (2, "object", ObjectError::CODES),
+    (3, "user", UserError::CODES),
+    (4, "auth", AuthError::CODES),
+    (5, "fetch", FetchError::CODES),
+    (6, "job", JobError::CODES),
+    (7, "client_log", ClientLogError::CODES),
+    (8, "admin", AdminError::CODES),
+    (9, "namespace", NamespaceError::CODES),
+    (10, "group", GroupError::CODES),
+    (11, "name", NameError::CODES),
+    (12, "scan", ScanError::CODES),
+    (99, "http", HttpError::CODES),
+    (
+        100,
+        "http",
+        &[ErrorCode {
+            code: 0,
+            name: "AxumHttp",
+            description: "failed to build the http response",
+        }],
+    ),
+    (
+        101,
+        "multipart",
+        &[ErrorCode {
+            code: 0,
+            name: "Multipart",
+            description: "the multipart form is malformed",
+        }],
+    ),
+];
+
+/// An `error_code` the api may respond with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CatalogEntry {
+    pub code: u32,
+    pub category: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Every `error_code` the api may respond with, sorted by code.
+pub fn error_catalog() -> Vec<CatalogEntry> {
+    let mut catalog: Vec<_> = CATEGORIES
+        .iter()
+        .flat_map(|&(c, category, codes)| {
+            codes.iter().map(move |code| CatalogEntry {
+                code: (c * 1000) + (code.code as u32),
+                category,
+                name: code.name,
+                description: code.description,
+            })
+        })
+        .collect();
+    catalog.sort_by_key(|entry| entry.code);
+    catalog
+}
+
+/// Lists the codes of the errors of the api, so clients can match on them
+/// instead of on the messages.
+pub async fn get_error_catalog() -> extractors::Body<Vec<CatalogEntry>> {
+    extractors::Body(error_catalog())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum HttpError {
     #[error(
@@ -86,8 +262,14 @@ pub enum HttpError {
     InvalidFormLength { expected: usize, got: usize },
     #[error("the provided form boundary is invalid")]
     InvalidFormBoundary,
+    #[error("the provided multipart metadata is invalid: {0}")]
+    InvalidFormMetadata(String),
+    #[error("the provided batch is invalid: {0}")]
+    InvalidBatch(String),
     #[error("route not found")]
     RouteNotFound,
+    #[error("the request took too long to be responded to")]
+    RequestTimeout,
     #[error("service panicked")]
     ServicePanicked,
 }
@@ -98,26 +280,45 @@ impl HttpError {
         match self {
             HttpError::InvalidFormBoundary => StatusCode::BAD_REQUEST,
             HttpError::InvalidFormLength { .. } => StatusCode::BAD_REQUEST,
+            HttpError::InvalidFormMetadata(..) => StatusCode::BAD_REQUEST,
+            HttpError::InvalidBatch(..) => StatusCode::BAD_REQUEST,
             HttpError::RouteNotFound => StatusCode::NOT_FOUND,
+            HttpError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
             HttpError::ServicePanicked => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+}
 
-    #[inline]
-    pub fn custom_code(&self) -> u8 {
-        match self {
-            HttpError::InvalidFormLength { .. } => 1,
-            HttpError::InvalidFormBoundary => 2,
-            HttpError::RouteNotFound => 100,
-            HttpError::ServicePanicked => 255,
-        }
+error_codes! {
+    HttpError {
+        InvalidFormLength { .. } => 1, "the multipart form length is invalid";
+        InvalidFormBoundary => 2, "the form boundary is invalid";
+        InvalidFormMetadata(..) => 3, "the multipart metadata is invalid";
+        InvalidBatch(..) => 4, "the batch is invalid";
+        RouteNotFound => 100, "route not found";
+        RequestTimeout => 101, "the request took too long to be responded to";
+        ServicePanicked => 255, "service panicked";
     }
 }
 
+/// Kept in the extensions of error responses, so they can be rendered in
+/// other formats, see `branding::html_errors`.
+#[derive(Debug, Clone)]
+pub struct ErrorDetails {
+    pub message: String,
+    pub error_code: u32,
+    /// When the embargoed file becomes available.
+    pub available_from: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     pub error: String,
     pub error_code: u32,
+    /// Also sent in the `x-request-id` header, to correlate reports with
+    /// the server logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
     #[serde(skip_serializing)]
     pub status_code: StatusCode,
 }
@@ -142,11 +343,190 @@ impl IntoResponse for ErrorResponse {
 impl IntoResponse for DownloaderError {
     #[inline]
     fn into_response(self) -> Response {
-        ErrorResponse {
-            error: self.to_string(),
-            error_code: self.custom_code(),
+        let retry_after = match &self {
+            DownloaderError::Auth(AuthError::LoginLocked(d))
+            | DownloaderError::ClientLog(ClientLogError::RateLimited(d)) => {
+                Some(d.as_secs().max(1))
+            }
+            DownloaderError::Auth(AuthError::Embargoed(at)) => {
+                let d = (*at - Utc::now()).to_std().unwrap_or_default();
+                Some(d.as_secs().max(1))
+            }
+            DownloaderError::Object(ObjectError::TooManyUploads) => Some(1),
+            _ => None,
+        };
+
+        let error_code = self.custom_code();
+        let locale = current_locale();
+        let message = locale.message(error_code);
+
+        let details = ErrorDetails {
+            message: message.map_or_else(|| self.to_string(), str::to_owned),
+            error_code,
+            available_from: match &self {
+                DownloaderError::Auth(AuthError::Embargoed(at)) => Some(*at),
+                _ => None,
+            },
+        };
+
+        let mut res = ErrorResponse {
+            error: details.message.clone(),
+            error_code: details.error_code,
+            request_id: current_request_id(),
             status_code: self.status_code(),
         }
-        .into_response()
+        .into_response();
+
+        if let Some(secs) = retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        }
+        if message.is_some() {
+            res.headers_mut().insert(
+                header::CONTENT_LANGUAGE,
+                HeaderValue::from_static(locale.as_str()),
+            );
+        }
+        res.extensions_mut().insert(details);
+        res
+    }
+}
+
+/// Body of error responses in the format of RFC 7807.
+#[derive(Debug, Serialize)]
+pub struct ProblemDetails {
+    /// Always `about:blank`, the `error_code` telling errors apart.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// Path of the request.
+    pub instance: String,
+    pub error_code: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Renders error responses as [`ProblemDetails`] for requests accepting
+/// [`PROBLEM_JSON`], so generic clients can parse them.
+pub async fn problem_errors(req: Request, next: Next) -> Response {
+    if !accepts(req.headers(), PROBLEM_JSON) {
+        return next.run(req).await;
+    }
+    let instance = req.uri().path().to_owned();
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned);
+
+    let res = next.run(req).await;
+    let Some(details) = res.extensions().get::<ErrorDetails>() else {
+        return res;
+    };
+
+    let status = res.status();
+    let problem = ProblemDetails {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error").to_owned(),
+        status: status.as_u16(),
+        detail: details.message.clone(),
+        instance,
+        error_code: details.error_code,
+        request_id,
+    };
+    let Ok(body) = serde_json::to_vec(&problem) else {
+        return res;
+    };
+
+    let (mut parts, _) = res.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, middleware, routing, Router};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn not_found() -> DownloaderError {
+        DownloaderError::Http(HttpError::RouteNotFound)
+    }
+
+    async fn get(uri: &str, accept: &str) -> Response {
+        let router = Router::new()
+            .route("/files/1", routing::get(|| async { not_found() }))
+            .route("/ok", routing::get(|| async { "ok" }))
+            .layer(middleware::from_fn(problem_errors));
+
+        let req = axum::http::Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .header("x-request-id", "req-1")
+            .body(Body::empty())
+            .unwrap();
+        router.oneshot(req).await.unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_problem_errors() {
+        let res = get("/files/1", PROBLEM_JSON).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": not_found().to_string(),
+                "instance": "/files/1",
+                "error_code": not_found().custom_code(),
+                "request_id": "req-1",
+            }),
+        );
+
+        // Left as is otherwise
+        let res = get("/files/1", "application/json").await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let res = get("/ok", PROBLEM_JSON).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[test]
+    fn test_error_catalog() {
+        let catalog = error_catalog();
+        assert!(catalog.windows(2).all(|w| w[0].code < w[1].code));
+
+        let find = |err: DownloaderError| {
+            let code = err.custom_code();
+            catalog
+                .iter()
+                .find(|entry| entry.code == code)
+                .unwrap()
+                .clone()
+        };
+        let entry = find(DownloaderError::Auth(AuthError::ExpiredToken));
+        assert_eq!(
+            entry,
+            CatalogEntry {
+                code: 4004,
+                category: "auth",
+                name: "ExpiredToken",
+                description: "the provided token is expired",
+            },
+        );
+        assert_eq!(find(not_found()).name, "RouteNotFound");
+        let err = DownloaderError::Other("other".into(), StatusCode::CONFLICT);
+        assert_eq!(find(err).code, 0);
     }
 }
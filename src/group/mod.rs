@@ -0,0 +1,161 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Type};
+use uuid::Uuid;
+
+use crate::auth::Permission;
+
+pub mod repository;
+pub mod routes;
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Selects the permissions of the groups of the user `$1`, whose union is
+/// granted on top of the permission of the user, see [`union_permissions`].
+pub const MEMBER_PERMISSIONS_QUERY: &str = "SELECT \"group\".permission \
+    FROM group_member JOIN \"group\" ON \"group\".id = group_member.group_id \
+    WHERE group_member.user_id = $1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum GroupError {
+    #[error("group `{0}` not found")]
+    NotFound(Uuid),
+    #[error("group with name `{0}` already exists")]
+    AlreadyExists(String),
+    #[error("invalid group name, expected 1 to {MAX_NAME_LEN} characters")]
+    InvalidName,
+    #[error("user `{0}` is not a member of the group")]
+    MemberNotFound(Uuid),
+    #[error("user `{0}` not found in the namespace of the group")]
+    UserNotFound(Uuid),
+    #[error("the object is not shared with the group")]
+    NotShared,
+    #[error("sqlx error: {0}")]
+    Sqlx(sqlx::Error),
+}
+
+impl GroupError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            GroupError::NotFound(..) => StatusCode::NOT_FOUND,
+            GroupError::AlreadyExists(..) => StatusCode::CONFLICT,
+            GroupError::InvalidName => StatusCode::BAD_REQUEST,
+            GroupError::MemberNotFound(..) => StatusCode::NOT_FOUND,
+            GroupError::UserNotFound(..) => StatusCode::NOT_FOUND,
+            GroupError::NotShared => StatusCode::NOT_FOUND,
+            GroupError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    GroupError {
+        NotFound(..) => 1, "group not found";
+        AlreadyExists(..) => 2, "a group with the name already exists";
+        InvalidName => 3, "the group name is invalid";
+        MemberNotFound(..) => 4, "the user is not a member of the group";
+        UserNotFound(..) => 5, "the user is not in the namespace of the group";
+        NotShared => 6, "the object is not shared with the group";
+        Sqlx(..) => 7, "database error";
+    }
+}
+
+/// A set of users of a namespace, which get the permission of the group on
+/// top of their own and can read the objects shared with it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub namespace: String,
+    pub name: String,
+    pub permission: Permission,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Group
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let id: Vec<u8> = row.try_get("id")?;
+        let id: [u8; 16] = id.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `id` uuid out of range".into())
+        })?;
+        let id = Uuid::from_bytes(id);
+
+        let created_at: i64 = row.try_get("created_at")?;
+        let created_at = DateTime::from_timestamp_millis(created_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `created_at` field gone wrong".into(),
+                )
+            })?;
+
+        let updated_at: i64 = row.try_get("updated_at")?;
+        let updated_at = DateTime::from_timestamp_millis(updated_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `updated_at` field gone wrong".into(),
+                )
+            })?;
+
+        let permission: i64 = row.try_get("permission")?;
+        let permission: u8 = permission.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `permission` u8 out of range".into())
+        })?;
+        let permission =
+            Permission::from_bits(permission).ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `permission` invalid bitflags".into(),
+                )
+            })?;
+
+        Ok(Self {
+            id,
+            created_at,
+            updated_at,
+            namespace: row.try_get("namespace")?,
+            name: row.try_get("name")?,
+            permission,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupData {
+    pub name: String,
+    pub permission: Permission,
+}
+
+impl GroupData {
+    pub fn validate(&self) -> Result<(), GroupError> {
+        let len = self.name.chars().count();
+        if len == 0 || len > MAX_NAME_LEN || self.name.trim() != self.name {
+            return Err(GroupError::InvalidName);
+        }
+        Ok(())
+    }
+}
+
+/// The union of the permissions selected by [`MEMBER_PERMISSIONS_QUERY`],
+/// ignoring unknown bits.
+pub fn union_permissions(
+    permissions: impl IntoIterator<Item = (i64,)>,
+) -> Permission {
+    permissions
+        .into_iter()
+        .map(|(bits,)| Permission::from_bits_truncate(bits as u8))
+        .fold(Permission::empty(), Permission::union)
+}
@@ -0,0 +1,578 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use crate::{
+    auth::Permission, namespace::DEFAULT_NAMESPACE, user::User,
+    utils::retry::retry_busy,
+};
+
+use super::{
+    union_permissions, Group, GroupData, GroupError, MEMBER_PERMISSIONS_QUERY,
+};
+
+pub struct GroupRepository<DB: Database> {
+    db: Pool<DB>,
+    /// Only the groups in it are visible if set.
+    namespace: Option<Arc<str>>,
+}
+
+impl<DB: Database> Clone for GroupRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            namespace: self.namespace.clone(),
+        }
+    }
+}
+
+impl<DB: Database> GroupRepository<DB> {
+    pub fn new(db: Pool<DB>) -> GroupRepository<DB> {
+        GroupRepository {
+            db,
+            namespace: None,
+        }
+    }
+
+    /// A repository of the groups in `namespace` only, which the new groups
+    /// are created in.
+    pub fn in_namespace(&self, namespace: &str) -> Self {
+        Self {
+            namespace: Some(namespace.into()),
+            ..self.clone()
+        }
+    }
+
+    /// The namespace new groups are created in.
+    pub fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+    }
+}
+
+impl<DB> GroupRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> Group: FromRow<'r, DB::Row>,
+    for<'r> User: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+{
+    pub async fn get_all(&self) -> Result<Vec<Group>, GroupError> {
+        sqlx::query_as(
+            "SELECT * FROM \"group\" \
+            WHERE $1 IS NULL OR namespace = $1 ORDER BY name",
+        )
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)
+    }
+
+    pub async fn get(&self, id: Uuid) -> Result<Group, GroupError> {
+        sqlx::query_as(
+            "SELECT * FROM \"group\" \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2)",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?
+        .ok_or(GroupError::NotFound(id))
+    }
+
+    pub async fn create(&self, data: &GroupData) -> Result<Group, GroupError> {
+        data.validate()?;
+
+        let id = Uuid::new_v4().into_bytes();
+        let now = Utc::now().timestamp_millis();
+
+        retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO \"group\" \
+                (id, created_at, updated_at, namespace, name, permission) \
+                VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            )
+            .bind(id.as_slice())
+            .bind(now)
+            .bind(now)
+            .bind(self.namespace())
+            .bind(data.name.as_str())
+            .bind(data.permission.bits() as i64)
+            .fetch_one(&self.db)
+        })
+        .await
+        .map_err(|error| conflict_error(error, &data.name))
+    }
+
+    pub async fn update(
+        &self,
+        id: Uuid,
+        data: &GroupData,
+    ) -> Result<Group, GroupError> {
+        data.validate()?;
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE \"group\" \
+                SET name = $1, permission = $2, updated_at = $3 \
+                WHERE id = $4 AND ($5 IS NULL OR namespace = $5) RETURNING *",
+            )
+            .bind(data.name.as_str())
+            .bind(data.permission.bits() as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| conflict_error(error, &data.name))?
+        .ok_or(GroupError::NotFound(id))
+    }
+
+    /// Deletes the group along with its memberships and shares.
+    pub async fn delete(&self, id: Uuid) -> Result<Group, GroupError> {
+        retry_busy(|| self.delete_once(id))
+            .await
+            .map_err(sqlx_error)?
+            .ok_or(GroupError::NotFound(id))
+    }
+
+    async fn delete_once(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<Group>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        let group = sqlx::query_as(
+            "DELETE FROM \"group\" \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2) RETURNING *",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&mut *tx)
+        .await?;
+        if group.is_none() {
+            return Ok(None);
+        }
+
+        for table in ["group_member", "group_object"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE group_id = $1"))
+                .bind(id.into_bytes().as_slice())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(group)
+    }
+
+    pub async fn get_members(&self, id: Uuid) -> Result<Vec<User>, GroupError> {
+        self.get(id).await?;
+
+        sqlx::query_as(
+            "SELECT user.* FROM group_member \
+            JOIN user ON user.id = group_member.user_id \
+            WHERE group_member.group_id = $1 ORDER BY user.username",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)
+    }
+
+    /// Adds the user `user_id` to the group, which must be in the same
+    /// namespace. Adding a member again does nothing.
+    pub async fn add_member(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), GroupError> {
+        let group = self.get(id).await?;
+
+        let ids = (id.into_bytes(), user_id.into_bytes());
+        let added: Option<(i64,)> = retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO group_member (group_id, user_id, created_at) \
+                SELECT $1, id, $2 FROM user WHERE id = $3 AND namespace = $4 \
+                ON CONFLICT DO NOTHING RETURNING created_at",
+            )
+            .bind(ids.0.as_slice())
+            .bind(Utc::now().timestamp_millis())
+            .bind(ids.1.as_slice())
+            .bind(group.namespace.as_str())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+        if added.is_some() {
+            return Ok(());
+        }
+
+        // Either already a member or not a user of the namespace
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM user WHERE id = $1 AND namespace = $2",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(group.namespace.as_str())
+        .fetch_one(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+        if count == 0 {
+            return Err(GroupError::UserNotFound(user_id));
+        }
+        Ok(())
+    }
+
+    pub async fn remove_member(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), GroupError> {
+        self.get(id).await?;
+
+        let ids = (id.into_bytes(), user_id.into_bytes());
+        let removed: Option<(i64,)> = retry_busy(|| {
+            sqlx::query_as(
+                "DELETE FROM group_member \
+                WHERE group_id = $1 AND user_id = $2 RETURNING created_at",
+            )
+            .bind(ids.0.as_slice())
+            .bind(ids.1.as_slice())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        if removed.is_none() {
+            return Err(GroupError::MemberNotFound(user_id));
+        }
+        Ok(())
+    }
+
+    /// The union of the permissions of the groups of the user `user_id`.
+    pub async fn get_permission(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Permission, GroupError> {
+        let permissions = sqlx::query_as(MEMBER_PERMISSIONS_QUERY)
+            .bind(user_id.into_bytes().as_slice())
+            .fetch_all(&self.db)
+            .await
+            .map_err(sqlx_error)?;
+
+        Ok(union_permissions(permissions))
+    }
+
+    /// Lets the members of the group read the object `object_id`, which
+    /// must be checked to be in the same namespace. Sharing an object again
+    /// does nothing.
+    pub async fn share(
+        &self,
+        id: Uuid,
+        object_id: Uuid,
+    ) -> Result<Group, GroupError> {
+        let group = self.get(id).await?;
+
+        let ids = (id.into_bytes(), object_id.into_bytes());
+        retry_busy(|| {
+            sqlx::query(
+                "INSERT INTO group_object (group_id, object_id, created_at) \
+                VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            )
+            .bind(ids.0.as_slice())
+            .bind(ids.1.as_slice())
+            .bind(Utc::now().timestamp_millis())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(group)
+    }
+
+    pub async fn unshare(
+        &self,
+        id: Uuid,
+        object_id: Uuid,
+    ) -> Result<Group, GroupError> {
+        let group = self.get(id).await?;
+
+        let ids = (id.into_bytes(), object_id.into_bytes());
+        let removed: Option<(i64,)> = retry_busy(|| {
+            sqlx::query_as(
+                "DELETE FROM group_object \
+                WHERE group_id = $1 AND object_id = $2 RETURNING created_at",
+            )
+            .bind(ids.0.as_slice())
+            .bind(ids.1.as_slice())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        if removed.is_none() {
+            return Err(GroupError::NotShared);
+        }
+        Ok(group)
+    }
+
+    /// The groups the object `object_id` is shared with.
+    pub async fn get_shares(
+        &self,
+        object_id: Uuid,
+    ) -> Result<Vec<Group>, GroupError> {
+        sqlx::query_as(
+            "SELECT \"group\".* FROM group_object \
+            JOIN \"group\" ON \"group\".id = group_object.group_id \
+            WHERE group_object.object_id = $1 \
+            AND ($2 IS NULL OR \"group\".namespace = $2) \
+            ORDER BY \"group\".name",
+        )
+        .bind(object_id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)
+    }
+
+    /// Whether the object `object_id` is shared with a group of the user
+    /// `user_id`.
+    pub async fn is_shared_with(
+        &self,
+        object_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<bool, GroupError> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM group_object \
+            JOIN group_member \
+            ON group_member.group_id = group_object.group_id \
+            WHERE group_object.object_id = $1 AND group_member.user_id = $2",
+        )
+        .bind(object_id.into_bytes().as_slice())
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_one(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(count > 0)
+    }
+}
+
+fn conflict_error(error: sqlx::Error, name: &str) -> GroupError {
+    if matches!(&error, sqlx::Error::Database(e) if e.is_unique_violation()) {
+        return GroupError::AlreadyExists(name.to_owned());
+    }
+    sqlx_error(error)
+}
+
+fn sqlx_error(error: sqlx::Error) -> GroupError {
+    tracing::error!(%error, "got sqlx error while querying groups");
+    GroupError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, Sqlite, SqlitePool};
+
+    use crate::{
+        namespace::repository::NamespaceRepository,
+        user::{
+            password::PasswordHasher, repository::UserRepository, UserData,
+        },
+    };
+
+    use super::*;
+
+    async fn repository() -> (GroupRepository<Sqlite>, UserRepository<Sqlite>) {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        NamespaceRepository::new(db.clone())
+            .create("team-a")
+            .await
+            .unwrap();
+
+        (
+            GroupRepository::new(db.clone()),
+            UserRepository::new(db, PasswordHasher::bcrypt(4)),
+        )
+    }
+
+    async fn create_user(repo: &UserRepository<Sqlite>) -> User {
+        let data = UserData {
+            username: Uuid::new_v4().to_string(),
+            password: "password".into(),
+        };
+        repo.create(Permission::SHARE, data).await.unwrap()
+    }
+
+    fn data(name: &str, permission: Permission) -> GroupData {
+        GroupData {
+            name: name.into(),
+            permission,
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_groups() {
+        let (repo, _) = repository().await;
+
+        let group = repo.create(&data("readers", Permission::READ_ALL)).await;
+        let group = group.unwrap();
+        assert_eq!(group.namespace, DEFAULT_NAMESPACE);
+        assert_eq!(repo.get(group.id).await.unwrap(), group);
+        assert!(matches!(
+            repo.create(&data("readers", Permission::empty())).await,
+            Err(GroupError::AlreadyExists(..)),
+        ));
+        assert!(matches!(
+            repo.create(&data(" ", Permission::empty())).await,
+            Err(GroupError::InvalidName),
+        ));
+
+        // Names are only unique within a namespace
+        let team = repo.in_namespace("team-a");
+        let other = team
+            .create(&data("readers", Permission::empty()))
+            .await
+            .unwrap();
+        assert_eq!(other.namespace, "team-a");
+        assert_eq!(team.get_all().await.unwrap(), vec![other.clone()]);
+        assert!(matches!(
+            team.get(group.id).await,
+            Err(GroupError::NotFound(..)),
+        ));
+
+        let updated = repo
+            .update(group.id, &data("writers", Permission::WRITE_ALL))
+            .await
+            .unwrap();
+        assert_eq!(updated.name, "writers");
+        assert_eq!(updated.permission, Permission::WRITE_ALL);
+
+        assert_eq!(repo.delete(group.id).await.unwrap(), updated);
+        assert!(matches!(
+            repo.delete(group.id).await,
+            Err(GroupError::NotFound(..)),
+        ));
+        assert_eq!(repo.get_all().await.unwrap(), [other]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_members() {
+        let (repo, users) = repository().await;
+        let user = create_user(&users).await;
+        let outsider = create_user(&users.in_namespace("team-a")).await;
+
+        let readers = repo
+            .create(&data("readers", Permission::READ_ALL))
+            .await
+            .unwrap();
+        let writers = repo
+            .create(&data("writers", Permission::WRITE_OWNED))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            repo.get_permission(user.id).await.unwrap(),
+            Permission::empty()
+        );
+        assert_eq!(
+            users.get_effective_permission(&user).await.unwrap(),
+            Permission::SHARE,
+        );
+
+        repo.add_member(readers.id, user.id).await.unwrap();
+        repo.add_member(readers.id, user.id).await.unwrap();
+        repo.add_member(writers.id, user.id).await.unwrap();
+        assert_eq!(
+            repo.get_members(readers.id).await.unwrap(),
+            vec![user.clone()]
+        );
+        assert_eq!(
+            users.get_effective_permission(&user).await.unwrap(),
+            Permission::SHARE | Permission::READ_ALL | Permission::WRITE_OWNED,
+        );
+
+        // Members must be in the namespace of the group
+        assert!(matches!(
+            repo.add_member(readers.id, outsider.id).await,
+            Err(GroupError::UserNotFound(..)),
+        ));
+
+        repo.remove_member(writers.id, user.id).await.unwrap();
+        assert!(matches!(
+            repo.remove_member(writers.id, user.id).await,
+            Err(GroupError::MemberNotFound(..)),
+        ));
+        assert_eq!(
+            repo.get_permission(user.id).await.unwrap(),
+            Permission::READ_ALL,
+        );
+
+        // Memberships go away with the group and with the user
+        repo.delete(readers.id).await.unwrap();
+        assert_eq!(
+            repo.get_permission(user.id).await.unwrap(),
+            Permission::empty()
+        );
+
+        repo.add_member(writers.id, user.id).await.unwrap();
+        users.delete(user.id, Default::default()).await.unwrap();
+        assert!(repo.get_members(writers.id).await.unwrap().is_empty());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_shares() {
+        let (repo, users) = repository().await;
+        let member = create_user(&users).await;
+        let other = create_user(&users).await;
+        let object_id = Uuid::new_v4();
+
+        let group = repo
+            .create(&data("readers", Permission::empty()))
+            .await
+            .unwrap();
+        repo.add_member(group.id, member.id).await.unwrap();
+        assert!(!repo.is_shared_with(object_id, member.id).await.unwrap());
+
+        repo.share(group.id, object_id).await.unwrap();
+        repo.share(group.id, object_id).await.unwrap();
+        assert_eq!(
+            repo.get_shares(object_id).await.unwrap(),
+            vec![group.clone()]
+        );
+        assert!(repo.is_shared_with(object_id, member.id).await.unwrap());
+        assert!(!repo.is_shared_with(object_id, other.id).await.unwrap());
+
+        // Groups of other namespaces can not be shared with
+        assert!(matches!(
+            repo.in_namespace("team-a").share(group.id, object_id).await,
+            Err(GroupError::NotFound(..)),
+        ));
+
+        repo.unshare(group.id, object_id).await.unwrap();
+        assert!(matches!(
+            repo.unshare(group.id, object_id).await,
+            Err(GroupError::NotShared),
+        ));
+        assert!(!repo.is_shared_with(object_id, member.id).await.unwrap());
+    }
+}
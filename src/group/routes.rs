@@ -0,0 +1,112 @@
+use axum::{extract::Path, routing, Extension, Router};
+use sqlx::Sqlite;
+use uuid::Uuid;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Permission, Token},
+    errors::DownloaderError,
+    user::User,
+    utils::extractors::Body,
+};
+
+use super::{repository::GroupRepository, Group, GroupData};
+
+pub fn group_routes<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router
+        .route("/", routing::get(get_groups))
+        .route("/", routing::post(post_group))
+        .route("/:id", routing::get(get_group))
+        .route("/:id", routing::put(update_group))
+        .route("/:id", routing::delete(delete_group))
+        .route("/:id/members", routing::get(get_group_members))
+        .route("/:id/members/:user_id", routing::put(put_group_member))
+        .route(
+            "/:id/members/:user_id",
+            routing::delete(delete_group_member),
+        )
+}
+
+/// Groups are managed by the administrators of their namespace.
+fn require_admin(token: &Token) -> Result<(), DownloaderError> {
+    if !token.permission().contains(Permission::ADMIN) {
+        return Err(AuthError::AccessDenied.into());
+    }
+    Ok(())
+}
+
+pub async fn get_groups(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+) -> Result<Body<Vec<Group>>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.get_all().await?))
+}
+
+pub async fn post_group(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Body(data): Body<GroupData>,
+) -> Result<Body<Group>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.create(&data).await?))
+}
+
+pub async fn get_group(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<Group>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.get(id).await?))
+}
+
+pub async fn update_group(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+    Body(data): Body<GroupData>,
+) -> Result<Body<Group>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.update(id, &data).await?))
+}
+
+pub async fn delete_group(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<Group>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.delete(id).await?))
+}
+
+pub async fn get_group_members(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<Vec<User>>, DownloaderError> {
+    require_admin(&token)?;
+    Ok(Body(repo.get_members(id).await?))
+}
+
+pub async fn put_group_member(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path((id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<Body<Vec<User>>, DownloaderError> {
+    require_admin(&token)?;
+    repo.add_member(id, user_id).await?;
+    Ok(Body(repo.get_members(id).await?))
+}
+
+pub async fn delete_group_member(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<GroupRepository<Sqlite>>,
+    Path((id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<Body<Vec<User>>, DownloaderError> {
+    require_admin(&token)?;
+    repo.remove_member(id, user_id).await?;
+    Ok(Body(repo.get_members(id).await?))
+}
@@ -0,0 +1,220 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Type};
+use uuid::Uuid;
+
+pub mod queue;
+pub mod repository;
+pub mod routes;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("job `{0}` not found")]
+    NotFound(Uuid),
+    #[error("the job queue is not running")]
+    QueueClosed,
+    #[error("sqlx error: {0}")]
+    Sqlx(sqlx::Error),
+}
+
+impl JobError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            JobError::NotFound(..) => StatusCode::NOT_FOUND,
+            JobError::QueueClosed => StatusCode::SERVICE_UNAVAILABLE,
+            JobError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    JobError {
+        NotFound(..) => 1, "job not found";
+        QueueClosed => 2, "the job queue is not running";
+        Sqlx(..) => 3, "database error";
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    FetchFile,
+    DeleteUserObjects,
+    Backup,
+    Maintenance,
+    PostProcess,
+}
+
+impl JobKind {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::FetchFile => "fetch_file",
+            JobKind::DeleteUserObjects => "delete_user_objects",
+            JobKind::Backup => "backup",
+            JobKind::Maintenance => "maintenance",
+            JobKind::PostProcess => "post_process",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fetch_file" => Some(JobKind::FetchFile),
+            "delete_user_objects" => Some(JobKind::DeleteUserObjects),
+            "backup" => Some(JobKind::Backup),
+            "maintenance" => Some(JobKind::Maintenance),
+            "post_process" => Some(JobKind::PostProcess),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued = 0,
+    Running = 1,
+    Succeeded = 2,
+    Failed = 3,
+}
+
+impl JobState {
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Succeeded | JobState::Failed)
+    }
+
+    fn from_i64(v: i64) -> Option<Self> {
+        match v {
+            0 => Some(JobState::Queued),
+            1 => Some(JobState::Running),
+            2 => Some(JobState::Succeeded),
+            3 => Some(JobState::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A long-running operation executed in background.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Uuid,
+    /// The user that started the job, if any.
+    pub user_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// Units of work done, bytes or items depending on the kind.
+    pub progress: u64,
+    pub total: Option<u64>,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Job
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+    Option<Vec<u8>>: Decode<'r, R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    Option<i64>: Decode<'r, R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let id: Vec<u8> = row.try_get("id")?;
+        let id: [u8; 16] = id.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `id` uuid out of range".into())
+        })?;
+        let id = Uuid::from_bytes(id);
+
+        let user_id: Option<Vec<u8>> = row.try_get("user_id")?;
+        let user_id = user_id
+            .map(|user_id| {
+                let user_id: [u8; 16] = user_id.try_into().map_err(|_| {
+                    sqlx::Error::Decode(
+                        "parse `user_id` uuid out of range".into(),
+                    )
+                })?;
+                Ok::<_, sqlx::Error>(Uuid::from_bytes(user_id))
+            })
+            .transpose()?;
+
+        let created_at: i64 = row.try_get("created_at")?;
+        let created_at = DateTime::from_timestamp_millis(created_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `created_at` field gone wrong".into(),
+                )
+            })?;
+
+        let updated_at: i64 = row.try_get("updated_at")?;
+        let updated_at = DateTime::from_timestamp_millis(updated_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `updated_at` field gone wrong".into(),
+                )
+            })?;
+
+        let kind: String = row.try_get("kind")?;
+        let kind = JobKind::parse(&kind).ok_or_else(|| {
+            sqlx::Error::Decode(
+                format!("parse `kind`: unknown `{kind}`").into(),
+            )
+        })?;
+
+        let state: i64 = row.try_get("state")?;
+        let state = JobState::from_i64(state).ok_or_else(|| {
+            sqlx::Error::Decode(
+                format!("parse `state`: unknown {state}").into(),
+            )
+        })?;
+
+        let progress: i64 = row.try_get("progress")?;
+        let progress = progress.try_into().map_err(|err| {
+            sqlx::Error::Decode(format!("parse `progress`: {err}").into())
+        })?;
+
+        let total: Option<i64> = row.try_get("total")?;
+        let total = total
+            .map(|total| {
+                total.try_into().map_err(|err| {
+                    sqlx::Error::Decode(format!("parse `total`: {err}").into())
+                })
+            })
+            .transpose()?;
+
+        let result: Option<String> = row.try_get("result")?;
+        let result = result
+            .map(|result| {
+                serde_json::from_str(&result).map_err(|err| {
+                    sqlx::Error::Decode(format!("parse `result`: {err}").into())
+                })
+            })
+            .transpose()?;
+
+        let error: Option<String> = row.try_get("error")?;
+
+        Ok(Self {
+            id,
+            user_id,
+            created_at,
+            updated_at,
+            kind,
+            state,
+            progress,
+            total,
+            result,
+            error,
+        })
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{TimeDelta, Utc};
+use serde::Serialize;
+use sqlx::Sqlite;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{config::JobConfig, errors::DownloaderError};
+
+use super::{repository::JobRepository, Job, JobError, JobKind, JobState};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+/// Kinds of the jobs left for their owners to resume after a restart.
+const RESUMABLE_KINDS: &[JobKind] = &[JobKind::FetchFile];
+
+/// Progress reported by a running job.
+#[derive(Debug)]
+pub struct JobProgress {
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Default for JobProgress {
+    fn default() -> Self {
+        Self {
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(UNKNOWN_TOTAL),
+        }
+    }
+}
+
+impl JobProgress {
+    #[inline]
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add(&self, done: u64) {
+        self.done.fetch_add(done, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> (u64, Option<u64>) {
+        let total = self.total.load(Ordering::Relaxed);
+        let total = (total != UNKNOWN_TOTAL).then_some(total);
+        (self.done.load(Ordering::Relaxed), total)
+    }
+}
+
+type JobTask =
+    Pin<Box<dyn Future<Output = Result<serde_json::Value, String>> + Send>>;
+
+struct QueuedJob {
+    id: Uuid,
+    progress: Arc<JobProgress>,
+    task: JobTask,
+}
+
+type ProgressMap = Arc<Mutex<HashMap<Uuid, Arc<JobProgress>>>>;
+
+/// Runs long operations in a pool of workers, keeping track of their state
+/// in the `job` table.
+///
+/// Jobs interrupted by a restart are marked as failed when the queue
+/// starts, but the resumable ones, which must be either resumed with
+/// [`JobQueue::resume`] or failed with [`JobQueue::fail`].
+pub struct JobQueue {
+    repo: JobRepository<Sqlite>,
+    sender: mpsc::UnboundedSender<QueuedJob>,
+    progress: ProgressMap,
+}
+
+impl JobQueue {
+    pub async fn start(
+        repo: JobRepository<Sqlite>,
+        cfg: &JobConfig,
+    ) -> Result<Arc<Self>, JobError> {
+        let interrupted = repo.fail_unfinished(RESUMABLE_KINDS).await?;
+        if interrupted > 0 {
+            tracing::warn!(count = interrupted, "failed interrupted jobs");
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let progress = ProgressMap::default();
+
+        for _ in 0..cfg.workers.max(1) {
+            tokio::spawn(run_worker(
+                repo.clone(),
+                progress.clone(),
+                receiver.clone(),
+            ));
+        }
+        if !cfg.retention.is_zero() {
+            tokio::spawn(run_prune(repo.clone(), cfg.retention));
+        }
+
+        Ok(Arc::new(Self {
+            repo,
+            sender,
+            progress,
+        }))
+    }
+
+    /// Queues the job built by `f`, whose output becomes the job result.
+    pub async fn enqueue<F, Fut, T>(
+        &self,
+        kind: JobKind,
+        user_id: Option<Uuid>,
+        f: F,
+    ) -> Result<Job, JobError>
+    where
+        F: FnOnce(Arc<JobProgress>) -> Fut,
+        Fut: Future<Output = Result<T, DownloaderError>> + Send + 'static,
+        T: Serialize,
+    {
+        let job = self.create(kind, user_id).await?;
+        self.submit(job.id, f).await?;
+        Ok(job)
+    }
+
+    /// Creates a queued job without running it, so it can be referred to
+    /// before being submitted with [`JobQueue::submit`].
+    pub async fn create(
+        &self,
+        kind: JobKind,
+        user_id: Option<Uuid>,
+    ) -> Result<Job, JobError> {
+        self.repo.create(Uuid::new_v4(), kind, user_id).await
+    }
+
+    /// Gets the jobs of `kind` interrupted by a restart, which are waiting
+    /// to be resumed.
+    pub async fn interrupted(
+        &self,
+        kind: JobKind,
+    ) -> Result<Vec<Job>, JobError> {
+        // Skips the ones already submitted again
+        let running: Vec<_> =
+            self.progress.lock().unwrap().keys().copied().collect();
+
+        let mut jobs = self.repo.get_unfinished(kind).await?;
+        jobs.retain(|job| !running.contains(&job.id));
+        Ok(jobs)
+    }
+
+    /// Runs the interrupted job `id` again with the task built by `f`.
+    pub async fn resume<F, Fut, T>(
+        &self,
+        id: Uuid,
+        f: F,
+    ) -> Result<Job, JobError>
+    where
+        F: FnOnce(Arc<JobProgress>) -> Fut,
+        Fut: Future<Output = Result<T, DownloaderError>> + Send + 'static,
+        T: Serialize,
+    {
+        let job = self.repo.requeue(id).await?;
+        self.submit(id, f).await?;
+        Ok(job)
+    }
+
+    /// Fails the interrupted job `id` instead of resuming it.
+    pub async fn fail(&self, id: Uuid, error: &str) -> Result<Job, JobError> {
+        self.repo.finish(id, 0, None, Err(error.to_owned())).await
+    }
+
+    /// Sends the task built by `f` to the workers, as the job `id`.
+    pub async fn submit<F, Fut, T>(
+        &self,
+        id: Uuid,
+        f: F,
+    ) -> Result<(), JobError>
+    where
+        F: FnOnce(Arc<JobProgress>) -> Fut,
+        Fut: Future<Output = Result<T, DownloaderError>> + Send + 'static,
+        T: Serialize,
+    {
+        let progress = Arc::new(JobProgress::default());
+        let fut = f(progress.clone());
+        let task = Box::pin(async move {
+            let output = fut.await.map_err(|e| e.to_string())?;
+            serde_json::to_value(output).map_err(|e| e.to_string())
+        });
+
+        self.progress.lock().unwrap().insert(id, progress.clone());
+
+        let queued = QueuedJob { id, progress, task };
+        if self.sender.send(queued).is_err() {
+            self.progress.lock().unwrap().remove(&id);
+            let _ = self
+                .repo
+                .finish(id, 0, None, Err(JobError::QueueClosed.to_string()))
+                .await;
+            return Err(JobError::QueueClosed);
+        }
+
+        Ok(())
+    }
+
+    /// Gets a job, with the live progress if it is still running.
+    pub async fn get(&self, id: Uuid) -> Result<Job, JobError> {
+        // Taken before reading the job, as workers mark jobs as running
+        // before running them, so no progress is seen in a queued state
+        let live = self
+            .progress
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|progress| progress.get());
+        let mut job = self.repo.get(id).await?;
+
+        if let (JobState::Running, Some(live)) = (job.state, live) {
+            (job.progress, job.total) = live;
+        }
+
+        Ok(job)
+    }
+}
+
+async fn run_worker(
+    repo: JobRepository<Sqlite>,
+    progress: ProgressMap,
+    receiver: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<QueuedJob>>>,
+) {
+    loop {
+        let Some(job) = receiver.lock().await.recv().await else {
+            return;
+        };
+        let id = job.id;
+
+        // Not run if it can not be marked as running, or it would run
+        // while reported as queued
+        if let Err(error) = repo.start(id).await {
+            tracing::error!(%error, %id, "failed to start job, skipping it");
+            let _ = repo.finish(id, 0, None, Err(error.to_string())).await;
+            progress.lock().unwrap().remove(&id);
+            continue;
+        }
+
+        // Spawned so panics only fail the job
+        let outcome = match tokio::spawn(job.task).await {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                tracing::error!(%error, %id, "job panicked");
+                Err("job panicked".into())
+            }
+        };
+        if let Err(error) = &outcome {
+            tracing::warn!(%error, %id, "job failed");
+        }
+
+        let (done, total) = job.progress.get();
+        let _ = repo.finish(id, done, total, outcome).await;
+        progress.lock().unwrap().remove(&id);
+    }
+}
+
+async fn run_prune(repo: JobRepository<Sqlite>, retention: Duration) {
+    let mut interval = tokio::time::interval(retention.min(PRUNE_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let Some(before) = TimeDelta::from_std(retention)
+            .ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+        else {
+            continue;
+        };
+
+        match repo.delete_finished(before).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "pruned finished jobs"),
+            Err(error) => {
+                tracing::error!(%error, "failed to prune finished jobs")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use sqlx::{migrate, SqlitePool};
+    use test_log::test;
+    use tokio::sync::Notify;
+    use uuid::Uuid;
+
+    use crate::{
+        config::JobConfig,
+        errors::DownloaderError,
+        job::{repository::JobRepository, Job, JobKind, JobState},
+    };
+
+    use super::JobQueue;
+
+    async fn repository() -> JobRepository<sqlx::Sqlite> {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        JobRepository::new(db)
+    }
+
+    async fn wait(jobs: &JobQueue, id: Uuid) -> Job {
+        for _ in 0..100 {
+            let job = jobs.get(id).await.unwrap();
+            if job.state.is_finished() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("job {id} did not finish");
+    }
+
+    #[test(tokio::test)]
+    async fn test_jobs() {
+        let jobs = JobQueue::start(repository().await, &JobConfig::default())
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+
+        let (progressed, notify) =
+            (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let job = jobs
+            .enqueue(JobKind::FetchFile, Some(user_id), |progress| {
+                let (progressed, notify) = (progressed.clone(), notify.clone());
+                async move {
+                    progress.set_total(10);
+                    progress.add(4);
+                    progressed.notify_one();
+                    notify.notified().await;
+                    progress.add(6);
+                    Ok("done")
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(job.user_id, Some(user_id));
+
+        // Live progress is reported while running
+        progressed.notified().await;
+        let running = jobs.get(job.id).await.unwrap();
+        assert_eq!(running.state, JobState::Running);
+        assert_eq!((running.progress, running.total), (4, Some(10)));
+        notify.notify_one();
+
+        let job = wait(&jobs, job.id).await;
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!((job.progress, job.total), (10, Some(10)));
+        assert_eq!(job.result, Some("done".into()));
+
+        let job = jobs
+            .enqueue(JobKind::FetchFile, None, |_| async {
+                Err::<(), _>(DownloaderError::Other(
+                    "boom".into(),
+                    axum::http::StatusCode::BAD_GATEWAY,
+                ))
+            })
+            .await
+            .unwrap();
+        let job = wait(&jobs, job.id).await;
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.error.as_deref(), Some("boom"));
+
+        let job = jobs
+            .enqueue(JobKind::FetchFile, None, |_| async {
+                if true {
+                    panic!("job panic");
+                }
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let job = wait(&jobs, job.id).await;
+        assert_eq!(job.state, JobState::Failed);
+    }
+
+    #[test(tokio::test)]
+    async fn test_interrupted_jobs() {
+        let repo = repository().await;
+        let queued = repo
+            .create(Uuid::new_v4(), JobKind::Backup, None)
+            .await
+            .unwrap();
+        let running = repo
+            .create(Uuid::new_v4(), JobKind::Backup, None)
+            .await
+            .unwrap();
+        repo.start(running.id).await.unwrap();
+        let resumable = repo
+            .create(Uuid::new_v4(), JobKind::FetchFile, None)
+            .await
+            .unwrap();
+        repo.start(resumable.id).await.unwrap();
+
+        let jobs = JobQueue::start(repo.clone(), &JobConfig::default())
+            .await
+            .unwrap();
+
+        for id in [queued.id, running.id] {
+            let job = jobs.get(id).await.unwrap();
+            assert_eq!(job.state, JobState::Failed);
+            assert!(job.error.is_some());
+        }
+
+        // Resumable jobs are left to their owners
+        let interrupted = jobs.interrupted(JobKind::FetchFile).await.unwrap();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].id, resumable.id);
+        jobs.resume(resumable.id, |progress| async move {
+            progress.add(3);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let job = wait(&jobs, resumable.id).await;
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!(job.progress, 3);
+        assert!(jobs
+            .interrupted(JobKind::FetchFile)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let earlier = chrono::Utc::now() - chrono::TimeDelta::seconds(60);
+        let count = repo.delete_finished(earlier).await.unwrap();
+        assert_eq!(count, 0, "recently finished jobs must be kept");
+        let later = chrono::Utc::now() + chrono::TimeDelta::seconds(1);
+        assert_eq!(repo.delete_finished(later).await.unwrap(), 3);
+    }
+}
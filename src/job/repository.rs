@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use crate::utils::retry::retry_busy;
+
+use super::{Job, JobError, JobKind, JobState};
+
+/// Error of the jobs that could not be resumed after a restart.
+pub const INTERRUPTED_ERROR: &str = "interrupted by a server restart";
+
+pub struct JobRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for JobRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> JobRepository<DB> {
+    pub fn new(db: Pool<DB>) -> JobRepository<DB> {
+        JobRepository { db }
+    }
+}
+
+impl<DB> JobRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> Job: FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+    for<'e> Option<&'e [u8]>: Encode<'e, DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+    for<'e> Option<i64>: Encode<'e, DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<String>: Encode<'e, DB>,
+    String: Type<DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<Job, JobError> {
+        sqlx::query_as("SELECT * FROM job WHERE id = $1")
+            .bind(id.into_bytes().as_slice())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, "got sqlx error while retrieving job");
+                JobError::Sqlx(error)
+            })?
+            .ok_or(JobError::NotFound(id))
+    }
+
+    pub async fn create(
+        &self,
+        id: Uuid,
+        kind: JobKind,
+        user_id: Option<Uuid>,
+    ) -> Result<Job, JobError> {
+        let now_ms = Utc::now().timestamp_millis();
+        let user_id = user_id.map(|id| id.into_bytes());
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO job \
+                (id, user_id, created_at, updated_at, kind, state, progress) \
+                VALUES ($1, $2, $3, $4, $5, $6, 0) \
+                RETURNING *",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(user_id.as_ref().map(|id| id.as_slice()))
+            .bind(now_ms)
+            .bind(now_ms)
+            .bind(kind.as_str())
+            .bind(JobState::Queued as i64)
+            .fetch_one(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while creating job");
+            JobError::Sqlx(error)
+        })
+    }
+
+    pub async fn start(&self, id: Uuid) -> Result<Job, JobError> {
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE job SET state = $1, updated_at = $2 \
+                WHERE id = $3 RETURNING *",
+            )
+            .bind(JobState::Running as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id_bytes.as_slice())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while starting job");
+            JobError::Sqlx(error)
+        })?
+        .ok_or(JobError::NotFound(id))
+    }
+
+    /// Stores the outcome of a job, succeeded if `outcome` is ok.
+    pub async fn finish(
+        &self,
+        id: Uuid,
+        progress: u64,
+        total: Option<u64>,
+        outcome: Result<serde_json::Value, String>,
+    ) -> Result<Job, JobError> {
+        let (state, result, error) = match outcome {
+            Ok(result) => (JobState::Succeeded, Some(result.to_string()), None),
+            Err(error) => (JobState::Failed, None, Some(error)),
+        };
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE job SET state = $1, updated_at = $2, progress = $3, \
+                total = $4, result = $5, error = $6 \
+                WHERE id = $7 RETURNING *",
+            )
+            .bind(state as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(progress.min(i64::MAX as u64) as i64)
+            .bind(total.map(|total| total.min(i64::MAX as u64) as i64))
+            .bind(result.clone())
+            .bind(error.clone())
+            .bind(id_bytes.as_slice())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while finishing job");
+            JobError::Sqlx(error)
+        })?
+        .ok_or(JobError::NotFound(id))
+    }
+
+    /// Puts a job back in the queued state, to run it again.
+    pub async fn requeue(&self, id: Uuid) -> Result<Job, JobError> {
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE job SET state = $1, updated_at = $2 \
+                WHERE id = $3 RETURNING *",
+            )
+            .bind(JobState::Queued as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id_bytes.as_slice())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while requeuing job");
+            JobError::Sqlx(error)
+        })?
+        .ok_or(JobError::NotFound(id))
+    }
+
+    /// Gets the queued and running jobs of `kind`.
+    pub async fn get_unfinished(
+        &self,
+        kind: JobKind,
+    ) -> Result<Vec<Job>, JobError> {
+        sqlx::query_as(
+            "SELECT * FROM job WHERE kind = $1 AND state IN ($2, $3) \
+            ORDER BY created_at",
+        )
+        .bind(kind.as_str())
+        .bind(JobState::Queued as i64)
+        .bind(JobState::Running as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving unfinished jobs",
+            );
+            JobError::Sqlx(error)
+        })
+    }
+
+    /// Fails the jobs left unfinished by a previous run, but the ones of the
+    /// `resumable` kinds, returning how many there were.
+    pub async fn fail_unfinished(
+        &self,
+        resumable: &[JobKind],
+    ) -> Result<usize, JobError> {
+        let placeholders = (0..resumable.len())
+            .map(|i| format!("${}", i + 6))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "UPDATE job SET state = $1, updated_at = $2, error = $3 \
+            WHERE state IN ($4, $5) AND kind NOT IN ({placeholders}) \
+            RETURNING id",
+        );
+
+        let mut query = sqlx::query_as(&sql)
+            .bind(JobState::Failed as i64)
+            .bind(Utc::now().timestamp_millis())
+            .bind(INTERRUPTED_ERROR)
+            .bind(JobState::Queued as i64)
+            .bind(JobState::Running as i64);
+        for kind in resumable {
+            query = query.bind(kind.as_str());
+        }
+        let ids: Vec<(Vec<u8>,)> =
+            query.fetch_all(&self.db).await.map_err(|error| {
+                tracing::error!(
+                    %error,
+                    "got sqlx error while failing unfinished jobs",
+                );
+                JobError::Sqlx(error)
+            })?;
+
+        Ok(ids.len())
+    }
+
+    /// Deletes the jobs that finished before `before`.
+    pub async fn delete_finished(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<usize, JobError> {
+        let ids: Vec<(Vec<u8>,)> = sqlx::query_as(
+            "DELETE FROM job WHERE state IN ($1, $2) AND updated_at < $3 \
+            RETURNING id",
+        )
+        .bind(JobState::Succeeded as i64)
+        .bind(JobState::Failed as i64)
+        .bind(before.timestamp_millis())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while deleting finished jobs",
+            );
+            JobError::Sqlx(error)
+        })?;
+
+        Ok(ids.len())
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, routing, Extension, Router};
+use uuid::Uuid;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Token},
+    errors::DownloaderError,
+    utils::extractors::Body,
+};
+
+use super::{queue::JobQueue, Job};
+
+pub fn job_routes<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.route("/:id", routing::get(get_job))
+}
+
+pub async fn get_job(
+    Authorization(token): Authorization,
+    Extension(jobs): Extension<Arc<JobQueue>>,
+    Path(id): Path<Uuid>,
+) -> Result<Body<Job>, DownloaderError> {
+    let job = jobs.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            job.user_id == Some(user_token.user_id) || token.can_read_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    Ok(Body(job))
+}
@@ -0,0 +1,27 @@
+pub mod access_log;
+pub mod admin;
+pub mod app;
+pub mod auth;
+pub mod backup;
+pub mod branding;
+pub mod client_log;
+pub mod config;
+pub mod database;
+pub mod errors;
+pub mod group;
+pub mod job;
+pub mod locale;
+pub mod maintenance;
+pub mod namespace;
+pub mod proxy;
+pub mod redirect;
+pub mod server;
+pub mod storage;
+pub mod telemetry;
+#[cfg(test)]
+pub mod testing;
+pub mod timeout;
+#[cfg(unix)]
+pub mod unix_socket;
+pub mod user;
+pub mod utils;
@@ -0,0 +1,325 @@
+//! Languages of the messages of error responses, negotiated from the
+//! `Accept-Language` header. The `error_code` stays the stable contract,
+//! messages are meant for humans only.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+tokio::task_local! {
+    static LOCALE: Locale;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    PtBr,
+}
+
+impl Locale {
+    /// The language tag, as sent in the `Content-Language` header.
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::PtBr => "pt-BR",
+        }
+    }
+
+    /// Matches a language tag by its primary subtag.
+    fn parse(tag: &str) -> Option<Self> {
+        let primary = tag.split('-').next().unwrap_or_default();
+        if primary.eq_ignore_ascii_case("en") || primary == "*" {
+            Some(Locale::En)
+        } else if primary.eq_ignore_ascii_case("pt") {
+            Some(Locale::PtBr)
+        } else {
+            None
+        }
+    }
+
+    /// The most preferred of the languages accepted by the request,
+    /// english if none is supported.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let mut best: Option<(f32, Locale)> = None;
+        let ranges = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for range in ranges {
+            let mut params = range.split(';');
+            let tag = params.next().unwrap_or_default().trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let Some(locale) = Locale::parse(tag) else {
+                continue;
+            };
+            // The first of equally preferred languages wins
+            if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                best = Some((q, locale));
+            }
+        }
+        best.map(|(_, locale)| locale).unwrap_or_default()
+    }
+
+    /// The translated message of the error with the `error_code`, `None`
+    /// if the english one, which carries the details, must be kept.
+    pub fn message(&self, error_code: u32) -> Option<&'static str> {
+        let messages = match self {
+            Locale::En => return None,
+            Locale::PtBr => PT_BR,
+        };
+        messages
+            .binary_search_by_key(&error_code, |&(code, _)| code)
+            .ok()
+            .map(|i| messages[i].1)
+    }
+}
+
+/// Locale of the request being handled, set by [`scope_locale`].
+pub fn current_locale() -> Locale {
+    LOCALE.try_with(|locale| *locale).unwrap_or_default()
+}
+
+/// Negotiates the locale of the request, for the error responses built
+/// while handling it.
+pub async fn scope_locale(req: Request, next: Next) -> Response {
+    let locale = Locale::negotiate(req.headers());
+    LOCALE.scope(locale, next.run(req)).await
+}
+
+/// Messages by `error_code`, sorted by code, see `errors::error_catalog`.
+/// Errors without a code have no fixed message, so they are kept as is.
+const PT_BR: &[(u32, &str)] = &[
+    (1001, "objeto não encontrado"),
+    (1002, "o limite informado está acima do máximo"),
+    (1003, "erro no banco de dados"),
+    (1004, "objeto não encontrado"),
+    (1005, "o objeto foi atualizado desde a versão informada"),
+    (2001, "erro de entrada e saída no sistema de arquivos"),
+    (2002, "arquivo não encontrado"),
+    (2003, "outra escrita do arquivo está em andamento"),
+    (
+        2004,
+        "checksum sha256 inválido, esperados 64 dígitos hexadecimais",
+    ),
+    (
+        2005,
+        "os dados não correspondem ao checksum sha256 esperado",
+    ),
+    (
+        2006,
+        "o trailer `x-checksum-sha256` anunciado não foi enviado",
+    ),
+    (
+        2007,
+        "digest sha-256 inválido, esperada uma sequência em base64",
+    ),
+    (
+        2008,
+        "muitos envios em andamento, tente novamente mais tarde",
+    ),
+    (3001, "usuário não encontrado"),
+    (3002, "já existe um usuário com esse nome de usuário"),
+    (3003, "senha incorreta"),
+    (3004, "falha ao gerar o hash da senha"),
+    (3005, "falha ao comparar a senha"),
+    (3006, "erro no banco de dados"),
+    (3007, "o usuário ainda possui objetos, exclua-os primeiro"),
+    (
+        3008,
+        "objetos não podem ser transferidos ao usuário sendo excluído",
+    ),
+    (
+        3009,
+        "o usuário designado para receber os objetos não existe",
+    ),
+    (3010, "o usuário foi atualizado desde a versão informada"),
+    (4001, "falha ao gerar o token"),
+    (4002, "a expiração do token é longa demais"),
+    (4003, "o token informado é inválido"),
+    (4004, "o token informado expirou"),
+    (4005, "o token informado só pode ser usado no futuro"),
+    (4006, "autorização necessária, mas nenhuma foi informada"),
+    (4007, "o cabeçalho Authorization informado é inválido"),
+    (4008, "a estratégia de autorização informada é inválida"),
+    (4009, "acesso negado à entidade solicitada"),
+    (
+        4010,
+        "não é possível criar um token com permissão maior que a sua",
+    ),
+    (4011, "chave de api não encontrada"),
+    (4012, "erro no banco de dados"),
+    (4013, "falha no login oidc"),
+    (4014, "o estado do login oidc é inválido ou expirou"),
+    (4015, "o login oidc não está habilitado"),
+    (
+        4016,
+        "um código de autenticação em dois fatores é necessário",
+    ),
+    (4017, "o código de autenticação em dois fatores é inválido"),
+    (4018, "a autenticação em dois fatores já está habilitada"),
+    (4019, "a autenticação em dois fatores não foi configurada"),
+    (
+        4020,
+        "muitas tentativas de login falharam, tente novamente mais tarde",
+    ),
+    (4021, "a url pré-assinada é inválida ou expirou"),
+    (4022, "a url pré-assinada já foi usada"),
+    (4023, "o envio não atende à política pré-assinada"),
+    (4024, "pedido de pré-assinatura inválido"),
+    (4025, "o arquivo ainda não está disponível"),
+    (4026, "o token pertence a outro namespace"),
+    (5001, "a busca de arquivos remotos não está habilitada"),
+    (5002, "url inválida"),
+    (5003, "o host não é permitido"),
+    (5004, "redirecionamentos demais"),
+    (5005, "o arquivo remoto é maior que o máximo"),
+    (5006, "a requisição remota falhou"),
+    (5007, "muitas buscas em andamento"),
+    (5008, "o limite diário de bytes buscados foi excedido"),
+    (5009, "a pasta deve ser um caminho relativo"),
+    (5010, "não é permitido buscar para essa pasta"),
+    (6001, "tarefa não encontrada"),
+    (6002, "a fila de tarefas não está em execução"),
+    (6003, "erro no banco de dados"),
+    (7001, "o relatório excede o tamanho máximo"),
+    (7002, "relatórios demais, tente novamente mais tarde"),
+    (7003, "relatório inválido"),
+    (7004, "erro no banco de dados"),
+    (8001, "arquivo de backup inválido"),
+    (8002, "nenhum usuário local para os objetos importados"),
+    (
+        8003,
+        "os dados de um objeto importado não correspondem ao checksum",
+    ),
+    (8004, "ids de exportação inválidos"),
+    (9001, "nome de namespace inválido"),
+    (9002, "namespace não encontrado"),
+    (9003, "o namespace já existe"),
+    (9004, "o namespace ainda possui usuários, grupos ou objetos"),
+    (9005, "o namespace padrão não pode ser excluído"),
+    (9006, "erro no banco de dados"),
+    (10001, "grupo não encontrado"),
+    (10002, "já existe um grupo com esse nome"),
+    (10003, "nome de grupo inválido"),
+    (10004, "o usuário não é membro do grupo"),
+    (10005, "o usuário não foi encontrado no namespace do grupo"),
+    (10006, "o objeto não está compartilhado com o grupo"),
+    (10007, "erro no banco de dados"),
+    (11001, "o nome está vazio"),
+    (11002, "o nome é longo demais"),
+    (11003, "o nome contém caracteres de controle"),
+    (11004, "o nome contém um caractere reservado"),
+    (11005, "o nome contém um segmento de caminho inválido"),
+    (12001, "o arquivo ainda não foi verificado contra malware"),
+    (12002, "o arquivo está infectado"),
+    (99001, "o tamanho do formulário multipart é inválido"),
+    (99002, "o delimitador do formulário é inválido"),
+    (99003, "os metadados do formulário multipart são inválidos"),
+    (99004, "o lote informado é inválido"),
+    (99100, "rota não encontrada"),
+    (99101, "a requisição demorou demais para ser respondida"),
+    (99255, "erro interno do serviço"),
+    (100000, "falha ao montar a resposta http"),
+    (101000, "formulário multipart inválido"),
+];
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{HeaderValue, StatusCode},
+        middleware, routing, Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        auth::AuthError,
+        errors::{error_catalog, DownloaderError},
+    };
+
+    use super::*;
+
+    fn negotiate(accept: &str) -> Locale {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_str(accept).unwrap(),
+        );
+        Locale::negotiate(&headers)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Locale::negotiate(&HeaderMap::new()), Locale::En);
+        assert_eq!(negotiate("pt-BR,pt;q=0.9,en;q=0.8"), Locale::PtBr);
+        assert_eq!(negotiate("PT"), Locale::PtBr);
+        assert_eq!(negotiate("en-US,pt;q=0.5"), Locale::En);
+        assert_eq!(negotiate("de, pt;q=0.7, en;q=0.3"), Locale::PtBr);
+        assert_eq!(negotiate("fr, de;q=0.5"), Locale::En);
+        assert_eq!(negotiate("pt;q=0, en;q=0.1"), Locale::En);
+    }
+
+    #[test]
+    fn test_messages() {
+        assert!(PT_BR.windows(2).all(|w| w[0].0 < w[1].0));
+        for entry in error_catalog().into_iter().filter(|e| e.code != 0) {
+            assert!(
+                Locale::PtBr.message(entry.code).is_some(),
+                "missing pt-BR message of {} ({})",
+                entry.code,
+                entry.name,
+            );
+        }
+        assert_eq!(Locale::En.message(4004), None);
+        assert_eq!(
+            Locale::PtBr.message(4004),
+            Some("o token informado expirou")
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_localized_errors() {
+        let router = Router::new()
+            .route(
+                "/",
+                routing::get(|| async {
+                    DownloaderError::from(AuthError::ExpiredToken)
+                }),
+            )
+            .layer(middleware::from_fn(scope_locale));
+
+        let get = |accept: &'static str| {
+            let req = axum::http::Request::get("/")
+                .header(header::ACCEPT_LANGUAGE, accept)
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let res = get("pt-BR,en;q=0.5").await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::CONTENT_LANGUAGE], "pt-BR");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "o token informado expirou");
+        assert_eq!(body["error_code"], 4004);
+
+        let res = get("en").await.unwrap();
+        assert!(res.headers().get(header::CONTENT_LANGUAGE).is_none());
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let err = DownloaderError::from(AuthError::ExpiredToken);
+        assert_eq!(body["error"], err.to_string());
+    }
+}
@@ -1,89 +1,458 @@
-use std::{error::Error, io::ErrorKind, path::Path, sync::Arc};
+use std::{
+    error::Error,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use auth::{repository::TokenRepository, routes::auth_routes};
-use axum::{Extension, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use config::{Args, Config};
-use jsonwebtoken::Algorithm;
-use server::layer_root_router;
-use sqlx::{migrate, SqlitePool};
-use storage::{
-    manager::ObjectManager, repository::ObjectRepository, routes::file_routes,
+#[cfg(unix)]
+use downloader::unix_socket::UnixSocketListener;
+use downloader::{
+    access_log::AccessLogLayer,
+    admin::stats::StatsCache,
+    app::{router, AppState},
+    auth::{
+        cache::TokenCache, keys::ApiKeyRepository, lockout::LoginLimiter,
+        oidc::OidcClient, presign::PresignRepository,
+        repository::TokenRepository, totp::TotpRepository,
+    },
+    backup::{create_backup, missing_objects, restore_backup, run_backups},
+    branding::Branding,
+    client_log::{
+        self, limiter::ReportLimiter, repository::ClientLogRepository,
+    },
+    config::{self, Args, Command, Config},
+    database::{self, run_optimize},
+    fatal,
+    group::repository::GroupRepository,
+    job::{queue::JobQueue, repository::JobRepository},
+    maintenance::{run_maintenance, Maintenance},
+    namespace::repository::NamespaceRepository,
+    proxy::TrustedProxies,
+    redirect::redirect_router,
+    storage::{
+        backend::Backend,
+        cache::ObjectCache,
+        embargo::EmbargoRepository,
+        fetch::RemoteFetcher,
+        fetch_job::{FetchContext, FetchJobs},
+        fetch_quota::FetchQuota,
+        history::HistoryRepository,
+        intent::recover_creates,
+        manager::ObjectManager,
+        meta::MetaRepository,
+        name::NamePolicy,
+        pipeline::{build_steps, Pipeline, StepDeps, StepRepository},
+        provenance::{ProvenanceRepository, Uploader},
+        repository::ObjectRepository,
+        scan::{run_scanner, ScanRepository, Scanner},
+        slots::UploadSlots,
+        slug::ObjectIds,
+        stats::{run_flush, run_rollup, DownloadStats, StatsRepository},
+        sweep::{run_sweep, SweepCounters},
+        trash::run_purge,
+        UndeleteWindow, UploadLimits, WriteLocks,
+    },
+    telemetry,
+    timeout::RequestTimeouts,
+    user::{
+        password::{PasswordAlgorithm, PasswordHasher},
+        repository::UserRepository,
+    },
+    utils::{
+        crypto::{fetch_jwt_cert_file, fetch_jwt_key_files},
+        sys::shutdown_signal,
+    },
 };
-use tokio::{runtime::Builder, select};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use sqlx::{migrate, SqlitePool};
+use tokio::{runtime::Builder, select, try_join};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::EnvFilter;
-use user::{repository::UserRepository, routes::user_routes};
-use utils::{crypto::fetch_jwt_key_files, sys::shutdown_signal};
-
-mod auth;
-mod config;
-mod errors;
-mod server;
-mod storage;
-mod user;
-mod utils;
-
-async fn run_http(cfg: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let manager = ObjectManager::new(&cfg.storage);
+use tracing_subscriber::{
+    filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
+async fn open_db(
+    cfg: &Config,
+) -> Result<SqlitePool, Box<dyn Error + Send + Sync>> {
     let sqlite_path = cfg.storage.state_dir.join("files.sqlite");
-    touch_file(&sqlite_path)?;
+    if !cfg.database.sqlite.in_memory {
+        touch_file(&sqlite_path)?;
+    }
 
-    let db = SqlitePool::connect(&format!(
-        "sqlite:{}",
-        sqlite_path.to_string_lossy()
-    ))
-    .await?;
+    let db = database::open(&sqlite_path, &cfg.database.sqlite).await?;
     migrate!().run(&db).await?;
 
-    let obj_repo = ObjectRepository::new(db.clone());
-    let user_repo = UserRepository::new(db, cfg.auth.password_hash_cost);
+    Ok(db)
+}
 
-    let (enc_key, dec_key) =
-        fetch_jwt_key_files(&cfg.auth.token_cert, &cfg.auth.token_key)
-            .await
-            .map_err(|e| format!("failed to get jwt key files: {e}"))?;
+async fn run_http(cfg: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manager = Backend::new(&cfg.storage);
+    let db = open_db(cfg).await?;
+
+    let mut obj_repo = ObjectRepository::new(db.clone())
+        .with_name_policy(NamePolicy::new(&cfg.storage.names));
+    if cfg.storage.object_cache_size > 0 {
+        obj_repo = obj_repo.with_cache(ObjectCache::new(
+            cfg.storage.object_cache_size,
+            cfg.storage.object_cache_ttl,
+        ));
+    }
+    let jobs = JobQueue::start(JobRepository::new(db.clone()), &cfg.jobs)
+        .await
+        .map_err(|e| format!("failed to start job queue: {e}"))?;
+    if let Some(backup_cfg) = &cfg.backup {
+        tokio::spawn(run_backups(db.clone(), jobs.clone(), backup_cfg.clone()));
+    }
+    let optimize_interval = cfg.database.sqlite.optimize_interval;
+    if !optimize_interval.is_zero() {
+        tokio::spawn(run_optimize(db.clone(), optimize_interval));
+    }
+    let maintenance = Maintenance::new(
+        db.clone(),
+        cfg.maintenance.as_ref().map_or(0, |cfg| cfg.vacuum_pages),
+    );
+    if let Some(maintenance_cfg) = &cfg.maintenance {
+        tokio::spawn(run_maintenance(
+            maintenance.clone(),
+            jobs.clone(),
+            maintenance_cfg.clone(),
+        ));
+    }
+    let stats_repo = StatsRepository::new(db.clone());
+    let download_stats = DownloadStats::new(stats_repo.clone());
+    tokio::spawn(run_flush(
+        download_stats.clone(),
+        cfg.storage.stats_flush_interval,
+    ));
+    tokio::spawn(run_rollup(
+        stats_repo,
+        cfg.storage.stats_rollup_interval,
+        cfg.storage.download_retention,
+    ));
+    let meta_repo = MetaRepository::new(db.clone());
+    let object_ids = ObjectIds::new(cfg.storage.expose_ids, db.clone())
+        .with_stats(download_stats.clone())
+        .with_metadata(meta_repo.clone());
+    let provenance_repo = ProvenanceRepository::new(db.clone());
+    let history_repo = HistoryRepository::new(db.clone());
+    let fetch_jobs =
+        FetchJobs::new(db.clone(), Path::new(cfg.storage.temp_dir.as_str()));
+    let fetch_quota = (cfg.storage.fetch.as_ref())
+        .map(|fetch_cfg| FetchQuota::new(db.clone(), fetch_cfg));
+    let embargo_repo = EmbargoRepository::new(db.clone());
+    let scan_repo = ScanRepository::new(db.clone());
+    let step_repo = StepRepository::new(db.clone());
+    let key_repo = ApiKeyRepository::new(db.clone());
+    let client_log_repo = ClientLogRepository::new(db.clone());
+    if !cfg.client_logs.retention.is_zero() {
+        tokio::spawn(client_log::run_prune(
+            client_log_repo.clone(),
+            cfg.client_logs.retention,
+        ));
+    }
+    let presign_repo = PresignRepository::new(
+        db.clone(),
+        &cfg.auth.secret_key,
+        cfg.auth.max_file_token_duration,
+    );
+    let totp_repo = TotpRepository::new(
+        db.clone(),
+        cfg.auth.totp.issuer.clone(),
+        cfg.auth.totp.required,
+    );
+    let hasher = match cfg.auth.password_algorithm {
+        PasswordAlgorithm::Argon2id => PasswordHasher::argon2id(
+            cfg.auth.argon2_memory_cost,
+            cfg.auth.argon2_time_cost,
+            cfg.auth.argon2_parallelism,
+        )
+        .map_err(|e| format!("invalid argon2 parameters: {e}"))?,
+        PasswordAlgorithm::Bcrypt => {
+            PasswordHasher::bcrypt(cfg.auth.password_hash_cost)
+        }
+    };
+    let namespace_repo = NamespaceRepository::new(db.clone());
+    let group_repo = GroupRepository::new(db.clone());
+    let user_repo = UserRepository::new(db, hasher);
+    user_repo
+        .check_delete_policy(cfg.storage.on_user_delete)
+        .await
+        .map_err(|e| format!("invalid `storage.on_user_delete`: {e}"))?;
+
+    let (enc_key, dec_key, kid) = fetch_jwt_key_files(
+        cfg.auth.token_algorithm,
+        cfg.auth.token_cert.as_ref().map(|cert| cert.as_str()),
+        &cfg.auth.token_key,
+    )
+    .await
+    .map_err(|e| format!("failed to get jwt key files: {e}"))?;
 
-    let token_repo = TokenRepository::new(
-        Algorithm::EdDSA,
+    let mut token_repo = TokenRepository::new(
+        cfg.auth.token_algorithm,
         enc_key,
         dec_key,
-        cfg.auth.token_duration,
-        cfg.auth.token_duration,
+        cfg.auth.user_token_duration,
+        cfg.auth.max_file_token_duration,
         cfg.auth.secret_key.clone(),
-    );
-
-    let app = layer_root_router(
-        Router::new()
-            .nest("/api/file", file_routes(Router::new()))
-            .nest("/api/auth", auth_routes(Router::new()))
-            .nest("/api/user", user_routes(Router::new())),
     )
-    .layer(Extension(obj_repo))
-    .layer(Extension(Arc::new(manager)))
-    .layer(Extension(user_repo))
-    .layer(Extension(Arc::new(token_repo)));
+    .with_key_id(kid)
+    .with_durations(&cfg.auth.token_durations);
 
-    let tls_cfg = load_tls_config(&cfg.ssl).await;
+    for cert in &cfg.auth.retired_token_certs {
+        let (kid, dec_key) =
+            fetch_jwt_cert_file(cfg.auth.token_algorithm, cert)
+                .await
+                .map_err(|e| {
+                    format!(
+                        "failed to get retired jwt cert file `{}`: {e}",
+                        **cert
+                    )
+                })?;
+        token_repo = token_repo.with_retired_key(kid, dec_key);
+    }
 
-    tracing::info!(
-        addr = %cfg.net.http_addr,
-        tls_enabled = tls_cfg.is_some(),
-        "listening for http connections",
-    );
+    if cfg.auth.token_cache_size > 0 {
+        token_repo = token_repo.with_cache(TokenCache::new(
+            cfg.auth.token_cache_size,
+            cfg.auth.token_cache_ttl,
+        ));
+    }
 
-    if let Some(tls_cfg) = tls_cfg {
-        axum_server::bind_rustls(cfg.net.http_addr, tls_cfg)
-            .serve(app.into_make_service())
-            .await?;
+    let manager = Arc::new(manager);
+    let fetcher = match (&cfg.storage.fetch, fetch_quota) {
+        (Some(fetch_cfg), Some(quota)) => Some((
+            Arc::new(RemoteFetcher::new(fetch_cfg.clone()).map_err(|e| {
+                format!("failed to create remote fetcher: {e}")
+            })?),
+            quota,
+        )),
+        _ => None,
+    };
+    let upload_limits = UploadLimits::new(&cfg.storage);
+    let write_locks = Arc::new(WriteLocks::new(cfg.storage.wait_for_writes));
+
+    let scanner = cfg.scanning.as_ref().map(|scan_cfg| {
+        let scanner = Scanner::new(scan_cfg, scan_repo);
+        tokio::spawn(run_scanner(
+            scanner.clone(),
+            obj_repo.clone(),
+            manager.clone(),
+            scan_cfg.interval,
+        ));
+        scanner
+    });
+    let pipeline = if cfg.storage.post_process.is_empty() {
+        None
     } else {
-        axum_server::bind(cfg.net.http_addr)
-            .serve(app.into_make_service())
-            .await?;
+        let deps = StepDeps {
+            scanner: scanner.clone(),
+            meta: meta_repo.clone(),
+            locks: write_locks.clone(),
+        };
+        let steps = build_steps(&cfg.storage.post_process, &deps)
+            .map_err(|e| format!("invalid post-processing steps: {e}"))?;
+        Some(Pipeline::new(
+            steps,
+            manager.clone(),
+            obj_repo.clone(),
+            step_repo.clone(),
+            jobs.clone(),
+        ))
+    };
+
+    let fetch_ctx = fetcher.clone().map(|(fetcher, quota)| FetchContext {
+        fetcher,
+        repo: obj_repo.clone(),
+        manager: manager.clone(),
+        limits: upload_limits,
+        ids: object_ids.clone(),
+        uploader: Uploader::detached(Some(provenance_repo.clone()))
+            .with_pipeline(pipeline.clone()),
+        quota,
+    });
+    let recovered = recover_creates(&obj_repo, manager.as_ref())
+        .await
+        .map_err(|e| format!("failed to recover object creations: {e}"))?;
+    if recovered.completed > 0 || recovered.aborted > 0 {
+        tracing::info!(
+            completed = recovered.completed,
+            aborted = recovered.aborted,
+            "recovered interrupted object creations",
+        );
+    }
+
+    let resumed = fetch_jobs
+        .resume(&jobs, fetch_ctx)
+        .await
+        .map_err(|e| format!("failed to resume fetch jobs: {e}"))?;
+    if resumed > 0 {
+        tracing::info!(count = resumed, "resumed interrupted fetch jobs");
+    }
+    if let Some(pipeline) = &pipeline {
+        let resumed = pipeline
+            .resume()
+            .await
+            .map_err(|e| format!("failed to resume post-processing: {e}"))?;
+        if resumed > 0 {
+            tracing::info!(
+                count = resumed,
+                "resumed interrupted post-processing"
+            );
+        }
     }
 
+    let sweeps = Arc::new(SweepCounters::default());
+    tokio::spawn(run_sweep(
+        manager.clone(),
+        sweeps.clone(),
+        cfg.storage.temp_sweep_interval,
+        cfg.storage.temp_max_age,
+    ));
+
+    let undelete_window = UndeleteWindow(cfg.storage.undelete_window);
+    if undelete_window.is_enabled() {
+        tokio::spawn(run_purge(
+            obj_repo.clone(),
+            manager.clone(),
+            undelete_window,
+        ));
+    }
+
+    let branding = match &cfg.branding {
+        Some(branding_cfg) => {
+            Some(Arc::new(Branding::new(branding_cfg).map_err(|e| {
+                format!("failed to load branding templates: {e}")
+            })?))
+        }
+        None => None,
+    };
+
+    let app = router(AppState {
+        obj_repo,
+        object_ids,
+        provenance_repo,
+        embargo_repo,
+        step_repo,
+        meta_repo,
+        history_repo,
+        download_stats,
+        manager,
+        jobs,
+        fetch_jobs,
+        maintenance,
+        sweeps,
+        undelete_window,
+        on_user_delete: cfg.storage.on_user_delete,
+        upload_limits,
+        upload_slots: Arc::new(UploadSlots::new(
+            &cfg.storage.upload_concurrency,
+        )),
+        write_locks,
+        stats_cache: Arc::new(StatsCache::new(Duration::from_secs(30))),
+        user_repo,
+        group_repo,
+        key_repo,
+        client_log_repo,
+        report_limiter: Arc::new(ReportLimiter::new(
+            cfg.client_logs.max_size,
+            cfg.client_logs.rate_limit,
+            Duration::from_secs(60),
+        )),
+        presign_repo,
+        totp_repo,
+        login_limiter: Arc::new(LoginLimiter::new(
+            cfg.auth.login_max_failures,
+            cfg.auth.login_lockout,
+            cfg.auth.login_max_lockout,
+        )),
+        token_repo: Arc::new(token_repo),
+        namespace_repo,
+        trusted_proxies: TrustedProxies::new(cfg.net.trusted_proxies.clone()),
+        timeouts: RequestTimeouts::new(&cfg.net.timeouts),
+        frontend_dir: (cfg.net.frontend_dir.as_ref())
+            .map(|dir| PathBuf::from(dir.as_str())),
+        oidc: (cfg.auth.oidc.as_ref())
+            .map(|oidc_cfg| Arc::new(OidcClient::new(oidc_cfg.clone()))),
+        branding,
+        fetcher,
+        scanner,
+        pipeline,
+    });
+
+    #[cfg(unix)]
+    let unix_listener = async {
+        let Some(path) = &cfg.net.unix_socket else {
+            return Ok(());
+        };
+        // Removed once dropped on shutdown
+        let listener = UnixSocketListener::bind(path, cfg.net.unix_socket_mode)
+            .map_err(|error| {
+                format!("failed to bind `{}`: {error}", path.display())
+            })?;
+
+        tracing::info!(
+            path = %listener.path().display(),
+            "listening for http connections",
+        );
+        listener.serve(app.clone()).await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    };
+    #[cfg(not(unix))]
+    let unix_listener = async { Ok::<_, Box<dyn Error + Send + Sync>>(()) };
+
+    let tcp_listener = async {
+        if !cfg.net.enable_http {
+            return Ok(());
+        }
+        let tls_cfg = load_tls_config(&cfg.ssl).await;
+
+        tracing::info!(
+            addr = %cfg.net.http_addr,
+            tls_enabled = tls_cfg.is_some(),
+            "listening for http connections",
+        );
+
+        let app = app.clone();
+        if let Some(tls_cfg) = tls_cfg {
+            let https = axum_server::bind_rustls(cfg.net.http_addr, tls_cfg)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+            let redirect = async {
+                if !cfg.ssl.redirect_http {
+                    return Ok(());
+                }
+                tracing::info!(
+                    addr = %cfg.ssl.redirect_addr,
+                    "redirecting http connections to https",
+                );
+                let router = redirect_router(
+                    cfg.net.http_addr.port(),
+                    cfg.ssl
+                        .acme_webroot
+                        .as_ref()
+                        .map(|dir| PathBuf::from(dir.as_str())),
+                );
+                axum_server::bind(cfg.ssl.redirect_addr)
+                    .serve(router.into_make_service())
+                    .await
+            };
+            try_join!(https, redirect)?;
+        } else {
+            axum_server::bind(cfg.net.http_addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    };
+
+    try_join!(unix_listener, tcp_listener)?;
+
     Ok(())
 }
 
@@ -92,11 +461,7 @@ async fn run(cfg: Config) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     select! {
         _ = signal => {}
-        res = run_http(&cfg) => {
-            if let Err(err) = res {
-                return Err(err);
-            }
-        }
+        res = run_http(&cfg) => res?,
     }
 
     tracing::info!("closed http server");
@@ -104,6 +469,54 @@ async fn run(cfg: Config) -> Result<(), Box<dyn Error + Send + Sync>> {
     Ok(())
 }
 
+async fn run_command(
+    cfg: Config,
+    command: Command,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        Command::Backup { path } => {
+            let db = open_db(&cfg).await?;
+            let info = create_backup(&db, &path).await?;
+            db.close().await;
+
+            tracing::info!(
+                path = %info.path.display(),
+                objects = info.objects,
+                size = info.size,
+                "created backup",
+            );
+        }
+        Command::Restore { path } => {
+            let sqlite_path = cfg.storage.state_dir.join("files.sqlite");
+            let manifest = restore_backup(&path, &sqlite_path).await?;
+
+            tracing::info!(
+                path = %path.display(),
+                created_at = %manifest.created_at,
+                objects = manifest.objects.len(),
+                "restored backup",
+            );
+
+            let manager = Backend::new(&cfg.storage);
+            for id in missing_objects(&manager, &manifest).await? {
+                tracing::warn!(%id, "data of restored object is missing");
+            }
+        }
+        Command::MigrateLayout => {
+            let manager = ObjectManager::new(&cfg.storage);
+            let moved = manager.migrate_layout().await?;
+
+            tracing::info!(
+                layout = ?cfg.storage.data_layout,
+                moved,
+                "migrated data layout",
+            );
+        }
+    }
+
+    Ok(())
+}
+
 fn touch_file(path: &Path) -> Result<(), String> {
     std::fs::File::open(path)
         .or_else(|err| {
@@ -138,32 +551,49 @@ async fn load_tls_config(cfg: &config::SslConfig) -> Option<RustlsConfig> {
     .ok()
 }
 
-fn main() {
-    let args = Args::parse();
-
-    if args.debug {
-        let builder =
-            tracing_subscriber::fmt().with_max_level(LevelFilter::DEBUG);
-
-        if args.json_logs {
-            builder.json().init();
+fn init_tracing(
+    args: &Args,
+    provider: Option<&SdkTracerProvider>,
+    access_log: Option<AccessLogLayer>,
+) {
+    let filter = || {
+        if args.debug {
+            EnvFilter::default().add_directive(LevelFilter::DEBUG.into())
         } else {
-            builder.init();
-        }
-    } else {
-        let builder = tracing_subscriber::fmt().with_env_filter(
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        );
-
-        if args.json_logs {
-            builder.json().init();
-        } else {
-            builder.init();
+                .from_env_lossy()
         }
-    }
+    };
+
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = if args.json_logs {
+        fmt.json().with_filter(filter()).boxed()
+    } else {
+        fmt.with_filter(filter()).boxed()
+    };
+
+    let otel = provider.map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("downloader"))
+            .with_filter(filter())
+    });
+
+    // Not affected by the log level, every request is logged
+    let access_log = access_log
+        .map(|layer| layer.with_filter(filter_fn(AccessLogLayer::wants)));
 
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(otel)
+        .with(access_log)
+        .init();
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // Loaded before logging is set up, fatal errors are still printed
     let cfg = match config::load(&args.config_path) {
         Ok(v) => v,
         Err(err) => {
@@ -176,13 +606,37 @@ fn main() {
         }
     };
 
+    let provider = cfg.telemetry.as_ref().map(|telemetry_cfg| {
+        telemetry::tracer_provider(telemetry_cfg).unwrap_or_else(|err| {
+            fatal!("Failed to create the trace exporter: {err}")
+        })
+    });
+    let access_log = cfg.logging.access_log.then(|| {
+        let dir = cfg.storage.state_dir.join("logs");
+        AccessLogLayer::new(dir, &cfg.logging).unwrap_or_else(|err| {
+            fatal!("Failed to open the access log: {err}")
+        })
+    });
+    init_tracing(&args, provider.as_ref(), access_log);
+
     tracing::debug!(config = ?cfg, "loaded configuration");
 
     let tokio_result = Builder::new_multi_thread()
         .enable_all()
         .build()
         .expect("Failed building the Runtime")
-        .block_on(run(cfg));
+        .block_on(async move {
+            match args.command {
+                Some(command) => run_command(cfg, command).await,
+                None => run(cfg).await,
+            }
+        });
+
+    if let Some(provider) = provider {
+        if let Err(error) = provider.shutdown() {
+            tracing::error!(%error, "failed to flush pending traces");
+        }
+    }
 
     if let Err(e) = tokio_result {
         fatal!("Unhandled error: {e}");
@@ -0,0 +1,255 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    config::MaintenanceConfig,
+    errors::DownloaderError,
+    job::{queue::JobQueue, Job, JobError, JobKind},
+};
+
+/// How often the schedule is checked for a due run.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// SQLite value of `PRAGMA auto_vacuum` for incremental vacuums.
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// Summary of a finished maintenance run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    /// Whether the database was fully vacuumed to enable incremental
+    /// vacuums, which only happens once.
+    pub full_vacuum: bool,
+    /// Free pages returned to the filesystem.
+    pub vacuumed_pages: u64,
+    /// Pages of the write-ahead log written back to the database.
+    pub checkpointed_pages: u64,
+    /// Whether the checkpoint was blocked by readers or writers, leaving
+    /// part of the log behind.
+    pub checkpoint_busy: bool,
+}
+
+/// Keeps the metadata database from bloating, returning the pages freed by
+/// deletions to the filesystem, refreshing the query planner statistics and
+/// truncating the write-ahead log.
+#[derive(Clone)]
+pub struct Maintenance {
+    db: SqlitePool,
+    vacuum_pages: u32,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Maintenance {
+    pub fn new(db: SqlitePool, vacuum_pages: u32) -> Self {
+        Self {
+            db,
+            vacuum_pages,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Runs the maintenance right away, waiting for a run in progress to
+    /// finish first.
+    pub async fn run(&self) -> Result<MaintenanceReport, sqlx::Error> {
+        let _guard = self.lock.lock().await;
+        let mut conn = self.db.acquire().await?;
+
+        // Changing the mode only takes effect after a full vacuum
+        let (auto_vacuum,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+            .fetch_one(&mut *conn)
+            .await?;
+        let full_vacuum = auto_vacuum != AUTO_VACUUM_INCREMENTAL;
+        if full_vacuum {
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        }
+
+        let (free_before,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+            .fetch_one(&mut *conn)
+            .await?;
+        sqlx::query(&format!(
+            "PRAGMA incremental_vacuum({})",
+            self.vacuum_pages
+        ))
+        .execute(&mut *conn)
+        .await?;
+        let (free_after,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+            .fetch_one(&mut *conn)
+            .await?;
+
+        sqlx::query("ANALYZE").execute(&mut *conn).await?;
+
+        // Returns -1 for the pages outside of the WAL mode
+        let (busy, _, checkpointed): (i64, i64, i64) =
+            sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+                .fetch_one(&mut *conn)
+                .await?;
+
+        Ok(MaintenanceReport {
+            full_vacuum,
+            vacuumed_pages: (free_before - free_after).max(0) as u64,
+            checkpointed_pages: checkpointed.max(0) as u64,
+            checkpoint_busy: busy != 0,
+        })
+    }
+
+    /// Queues a maintenance run as a job started by `user_id`.
+    pub async fn enqueue(
+        &self,
+        jobs: &JobQueue,
+        user_id: Option<Uuid>,
+    ) -> Result<Job, JobError> {
+        let maintenance = self.clone();
+        jobs.enqueue(JobKind::Maintenance, user_id, |_| async move {
+            let report = maintenance.run().await.map_err(|error| {
+                tracing::error!(%error, "failed to run database maintenance");
+                DownloaderError::Other(
+                    error.to_string(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })?;
+
+            tracing::info!(
+                full_vacuum = report.full_vacuum,
+                vacuumed_pages = report.vacuumed_pages,
+                checkpointed_pages = report.checkpointed_pages,
+                checkpoint_busy = report.checkpoint_busy,
+                "ran database maintenance",
+            );
+            Ok(report)
+        })
+        .await
+    }
+}
+
+/// Whether a scheduled run is due at `now`, the last one started at
+/// `last_run`.
+fn is_due(
+    cfg: &MaintenanceConfig,
+    now: DateTime<Utc>,
+    last_run: Option<DateTime<Utc>>,
+) -> bool {
+    let in_window = cfg.windows.is_empty()
+        || cfg.windows.iter().any(|window| window.contains(now.time()));
+    let elapsed = last_run.is_none_or(|last_run| {
+        (now - last_run).to_std().unwrap_or_default() >= cfg.interval
+    });
+    in_window && elapsed
+}
+
+/// Periodically queues maintenance runs in the windows of `cfg`. Without
+/// windows, the first run happens an interval after the start.
+pub async fn run_maintenance(
+    maintenance: Maintenance,
+    jobs: Arc<JobQueue>,
+    cfg: MaintenanceConfig,
+) {
+    let mut last_run = cfg.windows.is_empty().then(Utc::now);
+    let mut interval = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now();
+        if !is_due(&cfg, now, last_run) {
+            continue;
+        }
+
+        match maintenance.enqueue(&jobs, None).await {
+            Ok(..) => last_run = Some(now),
+            Err(error) => {
+                tracing::error!(%error, "failed to queue database maintenance")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone};
+    use sqlx::sqlite::SqliteConnectOptions;
+    use test_log::test;
+
+    use crate::config::MaintenanceWindow;
+
+    use super::*;
+
+    async fn churn(db: &SqlitePool) {
+        let blob = vec![0u8; 4096];
+        for _ in 0..64 {
+            sqlx::query("INSERT INTO churn (data) VALUES ($1)")
+                .bind(blob.as_slice())
+                .execute(db)
+                .await
+                .unwrap();
+        }
+        sqlx::query("DELETE FROM churn").execute(db).await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(dir.path().join("files.sqlite"))
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        sqlx::query("CREATE TABLE churn (data blob NOT NULL)")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let maintenance = Maintenance::new(db.clone(), 0);
+        churn(&db).await;
+
+        let report = maintenance.run().await.unwrap();
+        assert!(report.full_vacuum);
+        assert!(!report.checkpoint_busy);
+
+        churn(&db).await;
+        let report = maintenance.run().await.unwrap();
+        assert!(!report.full_vacuum);
+        assert!(report.vacuumed_pages >= 64, "{report:?}");
+
+        let (free,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(free, 0);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 1, 2, h, m, 0).unwrap();
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+
+        let mut cfg = MaintenanceConfig {
+            interval: Duration::from_secs(3600),
+            windows: Vec::new(),
+            vacuum_pages: 0,
+        };
+        assert!(is_due(&cfg, at(12, 0), None));
+        assert!(!is_due(&cfg, at(12, 0), Some(at(11, 30))));
+        assert!(is_due(&cfg, at(12, 0), Some(at(11, 0))));
+
+        // Crossing midnight
+        cfg.windows.push(MaintenanceWindow {
+            start: time(23),
+            end: time(2),
+        });
+        assert!(is_due(&cfg, at(23, 30), None));
+        assert!(is_due(&cfg, at(1, 59), None));
+        assert!(!is_due(&cfg, at(2, 0), None));
+        assert!(!is_due(&cfg, at(12, 0), None));
+        assert!(!is_due(&cfg, at(0, 30), Some(at(0, 0))));
+    }
+}
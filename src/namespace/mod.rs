@@ -0,0 +1,336 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Sqlite, Type};
+
+use crate::{
+    errors::DownloaderError, group::repository::GroupRepository,
+    storage::repository::ObjectRepository, user::repository::UserRepository,
+};
+
+use self::repository::NamespaceRepository;
+
+pub mod repository;
+pub mod routes;
+
+/// The namespace of the users and objects created before namespaces, and
+/// of the requests made without the `/api/ns/:namespace` prefix.
+pub const DEFAULT_NAMESPACE: &str = "default";
+const MAX_NAME_LEN: usize = 32;
+const PREFIX: &str = "/api/ns/";
+
+#[derive(Debug, thiserror::Error)]
+pub enum NamespaceError {
+    #[error(
+        "invalid namespace name `{0}`, expected up to {MAX_NAME_LEN} \
+        lowercase letters, digits and dashes"
+    )]
+    InvalidName(String),
+    #[error("namespace `{0}` not found")]
+    NotFound(String),
+    #[error("namespace `{0}` already exists")]
+    AlreadyExists(String),
+    #[error(
+        "namespace `{0}` still has users, groups or objects, delete them first"
+    )]
+    NotEmpty(String),
+    #[error("the default namespace can not be deleted")]
+    DeleteDefault,
+    #[error("sqlx error: {0}")]
+    Sqlx(sqlx::Error),
+}
+
+impl NamespaceError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            NamespaceError::InvalidName(..) => StatusCode::BAD_REQUEST,
+            NamespaceError::NotFound(..) => StatusCode::NOT_FOUND,
+            NamespaceError::AlreadyExists(..) => StatusCode::CONFLICT,
+            NamespaceError::NotEmpty(..) => StatusCode::CONFLICT,
+            NamespaceError::DeleteDefault => StatusCode::BAD_REQUEST,
+            NamespaceError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    NamespaceError {
+        InvalidName(..) => 1, "the namespace name is invalid";
+        NotFound(..) => 2, "namespace not found";
+        AlreadyExists(..) => 3, "the namespace already exists";
+        NotEmpty(..) => 4, "the namespace still has users, groups or objects";
+        DeleteDefault => 5, "the default namespace can not be deleted";
+        Sqlx(..) => 6, "database error";
+    }
+}
+
+/// An isolated group of users and the objects they own. User tokens are
+/// only accepted in the namespace of their user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Namespace
+where
+    &'r str: ColumnIndex<R>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let created_at: i64 = row.try_get("created_at")?;
+        let created_at = DateTime::from_timestamp_millis(created_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `created_at` field gone wrong".into(),
+                )
+            })?;
+
+        Ok(Self {
+            name: row.try_get("name")?,
+            created_at,
+        })
+    }
+}
+
+pub fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_owned()
+}
+
+/// Names are used in paths, so they are kept to lowercase letters, digits
+/// and dashes, not at the ends.
+pub fn validate_name(name: &str) -> Result<(), NamespaceError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+    if !valid {
+        return Err(NamespaceError::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// The namespace a request was made in, the default one unless it was
+/// routed by [`route_namespaces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestNamespace(pub String);
+
+impl Default for RequestNamespace {
+    fn default() -> Self {
+        Self(default_namespace())
+    }
+}
+
+impl RequestNamespace {
+    pub fn of(parts: &Parts) -> Self {
+        parts.extensions.get::<Self>().cloned().unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestNamespace {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::of(parts))
+    }
+}
+
+/// Routes the requests to `/api/ns/:namespace/*path` as `/api/*path`,
+/// marking them with their [`RequestNamespace`]. It must wrap the router,
+/// as layers of the router run after the routing.
+pub async fn route_namespaces(
+    State(repo): State<NamespaceRepository<Sqlite>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(rest) = req.uri().path().strip_prefix(PREFIX) else {
+        return next.run(req).await;
+    };
+    let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let name = name.to_owned();
+
+    match repo.exists(&name).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return DownloaderError::from(NamespaceError::NotFound(name))
+                .into_response()
+        }
+        Err(error) => return DownloaderError::from(error).into_response(),
+    }
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("/api/{path}?{query}"),
+        None => format!("/api/{path}"),
+    };
+    let mut uri = req.uri().clone().into_parts();
+    uri.path_and_query = path_and_query.parse().ok();
+    let Ok(uri) = Uri::from_parts(uri) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    *req.uri_mut() = uri;
+    req.extensions_mut().insert(RequestNamespace(name));
+    next.run(req).await
+}
+
+/// Scopes the object, user and group repositories of the request to its
+/// namespace, so the handlers neither see nor change the users, groups and
+/// objects of other namespaces.
+pub async fn scope_namespace(
+    RequestNamespace(namespace): RequestNamespace,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let extensions = req.extensions_mut();
+
+    if let Some(repo) = extensions.get::<ObjectRepository<Sqlite>>() {
+        let repo = repo.in_namespace(&namespace);
+        extensions.insert(repo);
+    }
+    if let Some(repo) = extensions.get::<UserRepository<Sqlite>>() {
+        let repo = repo.in_namespace(&namespace);
+        extensions.insert(repo);
+    }
+    if let Some(repo) = extensions.get::<GroupRepository<Sqlite>>() {
+        let repo = repo.in_namespace(&namespace);
+        extensions.insert(repo);
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+        middleware, routing, Extension, Router,
+    };
+    use sqlx::{migrate, SqlitePool};
+    use tower::ServiceExt;
+
+    use crate::{
+        auth::{
+            axum::Authorization, repository::tests::repository, Permission,
+        },
+        user::{password::PasswordHasher, UserData, UserError},
+    };
+
+    use super::*;
+
+    async fn get_namespace(
+        Authorization(_): Authorization,
+        Extension(users): Extension<UserRepository<Sqlite>>,
+    ) -> String {
+        users.namespace().to_owned()
+    }
+
+    #[test]
+    fn test_validate_name() {
+        for name in ["default", "team-a", "a", "0x1", &"a".repeat(32)] {
+            validate_name(name).unwrap();
+        }
+        for name in ["", "-a", "a-", "Team", "a_b", "a/b", &"a".repeat(33)] {
+            assert!(matches!(
+                validate_name(name),
+                Err(NamespaceError::InvalidName(..)),
+            ));
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_route_namespaces() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let namespaces = NamespaceRepository::new(db.clone());
+        namespaces.create("team-a").await.unwrap();
+
+        let users = UserRepository::new(db, PasswordHasher::bcrypt(4));
+        let token_repo = Arc::new(repository());
+
+        let data = || UserData {
+            username: "alice".into(),
+            password: "password".into(),
+        };
+        let default_user = users
+            .create(Permission::UNPRIVILEGED, data())
+            .await
+            .unwrap();
+        let team_user = users
+            .in_namespace("team-a")
+            .create(Permission::UNPRIVILEGED, data())
+            .await
+            .unwrap();
+        assert_eq!(team_user.namespace, "team-a");
+        assert!(matches!(
+            users.in_namespace("team-a").get(default_user.id).await,
+            Err(UserError::NotFound),
+        ));
+
+        let api = Router::new()
+            .route("/api/namespace", routing::get(get_namespace))
+            .layer(middleware::from_fn(scope_namespace))
+            .layer(Extension(users))
+            .layer(Extension(token_repo.clone()));
+        let router = Router::new().fallback_service(api).layer(
+            middleware::from_fn_with_state(namespaces, route_namespaces),
+        );
+
+        let get = |uri: &str, user: &crate::user::User| {
+            let token = token_repo
+                .generate_login_token(user, user.permission, false)
+                .unwrap();
+            let req = Request::get(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let res = get("/api/namespace", &default_user).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, DEFAULT_NAMESPACE);
+
+        let res = get("/api/ns/team-a/namespace", &team_user).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "team-a");
+
+        // User tokens are only accepted in the namespace of the user
+        let res = get("/api/namespace", &team_user).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = get("/api/ns/team-a/namespace", &default_user)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = get("/api/ns/team-b/namespace", &team_user).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
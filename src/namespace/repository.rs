@@ -0,0 +1,199 @@
+use chrono::Utc;
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+
+use crate::utils::retry::retry_busy;
+
+use super::{validate_name, Namespace, NamespaceError, DEFAULT_NAMESPACE};
+
+pub struct NamespaceRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for NamespaceRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> NamespaceRepository<DB> {
+    pub fn new(db: Pool<DB>) -> NamespaceRepository<DB> {
+        NamespaceRepository { db }
+    }
+}
+
+impl<DB> NamespaceRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> Namespace: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    pub async fn get_all(&self) -> Result<Vec<Namespace>, NamespaceError> {
+        sqlx::query_as("SELECT * FROM namespace ORDER BY name")
+            .fetch_all(&self.db)
+            .await
+            .map_err(sqlx_error)
+    }
+
+    pub async fn exists(&self, name: &str) -> Result<bool, NamespaceError> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM namespace WHERE name = $1")
+                .bind(name)
+                .fetch_one(&self.db)
+                .await
+                .map_err(sqlx_error)?;
+
+        Ok(count > 0)
+    }
+
+    pub async fn create(
+        &self,
+        name: &str,
+    ) -> Result<Namespace, NamespaceError> {
+        validate_name(name)?;
+
+        retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO namespace (name, created_at) VALUES ($1, $2) \
+                RETURNING *",
+            )
+            .bind(name)
+            .bind(Utc::now().timestamp_millis())
+            .fetch_one(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            if matches!(
+                &error,
+                sqlx::Error::Database(e) if e.is_unique_violation(),
+            ) {
+                return NamespaceError::AlreadyExists(name.to_owned());
+            }
+            sqlx_error(error)
+        })
+    }
+
+    /// Deletes the namespace `name`, which must have no users, groups nor
+    /// objects left, deleted ones included.
+    pub async fn delete(
+        &self,
+        name: &str,
+    ) -> Result<Namespace, NamespaceError> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(NamespaceError::DeleteDefault);
+        }
+
+        retry_busy(|| self.delete_once(name))
+            .await
+            .map_err(sqlx_error)?
+    }
+
+    async fn delete_once(
+        &self,
+        name: &str,
+    ) -> Result<Result<Namespace, NamespaceError>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT \
+            (SELECT COUNT(*) FROM user WHERE namespace = $1) + \
+            (SELECT COUNT(*) FROM \"group\" WHERE namespace = $1) + \
+            (SELECT COUNT(*) FROM object WHERE namespace = $1) + \
+            (SELECT COUNT(*) FROM deleted_object WHERE namespace = $1)",
+        )
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+        if count > 0 {
+            return Ok(Err(NamespaceError::NotEmpty(name.to_owned())));
+        }
+
+        let namespace =
+            sqlx::query_as("DELETE FROM namespace WHERE name = $1 RETURNING *")
+                .bind(name)
+                .fetch_optional(&mut *tx)
+                .await?;
+        tx.commit().await?;
+
+        Ok(namespace.ok_or_else(|| NamespaceError::NotFound(name.to_owned())))
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> NamespaceError {
+    tracing::error!(%error, "got sqlx error while querying namespaces");
+    NamespaceError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_namespaces() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = NamespaceRepository::new(db.clone());
+
+        assert!(repo.exists(DEFAULT_NAMESPACE).await.unwrap());
+        assert!(!repo.exists("team-a").await.unwrap());
+
+        let namespace = repo.create("team-a").await.unwrap();
+        assert_eq!(namespace.name, "team-a");
+        assert!(repo.exists("team-a").await.unwrap());
+        assert!(matches!(
+            repo.create("team-a").await,
+            Err(NamespaceError::AlreadyExists(..)),
+        ));
+        assert!(matches!(
+            repo.create("Team A").await,
+            Err(NamespaceError::InvalidName(..)),
+        ));
+
+        let names: Vec<_> = repo
+            .get_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|namespace| namespace.name)
+            .collect();
+        assert_eq!(names, ["default", "team-a"]);
+
+        sqlx::query(
+            "INSERT INTO user \
+            (id, created_at, updated_at, permission, username, password, \
+            namespace) VALUES (x'00', 0, 0, 0, 'a', '', 'team-a')",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        assert!(matches!(
+            repo.delete("team-a").await,
+            Err(NamespaceError::NotEmpty(..)),
+        ));
+
+        sqlx::query("DELETE FROM user").execute(&db).await.unwrap();
+        assert_eq!(repo.delete("team-a").await.unwrap(), namespace);
+        assert!(matches!(
+            repo.delete("team-a").await,
+            Err(NamespaceError::NotFound(..)),
+        ));
+        assert!(matches!(
+            repo.delete(DEFAULT_NAMESPACE).await,
+            Err(NamespaceError::DeleteDefault),
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+use axum::{extract::Path, routing, Extension, Router};
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Token},
+    errors::DownloaderError,
+    utils::extractors::Body,
+};
+
+use super::{repository::NamespaceRepository, Namespace};
+
+pub fn namespace_routes<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router
+        .route("/", routing::get(get_namespaces))
+        .route("/", routing::post(post_namespace))
+        .route("/:name", routing::delete(delete_namespace))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceRequestData {
+    pub name: String,
+}
+
+/// Namespaces are managed by the administrators of the server, not by the
+/// ones of each namespace.
+fn require_server_admin(token: &Token) -> Result<(), DownloaderError> {
+    if !token.is_server_admin() {
+        return Err(AuthError::AccessDenied.into());
+    }
+    Ok(())
+}
+
+pub async fn get_namespaces(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<NamespaceRepository<Sqlite>>,
+) -> Result<Body<Vec<Namespace>>, DownloaderError> {
+    require_server_admin(&token)?;
+    Ok(Body(repo.get_all().await?))
+}
+
+pub async fn post_namespace(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<NamespaceRepository<Sqlite>>,
+    Body(data): Body<NamespaceRequestData>,
+) -> Result<Body<Namespace>, DownloaderError> {
+    require_server_admin(&token)?;
+    Ok(Body(repo.create(&data.name).await?))
+}
+
+pub async fn delete_namespace(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<NamespaceRepository<Sqlite>>,
+    Path(name): Path<String>,
+) -> Result<Body<Namespace>, DownloaderError> {
+    require_server_admin(&token)?;
+    Ok(Body(repo.delete(&name).await?))
+}
@@ -0,0 +1,283 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, Extensions, HeaderMap},
+};
+use ipnet::IpNet;
+
+#[cfg(unix)]
+use crate::unix_socket::UnixPeer;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Networks of the reverse proxies whose forwarding headers are trusted.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<[IpNet]>);
+
+impl TrustedProxies {
+    pub fn new(nets: impl Into<Arc<[IpNet]>>) -> Self {
+        Self(nets.into())
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client of a request received from `peer`.
+    ///
+    /// The hops in the `Forwarded` header, or `X-Forwarded-For` if it is
+    /// missing, are walked from the nearest one while they are trusted
+    /// proxies, as any hop before an untrusted one may be forged.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        self.forwarded_client(headers).unwrap_or(peer)
+    }
+
+    /// The address of the client as told by the headers of a request from
+    /// a trusted peer, `None` if they tell none.
+    pub fn forwarded_client(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut hops = forwarded_hops(headers);
+        if hops.is_empty() {
+            hops = x_forwarded_for_hops(headers);
+        }
+
+        let mut client = None;
+        for hop in hops.into_iter().rev() {
+            // Nothing before an unknown or obfuscated hop can be relied on,
+            // so the request is attributed to the proxy that added it
+            let Some(ip) = hop else {
+                break;
+            };
+            let ip = ip.to_canonical();
+            client = Some(ip);
+            if !self.is_trusted(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// The `for` parameters of all `Forwarded` headers, in order, with `None`
+/// for those not holding an address.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, node)| parse_node(node.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+/// Parses an address, optionally with a port and IPv6 addresses optionally
+/// in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+/// Address of the client of the request, as told by trusted proxies when
+/// behind them. `None` when the peer is not known, as in tests or when
+/// received on the unix socket without forwarding headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(extensions: &Extensions, headers: &HeaderMap) -> Self {
+        let proxies = extensions.get::<TrustedProxies>();
+        #[cfg(unix)]
+        if extensions.get::<ConnectInfo<UnixPeer>>().is_some() {
+            return Self(proxies.and_then(|p| p.forwarded_client(headers)));
+        }
+
+        let Some(ConnectInfo(peer)) =
+            extensions.get::<ConnectInfo<SocketAddr>>()
+        else {
+            return Self(None);
+        };
+        let ip = match proxies {
+            Some(proxies) => proxies.client_ip(peer.ip(), headers),
+            None => peer.ip().to_canonical(),
+        };
+        Self(Some(ip))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::of(&parts.extensions, &parts.headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "::1/128".parse().unwrap(),
+        ])
+    }
+
+    fn header_map(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let headers = header_map(&[(X_FORWARDED_FOR, "1.1.1.1")]);
+        assert_eq!(proxies().client_ip(ip("8.8.8.8"), &headers), ip("8.8.8.8"),);
+        assert_eq!(
+            TrustedProxies::default().client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1"),
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let proxies = proxies();
+
+        let headers = header_map(&[(X_FORWARDED_FOR, "1.1.1.1, 10.0.0.2")]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("1.1.1.1"));
+
+        // Hops before the first untrusted one may be forged by the client
+        let headers =
+            header_map(&[(X_FORWARDED_FOR, "6.6.6.6, 2.2.2.2, 10.0.0.2")]);
+        assert_eq!(proxies.client_ip(ip("::1"), &headers), ip("2.2.2.2"));
+
+        let headers = header_map(&[
+            (X_FORWARDED_FOR, "3.3.3.3"),
+            (X_FORWARDED_FOR, "10.0.0.3:4000"),
+        ]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("3.3.3.3"));
+
+        // Only trusted hops
+        let headers = header_map(&[(X_FORWARDED_FOR, "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"),);
+
+        let headers = header_map(&[(X_FORWARDED_FOR, "1.1.1.1, garbage")]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"),);
+
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1"),
+        );
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies = proxies();
+
+        let headers = header_map(&[
+            ("forwarded", "for=1.1.1.1;proto=https"),
+            (X_FORWARDED_FOR, "6.6.6.6"),
+        ]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("1.1.1.1"));
+
+        let headers = header_map(&[(
+            "forwarded",
+            r#"For="[2001:db8::1]:4711", for=10.0.0.2;by=10.0.0.1"#,
+        )]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8::1"),
+        );
+
+        let headers = header_map(&[(
+            "forwarded",
+            "for=1.1.1.1, for=_hidden, for=10.0.0.2",
+        )]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"),);
+    }
+
+    #[test]
+    fn test_client_ip_of() {
+        let mut extensions = Extensions::new();
+        let headers = header_map(&[(X_FORWARDED_FOR, "1.1.1.1")]);
+        assert_eq!(ClientIp::of(&extensions, &headers), ClientIp(None));
+
+        extensions.insert(ConnectInfo(SocketAddr::from((
+            ip("::ffff:10.0.0.1"),
+            8080,
+        ))));
+        assert_eq!(
+            ClientIp::of(&extensions, &headers),
+            ClientIp(Some(ip("10.0.0.1"))),
+        );
+
+        extensions.insert(proxies());
+        assert_eq!(
+            ClientIp::of(&extensions, &headers),
+            ClientIp(Some(ip("1.1.1.1"))),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client_ip_unix() {
+        let headers = header_map(&[(X_FORWARDED_FOR, "1.1.1.1")]);
+
+        // Peers of the unix socket are trusted whatever the networks
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(UnixPeer));
+        extensions.insert(TrustedProxies::default());
+        assert_eq!(
+            ClientIp::of(&extensions, &headers),
+            ClientIp(Some(ip("1.1.1.1"))),
+        );
+        assert_eq!(
+            ClientIp::of(&extensions, &HeaderMap::new()),
+            ClientIp(None),
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{io, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::Path,
+    http::{header, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing, Extension, Router,
+};
+
+use crate::errors::DownloaderError;
+
+const ACME_CHALLENGE_DIR: &str = ".well-known/acme-challenge";
+
+#[derive(Clone)]
+struct RedirectConfig {
+    https_port: u16,
+    acme_webroot: Option<Arc<PathBuf>>,
+}
+
+/// Router of the plain http listener, which redirects requests to the same
+/// host on `https_port` and serves ACME http-01 challenges from
+/// `acme_webroot` when set.
+pub fn redirect_router(
+    https_port: u16,
+    acme_webroot: Option<PathBuf>,
+) -> Router {
+    Router::new()
+        .route(
+            &format!("/{ACME_CHALLENGE_DIR}/:token"),
+            routing::get(get_acme_challenge),
+        )
+        .fallback(redirect_https)
+        .layer(Extension(RedirectConfig {
+            https_port,
+            acme_webroot: acme_webroot.map(Arc::new),
+        }))
+}
+
+async fn redirect_https(
+    Extension(cfg): Extension<RedirectConfig>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, DownloaderError> {
+    let authority = match uri.authority() {
+        Some(authority) => Some(authority.clone()),
+        None => headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok()),
+    };
+    let Some(authority) = authority else {
+        return Err(DownloaderError::Other(
+            "missing or invalid host header".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let port = match cfg.https_port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let location = format!("https://{}{port}{path}", authority.host());
+
+    Ok((
+        StatusCode::MOVED_PERMANENTLY,
+        [(header::LOCATION, location)],
+    )
+        .into_response())
+}
+
+async fn get_acme_challenge(
+    Extension(cfg): Extension<RedirectConfig>,
+    Path(token): Path<String>,
+) -> Result<Response, DownloaderError> {
+    let not_found = || {
+        DownloaderError::Other(
+            "challenge not found".into(),
+            StatusCode::NOT_FOUND,
+        )
+    };
+
+    let Some(webroot) = cfg.acme_webroot else {
+        return Err(not_found());
+    };
+    // Tokens are base64url, which also keeps the path inside the webroot
+    if token.is_empty()
+        || !token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(not_found());
+    }
+
+    let path = webroot.join(ACME_CHALLENGE_DIR).join(&token);
+    match tokio::fs::read(&path).await {
+        Ok(data) => {
+            tracing::info!(%token, "served acme challenge");
+            Ok(([(header::CONTENT_TYPE, "text/plain")], data).into_response())
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Err(not_found())
+        }
+        Err(error) => Err(DownloaderError::Other(
+            format!("failed to read challenge: {error}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use test_log::test;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get(router: &Router, uri: &str, host: Option<&str>) -> Response {
+        let mut req = Request::get(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn location(res: &Response) -> &str {
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        res.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_redirect() {
+        let router = redirect_router(443, None);
+        let res = get(&router, "/files/1?a=b", Some("example.com")).await;
+        assert_eq!(location(&res), "https://example.com/files/1?a=b");
+
+        // The port of the http listener is replaced
+        let router = redirect_router(8443, None);
+        let res = get(&router, "/", Some("example.com:80")).await;
+        assert_eq!(location(&res), "https://example.com:8443/");
+
+        let res = get(&router, "/", Some("[::1]:80")).await;
+        assert_eq!(location(&res), "https://[::1]:8443/");
+
+        let res = get(&router, "/", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = get(&router, "/", Some("bad host")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_acme_challenge() {
+        let dir = tempfile::tempdir().unwrap();
+        let challenges = dir.path().join(ACME_CHALLENGE_DIR);
+        std::fs::create_dir_all(&challenges).unwrap();
+        std::fs::write(challenges.join("tok_en-1"), "tok_en-1.key").unwrap();
+        std::fs::write(dir.path().join("secret"), "secret").unwrap();
+
+        let router = redirect_router(443, Some(dir.path().to_owned()));
+        let uri = format!("/{ACME_CHALLENGE_DIR}/tok_en-1");
+        let res = get(&router, &uri, Some("example.com")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"tok_en-1.key");
+
+        for token in ["missing", "..%2Fsecret", "%2E%2E"] {
+            let uri = format!("/{ACME_CHALLENGE_DIR}/{token}");
+            let res = get(&router, &uri, Some("example.com")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{token}");
+        }
+
+        // Not served without a webroot
+        let router = redirect_router(443, None);
+        let res = get(&router, &uri, Some("example.com")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,8 +1,14 @@
-use std::{fmt::Display, iter::once, time::Duration};
+use std::{
+    borrow::Cow, fmt::Display, iter::once, path::Path, sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     body::Body,
-    http::{header, HeaderValue},
+    extract::Request,
+    handler::Handler,
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing, Router,
 };
@@ -12,17 +18,44 @@ use tower_http::{
     cors::CorsLayer,
     decompression::RequestDecompressionLayer,
     normalize_path::NormalizePathLayer,
+    request_id::{
+        MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+    },
     sensitive_headers::SetSensitiveHeadersLayer,
     set_header::SetResponseHeaderLayer,
     trace::{MakeSpan, OnFailure, OnRequest, OnResponse, TraceLayer},
 };
-use tracing::Level;
+use tracing::{field::Empty, Level};
 
 use crate::{
-    errors::{DownloaderError, HttpError},
-    utils::fmt::fmt_duration,
+    errors::{problem_errors, DownloaderError, HttpError},
+    locale::scope_locale,
+    proxy::ClientIp,
+    utils::{extractors::scope_format, fmt::fmt_duration},
 };
 
+tokio::task_local! {
+    static REQUEST_ID: HeaderValue;
+}
+
+/// Id of the request being handled, set by [`layer_root_router`].
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(|id| id.to_str().ok().map(str::to_owned))
+        .ok()
+        .flatten()
+}
+
+async fn scope_request_id(req: Request, next: Next) -> Response {
+    match req.extensions().get::<RequestId>() {
+        Some(id) => {
+            let id = id.header_value().clone();
+            REQUEST_ID.scope(id, next.run(req)).await
+        }
+        None => next.run(req).await,
+    }
+}
+
 #[cfg(feature = "embed")]
 #[derive(rust_embed::Embed)]
 #[folder = "frontend/build"]
@@ -39,6 +72,9 @@ impl<B> OnResponse<B> for CustomOnResponse {
         latency: Duration,
         span: &tracing::Span,
     ) {
+        span.record("status", response.status().as_u16());
+        span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+
         let _guard = span.enter();
         let latency = fmt_duration(latency);
 
@@ -77,12 +113,27 @@ struct CustomMakeSpan;
 impl<B> MakeSpan<B> for CustomMakeSpan {
     #[inline]
     fn make_span(&mut self, request: &axum::http::Request<B>) -> tracing::Span {
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok());
+        let ClientIp(client_ip) =
+            ClientIp::of(request.extensions(), request.headers());
+
         tracing::span!(
             Level::INFO,
             "request",
+            request_id,
             method = %request.method().as_str(),
             path = %request.uri().path(),
             version = ?request.version(),
+            client_ip = client_ip.map(tracing::field::display),
+            // Recorded once known, by the handlers and extractors
+            user_id = Empty,
+            object_id = Empty,
+            bytes = Empty,
+            status = Empty,
+            latency_ms = Empty,
         )
     }
 }
@@ -135,32 +186,71 @@ impl ResponseForPanic for JsonPanicHandler {
     }
 }
 
-#[cfg(not(feature = "embed"))]
-async fn fallback_handler() -> Response {
-    DownloaderError::Http(HttpError::RouteNotFound).into_response()
+/// Where the frontend is served from, see [`layer_root_router`].
+#[derive(Debug, Clone)]
+enum Frontend {
+    /// Files of a directory, which can be updated without rebuilding.
+    Dir(Arc<Path>),
+    #[cfg(feature = "embed")]
+    Embedded,
 }
 
-#[cfg(feature = "embed")]
-async fn fallback_handler(req: axum::extract::Request) -> Response {
-    use std::borrow::Cow;
+impl Frontend {
+    /// The directory if set, else the embedded files if compiled in.
+    fn new(dir: Option<&Path>) -> Option<Self> {
+        match dir {
+            Some(dir) => Some(Frontend::Dir(dir.into())),
+            #[cfg(feature = "embed")]
+            None => Some(Frontend::Embedded),
+            #[cfg(not(feature = "embed"))]
+            None => None,
+        }
+    }
+
+    /// The type and content of the file at `path`.
+    async fn get(&self, path: &str) -> Option<(String, Cow<'static, [u8]>)> {
+        match self {
+            Frontend::Dir(dir) => {
+                let mut file = dir.to_path_buf();
+                for part in path.split('/') {
+                    // Only the files inside of the directory are served
+                    if matches!(part, "" | "." | "..") || part.contains('\\') {
+                        return None;
+                    }
+                    file.push(part);
+                }
+
+                let data = tokio::fs::read(&file).await.ok()?;
+                let mime_type = mime_guess::from_path(&file)
+                    .first_or_octet_stream()
+                    .to_string();
+                Some((mime_type, Cow::Owned(data)))
+            }
+            #[cfg(feature = "embed")]
+            Frontend::Embedded => Asset::get(path).map(|content| {
+                (content.metadata.mimetype().to_owned(), content.data)
+            }),
+        }
+    }
+}
 
-    use axum::http::StatusCode;
+async fn not_found_handler() -> Response {
+    DownloaderError::Http(HttpError::RouteNotFound).into_response()
+}
 
-    const NO_CACHE_HEADER: &'static str =
+async fn fallback_handler(frontend: Frontend, req: Request) -> Response {
+    const NO_CACHE_HEADER: &str =
         "no-cache, no-store, max-age=0, must-revalidate";
-    const CACHE_HEADER: &'static str = "public, max-age=31536000";
-
-    const NOT_FOUND_STATUS: (
-        StatusCode,
-        Cow<'static, str>,
-        &'static str,
-        Cow<'static, [u8]>,
-    ) = (
-        StatusCode::NOT_FOUND,
-        Cow::Borrowed("text/plain"),
-        NO_CACHE_HEADER,
-        Cow::Borrowed(b"Not Found".as_slice()),
-    );
+    const CACHE_HEADER: &str = "public, max-age=31536000";
+
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            "text/plain".to_owned(),
+            NO_CACHE_HEADER,
+            Cow::Borrowed(b"Not Found".as_slice()),
+        )
+    };
 
     let path = req.uri().path().trim_start_matches("/");
 
@@ -174,35 +264,19 @@ async fn fallback_handler(req: axum::extract::Request) -> Response {
         "fetch static resource",
     );
 
-    let (status, content_type, cache_control, data) = match Asset::get(path) {
-        Some(content) => (
-            StatusCode::OK,
-            Cow::Owned(content.metadata.mimetype().to_owned()),
-            CACHE_HEADER,
-            content.data,
-        ),
-        None => {
-            if path.starts_with("_app") {
-                NOT_FOUND_STATUS
-            } else {
-                Asset::get("index.html")
-                    .map(|content| {
-                        (
-                            StatusCode::OK,
-                            Cow::Owned(content.metadata.mimetype().to_owned()),
-                            NO_CACHE_HEADER,
-                            content.data,
-                        )
-                    })
-                    .unwrap_or_else(|| NOT_FOUND_STATUS)
+    let (status, content_type, cache_control, data) =
+        match frontend.get(path).await {
+            Some((content_type, data)) => {
+                (StatusCode::OK, content_type, CACHE_HEADER, data)
             }
-        }
-    };
-
-    let content_type = match content_type {
-        Cow::Borrowed(s) => HeaderValue::from_static(s),
-        Cow::Owned(s) => HeaderValue::from_str(&s).unwrap(),
-    };
+            None if path.starts_with("_app") => not_found(),
+            None => match frontend.get("index.html").await {
+                Some((content_type, data)) => {
+                    (StatusCode::OK, content_type, NO_CACHE_HEADER, data)
+                }
+                None => not_found(),
+            },
+        };
 
     Response::builder()
         .status(status)
@@ -212,12 +286,23 @@ async fn fallback_handler(req: axum::extract::Request) -> Response {
         .unwrap()
 }
 
-pub fn layer_root_router<S>(router: Router<S>) -> Router<S>
+/// Serves the frontend from `frontend_dir` if set, else from the embedded
+/// files if compiled in, for the paths not matching any route.
+pub fn layer_root_router<S>(
+    router: Router<S>,
+    frontend_dir: Option<&Path>,
+) -> Router<S>
 where
     S: Clone + Send + Sync + 'static,
 {
     let layer = ServiceBuilder::new()
         .layer(SetSensitiveHeadersLayer::new(once(header::AUTHORIZATION)))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(middleware::from_fn(problem_errors))
+        .layer(middleware::from_fn(scope_request_id))
+        .layer(middleware::from_fn(scope_locale))
+        .layer(middleware::from_fn(scope_format))
         .layer(RequestDecompressionLayer::new())
         .layer(
             TraceLayer::new_for_http()
@@ -234,30 +319,126 @@ where
         .layer(CorsLayer::permissive().max_age(Duration::from_secs(86400)))
         .layer(NormalizePathLayer::trim_trailing_slash());
 
+    let Some(frontend) = Frontend::new(frontend_dir) else {
+        return router
+            .fallback(routing::any(not_found_handler))
+            .layer(layer);
+    };
+
+    let fallback_layer = ServiceBuilder::new()
+        .layer(SetSensitiveHeadersLayer::new(once(header::AUTHORIZATION)))
+        .layer(SetResponseHeaderLayer::overriding(
+            header::SERVER,
+            HeaderValue::from_static("axum/0.7"),
+        ))
+        .layer(CatchPanicLayer::new())
+        .layer(RequestDecompressionLayer::new());
     #[cfg(feature = "embed")]
-    {
-        use axum::handler::Handler;
-        use tower_http::compression::CompressionLayer;
-
-        let fallback_layer = ServiceBuilder::new()
-            .layer(SetSensitiveHeadersLayer::new(once(header::AUTHORIZATION)))
-            .layer(SetResponseHeaderLayer::overriding(
-                header::SERVER,
-                HeaderValue::from_static("axum/0.7"),
-            ))
-            .layer(CatchPanicLayer::new())
-            .layer(RequestDecompressionLayer::new())
-            .layer(CompressionLayer::new())
-            .layer(CorsLayer::permissive().max_age(Duration::from_secs(86400)))
-            .layer(NormalizePathLayer::trim_trailing_slash());
+    let fallback_layer =
+        fallback_layer.layer(tower_http::compression::CompressionLayer::new());
+    let fallback_layer = fallback_layer
+        .layer(CorsLayer::permissive().max_age(Duration::from_secs(86400)))
+        .layer(NormalizePathLayer::trim_trailing_slash());
 
-        return router
-            .layer(layer)
-            .fallback(routing::any(fallback_handler.layer(fallback_layer)));
+    let fallback = move |req: Request| fallback_handler(frontend.clone(), req);
+    router
+        .layer(layer)
+        .fallback(routing::any(fallback.layer(fallback_layer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        routing, Router,
+    };
+    use test_log::test;
+    use tower::ServiceExt;
+
+    use crate::errors::{DownloaderError, HttpError};
+
+    use super::layer_root_router;
+
+    async fn request(router: &Router, req: Request<Body>) -> (String, String) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let header = res.headers()["x-request-id"].to_str().unwrap().to_owned();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        (header, body["request_id"].as_str().unwrap().to_owned())
+    }
+
+    #[test(tokio::test)]
+    async fn test_error_request_id() {
+        let router = layer_root_router(
+            Router::new().route(
+                "/",
+                routing::get(|| async {
+                    DownloaderError::Http(HttpError::InvalidFormBoundary)
+                }),
+            ),
+            None,
+        );
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let (header, body) = request(&router, req).await;
+        assert!(!header.is_empty());
+        assert_eq!(header, body);
+
+        // Ids sent by clients are kept
+        let req = Request::get("/")
+            .header("x-request-id", "client-id")
+            .body(Body::empty())
+            .unwrap();
+        let (header, body) = request(&router, req).await;
+        assert_eq!(header, "client-id");
+        assert_eq!(body, "client-id");
     }
 
-    #[cfg(not(feature = "embed"))]
-    {
-        return router.fallback(routing::any(fallback_handler)).layer(layer);
+    #[test(tokio::test)]
+    async fn test_frontend_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html>").unwrap();
+        std::fs::write(dir.path().join("style.css"), "body {}").unwrap();
+        std::fs::create_dir(dir.path().join("_app")).unwrap();
+
+        let router = layer_root_router(Router::new(), Some(dir.path()));
+        let get = |uri: &str| {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let res = get("/style.css").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/css");
+        assert_eq!(
+            res.headers()[header::CACHE_CONTROL],
+            "public, max-age=31536000",
+        );
+
+        // Unknown paths are routed by the frontend
+        for uri in ["/", "/files/123", "/../Cargo.toml"] {
+            let res = get(uri).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers()[header::CACHE_CONTROL]
+                .to_str()
+                .unwrap()
+                .starts_with("no-cache"));
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, "<html>");
+        }
+
+        // Missing assets are not replaced by the index
+        for uri in ["/_app", "/_app/missing.js"] {
+            let res = get(uri).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        let res = get("/api/missing").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap();
     }
 }
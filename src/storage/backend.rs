@@ -0,0 +1,205 @@
+use std::{
+    io::{self, Cursor},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf, Take};
+use uuid::Uuid;
+
+use crate::config::StorageConfig;
+
+use super::{
+    manager::{
+        DirUsage, Manager, ObjectError, ObjectManager, ObjectReader,
+        StorageUsage,
+    },
+    memory::MemoryManager,
+};
+
+/// Where the object data is kept.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Files in the data dir.
+    #[default]
+    Stdfs,
+    /// Memory of the server, lost on restart.
+    Memory,
+}
+
+/// The [`Manager`] selected by the `backend` of the storage config.
+pub enum Backend {
+    Stdfs(ObjectManager),
+    Memory(MemoryManager),
+}
+
+impl Backend {
+    pub fn new(cfg: &StorageConfig) -> Self {
+        match cfg.backend {
+            BackendKind::Stdfs => Backend::Stdfs(ObjectManager::new(cfg)),
+            BackendKind::Memory => Backend::Memory(MemoryManager::new()),
+        }
+    }
+}
+
+/// Data of an object fetched by a [`Backend`].
+pub enum BackendReader {
+    Stdfs(ObjectReader),
+    Memory(Cursor<Bytes>),
+}
+
+impl AsyncRead for BackendReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendReader::Stdfs(reader) => Pin::new(reader).poll_read(cx, buf),
+            BackendReader::Memory(reader) => {
+                Pin::new(reader).poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+/// Wraps the reader of a range, keeping its limit.
+fn map_take<R: AsyncRead>(
+    reader: Take<R>,
+    f: impl FnOnce(R) -> BackendReader,
+) -> Take<BackendReader> {
+    let limit = reader.limit();
+    f(reader.into_inner()).take(limit)
+}
+
+impl Manager for Backend {
+    type Reader = BackendReader;
+
+    async fn store(
+        &self,
+        id: Uuid,
+        stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+        checksum: Option<[u8; 32]>,
+        size: Option<u64>,
+    ) -> Result<(u64, [u8; 32]), ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.store(id, stream, checksum, size).await,
+            Backend::Memory(m) => m.store(id, stream, checksum, size).await,
+        }
+    }
+
+    async fn store_batch(
+        &self,
+        objects: Vec<(Uuid, Bytes)>,
+    ) -> Result<Vec<(u64, [u8; 32])>, ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.store_batch(objects).await,
+            Backend::Memory(m) => m.store_batch(objects).await,
+        }
+    }
+
+    async fn fetch(&self, id: Uuid) -> Result<Self::Reader, ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.fetch(id).await.map(BackendReader::Stdfs),
+            Backend::Memory(m) => m.fetch(id).await.map(BackendReader::Memory),
+        }
+    }
+
+    async fn fetch_range(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> Result<Take<Self::Reader>, ObjectError> {
+        match self {
+            Backend::Stdfs(m) => {
+                let reader = m.fetch_range(id, offset, len).await?;
+                Ok(map_take(reader, BackendReader::Stdfs))
+            }
+            Backend::Memory(m) => {
+                let reader = m.fetch_range(id, offset, len).await?;
+                Ok(map_take(reader, BackendReader::Memory))
+            }
+        }
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<(), ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.delete(id).await,
+            Backend::Memory(m) => m.delete(id).await,
+        }
+    }
+
+    async fn snapshot(&self, id: Uuid) -> Result<(), ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.snapshot(id).await,
+            Backend::Memory(m) => m.snapshot(id).await,
+        }
+    }
+
+    async fn rollback(&self, id: Uuid) -> Result<(), ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.rollback(id).await,
+            Backend::Memory(m) => m.rollback(id).await,
+        }
+    }
+
+    async fn release(&self, id: Uuid) -> Result<(), ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.release(id).await,
+            Backend::Memory(m) => m.release(id).await,
+        }
+    }
+
+    async fn sweep(&self, max_age: Duration) -> Result<DirUsage, ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.sweep(max_age).await,
+            Backend::Memory(m) => m.sweep(max_age).await,
+        }
+    }
+
+    async fn usage(&self) -> Result<StorageUsage, ObjectError> {
+        match self {
+            Backend::Stdfs(m) => m.usage().await,
+            Backend::Memory(m) => m.usage().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        let cfg: StorageConfig = toml::from_str(
+            r#"
+            data_dir = "/"
+            temp_dir = "/"
+            state_dir = "/"
+            backend = "memory"
+            "#,
+        )
+        .unwrap();
+        let backend = Backend::new(&cfg);
+        assert!(matches!(backend, Backend::Memory(..)));
+
+        let id = Uuid::new_v4();
+        let data = stream::iter([Ok(Bytes::from_static(b"hello world"))]);
+        backend.store(id, data, None, None).await.unwrap();
+
+        let mut buf = Vec::new();
+        let mut range = backend.fetch_range(id, 6, 3).await.unwrap();
+        range.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"wor");
+    }
+}
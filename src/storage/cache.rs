@@ -0,0 +1,81 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use schnellru::{ByLength, LruMap};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::Object;
+
+struct CachedObject {
+    object: Object,
+    cached_at: Instant,
+}
+
+/// A bounded LRU cache of object metadata.
+///
+/// Entries live for at most `ttl`, bounding how long changes made to the
+/// database without going through the [`ObjectRepository`] stay unnoticed.
+///
+/// [`ObjectRepository`]: super::repository::ObjectRepository
+pub struct ObjectCache {
+    map: Mutex<LruMap<Uuid, CachedObject>>,
+    capacity: u32,
+    ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheUsage {
+    pub entries: u32,
+    pub capacity: u32,
+}
+
+impl ObjectCache {
+    pub fn new(capacity: u32, ttl: Duration) -> Self {
+        Self {
+            map: Mutex::new(LruMap::new(ByLength::new(capacity))),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Entries may be expired but not evicted yet.
+    pub fn usage(&self) -> CacheUsage {
+        CacheUsage {
+            entries: self.map.lock().unwrap().len() as u32,
+            capacity: self.capacity,
+        }
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Object> {
+        let mut map = self.map.lock().unwrap();
+
+        let entry = map.get(&id)?;
+        if entry.cached_at.elapsed() >= self.ttl {
+            map.remove(&id);
+            return None;
+        }
+
+        Some(entry.object.clone())
+    }
+
+    pub fn insert(&self, object: &Object) {
+        self.map.lock().unwrap().insert(
+            object.id,
+            CachedObject {
+                object: object.clone(),
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        self.map.lock().unwrap().remove(&id);
+    }
+
+    pub fn clear(&self) {
+        self.map.lock().unwrap().clear();
+    }
+}
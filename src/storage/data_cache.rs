@@ -0,0 +1,149 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use bytes::Bytes;
+use schnellru::{LruMap, Unlimited};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::DataCacheConfig;
+
+struct Entries {
+    map: LruMap<Uuid, Bytes, Unlimited>,
+    bytes: u64,
+}
+
+/// A LRU cache of the data of small objects, bounded by the total size of
+/// the data held.
+pub struct DataCache {
+    entries: Mutex<Entries>,
+    max_size: u64,
+    max_object_size: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct DataCacheUsage {
+    pub entries: u32,
+    pub bytes: u64,
+    pub max_size: u64,
+    /// Fetches served from the cache since startup.
+    pub hits: u64,
+    /// Fetches of objects small enough to be cached read from the disk
+    /// since startup.
+    pub misses: u64,
+}
+
+impl DataCache {
+    pub fn new(cfg: &DataCacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                map: LruMap::new(Unlimited),
+                bytes: 0,
+            }),
+            max_size: cfg.max_size,
+            max_object_size: cfg.max_object_size.min(cfg.max_size),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn usage(&self) -> DataCacheUsage {
+        let entries = self.entries.lock().unwrap();
+        DataCacheUsage {
+            entries: entries.map.len() as u32,
+            bytes: entries.bytes,
+            max_size: self.max_size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether objects of `size` bytes are kept.
+    #[inline]
+    pub fn admits(&self, size: u64) -> bool {
+        size <= self.max_object_size
+    }
+
+    /// Misses are counted by [`DataCache::insert`], as only the objects that
+    /// could be cached count.
+    pub fn get(&self, id: Uuid) -> Option<Bytes> {
+        let data = self.entries.lock().unwrap().map.get(&id).cloned();
+        if data.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        data
+    }
+
+    /// Keeps the data, evicting the least recently used objects to fit it.
+    pub fn insert(&self, id: Uuid, data: Bytes) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let len = data.len() as u64;
+        if !self.admits(len) {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(&id) {
+            entries.bytes -= old.len() as u64;
+        }
+        while entries.bytes + len > self.max_size {
+            match entries.map.pop_oldest() {
+                Some((_, old)) => entries.bytes -= old.len() as u64,
+                None => break,
+            }
+        }
+
+        entries.map.insert(id, data);
+        entries.bytes += len;
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(&id) {
+            entries.bytes -= old.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_cache() {
+        let cache = DataCache::new(&DataCacheConfig {
+            max_size: 100,
+            max_object_size: 40,
+        });
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+
+        assert!(cache.get(ids[0]).is_none());
+        for &id in &ids[..3] {
+            cache.insert(id, Bytes::from(vec![0; 30]));
+        }
+        assert_eq!(cache.get(ids[0]).unwrap().len(), 30);
+
+        // The least recently used is evicted to fit
+        cache.insert(ids[3], Bytes::from(vec![0; 30]));
+        assert!(cache.get(ids[1]).is_none());
+        assert!(cache.get(ids[0]).is_some());
+
+        // Too large to be kept
+        cache.insert(ids[1], Bytes::from(vec![0; 41]));
+        assert!(cache.get(ids[1]).is_none());
+
+        cache.remove(ids[0]);
+        assert!(cache.get(ids[0]).is_none());
+
+        let usage = cache.usage();
+        assert_eq!((usage.entries, usage.bytes), (2, 60));
+        assert_eq!((usage.hits, usage.misses), (2, 5));
+    }
+}
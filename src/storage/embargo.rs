@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthError, errors::DownloaderError, utils::retry::retry_busy,
+};
+
+use super::repository::RepositoryError;
+
+/// Fails with the release instant while it is still in the future.
+pub fn check_available(
+    available_from: Option<DateTime<Utc>>,
+) -> Result<(), AuthError> {
+    match available_from {
+        Some(at) if at > Utc::now() => Err(AuthError::Embargoed(at)),
+        _ => Ok(()),
+    }
+}
+
+/// When objects become available for download through shares, that is
+/// file tokens and presigned urls. Their owners are never restricted.
+pub struct EmbargoRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for EmbargoRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> EmbargoRepository<DB> {
+    pub fn new(db: Pool<DB>) -> EmbargoRepository<DB> {
+        EmbargoRepository { db }
+    }
+}
+
+impl<DB> EmbargoRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    pub async fn get(
+        &self,
+        id: Uuid,
+    ) -> Result<Option<DateTime<Utc>>, RepositoryError> {
+        let available_from: Option<(i64,)> = sqlx::query_as(
+            "SELECT available_from FROM object_embargo WHERE object_id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        available_from
+            .map(|(ms,)| {
+                DateTime::from_timestamp_millis(ms).ok_or_else(|| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse `available_from` field gone wrong".into(),
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Replaces the embargo of the object `id`, lifting it if `None`.
+    pub async fn set(
+        &self,
+        id: Uuid,
+        available_from: Option<DateTime<Utc>>,
+    ) -> Result<(), RepositoryError> {
+        let id_bytes = id.into_bytes();
+        retry_busy(|| match available_from {
+            Some(at) => sqlx::query(
+                "INSERT OR REPLACE INTO object_embargo \
+                (object_id, available_from) VALUES ($1, $2)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(at.timestamp_millis())
+            .execute(&self.db),
+            None => {
+                sqlx::query("DELETE FROM object_embargo WHERE object_id = $1")
+                    .bind(id_bytes.as_slice())
+                    .execute(&self.db)
+            }
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+
+    /// Fails if the object `id` is not available through shares yet.
+    pub async fn check(&self, id: Uuid) -> Result<(), DownloaderError> {
+        Ok(check_available(self.get(id).await?)?)
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object embargoes");
+    RepositoryError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_embargo() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = EmbargoRepository::new(db);
+        let id = Uuid::new_v4();
+
+        assert_eq!(repo.get(id).await.unwrap(), None);
+        repo.check(id).await.unwrap();
+
+        let at = DateTime::from_timestamp_millis(
+            (Utc::now() + TimeDelta::hours(1)).timestamp_millis(),
+        )
+        .unwrap();
+        repo.set(id, Some(at)).await.unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), Some(at));
+        assert!(matches!(
+            repo.check(id).await,
+            Err(DownloaderError::Auth(AuthError::Embargoed(e))) if e == at,
+        ));
+
+        repo.set(id, Some(Utc::now() - TimeDelta::seconds(1)))
+            .await
+            .unwrap();
+        repo.check(id).await.unwrap();
+
+        repo.set(id, None).await.unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), None);
+    }
+}
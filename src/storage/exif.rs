@@ -0,0 +1,470 @@
+use std::{io::Cursor, sync::Arc};
+
+use axum::async_trait;
+use chrono::{FixedOffset, NaiveDate};
+use exif::{DateTime, In, Reader, Tag, Value};
+use serde_json::json;
+use sqlx::Sqlite;
+use tokio::io::AsyncReadExt;
+
+use super::{
+    meta::{
+        validate_metadata, MetaRepository, Metadata, MAX_METADATA_VALUE_LEN,
+    },
+    pipeline::{sniff_mime_type, DataStore, Step, StepContext},
+    Object, ObjectData, WriteLocks,
+};
+
+/// Images larger than this are left unprocessed, as they are read into
+/// memory.
+const MAX_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+/// Bytes of the data the type is detected from.
+const HEAD_LEN: u64 = 16;
+
+const JPEG_SOI: &[u8] = b"\xff\xd8";
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_SOS: u8 = 0xda;
+const JPEG_EOI: u8 = 0xd9;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_EXIF_CHUNK: &[u8] = b"eXIf";
+
+/// Copies the EXIF fields of images into the metadata of their objects,
+/// under `exif.` keys.
+pub struct ExifStep {
+    meta: MetaRepository<Sqlite>,
+}
+
+impl ExifStep {
+    pub fn new(meta: MetaRepository<Sqlite>) -> Self {
+        Self { meta }
+    }
+}
+
+#[async_trait]
+impl Step for ExifStep {
+    fn name(&self) -> &'static str {
+        "exif"
+    }
+
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String> {
+        let id = ctx.object.id;
+        let Some(data) = read_image(ctx.store, ctx.object).await? else {
+            return Ok(json!({ "image": false }));
+        };
+
+        // Images without readable EXIF are not a failure of the step
+        let fields = match extract(&data) {
+            Ok(fields) => fields,
+            Err(error) => {
+                tracing::debug!(%error, %id, "found no exif in image");
+                return Ok(json!({ "image": true, "fields": {} }));
+            }
+        };
+
+        if !fields.is_empty() {
+            let mut metadata =
+                self.meta.get(id).await.map_err(|e| e.to_string())?;
+            metadata.extend(fields.clone());
+            validate_metadata(&metadata).map_err(|e| e.to_string())?;
+            self.meta
+                .set(id, &metadata)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(json!({ "image": true, "fields": fields }))
+    }
+}
+
+/// Removes the EXIF and XMP data of JPEG and PNG images, which may hold the
+/// location they were taken at, replacing the stored data.
+pub struct StripExifStep {
+    locks: Arc<WriteLocks>,
+}
+
+impl StripExifStep {
+    pub fn new(locks: Arc<WriteLocks>) -> Self {
+        Self { locks }
+    }
+}
+
+#[async_trait]
+impl Step for StripExifStep {
+    fn name(&self) -> &'static str {
+        "strip-exif"
+    }
+
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String> {
+        let id = ctx.object.id;
+        // Held until the entry is updated, like for the uploads, reading the
+        // object again as its data may have been replaced meanwhile
+        let _guard = self.locks.acquire(id).await.map_err(|e| e.to_string())?;
+        let object = ctx.repo.get(id).await.map_err(|e| e.to_string())?;
+
+        let Some(stripped) = read_image(ctx.store, &object)
+            .await?
+            .and_then(|data| strip_exif(&data))
+        else {
+            return Ok(json!({ "stripped": false }));
+        };
+
+        let (size, checksum_256) = ctx
+            .store
+            .replace(id, stripped.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        ctx.repo
+            .update(
+                id,
+                ObjectData {
+                    size,
+                    checksum_256,
+                    ..object.data
+                },
+            )
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    %error,
+                    %id,
+                    "update object entry failed after stripping exif",
+                );
+                error.to_string()
+            })?;
+
+        Ok(json!({ "stripped": true, "size": size }))
+    }
+}
+
+/// The data of the object if it is an image small enough to be processed.
+async fn read_image(
+    store: &dyn DataStore,
+    object: &Object,
+) -> Result<Option<Vec<u8>>, String> {
+    if object.data.size > MAX_IMAGE_SIZE {
+        return Ok(None);
+    }
+
+    let mut reader = store.open(object.id).await.map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    (&mut reader)
+        .take(HEAD_LEN)
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !sniff_mime_type(&data).starts_with("image/") {
+        return Ok(None);
+    }
+
+    reader
+        .take(MAX_IMAGE_SIZE)
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Some(data))
+}
+
+/// Reads the creation date and camera of an image from its EXIF.
+fn extract(data: &[u8]) -> Result<Metadata, exif::Error> {
+    let exif = Reader::new().read_from_container(&mut Cursor::new(data))?;
+    let ascii = |tag| {
+        let field = exif.get_field(tag, In::PRIMARY)?;
+        match &field.value {
+            Value::Ascii(values) => {
+                let value = String::from_utf8_lossy(values.first()?);
+                let value = value.trim_matches(['\0', ' ']);
+                (!value.is_empty() && value.len() <= MAX_METADATA_VALUE_LEN)
+                    .then(|| value.to_owned())
+            }
+            _ => None,
+        }
+    };
+
+    let mut metadata = Metadata::new();
+    let created_at = [
+        (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal),
+        (Tag::DateTime, Tag::OffsetTime),
+    ]
+    .into_iter()
+    .find_map(|(tag, offset_tag)| {
+        let mut datetime = DateTime::from_ascii(ascii(tag)?.as_bytes()).ok()?;
+        if let Some(offset) = ascii(offset_tag) {
+            datetime.parse_offset(offset.as_bytes()).ok();
+        }
+        format_datetime(&datetime)
+    });
+    if let Some(created_at) = created_at {
+        metadata.insert("exif.created_at".into(), created_at);
+    }
+    for (tag, key) in [
+        (Tag::Make, "exif.camera_make"),
+        (Tag::Model, "exif.camera_model"),
+    ] {
+        if let Some(value) = ascii(tag) {
+            metadata.insert(key.into(), value);
+        }
+    }
+    Ok(metadata)
+}
+
+/// Formats in RFC 3339 if the offset is known, else without it.
+fn format_datetime(datetime: &DateTime) -> Option<String> {
+    let naive = NaiveDate::from_ymd_opt(
+        datetime.year.into(),
+        datetime.month.into(),
+        datetime.day.into(),
+    )?
+    .and_hms_opt(
+        datetime.hour.into(),
+        datetime.minute.into(),
+        datetime.second.into(),
+    )?;
+
+    match datetime.offset {
+        Some(offset) => {
+            let offset = FixedOffset::east_opt(i32::from(offset) * 60)?;
+            let local = naive.and_local_timezone(offset).single()?;
+            Some(local.to_rfc3339())
+        }
+        None => Some(naive.format("%Y-%m-%dT%H:%M:%S").to_string()),
+    }
+}
+
+/// The image without its EXIF, or `None` if it has none or is not a JPEG
+/// or PNG image.
+fn strip_exif(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(JPEG_SOI) {
+        strip_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)
+    } else {
+        None
+    }
+}
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = JPEG_SOI.to_vec();
+    let mut pos = JPEG_SOI.len();
+    let mut stripped = false;
+
+    loop {
+        if data.get(pos) != Some(&0xff) {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // Fill byte before a marker
+            0xff => pos += 1,
+            // The entropy-coded data follows, without any more metadata
+            JPEG_SOS | JPEG_EOI => {
+                out.extend_from_slice(&data[pos..]);
+                break;
+            }
+            // Markers without a segment
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+            }
+            _ => {
+                let len = u16::from_be_bytes([
+                    *data.get(pos + 2)?,
+                    *data.get(pos + 3)?,
+                ]);
+                let end = pos + 2 + usize::from(len);
+                let payload = data.get(pos + 4..end)?;
+                if marker == JPEG_APP1
+                    && (payload.starts_with(EXIF_HEADER)
+                        || payload.starts_with(XMP_HEADER))
+                {
+                    stripped = true;
+                } else {
+                    out.extend_from_slice(&data[pos..end]);
+                }
+                pos = end;
+            }
+        }
+    }
+
+    stripped.then_some(out)
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut pos = PNG_SIGNATURE.len();
+    let mut stripped = false;
+
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?);
+        // Length, type, data and crc
+        let end = pos.checked_add(12)?.checked_add(len as usize)?;
+        let chunk = data.get(pos..end)?;
+        if &chunk[4..8] == PNG_EXIF_CHUNK {
+            stripped = true;
+        } else {
+            out.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+
+    stripped.then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use exif::{experimental::Writer, Field};
+    use futures_util::stream;
+    use sqlx::{migrate, SqlitePool};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::{
+        manager::Manager, memory::MemoryManager, repository::ObjectRepository,
+    };
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    /// A JPEG image, not decodable, with EXIF data and a comment.
+    fn jpeg() -> Vec<u8> {
+        let fields = [
+            ascii(Tag::Make, "Acme"),
+            ascii(Tag::Model, "X1"),
+            ascii(Tag::DateTimeOriginal, "2024:05:01 12:30:00"),
+            ascii(Tag::OffsetTimeOriginal, "+02:00"),
+            ascii(Tag::GPSLatitudeRef, "N"),
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut data = JPEG_SOI.to_vec();
+        data.extend_from_slice(&[0xff, JPEG_APP1]);
+        let len = 2 + EXIF_HEADER.len() + tiff.len();
+        data.extend_from_slice(&(len as u16).to_be_bytes());
+        data.extend_from_slice(EXIF_HEADER);
+        data.extend_from_slice(&tiff);
+        data.extend_from_slice(b"\xff\xfe\x00\x04hi");
+        data.extend_from_slice(b"\xff\xda\x00\x02\x12\x34\xff\xd9");
+        data
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    #[test]
+    fn test_extract() {
+        let metadata = extract(&jpeg()).unwrap();
+        assert_eq!(
+            metadata,
+            Metadata::from([
+                ("exif.camera_make".into(), "Acme".into()),
+                ("exif.camera_model".into(), "X1".into()),
+                ("exif.created_at".into(), "2024-05-01T12:30:00+02:00".into(),),
+            ]),
+        );
+
+        assert!(matches!(
+            extract(b"\xff\xd8\xff\xd9"),
+            Err(exif::Error::NotFound(..)),
+        ));
+    }
+
+    #[test]
+    fn test_strip_exif() {
+        let stripped = strip_exif(&jpeg()).unwrap();
+        assert_eq!(
+            stripped,
+            b"\xff\xd8\xff\xfe\x00\x04hi\xff\xda\x00\x02\x12\x34\xff\xd9",
+        );
+        assert!(strip_exif(&stripped).is_none());
+        // Truncated segments
+        assert!(strip_exif(&jpeg()[..20]).is_none());
+
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let iend = png_chunk(b"IEND", &[]);
+        let png = [
+            PNG_SIGNATURE,
+            &ihdr,
+            &png_chunk(PNG_EXIF_CHUNK, &[1, 2, 3]),
+            &iend,
+        ]
+        .concat();
+        let stripped = strip_exif(&png).unwrap();
+        assert_eq!(stripped, [PNG_SIGNATURE, &ihdr, &iend].concat());
+        assert!(strip_exif(&stripped).is_none());
+
+        assert!(strip_exif(b"hello world").is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_exif_steps() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ObjectRepository::new(db.clone());
+        let meta = MetaRepository::new(db.clone());
+        let manager = MemoryManager::new();
+
+        let id = Uuid::new_v4();
+        let data = stream::iter([Ok(Bytes::from(jpeg()))]);
+        let (size, checksum_256) =
+            manager.store(id, data, None, None).await.unwrap();
+        let data = ObjectData {
+            name: "photo.jpg".into(),
+            mime_type: "image/jpeg".into(),
+            size,
+            checksum_256,
+        };
+        let object = repo.create(id, Uuid::new_v4(), data).await.unwrap();
+        meta.set(id, &Metadata::from([("album".into(), "trip".into())]))
+            .await
+            .unwrap();
+
+        let ctx = StepContext {
+            object: &object,
+            repo: &repo,
+            store: &manager,
+        };
+        ExifStep::new(meta.clone()).run(&ctx).await.unwrap();
+        let metadata = meta.get(id).await.unwrap();
+        assert_eq!(metadata["album"], "trip");
+        assert_eq!(metadata["exif.camera_model"], "X1");
+
+        let step = StripExifStep::new(Arc::new(WriteLocks::new(true)));
+        let result = step.run(&ctx).await.unwrap();
+        assert_eq!(result["stripped"], true);
+
+        let updated = repo.get(id).await.unwrap();
+        assert!(updated.data.size < object.data.size);
+        let mut reader = manager.fetch(id).await.unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert!(extract(&data).is_err());
+
+        // Nothing left to strip
+        let result = step.run(&ctx).await.unwrap();
+        assert_eq!(result["stripped"], false);
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::RwLock,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::io::Take;
+use uuid::Uuid;
+
+use super::manager::{DirUsage, Manager, ObjectError, StorageUsage};
+
+/// Faults injected by a [`FaultyManager`] in the wrapped manager operations.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Delay applied before every operation.
+    pub latency: Duration,
+    pub fail_store: bool,
+    pub fail_fetch: bool,
+    pub fail_delete: bool,
+    /// Makes the stream being stored fail after the given amount of bytes,
+    /// leaving a partially written object behind the inner manager.
+    pub store_fail_after: Option<u64>,
+}
+
+/// A [`Manager`] wrapper that injects IO errors, latency and partial writes,
+/// used to exercise the cleanup logic of its callers.
+pub struct FaultyManager<M> {
+    inner: M,
+    faults: RwLock<Faults>,
+}
+
+impl<M> FaultyManager<M> {
+    pub fn new(inner: M, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults: RwLock::new(faults),
+        }
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        *self.faults.write().unwrap() = faults;
+    }
+
+    async fn faults(&self) -> Faults {
+        let faults = self.faults.read().unwrap().clone();
+        if !faults.latency.is_zero() {
+            tokio::time::sleep(faults.latency).await;
+        }
+        faults
+    }
+}
+
+#[inline]
+fn injected_error(op: &str) -> ObjectError {
+    ObjectError::IoError(io::Error::other(format!("injected {op} fault")))
+}
+
+impl<M: Manager> Manager for FaultyManager<M> {
+    type Reader = M::Reader;
+
+    async fn store(
+        &self,
+        id: Uuid,
+        stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+        checksum: Option<[u8; 32]>,
+        size: Option<u64>,
+    ) -> Result<(u64, [u8; 32]), ObjectError> {
+        let faults = self.faults().await;
+        if faults.fail_store {
+            return Err(injected_error("store"));
+        }
+
+        match faults.store_fail_after {
+            Some(remaining) => {
+                let stream = PartialStream {
+                    stream,
+                    remaining,
+                    failed: false,
+                };
+                self.inner.store(id, stream, checksum, size).await
+            }
+            None => self.inner.store(id, stream, checksum, size).await,
+        }
+    }
+
+    async fn store_batch(
+        &self,
+        objects: Vec<(Uuid, Bytes)>,
+    ) -> Result<Vec<(u64, [u8; 32])>, ObjectError> {
+        if self.faults().await.fail_store {
+            return Err(injected_error("store"));
+        }
+        self.inner.store_batch(objects).await
+    }
+
+    async fn fetch(&self, id: Uuid) -> Result<Self::Reader, ObjectError> {
+        if self.faults().await.fail_fetch {
+            return Err(injected_error("fetch"));
+        }
+        self.inner.fetch(id).await
+    }
+
+    async fn fetch_range(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> Result<Take<Self::Reader>, ObjectError> {
+        if self.faults().await.fail_fetch {
+            return Err(injected_error("fetch"));
+        }
+        self.inner.fetch_range(id, offset, len).await
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<(), ObjectError> {
+        if self.faults().await.fail_delete {
+            return Err(injected_error("delete"));
+        }
+        self.inner.delete(id).await
+    }
+
+    async fn snapshot(&self, id: Uuid) -> Result<(), ObjectError> {
+        self.faults().await;
+        self.inner.snapshot(id).await
+    }
+
+    async fn rollback(&self, id: Uuid) -> Result<(), ObjectError> {
+        self.faults().await;
+        self.inner.rollback(id).await
+    }
+
+    async fn release(&self, id: Uuid) -> Result<(), ObjectError> {
+        self.faults().await;
+        self.inner.release(id).await
+    }
+
+    async fn sweep(&self, max_age: Duration) -> Result<DirUsage, ObjectError> {
+        self.faults().await;
+        self.inner.sweep(max_age).await
+    }
+
+    async fn usage(&self) -> Result<StorageUsage, ObjectError> {
+        self.faults().await;
+        self.inner.usage().await
+    }
+}
+
+struct PartialStream<S> {
+    stream: S,
+    remaining: u64,
+    failed: bool,
+}
+
+impl<S> Stream for PartialStream<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>> + Unpin,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.remaining == 0 {
+            if this.failed {
+                return Poll::Ready(None);
+            }
+            this.failed = true;
+            return Poll::Ready(Some(Err(io::Error::other(
+                "injected partial write fault",
+            ))));
+        }
+
+        match ready!(this.stream.poll_next_unpin(cx)) {
+            Some(Ok(mut bytes)) => {
+                if bytes.len() as u64 > this.remaining {
+                    bytes.truncate(this.remaining as usize);
+                }
+                this.remaining -= bytes.len() as u64;
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            res => Poll::Ready(res),
+        }
+    }
+}
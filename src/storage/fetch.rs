@@ -0,0 +1,536 @@
+use std::{
+    error::Error as StdError,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+};
+
+use crate::{config::FetchConfig, utils::stream::LimitStream};
+use axum::http::{header, StatusCode};
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Url,
+};
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum FetchError {
+    #[error("fetching remote files is not enabled")]
+    Disabled,
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("host `{0}` is not allowed")]
+    HostNotAllowed(String),
+    #[error("too many redirects, the maximum is {0}")]
+    TooManyRedirects(usize),
+    #[error("remote file is larger than the maximum of {0} bytes")]
+    TooLarge(u64),
+    #[error("remote request failed: {0}")]
+    Request(String),
+    #[error("too many fetches in progress, the maximum is {0}")]
+    TooManyJobs(usize),
+    #[error("daily limit of {0} fetched bytes exceeded")]
+    DailyLimitExceeded(u64),
+    #[error("the folder must be a relative path")]
+    InvalidFolder,
+    #[error("fetching into folder `{0}` is not allowed")]
+    FolderNotAllowed(String),
+}
+
+impl FetchError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            FetchError::Disabled => StatusCode::NOT_FOUND,
+            FetchError::InvalidUrl(..) => StatusCode::BAD_REQUEST,
+            FetchError::HostNotAllowed(..) => StatusCode::FORBIDDEN,
+            FetchError::TooManyRedirects(..) => StatusCode::BAD_GATEWAY,
+            FetchError::TooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            FetchError::Request(..) => StatusCode::BAD_GATEWAY,
+            FetchError::TooManyJobs(..) => StatusCode::TOO_MANY_REQUESTS,
+            FetchError::DailyLimitExceeded(..) => StatusCode::TOO_MANY_REQUESTS,
+            FetchError::InvalidFolder => StatusCode::BAD_REQUEST,
+            FetchError::FolderNotAllowed(..) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    FetchError {
+        Disabled => 1, "fetching remote files is not enabled";
+        InvalidUrl(..) => 2, "the url is invalid";
+        HostNotAllowed(..) => 3, "the host is not allowed";
+        TooManyRedirects(..) => 4,
+            "the remote file was redirected too many times";
+        TooLarge(..) => 5, "the remote file is larger than the maximum";
+        Request(..) => 6, "the remote request failed";
+        TooManyJobs(..) => 7, "too many fetches in progress";
+        DailyLimitExceeded(..) => 8,
+            "the daily limit of fetched bytes was exceeded";
+        InvalidFolder => 9, "the folder is not a relative path";
+        FolderNotAllowed(..) => 10, "fetching into the folder is not allowed";
+    }
+}
+
+/// A remote file being downloaded.
+pub struct RemoteFile {
+    pub stream: Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>,
+    pub name: String,
+    pub mime_type: String,
+    /// The announced size of the whole file, if any.
+    pub size: Option<u64>,
+    /// Position of the stream in the file, zero unless resumed.
+    pub offset: u64,
+    /// Validators of the file version, used to resume the download.
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Downloads remote files on behalf of users, restricted to the allowed
+/// hosts and, unless configured otherwise, to public addresses.
+pub struct RemoteFetcher {
+    cfg: Arc<FetchConfig>,
+    client: reqwest::Client,
+}
+
+impl RemoteFetcher {
+    pub fn new(cfg: FetchConfig) -> Result<Self, reqwest::Error> {
+        let cfg = Arc::new(cfg);
+
+        let policy_cfg = cfg.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > policy_cfg.max_redirects {
+                let max = policy_cfg.max_redirects;
+                return attempt.error(FetchError::TooManyRedirects(max));
+            }
+            match check_url(attempt.url(), &policy_cfg) {
+                Ok(()) => attempt.follow(),
+                Err(error) => attempt.error(error),
+            }
+        });
+
+        let mut builder = reqwest::Client::builder()
+            .redirect(policy)
+            .connect_timeout(cfg.connect_timeout);
+        if !cfg.allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            cfg,
+        })
+    }
+
+    /// Checks whether `url` can be fetched, without resolving its host.
+    pub fn check(&self, url: &str) -> Result<Url, FetchError> {
+        let url = Url::parse(url)
+            .map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+        check_url(&url, &self.cfg)?;
+        Ok(url)
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<RemoteFile, FetchError> {
+        self.fetch_from(url, 0, None).await
+    }
+
+    /// Fetches the file from `offset` on, if it still matches `validator`,
+    /// an etag or modification date of a previous response. The whole file
+    /// is fetched otherwise, or if the server does not support ranges.
+    pub async fn fetch_from(
+        &self,
+        url: &str,
+        offset: u64,
+        validator: Option<&str>,
+    ) -> Result<RemoteFile, FetchError> {
+        let url = self.check(url)?;
+
+        let mut req = self.client.get(url);
+        if let Some(validator) = validator.filter(|_| offset > 0) {
+            req = req
+                .header(header::RANGE, format!("bytes={offset}-"))
+                .header(header::IF_RANGE, validator);
+        }
+        let res = req
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(request_error)?;
+
+        let (offset, size) = if res.status() == StatusCode::PARTIAL_CONTENT {
+            let (start, size) = res
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_content_range)
+                .filter(|(start, _)| *start == offset)
+                .ok_or_else(|| {
+                    FetchError::Request("unexpected content range".into())
+                })?;
+            (start, size)
+        } else {
+            (0, res.content_length())
+        };
+
+        let max_size = self.cfg.max_size;
+        if size.is_some_and(|len| len > max_size) {
+            return Err(FetchError::TooLarge(max_size));
+        }
+
+        let validator = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let etag = validator(header::ETAG);
+        let last_modified = validator(header::LAST_MODIFIED);
+
+        let name = res
+            .url()
+            .path_segments()
+            .and_then(|segments| segments.rev().find(|s| !s.is_empty()))
+            .unwrap_or("download")
+            .to_owned();
+        let mime_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
+            .to_owned();
+
+        let stream = stream::try_unfold(res, |mut res| async move {
+            let chunk = res.chunk().await.map_err(io::Error::other)?;
+            Ok(chunk.map(|chunk| (chunk, res)))
+        });
+
+        Ok(RemoteFile {
+            stream: Box::pin(LimitStream::new(
+                stream,
+                max_size.saturating_sub(offset),
+            )),
+            name,
+            mime_type,
+            size,
+            offset,
+            etag,
+            last_modified,
+        })
+    }
+}
+
+/// Parses the start and complete length of a `bytes start-end/length`
+/// content range.
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    let length = match length {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+    Some((start.parse().ok()?, length))
+}
+
+fn check_url(url: &Url, cfg: &FetchConfig) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl(format!(
+            "unsupported scheme `{}`",
+            url.scheme(),
+        )));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| FetchError::InvalidUrl("missing host".into()))?;
+
+    let allowed = cfg.allowed_hosts.is_empty()
+        || cfg.allowed_hosts.iter().any(|pattern| {
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => host.eq_ignore_ascii_case(pattern),
+            }
+        });
+
+    // Names are checked once resolved, by the client resolver
+    let public = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_or(true, is_public);
+
+    if !allowed || (!public && !cfg.allow_private) {
+        return Err(FetchError::HostNotAllowed(host.to_owned()));
+    }
+    Ok(())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Shared address space
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    // Unique local
+                    || (first & 0xfe00) == 0xfc00
+                    // Link local
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves names to their public addresses only, so urls can not reach
+/// internal services through the DNS.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0))
+                    .await?
+                    .filter(|addr| is_public(addr.ip()))
+                    .collect();
+
+            if addrs.is_empty() {
+                return Err(FetchError::HostNotAllowed(host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn request_error(error: reqwest::Error) -> FetchError {
+    let mut source = error.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<FetchError>() {
+            return err.clone();
+        }
+        source = err.source();
+    }
+
+    tracing::warn!(%error, "remote fetch request failed");
+    FetchError::Request(error.to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{io, time::Duration};
+
+    use axum::{
+        body::Body,
+        extract::Path,
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse, Redirect, Response},
+        routing, Router,
+    };
+    use bytes::Bytes;
+    use futures_util::{stream, TryStreamExt};
+    use test_log::test;
+    use tokio::net::TcpListener;
+
+    use crate::config::FetchConfig;
+
+    use super::{FetchError, RemoteFetcher, RemoteFile};
+
+    pub const RANGED: &[u8] = b"0123456789";
+    pub const ETAG: &str = "\"v1\"";
+
+    /// Serves ranges of [`RANGED`] while the `If-Range` matches [`ETAG`].
+    async fn ranged(headers: HeaderMap) -> Response {
+        let range = headers
+            .get(header::RANGE)
+            .filter(|_| {
+                headers.get(header::IF_RANGE).is_some_and(|v| v == ETAG)
+            })
+            .and_then(|v| {
+                v.to_str().ok()?.strip_prefix("bytes=")?.strip_suffix('-')
+            })
+            .and_then(|start| start.parse::<usize>().ok());
+
+        match range {
+            Some(start) => (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::ETAG, ETAG.to_owned()),
+                    (
+                        header::CONTENT_RANGE,
+                        format!("bytes {start}-9/{}", RANGED.len()),
+                    ),
+                ],
+                &RANGED[start..],
+            )
+                .into_response(),
+            None => ([(header::ETAG, ETAG)], RANGED).into_response(),
+        }
+    }
+
+    async fn redirect(Path(n): Path<u32>) -> Redirect {
+        match n {
+            0 => Redirect::temporary("/file.txt"),
+            n => Redirect::temporary(&format!("/redirect/{}", n - 1)),
+        }
+    }
+
+    pub async fn server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let app = Router::new()
+            .route(
+                "/file.txt",
+                routing::get(|| async {
+                    ([(header::CONTENT_TYPE, "text/plain")], "Hello World!")
+                }),
+            )
+            .route(
+                "/chunked",
+                routing::get(|| async {
+                    let chunks = (0..8).map(|_| {
+                        Ok::<_, io::Error>(Bytes::from_static(&[0u8; 64]))
+                    });
+                    Body::from_stream(stream::iter(chunks)).into_response()
+                }),
+            )
+            .route("/redirect/:n", routing::get(redirect))
+            .route("/ranged", routing::get(ranged));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{addr}")
+    }
+
+    pub fn fetcher(max_size: u64) -> RemoteFetcher {
+        RemoteFetcher::new(FetchConfig {
+            allowed_hosts: vec![],
+            allow_private: true,
+            max_size,
+            max_redirects: 2,
+            connect_timeout: Duration::from_secs(5),
+            max_jobs_per_user: 0,
+            daily_max_bytes_per_user: 0,
+            allowed_folders: vec![],
+        })
+        .unwrap()
+    }
+
+    async fn read(fetcher: &RemoteFetcher, url: &str) -> io::Result<Vec<u8>> {
+        collect(fetcher.fetch(url).await.unwrap()).await
+    }
+
+    async fn collect(file: RemoteFile) -> io::Result<Vec<u8>> {
+        file.stream
+            .try_fold(Vec::new(), |mut buf, chunk| async move {
+                buf.extend_from_slice(&chunk);
+                Ok(buf)
+            })
+            .await
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch() {
+        let base = server().await;
+        let fetcher = fetcher(1024);
+
+        let file = fetcher.fetch(&format!("{base}/redirect/1")).await.unwrap();
+        assert_eq!(file.name, "file.txt");
+        assert_eq!(file.mime_type, "text/plain");
+
+        let data = read(&fetcher, &format!("{base}/file.txt")).await.unwrap();
+        assert_eq!(data, b"Hello World!");
+
+        let res = fetcher.fetch(&format!("{base}/redirect/2")).await;
+        assert!(
+            matches!(res, Err(FetchError::TooManyRedirects(2))),
+            "expected redirect limit error, got {:?}",
+            res.err(),
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_from() {
+        let url = format!("{}/ranged", server().await);
+        let fetcher = fetcher(1024);
+
+        let file = fetcher.fetch_from(&url, 4, Some(ETAG)).await.unwrap();
+        assert_eq!((file.offset, file.size), (4, Some(10)));
+        assert_eq!(file.etag.as_deref(), Some(ETAG));
+        assert_eq!(collect(file).await.unwrap(), b"456789");
+
+        // Changed files are fetched again from the start
+        let file = fetcher.fetch_from(&url, 4, Some("\"v0\"")).await.unwrap();
+        assert_eq!((file.offset, file.size), (0, Some(10)));
+        assert_eq!(collect(file).await.unwrap(), RANGED);
+
+        let file = fetcher.fetch_from(&url, 4, None).await.unwrap();
+        assert_eq!(file.offset, 0);
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_too_large() {
+        let base = server().await;
+
+        let res = fetcher(8).fetch(&format!("{base}/file.txt")).await;
+        assert!(matches!(res, Err(FetchError::TooLarge(8))));
+
+        // Without a content length the limit is enforced while streaming
+        let err = read(&fetcher(500), &format!("{base}/chunked"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        let data = read(&fetcher(512), &format!("{base}/chunked")).await;
+        assert_eq!(data.unwrap().len(), 512);
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_not_allowed() {
+        let base = server().await;
+
+        let private = RemoteFetcher::new(FetchConfig {
+            allow_private: false,
+            ..fetcher(1024).cfg.as_ref().clone()
+        })
+        .unwrap();
+        for url in [
+            format!("{base}/file.txt"),
+            "http://localhost/file.txt".into(),
+            "http://[::ffff:10.0.0.1]/file.txt".into(),
+        ] {
+            let res = private.fetch(&url).await;
+            assert!(
+                matches!(res, Err(FetchError::HostNotAllowed(..))),
+                "{url} was not blocked, got {:?}",
+                res.err(),
+            );
+        }
+
+        let allowlist = RemoteFetcher::new(FetchConfig {
+            allowed_hosts: vec!["*.example.com".into()],
+            ..fetcher(1024).cfg.as_ref().clone()
+        })
+        .unwrap();
+        let res = allowlist.fetch(&format!("{base}/file.txt")).await;
+        assert!(matches!(res, Err(FetchError::HostNotAllowed(..))));
+
+        let res = fetcher(1024).fetch("file:///etc/passwd").await;
+        assert!(matches!(res, Err(FetchError::InvalidUrl(..))));
+    }
+}
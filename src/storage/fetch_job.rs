@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
+    Pool, Row, Sqlite, Type,
+};
+use tokio::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{
+    errors::DownloaderError,
+    job::{
+        queue::{JobProgress, JobQueue},
+        repository::INTERRUPTED_ERROR,
+        Job, JobKind,
+    },
+    utils::retry::retry_busy,
+};
+
+use super::{
+    fetch::{FetchError, RemoteFetcher},
+    fetch_quota::{object_name, FetchQuota},
+    manager::{Manager, ObjectError},
+    provenance::{Provenance, Uploader},
+    repository::{ObjectRepository, RepositoryError},
+    routes::create_object,
+    slug::{ObjectIds, PublicObject},
+    UploadLimits,
+};
+
+/// Progress is saved each time this many bytes are fetched.
+const CHECKPOINT_BYTES: u64 = 1024 * 1024;
+/// Subdirectory of the temp dir holding the partially fetched files.
+const PARTIAL_DIR: &str = "fetch";
+
+/// What is needed to resume a background fetch after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchState {
+    pub job_id: Uuid,
+    pub user_id: Uuid,
+    pub namespace: String,
+    pub url: String,
+    /// The requested name, or the one of the remote file once known.
+    pub name: Option<String>,
+    /// Where the remote file is fetched into when it names the object.
+    pub folder: Option<String>,
+    pub mime_type: Option<String>,
+    pub provenance: Provenance,
+    /// Bytes of the remote file fetched so far.
+    pub fetched: u64,
+    pub total: Option<u64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl FetchState {
+    /// The validator the fetch can be resumed with, as weak etags can not
+    /// be used with ranges.
+    pub fn validator(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for FetchState
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    Option<i64>: Decode<'r, R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let uuid = |name: &'static str| {
+            let id: Vec<u8> = row.try_get(name)?;
+            let id: [u8; 16] = id.try_into().map_err(|_| {
+                sqlx::Error::Decode(
+                    format!("parse `{name}` uuid out of range").into(),
+                )
+            })?;
+            Ok::<_, sqlx::Error>(Uuid::from_bytes(id))
+        };
+
+        let provenance: String = row.try_get("provenance")?;
+        let provenance = serde_json::from_str(&provenance).map_err(|err| {
+            sqlx::Error::Decode(format!("parse `provenance`: {err}").into())
+        })?;
+
+        let fetched: i64 = row.try_get("fetched")?;
+        let total: Option<i64> = row.try_get("total")?;
+
+        Ok(Self {
+            job_id: uuid("job_id")?,
+            user_id: uuid("user_id")?,
+            namespace: row.try_get("namespace")?,
+            url: row.try_get("url")?,
+            name: row.try_get("name")?,
+            folder: row.try_get("folder")?,
+            mime_type: row.try_get("mime_type")?,
+            provenance,
+            fetched: fetched.max(0) as u64,
+            total: total.map(|total| total.max(0) as u64),
+            etag: row.try_get("etag")?,
+            last_modified: row.try_get("last_modified")?,
+        })
+    }
+}
+
+pub struct FetchStateRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for FetchStateRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> FetchStateRepository<DB> {
+    pub fn new(db: Pool<DB>) -> FetchStateRepository<DB> {
+        FetchStateRepository { db }
+    }
+}
+
+impl<DB> FetchStateRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> FetchState: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+    for<'e> Option<i64>: Encode<'e, DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+    for<'e> String: Encode<'e, DB>,
+    String: Type<DB>,
+{
+    pub async fn get_all(&self) -> Result<Vec<FetchState>, RepositoryError> {
+        sqlx::query_as("SELECT * FROM fetch_state")
+            .fetch_all(&self.db)
+            .await
+            .map_err(sqlx_error)
+    }
+
+    /// Counts the fetches of `user_id` in progress.
+    pub async fn count_by_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<u64, RepositoryError> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM fetch_state WHERE user_id = $1",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_one(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(count as u64)
+    }
+
+    pub async fn create(
+        &self,
+        state: &FetchState,
+    ) -> Result<(), RepositoryError> {
+        let provenance =
+            serde_json::to_string(&state.provenance).map_err(|err| {
+                RepositoryError::Sqlx(sqlx::Error::Encode(err.into()))
+            })?;
+
+        let job_id = state.job_id.into_bytes();
+        let user_id = state.user_id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "INSERT INTO fetch_state \
+                (job_id, user_id, namespace, url, name, folder, provenance, \
+                fetched, updated_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            )
+            .bind(job_id.as_slice())
+            .bind(user_id.as_slice())
+            .bind(state.namespace.as_str())
+            .bind(state.url.as_str())
+            .bind(state.name.as_deref())
+            .bind(state.folder.as_deref())
+            .bind(provenance.clone())
+            .bind(state.fetched as i64)
+            .bind(Utc::now().timestamp_millis())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+
+    /// Saves the progress of the fetch and what is known of the remote file.
+    pub async fn update(
+        &self,
+        state: &FetchState,
+    ) -> Result<(), RepositoryError> {
+        let job_id = state.job_id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "UPDATE fetch_state SET name = $1, mime_type = $2, \
+                fetched = $3, total = $4, etag = $5, last_modified = $6, \
+                updated_at = $7 WHERE job_id = $8",
+            )
+            .bind(state.name.as_deref())
+            .bind(state.mime_type.as_deref())
+            .bind(state.fetched as i64)
+            .bind(state.total.map(|total| total as i64))
+            .bind(state.etag.as_deref())
+            .bind(state.last_modified.as_deref())
+            .bind(Utc::now().timestamp_millis())
+            .bind(job_id.as_slice())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, job_id: Uuid) -> Result<(), RepositoryError> {
+        let job_id = job_id.into_bytes();
+        retry_busy(|| {
+            sqlx::query("DELETE FROM fetch_state WHERE job_id = $1")
+                .bind(job_id.as_slice())
+                .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying fetch states");
+    RepositoryError::Sqlx(error)
+}
+
+/// What the fetched files are stored with.
+pub struct FetchContext<M: Manager> {
+    pub fetcher: Arc<RemoteFetcher>,
+    pub repo: ObjectRepository<Sqlite>,
+    pub manager: Arc<M>,
+    pub limits: UploadLimits,
+    pub ids: ObjectIds,
+    pub uploader: Uploader,
+    pub quota: FetchQuota,
+}
+
+impl<M: Manager> Clone for FetchContext<M> {
+    fn clone(&self) -> Self {
+        Self {
+            fetcher: self.fetcher.clone(),
+            repo: self.repo.clone(),
+            manager: self.manager.clone(),
+            limits: self.limits,
+            ids: self.ids.clone(),
+            uploader: self.uploader.clone(),
+            quota: self.quota.clone(),
+        }
+    }
+}
+
+/// Runs background fetches, saving their progress and the fetched data so
+/// they are resumed with range requests after a restart.
+#[derive(Clone)]
+pub struct FetchJobs {
+    states: FetchStateRepository<Sqlite>,
+    dir: PathBuf,
+}
+
+impl FetchJobs {
+    pub fn new(db: Pool<Sqlite>, temp_dir: &Path) -> Self {
+        Self {
+            states: FetchStateRepository::new(db),
+            dir: temp_dir.join(PARTIAL_DIR),
+        }
+    }
+
+    /// Queues the fetch of `url` into an object of `user_id`, named after
+    /// the remote file in `folder` unless `name` is set. Fails if the user
+    /// has too many fetches in progress or fetched too much today.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn enqueue<M: Manager>(
+        &self,
+        jobs: &JobQueue,
+        ctx: FetchContext<M>,
+        user_id: Uuid,
+        url: String,
+        name: Option<String>,
+        folder: Option<String>,
+        provenance: Provenance,
+    ) -> Result<Job, DownloaderError> {
+        if let Some(max_jobs) = ctx.quota.max_jobs() {
+            let count = self.states.count_by_user(user_id).await?;
+            if count >= max_jobs as u64 {
+                return Err(FetchError::TooManyJobs(max_jobs).into());
+            }
+        }
+        ctx.quota.check_daily(user_id, None).await?;
+
+        let job = jobs.create(JobKind::FetchFile, Some(user_id)).await?;
+
+        let state = FetchState {
+            job_id: job.id,
+            user_id,
+            namespace: ctx.repo.namespace().to_owned(),
+            url,
+            name,
+            folder,
+            mime_type: None,
+            provenance,
+            fetched: 0,
+            total: None,
+            etag: None,
+            last_modified: None,
+        };
+        if let Err(error) = self.states.create(&state).await {
+            let _ = jobs.fail(job.id, &error.to_string()).await;
+            return Err(error.into());
+        }
+
+        let this = self.clone();
+        jobs.submit(job.id, |progress| this.run(ctx, state, progress))
+            .await?;
+        Ok(job)
+    }
+
+    /// Resumes the fetches interrupted by a restart, failing them instead
+    /// without `ctx`, when fetching is disabled. Returns how many were
+    /// resumed.
+    pub async fn resume<M: Manager>(
+        &self,
+        jobs: &JobQueue,
+        ctx: Option<FetchContext<M>>,
+    ) -> Result<usize, DownloaderError> {
+        let mut states: HashMap<_, _> = self
+            .states
+            .get_all()
+            .await?
+            .into_iter()
+            .map(|state| (state.job_id, state))
+            .collect();
+
+        let mut resumed = 0;
+        for job in jobs.interrupted(JobKind::FetchFile).await? {
+            let state = states.remove(&job.id);
+            let (Some(state), Some(ctx)) = (state, &ctx) else {
+                jobs.fail(job.id, INTERRUPTED_ERROR).await?;
+                self.discard(job.id).await;
+                continue;
+            };
+
+            let ctx = FetchContext {
+                repo: ctx.repo.in_namespace(&state.namespace),
+                ..ctx.clone()
+            };
+            let this = self.clone();
+            jobs.resume(job.id, |progress| this.run(ctx, state, progress))
+                .await?;
+            resumed += 1;
+        }
+
+        // Left behind by jobs that are gone
+        for job_id in states.into_keys() {
+            self.discard(job_id).await;
+        }
+
+        Ok(resumed)
+    }
+
+    async fn run<M: Manager>(
+        self,
+        ctx: FetchContext<M>,
+        state: FetchState,
+        progress: Arc<JobProgress>,
+    ) -> Result<PublicObject, DownloaderError> {
+        let job_id = state.job_id;
+        let res = self.fetch(&ctx, state, &progress).await;
+
+        // Only interrupted fetches are resumed, failed ones start over
+        self.discard(job_id).await;
+        res
+    }
+
+    async fn fetch<M: Manager>(
+        &self,
+        ctx: &FetchContext<M>,
+        mut state: FetchState,
+        progress: &JobProgress,
+    ) -> Result<PublicObject, DownloaderError> {
+        let path = self.dir.join(state.job_id.to_string());
+        let mut file =
+            self.open_partial(&path).await.map_err(ObjectError::from)?;
+        let len = file.metadata().await.map_err(ObjectError::from)?.len();
+
+        let remote = ctx
+            .fetcher
+            .fetch_from(&state.url, len, state.validator())
+            .await?;
+        let remaining =
+            remote.size.map(|size| size.saturating_sub(remote.offset));
+        ctx.quota.check_daily(state.user_id, remaining).await?;
+        if len > 0 {
+            tracing::info!(
+                job_id = %state.job_id,
+                offset = remote.offset,
+                "resuming fetch",
+            );
+        }
+
+        // Starts over unless the server sent the rest of the file
+        file.set_len(remote.offset)
+            .await
+            .map_err(ObjectError::from)?;
+        file.seek(SeekFrom::Start(remote.offset))
+            .await
+            .map_err(ObjectError::from)?;
+
+        state.fetched = remote.offset;
+        state.total = remote.size;
+        state.etag = remote.etag;
+        state.last_modified = remote.last_modified;
+        let name = state
+            .name
+            .get_or_insert_with(|| {
+                object_name(state.folder.as_deref(), remote.name)
+            })
+            .clone();
+        let mime_type = state.mime_type.insert(remote.mime_type).clone();
+        self.states.update(&state).await?;
+
+        if let Some(total) = state.total {
+            progress.set_total(total);
+        }
+        progress.add(state.fetched);
+
+        let mut stream = ctx.limits.apply(remote.stream);
+        let mut saved = state.fetched;
+        while let Some(chunk) =
+            stream.try_next().await.map_err(ObjectError::from)?
+        {
+            file.write_all(&chunk).await.map_err(ObjectError::from)?;
+            progress.add(chunk.len() as u64);
+            state.fetched += chunk.len() as u64;
+
+            if state.fetched - saved >= CHECKPOINT_BYTES {
+                file.flush().await.map_err(ObjectError::from)?;
+                self.states.update(&state).await?;
+                ctx.quota.record(state.user_id, state.fetched - saved).await;
+                saved = state.fetched;
+            }
+        }
+        ctx.quota.record(state.user_id, state.fetched - saved).await;
+        file.sync_all().await.map_err(ObjectError::from)?;
+        drop(file);
+
+        let data = File::open(&path).await.map_err(ObjectError::from)?;
+        let obj = create_object(
+            ctx.repo.clone(),
+            ctx.manager.clone(),
+            Uuid::new_v4(),
+            state.user_id,
+            ReaderStream::new(data),
+            name,
+            mime_type,
+            None,
+            Some(state.fetched),
+        )
+        .await?;
+        ctx.uploader.record(obj.id, &state.provenance).await;
+
+        Ok(ctx.ids.expose(obj).await?)
+    }
+
+    async fn open_partial(&self, path: &Path) -> io::Result<File> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&self.dir).await?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options.open(path).await
+    }
+
+    /// Removes the state and data of the fetch of the job `job_id`.
+    async fn discard(&self, job_id: Uuid) {
+        // Errors are already logged by the repository
+        let _ = self.states.delete(job_id).await;
+
+        let path = self.dir.join(job_id.to_string());
+        match tokio::fs::remove_file(&path).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                tracing::warn!(%error, %job_id, "failed to remove fetched data")
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sqlx::{
+    Database, Encode, Executor, FromRow, IntoArguments, Pool, Sqlite, Type,
+};
+use uuid::Uuid;
+
+use crate::{
+    config::FetchConfig, errors::DownloaderError, utils::retry::retry_busy,
+};
+
+use super::{
+    fetch::FetchError, normalize_folder, repository::RepositoryError,
+    stats::day_of,
+};
+
+/// Replaced by the name of the user in the allowed folders.
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// Names the object of a fetched file, inside `folder` if any.
+pub fn object_name(folder: Option<&str>, name: String) -> String {
+    match folder {
+        Some(folder) => format!("{folder}/{name}"),
+        None => name,
+    }
+}
+
+/// Bytes fetched by each user per day, only the current day being kept.
+pub struct FetchUsageRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for FetchUsageRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> FetchUsageRepository<DB> {
+    pub fn new(db: Pool<DB>) -> FetchUsageRepository<DB> {
+        FetchUsageRepository { db }
+    }
+}
+
+impl<DB> FetchUsageRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    /// Returns the bytes fetched by `user_id` in the `day` counted since
+    /// the unix epoch.
+    pub async fn get(
+        &self,
+        user_id: Uuid,
+        day: i64,
+    ) -> Result<u64, RepositoryError> {
+        let bytes: Option<(i64,)> = sqlx::query_as(
+            "SELECT bytes FROM fetch_usage WHERE user_id = $1 AND day = $2",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(day)
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(bytes
+            .map(|(bytes,)| bytes.max(0) as u64)
+            .unwrap_or_default())
+    }
+
+    /// Adds `bytes` to the ones fetched by `user_id` in the `day`, removing
+    /// the usage of the days before.
+    pub async fn add(
+        &self,
+        user_id: Uuid,
+        day: i64,
+        bytes: u64,
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.add_once(user_id, day, bytes))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn add_once(
+        &self,
+        user_id: Uuid,
+        day: i64,
+        bytes: u64,
+    ) -> Result<(), sqlx::Error> {
+        let user_id = user_id.into_bytes();
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO fetch_usage (user_id, day, bytes) VALUES ($1, $2, $3) \
+            ON CONFLICT (user_id, day) DO UPDATE SET \
+            bytes = bytes + excluded.bytes",
+        )
+        .bind(user_id.as_slice())
+        .bind(day)
+        .bind(bytes as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM fetch_usage WHERE user_id = $1 AND day < $2")
+            .bind(user_id.as_slice())
+            .bind(day)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying fetch usage");
+    RepositoryError::Sqlx(error)
+}
+
+/// Limits of the fetches of each user, so fetching can be enabled on
+/// instances shared by many of them.
+#[derive(Clone)]
+pub struct FetchQuota {
+    usage: FetchUsageRepository<Sqlite>,
+    max_jobs: usize,
+    daily_max_bytes: u64,
+    allowed_folders: Arc<[String]>,
+}
+
+impl FetchQuota {
+    pub fn new(db: Pool<Sqlite>, cfg: &FetchConfig) -> Self {
+        Self {
+            usage: FetchUsageRepository::new(db),
+            max_jobs: cfg.max_jobs_per_user,
+            daily_max_bytes: cfg.daily_max_bytes_per_user,
+            allowed_folders: cfg.allowed_folders.clone().into(),
+        }
+    }
+
+    /// Fetches each user can have in progress at once, if limited.
+    #[inline]
+    pub fn max_jobs(&self) -> Option<usize> {
+        (self.max_jobs > 0).then_some(self.max_jobs)
+    }
+
+    /// Checks if `username` can fetch files into `folder`, the root one
+    /// when `None`.
+    pub fn check_folder(
+        &self,
+        username: &str,
+        folder: Option<&str>,
+    ) -> Result<(), FetchError> {
+        if self.allowed_folders.is_empty() {
+            return Ok(());
+        }
+        let Some(folder) = folder else {
+            return Err(FetchError::FolderNotAllowed("/".into()));
+        };
+
+        let allowed = normalize_folder(folder) == Some(folder)
+            && self.allowed_folders.iter().any(|allowed| {
+                let allowed = allowed.replace(USERNAME_PLACEHOLDER, username);
+                let allowed = allowed.trim_matches('/');
+                folder.strip_prefix(allowed).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/')
+                })
+            });
+        if !allowed {
+            return Err(FetchError::FolderNotAllowed(folder.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Checks if `user_id` can still fetch today, and `size` more bytes if
+    /// known.
+    pub async fn check_daily(
+        &self,
+        user_id: Uuid,
+        size: Option<u64>,
+    ) -> Result<(), DownloaderError> {
+        if self.daily_max_bytes == 0 {
+            return Ok(());
+        }
+
+        let used = self.usage.get(user_id, day_of(Utc::now())).await?;
+        if used >= self.daily_max_bytes
+            || size.is_some_and(|size| {
+                used.saturating_add(size) > self.daily_max_bytes
+            })
+        {
+            return Err(
+                FetchError::DailyLimitExceeded(self.daily_max_bytes).into()
+            );
+        }
+        Ok(())
+    }
+
+    /// Counts `bytes` as fetched today by `user_id`.
+    pub async fn record(&self, user_id: Uuid, bytes: u64) {
+        if self.daily_max_bytes == 0 || bytes == 0 {
+            return;
+        }
+
+        let day = day_of(Utc::now());
+        if let Err(error) = self.usage.add(user_id, day, bytes).await {
+            tracing::warn!(%error, %user_id, "failed to record fetched bytes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    fn config() -> FetchConfig {
+        FetchConfig {
+            allowed_hosts: vec![],
+            allow_private: true,
+            max_size: 1024,
+            max_redirects: 0,
+            connect_timeout: Duration::from_secs(5),
+            max_jobs_per_user: 2,
+            daily_max_bytes_per_user: 100,
+            allowed_folders: vec!["shared/".into(), "users/{username}".into()],
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_check_folder() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let quota = FetchQuota::new(db.clone(), &config());
+
+        for folder in ["shared", "shared/a", "users/alice", "users/alice/b"] {
+            quota.check_folder("alice", Some(folder)).unwrap();
+        }
+        for folder in [
+            None,
+            Some("sharedx"),
+            Some("users/bob"),
+            Some("users/alice/../bob"),
+            Some("users/alicex"),
+            Some("other"),
+        ] {
+            assert!(matches!(
+                quota.check_folder("alice", folder),
+                Err(FetchError::FolderNotAllowed(..)),
+            ));
+        }
+
+        let quota = FetchQuota::new(
+            db,
+            &FetchConfig {
+                allowed_folders: vec![],
+                ..config()
+            },
+        );
+        quota.check_folder("alice", None).unwrap();
+        quota.check_folder("alice", Some("other")).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_daily_usage() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let quota = FetchQuota::new(db.clone(), &config());
+        let user_id = Uuid::new_v4();
+        let today = day_of(Utc::now());
+
+        quota.check_daily(user_id, Some(100)).await.unwrap();
+        quota.record(user_id, 60).await;
+        quota.check_daily(user_id, Some(40)).await.unwrap();
+        assert!(matches!(
+            quota.check_daily(user_id, Some(41)).await,
+            Err(DownloaderError::Fetch(FetchError::DailyLimitExceeded(100))),
+        ));
+
+        quota.record(user_id, 40).await;
+        assert!(matches!(
+            quota.check_daily(user_id, None).await,
+            Err(DownloaderError::Fetch(FetchError::DailyLimitExceeded(100))),
+        ));
+        quota.check_daily(Uuid::new_v4(), None).await.unwrap();
+
+        // Only the usage of the current day is kept
+        let repo = FetchUsageRepository::new(db);
+        repo.add(user_id, today + 1, 10).await.unwrap();
+        assert_eq!(repo.get(user_id, today).await.unwrap(), 0);
+        assert_eq!(repo.get(user_id, today + 1).await.unwrap(), 10);
+    }
+}
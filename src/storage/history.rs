@@ -0,0 +1,348 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
+    Pool, Row, Type,
+};
+use uuid::Uuid;
+
+use crate::{auth::Token, utils::retry::retry_busy};
+
+use super::{
+    provenance::TokenType,
+    repository::{RepositoryError, MAX_LIMIT},
+    Object,
+};
+
+/// An update of the info of an object and who made it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub changed_at: DateTime<Utc>,
+    /// User of the token the update was made with, `None` for file and
+    /// server tokens.
+    pub user_id: Option<Uuid>,
+    pub token_type: TokenType,
+    /// Id of the api key the update was made with.
+    pub token_id: Option<String>,
+    pub previous_name: String,
+    pub name: String,
+    /// Fields changed, among `name`, `mime_type`, `description`,
+    /// `metadata` and `owner`.
+    pub fields: Vec<String>,
+}
+
+impl HistoryEntry {
+    /// The update made with `token` of the object from `before` to `after`,
+    /// whose custom metadata was replaced if `metadata`.
+    pub fn new(
+        token: &Token,
+        before: &Object,
+        after: &Object,
+        metadata: bool,
+    ) -> Self {
+        let (token_type, token_id) = TokenType::of(Some(token));
+        let user_id = match token {
+            Token::User(user_token) => Some(user_token.user_id),
+            Token::File(..) | Token::Server => None,
+        };
+
+        let fields = [
+            ("name", before.data.name != after.data.name),
+            ("mime_type", before.data.mime_type != after.data.mime_type),
+            ("description", before.description != after.description),
+            ("metadata", metadata),
+            ("owner", before.user_id != after.user_id),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(field, _)| field.to_owned())
+        .collect();
+
+        Self {
+            changed_at: after.updated_at,
+            user_id,
+            token_type,
+            token_id,
+            previous_name: before.data.name.clone(),
+            name: after.data.name.clone(),
+            fields,
+        }
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for HistoryEntry
+where
+    &'r str: ColumnIndex<R>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
+
+    Option<Vec<u8>>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let changed_at: i64 = row.try_get("changed_at")?;
+        let changed_at = DateTime::from_timestamp_millis(changed_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `changed_at` field gone wrong".into(),
+                )
+            })?;
+
+        let user_id: Option<Vec<u8>> = row.try_get("user_id")?;
+        let user_id = user_id.map(parse_user_id).transpose()?;
+
+        let token_type: String = row.try_get("token_type")?;
+        let token_type = TokenType::parse(&token_type).ok_or_else(|| {
+            sqlx::Error::Decode(
+                format!("parse `token_type`: unknown `{token_type}`").into(),
+            )
+        })?;
+
+        let fields: String = row.try_get("fields")?;
+        let fields = fields
+            .split(',')
+            .filter(|field| !field.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        Ok(HistoryEntry {
+            changed_at,
+            user_id,
+            token_type,
+            token_id: row.try_get("token_id")?,
+            previous_name: row.try_get("previous_name")?,
+            name: row.try_get("name")?,
+            fields,
+        })
+    }
+}
+
+fn parse_user_id(id: Vec<u8>) -> Result<Uuid, sqlx::Error> {
+    Uuid::from_slice(&id).map_err(|_| {
+        sqlx::Error::Decode("parse `user_id` uuid out of range".into())
+    })
+}
+
+/// The updates of an object, newest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectHistory {
+    /// User that last updated the info of the object, `None` if never
+    /// updated or last updated with a file or server token.
+    pub updated_by: Option<Uuid>,
+    pub entries: Vec<HistoryEntry>,
+}
+
+pub struct HistoryRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for HistoryRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> HistoryRepository<DB> {
+    pub fn new(db: Pool<DB>) -> HistoryRepository<DB> {
+        HistoryRepository { db }
+    }
+}
+
+impl<DB> HistoryRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> HistoryEntry: FromRow<'r, DB::Row>,
+    for<'r> (Option<Vec<u8>>,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+    for<'e> Option<&'e [u8]>: Encode<'e, DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+{
+    /// Returns who last updated the object `id` and its updates.
+    pub async fn get(
+        &self,
+        id: Uuid,
+        limit: u32,
+        offset: u32,
+    ) -> Result<ObjectHistory, RepositoryError> {
+        if limit > MAX_LIMIT {
+            return Err(RepositoryError::LimitOutOfRange(limit));
+        }
+
+        let (updated_by,): (Option<Vec<u8>>,) =
+            sqlx::query_as("SELECT updated_by FROM object WHERE id = $1")
+                .bind(id.into_bytes().as_slice())
+                .fetch_optional(&self.db)
+                .await
+                .map_err(sqlx_error)?
+                .ok_or(RepositoryError::NotFound(id))?;
+        let updated_by = updated_by
+            .map(parse_user_id)
+            .transpose()
+            .map_err(RepositoryError::Sqlx)?;
+
+        let entries = sqlx::query_as(
+            "SELECT * FROM object_history WHERE object_id = $1 \
+            ORDER BY changed_at DESC, rowid DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(ObjectHistory {
+            updated_by,
+            entries,
+        })
+    }
+
+    /// Adds `entry` to the history of the object `id`, which is marked as
+    /// last updated by its user.
+    pub async fn record(
+        &self,
+        id: Uuid,
+        entry: &HistoryEntry,
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.record_once(id, entry))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn record_once(
+        &self,
+        id: Uuid,
+        entry: &HistoryEntry,
+    ) -> Result<(), sqlx::Error> {
+        let user_id = entry.user_id.map(|id| id.into_bytes());
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO object_history \
+            (object_id, changed_at, user_id, token_type, token_id, \
+            previous_name, name, fields) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(entry.changed_at.timestamp_millis())
+        .bind(user_id.as_ref().map(|id| id.as_slice()))
+        .bind(entry.token_type.as_str())
+        .bind(entry.token_id.as_deref())
+        .bind(entry.previous_name.as_str())
+        .bind(entry.name.as_str())
+        .bind(entry.fields.join(",").as_str())
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE object SET updated_by = $1 WHERE id = $2")
+            .bind(user_id.as_ref().map(|id| id.as_slice()))
+            .bind(id.into_bytes().as_slice())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object history");
+    RepositoryError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sqlx::{migrate, SqlitePool};
+
+    use crate::{
+        auth::{Permission, UserToken},
+        storage::{repository::ObjectRepository, ObjectData},
+    };
+
+    use super::*;
+
+    fn user_token(user_id: Uuid) -> Token {
+        Token::User(UserToken {
+            user_id,
+            created_at: Utc::now(),
+            expiration: Utc::now(),
+            issuer: "key/k1".into(),
+            permission: Permission::UNPRIVILEGED,
+            username: "test".into(),
+            namespace: "default".into(),
+        })
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_history() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let objects = ObjectRepository::new(db.clone());
+        let repo = HistoryRepository::new(db);
+
+        let (id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let data = ObjectData {
+            name: "fox.txt".into(),
+            mime_type: "text/plain".into(),
+            size: 0,
+            checksum_256: [0; 32],
+        };
+        let before = objects.create(id, user_id, data).await.unwrap();
+
+        let history = repo.get(id, 10, 0).await.unwrap();
+        assert_eq!(history.updated_by, None);
+        assert!(history.entries.is_empty());
+
+        let after = objects
+            .update_info(id, "dog.txt".into(), "text/plain".into(), None, None)
+            .await
+            .unwrap();
+        let entry =
+            HistoryEntry::new(&user_token(user_id), &before, &after, true);
+        assert_eq!(entry.token_type, TokenType::ApiKey);
+        assert_eq!(entry.token_id.as_deref(), Some("k1"));
+        assert_eq!(entry.fields, ["name", "metadata"]);
+        repo.record(id, &entry).await.unwrap();
+
+        let history = repo.get(id, 10, 0).await.unwrap();
+        assert_eq!(history.updated_by, Some(user_id));
+        assert_eq!(history.entries.len(), 1);
+        assert_eq!(history.entries[0], entry);
+
+        let server = HistoryEntry::new(&Token::Server, &after, &after, false);
+        assert!(server.fields.is_empty());
+        repo.record(id, &server).await.unwrap();
+
+        // Newest first
+        let history = repo.get(id, 10, 0).await.unwrap();
+        assert_eq!(history.updated_by, None);
+        assert_eq!(history.entries.len(), 2);
+        assert_eq!(history.entries[0].token_type, TokenType::Server);
+        assert_eq!(repo.get(id, 1, 1).await.unwrap().entries, [entry]);
+
+        assert!(matches!(
+            repo.get(Uuid::new_v4(), 10, 0).await,
+            Err(RepositoryError::NotFound(..)),
+        ));
+    }
+}
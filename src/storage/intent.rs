@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Sqlite, Type};
+use uuid::Uuid;
+
+use super::{
+    manager::{Manager, ObjectError},
+    repository::{ObjectRepository, RepositoryError},
+    ObjectData,
+};
+
+/// A creation of an object not finished yet, see
+/// [`ObjectRepository::begin_create`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateIntent {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub namespace: String,
+    pub name: String,
+    pub mime_type: String,
+    /// The size and checksum of the data once in place.
+    pub committed: Option<(u64, [u8; 32])>,
+}
+
+impl CreateIntent {
+    /// The data of the object, if committed.
+    pub fn data(&self) -> Option<ObjectData> {
+        self.committed.map(|(size, checksum_256)| ObjectData {
+            name: self.name.clone(),
+            mime_type: self.mime_type.clone(),
+            size,
+            checksum_256,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for CreateIntent
+where
+    &'r str: ColumnIndex<R>,
+
+    Vec<u8>: Decode<'r, R::Database>,
+    Vec<u8>: Type<R::Database>,
+    Option<Vec<u8>>: Decode<'r, R::Database>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    Option<i64>: Decode<'r, R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let parse_uuid = |column: &'static str| {
+            let bytes: Vec<u8> = row.try_get(column)?;
+            Uuid::from_slice(&bytes).map_err(|_| {
+                sqlx::Error::Decode(
+                    format!("parse `{column}` uuid out of range").into(),
+                )
+            })
+        };
+
+        let state: i64 = row.try_get("state")?;
+        let committed = if state == 0 {
+            None
+        } else {
+            let size: Option<i64> = row.try_get("size")?;
+            let size = size.unwrap_or_default().try_into().map_err(|err| {
+                sqlx::Error::Decode(format!("parse `size`: {err}").into())
+            })?;
+
+            let checksum_256: Option<Vec<u8>> = row.try_get("checksum_256")?;
+            let checksum_256 =
+                checksum_256.unwrap_or_default().try_into().map_err(|_| {
+                    sqlx::Error::Decode(
+                        "parse `checksum_256` array out of range".into(),
+                    )
+                })?;
+
+            Some((size, checksum_256))
+        };
+
+        Ok(Self {
+            id: parse_uuid("id")?,
+            user_id: parse_uuid("user_id")?,
+            namespace: row.try_get("namespace")?,
+            name: row.try_get("name")?,
+            mime_type: row.try_get("mime_type")?,
+            committed,
+        })
+    }
+}
+
+/// Creations found unfinished on startup.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct Recovered {
+    /// Committed ones, whose objects were created.
+    pub completed: u64,
+    /// Pending ones, whose data was removed.
+    pub aborted: u64,
+}
+
+/// Finishes the creations interrupted by a crash, creating the objects whose
+/// data was already in place and removing the partial data of the others.
+/// Must run before any creation starts.
+pub async fn recover_creates<M: Manager>(
+    repo: &ObjectRepository<Sqlite>,
+    manager: &M,
+) -> Result<Recovered, RepositoryError> {
+    let mut recovered = Recovered::default();
+
+    for intent in repo.create_intents().await? {
+        let id = intent.id;
+
+        let Some(data) = intent.data() else {
+            match manager.delete(id).await {
+                Ok(()) | Err(ObjectError::NotFound) => {}
+                Err(error) => {
+                    tracing::error!(
+                        %error,
+                        %id,
+                        "failed to delete data of aborted object creation",
+                    );
+                    continue;
+                }
+            }
+            repo.abort_create(id).await?;
+            recovered.aborted += 1;
+            continue;
+        };
+
+        repo.in_namespace(&intent.namespace)
+            .create(id, intent.user_id, data)
+            .await?;
+        recovered.completed += 1;
+    }
+
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::stream;
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+    use crate::{
+        config::StorageConfig,
+        storage::{manager::ObjectManager, routes::create_object},
+    };
+
+    async fn setup() -> (
+        tempfile::TempDir,
+        ObjectRepository<Sqlite>,
+        Arc<ObjectManager>,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        let temp_dir = dir.path().join("temp");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        let cfg: StorageConfig = toml::from_str(&format!(
+            "data_dir = {:?}\ntemp_dir = {:?}\nstate_dir = {:?}",
+            data_dir,
+            temp_dir,
+            dir.path(),
+        ))
+        .unwrap();
+
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let repo = ObjectRepository::new(db);
+        let manager = Arc::new(ObjectManager::new(&cfg));
+        (dir, repo, manager)
+    }
+
+    async fn store(manager: &ObjectManager, id: Uuid) -> (u64, [u8; 32]) {
+        let data = stream::iter([Ok(bytes::Bytes::from_static(b"data"))]);
+        manager.store(id, data, None, None).await.unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_recover_creates() {
+        let (_dir, repo, manager) = setup().await;
+        let user_id = Uuid::new_v4();
+
+        // Finished creations leave no intent behind
+        let data = stream::iter([Ok(bytes::Bytes::from_static(b"data"))]);
+        let obj = create_object(
+            repo.clone(),
+            manager.clone(),
+            Uuid::new_v4(),
+            user_id,
+            data,
+            "done.txt".into(),
+            "text/plain".into(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(repo.create_intents().await.unwrap().is_empty());
+
+        // Crashed after storing the data, before marking it committed
+        let pending = Uuid::new_v4();
+        repo.begin_create(pending, user_id, "pending.txt", "text/plain")
+            .await
+            .unwrap();
+        store(&manager, pending).await;
+
+        // Crashed after marking it committed, before creating the object
+        let committed = Uuid::new_v4();
+        repo.begin_create(committed, user_id, "committed.txt", "text/plain")
+            .await
+            .unwrap();
+        let (size, checksum) = store(&manager, committed).await;
+        repo.commit_create(committed, size, checksum).await.unwrap();
+
+        let recovered = recover_creates(&repo, manager.as_ref()).await.unwrap();
+        assert_eq!(
+            recovered,
+            Recovered {
+                completed: 1,
+                aborted: 1
+            },
+        );
+        assert!(repo.create_intents().await.unwrap().is_empty());
+
+        assert!(matches!(
+            manager.fetch(pending).await,
+            Err(ObjectError::NotFound),
+        ));
+        assert!(matches!(
+            repo.get(pending).await,
+            Err(RepositoryError::NotFound(..)),
+        ));
+
+        let obj_committed = repo.get(committed).await.unwrap();
+        assert_eq!(obj_committed.data.name, "committed.txt");
+        assert_eq!(obj_committed.data.size, 4);
+        assert_eq!(obj_committed.data.checksum_256, obj.data.checksum_256);
+        manager.fetch(committed).await.unwrap();
+    }
+}
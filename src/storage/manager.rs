@@ -1,50 +1,276 @@
 use std::{
-    io::{self, ErrorKind},
-    path::PathBuf,
-    time::Instant,
+    future::Future,
+    io::{self, Cursor, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use axum::http::StatusCode;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures_util::{Stream, StreamExt};
-use sha2::Sha256;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{digest::Output, Digest, Sha256};
 use tokio::{
-    fs::{remove_file, rename, File},
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    fs::{
+        create_dir_all, hard_link, read_dir, remove_dir, remove_file, rename,
+        try_exists, DirBuilder, File, OpenOptions,
+    },
+    io::{
+        AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter, ReadBuf, Take,
+    },
 };
 use tracing::instrument;
 use uuid::Uuid;
 
 use crate::{
-    config::StorageConfig,
+    config::{IoConfig, StorageConfig},
     utils::{
-        crypto::HashStream,
+        crypto::ConcurrentHasher,
         fmt::{fmt_hex, fmt_since},
+        lock::KeyedLock,
+        stream::CoalesceStream,
+        sys::{fadvise, preallocate, sync_filesystem, Advice},
     },
 };
 
+use super::data_cache::{DataCache, DataCacheUsage};
+
 #[derive(Debug, thiserror::Error)]
 pub enum ObjectError {
     #[error("io error in file system: {0}")]
-    IoError(#[from] io::Error),
+    IoError(#[source] io::Error),
     #[error("file not found")]
     NotFound,
+    #[error("another write of the file is in progress")]
+    WriteConflict,
+    #[error("invalid sha256 checksum, expected 64 hex digits")]
+    InvalidChecksum,
+    #[error("the data does not match the expected sha256 checksum")]
+    ChecksumMismatch,
+    #[error("the announced `x-checksum-sha256` trailer was not sent")]
+    MissingChecksumTrailer,
+    #[error("invalid sha-256 digest, expected a base64 byte sequence")]
+    InvalidDigest,
+    #[error("too many uploads in progress, try again later")]
+    TooManyUploads,
+}
+
+impl From<io::Error> for ObjectError {
+    /// Unwraps the errors of data streams failed with an [`ObjectError`].
+    fn from(error: io::Error) -> Self {
+        if error.get_ref().is_some_and(|e| e.is::<ObjectError>()) {
+            return *error.into_inner().unwrap().downcast().unwrap();
+        }
+        ObjectError::IoError(error)
+    }
 }
 
 impl ObjectError {
     #[inline]
     pub fn status_code(&self) -> StatusCode {
         match self {
+            ObjectError::IoError(e) if e.kind() == ErrorKind::TimedOut => {
+                StatusCode::REQUEST_TIMEOUT
+            }
+            ObjectError::IoError(e) if e.kind() == ErrorKind::FileTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
             ObjectError::IoError(..) => StatusCode::INTERNAL_SERVER_ERROR,
             ObjectError::NotFound => StatusCode::NOT_FOUND,
+            ObjectError::WriteConflict => StatusCode::CONFLICT,
+            ObjectError::InvalidChecksum => StatusCode::BAD_REQUEST,
+            ObjectError::ChecksumMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            ObjectError::MissingChecksumTrailer => StatusCode::BAD_REQUEST,
+            ObjectError::InvalidDigest => StatusCode::BAD_REQUEST,
+            ObjectError::TooManyUploads => StatusCode::TOO_MANY_REQUESTS,
         }
     }
+}
+
+crate::errors::error_codes! {
+    ObjectError {
+        IoError(..) => 1, "io error in the file system";
+        NotFound => 2, "file not found";
+        WriteConflict => 3, "another write of the file is in progress";
+        InvalidChecksum => 4, "the sha256 checksum is not 64 hex digits";
+        ChecksumMismatch => 5,
+            "the data does not match the expected sha256 checksum";
+        MissingChecksumTrailer => 6,
+            "the announced `x-checksum-sha256` trailer was not sent";
+        InvalidDigest => 7, "the sha-256 digest is not a base64 byte sequence";
+        TooManyUploads => 8, "too many uploads in progress";
+    }
+}
 
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct DirUsage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl DirUsage {
     #[inline]
-    pub fn custom_code(&self) -> u8 {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Space used by a [`Manager`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    pub data: DirUsage,
+    /// Objects being stored, including the orphaned ones.
+    pub incomplete: DirUsage,
+    /// Incomplete objects left behind by stores that are no longer running,
+    /// which can be safely removed.
+    pub orphaned: DirUsage,
+    pub oldest_incomplete: Option<DateTime<Utc>>,
+    /// Data of small objects kept in memory, if enabled.
+    pub data_cache: Option<DataCacheUsage>,
+}
+
+/// Storage backend of object data, addressed by the object id.
+pub trait Manager: Send + Sync + 'static {
+    type Reader: AsyncRead + Send + Unpin + 'static;
+
+    /// Stores the stream as the object data, returning the written size and
+    /// its sha256 checksum, which must match `checksum` if set. No data is
+    /// left behind if the store fails. `size` is the expected size of the
+    /// data when known, only used as a hint.
+    fn store(
+        &self,
+        id: Uuid,
+        stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+        checksum: Option<[u8; 32]>,
+        size: Option<u64>,
+    ) -> impl Future<Output = Result<(u64, [u8; 32]), ObjectError>> + Send;
+
+    /// Stores many small objects at once, writing their data to the disk
+    /// together before putting it in place instead of one by one. Returns
+    /// the size and sha256 checksum of each object, in order. No data is
+    /// left behind if the store fails.
+    fn store_batch(
+        &self,
+        objects: Vec<(Uuid, Bytes)>,
+    ) -> impl Future<Output = Result<Vec<(u64, [u8; 32])>, ObjectError>> + Send;
+
+    fn fetch(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<Self::Reader, ObjectError>> + Send;
+
+    /// Reads up to `len` bytes of the object data starting at `offset`,
+    /// nothing if it is past the end.
+    fn fetch_range(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> impl Future<Output = Result<Take<Self::Reader>, ObjectError>> + Send;
+
+    fn delete(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<(), ObjectError>> + Send;
+
+    /// Keeps the current data of the object, so the stores following it can
+    /// be undone with [`Manager::rollback`] until [`Manager::release`] is
+    /// called. Callers must serialize the writes of the object meanwhile.
+    fn snapshot(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<(), ObjectError>> + Send;
+
+    /// Puts back the data kept by [`Manager::snapshot`].
+    fn rollback(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<(), ObjectError>> + Send;
+
+    /// Drops the data kept by [`Manager::snapshot`], if any.
+    fn release(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<(), ObjectError>> + Send;
+
+    /// Removes the incomplete objects left behind by stores that are no
+    /// longer running and were last written `max_age` ago, returning the
+    /// space freed.
+    fn sweep(
+        &self,
+        max_age: Duration,
+    ) -> impl Future<Output = Result<DirUsage, ObjectError>> + Send;
+
+    fn usage(
+        &self,
+    ) -> impl Future<Output = Result<StorageUsage, ObjectError>> + Send;
+}
+
+/// Subdirectory of the temp dir holding incomplete objects, only
+/// accessible by the server user.
+pub(super) const INCOMPLETE_DIR: &str = "incomplete";
+
+/// Subdirectory of the temp dir holding the snapshots of objects, named by
+/// their id. Snapshots are hard links, so the temp dir must be in the same
+/// filesystem as the data dir, which stores already require.
+const SNAPSHOT_DIR: &str = "snapshot";
+
+/// How the object data is laid out in the data dir.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DataLayout {
+    /// All the objects directly in the data dir.
+    #[default]
+    Flat,
+    /// Objects in two levels of subdirectories named after the first four
+    /// hex digits of their id, as in `ab/cd/abcd...`, so no directory grows
+    /// too large.
+    Sharded,
+}
+
+impl DataLayout {
+    fn path(self, data_dir: &Path, id: &str) -> PathBuf {
         match self {
-            ObjectError::IoError(..) => 1,
-            ObjectError::NotFound => 2,
+            DataLayout::Flat => data_dir.join(id),
+            DataLayout::Sharded => {
+                data_dir.join(&id[..2]).join(&id[2..4]).join(id)
+            }
+        }
+    }
+
+    fn other(self) -> DataLayout {
+        match self {
+            DataLayout::Flat => DataLayout::Sharded,
+            DataLayout::Sharded => DataLayout::Flat,
+        }
+    }
+}
+
+/// Data of an object fetched by an [`ObjectManager`].
+pub enum ObjectReader {
+    File(BufReader<File>),
+    Cached(Cursor<Bytes>),
+}
+
+impl AsyncRead for ObjectReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ObjectReader::File(reader) => Pin::new(reader).poll_read(cx, buf),
+            ObjectReader::Cached(cursor) => Pin::new(cursor).poll_read(cx, buf),
         }
     }
 }
@@ -52,6 +278,10 @@ impl ObjectError {
 pub struct ObjectManager {
     data_dir: PathBuf,
     temp_dir: PathBuf,
+    layout: DataLayout,
+    io: IoConfig,
+    cache: Option<DataCache>,
+    locks: KeyedLock<Uuid>,
 }
 
 impl ObjectManager {
@@ -59,39 +289,214 @@ impl ObjectManager {
         Self {
             data_dir: PathBuf::from(cfg.data_dir.as_str()),
             temp_dir: PathBuf::from(cfg.temp_dir.as_str()),
+            layout: cfg.data_layout,
+            io: cfg.io.clone(),
+            cache: cfg.cache.as_ref().map(DataCache::new),
+            locks: KeyedLock::new(),
+        }
+    }
+
+    /// Returns the path of the data of the object `id`, first moving it
+    /// there if it is still in the other layout, so objects are migrated as
+    /// they are used.
+    async fn locate(&self, id: &str) -> io::Result<PathBuf> {
+        let path = self.layout.path(&self.data_dir, id);
+        let old_path = self.layout.other().path(&self.data_dir, id);
+
+        if move_file(&old_path, &path).await? {
+            tracing::info!(
+                target: "object_fs",
+                from = ?old_path,
+                to = ?path,
+                "moved file to the data layout",
+            );
+            remove_empty_shard(&self.data_dir, &old_path).await;
+        }
+        Ok(path)
+    }
+
+    /// Moves the data of all the objects to the configured layout, returning
+    /// how many were moved. Must not run along with stores, as they would
+    /// race to replace the data.
+    pub async fn migrate_layout(&self) -> io::Result<u64> {
+        let mut ids = Vec::new();
+        for_each_file(&self.data_dir, 2, |path, _| {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                return;
+            };
+            if Uuid::try_parse(name).is_ok()
+                && *path != self.layout.path(&self.data_dir, name)
+            {
+                ids.push(name.to_owned());
+            }
+        })
+        .await?;
+
+        let mut moved = 0;
+        for id in ids {
+            let old_path = self.layout.other().path(&self.data_dir, &id);
+            let path = self.layout.path(&self.data_dir, &id);
+
+            if move_file(&old_path, &path).await? {
+                remove_empty_shard(&self.data_dir, &old_path).await;
+                moved += 1;
+            }
         }
+        Ok(moved)
+    }
+
+    /// Creates a file with an unpredictable name for a single store attempt.
+    async fn create_temp_file(&self, id: &str) -> io::Result<(PathBuf, File)> {
+        let dir = self.temp_dir.join(INCOMPLETE_DIR);
+
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&dir).await?;
+
+        let mut suffix = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut suffix);
+        let path = dir.join(format!("{id}-{}", hex::encode(suffix)));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(&path).await?;
+
+        Ok((path, file))
+    }
+
+    /// Writes the incomplete objects of a batch store, pushing their paths
+    /// to `temp_paths` as they are created.
+    async fn write_batch(
+        &self,
+        objects: &[(Uuid, Bytes)],
+        temp_paths: &mut Vec<PathBuf>,
+    ) -> io::Result<Vec<(u64, [u8; 32])>> {
+        let mut written = Vec::with_capacity(objects.len());
+        for (id, data) in objects {
+            let (path, mut file) =
+                self.create_temp_file(&id.to_string()).await?;
+            temp_paths.push(path);
+
+            file.write_all(data).await?;
+            file.flush().await?;
+            written.push((data.len() as u64, Sha256::digest(data).into()));
+        }
+
+        // A single sync instead of one per object, where supported
+        let dir = self.temp_dir.join(INCOMPLETE_DIR);
+        let paths = temp_paths.clone();
+        tokio::task::spawn_blocking(move || {
+            match sync_filesystem(&std::fs::File::open(dir)?) {
+                Err(error) if error.kind() == ErrorKind::Unsupported => {
+                    paths.iter().try_for_each(|path| {
+                        std::fs::File::open(path)?.sync_data()
+                    })
+                }
+                res => res,
+            }
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        Ok(written)
+    }
+
+    /// Whether objects of `size` bytes are dropped from the page cache once
+    /// stored.
+    fn drops_cache(&self, size: u64) -> bool {
+        self.io.drop_cache_size != 0 && size >= self.io.drop_cache_size
+    }
+
+    /// Whether the incomplete object at `path` was left behind by a store
+    /// that is no longer running.
+    fn is_orphaned(&self, path: &Path) -> bool {
+        // Names are the object id followed by a random suffix
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.get(..36))
+            .and_then(|id| Uuid::try_parse(id).ok())
+            .is_none_or(|id| self.locks.try_lock(&id).is_some())
     }
 }
 
-impl ObjectManager {
+impl Manager for ObjectManager {
+    type Reader = ObjectReader;
+
     #[instrument(target = "object_fs", name = "store", skip(self, stream))]
-    pub async fn store(
+    async fn store(
         &self,
         id: Uuid,
-        stream: impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
+        stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+        checksum: Option<[u8; 32]>,
+        size: Option<u64>,
     ) -> Result<(u64, [u8; 32]), ObjectError> {
-        let mut stream = HashStream::<_, Sha256>::new(stream);
+        let mut stream = CoalesceStream::new(stream, self.io.coalesce_size);
 
         let start = Instant::now();
 
-        tracing::info!(target: "object_fs", "starting store");
+        // Concurrent stores of the same object would race to replace it
+        let _guard = self.locks.lock(&id).await;
 
-        let id = id.to_string();
-        let temp_dir = self.temp_dir.join(format!("{id}-incomplete"));
+        tracing::info!(target: "object_fs", "starting store");
 
-        let file = File::create(&temp_dir).await.inspect_err(|error| {
-            tracing::error!(
-                target: "object_fs",
-                %error,
-                path = ?temp_dir,
-                took = %fmt_since(start),
-                "create file failed",
-            );
-        })?;
+        let name = id.to_string();
+        let (temp_path, file) =
+            self.create_temp_file(&name).await.inspect_err(|error| {
+                tracing::error!(
+                    target: "object_fs",
+                    %error,
+                    took = %fmt_since(start),
+                    "create file failed",
+                );
+            })?;
+
+        let preallocated = match size.filter(|_| self.io.preallocate) {
+            Some(len) if len > 0 => match preallocate(&file, len) {
+                Ok(()) => Some(len),
+                Err(error) if error.kind() == ErrorKind::Unsupported => None,
+                Err(error) => {
+                    tracing::warn!(
+                        target: "object_fs",
+                        %error,
+                        len,
+                        "preallocate file failed",
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
 
-        let mut file = BufWriter::with_capacity(1024 * 1024, file);
+        let mut file =
+            BufWriter::with_capacity(self.io.write_buffer_size, file);
+
+        let hasher = ConcurrentHasher::<Sha256>::new();
+        let flush_interval = self.io.flush_interval;
+        let res =
+            match copy_impl(&mut stream, &mut file, hasher, flush_interval)
+                .await
+            {
+                // The preallocated space past the data would read as zeroes
+                Ok((size, hash))
+                    if preallocated.is_some_and(|len| size < len) =>
+                {
+                    file.get_ref().set_len(size).await.map(|()| (size, hash))
+                }
+                res => res,
+            };
+        let res = match res {
+            Ok((size, hash)) if self.drops_cache(size) => {
+                drop_cache(file.get_ref()).await.map(|()| (size, hash))
+            }
+            res => res,
+        };
+        drop(file);
 
-        let size = match copy_impl(&mut stream, &mut file).await {
+        let (size, hash) = match res {
             Ok(v) => v,
             Err(error) => {
                 tracing::warn!(
@@ -101,11 +506,11 @@ impl ObjectManager {
                     "interrupted by IO",
                 );
 
-                let _ = remove_file(&temp_dir).await.map_err(|error| {
+                let _ = remove_file(&temp_path).await.map_err(|error| {
                     tracing::error!(
                         target: "object_fs",
                         %error,
-                        path = ?temp_dir,
+                        path = ?temp_path,
                         took = %fmt_since(start),
                         "delete file after IO interruption failed",
                     );
@@ -115,9 +520,43 @@ impl ObjectManager {
             }
         };
 
-        let def_dir = self.data_dir.join(&id);
+        let hash: [u8; 32] = hash.into();
+
+        // Never replaces the object with data other than the expected one
+        if checksum.is_some_and(|checksum| checksum != hash) {
+            tracing::warn!(
+                target: "object_fs",
+                hash = %fmt_hex(&hash),
+                took = %fmt_since(start),
+                "checksum mismatch",
+            );
 
-        if let Err(error) = rename(&temp_dir, &def_dir).await {
+            let _ = remove_file(&temp_path).await.map_err(|error| {
+                tracing::error!(
+                    target: "object_fs",
+                    %error,
+                    path = ?temp_path,
+                    took = %fmt_since(start),
+                    "delete file after checksum mismatch failed",
+                );
+            });
+
+            return Err(ObjectError::ChecksumMismatch);
+        }
+
+        let res = match self.locate(&name).await {
+            Ok(path) => move_file(&temp_path, &path).await.and_then(|moved| {
+                // Only if the temp file was removed meanwhile
+                moved.then_some(()).ok_or(ErrorKind::NotFound.into())
+            }),
+            Err(error) => Err(error),
+        };
+
+        if let Some(cache) = &self.cache {
+            cache.remove(id);
+        }
+
+        if let Err(error) = res {
             tracing::error!(
                 target: "object_fs",
                 %error,
@@ -125,11 +564,11 @@ impl ObjectManager {
                 "move file failed",
             );
 
-            let _ = remove_file(&temp_dir).await.map_err(|error| {
+            let _ = remove_file(&temp_path).await.map_err(|error| {
                 tracing::error!(
                     target: "object_fs",
                     %error,
-                    path = ?temp_dir,
+                    path = ?temp_path,
                     took = %fmt_since(start),
                     "delete file after move failed",
                 );
@@ -138,8 +577,6 @@ impl ObjectManager {
             return Err(error.into());
         }
 
-        let hash: [u8; 32] = stream.hash_into();
-
         tracing::info!(
             target: "object_fs",
             took = %fmt_since(start),
@@ -151,19 +588,107 @@ impl ObjectManager {
         Ok((size, hash))
     }
 
-    #[instrument(target = "object_fs", name = "fetch", skip(self))]
-    pub async fn fetch(
+    #[instrument(
+        target = "object_fs",
+        name = "store_batch",
+        skip_all,
+        fields(count = objects.len()),
+    )]
+    async fn store_batch(
         &self,
-        id: Uuid,
-    ) -> Result<impl AsyncRead + Unpin, ObjectError> {
+        objects: Vec<(Uuid, Bytes)>,
+    ) -> Result<Vec<(u64, [u8; 32])>, ObjectError> {
+        let start = Instant::now();
+
+        // Locked in order, so concurrent batches can not deadlock
+        let mut ids: Vec<Uuid> = objects.iter().map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut _guards = Vec::with_capacity(ids.len());
+        for id in &ids {
+            _guards.push(self.locks.lock(id).await);
+        }
+
+        tracing::info!(target: "object_fs", "starting batch store");
+
+        let mut temp_paths = Vec::with_capacity(objects.len());
+        let written = match self.write_batch(&objects, &mut temp_paths).await {
+            Ok(written) => written,
+            Err(error) => {
+                tracing::warn!(
+                    target: "object_fs",
+                    %error,
+                    took = %fmt_since(start),
+                    "batch interrupted by IO",
+                );
+                remove_files(&temp_paths).await;
+                return Err(error.into());
+            }
+        };
+
+        let mut moved = Vec::with_capacity(objects.len());
+        for ((id, _), temp_path) in objects.iter().zip(&temp_paths) {
+            if let Some(cache) = &self.cache {
+                cache.remove(*id);
+            }
+
+            let res = match self.locate(&id.to_string()).await {
+                Ok(path) => {
+                    move_file(temp_path, &path).await.and_then(|ok| {
+                        // Only if the temp file was removed meanwhile
+                        ok.then_some(path).ok_or(ErrorKind::NotFound.into())
+                    })
+                }
+                Err(error) => Err(error),
+            };
+
+            match res {
+                Ok(path) => moved.push(path),
+                Err(error) => {
+                    tracing::error!(
+                        target: "object_fs",
+                        %error,
+                        took = %fmt_since(start),
+                        "move file of batch failed",
+                    );
+                    remove_files(&moved).await;
+                    remove_files(&temp_paths[moved.len()..]).await;
+                    return Err(error.into());
+                }
+            }
+        }
+
+        tracing::info!(
+            target: "object_fs",
+            took = %fmt_since(start),
+            written_bytes = written.iter().map(|(size, _)| size).sum::<u64>(),
+            "finished batch store",
+        );
+
+        Ok(written)
+    }
+
+    #[instrument(target = "object_fs", name = "fetch", skip(self))]
+    async fn fetch(&self, id: Uuid) -> Result<Self::Reader, ObjectError> {
         let start = Instant::now();
 
         tracing::info!(target: "object_fs", "starting fetch");
 
-        let id = id.to_string();
-        let path = self.data_dir.join(&id);
+        if let Some(data) = self.cache.as_ref().and_then(|c| c.get(id)) {
+            tracing::info!(
+                target: "object_fs",
+                took = %fmt_since(start),
+                "fetched cached data",
+            );
+            return Ok(ObjectReader::Cached(Cursor::new(data)));
+        }
+        // Held while reading the data to cache, so stores can not replace it
+        // before it is cached
+        let guard = self.cache.as_ref().and_then(|_| self.locks.try_lock(&id));
 
-        let file = File::open(&path).await.map_err(|error| {
+        let path = self.locate(&id.to_string()).await?;
+
+        let mut file = File::open(&path).await.map_err(|error| {
             if error.kind() == ErrorKind::NotFound {
                 ObjectError::NotFound
             } else {
@@ -195,6 +720,35 @@ impl ObjectManager {
 
         debug_assert_ne!(file_size, None);
 
+        if let (Some(cache), Some(size), Some(_guard)) =
+            (&self.cache, file_size, guard)
+        {
+            if cache.admits(size) {
+                let mut data = Vec::with_capacity(size as usize);
+                file.read_to_end(&mut data).await?;
+                let data = Bytes::from(data);
+                cache.insert(id, data.clone());
+
+                tracing::info!(
+                    target: "object_fs",
+                    took = %fmt_since(start),
+                    "fetched data to cache",
+                );
+                return Ok(ObjectReader::Cached(Cursor::new(data)));
+            }
+        }
+
+        if self.io.sequential_reads {
+            if let Err(error) = fadvise(&file, Advice::Sequential) {
+                tracing::warn!(
+                    target: "object_fs",
+                    %error,
+                    path = ?path,
+                    "advise sequential reads failed",
+                );
+            }
+        }
+
         tracing::info!(
             target: "object_fs",
             took = %fmt_since(start),
@@ -203,17 +757,46 @@ impl ObjectManager {
 
         let buf_cap = buffer_cap(file_size) as usize;
 
-        Ok(BufReader::with_capacity(buf_cap, file))
+        Ok(ObjectReader::File(BufReader::with_capacity(buf_cap, file)))
+    }
+
+    #[instrument(target = "object_fs", name = "fetch_range", skip(self))]
+    async fn fetch_range(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> Result<Take<Self::Reader>, ObjectError> {
+        let reader = match self.fetch(id).await? {
+            ObjectReader::File(reader) => {
+                let mut file = reader.into_inner();
+                file.seek(SeekFrom::Start(offset)).await?;
+
+                // Sized for the range rather than the whole object
+                let buf_cap = buffer_cap(Some(len)) as usize;
+                ObjectReader::File(BufReader::with_capacity(buf_cap, file))
+            }
+            ObjectReader::Cached(mut cursor) => {
+                cursor.set_position(offset);
+                ObjectReader::Cached(cursor)
+            }
+        };
+        Ok(reader.take(len))
     }
 
     #[instrument(target = "object_fs", name = "delete", skip(self))]
-    pub async fn delete(&self, id: Uuid) -> Result<(), ObjectError> {
+    async fn delete(&self, id: Uuid) -> Result<(), ObjectError> {
         let start = Instant::now();
 
         tracing::info!(target: "object_fs", "starting delete");
 
-        let id = id.to_string();
-        let path = self.data_dir.join(&id);
+        // Fetches must not cache the data while it is removed
+        let _guard = self.locks.lock(&id).await;
+        if let Some(cache) = &self.cache {
+            cache.remove(id);
+        }
+
+        let path = self.locate(&id.to_string()).await?;
 
         remove_file(&path).await.map_err(|error| {
             tracing::error!(
@@ -232,6 +815,238 @@ impl ObjectManager {
 
         Ok(())
     }
+
+    #[instrument(target = "object_fs", name = "snapshot", skip(self))]
+    async fn snapshot(&self, id: Uuid) -> Result<(), ObjectError> {
+        let dir = self.temp_dir.join(SNAPSHOT_DIR);
+
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&dir).await?;
+
+        let id = id.to_string();
+        let path = dir.join(&id);
+
+        // Left behind by a crash, older than the current data
+        match remove_file(&path).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                return Err(error.into());
+            }
+            _ => {}
+        }
+
+        hard_link(self.locate(&id).await?, &path)
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    target: "object_fs",
+                    %error,
+                    path = ?path,
+                    "link snapshot failed",
+                );
+                if error.kind() == ErrorKind::NotFound {
+                    ObjectError::NotFound
+                } else {
+                    ObjectError::IoError(error)
+                }
+            })
+    }
+
+    #[instrument(target = "object_fs", name = "rollback", skip(self))]
+    async fn rollback(&self, id: Uuid) -> Result<(), ObjectError> {
+        let _guard = self.locks.lock(&id).await;
+        if let Some(cache) = &self.cache {
+            cache.remove(id);
+        }
+
+        let name = id.to_string();
+        let path = self.temp_dir.join(SNAPSHOT_DIR).join(&name);
+
+        rename(&path, self.layout.path(&self.data_dir, &name))
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    target: "object_fs",
+                    %error,
+                    path = ?path,
+                    "restore snapshot failed",
+                );
+                if error.kind() == ErrorKind::NotFound {
+                    ObjectError::NotFound
+                } else {
+                    ObjectError::IoError(error)
+                }
+            })
+    }
+
+    #[instrument(target = "object_fs", name = "release", skip(self))]
+    async fn release(&self, id: Uuid) -> Result<(), ObjectError> {
+        let path = self.temp_dir.join(SNAPSHOT_DIR).join(id.to_string());
+
+        match remove_file(&path).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                tracing::error!(
+                    target: "object_fs",
+                    %error,
+                    path = ?path,
+                    "delete snapshot failed",
+                );
+                Err(error.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(target = "object_fs", name = "sweep", skip(self))]
+    async fn sweep(&self, max_age: Duration) -> Result<DirUsage, ObjectError> {
+        let dir = self.temp_dir.join(INCOMPLETE_DIR);
+
+        let mut expired = Vec::new();
+        let res = for_each_file(&dir, 0, |path, meta| {
+            let old = meta
+                .modified()
+                .is_ok_and(|t| t.elapsed().is_ok_and(|age| age >= max_age));
+            if old && self.is_orphaned(path) {
+                expired.push((path.to_owned(), meta.len()));
+            }
+        })
+        .await;
+        match res {
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            res => res?,
+        }
+
+        let mut swept = DirUsage::default();
+        for (path, len) in expired {
+            match remove_file(&path).await {
+                Ok(()) => swept.add(len),
+                // Removed meanwhile by the store that left it behind
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => {
+                    tracing::error!(
+                        target: "object_fs",
+                        %error,
+                        path = ?path,
+                        "delete incomplete file failed",
+                    );
+                }
+            }
+        }
+
+        Ok(swept)
+    }
+
+    #[instrument(target = "object_fs", name = "usage", skip(self))]
+    async fn usage(&self) -> Result<StorageUsage, ObjectError> {
+        let mut usage = StorageUsage {
+            data_cache: self.cache.as_ref().map(DataCache::usage),
+            ..Default::default()
+        };
+
+        // Both layouts, as objects are migrated lazily
+        for_each_file(&self.data_dir, 2, |_, meta| usage.data.add(meta.len()))
+            .await?;
+
+        let incomplete = self.temp_dir.join(INCOMPLETE_DIR);
+        let res = for_each_file(&incomplete, 0, |path, meta| {
+            usage.incomplete.add(meta.len());
+
+            if self.is_orphaned(path) {
+                usage.orphaned.add(meta.len());
+            }
+
+            if let Ok(modified) = meta.modified() {
+                let modified = DateTime::<Utc>::from(modified);
+                if usage.oldest_incomplete.is_none_or(|t| modified < t) {
+                    usage.oldest_incomplete = Some(modified);
+                }
+            }
+        })
+        .await;
+
+        match res {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                Err(error.into())
+            }
+            _ => Ok(usage),
+        }
+    }
+}
+
+/// Removes the files left behind by a failed store, logging the failures.
+async fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = remove_file(path).await.map_err(|error| {
+            tracing::error!(
+                target: "object_fs",
+                %error,
+                path = ?path,
+                "delete file after failed store failed",
+            );
+        });
+    }
+}
+
+/// Writes the data of the file to disk and drops it from the page cache.
+async fn drop_cache(file: &File) -> io::Result<()> {
+    // Dirty pages are not dropped
+    file.sync_data().await?;
+    fadvise(file, Advice::DontNeed)
+}
+
+/// Calls `f` with the path of every file in `dir` and in its subdirectories
+/// up to `max_depth` levels deep.
+async fn for_each_file(
+    dir: &Path,
+    max_depth: usize,
+    mut f: impl FnMut(&Path, &std::fs::Metadata),
+) -> io::Result<()> {
+    let mut dirs = vec![(dir.to_owned(), 0)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut entries = read_dir(&dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if meta.is_file() {
+                f(&entry.path(), &meta);
+            } else if meta.is_dir() && depth < max_depth {
+                dirs.push((entry.path(), depth + 1));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the file `from` to `to`, creating the parent dirs of `to` if
+/// missing. Returns false if there is no file at `from`.
+async fn move_file(from: &Path, to: &Path) -> io::Result<bool> {
+    match rename(from, to).await {
+        Ok(()) => return Ok(true),
+        Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+        Err(_) => {}
+    }
+    if !try_exists(from).await? {
+        return Ok(false);
+    }
+
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent).await?;
+    }
+    rename(from, to).await.map(|()| true)
+}
+
+/// Removes the shard dirs of the file moved out of `path` once empty.
+async fn remove_empty_shard(data_dir: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        // Fails while the dir has other objects
+        if dir == data_dir || remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
 }
 
 #[inline]
@@ -253,27 +1068,43 @@ const fn buffer_cap(file_size: Option<u64>) -> u64 {
     }
 }
 
-pub(super) async fn copy_impl<S, W>(
+/// Writes the stream while `hasher` hashes it on another thread, returning
+/// the written size and its hash. The writer is flushed at least every
+/// `flush_interval` as data arrives, unless zero.
+pub(super) async fn copy_impl<S, W, H>(
     stream: &mut S,
     writer: &mut W,
-) -> io::Result<u64>
+    mut hasher: ConcurrentHasher<H>,
+    flush_interval: Duration,
+) -> io::Result<(u64, Output<H>)>
 where
     S: Stream<Item = Result<Bytes, io::Error>> + Unpin,
     W: AsyncWrite + Unpin,
+    H: Digest + Send + 'static,
 {
     let mut n = 0;
+    let mut flushed_at = Instant::now();
     while let Some(res) = stream.next().await {
         match res {
             Ok(v) => {
+                // Cheap, as bytes are reference counted
+                hasher.update(v.clone()).await;
                 writer.write_all(&v).await?;
                 n += v.len();
+
+                if !flush_interval.is_zero()
+                    && flushed_at.elapsed() >= flush_interval
+                {
+                    writer.flush().await?;
+                    flushed_at = Instant::now();
+                }
             }
             Err(err) => return Err(err),
         }
     }
 
     writer.flush().await?;
-    Ok(n as u64)
+    Ok((n as u64, hasher.finish().await?))
 }
 
 #[cfg(test)]
@@ -308,6 +1139,10 @@ mod tests {
             ObjectManager {
                 data_dir: data_dir.path().to_owned(),
                 temp_dir: temp_dir.path().to_owned(),
+                layout: DataLayout::Flat,
+                io: IoConfig::default(),
+                cache: None,
+                locks: KeyedLock::new(),
             },
             TempHolder { data_dir, temp_dir },
         )
@@ -318,7 +1153,7 @@ mod tests {
         holder: &TempHolder,
         size: usize,
     ) -> (
-        impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
+        impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
         [u8; 32],
     ) {
         // Intentionally not 1024 * 1024
@@ -335,7 +1170,7 @@ mod tests {
             thread_rng.fill_bytes(&mut buf);
             hash.update(&buf);
 
-            file.write(&buf).unwrap();
+            file.write_all(&buf).unwrap();
         }
 
         let file = File::open(path).await.unwrap();
@@ -352,7 +1187,8 @@ mod tests {
 
         let (reader, reader_hash) = create_rand_file(&holder, SIZE).await;
         let id = Uuid::new_v4();
-        let (written, store_hash) = repo.store(id, reader).await.unwrap();
+        let (written, store_hash) =
+            repo.store(id, reader, None, None).await.unwrap();
 
         assert!(
             reader_hash.iter().eq(store_hash.iter()),
@@ -383,6 +1219,191 @@ mod tests {
         );
     }
 
+    #[test(tokio::test)]
+    async fn test_store_checksum() {
+        let (repo, holder) = repository();
+        let id = Uuid::new_v4();
+
+        let (reader, hash) = create_rand_file(&holder, 1).await;
+        repo.store(id, reader, Some(hash), None).await.unwrap();
+
+        let (reader, _) = create_rand_file(&holder, 1).await;
+        let res = repo.store(id, reader, Some(hash), None).await;
+        assert!(matches!(res, Err(ObjectError::ChecksumMismatch)));
+
+        // The previous data is kept, and the rejected one is not left behind
+        let mut reader =
+            HashRead::<_, Sha256>::new(repo.fetch(id).await.unwrap());
+        copy(&mut reader, &mut tokio::io::sink()).await.unwrap();
+        assert_eq!(reader.hash_into::<[u8; 32]>(), hash);
+        let incomplete = holder.temp_dir.path().join(INCOMPLETE_DIR);
+        assert_eq!(std::fs::read_dir(&incomplete).unwrap().count(), 0);
+    }
+
+    #[test(tokio::test)]
+    async fn test_store_size_hint() {
+        let (mut repo, holder) = repository();
+        repo.io.drop_cache_size = 1;
+        let id = Uuid::new_v4();
+        let path = holder.data_dir.path().join(id.to_string());
+
+        // Wrong hints only cost an extra resize
+        for hint in [None, Some(1000 * 1000), Some(500), Some(4000 * 1000)] {
+            let (reader, hash) = create_rand_file(&holder, 1).await;
+            let stored = repo.store(id, reader, None, hint).await.unwrap();
+            assert_eq!(stored, (1000 * 1000, hash));
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 1000 * 1000);
+
+            let mut reader =
+                HashRead::<_, Sha256>::new(repo.fetch(id).await.unwrap());
+            copy(&mut reader, &mut tokio::io::sink()).await.unwrap();
+            assert_eq!(reader.hash_into::<[u8; 32]>(), hash);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_store_batch() {
+        let (repo, holder) = repository();
+        let objects: Vec<(Uuid, Bytes)> = (0..10)
+            .map(|i| (Uuid::new_v4(), Bytes::from(vec![i as u8; i * 100])))
+            .collect();
+
+        let stored = repo.store_batch(objects.clone()).await.unwrap();
+        assert_eq!(stored.len(), objects.len());
+
+        for ((id, data), (size, hash)) in objects.iter().zip(stored) {
+            assert_eq!(size, data.len() as u64);
+            assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(data)));
+
+            let mut buf = Vec::new();
+            let mut reader = repo.fetch(*id).await.unwrap();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, data.as_ref());
+        }
+
+        let incomplete = holder.temp_dir.path().join(INCOMPLETE_DIR);
+        assert_eq!(std::fs::read_dir(incomplete).unwrap().count(), 0);
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_range() {
+        let (repo, _holder) = repository();
+        let id = Uuid::new_v4();
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let stream =
+            futures_util::stream::iter([Ok(Bytes::from(data.clone()))]);
+        repo.store(id, stream, None, None).await.unwrap();
+
+        for (offset, len, range) in [
+            (0, 10, 0..10),
+            (4000, 1000, 4000..5000),
+            (9990, 100, 9990..10_000),
+            (20_000, 100, 0..0),
+        ] {
+            let mut buf = Vec::new();
+            let mut reader = repo.fetch_range(id, offset, len).await.unwrap();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, data[range]);
+        }
+
+        assert!(matches!(
+            repo.fetch_range(Uuid::new_v4(), 0, 1).await,
+            Err(ObjectError::NotFound),
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn test_data_cache() {
+        let (mut repo, _holder) = repository();
+        repo.cache = Some(DataCache::new(&crate::config::DataCacheConfig {
+            max_size: 1024,
+            max_object_size: 100,
+        }));
+        let id = Uuid::new_v4();
+
+        let read = |reader: ObjectReader| async {
+            let mut buf = Vec::new();
+            let mut reader = reader;
+            reader.read_to_end(&mut buf).await.unwrap();
+            (matches!(reader, ObjectReader::Cached(..)), buf)
+        };
+        let store = |data: &'static [u8]| {
+            let stream = futures_util::stream::iter([Ok(Bytes::from(data))]);
+            repo.store(id, stream, None, None)
+        };
+
+        store(b"first").await.unwrap();
+        assert_eq!(
+            read(repo.fetch(id).await.unwrap()).await,
+            (true, b"first".into())
+        );
+        assert_eq!(
+            read(repo.fetch(id).await.unwrap()).await,
+            (true, b"first".into())
+        );
+        let range = repo.fetch_range(id, 1, 3).await.unwrap().into_inner();
+        assert_eq!(read(range).await.1, b"irst");
+
+        // Invalidated by stores and deletes
+        store(b"second").await.unwrap();
+        assert_eq!(read(repo.fetch(id).await.unwrap()).await.1, b"second");
+        repo.delete(id).await.unwrap();
+        assert!(matches!(repo.fetch(id).await, Err(ObjectError::NotFound)));
+
+        let other = Uuid::new_v4();
+        let stream =
+            futures_util::stream::iter([Ok(Bytes::from(vec![0; 101]))]);
+        repo.store(other, stream, None, None).await.unwrap();
+        assert!(!read(repo.fetch(other).await.unwrap()).await.0);
+
+        let usage = repo.usage().await.unwrap().data_cache.unwrap();
+        assert_eq!((usage.entries, usage.bytes), (0, 0));
+        assert_eq!((usage.hits, usage.misses), (2, 2));
+    }
+
+    #[test(tokio::test)]
+    async fn test_snapshot() {
+        let (repo, holder) = repository();
+        let id = Uuid::new_v4();
+
+        let fetch_hash = || async {
+            let mut reader =
+                HashRead::<_, Sha256>::new(repo.fetch(id).await.unwrap());
+            copy(&mut reader, &mut tokio::io::sink()).await.unwrap();
+            reader.hash_into::<[u8; 32]>()
+        };
+
+        assert!(matches!(
+            repo.snapshot(id).await,
+            Err(ObjectError::NotFound),
+        ));
+
+        let (reader, hash_a) = create_rand_file(&holder, 1).await;
+        repo.store(id, reader, None, None).await.unwrap();
+
+        repo.snapshot(id).await.unwrap();
+        let (reader, hash_b) = create_rand_file(&holder, 1).await;
+        repo.store(id, reader, None, None).await.unwrap();
+        assert_eq!(fetch_hash().await, hash_b);
+        repo.rollback(id).await.unwrap();
+        assert_eq!(fetch_hash().await, hash_a);
+
+        repo.snapshot(id).await.unwrap();
+        let (reader, hash_c) = create_rand_file(&holder, 1).await;
+        repo.store(id, reader, None, None).await.unwrap();
+        repo.release(id).await.unwrap();
+        assert_eq!(fetch_hash().await, hash_c);
+        assert!(matches!(
+            repo.rollback(id).await,
+            Err(ObjectError::NotFound)
+        ));
+        repo.release(id).await.unwrap();
+
+        let snapshots = holder.temp_dir.path().join(SNAPSHOT_DIR);
+        assert_eq!(std::fs::read_dir(&snapshots).unwrap().count(), 0);
+    }
+
     #[test(tokio::test)]
     async fn test_delete() {
         const SIZE: usize = 1;
@@ -398,7 +1419,7 @@ mod tests {
         );
 
         let (reader, _) = create_rand_file(&holder, SIZE).await;
-        repo.store(id, reader).await.unwrap();
+        repo.store(id, reader, None, None).await.unwrap();
 
         repo.fetch(id).await.expect("could not fetch created file");
         repo.delete(id)
@@ -411,4 +1432,171 @@ mod tests {
             "expected ObjectError::NotFound for deleted file",
         );
     }
+
+    #[test(tokio::test)]
+    async fn test_store_concurrent() {
+        let (repo, holder) = repository();
+        let id = Uuid::new_v4();
+
+        let (reader_a, hash_a) = create_rand_file(&holder, 2).await;
+        let (reader_b, hash_b) = create_rand_file(&holder, 2).await;
+
+        let (res_a, res_b) = tokio::join!(
+            repo.store(id, reader_a, None, None),
+            repo.store(id, reader_b, None, None)
+        );
+        assert_eq!(res_a.unwrap().1, hash_a);
+        assert_eq!(res_b.unwrap().1, hash_b);
+
+        let mut reader =
+            HashRead::<_, Sha256>::new(repo.fetch(id).await.unwrap());
+        let mut dev_null = File::from_std(tempfile::tempfile().unwrap());
+        copy(&mut reader, &mut dev_null).await.unwrap();
+        let fetch_hash: [u8; 32] = reader.hash_into();
+        assert!(
+            fetch_hash == hash_a || fetch_hash == hash_b,
+            "concurrent stores interleaved their data",
+        );
+
+        let incomplete = holder.temp_dir.path().join(INCOMPLETE_DIR);
+        assert_eq!(std::fs::read_dir(&incomplete).unwrap().count(), 0);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode =
+                std::fs::metadata(&incomplete).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700, "temp dir is accessible by others");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_usage() {
+        let (repo, holder) = repository();
+
+        let usage = repo.usage().await.unwrap();
+        assert_eq!(usage, StorageUsage::default());
+
+        let (reader, _) = create_rand_file(&holder, 1).await;
+        let (size, _) = repo
+            .store(Uuid::new_v4(), reader, None, None)
+            .await
+            .unwrap();
+
+        let orphan_id = Uuid::new_v4();
+        let (path, _) =
+            repo.create_temp_file(&orphan_id.to_string()).await.unwrap();
+        std::fs::write(&path, b"orphan").unwrap();
+
+        let running_id = Uuid::new_v4();
+        let _guard = repo.locks.lock(&running_id).await;
+        let (path, _) = repo
+            .create_temp_file(&running_id.to_string())
+            .await
+            .unwrap();
+        std::fs::write(&path, b"running").unwrap();
+
+        let usage = repo.usage().await.unwrap();
+        assert_eq!(
+            usage.data,
+            DirUsage {
+                files: 1,
+                bytes: size
+            }
+        );
+        assert_eq!(
+            usage.incomplete,
+            DirUsage {
+                files: 2,
+                bytes: 13
+            }
+        );
+        assert_eq!(usage.orphaned, DirUsage { files: 1, bytes: 6 });
+        assert!(usage.oldest_incomplete.is_some());
+    }
+
+    #[test(tokio::test)]
+    async fn test_sweep() {
+        let (repo, holder) = repository();
+
+        assert_eq!(
+            repo.sweep(Duration::ZERO).await.unwrap(),
+            DirUsage::default(),
+        );
+
+        let (reader, _) = create_rand_file(&holder, 1).await;
+        let id = Uuid::new_v4();
+        repo.store(id, reader, None, None).await.unwrap();
+
+        let (orphan, _) = repo
+            .create_temp_file(&Uuid::new_v4().to_string())
+            .await
+            .unwrap();
+        std::fs::write(&orphan, b"orphan").unwrap();
+
+        let running_id = Uuid::new_v4();
+        let _guard = repo.locks.lock(&running_id).await;
+        let (running, _) = repo
+            .create_temp_file(&running_id.to_string())
+            .await
+            .unwrap();
+        std::fs::write(&running, b"running").unwrap();
+
+        // Too recent to be swept
+        let swept = repo.sweep(Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(swept, DirUsage::default());
+        assert!(orphan.exists());
+
+        let swept = repo.sweep(Duration::ZERO).await.unwrap();
+        assert_eq!(swept, DirUsage { files: 1, bytes: 6 });
+        assert!(!orphan.exists());
+        assert!(running.exists());
+        repo.fetch(id).await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_data_layout() {
+        let (mut repo, holder) = repository();
+        let data_dir = holder.data_dir.path();
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let (reader, _) = create_rand_file(&holder, 1).await;
+            let id = Uuid::new_v4();
+            repo.store(id, reader, None, None).await.unwrap();
+            ids.push(id.to_string());
+        }
+        assert!(data_dir.join(&ids[0]).is_file());
+
+        // Moved lazily when used
+        repo.layout = DataLayout::Sharded;
+        let sharded =
+            |id: &str| data_dir.join(&id[..2]).join(&id[2..4]).join(id);
+        repo.fetch(Uuid::parse_str(&ids[0]).unwrap()).await.unwrap();
+        assert!(sharded(&ids[0]).is_file());
+        assert!(!data_dir.join(&ids[0]).exists());
+        assert_eq!(repo.usage().await.unwrap().data.files, 3);
+
+        assert_eq!(repo.migrate_layout().await.unwrap(), 2);
+        for id in &ids {
+            assert!(sharded(id).is_file());
+        }
+        assert_eq!(repo.migrate_layout().await.unwrap(), 0);
+
+        let (reader, hash) = create_rand_file(&holder, 1).await;
+        let id = Uuid::new_v4();
+        repo.store(id, reader, None, None).await.unwrap();
+        let mut reader =
+            HashRead::<_, Sha256>::new(repo.fetch(id).await.unwrap());
+        copy(&mut reader, &mut tokio::io::sink()).await.unwrap();
+        assert_eq!(reader.hash_into::<[u8; 32]>(), hash);
+        assert!(sharded(&id.to_string()).is_file());
+
+        // And back, leaving no empty shard dirs behind
+        repo.layout = DataLayout::Flat;
+        assert_eq!(repo.migrate_layout().await.unwrap(), 4);
+        let entries = std::fs::read_dir(data_dir).unwrap();
+        assert!(entries.map(|e| e.unwrap()).all(|e| e.path().is_file()));
+        assert_eq!(repo.usage().await.unwrap().data.files, 4);
+    }
 }
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    sync::Mutex,
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, Take};
+use uuid::Uuid;
+
+use super::manager::{DirUsage, Manager, ObjectError, StorageUsage};
+
+#[derive(Default)]
+struct Objects {
+    data: HashMap<Uuid, Bytes>,
+    snapshots: HashMap<Uuid, Bytes>,
+}
+
+/// A [`Manager`] keeping the object data in memory, lost on restart. Meant
+/// for tests and benchmarks, where the filesystem would get in the way.
+#[derive(Default)]
+pub struct MemoryManager {
+    objects: Mutex<Objects>,
+}
+
+impl MemoryManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Manager for MemoryManager {
+    type Reader = Cursor<Bytes>;
+
+    async fn store(
+        &self,
+        id: Uuid,
+        mut stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+        checksum: Option<[u8; 32]>,
+        size: Option<u64>,
+    ) -> Result<(u64, [u8; 32]), ObjectError> {
+        let mut data = BytesMut::with_capacity(
+            size.map_or(0, |size| size.min(1024 * 1024) as usize),
+        );
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            data.extend_from_slice(&chunk);
+        }
+
+        let hash: [u8; 32] = hasher.finalize().into();
+        if checksum.is_some_and(|checksum| checksum != hash) {
+            return Err(ObjectError::ChecksumMismatch);
+        }
+
+        let size = data.len() as u64;
+        self.objects.lock().unwrap().data.insert(id, data.freeze());
+        Ok((size, hash))
+    }
+
+    async fn store_batch(
+        &self,
+        objects: Vec<(Uuid, Bytes)>,
+    ) -> Result<Vec<(u64, [u8; 32])>, ObjectError> {
+        let written = objects
+            .iter()
+            .map(|(_, data)| (data.len() as u64, Sha256::digest(data).into()))
+            .collect();
+        self.objects.lock().unwrap().data.extend(objects);
+        Ok(written)
+    }
+
+    async fn fetch(&self, id: Uuid) -> Result<Self::Reader, ObjectError> {
+        let objects = self.objects.lock().unwrap();
+        let data = objects.data.get(&id).ok_or(ObjectError::NotFound)?;
+        Ok(Cursor::new(data.clone()))
+    }
+
+    async fn fetch_range(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> Result<Take<Self::Reader>, ObjectError> {
+        let mut reader = self.fetch(id).await?;
+        reader.set_position(offset);
+        Ok(reader.take(len))
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<(), ObjectError> {
+        let mut objects = self.objects.lock().unwrap();
+        objects.data.remove(&id).ok_or(ObjectError::NotFound)?;
+        Ok(())
+    }
+
+    async fn snapshot(&self, id: Uuid) -> Result<(), ObjectError> {
+        let mut objects = self.objects.lock().unwrap();
+        let data = objects.data.get(&id).ok_or(ObjectError::NotFound)?;
+        let data = data.clone();
+        objects.snapshots.insert(id, data);
+        Ok(())
+    }
+
+    async fn rollback(&self, id: Uuid) -> Result<(), ObjectError> {
+        let mut objects = self.objects.lock().unwrap();
+        let data =
+            objects.snapshots.remove(&id).ok_or(ObjectError::NotFound)?;
+        objects.data.insert(id, data);
+        Ok(())
+    }
+
+    async fn release(&self, id: Uuid) -> Result<(), ObjectError> {
+        self.objects.lock().unwrap().snapshots.remove(&id);
+        Ok(())
+    }
+
+    /// Stores are never left incomplete, as they are only kept once done.
+    async fn sweep(&self, _max_age: Duration) -> Result<DirUsage, ObjectError> {
+        Ok(DirUsage::default())
+    }
+
+    async fn usage(&self) -> Result<StorageUsage, ObjectError> {
+        let objects = self.objects.lock().unwrap();
+        let data = DirUsage {
+            files: objects.data.len() as u64,
+            bytes: objects.data.values().map(|d| d.len() as u64).sum(),
+        };
+        Ok(StorageUsage {
+            data,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+
+    async fn read(manager: &MemoryManager, id: Uuid) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut reader = manager.fetch(id).await.unwrap();
+        reader.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    fn chunks(
+        data: &'static [u8],
+    ) -> impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin {
+        stream::iter(data.chunks(3).map(|c| Ok(Bytes::from_static(c))))
+    }
+
+    #[tokio::test]
+    async fn test_memory_manager() {
+        let manager = MemoryManager::new();
+        let id = Uuid::new_v4();
+
+        let (size, hash) = manager
+            .store(id, chunks(b"hello world"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(b"hello world")));
+        assert_eq!(read(&manager, id).await, b"hello world");
+
+        let mut buf = Vec::new();
+        let mut range = manager.fetch_range(id, 6, 3).await.unwrap();
+        range.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"wor");
+
+        // Mismatching data never replaces the object
+        let res = manager.store(id, chunks(b"other"), Some(hash), None).await;
+        assert!(matches!(res, Err(ObjectError::ChecksumMismatch)));
+        assert_eq!(read(&manager, id).await, b"hello world");
+
+        manager.snapshot(id).await.unwrap();
+        manager
+            .store(id, chunks(b"other"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(read(&manager, id).await, b"other");
+        manager.rollback(id).await.unwrap();
+        assert_eq!(read(&manager, id).await, b"hello world");
+
+        let usage = manager.usage().await.unwrap();
+        assert_eq!((usage.data.files, usage.data.bytes), (1, 11));
+
+        manager.delete(id).await.unwrap();
+        assert!(matches!(
+            manager.fetch(id).await,
+            Err(ObjectError::NotFound),
+        ));
+        assert!(matches!(
+            manager.delete(id).await,
+            Err(ObjectError::NotFound),
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use axum::http::StatusCode;
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use uuid::Uuid;
+
+use crate::{errors::DownloaderError, utils::retry::retry_busy};
+
+use super::repository::RepositoryError;
+
+pub const MAX_DESCRIPTION_LEN: usize = 4096;
+pub const MAX_METADATA_ENTRIES: usize = 64;
+pub const MAX_METADATA_KEY_LEN: usize = 128;
+pub const MAX_METADATA_VALUE_LEN: usize = 1024;
+
+/// Custom key-value metadata of objects.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Fails unless the description fits in [`MAX_DESCRIPTION_LEN`] bytes.
+pub fn validate_description(description: &str) -> Result<(), DownloaderError> {
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return Err(DownloaderError::Other(
+            format!(
+                "descriptions can not be longer than \
+                {MAX_DESCRIPTION_LEN} bytes",
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if the metadata has too many entries, or entries with empty or too
+/// long keys or too long values.
+pub fn validate_metadata(metadata: &Metadata) -> Result<(), DownloaderError> {
+    let error =
+        |msg: String| Err(DownloaderError::Other(msg, StatusCode::BAD_REQUEST));
+
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return error(format!(
+            "metadata can not have more than {MAX_METADATA_ENTRIES} entries",
+        ));
+    }
+    for (key, value) in metadata {
+        if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN {
+            return error(format!(
+                "metadata keys must have 1 to {MAX_METADATA_KEY_LEN} bytes",
+            ));
+        }
+        if value.len() > MAX_METADATA_VALUE_LEN {
+            return error(format!(
+                "the value of the metadata key `{key}` is longer than \
+                {MAX_METADATA_VALUE_LEN} bytes",
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub struct MetaRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for MetaRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> MetaRepository<DB> {
+    pub fn new(db: Pool<DB>) -> MetaRepository<DB> {
+        MetaRepository { db }
+    }
+}
+
+impl<DB> MetaRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> (String, String): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<Metadata, RepositoryError> {
+        let entries: Vec<(String, String)> = sqlx::query_as(
+            "SELECT key, value FROM object_meta WHERE object_id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Replaces all the metadata of the object `id`.
+    pub async fn set(
+        &self,
+        id: Uuid,
+        metadata: &Metadata,
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.set_once(id, metadata))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn set_once(
+        &self,
+        id: Uuid,
+        metadata: &Metadata,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        replace_metadata::<DB>(&mut tx, id, metadata).await?;
+        tx.commit().await
+    }
+}
+
+/// Replaces all the metadata of the object `id` through `conn`, so it can
+/// be part of a larger transaction.
+pub(super) async fn replace_metadata<DB>(
+    conn: &mut DB::Connection,
+    id: Uuid,
+    metadata: &Metadata,
+) -> Result<(), sqlx::Error>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    let id_bytes = id.into_bytes();
+
+    sqlx::query("DELETE FROM object_meta WHERE object_id = $1")
+        .bind(id_bytes.as_slice())
+        .execute(&mut *conn)
+        .await?;
+
+    for (key, value) in metadata {
+        sqlx::query(
+            "INSERT INTO object_meta (object_id, key, value) \
+            VALUES ($1, $2, $3)",
+        )
+        .bind(id_bytes.as_slice())
+        .bind(key.as_str())
+        .bind(value.as_str())
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object metadata");
+    RepositoryError::Sqlx(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_metadata() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = MetaRepository::new(db);
+        let (id, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(repo.get(id).await.unwrap().is_empty());
+
+        let metadata = Metadata::from([
+            ("author".into(), "fox".into()),
+            ("project".into(), "downloader".into()),
+        ]);
+        repo.set(id, &metadata).await.unwrap();
+        repo.set(other, &metadata).await.unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), metadata);
+
+        let metadata = Metadata::from([("author".into(), "dog".into())]);
+        repo.set(id, &metadata).await.unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), metadata);
+        assert_eq!(repo.get(other).await.unwrap().len(), 2);
+
+        repo.set(id, &Metadata::new()).await.unwrap();
+        assert!(repo.get(id).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_metadata() {
+        let valid = Metadata::from([("a".into(), "b".repeat(1024))]);
+        validate_metadata(&valid).unwrap();
+
+        let too_many = (0..65).map(|i| (i.to_string(), String::new()));
+        for metadata in [
+            Metadata::from([(String::new(), "b".into())]),
+            Metadata::from([("a".repeat(129), "b".into())]),
+            Metadata::from([("a".into(), "b".repeat(1025))]),
+            too_many.collect(),
+        ] {
+            let err = validate_metadata(&metadata).unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        }
+
+        validate_description(&"a".repeat(MAX_DESCRIPTION_LEN)).unwrap();
+        validate_description(&"a".repeat(MAX_DESCRIPTION_LEN + 1)).unwrap_err();
+    }
+}
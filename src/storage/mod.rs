@@ -1,11 +1,244 @@
-use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderValue},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use manager::ObjectError;
 use serde::{Deserialize, Serialize};
 use sqlx::{ColumnIndex, Decode, FromRow, Row, Type};
+use tokio::sync::OwnedMutexGuard;
 use uuid::Uuid;
 
+use crate::{
+    config::StorageConfig,
+    errors::DownloaderError,
+    namespace::default_namespace,
+    utils::{lock::KeyedLock, stream::DeadlineStream},
+};
+
+pub mod backend;
+pub mod cache;
+pub mod data_cache;
+pub mod embargo;
+pub mod exif;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod faulty;
+pub mod fetch;
+pub mod fetch_job;
+pub mod fetch_quota;
+pub mod history;
+pub mod intent;
 pub mod manager;
+pub mod memory;
+pub mod meta;
+pub mod name;
+pub mod pipeline;
+pub mod preview;
+pub mod progress;
+pub mod provenance;
 pub mod repository;
 pub mod routes;
+pub mod scan;
+pub mod share;
+pub mod slots;
+pub mod slug;
+pub mod stats;
+pub mod sweep;
+pub mod trailer;
+pub mod trash;
+pub mod ws;
+
+/// Bounds how long and how slowly clients can upload object data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadLimits {
+    pub timeout: Duration,
+    pub min_rate: u64,
+    pub rate_window: Duration,
+}
+
+impl UploadLimits {
+    pub fn new(cfg: &StorageConfig) -> Self {
+        Self {
+            timeout: cfg.upload_timeout,
+            min_rate: cfg.upload_min_rate,
+            rate_window: cfg.upload_rate_window,
+        }
+    }
+
+    #[inline]
+    pub fn apply<S>(&self, stream: S) -> DeadlineStream<S> {
+        DeadlineStream::new(
+            stream,
+            self.timeout,
+            self.min_rate,
+            self.rate_window,
+        )
+    }
+}
+
+/// How long deleted objects can be restored, disabled when zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndeleteWindow(pub Duration);
+
+impl UndeleteWindow {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.0.is_zero()
+    }
+
+    /// Objects deleted before this instant can no longer be restored.
+    pub fn cutoff(&self) -> DateTime<Utc> {
+        TimeDelta::from_std(self.0)
+            .ok()
+            .and_then(|window| Utc::now().checked_sub_signed(window))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+}
+
+/// Header with the sha256 checksum uploaded data must match, in hex.
+pub const CONTENT_SHA256_HEADER: &str = "x-content-sha256";
+
+pub fn parse_checksum(checksum: &str) -> Result<[u8; 32], ObjectError> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(checksum, &mut bytes)
+        .map_err(|_| ObjectError::InvalidChecksum)?;
+    Ok(bytes)
+}
+
+/// RFC 9530 header with the digest of the data of a message, before any
+/// content coding.
+pub const REPR_DIGEST_HEADER: &str = "repr-digest";
+/// RFC 9530 header with the digest of the content of a message. Requests
+/// are decompressed before their checksum is checked, so for uploads it
+/// must be of the uncompressed data, like the [`REPR_DIGEST_HEADER`].
+pub const CONTENT_DIGEST_HEADER: &str = "content-digest";
+
+/// Formats the checksum as the value of a digest header.
+pub fn format_digest(checksum: &[u8; 32]) -> String {
+    format!("sha-256=:{}:", BASE64_STANDARD.encode(checksum))
+}
+
+/// Parses the `sha-256` digest out of a digest header, ignoring the other
+/// algorithms. Returns `None` if it has no `sha-256` digest.
+pub fn parse_digest(value: &str) -> Result<Option<[u8; 32]>, ObjectError> {
+    for member in value.split(',') {
+        let Some((algorithm, digest)) = member.split_once('=') else {
+            continue;
+        };
+        if !algorithm.trim().eq_ignore_ascii_case("sha-256") {
+            continue;
+        }
+
+        let digest = digest
+            .trim()
+            .strip_prefix(':')
+            .and_then(|digest| digest.strip_suffix(':'))
+            .ok_or(ObjectError::InvalidDigest)?;
+        let digest = BASE64_STANDARD
+            .decode(digest)
+            .map_err(|_| ObjectError::InvalidDigest)?;
+        return digest
+            .try_into()
+            .map(Some)
+            .map_err(|_| ObjectError::InvalidDigest);
+    }
+    Ok(None)
+}
+
+/// The checksum of the request, in the [`CONTENT_SHA256_HEADER`] or in the
+/// `sha-256` digest of the [`REPR_DIGEST_HEADER`] or
+/// [`CONTENT_DIGEST_HEADER`], if any. Fails if they do not agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentChecksum(pub Option<[u8; 32]>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ContentChecksum {
+    type Rejection = DownloaderError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts.headers.get(name).map(HeaderValue::to_str).transpose()
+        };
+
+        let mut checksums = Vec::with_capacity(3);
+        if let Some(checksum) = header(CONTENT_SHA256_HEADER)
+            .map_err(|_| ObjectError::InvalidChecksum)?
+        {
+            checksums.push(parse_checksum(checksum)?);
+        }
+        for name in [REPR_DIGEST_HEADER, CONTENT_DIGEST_HEADER] {
+            let value = header(name).map_err(|_| ObjectError::InvalidDigest)?;
+            if let Some(checksum) = value.map(parse_digest).transpose()? {
+                checksums.extend(checksum);
+            }
+        }
+
+        if checksums.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(ObjectError::ChecksumMismatch.into());
+        }
+        Ok(Self(checksums.first().copied()))
+    }
+}
+
+impl ContentChecksum {
+    /// The checksum of the header, or else the one of the `sha256` query
+    /// parameter.
+    pub fn or_query(
+        self,
+        sha256: Option<&str>,
+    ) -> Result<Option<[u8; 32]>, ObjectError> {
+        match (self.0, sha256) {
+            (Some(checksum), _) => Ok(Some(checksum)),
+            (None, Some(sha256)) => parse_checksum(sha256).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Trims the slashes around `folder`, returning `None` unless what is left
+/// is a relative path without empty nor `..` segments.
+pub fn normalize_folder(folder: &str) -> Option<&str> {
+    let folder = folder.trim_matches('/');
+    let invalid = folder.is_empty()
+        || folder.split('/').any(|s| s.is_empty() || s == "..");
+    (!invalid).then_some(folder)
+}
+
+/// Serializes writes of the same object, so concurrent updates can not
+/// interleave their data and metadata.
+pub struct WriteLocks {
+    locks: KeyedLock<Uuid>,
+    wait: bool,
+}
+
+impl WriteLocks {
+    /// If `wait` is false, writes fail with [`ObjectError::WriteConflict`]
+    /// instead of waiting for the one in progress to finish.
+    pub fn new(wait: bool) -> Self {
+        Self {
+            locks: KeyedLock::new(),
+            wait,
+        }
+    }
+
+    pub async fn acquire(
+        &self,
+        id: Uuid,
+    ) -> Result<OwnedMutexGuard<()>, ObjectError> {
+        if self.wait {
+            Ok(self.locks.lock(&id).await)
+        } else {
+            self.locks.try_lock(&id).ok_or(ObjectError::WriteConflict)
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -15,6 +248,22 @@ pub struct Object {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub data: ObjectData,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Incremented by every update, see [`Object::etag`].
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Object {
+    /// Strong validator of the current version, matched against the
+    /// `If-Match` header of conditional updates.
+    #[inline]
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
 }
 
 impl<'r, R: Row> FromRow<'r, R> for Object
@@ -29,6 +278,7 @@ where
 
     String: Decode<'r, R::Database>,
     String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
 {
     fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
         let id: Vec<u8> = row.try_get("id")?;
@@ -74,6 +324,11 @@ where
             )
         })?;
 
+        let version: i64 = row.try_get("version")?;
+        let version = version.try_into().map_err(|err| {
+            sqlx::Error::Decode(format!("parse `version`: {err}").into())
+        })?;
+
         Ok(Self {
             id,
             user_id,
@@ -85,6 +340,9 @@ where
                 size,
                 checksum_256,
             },
+            namespace: row.try_get("namespace")?,
+            description: row.try_get("description")?,
+            version,
         })
     }
 }
@@ -129,3 +387,89 @@ mod hex_sha256 {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{format_digest, parse_digest, ObjectData};
+    use crate::storage::manager::ObjectError;
+
+    fn object_data() -> impl Strategy<Value = ObjectData> {
+        (".*", ".*", any::<u64>(), any::<[u8; 32]>()).prop_map(
+            |(name, mime_type, size, checksum_256)| ObjectData {
+                name,
+                mime_type,
+                size,
+                checksum_256,
+            },
+        )
+    }
+
+    fn with_checksum(data: &ObjectData, checksum: &str) -> serde_json::Value {
+        let mut json = serde_json::to_value(data).unwrap();
+        json["checksum_256"] = checksum.into();
+        json
+    }
+
+    #[test]
+    fn test_parse_digest() {
+        let checksum = [7u8; 32];
+        let digest = format_digest(&checksum);
+
+        let value = format!("sha-512=:AAAA:, {digest}");
+        assert_eq!(parse_digest(&value).unwrap(), Some(checksum));
+        assert_eq!(parse_digest("sha-512=:AAAA:").unwrap(), None);
+
+        for value in ["sha-256=AAAA", "sha-256=:!!:", "sha-256=:AAAA:"] {
+            assert!(matches!(
+                parse_digest(value),
+                Err(ObjectError::InvalidDigest),
+            ));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_digest_roundtrip(checksum in any::<[u8; 32]>()) {
+            let digest = format_digest(&checksum);
+            prop_assert_eq!(parse_digest(&digest).unwrap(), Some(checksum));
+        }
+
+        #[test]
+        fn test_object_data_roundtrip(data in object_data()) {
+            let json = serde_json::to_string(&data).unwrap();
+            let data2: ObjectData = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(data, data2);
+        }
+
+        #[test]
+        fn test_checksum_hex_case(data in object_data()) {
+            let upper = hex::encode_upper(data.checksum_256);
+            let json = with_checksum(&data, &upper);
+
+            let data2: ObjectData = serde_json::from_value(json).unwrap();
+            prop_assert_eq!(data.checksum_256, data2.checksum_256);
+        }
+
+        #[test]
+        fn test_checksum_invalid_length(
+            data in object_data(),
+            bytes in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            prop_assume!(bytes.len() != 32);
+
+            let json = with_checksum(&data, &hex::encode(bytes));
+            prop_assert!(serde_json::from_value::<ObjectData>(json).is_err());
+        }
+
+        #[test]
+        fn test_checksum_invalid_hex(
+            data in object_data(),
+            checksum in "[0-9a-f]{63}[g-z]",
+        ) {
+            let json = with_checksum(&data, &checksum);
+            prop_assert!(serde_json::from_value::<ObjectData>(json).is_err());
+        }
+    }
+}
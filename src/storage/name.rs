@@ -0,0 +1,201 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NameConfig;
+
+/// Characters not allowed in the names of files by some filesystems.
+const RESERVED_CHARS: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+#[derive(Debug, thiserror::Error)]
+pub enum NameError {
+    #[error("the name is empty")]
+    Empty,
+    #[error("the name is longer than {0} bytes")]
+    TooLong(usize),
+    #[error("the name contains control characters")]
+    ControlCharacter,
+    #[error("the name contains the reserved character `{0}`")]
+    ReservedCharacter(char),
+    #[error("the name contains the invalid path segment `{0}`")]
+    InvalidSegment(String),
+}
+
+impl NameError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+crate::errors::error_codes! {
+    NameError {
+        Empty => 1, "the name is empty";
+        TooLong(..) => 2, "the name is too long";
+        ControlCharacter => 3, "the name contains control characters";
+        ReservedCharacter(..) => 4, "the name contains a reserved character";
+        InvalidSegment(..) => 5, "the name contains an invalid path segment";
+    }
+}
+
+/// How strictly the names of objects are validated.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NameStrictness {
+    /// Only the length and the control characters are checked.
+    #[default]
+    Lenient,
+    /// The names must also be valid relative paths on any filesystem.
+    Strict,
+}
+
+/// Validates the names given to objects, so they can be used as paths when
+/// exporting them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamePolicy {
+    max_length: usize,
+    strictness: NameStrictness,
+    normalize_separators: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self::new(&NameConfig::default())
+    }
+}
+
+impl NamePolicy {
+    pub fn new(cfg: &NameConfig) -> Self {
+        Self {
+            max_length: cfg.max_length,
+            strictness: cfg.strictness,
+            normalize_separators: cfg.normalize_separators,
+        }
+    }
+
+    /// Returns `name` normalized if enabled, or why it is not valid.
+    pub fn apply(&self, name: &str) -> Result<String, NameError> {
+        let name = if self.normalize_separators {
+            name.split(['/', '\\'])
+                .filter(|segment| !segment.is_empty())
+                .collect::<Vec<_>>()
+                .join("/")
+        } else {
+            name.to_owned()
+        };
+
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if self.max_length > 0 && name.len() > self.max_length {
+            return Err(NameError::TooLong(self.max_length));
+        }
+        if name.chars().any(char::is_control) {
+            return Err(NameError::ControlCharacter);
+        }
+
+        if self.strictness == NameStrictness::Strict {
+            if let Some(c) = name.chars().find(|c| RESERVED_CHARS.contains(c)) {
+                return Err(NameError::ReservedCharacter(c));
+            }
+            let invalid = name.split('/').find(|segment| {
+                segment.is_empty()
+                    || segment.starts_with(' ')
+                    || segment.ends_with([' ', '.'])
+            });
+            if let Some(segment) = invalid {
+                return Err(NameError::InvalidSegment(segment.to_owned()));
+            }
+        }
+
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(
+        max_length: usize,
+        strictness: NameStrictness,
+        normalize_separators: bool,
+    ) -> NamePolicy {
+        NamePolicy::new(&NameConfig {
+            max_length,
+            strictness,
+            normalize_separators,
+        })
+    }
+
+    #[test]
+    fn test_lenient() {
+        let policy = policy(8, NameStrictness::Lenient, false);
+
+        for name in ["a.txt", "a/b.txt", "../a", "a\\b", "/a//b", "ñ.txt"] {
+            assert_eq!(policy.apply(name).unwrap(), name);
+        }
+        assert!(matches!(policy.apply(""), Err(NameError::Empty)));
+        assert!(matches!(
+            policy.apply("123456789"),
+            Err(NameError::TooLong(8)),
+        ));
+        // Lengths are counted in bytes
+        assert!(matches!(
+            policy.apply("ññññ.txt"),
+            Err(NameError::TooLong(8)),
+        ));
+        for name in ["a\nb", "a\0", "\u{7f}", "a\u{85}"] {
+            assert!(matches!(
+                policy.apply(name),
+                Err(NameError::ControlCharacter),
+            ));
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let policy = policy(0, NameStrictness::Strict, false);
+
+        for name in ["a.txt", "a/b.txt", ".env", "a b/c d.txt"] {
+            assert_eq!(policy.apply(name).unwrap(), name);
+        }
+        assert_eq!(policy.apply(&"a".repeat(1000)).unwrap().len(), 1000);
+
+        for (name, c) in [("a:b", ':'), ("a\\b", '\\'), ("what?", '?')] {
+            assert!(matches!(
+                policy.apply(name),
+                Err(NameError::ReservedCharacter(r)) if r == c,
+            ));
+        }
+        for (name, segment) in [
+            ("../a", ".."),
+            ("a/./b", "."),
+            ("/a", ""),
+            ("a//b", ""),
+            ("a/", ""),
+            ("a./b", "a."),
+            ("a/b ", "b "),
+            (" a", " a"),
+        ] {
+            assert!(matches!(
+                policy.apply(name),
+                Err(NameError::InvalidSegment(s)) if s == segment,
+            ));
+        }
+    }
+
+    #[test]
+    fn test_normalize_separators() {
+        let policy = policy(0, NameStrictness::Strict, true);
+
+        assert_eq!(policy.apply("/a//b\\c.txt/").unwrap(), "a/b/c.txt");
+        assert_eq!(policy.apply("a\\b").unwrap(), "a/b");
+        assert!(matches!(policy.apply("//\\"), Err(NameError::Empty)));
+        assert!(matches!(
+            policy.apply("a\\..\\b"),
+            Err(NameError::InvalidSegment(..)),
+        ));
+    }
+}
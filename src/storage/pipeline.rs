@@ -0,0 +1,755 @@
+use std::sync::Arc;
+
+use axum::{async_trait, http::StatusCode};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    Database, Encode, Executor, FromRow, IntoArguments, Pool, Sqlite, Type,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use uuid::Uuid;
+
+use crate::{
+    errors::DownloaderError,
+    job::{
+        queue::{JobProgress, JobQueue},
+        Job, JobKind,
+    },
+    utils::retry::retry_busy,
+};
+
+use super::{
+    exif::{ExifStep, StripExifStep},
+    manager::{Manager, ObjectError},
+    meta::MetaRepository,
+    repository::{ObjectRepository, RepositoryError},
+    scan::Scanner,
+    Object, WriteLocks,
+};
+
+/// Bytes of the data the type is detected from by [`SniffStep`].
+const SNIFF_LEN: usize = 512;
+/// Size of the reads of [`VerifyStep`].
+const VERIFY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Access to the data of objects for the steps, as a [`Manager`] can not be
+/// used as a trait object.
+#[async_trait]
+pub trait DataStore: Send + Sync {
+    async fn open(
+        &self,
+        id: Uuid,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, ObjectError>;
+
+    async fn remove(&self, id: Uuid) -> Result<(), ObjectError>;
+
+    /// Stores `data` as the data of the object `id`, returning its size and
+    /// checksum.
+    async fn replace(
+        &self,
+        id: Uuid,
+        data: Bytes,
+    ) -> Result<(u64, [u8; 32]), ObjectError>;
+}
+
+#[async_trait]
+impl<M: Manager> DataStore for M {
+    async fn open(
+        &self,
+        id: Uuid,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, ObjectError> {
+        Ok(Box::new(self.fetch(id).await?))
+    }
+
+    async fn remove(&self, id: Uuid) -> Result<(), ObjectError> {
+        self.delete(id).await
+    }
+
+    async fn replace(
+        &self,
+        id: Uuid,
+        data: Bytes,
+    ) -> Result<(u64, [u8; 32]), ObjectError> {
+        let size = data.len() as u64;
+        self.store(id, stream::iter([Ok(data)]), None, Some(size))
+            .await
+    }
+}
+
+/// What a [`Step`] processes.
+pub struct StepContext<'a> {
+    pub object: &'a Object,
+    pub repo: &'a ObjectRepository<Sqlite>,
+    pub store: &'a dyn DataStore,
+}
+
+/// A step of the post-processing of the data of objects.
+#[async_trait]
+pub trait Step: Send + Sync {
+    /// Name the state of the step is recorded with.
+    fn name(&self) -> &'static str;
+
+    /// The output is recorded as the result of the step. Failures skip the
+    /// steps after it.
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String>;
+}
+
+/// The steps of the `post_process` list of the storage config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepKind {
+    /// Scans the data for malware, see [`Scanner`].
+    Scan,
+    /// Reads the data back, checking it matches its checksum.
+    Verify,
+    /// Detects the type of the data from its first bytes.
+    Sniff,
+    /// Copies the EXIF of images into their metadata, see [`ExifStep`].
+    Exif,
+    /// Removes the EXIF of images, see [`StripExifStep`].
+    StripExif,
+}
+
+/// What the steps built by [`build_steps`] use.
+pub struct StepDeps {
+    /// Required by the scan step.
+    pub scanner: Option<Scanner>,
+    pub meta: MetaRepository<Sqlite>,
+    pub locks: Arc<WriteLocks>,
+}
+
+/// Builds the steps of `kinds`, in order.
+pub fn build_steps(
+    kinds: &[StepKind],
+    deps: &StepDeps,
+) -> Result<Vec<Box<dyn Step>>, String> {
+    kinds
+        .iter()
+        .map(|kind| -> Result<Box<dyn Step>, String> {
+            match kind {
+                StepKind::Scan => match &deps.scanner {
+                    Some(scanner) => Ok(Box::new(scanner.clone())),
+                    None => Err("the `scan` post-processing step requires \
+                        scanning to be configured"
+                        .to_owned()),
+                },
+                StepKind::Verify => Ok(Box::new(VerifyStep)),
+                StepKind::Sniff => Ok(Box::new(SniffStep)),
+                StepKind::Exif => {
+                    Ok(Box::new(ExifStep::new(deps.meta.clone())))
+                }
+                StepKind::StripExif => {
+                    Ok(Box::new(StripExifStep::new(deps.locks.clone())))
+                }
+            }
+        })
+        .collect()
+}
+
+pub struct VerifyStep;
+
+#[async_trait]
+impl Step for VerifyStep {
+    fn name(&self) -> &'static str {
+        "verify"
+    }
+
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String> {
+        let mut reader = ctx
+            .store
+            .open(ctx.object.id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; VERIFY_BUFFER_SIZE];
+        let mut size = 0;
+        loop {
+            let n = reader.read(&mut buf).await.map_err(|e| e.to_string())?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+
+        let hash: [u8; 32] = hasher.finalize().into();
+        if size != ctx.object.data.size || hash != ctx.object.data.checksum_256
+        {
+            return Err(ObjectError::ChecksumMismatch.to_string());
+        }
+        Ok(json!({ "size": size }))
+    }
+}
+
+pub struct SniffStep;
+
+#[async_trait]
+impl Step for SniffStep {
+    fn name(&self) -> &'static str {
+        "sniff"
+    }
+
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String> {
+        let reader = ctx
+            .store
+            .open(ctx.object.id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        reader
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mime_type = sniff_mime_type(&head);
+        let declared = ctx.object.data.mime_type.split(';').next();
+        Ok(json!({
+            "mime_type": mime_type,
+            "matches_declared": declared
+                .is_some_and(|declared| declared.trim() == mime_type),
+        }))
+    }
+}
+
+/// Detects the type of data starting with `head`, falling back to text if
+/// it is valid UTF-8.
+pub(super) fn sniff_mime_type(head: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"II*\0", "image/tiff"),
+        (b"MM\0*", "image/tiff"),
+        (b"\x7fELF", "application/x-executable"),
+    ];
+
+    if let Some((_, mime_type)) =
+        SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic))
+    {
+        return mime_type;
+    }
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return "image/webp";
+    }
+
+    // The head may end in the middle of a character
+    match std::str::from_utf8(head) {
+        Ok(..) => "text/plain",
+        Err(e) if e.error_len().is_none() => "text/plain",
+        Err(..) => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepState {
+    Pending = 0,
+    Running = 1,
+    Succeeded = 2,
+    Failed = 3,
+    /// Not run, as a step before it failed.
+    Skipped = 4,
+}
+
+impl StepState {
+    fn from_i64(v: i64) -> Option<Self> {
+        match v {
+            0 => Some(StepState::Pending),
+            1 => Some(StepState::Running),
+            2 => Some(StepState::Succeeded),
+            3 => Some(StepState::Failed),
+            4 => Some(StepState::Skipped),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a step of the post-processing of an object.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepStatus {
+    pub step: String,
+    pub state: StepState,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+type StepRow = (String, i64, Option<String>, Option<String>, i64);
+
+pub struct StepRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for StepRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> StepRepository<DB> {
+    pub fn new(db: Pool<DB>) -> StepRepository<DB> {
+        StepRepository { db }
+    }
+}
+
+impl<DB> StepRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> StepRow: FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    /// The steps of the object `id`, in the order they run.
+    pub async fn get(
+        &self,
+        id: Uuid,
+    ) -> Result<Vec<StepStatus>, RepositoryError> {
+        let rows: Vec<StepRow> = sqlx::query_as(
+            "SELECT step, state, result, error, updated_at FROM object_step \
+            WHERE object_id = $1 ORDER BY position",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        rows.into_iter()
+            .map(|(step, state, result, error, updated_at)| {
+                let decode_error = |msg: String| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(msg.into()))
+                };
+                Ok(StepStatus {
+                    step,
+                    state: StepState::from_i64(state).ok_or_else(|| {
+                        decode_error(format!("parse `state`: unknown {state}"))
+                    })?,
+                    result: result
+                        .map(|result| serde_json::from_str(&result))
+                        .transpose()
+                        .map_err(|e| {
+                            decode_error(format!("parse `result`: {e}"))
+                        })?,
+                    error,
+                    updated_at: DateTime::from_timestamp_millis(updated_at)
+                        .ok_or_else(|| {
+                            decode_error(
+                                "parse `updated_at` field gone wrong".into(),
+                            )
+                        })?,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the steps of the object `id` with `steps`, all pending.
+    pub async fn start(
+        &self,
+        id: Uuid,
+        steps: &[&str],
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.start_once(id, steps))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn start_once(
+        &self,
+        id: Uuid,
+        steps: &[&str],
+    ) -> Result<(), sqlx::Error> {
+        let id_bytes = id.into_bytes();
+        let now = Utc::now().timestamp_millis();
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query("DELETE FROM object_step WHERE object_id = $1")
+            .bind(id_bytes.as_slice())
+            .execute(&mut *tx)
+            .await?;
+        for (position, step) in steps.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO object_step \
+                (object_id, step, position, state, updated_at) \
+                VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(*step)
+            .bind(position as i64)
+            .bind(StepState::Pending as i64)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    pub async fn set(
+        &self,
+        id: Uuid,
+        step: &str,
+        state: StepState,
+        result: Result<Option<&serde_json::Value>, &str>,
+    ) -> Result<(), RepositoryError> {
+        let id_bytes = id.into_bytes();
+        let (result, error) = match result {
+            Ok(result) => (result.map(|r| r.to_string()), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        retry_busy(|| {
+            sqlx::query(
+                "UPDATE object_step \
+                SET state = $1, result = $2, error = $3, updated_at = $4 \
+                WHERE object_id = $5 AND step = $6",
+            )
+            .bind(state as i64)
+            .bind(result.as_deref())
+            .bind(error)
+            .bind(Utc::now().timestamp_millis())
+            .bind(id_bytes.as_slice())
+            .bind(step)
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+
+    /// Objects with steps left to run, as their processing was interrupted.
+    pub async fn unfinished(&self) -> Result<Vec<Uuid>, RepositoryError> {
+        let rows: Vec<(Vec<u8>,)> = sqlx::query_as(
+            "SELECT DISTINCT object_id FROM object_step \
+            WHERE state IN ($1, $2)",
+        )
+        .bind(StepState::Pending as i64)
+        .bind(StepState::Running as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        rows.into_iter()
+            .map(|(id,)| {
+                Uuid::from_slice(&id).map_err(|_| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse `object_id` uuid out of range".into(),
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object steps");
+    RepositoryError::Sqlx(error)
+}
+
+/// Runs the configured steps on the data of objects once stored, as jobs.
+#[derive(Clone)]
+pub struct Pipeline {
+    steps: Arc<[Box<dyn Step>]>,
+    store: Arc<dyn DataStore>,
+    repo: ObjectRepository<Sqlite>,
+    statuses: StepRepository<Sqlite>,
+    jobs: Arc<JobQueue>,
+}
+
+impl Pipeline {
+    pub fn new(
+        steps: Vec<Box<dyn Step>>,
+        store: Arc<dyn DataStore>,
+        repo: ObjectRepository<Sqlite>,
+        statuses: StepRepository<Sqlite>,
+        jobs: Arc<JobQueue>,
+    ) -> Self {
+        Self {
+            steps: steps.into(),
+            store,
+            repo,
+            statuses,
+            jobs,
+        }
+    }
+
+    /// Queues the processing of the data just stored in the object `id`,
+    /// replacing the steps recorded for its previous data.
+    pub async fn submit(&self, id: Uuid) -> Result<Job, DownloaderError> {
+        let names: Vec<_> = self.steps.iter().map(|step| step.name()).collect();
+        self.statuses.start(id, &names).await?;
+
+        let pipeline = self.clone();
+        let job = self
+            .jobs
+            .enqueue(JobKind::PostProcess, None, |progress| async move {
+                pipeline.run(id, &progress).await
+            })
+            .await?;
+        Ok(job)
+    }
+
+    /// Queues again the processing of the objects interrupted by a restart,
+    /// returning how many.
+    pub async fn resume(&self) -> Result<usize, DownloaderError> {
+        let ids = self.statuses.unfinished().await?;
+        for &id in &ids {
+            self.submit(id).await?;
+        }
+        Ok(ids.len())
+    }
+
+    async fn run(
+        &self,
+        id: Uuid,
+        progress: &JobProgress,
+    ) -> Result<Vec<StepStatus>, DownloaderError> {
+        progress.set_total(self.steps.len() as u64);
+
+        let mut failed = None;
+        let mut deleted = false;
+        for step in self.steps.iter() {
+            let name = step.name();
+            // Read before every step, as the ones before may have replaced
+            // the data, with nothing left to process once deleted meanwhile
+            let object = if failed.is_some() || deleted {
+                None
+            } else {
+                match self.repo.get(id).await {
+                    Ok(object) => Some(object),
+                    Err(RepositoryError::NotFound(..)) => {
+                        deleted = true;
+                        None
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            };
+            let Some(object) = object else {
+                self.statuses
+                    .set(id, name, StepState::Skipped, Ok(None))
+                    .await?;
+                continue;
+            };
+
+            self.statuses
+                .set(id, name, StepState::Running, Ok(None))
+                .await?;
+            let ctx = StepContext {
+                object: &object,
+                repo: &self.repo,
+                store: self.store.as_ref(),
+            };
+            match step.run(&ctx).await {
+                Ok(result) => {
+                    self.statuses
+                        .set(id, name, StepState::Succeeded, Ok(Some(&result)))
+                        .await?;
+                }
+                Err(error) => {
+                    tracing::warn!(%error, %id, step = name, "step failed");
+                    self.statuses
+                        .set(id, name, StepState::Failed, Err(&error))
+                        .await?;
+                    failed = Some(format!("step `{name}` failed: {error}"));
+                }
+            }
+            progress.add(1);
+        }
+
+        match failed {
+            Some(error) => Err(DownloaderError::Other(
+                error,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+            None => Ok(self.statuses.get(id).await?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures_util::stream;
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+    use crate::{
+        config::JobConfig,
+        job::{repository::JobRepository, JobState},
+        storage::{memory::MemoryManager, ObjectData},
+    };
+
+    /// Fails for objects named `fail.txt`.
+    struct FailStep;
+
+    #[async_trait]
+    impl Step for FailStep {
+        fn name(&self) -> &'static str {
+            "fail"
+        }
+
+        async fn run(
+            &self,
+            ctx: &StepContext<'_>,
+        ) -> Result<serde_json::Value, String> {
+            match ctx.object.data.name.as_str() {
+                "fail.txt" => Err("told to fail".into()),
+                _ => Ok(serde_json::Value::Null),
+            }
+        }
+    }
+
+    async fn wait(jobs: &JobQueue, job: &Job) -> Job {
+        loop {
+            let job = jobs.get(job.id).await.unwrap();
+            if job.state.is_finished() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn test_sniff_mime_type() {
+        assert_eq!(sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime_type(b"%PDF-1.7"), "application/pdf");
+        assert_eq!(sniff_mime_type(b"hello world"), "text/plain");
+        // Cut in the middle of a character
+        assert_eq!(
+            sniff_mime_type("hello wörld".as_bytes().split_at(8).0),
+            "text/plain"
+        );
+        assert_eq!(sniff_mime_type(b"\0\xff\xfe"), "application/octet-stream");
+    }
+
+    #[tokio::test]
+    async fn test_build_steps() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let deps = StepDeps {
+            scanner: None,
+            meta: MetaRepository::new(db),
+            locks: Arc::new(WriteLocks::new(true)),
+        };
+        let kinds = [StepKind::Sniff, StepKind::Exif, StepKind::StripExif];
+        let steps = build_steps(&kinds, &deps).unwrap();
+        let names: Vec<_> = steps.iter().map(|step| step.name()).collect();
+        assert_eq!(names, ["sniff", "exif", "strip-exif"]);
+
+        assert!(build_steps(&[StepKind::Scan], &deps).is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pipeline() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ObjectRepository::new(db.clone());
+        let statuses = StepRepository::new(db.clone());
+        let manager = Arc::new(MemoryManager::new());
+        let jobs = JobQueue::start(
+            JobRepository::new(db.clone()),
+            &JobConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let steps: Vec<Box<dyn Step>> = vec![
+            Box::new(SniffStep),
+            Box::new(FailStep),
+            Box::new(VerifyStep),
+        ];
+        let pipeline = Pipeline::new(
+            steps,
+            manager.clone(),
+            repo.clone(),
+            statuses.clone(),
+            jobs.clone(),
+        );
+
+        let mut objects = Vec::new();
+        for name in ["ok.txt", "fail.txt"] {
+            let id = Uuid::new_v4();
+            let data = stream::iter([Ok(Bytes::from_static(b"hello world"))]);
+            let (size, checksum_256) =
+                manager.store(id, data, None, None).await.unwrap();
+            let data = ObjectData {
+                name: name.into(),
+                mime_type: "text/plain".into(),
+                size,
+                checksum_256,
+            };
+            objects.push(repo.create(id, Uuid::new_v4(), data).await.unwrap());
+        }
+
+        let job = pipeline.submit(objects[0].id).await.unwrap();
+        assert_eq!(wait(&jobs, &job).await.state, JobState::Succeeded);
+        let steps = statuses.get(objects[0].id).await.unwrap();
+        let states: Vec<_> = steps.iter().map(|s| s.state).collect();
+        assert_eq!(states, [StepState::Succeeded; 3]);
+        assert_eq!(
+            steps[0].result,
+            Some(
+                json!({ "mime_type": "text/plain", "matches_declared": true })
+            ),
+        );
+        assert_eq!(steps[2].result, Some(json!({ "size": 11 })));
+
+        // The steps after a failed one are skipped
+        let job = pipeline.submit(objects[1].id).await.unwrap();
+        let job = wait(&jobs, &job).await;
+        assert_eq!(job.state, JobState::Failed);
+        let steps = statuses.get(objects[1].id).await.unwrap();
+        let states: Vec<_> = steps.iter().map(|s| s.state).collect();
+        assert_eq!(
+            states,
+            [StepState::Succeeded, StepState::Failed, StepState::Skipped],
+        );
+        assert_eq!(steps[1].error.as_deref(), Some("told to fail"));
+        assert!(statuses.unfinished().await.unwrap().is_empty());
+
+        // Processing interrupted by a restart is resumed
+        statuses
+            .start(objects[0].id, &["sniff", "fail", "verify"])
+            .await
+            .unwrap();
+        assert_eq!(statuses.unfinished().await.unwrap(), [objects[0].id]);
+        assert_eq!(pipeline.resume().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,267 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
+
+use super::{pipeline::sniff_mime_type, Object};
+
+/// KiB of text previewed unless the query sets another size.
+pub const DEFAULT_PREVIEW_KIB: u64 = 16;
+pub const MAX_PREVIEW_KIB: u64 = 256;
+
+/// What the preview of a file shows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Preview {
+    /// The first bytes of a text file, decoded.
+    Text {
+        charset: String,
+        text: String,
+        /// Whether the file has more text than previewed.
+        truncated: bool,
+    },
+    /// A summary of any other file, to be shown in place of its data.
+    Stub {
+        name: String,
+        mime_type: String,
+        size: u64,
+        category: Category,
+    },
+}
+
+/// Broad kind of a file the frontend picks the icon of a stub from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Image,
+    Audio,
+    Video,
+    Archive,
+    Document,
+    Binary,
+}
+
+impl Category {
+    fn of(mime_type: &str) -> Self {
+        let essence = essence(mime_type);
+        match essence.split_once('/').map_or(essence, |(kind, _)| kind) {
+            "image" => Category::Image,
+            "audio" => Category::Audio,
+            "video" => Category::Video,
+            _ => match essence {
+                "application/zip"
+                | "application/gzip"
+                | "application/x-tar"
+                | "application/x-7z-compressed"
+                | "application/x-bzip2"
+                | "application/x-xz"
+                | "application/zstd" => Category::Archive,
+                "application/pdf"
+                | "application/msword"
+                | "application/rtf"
+                | "application/epub+zip" => Category::Document,
+                _ if essence.starts_with("application/vnd.oasis")
+                    || essence
+                        .starts_with("application/vnd.openxmlformats") =>
+                {
+                    Category::Document
+                }
+                _ => Category::Binary,
+            },
+        }
+    }
+}
+
+/// The preview of `object` from the first bytes of its data, `head`, with
+/// `truncated` set if the data goes on after them.
+pub fn preview(object: &Object, head: &[u8], truncated: bool) -> Preview {
+    let declared = &object.data.mime_type;
+    match detect_encoding(declared, head, truncated) {
+        Some(encoding) => {
+            let mut decoder = encoding.new_decoder_with_bom_removal();
+            let mut text = String::with_capacity(
+                decoder
+                    .max_utf8_buffer_length(head.len())
+                    .unwrap_or(head.len()),
+            );
+            // A character cut by the end of the head is left out unless the
+            // data ends there
+            let _ = decoder.decode_to_string(head, &mut text, !truncated);
+            Preview::Text {
+                charset: encoding.name().to_owned(),
+                text,
+                truncated,
+            }
+        }
+        None => {
+            let sniffed = sniff_mime_type(head);
+            let category = match Category::of(declared) {
+                Category::Binary => Category::of(sniffed),
+                category => category,
+            };
+            Preview::Stub {
+                name: object.data.name.clone(),
+                mime_type: declared.clone(),
+                size: object.data.size,
+                category,
+            }
+        }
+    }
+}
+
+/// The encoding of the data if it is text, from its BOM, the charset of
+/// its type or its content, in this order.
+fn detect_encoding(
+    mime_type: &str,
+    head: &[u8],
+    truncated: bool,
+) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(head) {
+        return Some(encoding);
+    }
+    // Formats recognized by their first bytes are never text, nor are media
+    // and archives whatever their data looks like
+    let sniffed = sniff_mime_type(head);
+    if !matches!(sniffed, "text/plain" | "application/octet-stream")
+        || !matches!(
+            Category::of(mime_type),
+            Category::Document | Category::Binary,
+        )
+    {
+        return None;
+    }
+
+    let declared = mime_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| {
+            Encoding::for_label(label.trim().trim_matches('"').as_bytes())
+        });
+    if declared.is_some() {
+        return declared;
+    }
+
+    // Control characters other than whitespace only appear in binary data
+    if head
+        .iter()
+        .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c))
+    {
+        return None;
+    }
+    if sniffed == "text/plain" {
+        return Some(UTF_8);
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(head, !truncated);
+    Some(detector.guess(None, true))
+}
+
+fn essence(mime_type: &str) -> &str {
+    mime_type.split(';').next().unwrap_or_default().trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::ObjectData;
+
+    fn object(mime_type: &str) -> Object {
+        Object {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            data: ObjectData {
+                name: "file".into(),
+                mime_type: mime_type.into(),
+                size: 1024,
+                checksum_256: [0; 32],
+            },
+            namespace: "default".into(),
+            description: None,
+            version: 0,
+        }
+    }
+
+    fn as_text(preview: Preview) -> (String, String) {
+        match preview {
+            Preview::Text { charset, text, .. } => (charset, text),
+            preview => panic!("expected a text preview, got {preview:?}"),
+        }
+    }
+
+    #[test]
+    fn test_preview_text() {
+        let obj = object("text/plain");
+        let (charset, text) = as_text(preview(&obj, b"hello world", false));
+        assert_eq!((charset.as_str(), text.as_str()), ("UTF-8", "hello world"));
+
+        // Cut in the middle of a character
+        let head = &"olá".as_bytes()[..3];
+        let (_, text) = as_text(preview(&obj, head, true));
+        assert_eq!(text, "ol");
+
+        let (charset, text) = as_text(preview(&obj, b"\xff\xfeh\0i\0", false));
+        assert_eq!((charset.as_str(), text.as_str()), ("UTF-16LE", "hi"));
+
+        let obj = object("text/plain; charset=ISO-8859-1");
+        let (charset, text) = as_text(preview(&obj, b"ol\xe1", false));
+        assert_eq!((charset.as_str(), text.as_str()), ("windows-1252", "olá"));
+
+        // Detected even when the type is not known to be text
+        let obj = object("application/octet-stream");
+        let head = "Ceci est un texte en français, écrit à Orléans."
+            .chars()
+            .map(|c| c as u8)
+            .collect::<Vec<_>>();
+        let (charset, text) = as_text(preview(&obj, &head, false));
+        assert_eq!(charset, "windows-1252");
+        assert!(text.contains("écrit"));
+    }
+
+    #[test]
+    fn test_preview_stub() {
+        let obj = object("image/png");
+        assert_eq!(
+            preview(&obj, b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", true),
+            Preview::Stub {
+                name: "file".into(),
+                mime_type: "image/png".into(),
+                size: 1024,
+                category: Category::Image,
+            },
+        );
+
+        // Falls back to the type detected from the data
+        let obj = object("application/octet-stream");
+        let stub = preview(&obj, b"PK\x03\x04\x14\0\0\0", true);
+        assert!(matches!(
+            stub,
+            Preview::Stub {
+                category: Category::Archive,
+                ..
+            },
+        ));
+
+        let stub = preview(&obj, b"\0\x01\x02\x03", false);
+        assert!(matches!(
+            stub,
+            Preview::Stub {
+                category: Category::Binary,
+                ..
+            },
+        ));
+
+        // Text in a format with a binary type
+        let obj = object("video/mp4");
+        assert!(matches!(
+            preview(&obj, b"hello", false),
+            Preview::Stub { .. }
+        ));
+    }
+}
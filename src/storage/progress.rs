@@ -0,0 +1,395 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
+use uuid::Uuid;
+
+use crate::{auth::Token, errors::DownloaderError};
+
+/// Header with the client chosen id of a transfer to track.
+pub const TRANSFER_ID_HEADER: &str = "x-transfer-id";
+
+const MIN_ID_LEN: usize = 8;
+const MAX_ID_LEN: usize = 64;
+/// Tracked transfers and subscriptions, further ones are not tracked.
+const MAX_TRANSFERS: usize = 4096;
+/// Progress is published at most once per interval, besides the last one.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct TransferProgress {
+    /// Bytes of object data transferred so far.
+    pub bytes: u64,
+    pub total: Option<u64>,
+    /// Whether the transfer ended, successfully or not.
+    pub done: bool,
+}
+
+type TransferKey = (Uuid, String);
+
+struct Channel {
+    tx: watch::Sender<TransferProgress>,
+    active: bool,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            tx: watch::channel(TransferProgress::default()).0,
+            active: false,
+        }
+    }
+}
+
+/// Progress of the transfers of users, keyed by the user and the id they
+/// chose for each transfer.
+#[derive(Default)]
+pub struct Transfers {
+    channels: Mutex<HashMap<TransferKey, Channel>>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a transfer, returning `None` if a transfer with the
+    /// same id is in progress or too many are tracked.
+    pub fn start(
+        self: &Arc<Self>,
+        user_id: Uuid,
+        id: String,
+        total: Option<u64>,
+    ) -> Option<Transfer> {
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(&(user_id, id.clone()))
+            && channels.len() >= MAX_TRANSFERS
+        {
+            return None;
+        }
+
+        let key = (user_id, id);
+        let channel = channels.entry(key.clone()).or_default();
+        if channel.active {
+            return None;
+        }
+        channel.active = true;
+
+        let progress = TransferProgress {
+            bytes: 0,
+            total,
+            done: false,
+        };
+        channel.tx.send_replace(progress);
+
+        Some(Transfer {
+            transfers: self.clone(),
+            key,
+            tx: channel.tx.clone(),
+            progress,
+            published_at: Instant::now(),
+        })
+    }
+
+    /// Streams the progress of a transfer until it ends, starting with the
+    /// current one. The transfer may start after subscribing.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        user_id: Uuid,
+        id: String,
+    ) -> Option<impl Stream<Item = TransferProgress> + Send + 'static> {
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(&(user_id, id.clone()))
+            && channels.len() >= MAX_TRANSFERS
+        {
+            return None;
+        }
+
+        let key = (user_id, id);
+        let tx = &channels.entry(key.clone()).or_default().tx;
+
+        let subscription = Subscription {
+            transfers: self.clone(),
+            key,
+            tx: tx.clone(),
+            rx: Some(tx.subscribe()),
+        };
+        Some(stream::unfold(
+            (subscription, true),
+            |(mut sub, first)| async move {
+                let rx = sub.rx.as_mut()?;
+                if !first {
+                    rx.changed().await.ok()?;
+                }
+
+                let progress = *rx.borrow_and_update();
+                // Ends after yielding the last progress
+                if progress.done {
+                    sub.rx = None;
+                }
+                Some((progress, (sub, false)))
+            },
+        ))
+    }
+}
+
+struct Subscription {
+    transfers: Arc<Transfers>,
+    key: TransferKey,
+    /// Identifies the channel, which may have been replaced in the map.
+    tx: watch::Sender<TransferProgress>,
+    rx: Option<watch::Receiver<TransferProgress>>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Not counted as a receiver anymore
+        self.rx = None;
+
+        let mut channels = self.transfers.channels.lock().unwrap();
+        if let Some(channel) = channels.get(&self.key) {
+            if channel.tx.same_channel(&self.tx)
+                && !channel.active
+                && channel.tx.receiver_count() == 0
+            {
+                channels.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A tracked transfer, done once dropped.
+pub struct Transfer {
+    transfers: Arc<Transfers>,
+    key: TransferKey,
+    tx: watch::Sender<TransferProgress>,
+    progress: TransferProgress,
+    published_at: Instant,
+}
+
+impl Transfer {
+    fn advance(&mut self, bytes: u64) {
+        self.progress.bytes += bytes;
+        if self.published_at.elapsed() >= PUBLISH_INTERVAL {
+            self.published_at = Instant::now();
+            self.tx.send_replace(self.progress);
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.progress.done = true;
+        self.tx.send_replace(self.progress);
+
+        let mut channels = self.transfers.channels.lock().unwrap();
+        channels.remove(&self.key);
+    }
+}
+
+pin_project! {
+    /// Counts the data of a stream as the progress of a transfer.
+    pub struct TrackedStream<S> {
+        #[pin]
+        stream: S,
+        transfer: Option<Transfer>,
+    }
+}
+
+impl<S> TrackedStream<S> {
+    #[inline]
+    pub fn new(stream: S, transfer: Option<Transfer>) -> Self {
+        Self { stream, transfer }
+    }
+}
+
+impl<S, E> Stream for TrackedStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = this.stream.poll_next(cx);
+
+        match &res {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(transfer) = this.transfer {
+                    transfer.advance(chunk.len() as u64);
+                }
+            }
+            // Publishes the end without waiting for the stream to drop
+            Poll::Ready(Some(Err(..)) | None) => *this.transfer = None,
+            Poll::Pending => {}
+        }
+        res
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+/// Tracks the transfer in the [`TRANSFER_ID_HEADER`] of the request, if
+/// any. Only transfers authorized by user tokens are tracked.
+pub struct Progress {
+    transfers: Option<Arc<Transfers>>,
+    id: Option<String>,
+    content_length: Option<u64>,
+}
+
+impl Progress {
+    /// Length of the request body, if known upfront.
+    #[inline]
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    pub fn start(&self, token: &Token, total: Option<u64>) -> Option<Transfer> {
+        let Token::User(user_token) = token else {
+            return None;
+        };
+        let transfers = self.transfers.as_ref()?;
+        let id = self.id.clone()?;
+
+        let transfer = transfers.start(user_token.user_id, id, total);
+        if transfer.is_none() {
+            tracing::debug!("transfer progress not tracked");
+        }
+        transfer
+    }
+
+    pub fn track<S>(
+        &self,
+        token: &Token,
+        total: Option<u64>,
+        stream: S,
+    ) -> TrackedStream<S> {
+        TrackedStream::new(stream, self.start(token, total))
+    }
+}
+
+pub fn validate_transfer_id(id: &str) -> Result<(), DownloaderError> {
+    let valid = (MIN_ID_LEN..=MAX_ID_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    if !valid {
+        return Err(DownloaderError::Other(
+            format!(
+                "transfer ids must have {MIN_ID_LEN} to {MAX_ID_LEN} \
+                alphanumeric, `-` or `_` characters",
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Progress {
+    type Rejection = DownloaderError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let id = match parts.headers.get(TRANSFER_ID_HEADER) {
+            Some(id) => {
+                let id = id.to_str().unwrap_or_default();
+                validate_transfer_id(id)?;
+                Some(id.to_owned())
+            }
+            None => None,
+        };
+
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok());
+
+        Ok(Progress {
+            transfers: parts.extensions.get::<Arc<Transfers>>().cloned(),
+            id,
+            content_length,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_progress() {
+        let transfers = Arc::new(Transfers::new());
+        let user_id = Uuid::new_v4();
+        let id = "upload-1".to_owned();
+
+        let mut updates =
+            Box::pin(transfers.subscribe(user_id, id.clone()).unwrap());
+        assert_eq!(updates.next().await.unwrap(), TransferProgress::default());
+
+        let chunks = stream::iter([
+            Ok::<_, Infallible>(Bytes::from_static(b"abc")),
+            Ok(Bytes::from_static(b"de")),
+        ]);
+        let transfer = transfers.start(user_id, id.clone(), Some(5)).unwrap();
+        assert!(transfers.start(user_id, id.clone(), None).is_none());
+
+        let tracked = TrackedStream::new(chunks, Some(transfer));
+        let data: Vec<_> = tracked.collect().await;
+        assert_eq!(data.len(), 2);
+
+        let mut last = TransferProgress::default();
+        while let Some(progress) = updates.next().await {
+            last = progress;
+        }
+        assert_eq!(
+            last,
+            TransferProgress {
+                bytes: 5,
+                total: Some(5),
+                done: true,
+            },
+        );
+
+        drop(updates);
+        assert!(transfers.channels.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transfer_id() {
+        assert!(validate_transfer_id("0f9c2a1e-upload").is_ok());
+        assert!(validate_transfer_id("short").is_err());
+        assert!(validate_transfer_id("with spaces in it").is_err());
+        assert!(validate_transfer_id(&"a".repeat(65)).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
+    Pool, Row, Sqlite, Type,
+};
+use uuid::Uuid;
+
+use crate::{auth::Token, proxy::ClientIp, utils::retry::retry_busy};
+
+use super::{pipeline::Pipeline, repository::RepositoryError};
+
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// How the upload of an object was authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    User,
+    ApiKey,
+    File,
+    Server,
+    Presigned,
+}
+
+impl TokenType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenType::User => "user",
+            TokenType::ApiKey => "api_key",
+            TokenType::File => "file",
+            TokenType::Server => "server",
+            TokenType::Presigned => "presigned",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "user" => TokenType::User,
+            "api_key" => TokenType::ApiKey,
+            "file" => TokenType::File,
+            "server" => TokenType::Server,
+            "presigned" => TokenType::Presigned,
+            _ => return None,
+        })
+    }
+
+    /// The type of `token`, or of a presigned url if `None`, along with the
+    /// id of the api key it was issued from, if any.
+    pub fn of(token: Option<&Token>) -> (Self, Option<String>) {
+        match token {
+            Some(Token::User(user_token)) => {
+                match user_token.issuer.strip_prefix("key/") {
+                    Some(id) => (TokenType::ApiKey, Some(id.to_owned())),
+                    None => (TokenType::User, None),
+                }
+            }
+            Some(Token::File(..)) => (TokenType::File, None),
+            Some(Token::Server) => (TokenType::Server, None),
+            None => (TokenType::Presigned, None),
+        }
+    }
+}
+
+/// Where the current data of an object came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub recorded_at: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub token_type: TokenType,
+    /// Id of the api key the data was uploaded with.
+    pub token_id: Option<String>,
+    /// Version of the server that stored the data.
+    pub server_version: String,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Provenance
+where
+    &'r str: ColumnIndex<R>,
+
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    Option<String>: Decode<'r, R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let recorded_at: i64 = row.try_get("recorded_at")?;
+        let recorded_at = DateTime::from_timestamp_millis(recorded_at)
+            .ok_or_else(|| {
+                sqlx::Error::Decode(
+                    "parse `recorded_at` field gone wrong".into(),
+                )
+            })?;
+
+        let token_type: String = row.try_get("token_type")?;
+        let token_type = TokenType::parse(&token_type).ok_or_else(|| {
+            sqlx::Error::Decode(
+                format!("parse `token_type`: unknown `{token_type}`").into(),
+            )
+        })?;
+
+        Ok(Provenance {
+            recorded_at,
+            ip: row.try_get("ip")?,
+            user_agent: row.try_get("user_agent")?,
+            token_type,
+            token_id: row.try_get("token_id")?,
+            server_version: row.try_get("server_version")?,
+        })
+    }
+}
+
+pub struct ProvenanceRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for ProvenanceRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> ProvenanceRepository<DB> {
+    pub fn new(db: Pool<DB>) -> ProvenanceRepository<DB> {
+        ProvenanceRepository { db }
+    }
+}
+
+impl<DB> ProvenanceRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> Provenance: FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<Provenance, RepositoryError> {
+        sqlx::query_as("SELECT * FROM object_provenance WHERE object_id = $1")
+            .bind(id.into_bytes().as_slice())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(sqlx_error)?
+            .ok_or(RepositoryError::NotFound(id))
+    }
+
+    /// Replaces the provenance of the object `id`.
+    pub async fn set(
+        &self,
+        id: Uuid,
+        provenance: &Provenance,
+    ) -> Result<(), RepositoryError> {
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "INSERT OR REPLACE INTO object_provenance \
+                (object_id, recorded_at, ip, user_agent, token_type, \
+                token_id, server_version) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(provenance.recorded_at.timestamp_millis())
+            .bind(provenance.ip.as_deref())
+            .bind(provenance.user_agent.as_deref())
+            .bind(provenance.token_type.as_str())
+            .bind(provenance.token_id.as_deref())
+            .bind(provenance.server_version.as_str())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object provenance");
+    RepositoryError::Sqlx(error)
+}
+
+/// The client uploading object data, whose provenance is recorded if the
+/// repository extension is present, and whose data is post-processed if the
+/// [`Pipeline`] extension is.
+#[derive(Clone)]
+pub struct Uploader {
+    repo: Option<ProvenanceRepository<Sqlite>>,
+    pipeline: Option<Pipeline>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Uploader {
+    /// An uploader outside of any request, like the jobs resumed at startup.
+    pub fn detached(repo: Option<ProvenanceRepository<Sqlite>>) -> Self {
+        Self {
+            repo,
+            pipeline: None,
+            ip: None,
+            user_agent: None,
+        }
+    }
+
+    pub fn with_pipeline(mut self, pipeline: Option<Pipeline>) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// The provenance of data uploaded with `token`, or with a presigned
+    /// url if `None`.
+    pub fn provenance(&self, token: Option<&Token>) -> Provenance {
+        let (token_type, token_id) = TokenType::of(token);
+
+        Provenance {
+            recorded_at: Utc::now(),
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+            token_type,
+            token_id,
+            server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+
+    /// Records the provenance of the data just stored in the object `id`
+    /// and queues its post-processing. Failures are only logged, as the data
+    /// is already stored.
+    pub async fn record(&self, id: Uuid, provenance: &Provenance) {
+        if let Some(repo) = &self.repo {
+            if let Err(error) = repo.set(id, provenance).await {
+                tracing::error!(
+                    %error,
+                    %id,
+                    "failed to record object provenance",
+                );
+            }
+        }
+        if let Some(pipeline) = &self.pipeline {
+            if let Err(error) = pipeline.submit(id).await {
+                tracing::error!(%error, %id, "failed to queue post-processing");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Uploader {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = ClientIp::of(&parts.extensions, &parts.headers);
+        let ip = ip.map(|ip| ip.to_string());
+
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| match v.char_indices().nth(MAX_USER_AGENT_LEN) {
+                Some((i, _)) => v[..i].to_owned(),
+                None => v.to_owned(),
+            });
+
+        Ok(Uploader {
+            repo: parts
+                .extensions
+                .get::<ProvenanceRepository<Sqlite>>()
+                .cloned(),
+            pipeline: parts.extensions.get::<Pipeline>().cloned(),
+            ip,
+            user_agent,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_provenance() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ProvenanceRepository::new(db);
+        let id = Uuid::new_v4();
+
+        assert!(matches!(
+            repo.get(id).await,
+            Err(RepositoryError::NotFound(..)),
+        ));
+
+        let uploader = Uploader {
+            repo: Some(repo.clone()),
+            pipeline: None,
+            ip: Some("10.0.0.1".into()),
+            user_agent: Some("curl/8.0".into()),
+        };
+        uploader.record(id, &uploader.provenance(None)).await;
+
+        let provenance = repo.get(id).await.unwrap();
+        assert_eq!(provenance.token_type, TokenType::Presigned);
+        assert_eq!(provenance.ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(provenance.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(provenance.server_version, env!("CARGO_PKG_VERSION"));
+
+        let provenance = uploader.provenance(Some(&Token::Server));
+        uploader.record(id, &provenance).await;
+        assert_eq!(repo.get(id).await.unwrap().token_type, TokenType::Server);
+    }
+}
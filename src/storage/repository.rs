@@ -1,9 +1,21 @@
+use std::sync::Arc;
+
 use axum::http::StatusCode;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
 use uuid::Uuid;
 
-use super::{Object, ObjectData};
+use crate::{namespace::DEFAULT_NAMESPACE, utils::retry::retry_busy};
+
+use super::{
+    cache::{CacheUsage, ObjectCache},
+    intent::CreateIntent,
+    manager::DirUsage,
+    meta::{replace_metadata, Metadata},
+    name::{NameError, NamePolicy},
+    Object, ObjectData,
+};
 
 pub const MAX_LIMIT: u32 = 100;
 
@@ -15,6 +27,10 @@ pub enum RepositoryError {
     LimitOutOfRange(u32),
     #[error("sqlx error: {0}")]
     Sqlx(sqlx::Error),
+    #[error("object `{0}` not found")]
+    SlugNotFound(String),
+    #[error("object `{0}` was updated since the version given")]
+    VersionMismatch(Uuid),
 }
 
 impl RepositoryError {
@@ -24,21 +40,50 @@ impl RepositoryError {
             RepositoryError::NotFound(..) => StatusCode::NOT_FOUND,
             RepositoryError::LimitOutOfRange(..) => StatusCode::BAD_REQUEST,
             RepositoryError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            RepositoryError::SlugNotFound(..) => StatusCode::NOT_FOUND,
+            RepositoryError::VersionMismatch(..) => {
+                StatusCode::PRECONDITION_FAILED
+            }
         }
     }
+}
 
-    #[inline]
-    pub fn custom_code(&self) -> u8 {
-        match self {
-            RepositoryError::NotFound(..) => 1,
-            RepositoryError::LimitOutOfRange(..) => 2,
-            RepositoryError::Sqlx(..) => 3,
+crate::errors::error_codes! {
+    RepositoryError {
+        NotFound(..) => 1, "object not found";
+        LimitOutOfRange(..) => 2, "the limit is beyond the maximum";
+        Sqlx(..) => 3, "database error";
+        SlugNotFound(..) => 4, "no object has the slug";
+        VersionMismatch(..) => 5, "the object was updated since the version given";
+    }
+}
+
+/// Space used by the objects, and by the deleted ones whose data is kept
+/// until they are purged.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ObjectUsage {
+    pub stored: DirUsage,
+    pub trash: DirUsage,
+}
+
+impl ObjectUsage {
+    /// Space taken on disk, trash included.
+    pub fn total(&self) -> DirUsage {
+        DirUsage {
+            files: self.stored.files + self.trash.files,
+            bytes: self.stored.bytes + self.trash.bytes,
         }
     }
 }
 
 pub struct ObjectRepository<DB: Database> {
     db: Pool<DB>,
+    cache: Option<Arc<ObjectCache>>,
+    /// Only the objects in it are visible if set.
+    namespace: Option<Arc<str>>,
+    names: NamePolicy,
 }
 
 impl<DB: Database> Clone for ObjectRepository<DB> {
@@ -46,13 +91,73 @@ impl<DB: Database> Clone for ObjectRepository<DB> {
     fn clone(&self) -> Self {
         Self {
             db: self.db.clone(),
+            cache: self.cache.clone(),
+            namespace: self.namespace.clone(),
+            names: self.names,
         }
     }
 }
 
 impl<DB: Database> ObjectRepository<DB> {
     pub fn new(db: Pool<DB>) -> ObjectRepository<DB> {
-        ObjectRepository { db }
+        ObjectRepository {
+            db,
+            cache: None,
+            namespace: None,
+            names: NamePolicy::default(),
+        }
+    }
+
+    /// A repository of the objects in `namespace` only, which the new
+    /// objects are created in.
+    pub fn in_namespace(&self, namespace: &str) -> Self {
+        Self {
+            namespace: Some(namespace.into()),
+            ..self.clone()
+        }
+    }
+
+    /// The namespace new objects are created in.
+    pub fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+    }
+
+    /// Caches the objects returned by [`ObjectRepository::get`], keeping
+    /// them up to date with the changes made through this repository.
+    pub fn with_cache(mut self, cache: ObjectCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Validates the names of the objects created and renamed through the
+    /// routes with `names`.
+    pub fn with_name_policy(mut self, names: NamePolicy) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Returns `name` as it must be stored, or why it is not valid.
+    #[inline]
+    pub fn check_name(&self, name: &str) -> Result<String, NameError> {
+        self.names.apply(name)
+    }
+
+    /// Drops all the cached objects, must be called after changing objects
+    /// without using this repository.
+    pub fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    pub fn cache_usage(&self) -> Option<CacheUsage> {
+        self.cache.as_ref().map(|cache| cache.usage())
+    }
+
+    fn cache_insert(&self, object: &Object) {
+        if let Some(cache) = &self.cache {
+            cache.insert(object);
+        }
     }
 }
 
@@ -61,202 +166,1188 @@ where
     DB: Database,
     for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
     for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> Object: FromRow<'r, DB::Row>,
+    for<'r> CreateIntent: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> (i64, i64): FromRow<'r, DB::Row>,
+    for<'r> (i64, i64, i64, i64): FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+    for<'r> (String, i64, i64): FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>, i64, i64): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+    for<'e> Option<i64>: Encode<'e, DB>,
+
+    for<'e> String: Encode<'e, DB>,
+    String: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<Object, RepositoryError> {
+        if let Some(obj) = self.cache.as_ref().and_then(|c| c.get(id)) {
+            if self.namespace.is_some() && obj.namespace != self.namespace() {
+                return Err(RepositoryError::NotFound(id));
+            }
+            return Ok(obj);
+        }
+
+        let obj = sqlx::query_as(
+            "SELECT * FROM object \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2)",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while retrieving object");
+            RepositoryError::Sqlx(error)
+        })?
+        .ok_or(RepositoryError::NotFound(id))?;
+
+        self.cache_insert(&obj);
+        Ok(obj)
+    }
+
+    pub async fn get_all(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<Object>, RepositoryError> {
+        if limit > MAX_LIMIT {
+            return Err(RepositoryError::LimitOutOfRange(limit));
+        }
+
+        sqlx::query_as(
+            "SELECT * FROM object \
+            WHERE rowid > $1 AND ($3 IS NULL OR namespace = $3) \
+            ORDER BY rowid LIMIT $2",
+        )
+        .bind(offset as i64)
+        .bind(limit as i64)
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving multiple objects",
+            );
+            RepositoryError::Sqlx(error)
+        })
+    }
+
+    /// Offset of [`ObjectRepository::get_all`] continuing the listing
+    /// after the object `id`, as offsets are positions in the table.
+    pub async fn position(&self, id: Uuid) -> Result<u64, RepositoryError> {
+        let (rowid,): (i64,) =
+            sqlx::query_as("SELECT rowid FROM object WHERE id = $1")
+                .bind(id.into_bytes().as_slice())
+                .fetch_optional(&self.db)
+                .await
+                .map_err(|error| {
+                    tracing::error!(
+                        %error,
+                        "got sqlx error while retrieving object position",
+                    );
+                    RepositoryError::Sqlx(error)
+                })?
+                .ok_or(RepositoryError::NotFound(id))?;
+
+        Ok(rowid as u64)
+    }
+
+    pub async fn get_by_user(
+        &self,
+        user_id: Uuid,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<Object>, RepositoryError> {
+        if limit > MAX_LIMIT {
+            return Err(RepositoryError::LimitOutOfRange(limit));
+        }
+
+        sqlx::query_as(
+            "SELECT * FROM object \
+            WHERE user_id = $1 AND ($4 IS NULL OR namespace = $4) \
+            ORDER BY rowid LIMIT $2 OFFSET $3",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving multiple user objects",
+            );
+            RepositoryError::Sqlx(error)
+        })
+    }
+
+    pub async fn count_by_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<u64, RepositoryError> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM object \
+            WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2)",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while counting user objects",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(count as u64)
+    }
+
+    /// Returns the oldest object of the user with the sha256 `checksum`.
+    pub async fn get_by_checksum(
+        &self,
+        user_id: Uuid,
+        checksum: &[u8; 32],
+    ) -> Result<Option<Object>, RepositoryError> {
+        sqlx::query_as(
+            "SELECT * FROM object \
+            WHERE user_id = $1 AND checksum_256 = $2 \
+            AND ($3 IS NULL OR namespace = $3) \
+            ORDER BY rowid LIMIT 1",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(checksum.as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving object by checksum",
+            );
+            RepositoryError::Sqlx(error)
+        })
+    }
+
+    /// Returns a counter incremented on every change to the objects owned
+    /// by the user, zero if it never owned any.
+    pub async fn get_version(
+        &self,
+        user_id: Uuid,
+    ) -> Result<u64, RepositoryError> {
+        let version: Option<(i64,)> = sqlx::query_as(
+            "SELECT version FROM object_version WHERE user_id = $1",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving object version",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(version.map_or(0, |(v,)| v as u64))
+    }
+
+    pub async fn get_largest(
+        &self,
+        limit: u32,
+    ) -> Result<Vec<Object>, RepositoryError> {
+        if limit > MAX_LIMIT {
+            return Err(RepositoryError::LimitOutOfRange(limit));
+        }
+
+        sqlx::query_as(
+            "SELECT * FROM object WHERE $2 IS NULL OR namespace = $2 \
+            ORDER BY size DESC LIMIT $1",
+        )
+        .bind(limit as i64)
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving largest objects",
+            );
+            RepositoryError::Sqlx(error)
+        })
+    }
+
+    /// Space used by the deleted objects that can still be restored.
+    pub async fn get_trash_usage(&self) -> Result<DirUsage, RepositoryError> {
+        let (files, bytes): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM deleted_object \
+            WHERE $1 IS NULL OR namespace = $1",
+        )
+        .bind(self.namespace.as_deref())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving trash usage",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(DirUsage {
+            files: files as u64,
+            bytes: bytes as u64,
+        })
+    }
+
+    /// Space used by all the objects, trash included.
+    pub async fn total_usage(&self) -> Result<ObjectUsage, RepositoryError> {
+        let usage = sqlx::query_as(
+            "SELECT \
+            (SELECT COUNT(*) FROM object \
+            WHERE $1 IS NULL OR namespace = $1), \
+            (SELECT COALESCE(SUM(size), 0) FROM object \
+            WHERE $1 IS NULL OR namespace = $1), \
+            (SELECT COUNT(*) FROM deleted_object \
+            WHERE $1 IS NULL OR namespace = $1), \
+            (SELECT COALESCE(SUM(size), 0) FROM deleted_object \
+            WHERE $1 IS NULL OR namespace = $1)",
+        )
+        .bind(self.namespace.as_deref())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving total usage",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(object_usage(usage))
+    }
+
+    /// Space used by the objects of the user `user_id`, trash included.
+    pub async fn usage_by_user(
+        &self,
+        user_id: Uuid,
+    ) -> Result<ObjectUsage, RepositoryError> {
+        let usage = sqlx::query_as(
+            "SELECT \
+            (SELECT COUNT(*) FROM object \
+            WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2)), \
+            (SELECT COALESCE(SUM(size), 0) FROM object \
+            WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2)), \
+            (SELECT COUNT(*) FROM deleted_object \
+            WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2)), \
+            (SELECT COALESCE(SUM(size), 0) FROM deleted_object \
+            WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2))",
+        )
+        .bind(user_id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                %user_id,
+                "got sqlx error while retrieving user usage",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(object_usage(usage))
+    }
+
+    /// Amount and total size of the objects created since `since`, all
+    /// of them if `None`.
+    pub async fn get_usage(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<DirUsage, RepositoryError> {
+        let since = since.map_or(i64::MIN, |since| since.timestamp_millis());
+        let (files, bytes): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM object \
+            WHERE created_at >= $1 AND ($2 IS NULL OR namespace = $2)",
+        )
+        .bind(since)
+        .bind(self.namespace.as_deref())
+        .fetch_one(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving object usage",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(DirUsage {
+            files: files as u64,
+            bytes: bytes as u64,
+        })
+    }
+
+    /// Usage of the objects by the part of their mime type before the
+    /// slash, the largest first.
+    pub async fn get_usage_by_mime_family(
+        &self,
+    ) -> Result<Vec<(String, DirUsage)>, RepositoryError> {
+        let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+            "SELECT CASE WHEN instr(mime_type, '/') > 0 \
+            THEN substr(mime_type, 1, instr(mime_type, '/') - 1) \
+            ELSE mime_type END AS family, COUNT(*), SUM(size) AS bytes \
+            FROM object WHERE $1 IS NULL OR namespace = $1 \
+            GROUP BY family ORDER BY bytes DESC",
+        )
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving usage by mime type",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(family, files, bytes)| {
+                let usage = DirUsage {
+                    files: files as u64,
+                    bytes: bytes as u64,
+                };
+                (family, usage)
+            })
+            .collect())
+    }
+
+    /// The `limit` users whose objects use the most space, the largest
+    /// first.
+    pub async fn get_top_users(
+        &self,
+        limit: u32,
+    ) -> Result<Vec<(Uuid, DirUsage)>, RepositoryError> {
+        if limit > MAX_LIMIT {
+            return Err(RepositoryError::LimitOutOfRange(limit));
+        }
+
+        let rows: Vec<(Vec<u8>, i64, i64)> = sqlx::query_as(
+            "SELECT user_id, COUNT(*), SUM(size) AS bytes FROM object \
+            WHERE $2 IS NULL OR namespace = $2 \
+            GROUP BY user_id ORDER BY bytes DESC LIMIT $1",
+        )
+        .bind(limit as i64)
+        .bind(self.namespace.as_deref())
+        .fetch_all(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving top users",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        rows.into_iter()
+            .map(|(user_id, files, bytes)| {
+                let user_id = Uuid::from_slice(&user_id).map_err(|_| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse `user_id` uuid out of range".into(),
+                    ))
+                })?;
+                let usage = DirUsage {
+                    files: files as u64,
+                    bytes: bytes as u64,
+                };
+                Ok((user_id, usage))
+            })
+            .collect()
+    }
+
+    /// Creates the object, completing its creation if begun with
+    /// [`ObjectRepository::begin_create`].
+    pub async fn create(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        data: ObjectData,
+    ) -> Result<Object, RepositoryError> {
+        let mut objects = self.create_batch(user_id, vec![(id, data)]).await?;
+        objects.pop().ok_or(RepositoryError::NotFound(id))
+    }
+
+    /// Creates all the objects in a single transaction, completing their
+    /// creations if begun with [`ObjectRepository::begin_create_batch`].
+    pub async fn create_batch(
+        &self,
+        user_id: Uuid,
+        objects: Vec<(Uuid, ObjectData)>,
+    ) -> Result<Vec<Object>, RepositoryError> {
+        let objects = objects
+            .into_iter()
+            .map(|(id, data)| {
+                let size: i64 = data.size.try_into().map_err(|_| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "encode `size`: out of range".into(),
+                    ))
+                })?;
+                Ok((id, data, size))
+            })
+            .collect::<Result<Vec<_>, RepositoryError>>()?;
+
+        retry_busy(|| self.create_batch_once(user_id, &objects))
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, "got sqlx error while creating object");
+                RepositoryError::Sqlx(error)
+            })
+    }
+
+    async fn create_batch_once(
+        &self,
+        user_id: Uuid,
+        objects: &[(Uuid, ObjectData, i64)],
+    ) -> Result<Vec<Object>, sqlx::Error> {
+        let now_ms = Utc::now().timestamp_millis();
+        let user_id_bytes = user_id.into_bytes();
+        let mut tx = self.db.begin().await?;
+
+        let mut created = Vec::with_capacity(objects.len());
+        for (id, data, size) in objects {
+            let id_bytes = id.into_bytes();
+
+            let obj = sqlx::query_as(
+                "INSERT INTO object \
+                (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256, namespace) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+                RETURNING *",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(user_id_bytes.as_slice())
+            .bind(now_ms)
+            .bind(now_ms)
+            .bind(data.name.as_str())
+            .bind(data.mime_type.as_str())
+            .bind(*size)
+            .bind(data.checksum_256.as_slice())
+            .bind(self.namespace())
+            .fetch_one(&mut *tx)
+            .await?;
+
+            sqlx::query("DELETE FROM object_intent WHERE id = $1")
+                .bind(id_bytes.as_slice())
+                .execute(&mut *tx)
+                .await?;
+
+            created.push(obj);
+        }
+
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    /// Records that the object is about to be created, before its data is
+    /// stored. The creation must then be committed with
+    /// [`ObjectRepository::commit_create`] once the data is in place and
+    /// completed with [`ObjectRepository::create`], or aborted, so the
+    /// creations interrupted by a crash can be finished on startup.
+    pub async fn begin_create(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<(), RepositoryError> {
+        self.begin_create_batch(user_id, &[(id, name, mime_type)])
+            .await
+    }
+
+    /// Like [`ObjectRepository::begin_create`] for many objects at once,
+    /// given their id, name and mime type. The creations are only aborted
+    /// after a crash unless committed one by one.
+    pub async fn begin_create_batch(
+        &self,
+        user_id: Uuid,
+        objects: &[(Uuid, &str, &str)],
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.begin_create_batch_once(user_id, objects))
+            .await
+            .map_err(intent_error)
+    }
+
+    async fn begin_create_batch_once(
+        &self,
+        user_id: Uuid,
+        objects: &[(Uuid, &str, &str)],
+    ) -> Result<(), sqlx::Error> {
+        let now_ms = Utc::now().timestamp_millis();
+        let user_id_bytes = user_id.into_bytes();
+        let mut tx = self.db.begin().await?;
+
+        for &(id, name, mime_type) in objects {
+            sqlx::query(
+                "INSERT INTO object_intent \
+                (id, user_id, namespace, name, mime_type, state, created_at) \
+                VALUES ($1, $2, $3, $4, $5, 0, $6)",
+            )
+            .bind(id.into_bytes().as_slice())
+            .bind(user_id_bytes.as_slice())
+            .bind(self.namespace())
+            .bind(name)
+            .bind(mime_type)
+            .bind(now_ms)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Marks the creation committed once the data of the object is in
+    /// place, so it is completed instead of aborted after a crash.
+    pub async fn commit_create(
+        &self,
+        id: Uuid,
+        size: u64,
+        checksum_256: [u8; 32],
+    ) -> Result<(), RepositoryError> {
+        let size: i64 = size.try_into().map_err(|_| {
+            RepositoryError::Sqlx(sqlx::Error::Decode(
+                "encode `size`: out of range".into(),
+            ))
+        })?;
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "UPDATE object_intent \
+                SET state = 1, size = $1, checksum_256 = $2 WHERE id = $3",
+            )
+            .bind(size)
+            .bind(checksum_256.as_slice())
+            .bind(id_bytes.as_slice())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(intent_error)?;
+
+        Ok(())
+    }
+
+    /// Forgets the creation, whose data must not be in place anymore.
+    pub async fn abort_create(&self, id: Uuid) -> Result<(), RepositoryError> {
+        self.abort_create_batch(&[id]).await
+    }
+
+    /// Forgets the creations of all the objects, in a single transaction.
+    pub async fn abort_create_batch(
+        &self,
+        ids: &[Uuid],
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.abort_create_batch_once(ids))
+            .await
+            .map_err(intent_error)
+    }
+
+    async fn abort_create_batch_once(
+        &self,
+        ids: &[Uuid],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        for id in ids {
+            sqlx::query("DELETE FROM object_intent WHERE id = $1")
+                .bind(id.into_bytes().as_slice())
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// The creations begun and not completed nor aborted yet, in all the
+    /// namespaces.
+    pub async fn create_intents(
+        &self,
+    ) -> Result<Vec<CreateIntent>, RepositoryError> {
+        sqlx::query_as("SELECT * FROM object_intent ORDER BY created_at")
+            .fetch_all(&self.db)
+            .await
+            .map_err(intent_error)
+    }
+
+    /// Creates an object copied from another instance, keeping its id and
+    /// timestamps.
+    pub async fn import(
+        &self,
+        object: &Object,
+    ) -> Result<Object, RepositoryError> {
+        let size: i64 = object.data.size.try_into().map_err(|_| {
+            RepositoryError::Sqlx(sqlx::Error::Decode(
+                "encode `size`: out of range".into(),
+            ))
+        })?;
+
+        let id_bytes = object.id.into_bytes();
+        let user_id_bytes = object.user_id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO object \
+                (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256, namespace, description) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+                RETURNING *",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(user_id_bytes.as_slice())
+            .bind(object.created_at.timestamp_millis())
+            .bind(object.updated_at.timestamp_millis())
+            .bind(object.data.name.clone())
+            .bind(object.data.mime_type.clone())
+            .bind(size)
+            .bind(object.data.checksum_256.as_slice())
+            .bind(object.namespace.as_str())
+            .bind(object.description.as_deref())
+            .fetch_one(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while importing object");
+            RepositoryError::Sqlx(error)
+        })
+    }
+
+    /// Whether `id` is used by an object, deleted or not.
+    pub async fn exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        let row: Option<(Vec<u8>,)> = sqlx::query_as(
+            "SELECT id FROM object WHERE id = $1 \
+            UNION ALL SELECT id FROM deleted_object WHERE id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                %error,
+                "got sqlx error while checking object existence",
+            );
+            RepositoryError::Sqlx(error)
+        })?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn update(
+        &self,
+        id: Uuid,
+        data: ObjectData,
+    ) -> Result<Object, RepositoryError> {
+        let now = Utc::now();
+        let now_ms = now.timestamp_millis();
+
+        let id_bytes = id.into_bytes();
+        let obj = retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE object \
+                SET updated_at = $1, name = $2, mime_type = $3, \
+                size = $4, checksum_256 = $5, version = version + 1 \
+                WHERE id = $6 AND ($7 IS NULL OR namespace = $7) \
+                RETURNING *",
+            )
+            .bind(now_ms)
+            .bind(data.name.clone())
+            .bind(data.mime_type.clone())
+            .bind(data.size as i64)
+            .bind(data.checksum_256.as_slice())
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while updating object");
+            RepositoryError::Sqlx(error)
+        })?
+        .ok_or(RepositoryError::NotFound(id))?;
+
+        self.cache_insert(&obj);
+        Ok(obj)
+    }
+
+    /// Leaves the description unchanged if `None`, removing it if empty.
+    ///
+    /// Only updates the object if still at `version`, if given, failing
+    /// with [`RepositoryError::VersionMismatch`] otherwise.
+    pub async fn update_info(
+        &self,
+        id: Uuid,
+        name: String,
+        mime_type: String,
+        description: Option<&str>,
+        version: Option<u64>,
+    ) -> Result<Object, RepositoryError> {
+        let now = Utc::now();
+        let now_ms = now.timestamp_millis();
+
+        let id_bytes = id.into_bytes();
+        let obj: Option<Object> = retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE object \
+                SET updated_at = $1, name = $2, mime_type = $3, \
+                description = NULLIF(COALESCE($6, description), ''), \
+                version = version + 1 \
+                WHERE id = $4 AND ($5 IS NULL OR namespace = $5) \
+                AND ($7 IS NULL OR version = $7) \
+                RETURNING *",
+            )
+            .bind(now_ms)
+            .bind(name.clone())
+            .bind(mime_type.clone())
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .bind(description)
+            .bind(version.map(|v| v as i64))
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while updating object");
+            RepositoryError::Sqlx(error)
+        })?;
+
+        let Some(obj) = obj else {
+            // Missing or at another version, told apart only when needed
+            if version.is_none() {
+                return Err(RepositoryError::NotFound(id));
+            }
+            let current: Option<(i64,)> = sqlx::query_as(
+                "SELECT version FROM object \
+                WHERE id = $1 AND ($2 IS NULL OR namespace = $2)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, "got sqlx error while updating object");
+                RepositoryError::Sqlx(error)
+            })?;
+            return Err(match current {
+                Some(..) => RepositoryError::VersionMismatch(id),
+                None => RepositoryError::NotFound(id),
+            });
+        };
+
+        self.cache_insert(&obj);
+        Ok(obj)
+    }
+
+    /// Hands the object over to the user `user_id`, which must exist in
+    /// the namespace of the object.
+    pub async fn transfer(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Object, RepositoryError> {
+        let id_bytes = id.into_bytes();
+        let user_id_bytes = user_id.into_bytes();
+        let obj = retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE object \
+                SET user_id = $1, updated_at = $2, version = version + 1 \
+                WHERE id = $3 AND ($4 IS NULL OR namespace = $4) \
+                RETURNING *",
+            )
+            .bind(user_id_bytes.as_slice())
+            .bind(Utc::now().timestamp_millis())
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while transferring object");
+            RepositoryError::Sqlx(error)
+        })?
+        .ok_or(RepositoryError::NotFound(id))?;
+
+        self.cache_insert(&obj);
+        Ok(obj)
+    }
+
+    /// Updates the data and info of the object, replacing its custom
+    /// metadata if set, all in a single transaction. The description is
+    /// handled like by [`ObjectRepository::update_info`].
+    pub async fn update_full(
+        &self,
+        id: Uuid,
+        data: ObjectData,
+        description: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> Result<Object, RepositoryError> {
+        let obj = retry_busy(|| {
+            self.update_full_once(id, &data, description, metadata)
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while updating object");
+            RepositoryError::Sqlx(error)
+        })?
+        .ok_or(RepositoryError::NotFound(id))?;
+
+        self.cache_insert(&obj);
+        Ok(obj)
+    }
 
-    for<'r> Object: FromRow<'r, DB::Row>,
+    async fn update_full_once(
+        &self,
+        id: Uuid,
+        data: &ObjectData,
+        description: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> Result<Option<Object>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
 
-    for<'e> &'e [u8]: Encode<'e, DB>,
-    for<'e> &'e [u8]: Type<DB>,
+        let obj: Option<Object> = sqlx::query_as(
+            "UPDATE object \
+            SET updated_at = $1, name = $2, mime_type = $3, \
+            size = $4, checksum_256 = $5, version = version + 1, \
+            description = NULLIF(COALESCE($8, description), '') \
+            WHERE id = $6 AND ($7 IS NULL OR namespace = $7) \
+            RETURNING *",
+        )
+        .bind(Utc::now().timestamp_millis())
+        .bind(data.name.as_str())
+        .bind(data.mime_type.as_str())
+        .bind(data.size as i64)
+        .bind(data.checksum_256.as_slice())
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .bind(description)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(obj) = obj else {
+            return Ok(None);
+        };
+
+        if let Some(metadata) = metadata {
+            replace_metadata::<DB>(&mut tx, id, metadata).await?;
+        }
 
-    for<'e> i64: Encode<'e, DB>,
-    i64: Type<DB>,
+        tx.commit().await?;
 
-    for<'e> String: Encode<'e, DB>,
-    String: Type<DB>,
-{
-    pub async fn get(&self, id: Uuid) -> Result<Object, RepositoryError> {
-        sqlx::query_as("SELECT * FROM object WHERE id = $1")
-            .bind(id.into_bytes().as_slice())
-            .fetch_optional(&self.db)
-            .await
-            .map_err(|error| {
-                tracing::error!(
-                    %error,
-                    "got sqlx error while retrieving object",
-                );
-                RepositoryError::Sqlx(error)
-            })?
-            .ok_or(RepositoryError::NotFound(id))
+        Ok(Some(obj))
     }
 
-    pub async fn get_all(
-        &self,
-        limit: u32,
-        offset: u32,
-    ) -> Result<Vec<Object>, RepositoryError> {
-        if limit > MAX_LIMIT {
-            return Err(RepositoryError::LimitOutOfRange(limit));
+    pub async fn delete(&self, id: Uuid) -> Result<Object, RepositoryError> {
+        if let Some(cache) = &self.cache {
+            cache.remove(id);
         }
 
-        sqlx::query_as(
-            "SELECT * FROM object WHERE rowid > $1 \
-            ORDER BY rowid LIMIT $2",
-        )
-        .bind(offset as i64)
-        .bind(limit as i64)
-        .fetch_all(&self.db)
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "DELETE FROM object \
+                WHERE id = $1 AND ($2 IS NULL OR namespace = $2) RETURNING *",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .fetch_optional(&self.db)
+        })
         .await
         .map_err(|error| {
-            tracing::error!(
-                %error,
-                "got sqlx error while retrieving multiple objects",
-            );
+            tracing::error!(%error, "got sqlx error while deleting object");
             RepositoryError::Sqlx(error)
-        })
+        })?
+        .ok_or(RepositoryError::NotFound(id))
     }
 
-    pub async fn get_by_user(
+    /// Deletes up to `limit` objects of the user `user_id`, returning their
+    /// ids so the data can be removed. Called until it returns none to
+    /// delete them all without holding the database for long.
+    pub async fn delete_by_user(
         &self,
         user_id: Uuid,
         limit: u32,
-        offset: u32,
-    ) -> Result<Vec<Object>, RepositoryError> {
-        if limit > MAX_LIMIT {
-            return Err(RepositoryError::LimitOutOfRange(limit));
-        }
-
-        sqlx::query_as(
-            "SELECT * FROM object WHERE user_id = $1 \
-            ORDER BY rowid LIMIT $2 OFFSET $3",
-        )
-        .bind(user_id.into_bytes().as_slice())
-        .bind(limit as i64)
-        .bind(offset as i64)
-        .fetch_all(&self.db)
+    ) -> Result<Vec<Uuid>, RepositoryError> {
+        let user_id_bytes = user_id.into_bytes();
+        let ids: Vec<(Vec<u8>,)> = retry_busy(|| {
+            sqlx::query_as(
+                "DELETE FROM object WHERE rowid IN \
+                (SELECT rowid FROM object \
+                WHERE user_id = $1 AND ($2 IS NULL OR namespace = $2) \
+                LIMIT $3) \
+                RETURNING id",
+            )
+            .bind(user_id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .bind(limit as i64)
+            .fetch_all(&self.db)
+        })
         .await
         .map_err(|error| {
             tracing::error!(
                 %error,
-                "got sqlx error while retrieving multiple user objects",
+                %user_id,
+                "got sqlx error while deleting user objects",
             );
             RepositoryError::Sqlx(error)
-        })
+        })?;
+
+        let ids = ids
+            .into_iter()
+            .map(|(id,)| {
+                Uuid::from_slice(&id).map_err(|_| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse `id` uuid out of range".into(),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(cache) = &self.cache {
+            for id in &ids {
+                cache.remove(*id);
+            }
+        }
+        Ok(ids)
     }
 
-    pub async fn create(
+    /// Deletes the object, keeping its entry so it can be restored with
+    /// [`ObjectRepository::undelete`] until purged.
+    pub async fn trash(&self, id: Uuid) -> Result<Object, RepositoryError> {
+        if let Some(cache) = &self.cache {
+            cache.remove(id);
+        }
+
+        retry_busy(|| self.trash_once(id))
+            .await
+            .map_err(trash_error)?
+            .ok_or(RepositoryError::NotFound(id))
+    }
+
+    async fn trash_once(
         &self,
         id: Uuid,
-        user_id: Uuid,
-        data: ObjectData,
-    ) -> Result<Object, RepositoryError> {
-        let now_ms = Utc::now().timestamp_millis();
+    ) -> Result<Option<Object>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
 
-        let size: i64 = data.size.try_into().map_err(|_| {
-            RepositoryError::Sqlx(sqlx::Error::Decode(
-                format!("encode `size`: out of range").into(),
-            ))
-        })?;
-
-        sqlx::query_as(
-            "INSERT INTO object \
-            (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256) \
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
-            RETURNING *",
+        let obj: Option<Object> = sqlx::query_as(
+            "DELETE FROM object \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2) RETURNING *",
         )
         .bind(id.into_bytes().as_slice())
-        .bind(user_id.into_bytes().as_slice())
-        .bind(now_ms)
-        .bind(now_ms)
-        .bind(data.name)
-        .bind(data.mime_type)
-        .bind(size)
-        .bind(data.checksum_256.as_slice())
-        .fetch_one(&self.db)
-        .await
-        .map_err(|error| {
-            tracing::error!(%error, "got sqlx error while creating object");
-            RepositoryError::Sqlx(error)
-        })
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(obj) = obj else {
+            return Ok(None);
+        };
+
+        sqlx::query(
+            "INSERT INTO deleted_object \
+            (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256, deleted_at, namespace, description, version) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        )
+        .bind(obj.id.into_bytes().as_slice())
+        .bind(obj.user_id.into_bytes().as_slice())
+        .bind(obj.created_at.timestamp_millis())
+        .bind(obj.updated_at.timestamp_millis())
+        .bind(obj.data.name.clone())
+        .bind(obj.data.mime_type.clone())
+        .bind(obj.data.size as i64)
+        .bind(obj.data.checksum_256.as_slice())
+        .bind(Utc::now().timestamp_millis())
+        .bind(obj.namespace.as_str())
+        .bind(obj.description.as_deref())
+        .bind(obj.version as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(obj))
     }
 
-    pub async fn update(
+    /// Gets an object deleted after `since`.
+    pub async fn get_deleted(
         &self,
         id: Uuid,
-        data: ObjectData,
+        since: DateTime<Utc>,
     ) -> Result<Object, RepositoryError> {
-        let now = Utc::now();
-        let now_ms = now.timestamp_millis();
-
         sqlx::query_as(
-            "UPDATE object \
-            SET updated_at = $1, name = $2, mime_type = $3, \
-            size = $4, checksum_256 = $5 \
-            WHERE id = $6 RETURNING *",
+            "SELECT * FROM deleted_object \
+            WHERE id = $1 AND deleted_at >= $2 \
+            AND ($3 IS NULL OR namespace = $3)",
         )
-        .bind(now_ms)
-        .bind(data.name)
-        .bind(data.mime_type)
-        .bind(data.size as i64)
-        .bind(data.checksum_256.as_slice())
         .bind(id.into_bytes().as_slice())
+        .bind(since.timestamp_millis())
+        .bind(self.namespace.as_deref())
         .fetch_optional(&self.db)
         .await
         .map_err(|error| {
-            tracing::error!(%error, "got sqlx error while updating object");
+            tracing::error!(
+                %error,
+                "got sqlx error while retrieving deleted object",
+            );
             RepositoryError::Sqlx(error)
         })?
         .ok_or(RepositoryError::NotFound(id))
     }
 
-    pub async fn update_info(
+    /// Restores an object deleted after `since`.
+    pub async fn undelete(
         &self,
         id: Uuid,
-        name: String,
-        mime_type: String,
+        since: DateTime<Utc>,
     ) -> Result<Object, RepositoryError> {
-        let now = Utc::now();
-        let now_ms = now.timestamp_millis();
+        retry_busy(|| self.undelete_once(id, since))
+            .await
+            .map_err(undelete_error)?
+            .ok_or(RepositoryError::NotFound(id))
+    }
 
-        sqlx::query_as(
-            "UPDATE object \
-            SET updated_at = $1, name = $2, mime_type = $3
-            WHERE id = $4 RETURNING *",
+    async fn undelete_once(
+        &self,
+        id: Uuid,
+        since: DateTime<Utc>,
+    ) -> Result<Option<Object>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        let obj: Option<Object> = sqlx::query_as(
+            "DELETE FROM deleted_object WHERE id = $1 AND deleted_at >= $2 \
+            AND ($3 IS NULL OR namespace = $3) RETURNING *",
         )
-        .bind(now_ms)
-        .bind(name)
-        .bind(mime_type)
         .bind(id.into_bytes().as_slice())
-        .fetch_optional(&self.db)
+        .bind(since.timestamp_millis())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(obj) = obj else {
+            return Ok(None);
+        };
+
+        let obj = sqlx::query_as(
+            "INSERT INTO object \
+            (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256, namespace, description, version) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+            RETURNING *",
+        )
+        .bind(obj.id.into_bytes().as_slice())
+        .bind(obj.user_id.into_bytes().as_slice())
+        .bind(obj.created_at.timestamp_millis())
+        .bind(obj.updated_at.timestamp_millis())
+        .bind(obj.data.name)
+        .bind(obj.data.mime_type)
+        .bind(obj.data.size as i64)
+        .bind(obj.data.checksum_256.as_slice())
+        .bind(obj.namespace)
+        .bind(obj.description.as_deref())
+        .bind(obj.version as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(obj))
+    }
+
+    /// Drops the entries of objects deleted before `before`, returning
+    /// their ids so the data can be removed.
+    pub async fn purge_deleted(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Uuid>, RepositoryError> {
+        let ids: Vec<(Vec<u8>,)> = retry_busy(|| {
+            sqlx::query_as(
+                "DELETE FROM deleted_object WHERE deleted_at < $1 RETURNING id",
+            )
+            .bind(before.timestamp_millis())
+            .fetch_all(&self.db)
+        })
         .await
         .map_err(|error| {
-            tracing::error!(%error, "got sqlx error while updating object");
+            tracing::error!(
+                %error,
+                "got sqlx error while purging deleted objects",
+            );
             RepositoryError::Sqlx(error)
-        })?
-        .ok_or(RepositoryError::NotFound(id))
+        })?;
+
+        ids.into_iter()
+            .map(|(id,)| {
+                Uuid::from_slice(&id).map_err(|_| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse `id` uuid out of range".into(),
+                    ))
+                })
+            })
+            .collect()
     }
+}
 
-    pub async fn delete(&self, id: Uuid) -> Result<Object, RepositoryError> {
-        sqlx::query_as("DELETE FROM object WHERE id = $1 RETURNING *")
-            .bind(id.into_bytes().as_slice())
-            .fetch_optional(&self.db)
-            .await
-            .map_err(|error| {
-                tracing::error!(%error, "got sqlx error while deleting object");
-                RepositoryError::Sqlx(error)
-            })?
-            .ok_or(RepositoryError::NotFound(id))
+fn intent_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object intents");
+    RepositoryError::Sqlx(error)
+}
+
+fn trash_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while trashing object");
+    RepositoryError::Sqlx(error)
+}
+
+fn undelete_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while restoring object");
+    RepositoryError::Sqlx(error)
+}
+
+fn object_usage(
+    (files, bytes, trash_files, trash_bytes): (i64, i64, i64, i64),
+) -> ObjectUsage {
+    ObjectUsage {
+        stored: DirUsage {
+            files: files as u64,
+            bytes: bytes as u64,
+        },
+        trash: DirUsage {
+            files: trash_files as u64,
+            bytes: trash_bytes as u64,
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use sha2::{Digest, Sha256};
     use sqlx::{migrate, Pool, Sqlite};
     use test_log::test;
     use uuid::Uuid;
 
-    use crate::storage::{repository::RepositoryError, ObjectData};
+    use crate::storage::{
+        cache::ObjectCache, manager::DirUsage, repository::RepositoryError,
+        ObjectData,
+    };
 
-    use super::ObjectRepository;
+    use super::{ObjectRepository, ObjectUsage};
 
     fn rand_string() -> String {
         Uuid::new_v4().to_string()
@@ -294,6 +1385,12 @@ mod tests {
         }
     }
 
+    /// Timestamps are stored in milliseconds, so updates must happen at
+    /// least one millisecond after creation to be observable.
+    async fn wait_next_ms() {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+
     async fn repository() -> ObjectRepository<Sqlite> {
         let db = Pool::connect("sqlite::memory:").await.unwrap();
         migrate!().run(&db).await.unwrap();
@@ -421,6 +1518,64 @@ mod tests {
         assert!(all_data.into_iter().map(|v| (v.id, v.data)).eq(datas));
     }
 
+    #[test(tokio::test)]
+    async fn test_delete_by_user() {
+        let repo = repository().await;
+        let user_id = Uuid::new_v4();
+
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let id = Uuid::new_v4();
+            repo.create(id, user_id, rand_data()).await.unwrap();
+            ids.push(id);
+        }
+        let other = Uuid::new_v4();
+        repo.create(other, Uuid::new_v4(), rand_data())
+            .await
+            .unwrap();
+
+        let mut deleted = Vec::new();
+        loop {
+            let batch = repo.delete_by_user(user_id, 2).await.unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            assert!(batch.len() <= 2);
+            deleted.extend(batch);
+        }
+
+        deleted.sort();
+        ids.sort();
+        assert_eq!(deleted, ids);
+        assert!(repo.get_by_user(user_id, 10, 0).await.unwrap().is_empty());
+        assert!(repo.exists(other).await.unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn test_count_by_user() {
+        const SIZE: usize = 7;
+
+        let repo = repository().await;
+        let user_id = Uuid::new_v4();
+
+        assert_eq!(repo.count_by_user(user_id).await.unwrap(), 0);
+
+        for _ in 0..SIZE {
+            repo.create(Uuid::new_v4(), user_id, rand_data())
+                .await
+                .unwrap();
+        }
+        repo.create(Uuid::new_v4(), Uuid::new_v4(), rand_data())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            repo.count_by_user(user_id).await.unwrap(),
+            SIZE as u64,
+            "counted objects mismatch the created ones",
+        );
+    }
+
     #[test(tokio::test)]
     async fn test_create() {
         let repo = repository().await;
@@ -456,6 +1611,7 @@ mod tests {
 
         let mut old_obj = obj.clone();
 
+        wait_next_ms().await;
         let obj = repo.update(obj.id, data.clone()).await.unwrap();
         assert!(
             obj.updated_at > old_obj.updated_at,
@@ -463,6 +1619,7 @@ mod tests {
         );
         old_obj.updated_at = obj.updated_at;
         old_obj.data = data;
+        old_obj.version += 1;
 
         assert_eq!(obj, old_obj, "updated data mismatches the provided one");
 
@@ -483,8 +1640,15 @@ mod tests {
         let new_name = rand_string();
         let new_mime_type = rand_mime();
 
+        wait_next_ms().await;
         let obj = repo
-            .update_info(old_obj.id, new_name.clone(), new_mime_type.clone())
+            .update_info(
+                old_obj.id,
+                new_name.clone(),
+                new_mime_type.clone(),
+                Some("a fox"),
+                None,
+            )
             .await
             .unwrap();
 
@@ -493,11 +1657,50 @@ mod tests {
         old_obj.data.name = new_name;
         old_obj.data.mime_type = new_mime_type;
         old_obj.updated_at = obj.updated_at;
+        old_obj.description = Some("a fox".into());
+        old_obj.version += 1;
 
         assert_eq!(obj, old_obj);
 
         let obj = repo.get(old_obj.id).await.unwrap();
         assert_eq!(obj, old_obj);
+
+        // The description is kept unless given, and removed if empty
+        let (name, mime_type) = (obj.data.name, obj.data.mime_type);
+        let obj = repo
+            .update_info(obj.id, name.clone(), mime_type.clone(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(obj.description.as_deref(), Some("a fox"));
+        let obj = repo
+            .update_info(
+                obj.id,
+                name.clone(),
+                mime_type.clone(),
+                Some(""),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(obj.description, None);
+        assert_eq!(obj.version, 3);
+
+        // Only updated while at the given version
+        let stale = Some(obj.version - 1);
+        let res = repo
+            .update_info(obj.id, rand_string(), mime_type.clone(), None, stale)
+            .await;
+        assert!(matches!(res, Err(RepositoryError::VersionMismatch(..))));
+        let current = Some(obj.version);
+        let updated = repo
+            .update_info(obj.id, name.clone(), mime_type.clone(), None, current)
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 4);
+        let res = repo
+            .update_info(Uuid::new_v4(), name, mime_type, None, current)
+            .await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(..))));
     }
 
     #[test(tokio::test)]
@@ -523,4 +1726,147 @@ mod tests {
             "expected `ObjectError::NotFound` while fetching deleted object",
         )
     }
+
+    #[test(tokio::test)]
+    async fn test_usage() {
+        let repo = repository().await;
+        let user_id = Uuid::new_v4();
+        assert_eq!(repo.total_usage().await.unwrap(), ObjectUsage::default());
+
+        let mut ids = Vec::new();
+        for size in [10, 20, 30] {
+            let id = Uuid::new_v4();
+            let data = ObjectData {
+                size,
+                ..rand_data()
+            };
+            repo.create(id, user_id, data).await.unwrap();
+            ids.push(id);
+        }
+        let data = ObjectData {
+            size: 100,
+            ..rand_data()
+        };
+        repo.create(Uuid::new_v4(), Uuid::new_v4(), data)
+            .await
+            .unwrap();
+
+        repo.trash(ids[0]).await.unwrap();
+        repo.delete(ids[1]).await.unwrap();
+
+        let usage = repo.usage_by_user(user_id).await.unwrap();
+        assert_eq!(
+            usage.stored,
+            DirUsage {
+                files: 1,
+                bytes: 30
+            }
+        );
+        assert_eq!(
+            usage.trash,
+            DirUsage {
+                files: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(
+            usage.total(),
+            DirUsage {
+                files: 2,
+                bytes: 40
+            }
+        );
+
+        let usage = repo.total_usage().await.unwrap();
+        assert_eq!(
+            usage.stored,
+            DirUsage {
+                files: 2,
+                bytes: 130
+            }
+        );
+        assert_eq!(
+            usage.total(),
+            DirUsage {
+                files: 3,
+                bytes: 140
+            }
+        );
+
+        let usage = repo.usage_by_user(Uuid::new_v4()).await.unwrap();
+        assert_eq!(usage, ObjectUsage::default());
+    }
+
+    #[test(tokio::test)]
+    async fn test_cached_get() {
+        let repo = repository()
+            .await
+            .with_cache(ObjectCache::new(16, Duration::from_secs(60)));
+
+        let id = Uuid::new_v4();
+        let obj = repo.create(id, Uuid::new_v4(), rand_data()).await.unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), obj);
+
+        // Changes made behind the repository are not observed
+        sqlx::query("UPDATE object SET name = 'other' WHERE id = $1")
+            .bind(id.into_bytes().as_slice())
+            .execute(&repo.db)
+            .await
+            .unwrap();
+        assert_eq!(repo.get(id).await.unwrap(), obj, "object was not cached");
+
+        repo.invalidate_cache();
+        assert_eq!(repo.get(id).await.unwrap().data.name, "other");
+
+        wait_next_ms().await;
+        let obj = repo
+            .update_info(id, rand_string(), rand_mime(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            repo.get(id).await.unwrap(),
+            obj,
+            "stale object after update"
+        );
+
+        repo.delete(id).await.unwrap();
+        let res = repo.get(id).await;
+        assert!(
+            matches!(res, Err(RepositoryError::NotFound(id2)) if id2 == id),
+            "deleted object still cached",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_version() {
+        let repo = repository().await;
+        let (user_a, user_b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(repo.get_version(user_a).await.unwrap(), 0);
+
+        let id = Uuid::new_v4();
+        repo.create(id, user_a, rand_data()).await.unwrap();
+        repo.create(Uuid::new_v4(), user_a, rand_data())
+            .await
+            .unwrap();
+        assert_eq!(repo.get_version(user_a).await.unwrap(), 2);
+
+        repo.update_info(id, rand_string(), rand_mime(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(repo.get_version(user_a).await.unwrap(), 3);
+
+        // Transfers change the listings of both users
+        sqlx::query("UPDATE object SET user_id = $1 WHERE user_id = $2")
+            .bind(user_b.into_bytes().as_slice())
+            .bind(user_a.into_bytes().as_slice())
+            .execute(&repo.db)
+            .await
+            .unwrap();
+        assert_eq!(repo.get_version(user_a).await.unwrap(), 5);
+        assert_eq!(repo.get_version(user_b).await.unwrap(), 2);
+
+        repo.delete(id).await.unwrap();
+        assert_eq!(repo.get_version(user_b).await.unwrap(), 3);
+    }
 }
@@ -1,50 +1,294 @@
-use std::{io, sync::Arc};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
-    body::Body,
-    extract::{multipart::MultipartError, Multipart, Path, Request},
-    http::{header, HeaderValue},
-    response::Response,
+    extract::{
+        multipart::{Field, MultipartError},
+        DefaultBodyLimit, FromRequest, Multipart, OriginalUri, Path, Request,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing, Extension, Router,
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
 use bytes::Bytes;
-use futures_util::{Stream, TryStreamExt};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use futures_util::{future::Either, Stream, StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sqlx::Sqlite;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio_util::io::ReaderStream;
 use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::{
-    auth::{axum::Authorization, AuthError, Token},
+    auth::{
+        axum::Authorization,
+        presign::{
+            PresignAction, PresignRepository, PresignedQuery, UploadPolicy,
+        },
+        AuthError, FileScope, Token,
+    },
     errors::{DownloaderError, HttpError},
-    storage::ObjectData,
-    utils::extractors::{Json, Query},
+    group::{repository::GroupRepository, Group},
+    job::{queue::JobQueue, Job, JobKind},
+    storage::{
+        embargo::EmbargoRepository,
+        fetch::{FetchError, RemoteFetcher},
+        fetch_job::{FetchContext, FetchJobs},
+        fetch_quota::{object_name, FetchQuota},
+        format_digest,
+        history::{HistoryEntry, HistoryRepository, ObjectHistory},
+        meta::{
+            validate_description, validate_metadata, MetaRepository, Metadata,
+        },
+        normalize_folder,
+        pipeline::{StepRepository, StepStatus},
+        preview::{preview, Preview, DEFAULT_PREVIEW_KIB, MAX_PREVIEW_KIB},
+        progress::{
+            validate_transfer_id, Progress, TrackedStream, Transfer,
+            TransferProgress, Transfers,
+        },
+        provenance::Uploader,
+        scan::Scanner,
+        slots::UploadSlots,
+        slug::{ObjectId, ObjectIds, PublicObject},
+        stats::{DailyDownloads, DownloadStats, MAX_HISTORY_DAYS},
+        ContentChecksum, ObjectData, UndeleteWindow, UploadLimits, WriteLocks,
+        REPR_DIGEST_HEADER,
+    },
+    timeout::transfer,
+    user::repository::UserRepository,
+    utils::{
+        extractors::{Body, Envelope, Page, Paged, Query},
+        stream::LimitStream,
+    },
 };
 
-use super::{manager::ObjectManager, repository::ObjectRepository, Object};
+use super::{
+    manager::{Manager, ObjectError},
+    repository::{ObjectRepository, RepositoryError},
+    trailer::{announces_checksum, TrailerBody, Trailers, VerifyTrailer},
+    Object,
+};
 
-pub fn file_routes<S>(router: Router<S>) -> Router<S>
+pub fn file_routes<S, M>(router: Router<S>) -> Router<S>
 where
     S: Clone + Send + Sync + 'static,
+    M: Manager,
 {
     router
         .route("/", routing::get(get_all_files))
         .route("/user/:user_id", routing::get(get_files_by_user))
+        .route("/version", routing::get(get_files_version))
+        .route(
+            "/transfer/:transfer_id/progress",
+            routing::get(get_transfer_progress),
+        )
         .route("/:id", routing::get(get_file))
-        .route("/:id/data", routing::get(download_file))
-        .route("/", routing::post(upload_file))
-        .route("/multipart", routing::post(upload_file_multipart))
+        .route("/:id/data", transfer(routing::get(download_file::<M>)))
+        .route("/:id/preview", routing::get(preview_file::<M>))
+        .route("/", transfer(routing::post(upload_file::<M>)))
+        .route(
+            "/multipart",
+            transfer(routing::post(upload_file_multipart::<M>)),
+        )
+        .route(
+            "/batch",
+            transfer(
+                routing::post(upload_file_batch::<M>)
+                    .layer(DefaultBodyLimit::max(MAX_BATCH_SIZE)),
+            ),
+        )
+        .route("/fetch", routing::post(fetch_file::<M>))
         .route("/:id", routing::put(update_file))
-        .route("/:id/data", routing::put(update_file_data))
-        .route("/:id/multipart", routing::put(update_file_data_multipart))
-        .route("/:id", routing::delete(delete_file))
+        .route("/:id/data", transfer(routing::put(update_file_data::<M>)))
+        .route(
+            "/:id/multipart",
+            transfer(routing::put(update_file_data_multipart::<M>)),
+        )
+        .route("/:id/full", transfer(routing::put(update_file_full::<M>)))
+        .route("/:id", routing::delete(delete_file::<M>))
+        .route("/user/:user_id", routing::delete(delete_files_by_user::<M>))
+        .route("/:id/undelete", routing::post(undelete_file))
+        .route("/:id/embargo", routing::get(get_file_embargo))
+        .route("/:id/embargo", routing::put(update_file_embargo))
+        .route("/:id/stats", routing::get(get_file_stats))
+        .route("/:id/steps", routing::get(get_file_steps))
+        .route("/:id/history", routing::get(get_file_history))
+        .route("/:id/transfer", routing::post(transfer_file))
+        .route("/:id/groups", routing::get(get_file_groups))
+        .route("/:id/groups/:group_id", routing::put(share_file))
+        .route("/:id/groups/:group_id", routing::delete(unshare_file))
+        .route("/presign", routing::post(presign_create))
+        .route("/:id/presign", routing::post(presign_file))
+        .route(
+            "/:id/presigned",
+            transfer(routing::get(download_presigned::<M>)),
+        )
+        .route(
+            "/:id/presigned",
+            transfer(routing::put(upload_presigned::<M>)),
+        )
+        .route(
+            "/:id/presigned",
+            transfer(routing::post(create_presigned::<M>)),
+        )
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PostFileRequestData {
     pub name: String,
+    /// Hex sha256 checksum the data must match, unless set in the
+    /// `X-Content-Sha256` header.
+    pub sha256: Option<String>,
+    /// Responds with the oldest object of the user with the checksum, if
+    /// any, instead of storing the data again. Only for new objects.
+    #[serde(default)]
+    pub reuse_existing: bool,
+}
+
+/// Name of the part of multipart uploads holding their [`UploadMetadata`].
+const METADATA_FIELD: &str = "metadata";
+
+/// The `metadata` part of multipart uploads, which must come before the
+/// `file` part.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadMetadata {
+    /// Defaults to the file name of the `file` part.
+    pub name: Option<String>,
+    /// Prefixed to the name.
+    pub folder: Option<String>,
+    /// Embargoes the object until this instant.
+    pub available_from: Option<DateTime<Utc>>,
+    /// Shares the object with these groups, which requires the share
+    /// permission.
+    #[serde(default)]
+    pub groups: Vec<Uuid>,
+}
+
+/// Files of a batch upload at most.
+pub const MAX_BATCH_FILES: usize = 1000;
+
+/// Size of each file of a batch upload at most.
+pub const MAX_BATCH_FILE_SIZE: usize = 1024 * 1024;
+
+/// Size of the body of a batch upload at most.
+pub const MAX_BATCH_SIZE: usize = 64 * 1024 * 1024;
+
+/// Files deleted at once by [`delete_files_by_user`].
+const DELETE_BY_USER_BATCH: u32 = 500;
+
+/// A line of NDJSON batch uploads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchFileData {
+    pub name: String,
+    /// Defaults to `application/octet-stream`.
+    pub mime_type: Option<String>,
+    /// Base64 encoded data of the file.
+    pub data: String,
+}
+
+/// A file of a batch upload, held in memory as it is small.
+struct BatchFile {
+    name: String,
+    mime_type: String,
+    data: Bytes,
+}
+
+/// The `metadata` part of full updates, which must come before the `file`
+/// part.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FullUpdateMetadata {
+    /// Defaults to the file name of the `file` part.
+    pub name: Option<String>,
+    /// Defaults to the content type of the `file` part.
+    pub mime_type: Option<String>,
+    /// Left unchanged if missing, removed if empty.
+    pub description: Option<String>,
+    /// Replaces all the custom metadata, left unchanged if missing.
+    pub metadata: Option<Metadata>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchFileRequestData {
+    pub url: String,
+    /// Defaults to the last segment of the url path.
+    pub name: Option<String>,
+    /// Prefixed to the name, required when fetches are restricted to some
+    /// folders.
+    pub folder: Option<String>,
+    /// Fetches in a background job, responding with the job instead.
+    #[serde(default)]
+    pub background: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PresignRequestData {
+    pub action: PresignAction,
+    pub duration: Option<u64>,
+    /// Constraints of the upload, only for the `upload` action.
+    pub policy: Option<PolicyRequestData>,
+    /// Before this instant the url is rejected, only for the `download`
+    /// action.
+    pub available_from: Option<DateTime<Utc>>,
+    /// Serves the file to be displayed instead of saved, only for the
+    /// `download` action.
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRequestData {
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    /// Only for new objects.
+    pub folder: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PresignCreateRequestData {
+    pub duration: Option<u64>,
+    pub policy: Option<PolicyRequestData>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresignResponseData {
+    pub file: PublicObject,
+    pub url: String,
+    /// Page with the details of the file linking to `url`, only for the
+    /// `download` action.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_url: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresignCreateResponseData {
+    /// Id of the object the url creates.
+    pub id: String,
+    /// Accepts a `multipart/form-data` POST with the file.
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+    pub policy: UploadPolicy,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,42 +300,134 @@ pub struct PaginationData {
     pub offset: u32,
 }
 
-const fn default_pagination_limit() -> u32 {
+pub(super) const fn default_pagination_limit() -> u32 {
     100
 }
 
-const fn default_pagination_offset() -> u32 {
+pub(super) const fn default_pagination_offset() -> u32 {
     0
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilesVersionQuery {
+    /// Defaults to the owner of the token.
+    pub user_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilesVersion {
+    pub user_id: Uuid,
+    pub version: u64,
+}
+
+/// Shares of the file can not download it before `available_from`, if
+/// any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbargoData {
+    pub available_from: Option<DateTime<Utc>>,
+}
+
+/// Unknown fields are allowed, as tokens can be sent in the query too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownloadQuery {
+    /// Serves the file to be displayed instead of saved.
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// Unknown fields are allowed, as tokens can be sent in the query too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewQuery {
+    /// KiB of text previewed at most.
+    #[serde(default = "default_preview_kib")]
+    pub kib: u64,
+}
+
+const fn default_preview_kib() -> u64 {
+    DEFAULT_PREVIEW_KIB
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsQuery {
+    /// Days of the histogram, today included.
+    #[serde(default = "default_stats_days")]
+    pub days: u32,
+}
+
+const fn default_stats_days() -> u32 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStatsData {
+    pub downloads: u64,
+    pub last_accessed_at: Option<DateTime<Utc>>,
+    /// Downloads per day, the oldest first.
+    pub daily: Vec<DailyDownloads>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransferFileRequestData {
+    /// The user receiving the file, in the namespace of the file.
+    pub user_id: Uuid,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct UpdateFileRequestData {
     pub name: String,
     pub mime_type: String,
+    /// Left unchanged if missing, removed if empty.
+    pub description: Option<String>,
+    /// Replaces all the custom metadata, left unchanged if missing.
+    pub metadata: Option<Metadata>,
+    /// Only updates the file if still at this version, like an `If-Match`
+    /// with its `ETag`.
+    pub version: Option<u64>,
 }
 
 pub async fn get_all_files(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    ids: ObjectIds,
+    envelope: Envelope,
     Query(data): Query<PaginationData>,
-) -> Result<Json<Vec<Object>>, DownloaderError> {
+) -> Result<Paged<PublicObject>, DownloaderError> {
     if !token.can_read_all() {
         return Err(AuthError::AccessDenied.into());
     }
 
-    repo.get_all(data.limit, data.offset)
-        .await
-        .map(Json)
-        .map_err(DownloaderError::Repository)
+    let objects = repo.get_all(data.limit, data.offset).await?;
+
+    let (mut next_cursor, mut total) = (None, None);
+    if envelope.0 {
+        // Offsets of this listing are positions in the table, not counts
+        let full = objects.len() >= data.limit as usize;
+        if let Some(last) = objects.last().filter(|_| full) {
+            next_cursor = Some(repo.position(last.id).await?.to_string());
+        }
+        total = Some(repo.total_usage().await?.stored.files);
+    }
+
+    Ok(envelope.wrap(Page {
+        items: ids.expose_all(objects).await?,
+        next_cursor,
+        total,
+    }))
 }
 
 pub async fn get_files_by_user(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    ids: ObjectIds,
+    envelope: Envelope,
     Path(user_id): Path<Uuid>,
     Query(data): Query<PaginationData>,
-) -> Result<Json<Vec<Object>>, DownloaderError> {
+) -> Result<Paged<PublicObject>, DownloaderError> {
     let can_access = token.can_read_all()
         || match token {
             Token::User(user_token) => user_token.user_id == user_id,
@@ -102,174 +438,621 @@ pub async fn get_files_by_user(
         return Err(AuthError::AccessDenied.into());
     }
 
-    repo.get_by_user(user_id, data.limit, data.offset)
-        .await
-        .map(Json)
-        .map_err(DownloaderError::Repository)
+    let objects = repo.get_by_user(user_id, data.limit, data.offset).await?;
+    let mut page =
+        Page::new(ids.expose_all(objects).await?, data.limit, data.offset);
+    if envelope.0 {
+        page = page.with_total(repo.usage_by_user(user_id).await?.stored.files);
+    }
+    Ok(envelope.wrap(page))
+}
+
+/// Returns the change counter of the files owned by an user, with an `ETag`
+/// so clients can poll it with `If-None-Match` and only fetch the listing
+/// again once it changes.
+pub async fn get_files_version(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Query(FilesVersionQuery { user_id }): Query<FilesVersionQuery>,
+    headers: HeaderMap,
+) -> Result<Response, DownloaderError> {
+    let token_user_id = match &token {
+        Token::User(user_token) => Some(user_token.user_id),
+        _ => None,
+    };
+    let user_id = user_id.or(token_user_id).ok_or(AuthError::AccessDenied)?;
+
+    if !token.can_read_all() && token_user_id != Some(user_id) {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let version = repo.get_version(user_id).await?;
+    let etag = format!("\"{user_id}-{version}\"");
+
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*");
+
+    let headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "no-cache".to_owned()),
+    ];
+
+    if not_modified {
+        Ok((StatusCode::NOT_MODIFIED, headers).into_response())
+    } else {
+        Ok((headers, Body(FilesVersion { user_id, version })).into_response())
+    }
+}
+
+/// Streams the progress of a transfer of the user, sent with its id in the
+/// `X-Transfer-Id` header, as `progress` server-sent events.
+pub async fn get_transfer_progress(
+    Authorization(token): Authorization,
+    Extension(transfers): Extension<Arc<Transfers>>,
+    Path(transfer_id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, DownloaderError>
+{
+    let Token::User(user_token) = token else {
+        return Err(AuthError::AccessDenied.into());
+    };
+    validate_transfer_id(&transfer_id)?;
+
+    let updates = transfers
+        .subscribe(user_token.user_id, transfer_id)
+        .ok_or_else(|| {
+            DownloaderError::Other(
+                "too many transfers tracked".into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        })?;
+
+    let events = updates.map(|progress: TransferProgress| {
+        Event::default().event("progress").json_data(progress)
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
+/// A response with the `ETag` of the object, see [`Object::etag`].
+pub type WithEtag<T> = ([(HeaderName, String); 1], Body<T>);
+
 pub async fn get_file(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Path(id): Path<Uuid>,
-) -> Result<Json<Object>, DownloaderError> {
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+) -> Result<WithEtag<PublicObject>, DownloaderError> {
     let object = repo.get(id).await?;
 
-    let can_access = token.can_read_all()
-        || (object.user_id
-            == match token {
-                Token::User(user_token) => user_token.user_id,
-                _ => Uuid::nil(),
-            });
+    let can_access = match &token {
+        Token::User(user_token) => {
+            token.can_read_all()
+                || object.user_id == user_token.user_id
+                || groups.is_shared_with(id, user_token.user_id).await?
+        }
+        Token::File(file_token) => file_token.file_id == id,
+        Token::Server => true,
+    };
 
     if !can_access {
         return Err(AuthError::AccessDenied.into());
     }
 
-    Ok(Json(object))
+    let etag = object.etag();
+    Ok(([(header::ETAG, etag)], Body(ids.expose(object).await?)))
 }
 
-pub async fn download_file(
+#[allow(clippy::too_many_arguments)]
+pub async fn download_file<M: Manager>(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
-    Path(id): Path<Uuid>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    stats: Option<Extension<DownloadStats>>,
+    scanner: Option<Extension<Scanner>>,
+    progress: Progress,
+    ObjectId(id): ObjectId,
+    Query(DownloadQuery { inline }): Query<DownloadQuery>,
 ) -> Result<Response, DownloaderError> {
     let object = repo.get(id).await?;
 
-    let can_access = token.can_read_all()
-        || (object.user_id
-            == match token {
-                Token::User(user_token) => user_token.user_id,
-                _ => Uuid::nil(),
-            });
+    let can_access = match &token {
+        Token::User(user_token) => {
+            token.can_read_all()
+                || object.user_id == user_token.user_id
+                || groups.is_shared_with(id, user_token.user_id).await?
+        }
+        Token::File(file_token) => file_token.allows(id, FileScope::DOWNLOAD),
+        Token::Server => true,
+    };
 
     if !can_access {
         return Err(AuthError::AccessDenied.into());
     }
+    if !bypasses_embargo(&token, &object) {
+        embargoes.check(id).await?;
+    }
+    if let Some(Extension(scanner)) = &scanner {
+        scanner.check(&object).await?;
+    }
 
     let reader = manager.fetch(id).await?;
+    if let Some(Extension(stats)) = stats {
+        stats.record(id);
+    }
+    let transfer = progress.start(&token, Some(object.data.size));
+    data_response(object, reader, transfer, inline)
+}
+
+/// Previews the first bytes of text files, or summarizes any other file,
+/// without the whole data being downloaded.
+#[allow(clippy::too_many_arguments)]
+pub async fn preview_file<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    scanner: Option<Extension<Scanner>>,
+    ObjectId(id): ObjectId,
+    Query(PreviewQuery { kib }): Query<PreviewQuery>,
+) -> Result<Body<Preview>, DownloaderError> {
+    if kib == 0 || kib > MAX_PREVIEW_KIB {
+        return Err(DownloaderError::Other(
+            format!("kib must be between 1 and {MAX_PREVIEW_KIB}"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let object = repo.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            token.can_read_all()
+                || object.user_id == user_token.user_id
+                || groups.is_shared_with(id, user_token.user_id).await?
+        }
+        Token::File(file_token) => file_token.allows(id, FileScope::DOWNLOAD),
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+    if !bypasses_embargo(&token, &object) {
+        embargoes.check(id).await?;
+    }
+    if let Some(Extension(scanner)) = &scanner {
+        scanner.check(&object).await?;
+    }
+
+    let len = (kib * 1024).min(object.data.size);
+    let mut head = Vec::with_capacity(len as usize);
+    if len > 0 {
+        let mut reader = manager.fetch_range(id, 0, len).await?;
+        reader
+            .read_to_end(&mut head)
+            .await
+            .map_err(ObjectError::from)?;
+    }
+    Ok(Body(preview(&object, &head, object.data.size > len)))
+}
+
+/// Whether `token` can access the `object` before its embargo lifts, which
+/// only its owner and those who can read all the files can.
+fn bypasses_embargo(token: &Token, object: &Object) -> bool {
+    match token {
+        Token::User(user_token) => {
+            object.user_id == user_token.user_id || token.can_read_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    }
+}
+
+fn data_response(
+    object: Object,
+    reader: impl AsyncRead + Send + 'static,
+    transfer: Option<Transfer>,
+    inline: bool,
+) -> Result<Response, DownloaderError> {
+    tracing::Span::current().record("bytes", object.data.size);
+
+    // Files are served from the same origin as the app, so the types that
+    // could run scripts are only ever downloaded, never displayed
+    let passive = is_passive(&object.data.mime_type);
+    let mime_type = match passive {
+        true => object.data.mime_type,
+        false => "application/octet-stream".to_owned(),
+    };
 
     Response::builder()
-        .header(header::CONTENT_TYPE, object.data.mime_type)
+        .header(header::CONTENT_TYPE, mime_type)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::CONTENT_SECURITY_POLICY, "sandbox")
         .header(
             header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", object.data.name),
+            content_disposition(&object.data.name, inline && passive),
         )
         .header(header::CONTENT_LENGTH, object.data.size.to_string())
-        .body(Body::from_stream(ReaderStream::new(reader)))
+        .header(REPR_DIGEST_HEADER, format_digest(&object.data.checksum_256))
+        .body(axum::body::Body::from_stream(TrackedStream::new(
+            ReaderStream::new(reader),
+            transfer,
+        )))
         .map_err(DownloaderError::from)
 }
 
-pub async fn upload_file(
+/// Whether files of `mime_type` can be displayed by browsers without
+/// running anything, like images other than SVG, PDF, audio, video and
+/// plain text.
+fn is_passive(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    let Some((ty, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+    match ty.to_ascii_lowercase().as_str() {
+        "image" => !subtype.eq_ignore_ascii_case("svg+xml"),
+        "audio" | "video" => true,
+        "application" => subtype.eq_ignore_ascii_case("pdf"),
+        "text" => subtype.eq_ignore_ascii_case("plain"),
+        _ => false,
+    }
+}
+
+/// Builds a `Content-Disposition` header naming the file after the last
+/// segment of `name`. Names that are not plain ASCII get an RFC 5987
+/// `filename*` parameter, with a sanitized `filename` for older clients.
+fn content_disposition(name: &str, inline: bool) -> HeaderValue {
+    let disposition = if inline { "inline" } else { "attachment" };
+    let name = name.rsplit('/').next().unwrap_or_default();
+
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let fallback = match fallback.trim() {
+        "" => "download",
+        fallback => fallback,
+    };
+
+    let mut value = format!("{disposition}; filename=\"{fallback}\"");
+    if fallback != name && !name.is_empty() {
+        value.push_str("; filename*=UTF-8''");
+        for byte in name.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => {
+                    value.push(byte as char)
+                }
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^'
+                | b'_' | b'`' | b'|' | b'~' => value.push(byte as char),
+                _ => value.push_str(&format!("%{byte:02X}")),
+            }
+        }
+    }
+
+    // Only visible ASCII is left, which is always a valid header value
+    HeaderValue::from_str(&value)
+        .unwrap_or_else(|_| HeaderValue::from_static("attachment"))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file<M: Manager>(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
-    Query(PostFileRequestData { name }): Query<PostFileRequestData>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    ids: ObjectIds,
+    progress: Progress,
+    uploader: Uploader,
+    checksum: ContentChecksum,
+    Query(query): Query<PostFileRequestData>,
     req: Request,
-) -> Result<Json<Object>, DownloaderError> {
-    let (stream, mime_type) = extract_request_body_file(req);
+) -> Result<Body<PublicObject>, DownloaderError> {
+    let checksum = checksum.or_query(query.sha256.as_deref())?;
 
-    post_file_internal(token, repo, manager, stream, name, mime_type)
-        .await
-        .map(Json)
+    // Checked before to avoid reading data that is already stored
+    if let (true, Some(checksum), Token::User(user_token)) =
+        (query.reuse_existing, &checksum, &token)
+    {
+        if token.can_write_owned() {
+            let existing =
+                repo.get_by_checksum(user_token.user_id, checksum).await?;
+            if let Some(obj) = existing {
+                return Ok(Body(ids.expose(obj).await?));
+            }
+        }
+    }
+
+    let _slot = slots.acquire_for(&token).await?;
+    let (stream, trailers, mime_type) = extract_request_body_file(req);
+    let stream =
+        progress.track(&token, progress.content_length(), limits.apply(stream));
+
+    let provenance = uploader.provenance(Some(&token));
+
+    let obj = post_file_internal(
+        token,
+        repo,
+        manager,
+        stream,
+        query.name,
+        mime_type,
+        checksum,
+        trailers,
+        progress.content_length(),
+    )
+    .await?;
+    uploader.record(obj.id, &provenance).await;
+    Ok(Body(ids.expose(obj).await?))
 }
 
-pub async fn upload_file_multipart(
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file_multipart<M: Manager>(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    ids: ObjectIds,
+    progress: Progress,
+    uploader: Uploader,
+    ContentChecksum(checksum): ContentChecksum,
     mut multipart: Multipart,
-) -> Result<Json<Object>, DownloaderError> {
-    let (stream, name, mime_type) =
-        extract_multipart_file(&mut multipart).await?;
+) -> Result<Body<PublicObject>, DownloaderError> {
+    // The metadata must come first, as the file part is streamed
+    let mut field = next_multipart_field(&mut multipart, 1).await?;
+    let mut metadata = UploadMetadata::default();
+    if field.name() == Some(METADATA_FIELD) {
+        metadata = multipart_metadata(field).await?;
+        field = next_multipart_field(&mut multipart, 2).await?;
+    }
 
-    post_file_internal(token, repo, manager, stream, name, mime_type)
-        .await
-        .map(Json)
+    // Checked before to avoid storing files that could not be shared
+    if !metadata.groups.is_empty() {
+        if !token.can_share() {
+            return Err(AuthError::AccessDenied.into());
+        }
+        for &group_id in &metadata.groups {
+            groups.get(group_id).await?;
+        }
+    }
+    let folder = match metadata.folder.as_deref() {
+        Some(folder) => Some(normalize_folder(folder).ok_or_else(|| {
+            HttpError::InvalidFormMetadata(
+                "the folder must be a relative path".into(),
+            )
+        })?),
+        None => None,
+    };
+
+    let (name, mime_type) = multipart_file(&field)?;
+    let name = object_name(folder, metadata.name.unwrap_or(name));
+    let _slot = slots.acquire_for(&token).await?;
+    let stream = field.map_err(io::Error::other);
+    let stream = progress.track(&token, None, limits.apply(stream));
+
+    let provenance = uploader.provenance(Some(&token));
+
+    let obj = post_file_internal(
+        token, repo, manager, stream, name, mime_type, checksum, None, None,
+    )
+    .await?;
+    uploader.record(obj.id, &provenance).await;
+
+    if metadata.available_from.is_some() {
+        embargoes.set(obj.id, metadata.available_from).await?;
+    }
+    for group_id in metadata.groups {
+        groups.share(group_id, obj.id).await?;
+    }
+    Ok(Body(ids.expose(obj).await?))
 }
 
-pub async fn update_file(
+/// Creates many small files at once, either from a `multipart/form-data`
+/// body with a part per file or from an `application/x-ndjson` body with a
+/// [`BatchFileData`] per line. The objects are all created or none is.
+pub async fn upload_file_batch<M: Manager>(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Path(id): Path<Uuid>,
-    Json(data): Json<UpdateFileRequestData>,
-) -> Result<Json<Object>, DownloaderError> {
-    // Placed before to avoid unecessary database queries in case the
-    // write permission is missing
+    Extension(manager): Extension<Arc<M>>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    ids: ObjectIds,
+    uploader: Uploader,
+    req: Request,
+) -> Result<Body<Vec<PublicObject>>, DownloaderError> {
     if !token.can_write_owned() {
         return Err(AuthError::AccessDenied.into());
     }
+    let user_id = match &token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
 
-    let can_access = match &token {
-        Token::User(user_token) => {
-            let obj = repo.get(id).await?;
-
-            obj.user_id == user_token.user_id || token.can_write_all()
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok());
+    let files = match content_type {
+        Some(mime) if mime.essence_str() == "application/x-ndjson" => {
+            let body = axum::body::to_bytes(req.into_body(), MAX_BATCH_SIZE)
+                .await
+                .map_err(|error| HttpError::InvalidBatch(error.to_string()))?;
+            ndjson_batch(&body)?
+        }
+        Some(mime) if mime.essence_str() == "multipart/form-data" => {
+            let multipart = Multipart::from_request(req, &())
+                .await
+                .map_err(|error| HttpError::InvalidBatch(error.body_text()))?;
+            multipart_batch(multipart).await?
+        }
+        _ => {
+            return Err(HttpError::InvalidBatch(
+                "the content type must be multipart/form-data \
+                or application/x-ndjson"
+                    .into(),
+            )
+            .into())
         }
-        Token::File(file_token) => file_token.file_id == id,
-        Token::Server => true,
     };
-
-    if !can_access {
-        return Err(AuthError::AccessDenied.into());
+    if files.is_empty() {
+        return Err(HttpError::InvalidBatch("no files provided".into()).into());
     }
 
-    let obj = repo.update_info(id, data.name, data.mime_type).await?;
-    Ok(Json(obj))
-}
+    let files = files
+        .into_iter()
+        .map(|file| {
+            Ok(BatchFile {
+                name: repo.check_name(&file.name)?,
+                ..file
+            })
+        })
+        .collect::<Result<Vec<_>, DownloaderError>>()?;
 
-pub async fn update_file_data(
-    Authorization(token): Authorization,
-    Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
-    Path(id): Path<Uuid>,
-    Query(PostFileRequestData { name }): Query<PostFileRequestData>,
-    req: Request,
-) -> Result<Json<Object>, DownloaderError> {
-    let (stream, mime_type) = extract_request_body_file(req);
-    // pin_mut!(reader);
+    let provenance = uploader.provenance(Some(&token));
 
-    update_file_internal(token, repo, manager, id, stream, name, mime_type)
-        .await
-        .map(Json)
+    // The whole batch is written at once
+    let _slot = slots.acquire(Some(user_id)).await?;
+    let objects = create_objects(repo, manager, user_id, files).await?;
+
+    let mut exposed = Vec::with_capacity(objects.len());
+    for obj in objects {
+        uploader.record(obj.id, &provenance).await;
+        exposed.push(ids.expose(obj).await?);
+    }
+    Ok(Body(exposed))
 }
 
-pub async fn update_file_data_multipart(
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_file<M: Manager>(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
-    Path(id): Path<Uuid>,
-    mut multipart: Multipart,
-) -> Result<Json<Object>, DownloaderError> {
-    let (stream, name, mime_type) =
-        extract_multipart_file(&mut multipart).await?;
-    // pin_mut!(reader);
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(jobs): Extension<Arc<JobQueue>>,
+    Extension(fetches): Extension<FetchJobs>,
+    fetcher: Option<Extension<Arc<RemoteFetcher>>>,
+    quota: Option<Extension<FetchQuota>>,
+    ids: ObjectIds,
+    uploader: Uploader,
+    Body(data): Body<FetchFileRequestData>,
+) -> Result<Response, DownloaderError> {
+    let (Some(Extension(fetcher)), Some(Extension(quota))) = (fetcher, quota)
+    else {
+        return Err(FetchError::Disabled.into());
+    };
+    // Checked before to avoid fetching files that could not be stored
+    let (user_id, username) = match &token {
+        Token::User(user_token) if token.can_write_owned() => {
+            (user_token.user_id, user_token.username.as_str())
+        }
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
 
-    update_file_internal(token, repo, manager, id, stream, name, mime_type)
-        .await
-        .map(Json)
+    let folder = match data.folder.as_deref() {
+        Some(folder) => {
+            Some(normalize_folder(folder).ok_or(FetchError::InvalidFolder)?)
+        }
+        None => None,
+    };
+    let name = data
+        .name
+        .map(|name| repo.check_name(&object_name(folder, name)))
+        .transpose()?;
+    // The name may hold folders of its own
+    let dir = match &name {
+        Some(name) => name.rsplit_once('/').map(|(dir, _)| dir),
+        None => folder,
+    };
+    quota.check_folder(username, dir)?;
+
+    let provenance = uploader.provenance(Some(&token));
+    let folder = folder.map(str::to_owned);
+
+    if !data.background {
+        let file = fetcher.fetch(&data.url).await?;
+        quota.check_daily(user_id, file.size).await?;
+
+        let fetched = Arc::new(AtomicU64::new(0));
+        let counter = fetched.clone();
+        let stream = file.stream.inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
+        let res = post_file_internal(
+            token,
+            repo,
+            manager,
+            limits.apply(stream),
+            name.unwrap_or_else(|| object_name(folder.as_deref(), file.name)),
+            file.mime_type,
+            None,
+            None,
+            file.size,
+        )
+        .await;
+        quota.record(user_id, fetched.load(Ordering::Relaxed)).await;
+
+        let obj = res?;
+        uploader.record(obj.id, &provenance).await;
+        return Ok(Body(ids.expose(obj).await?).into_response());
+    }
+
+    fetcher.check(&data.url)?;
+
+    let ctx = FetchContext {
+        fetcher,
+        repo,
+        manager,
+        limits,
+        ids,
+        uploader,
+        quota,
+    };
+    let job = fetches
+        .enqueue(&jobs, ctx, user_id, data.url, name, folder, provenance)
+        .await?;
+
+    Ok((StatusCode::ACCEPTED, Body(job)).into_response())
 }
 
-pub async fn delete_file(
+#[allow(clippy::too_many_arguments)]
+pub async fn update_file(
     Authorization(token): Authorization,
     Extension(repo): Extension<ObjectRepository<Sqlite>>,
-    Extension(manager): Extension<Arc<ObjectManager>>,
-    Path(id): Path<Uuid>,
-) -> Result<Json<Object>, DownloaderError> {
+    Extension(metas): Extension<MetaRepository<Sqlite>>,
+    Extension(history): Extension<HistoryRepository<Sqlite>>,
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+    headers: HeaderMap,
+    Body(data): Body<UpdateFileRequestData>,
+) -> Result<WithEtag<PublicObject>, DownloaderError> {
     // Placed before to avoid unecessary database queries in case the
     // write permission is missing
     if !token.can_write_owned() {
         return Err(AuthError::AccessDenied.into());
     }
 
+    // Needed by the history entry anyway
+    let before = repo.get(id).await?;
+
     let can_access = match &token {
         Token::User(user_token) => {
-            let obj = repo.get(id).await?;
-
-            obj.user_id == user_token.user_id || token.can_write_all()
+            before.user_id == user_token.user_id || token.can_write_all()
         }
-        Token::File(file_token) => file_token.file_id == id,
+        Token::File(file_token) => file_token.allows(id, FileScope::METADATA),
         Token::Server => true,
     };
 
@@ -277,101 +1060,284 @@ pub async fn delete_file(
         return Err(AuthError::AccessDenied.into());
     }
 
-    let obj = repo.delete(id).await?;
+    let name = repo.check_name(&data.name)?;
+    if let Some(description) = &data.description {
+        validate_description(description)?;
+    }
+    if let Some(metadata) = &data.metadata {
+        validate_metadata(metadata)?;
+    }
 
-    tokio::spawn(async move {
-        manager
-            .delete(id)
-            .instrument(tracing::span!(
-                tracing::Level::WARN,
-                "delete_background"
-            ))
-            .await
-    });
+    // Checked against the version read first, then only updated if still
+    // at it, so concurrent updates do not overwrite each other
+    let matches = if_match(&headers, &before.etag());
+    if matches == Some(false)
+        || data.version.is_some_and(|v| v != before.version)
+    {
+        return Err(RepositoryError::VersionMismatch(id).into());
+    }
+    let version =
+        (matches.is_some() || data.version.is_some()).then_some(before.version);
 
-    Ok(Json(obj))
+    let description = data.description.as_deref();
+    let obj = repo
+        .update_info(id, name, data.mime_type, description, version)
+        .await?;
+    if let Some(metadata) = &data.metadata {
+        metas.set(id, metadata).await?;
+    }
+
+    // The update is already made, so the request must not fail
+    let entry =
+        HistoryEntry::new(&token, &before, &obj, data.metadata.is_some());
+    if let Err(error) = history.record(id, &entry).await {
+        tracing::error!(%error, %id, "failed to record object history");
+    }
+    let etag = obj.etag();
+    Ok(([(header::ETAG, etag)], Body(ids.expose(obj).await?)))
 }
 
-async fn extract_multipart_file<'a>(
-    multipart: &'a mut Multipart,
-) -> Result<
-    (
-        futures_util::stream::MapErr<
-            axum::extract::multipart::Field<'a>,
-            impl FnMut(MultipartError) -> io::Error,
-        >,
-        String,
-        String,
-    ),
-    DownloaderError,
-> {
-    let field =
-        multipart
-            .next_field()
-            .await?
-            .ok_or(HttpError::InvalidFormLength {
-                expected: 1,
-                got: 0,
-            })?;
+/// Whether the `If-Match` header matches the strong `etag`, `None` if
+/// missing or matching any version.
+fn if_match(headers: &HeaderMap, etag: &str) -> Option<bool> {
+    let tags: Vec<_> = headers
+        .get_all(header::IF_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if tags.is_empty() || tags.contains(&"*") {
+        return None;
+    }
+    Some(tags.contains(&etag))
+}
 
-    let name = field
-        .file_name()
-        .ok_or(HttpError::InvalidFormBoundary)?
-        .to_string();
+/// Hands the file over to another user, recorded in its history like the
+/// updates of its info.
+pub async fn transfer_file(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(users): Extension<UserRepository<Sqlite>>,
+    Extension(history): Extension<HistoryRepository<Sqlite>>,
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+    Body(data): Body<TransferFileRequestData>,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    if !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
 
-    let mime_type = field
-        .content_type()
-        .ok_or(HttpError::InvalidFormBoundary)?
-        .to_string();
+    let before = repo.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            before.user_id == user_token.user_id || token.can_write_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    // Scoped to the namespace of the request, which is the one of the file
+    let target = users.get(data.user_id).await?;
+    if target.id == before.user_id {
+        return Ok(Body(ids.expose(before).await?));
+    }
 
-    let field_stream =
-        field.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let obj = repo.transfer(id, target.id).await?;
+    tracing::info!(
+        %id,
+        from = %before.user_id,
+        to = %target.id,
+        "transferred object ownership",
+    );
 
-    Ok((field_stream, name, mime_type))
+    // The transfer is already made, so the request must not fail
+    let entry = HistoryEntry::new(&token, &before, &obj, false);
+    if let Err(error) = history.record(id, &entry).await {
+        tracing::error!(%error, %id, "failed to record object history");
+    }
+    Ok(Body(ids.expose(obj).await?))
 }
 
-fn extract_request_body_file(
-    req: Request,
-) -> (
-    futures_util::stream::MapErr<
-        axum::body::BodyDataStream,
-        impl FnMut(axum::Error) -> io::Error,
-    >,
-    String,
-) {
-    let mime_type = req
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .unwrap_or(&HeaderValue::from_static(mime::OCTET_STREAM.as_str()))
-        .to_str()
-        .unwrap_or(mime::OCTET_STREAM.as_str())
-        .to_string();
+/// Returns who updated the info of the file and how, newest first.
+pub async fn get_file_history(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    Extension(history): Extension<HistoryRepository<Sqlite>>,
+    ObjectId(id): ObjectId,
+    Query(data): Query<PaginationData>,
+) -> Result<Body<ObjectHistory>, DownloaderError> {
+    let object = repo.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            token.can_read_all()
+                || object.user_id == user_token.user_id
+                || groups.is_shared_with(id, user_token.user_id).await?
+        }
+        Token::File(file_token) => file_token.file_id == id,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    Ok(Body(history.get(id, data.limit, data.offset).await?))
+}
 
-    let stream = req.into_body().into_data_stream();
+#[allow(clippy::too_many_arguments)]
+pub async fn update_file_data<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    Extension(locks): Extension<Arc<WriteLocks>>,
+    ids: ObjectIds,
+    progress: Progress,
+    uploader: Uploader,
+    ObjectId(id): ObjectId,
+    checksum: ContentChecksum,
+    Query(query): Query<PostFileRequestData>,
+    req: Request,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    let checksum = checksum.or_query(query.sha256.as_deref())?;
+    let _slot = slots.acquire_for(&token).await?;
+    let (stream, _, mime_type) = extract_request_body_file(req);
     let stream =
-        stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        progress.track(&token, progress.content_length(), limits.apply(stream));
+
+    let provenance = uploader.provenance(Some(&token));
 
-    (stream, mime_type)
+    let obj = update_file_internal(
+        token,
+        repo,
+        manager,
+        locks,
+        id,
+        stream,
+        query.name,
+        mime_type,
+        checksum,
+        progress.content_length(),
+    )
+    .await?;
+    uploader.record(obj.id, &provenance).await;
+    Ok(Body(ids.expose(obj).await?))
 }
 
-async fn post_file_internal(
-    token: Token,
-    repo: ObjectRepository<Sqlite>,
-    manager: Arc<ObjectManager>,
-    stream: impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
-    name: String,
-    mime_type: String,
-) -> Result<Object, DownloaderError> {
+#[allow(clippy::too_many_arguments)]
+pub async fn update_file_data_multipart<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    Extension(locks): Extension<Arc<WriteLocks>>,
+    ids: ObjectIds,
+    progress: Progress,
+    uploader: Uploader,
+    ObjectId(id): ObjectId,
+    ContentChecksum(checksum): ContentChecksum,
+    mut multipart: Multipart,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    let _slot = slots.acquire_for(&token).await?;
+    let (stream, name, mime_type) =
+        extract_multipart_file(&mut multipart).await?;
+    let stream = progress.track(&token, None, limits.apply(stream));
+
+    let provenance = uploader.provenance(Some(&token));
+
+    let obj = update_file_internal(
+        token, repo, manager, locks, id, stream, name, mime_type, checksum,
+        None,
+    )
+    .await?;
+    uploader.record(obj.id, &provenance).await;
+    Ok(Body(ids.expose(obj).await?))
+}
+
+/// Replaces the data and all the metadata of the file at once, putting the
+/// previous data back if its entry can not be updated.
+#[allow(clippy::too_many_arguments)]
+pub async fn update_file_full<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    Extension(locks): Extension<Arc<WriteLocks>>,
+    ids: ObjectIds,
+    progress: Progress,
+    uploader: Uploader,
+    ObjectId(id): ObjectId,
+    ContentChecksum(checksum): ContentChecksum,
+    mut multipart: Multipart,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    // Placed before to avoid unecessary database queries in case the
+    // write permission is missing
     if !token.can_write_owned() {
         return Err(AuthError::AccessDenied.into());
     }
-    let token = match token {
-        Token::User(user_token) => user_token,
-        _ => return Err(AuthError::AccessDenied.into()),
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_write_all()
+        }
+        Token::File(file_token) => {
+            file_token.allows(id, FileScope::UPDATE | FileScope::METADATA)
+        }
+        Token::Server => true,
     };
 
-    let id = Uuid::new_v4();
-    let (size, checksum_256) = manager.store(id, stream).await?;
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    // The metadata must come first, as the file part is streamed
+    let mut field = next_multipart_field(&mut multipart, 1).await?;
+    let mut metadata = FullUpdateMetadata::default();
+    if field.name() == Some(METADATA_FIELD) {
+        metadata = multipart_metadata(field).await?;
+        field = next_multipart_field(&mut multipart, 2).await?;
+    }
+    if let Some(description) = &metadata.description {
+        validate_description(description)?;
+    }
+    if let Some(metadata) = &metadata.metadata {
+        validate_metadata(metadata)?;
+    }
+
+    let (name, mime_type) = multipart_file(&field)?;
+    let name = repo.check_name(&metadata.name.unwrap_or(name))?;
+    let mime_type = metadata.mime_type.unwrap_or(mime_type);
+    let _slot = slots.acquire_for(&token).await?;
+    let stream = field.map_err(io::Error::other);
+    let stream = progress.track(&token, None, limits.apply(stream));
+
+    let provenance = uploader.provenance(Some(&token));
+
+    // Held until the entry is updated, so it always matches the stored data
+    let _guard = locks.acquire(id).await?;
+    manager.snapshot(id).await?;
+    let (size, checksum_256) =
+        match manager.store(id, stream, checksum, None).await {
+            Ok(stored) => stored,
+            Err(error) => {
+                let _ = manager.release(id).await;
+                return Err(error.into());
+            }
+        };
+    tracing::Span::current().record("bytes", size);
 
     let data = ObjectData {
         name,
@@ -379,42 +1345,200 @@ async fn post_file_internal(
         size,
         checksum_256,
     };
-
-    match repo.create(id, token.user_id, data).await {
-        Ok(v) => Ok(v),
+    let description = metadata.description.as_deref();
+    let res = repo
+        .update_full(id, data, description, metadata.metadata.as_ref())
+        .await;
+    let obj = match res {
+        Ok(obj) => obj,
         Err(error) => {
             tracing::error!(
-                target: "routes::post",
+                target: "storage::routes::update",
                 %error,
                 %id,
-                "create object entry failed after store",
+                "update object entry failed after store, rolling back data",
             );
+            let _ = manager.rollback(id).await;
+            return Err(error.into());
+        }
+    };
+    let _ = manager.release(id).await;
 
-            let _ = manager.delete(id).await.map_err(|error| {
-                tracing::error!(
-                    target: "storage::routes::post",
-                    %error,
-                    %id,
-                    "delete object without repository entry failed",
-                );
-            });
+    uploader.record(obj.id, &provenance).await;
+    Ok(Body(ids.expose(obj).await?))
+}
 
-            Err(error.into())
+pub async fn delete_file<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(window): Extension<UndeleteWindow>,
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    // Placed before to avoid unecessary database queries in case the
+    // write permission is missing
+    if !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_write_all()
         }
+        Token::File(file_token) => file_token.allows(id, FileScope::DELETE),
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    // The data is kept until purged, once the undelete window ends
+    if window.is_enabled() {
+        let obj = repo.trash(id).await?;
+        return Ok(Body(ids.expose(obj).await?));
     }
+
+    let obj = repo.delete(id).await?;
+
+    tokio::spawn(async move {
+        manager
+            .delete(id)
+            .instrument(tracing::span!(
+                tracing::Level::WARN,
+                "delete_background"
+            ))
+            .await
+    });
+
+    Ok(Body(ids.expose(obj).await?))
 }
 
-async fn update_file_internal(
-    token: Token,
-    repo: ObjectRepository<Sqlite>,
-    manager: Arc<ObjectManager>,
-    id: Uuid,
-    stream: impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
-    name: String,
-    mime_type: String,
-) -> Result<Object, DownloaderError> {
-    // Placed before to avoid unecessary database queries in case the
-    // write permission is missing
+/// Deletes all the files of an user in a background job, for offboarding
+/// users with many files. Files are deleted regardless of the undelete
+/// window, their data removed along with each batch.
+pub async fn delete_files_by_user<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(jobs): Extension<Arc<JobQueue>>,
+    Path(user_id): Path<Uuid>,
+) -> Result<(StatusCode, Body<Job>), DownloaderError> {
+    if !token.can_write_all() {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let total = repo.usage_by_user(user_id).await?.stored.files;
+    let started_by = match &token {
+        Token::User(user_token) => Some(user_token.user_id),
+        _ => None,
+    };
+
+    let job = jobs
+        .enqueue(JobKind::DeleteUserObjects, started_by, |progress| {
+            progress.set_total(total);
+
+            async move {
+                let (mut deleted, mut failed) = (0, 0);
+                loop {
+                    let ids = repo
+                        .delete_by_user(user_id, DELETE_BY_USER_BATCH)
+                        .await?;
+                    if ids.is_empty() {
+                        break;
+                    }
+
+                    deleted += ids.len();
+                    for id in ids {
+                        // Errors are already logged by the manager
+                        if manager.delete(id).await.is_err() {
+                            failed += 1;
+                        }
+                        progress.add(1);
+                    }
+                }
+
+                tracing::info!(deleted, failed, "deleted user files");
+                Ok(serde_json::json!({ "deleted": deleted, "failed": failed }))
+            }
+            .instrument(tracing::span!(
+                tracing::Level::WARN,
+                "delete_files_by_user_background",
+                %user_id,
+            ))
+        })
+        .await?;
+
+    Ok((StatusCode::ACCEPTED, Body(job)))
+}
+
+pub async fn undelete_file(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(window): Extension<UndeleteWindow>,
+    ids: ObjectIds,
+    ObjectId(id): ObjectId,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    if !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let cutoff = window.cutoff();
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get_deleted(id, cutoff).await?;
+
+            obj.user_id == user_token.user_id || token.can_write_all()
+        }
+        Token::File(file_token) => file_token.allows(id, FileScope::DELETE),
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let obj = repo.undelete(id, cutoff).await?;
+    Ok(Body(ids.expose(obj).await?))
+}
+
+pub async fn get_file_embargo(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    ObjectId(id): ObjectId,
+) -> Result<Body<EmbargoData>, DownloaderError> {
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_read_all()
+        }
+        Token::File(file_token) => file_token.allows(id, FileScope::DOWNLOAD),
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let available_from = embargoes.get(id).await?;
+    Ok(Body(EmbargoData { available_from }))
+}
+
+/// Sets when shares of the file can download it, lifting the embargo if
+/// `available_from` is null. File tokens can not change it.
+pub async fn update_file_embargo(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    ObjectId(id): ObjectId,
+    Body(data): Body<EmbargoData>,
+) -> Result<Body<EmbargoData>, DownloaderError> {
     if !token.can_write_owned() {
         return Err(AuthError::AccessDenied.into());
     }
@@ -425,7 +1549,7 @@ async fn update_file_internal(
 
             obj.user_id == user_token.user_id || token.can_write_all()
         }
-        Token::File(file_token) => file_token.file_id == id,
+        Token::File(..) => false,
         Token::Server => true,
     };
 
@@ -433,25 +1557,3107 @@ async fn update_file_internal(
         return Err(AuthError::AccessDenied.into());
     }
 
-    let (size, checksum_256) = manager.store(id, stream).await?;
+    embargoes.set(id, data.available_from).await?;
+    Ok(Body(data))
+}
 
-    repo.update(
-        id,
-        ObjectData {
-            name,
-            mime_type,
-            size,
-            checksum_256,
-        },
-    )
-    .await
-    .map_err(|error| {
-        tracing::error!(
-            target: "storage::routes::update",
-            %error,
-            %id,
-            "update object entry failed after store",
-        );
-        error.into()
-    })
+/// Returns the groups the file is shared with. Only for its owner.
+pub async fn get_file_groups(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    ObjectId(id): ObjectId,
+) -> Result<Body<Vec<Group>>, DownloaderError> {
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_read_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    Ok(Body(groups.get_shares(id).await?))
+}
+
+/// Lets the members of a group of the namespace download the file,
+/// returning the groups it is shared with.
+pub async fn share_file(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    ids: ObjectIds,
+    Path((id, group_id)): Path<(String, Uuid)>,
+) -> Result<Body<Vec<Group>>, DownloaderError> {
+    let id = ids.resolve(&id).await?;
+    check_group_share(&token, &repo, id).await?;
+
+    groups.share(group_id, id).await?;
+    Ok(Body(groups.get_shares(id).await?))
+}
+
+pub async fn unshare_file(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    ids: ObjectIds,
+    Path((id, group_id)): Path<(String, Uuid)>,
+) -> Result<Body<Vec<Group>>, DownloaderError> {
+    let id = ids.resolve(&id).await?;
+    check_group_share(&token, &repo, id).await?;
+
+    groups.unshare(group_id, id).await?;
+    Ok(Body(groups.get_shares(id).await?))
+}
+
+/// Only the owner of the file, or who can write all files, may change the
+/// groups it is shared with.
+async fn check_group_share(
+    token: &Token,
+    repo: &ObjectRepository<Sqlite>,
+    id: Uuid,
+) -> Result<(), DownloaderError> {
+    if !token.can_share() {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    // Also checks the file is in the namespace of the groups
+    let obj = repo.get(id).await?;
+
+    let can_access = match token {
+        Token::User(user_token) => {
+            obj.user_id == user_token.user_id || token.can_write_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+    Ok(())
+}
+
+/// Returns the downloads of the file, with how many of them were made in
+/// each of the last `days` days. Only for its owner.
+pub async fn get_file_stats(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(stats): Extension<DownloadStats>,
+    ObjectId(id): ObjectId,
+    Query(StatsQuery { days }): Query<StatsQuery>,
+) -> Result<Body<FileStatsData>, DownloaderError> {
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_read_all()
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    if days == 0 || days > MAX_HISTORY_DAYS {
+        return Err(DownloaderError::Other(
+            format!("days must be between 1 and {MAX_HISTORY_DAYS}"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let totals = stats.get(id).await?;
+    Ok(Body(FileStatsData {
+        downloads: totals.downloads,
+        last_accessed_at: totals.last_accessed_at,
+        daily: stats.get_daily(id, days).await?,
+    }))
+}
+
+pub async fn get_file_steps(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    Extension(steps): Extension<StepRepository<Sqlite>>,
+    ObjectId(id): ObjectId,
+) -> Result<Body<Vec<StepStatus>>, DownloaderError> {
+    let object = repo.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            token.can_read_all()
+                || object.user_id == user_token.user_id
+                || groups.is_shared_with(id, user_token.user_id).await?
+        }
+        Token::File(file_token) => file_token.file_id == id,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    Ok(Body(steps.get(id).await?))
+}
+
+/// Returns a single-use url to download or upload the file without
+/// authorization, which can be shared like a file token.
+pub async fn presign_file(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    ids: ObjectIds,
+    OriginalUri(uri): OriginalUri,
+    ObjectId(id): ObjectId,
+    Body(data): Body<PresignRequestData>,
+) -> Result<Body<PresignResponseData>, DownloaderError> {
+    if !token.can_share() {
+        return Err(AuthError::AccessDenied.into());
+    }
+    if data.action == PresignAction::Upload && !token.can_write_owned() {
+        return Err(AuthError::HigherPermissionRequired.into());
+    }
+    if data.available_from.is_some() && data.action != PresignAction::Download {
+        return Err(AuthError::InvalidPresignRequest(
+            "only downloads can be made available later",
+        )
+        .into());
+    }
+    if data.inline && data.action != PresignAction::Download {
+        return Err(AuthError::InvalidPresignRequest(
+            "only downloads can be served inline",
+        )
+        .into());
+    }
+
+    let policy = match (data.action, data.policy) {
+        (PresignAction::Create, _) => {
+            return Err(AuthError::InvalidPresignRequest(
+                "new objects are presigned with POST /presign",
+            )
+            .into());
+        }
+        (_, None) => None,
+        (PresignAction::Upload, Some(policy)) => {
+            if policy.folder.is_some() {
+                return Err(AuthError::InvalidPresignRequest(
+                    "the folder only applies to new objects",
+                )
+                .into());
+            }
+            Some(UploadPolicy {
+                max_size: policy.max_size,
+                mime_types: policy.mime_types,
+                ..Default::default()
+            })
+        }
+        (PresignAction::Download, Some(..)) => {
+            return Err(AuthError::InvalidPresignRequest(
+                "policies only apply to uploads",
+            )
+            .into());
+        }
+    };
+
+    let file = repo.get(id).await?;
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            file.user_id == user_token.user_id
+                || match data.action {
+                    PresignAction::Download => token.can_read_all(),
+                    PresignAction::Upload | PresignAction::Create => {
+                        token.can_write_all()
+                    }
+                }
+        }
+        Token::File(..) => false,
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let duration = Duration::from_secs(data.duration.unwrap_or(3600));
+    let query = presign_repo
+        .create(
+            id,
+            data.action,
+            duration,
+            policy.as_ref(),
+            data.available_from,
+            data.inline,
+        )
+        .await?;
+
+    let path = uri.path();
+    let url = format!(
+        "{}/presigned?{}",
+        path.strip_suffix("/presign").unwrap_or(path),
+        query.to_query_string(),
+    );
+    let expires_at =
+        DateTime::from_timestamp(query.expires, 0).unwrap_or_default();
+
+    let file = ids.expose(file).await?;
+    let share_url = (data.action == PresignAction::Download)
+        .then(|| format!("/s/{}?{}", file.id, query.to_query_string()));
+
+    Ok(Body(PresignResponseData {
+        file,
+        url,
+        share_url,
+        expires_at,
+    }))
+}
+
+pub async fn presign_create(
+    Authorization(token): Authorization,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    ids: ObjectIds,
+    OriginalUri(uri): OriginalUri,
+    Body(data): Body<PresignCreateRequestData>,
+) -> Result<Body<PresignCreateResponseData>, DownloaderError> {
+    if !token.can_share() || !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
+    let user_id = match &token {
+        Token::User(user_token) => user_token.user_id,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    let policy = data.policy.unwrap_or_default();
+    let folder = match policy.folder {
+        Some(folder) => match normalize_folder(&folder) {
+            Some(folder) => Some(folder.to_owned()),
+            None => {
+                return Err(AuthError::InvalidPresignRequest(
+                    "the folder must be a relative path",
+                )
+                .into())
+            }
+        },
+        None => None,
+    };
+
+    let policy = UploadPolicy {
+        user_id: Some(user_id),
+        max_size: policy.max_size,
+        mime_types: policy.mime_types,
+        folder,
+    };
+
+    let id = Uuid::new_v4();
+    let duration = Duration::from_secs(data.duration.unwrap_or(3600));
+    let query = presign_repo
+        .create(
+            id,
+            PresignAction::Create,
+            duration,
+            Some(&policy),
+            None,
+            false,
+        )
+        .await?;
+    let id = ids.expose_id(id).await?;
+
+    let path = uri.path();
+    let url = format!(
+        "{}/{id}/presigned?{}",
+        path.strip_suffix("/presign").unwrap_or(path),
+        query.to_query_string(),
+    );
+    let expires_at =
+        DateTime::from_timestamp(query.expires, 0).unwrap_or_default();
+
+    Ok(Body(PresignCreateResponseData {
+        id,
+        url,
+        expires_at,
+        policy,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn download_presigned<M: Manager>(
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    stats: Option<Extension<DownloadStats>>,
+    scanner: Option<Extension<Scanner>>,
+    ObjectId(id): ObjectId,
+    Query(query): Query<PresignedQuery>,
+) -> Result<Response, DownloaderError> {
+    // Checked before using the url, which is single use, and after
+    // verifying it, so the embargo is only told to holders of the url
+    presign_repo.verify(id, PresignAction::Download, &query)?;
+    embargoes.check(id).await?;
+    let object = repo.get(id).await?;
+    if let Some(Extension(scanner)) = &scanner {
+        scanner.check(&object).await?;
+    }
+
+    presign_repo
+        .consume(id, PresignAction::Download, &query)
+        .await?;
+
+    let reader = manager.fetch(id).await?;
+    if let Some(Extension(stats)) = stats {
+        stats.record(id);
+    }
+    data_response(object, reader, None, query.inline)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_presigned<M: Manager>(
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    Extension(locks): Extension<Arc<WriteLocks>>,
+    ids: ObjectIds,
+    uploader: Uploader,
+    ObjectId(id): ObjectId,
+    ContentChecksum(checksum): ContentChecksum,
+    Query(query): Query<PresignedQuery>,
+    req: Request,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    let policy = presign_repo
+        .verify(id, PresignAction::Upload, &query)?
+        .unwrap_or_default();
+
+    let size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+    let (stream, _, mime_type) = extract_request_body_file(req);
+    policy.check(&mime_type, size)?;
+
+    // Taken before the url is used up, which a retry after failing to get
+    // a slot would need
+    let _slot = slots.acquire(None).await?;
+    presign_repo
+        .consume(id, PresignAction::Upload, &query)
+        .await?;
+
+    let name = repo.get(id).await?.data.name;
+    let stream = LimitStream::new(stream, policy.max_size.unwrap_or(u64::MAX));
+
+    let obj = store_update(
+        repo,
+        manager,
+        locks,
+        id,
+        limits.apply(stream),
+        name,
+        mime_type,
+        checksum,
+        size,
+    )
+    .await?;
+    uploader.record(obj.id, &uploader.provenance(None)).await;
+    Ok(Body(ids.expose(obj).await?))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn create_presigned<M: Manager>(
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(limits): Extension<UploadLimits>,
+    Extension(slots): Extension<Arc<UploadSlots>>,
+    ids: ObjectIds,
+    uploader: Uploader,
+    Path(id): Path<String>,
+    ContentChecksum(checksum): ContentChecksum,
+    Query(query): Query<PresignedQuery>,
+    mut multipart: Multipart,
+) -> Result<Body<PublicObject>, DownloaderError> {
+    // The slug of the object is created along with the url
+    let id = ids.resolve_pending(&id).await?;
+    let policy = presign_repo.verify(id, PresignAction::Create, &query)?;
+    let Some((policy, user_id)) =
+        policy.and_then(|p| p.user_id.map(|user_id| (p, user_id)))
+    else {
+        return Err(AuthError::InvalidPresignedUrl.into());
+    };
+
+    let (stream, name, mime_type) =
+        extract_multipart_file(&mut multipart).await?;
+    policy.check(&mime_type, None)?;
+    let name = repo.check_name(&policy.object_name(&name)?)?;
+
+    let _slot = slots.acquire(Some(user_id)).await?;
+    presign_repo
+        .consume(id, PresignAction::Create, &query)
+        .await?;
+
+    let stream = LimitStream::new(stream, policy.max_size.unwrap_or(u64::MAX));
+
+    let obj = create_object(
+        repo,
+        manager,
+        id,
+        user_id,
+        limits.apply(stream),
+        name,
+        mime_type,
+        checksum,
+        None,
+    )
+    .await?;
+    uploader.record(obj.id, &uploader.provenance(None)).await;
+    Ok(Body(ids.expose(obj).await?))
+}
+
+pub async fn extract_multipart_file<'a>(
+    multipart: &'a mut Multipart,
+) -> Result<
+    (
+        futures_util::stream::MapErr<
+            Field<'a>,
+            impl FnMut(MultipartError) -> io::Error,
+        >,
+        String,
+        String,
+    ),
+    DownloaderError,
+> {
+    let field = next_multipart_field(multipart, 1).await?;
+    let (name, mime_type) = multipart_file(&field)?;
+
+    Ok((field.map_err(io::Error::other), name, mime_type))
+}
+
+async fn next_multipart_field<'a>(
+    multipart: &'a mut Multipart,
+    expected: usize,
+) -> Result<Field<'a>, DownloaderError> {
+    multipart.next_field().await?.ok_or_else(|| {
+        HttpError::InvalidFormLength {
+            expected,
+            got: expected - 1,
+        }
+        .into()
+    })
+}
+
+/// Returns the file name and mime type of the file part `field`.
+fn multipart_file(
+    field: &Field<'_>,
+) -> Result<(String, String), DownloaderError> {
+    let name = field
+        .file_name()
+        .ok_or(HttpError::InvalidFormBoundary)?
+        .to_string();
+
+    let mime_type = field
+        .content_type()
+        .ok_or(HttpError::InvalidFormBoundary)?
+        .to_string();
+
+    Ok((name, mime_type))
+}
+
+/// Adds the file to the batch, failing if it goes over the limits.
+fn push_batch_file(
+    files: &mut Vec<BatchFile>,
+    file: BatchFile,
+) -> Result<(), HttpError> {
+    if files.len() >= MAX_BATCH_FILES {
+        return Err(HttpError::InvalidBatch(format!(
+            "more than {MAX_BATCH_FILES} files provided"
+        )));
+    }
+    if file.data.len() > MAX_BATCH_FILE_SIZE {
+        return Err(HttpError::InvalidBatch(format!(
+            "file `{}` is larger than {MAX_BATCH_FILE_SIZE} bytes",
+            file.name,
+        )));
+    }
+
+    files.push(file);
+    Ok(())
+}
+
+async fn multipart_batch(
+    mut multipart: Multipart,
+) -> Result<Vec<BatchFile>, DownloaderError> {
+    let mut files = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        let (name, mime_type) = multipart_file(&field)?;
+        let data = field.bytes().await?;
+        push_batch_file(
+            &mut files,
+            BatchFile {
+                name,
+                mime_type,
+                data,
+            },
+        )?;
+    }
+    Ok(files)
+}
+
+fn ndjson_batch(body: &[u8]) -> Result<Vec<BatchFile>, HttpError> {
+    let mut files = Vec::new();
+    for (i, line) in body.split(|&b| b == b'\n').enumerate() {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let invalid = |error: &dyn std::fmt::Display| {
+            HttpError::InvalidBatch(format!("line {}: {error}", i + 1))
+        };
+
+        let file: BatchFileData =
+            serde_json::from_slice(line).map_err(|error| invalid(&error))?;
+        let data = BASE64_STANDARD
+            .decode(&file.data)
+            .map_err(|error| invalid(&error))?;
+
+        push_batch_file(
+            &mut files,
+            BatchFile {
+                name: file.name,
+                mime_type: file.mime_type.unwrap_or_else(|| {
+                    mime::APPLICATION_OCTET_STREAM.to_string()
+                }),
+                data: data.into(),
+            },
+        )?;
+    }
+    Ok(files)
+}
+
+async fn multipart_metadata<T: DeserializeOwned>(
+    field: Field<'_>,
+) -> Result<T, DownloaderError> {
+    let data = field.bytes().await?;
+    serde_json::from_slice(&data).map_err(|error| {
+        HttpError::InvalidFormMetadata(error.to_string()).into()
+    })
+}
+
+/// Also returns the trailers of the body if the checksum trailer is
+/// announced, see [`announces_checksum`].
+fn extract_request_body_file(
+    req: Request,
+) -> (TrailerBody, Option<Trailers>, String) {
+    let mime_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .unwrap_or(&HeaderValue::from_static(mime::OCTET_STREAM.as_str()))
+        .to_str()
+        .unwrap_or(mime::OCTET_STREAM.as_str())
+        .to_string();
+
+    let announced = announces_checksum(req.headers());
+    let (stream, trailers) = TrailerBody::new(req.into_body());
+
+    (stream, announced.then_some(trailers), mime_type)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn post_file_internal<M: Manager>(
+    token: Token,
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+    name: String,
+    mime_type: String,
+    checksum: Option<[u8; 32]>,
+    trailers: Option<Trailers>,
+    size: Option<u64>,
+) -> Result<Object, DownloaderError> {
+    if !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
+    let token = match token {
+        Token::User(user_token) => user_token,
+        _ => return Err(AuthError::AccessDenied.into()),
+    };
+
+    // Checked as the data is stored, so mismatching data is never kept
+    let stream = match trailers {
+        Some(trailers) => Either::Left(VerifyTrailer::new(stream, trailers)),
+        None => Either::Right(stream),
+    };
+
+    let id = Uuid::new_v4();
+    create_object(
+        repo,
+        manager,
+        id,
+        token.user_id,
+        stream,
+        name,
+        mime_type,
+        checksum,
+        size,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn create_object<M: Manager>(
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    id: Uuid,
+    user_id: Uuid,
+    stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+    name: String,
+    mime_type: String,
+    checksum: Option<[u8; 32]>,
+    size: Option<u64>,
+) -> Result<Object, DownloaderError> {
+    let name = repo.check_name(&name)?;
+
+    // Recorded first so the data is removed if the server crashes before the
+    // object is created, see `recover_creates`
+    repo.begin_create(id, user_id, &name, &mime_type).await?;
+    let (size, checksum_256) =
+        match manager.store(id, stream, checksum, size).await {
+            Ok(v) => v,
+            Err(error) => {
+                abort_creates(&repo, &[id]).await;
+                return Err(error.into());
+            }
+        };
+    tracing::Span::current().record("bytes", size);
+
+    let data = ObjectData {
+        name,
+        mime_type,
+        size,
+        checksum_256,
+    };
+
+    let res = match repo.commit_create(id, size, checksum_256).await {
+        Ok(()) => repo.create(id, user_id, data).await,
+        Err(error) => Err(error),
+    };
+    match res {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(
+                target: "routes::post",
+                %error,
+                %id,
+                "create object entry failed after store",
+            );
+
+            // The data is only removed once the creation can't be completed
+            // on startup anymore
+            if abort_creates(&repo, &[id]).await {
+                let _ = manager.delete(id).await.map_err(|error| {
+                    tracing::error!(
+                        target: "storage::routes::post",
+                        %error,
+                        %id,
+                        "delete object without repository entry failed",
+                    );
+                });
+            }
+
+            Err(error.into())
+        }
+    }
+}
+
+/// Creates the objects of a batch upload, storing their data together
+/// and inserting them in a single transaction.
+async fn create_objects<M: Manager>(
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    user_id: Uuid,
+    files: Vec<BatchFile>,
+) -> Result<Vec<Object>, DownloaderError> {
+    let ids: Vec<Uuid> = files.iter().map(|_| Uuid::new_v4()).collect();
+
+    let intents: Vec<_> = (ids.iter().zip(&files))
+        .map(|(&id, file)| (id, file.name.as_str(), file.mime_type.as_str()))
+        .collect();
+    repo.begin_create_batch(user_id, &intents).await?;
+
+    let data = (ids.iter().zip(&files))
+        .map(|(&id, file)| (id, file.data.clone()))
+        .collect();
+    let written = match manager.store_batch(data).await {
+        Ok(v) => v,
+        Err(error) => {
+            abort_creates(&repo, &ids).await;
+            return Err(error.into());
+        }
+    };
+    tracing::Span::current()
+        .record("bytes", written.iter().map(|(size, _)| size).sum::<u64>());
+
+    let objects = (ids.iter().zip(files).zip(written))
+        .map(|((&id, file), (size, checksum_256))| {
+            let data = ObjectData {
+                name: file.name,
+                mime_type: file.mime_type,
+                size,
+                checksum_256,
+            };
+            (id, data)
+        })
+        .collect();
+
+    match repo.create_batch(user_id, objects).await {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(
+                target: "routes::post",
+                %error,
+                count = ids.len(),
+                "create object entries failed after batch store",
+            );
+
+            if abort_creates(&repo, &ids).await {
+                for &id in &ids {
+                    let _ = manager.delete(id).await.map_err(|error| {
+                        tracing::error!(
+                            target: "storage::routes::post",
+                            %error,
+                            %id,
+                            "delete object without repository entry failed",
+                        );
+                    });
+                }
+            }
+
+            Err(error.into())
+        }
+    }
+}
+
+/// Whether the creations were aborted, logging the error otherwise.
+async fn abort_creates(repo: &ObjectRepository<Sqlite>, ids: &[Uuid]) -> bool {
+    match repo.abort_create_batch(ids).await {
+        Ok(()) => true,
+        Err(error) => {
+            tracing::error!(
+                target: "storage::routes::post",
+                %error,
+                count = ids.len(),
+                "abort object creation failed",
+            );
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn update_file_internal<M: Manager>(
+    token: Token,
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    locks: Arc<WriteLocks>,
+    id: Uuid,
+    stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+    name: String,
+    mime_type: String,
+    checksum: Option<[u8; 32]>,
+    size: Option<u64>,
+) -> Result<Object, DownloaderError> {
+    // Placed before to avoid unecessary database queries in case the
+    // write permission is missing
+    if !token.can_write_owned() {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let can_access = match &token {
+        Token::User(user_token) => {
+            let obj = repo.get(id).await?;
+
+            obj.user_id == user_token.user_id || token.can_write_all()
+        }
+        Token::File(file_token) => file_token.allows(id, FileScope::UPDATE),
+        Token::Server => true,
+    };
+
+    if !can_access {
+        return Err(AuthError::AccessDenied.into());
+    }
+
+    let name = repo.check_name(&name)?;
+    store_update(
+        repo, manager, locks, id, stream, name, mime_type, checksum, size,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn store_update<M: Manager>(
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    locks: Arc<WriteLocks>,
+    id: Uuid,
+    stream: impl Stream<Item = Result<Bytes, io::Error>> + Send + Unpin,
+    name: String,
+    mime_type: String,
+    checksum: Option<[u8; 32]>,
+    size: Option<u64>,
+) -> Result<Object, DownloaderError> {
+    // Held until the entry is updated, so it always matches the stored data
+    let _guard = locks.acquire(id).await?;
+    let (size, checksum_256) =
+        manager.store(id, stream, checksum, size).await?;
+    tracing::Span::current().record("bytes", size);
+
+    repo.update(
+        id,
+        ObjectData {
+            name,
+            mime_type,
+            size,
+            checksum_256,
+        },
+    )
+    .await
+    .map_err(|error| {
+        tracing::error!(
+            target: "storage::routes::update",
+            %error,
+            %id,
+            "update object entry failed after store",
+        );
+        error.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path, sync::Arc, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request, StatusCode},
+        routing, Router,
+    };
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use bytes::Bytes;
+    use chrono::{TimeDelta, Utc};
+    use futures_util::{stream, StreamExt};
+    use test_log::test;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::{
+        auth::{FileScope, Permission, Token},
+        config::{FetchConfig, NameConfig},
+        group::{repository::GroupRepository, Group, GroupData},
+        job::{Job, JobKind, JobState},
+        namespace::default_namespace,
+        storage::{
+            faulty::Faults,
+            fetch,
+            fetch_job::{
+                FetchContext, FetchJobs, FetchState, FetchStateRepository,
+            },
+            fetch_quota::FetchQuota,
+            history::ObjectHistory,
+            manager::INCOMPLETE_DIR,
+            meta::{MetaRepository, Metadata},
+            name::NameStrictness,
+            progress::{TransferProgress, TRANSFER_ID_HEADER},
+            provenance::{ProvenanceRepository, TokenType, Uploader},
+            slug::{IdExposure, ObjectIds, PublicObject},
+            trash::purge_expired,
+            Object, UndeleteWindow, UploadLimits, CONTENT_SHA256_HEADER,
+        },
+        testing::{test_app, test_app_with, TestApp},
+        user::{
+            password::PasswordHasher, repository::UserRepository, UserData,
+        },
+        utils::extractors::{Page, PAGE_MEDIA_TYPE},
+    };
+
+    use super::{
+        content_disposition, is_passive, EmbargoData, FileStatsData,
+        PresignCreateResponseData, PresignResponseData, MAX_BATCH_FILE_SIZE,
+    };
+
+    const CONTENT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    async fn upload(app: &TestApp) -> Object {
+        let (status, body) = app
+            .request(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        parse_object(&body)
+    }
+
+    /// Parses an object exposed with its uuid.
+    fn parse_object(body: &[u8]) -> Object {
+        into_object(serde_json::from_slice(body).unwrap())
+    }
+
+    fn into_object(obj: PublicObject) -> Object {
+        Object {
+            id: obj.id.parse().unwrap(),
+            user_id: obj.user_id,
+            created_at: obj.created_at,
+            updated_at: obj.updated_at,
+            data: obj.data,
+            namespace: default_namespace(),
+            description: obj.description,
+            version: obj.version,
+        }
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).map_or(0, |entries| entries.count())
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_partial_write() {
+        let app = test_app().await;
+
+        app.manager.set_faults(Faults {
+            store_fail_after: Some(8),
+            ..Default::default()
+        });
+
+        let (status, _) = app
+            .request(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert_eq!(
+            count_files(&app.temp_dir.path().join(INCOMPLETE_DIR)),
+            0,
+            "partially written temp file was not cleaned up",
+        );
+        assert_eq!(count_files(app.data_dir.path()), 0);
+        assert!(app.obj_repo.get_all(10, 0).await.unwrap().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_store_failure() {
+        let app = test_app().await;
+
+        app.manager.set_faults(Faults {
+            fail_store: true,
+            ..Default::default()
+        });
+
+        let (status, _) = app
+            .request(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(
+            app.obj_repo.get_all(10, 0).await.unwrap().is_empty(),
+            "object entry created for failed store",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_repository_failure() {
+        let app = test_app().await;
+
+        app.db.close().await;
+
+        let (status, _) = app
+            .request(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert_eq!(
+            count_files(app.data_dir.path()),
+            0,
+            "stored data was not deleted after repository failure",
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_partial_write() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        app.manager.set_faults(Faults {
+            store_fail_after: Some(4),
+            latency: Duration::from_millis(5),
+            ..Default::default()
+        });
+
+        let uri = format!("/api/file/{}/data?name=other.txt", obj.id);
+        let (status, _) = app
+            .request(Method::PUT, &uri, Some(&app.token), b"overwritten")
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        app.manager.set_faults(Faults::default());
+
+        assert_eq!(
+            app.obj_repo.get(obj.id).await.unwrap(),
+            obj,
+            "object entry changed after failed update",
+        );
+        assert_eq!(count_files(&app.temp_dir.path().join(INCOMPLETE_DIR)), 0);
+
+        let uri = format!("/api/file/{}/data", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT, "object data changed after failed update");
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_full() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let metas = MetaRepository::new(app.db.clone());
+
+        let form = |metadata: &str, content: &str| {
+            format!(
+                "--boundary\r\n\
+                Content-Disposition: form-data; name=\"metadata\"\r\n\
+                Content-Type: application/json\r\n\r\n\
+                {metadata}\r\n\
+                --boundary\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                Content-Type: text/plain\r\n\r\n\
+                {content}\r\n\
+                --boundary--\r\n"
+            )
+        };
+        let uri = format!("/api/file/{}/full", obj.id);
+        let update = |body: String| {
+            let token = Some(app.token.as_str());
+            let content_type = "multipart/form-data; boundary=boundary";
+            app.request_with(Method::PUT, &uri, token, content_type, body)
+        };
+        let download = || async {
+            let uri = format!("/api/file/{}/data", obj.id);
+            let (status, body) =
+                app.request(Method::GET, &uri, Some(&app.token), b"").await;
+            assert_eq!(status, StatusCode::OK);
+            body
+        };
+
+        let metadata = r#"{"name":"b.md","mime_type":"text/markdown",
+            "description":"Hello","metadata":{"lang":"en"}}"#;
+        let (status, body) = update(form(metadata, "hello")).await;
+        assert_eq!(status, StatusCode::OK);
+        let updated = parse_object(&body);
+        assert_eq!(updated.data.name, "b.md");
+        assert_eq!(updated.data.mime_type, "text/markdown");
+        assert_eq!(updated.data.size, 5);
+        assert_eq!(updated.description.as_deref(), Some("Hello"));
+        assert_eq!(metas.get(obj.id).await.unwrap()["lang"], "en");
+        assert_eq!(download().await, b"hello");
+
+        // Nothing changes if the entry can not be updated
+        sqlx::query(
+            "CREATE TRIGGER fail_update BEFORE UPDATE ON object \
+            BEGIN SELECT RAISE(ABORT, 'injected update fault'); END",
+        )
+        .execute(&app.db)
+        .await
+        .unwrap();
+
+        let metadata = r#"{"metadata":{"lang":"pt"}}"#;
+        let (status, _) = update(form(metadata, "olá")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(app.obj_repo.get(obj.id).await.unwrap(), updated);
+        assert_eq!(metas.get(obj.id).await.unwrap()["lang"], "en");
+        assert_eq!(download().await, b"hello");
+        assert_eq!(count_files(&app.temp_dir.path().join("snapshot")), 0);
+
+        sqlx::query("DROP TRIGGER fail_update")
+            .execute(&app.db)
+            .await
+            .unwrap();
+
+        // Defaults to the file part, leaving the rest unchanged
+        let (status, body) = update(form("{}", "bye")).await;
+        assert_eq!(status, StatusCode::OK);
+        let updated = parse_object(&body);
+        assert_eq!(updated.data.name, "a.txt");
+        assert_eq!(updated.data.mime_type, "text/plain");
+        assert_eq!(updated.description.as_deref(), Some("Hello"));
+        assert_eq!(metas.get(obj.id).await.unwrap()["lang"], "en");
+        assert_eq!(download().await, b"bye");
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_write_conflict() {
+        let app = test_app_with(|cfg| cfg.wait_for_writes = false).await;
+        let obj = upload(&app).await;
+        let uri = format!("/api/file/{}/data?name=other.txt", obj.id);
+
+        let guard = app.write_locks.acquire(obj.id).await.unwrap();
+        let (status, _) = app
+            .request(Method::PUT, &uri, Some(&app.token), b"overwritten")
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(app.obj_repo.get(obj.id).await.unwrap(), obj);
+
+        drop(guard);
+        let (status, _) = app
+            .request(Method::PUT, &uri, Some(&app.token), b"overwritten")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test(tokio::test)]
+    async fn test_file_token_scope() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let other = upload(&app).await;
+
+        // The permission bits alone would allow writes
+        let token = app
+            .token_repo
+            .generate_file_token(
+                obj.id,
+                Duration::from_secs(60),
+                "test".into(),
+                Permission::SINGLE_FILE_RW,
+                FileScope::DOWNLOAD,
+            )
+            .unwrap();
+
+        let uri = format!("/api/file/{}/data", obj.id);
+        let (status, body) = app
+            .request_with(Method::GET, &uri, Some(&token), "text/plain", b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+
+        let uri = format!("/api/file/{}/data", other.id);
+        let (status, _) = app
+            .request_with(Method::GET, &uri, Some(&token), "text/plain", b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let denied = [
+            (Method::PUT, format!("/api/file/{}", obj.id)),
+            (
+                Method::PUT,
+                format!("/api/file/{}/data?name=other.txt", obj.id),
+            ),
+            (Method::DELETE, format!("/api/file/{}", obj.id)),
+        ];
+        for (method, uri) in denied {
+            let body = br#"{"name":"other.txt","mime_type":"text/plain"}"#;
+            let (status, _) = app
+                .request_with(
+                    method.clone(),
+                    &uri,
+                    Some(&token),
+                    "application/json",
+                    body,
+                )
+                .await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri}");
+        }
+
+        assert_eq!(app.obj_repo.get(obj.id).await.unwrap(), obj);
+    }
+
+    #[test(tokio::test)]
+    async fn test_file_metadata() {
+        let app = test_app().await;
+
+        let obj = upload(&app).await;
+        let token = Some(app.token.as_str());
+        let uri = format!("/api/file/{}", obj.id);
+        let update = |body: &'static str| {
+            app.request_with(Method::PUT, &uri, token, "application/json", body)
+        };
+
+        let (status, body) = update(
+            r#"{"name":"fox.txt","mime_type":"text/plain",
+            "description":"A quick fox",
+            "metadata":{"author":"fox","color":"brown"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let exposed: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exposed.description.as_deref(), Some("A quick fox"));
+        assert_eq!(exposed.metadata.len(), 2);
+        assert_eq!(exposed.metadata["color"], "brown");
+
+        // Missing fields are left unchanged
+        let (status, body) = update(
+            r#"{"name":"fox.txt","mime_type":"text/plain",
+            "metadata":{"author":"dog"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let exposed: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exposed.description.as_deref(), Some("A quick fox"));
+        assert_eq!(
+            exposed.metadata,
+            Metadata::from([("author".into(), "dog".into())]),
+        );
+
+        let (status, _) = update(
+            r#"{"name":"fox.txt","mime_type":"text/plain",
+            "metadata":{"":"empty"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let exposed: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exposed.metadata["author"], "dog");
+
+        let (status, body) = update(
+            r#"{"name":"fox.txt","mime_type":"text/plain",
+            "description":"","metadata":{}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let exposed: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exposed.description, None);
+        assert!(exposed.metadata.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_conditional_update() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let uri = format!("/api/file/{}", obj.id);
+        let auth = format!("Bearer {}", app.token);
+
+        let update = |if_match: Option<&str>, body: String| {
+            let mut req = Request::put(&uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, &auth);
+            if let Some(tag) = if_match {
+                req = req.header(header::IF_MATCH, tag);
+            }
+            let req = req.body(Body::from(body)).unwrap();
+            app.router.clone().oneshot(req)
+        };
+        let body = |version: Option<u64>| {
+            serde_json::json!({
+                "name": "fox.txt",
+                "mime_type": "text/plain",
+                "version": version,
+            })
+            .to_string()
+        };
+
+        let req = Request::get(&uri)
+            .header(header::AUTHORIZATION, &auth)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_owned();
+        assert_eq!(etag, obj.etag());
+
+        let res = update(Some(&etag), body(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let updated = res.headers()[header::ETAG].to_str().unwrap().to_owned();
+        assert_ne!(updated, etag);
+
+        // Updated since read
+        let res = update(Some(&etag), body(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let tags = format!("{etag}, {updated}");
+        let res = update(Some(&tags), body(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = update(None, body(Some(1))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = update(None, body(Some(2))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Unconditional
+        let res = update(Some("*"), body(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = update(None, body(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(app.obj_repo.get(obj.id).await.unwrap().version, 5);
+    }
+
+    #[test(tokio::test)]
+    async fn test_slug_ids() {
+        let app = test_app_with(|cfg| cfg.expose_ids = IdExposure::Slug).await;
+
+        let (status, body) = app
+            .request(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert!(obj.id.parse::<Uuid>().is_err(), "uuid exposed");
+
+        let id = app.obj_repo.get_all(1, 0).await.unwrap()[0].id;
+        let uri = format!("/api/file/user/{}", obj.user_id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let objects: Vec<PublicObject> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(objects, vec![obj.clone()]);
+
+        let (status, body) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<PublicObject>(&body).unwrap(), obj);
+
+        let (status, body) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{}/data", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+
+        let (status, _) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{id}"),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "uuid accepted");
+
+        let (status, _) = app
+            .request(
+                Method::DELETE,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let body = String::from_utf8(body).unwrap();
+        assert!(!body.contains(&id.to_string()), "uuid exposed: {body}");
+    }
+
+    #[test(tokio::test)]
+    async fn test_undelete() {
+        let window = Duration::from_millis(200);
+        let app = test_app_with(|cfg| cfg.undelete_window = window).await;
+
+        let obj = upload(&app).await;
+        let undelete = format!("/api/file/{}/undelete", obj.id);
+        let data = format!("/api/file/{}/data", obj.id);
+
+        let (status, _) = app
+            .request(Method::POST, &undelete, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "restored a live object");
+
+        let (status, _) = app
+            .request(
+                Method::DELETE,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) =
+            app.request(Method::GET, &data, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = app
+            .request(Method::POST, &undelete, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parse_object(&body), obj);
+
+        let (status, body) =
+            app.request(Method::GET, &data, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT, "object data lost after undelete");
+
+        let (status, _) = app
+            .request(
+                Method::DELETE,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        tokio::time::sleep(window).await;
+        let (status, _) = app
+            .request(Method::POST, &undelete, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "restored after window");
+
+        let window = UndeleteWindow(window);
+        let purged = purge_expired(&app.obj_repo, app.manager.as_ref(), window)
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(count_files(app.data_dir.path()), 0);
+    }
+
+    async fn presign(
+        app: &TestApp,
+        id: Uuid,
+        body: &'static [u8],
+    ) -> PresignResponseData {
+        let token = Some(app.token.as_str());
+        let uri = format!("/api/file/{id}/presign");
+        let (status, body) = app
+            .request_with(Method::POST, &uri, token, "application/json", body)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_presign() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        let url =
+            presign(&app, obj.id, br#"{"action":"upload","duration":60}"#)
+                .await
+                .url;
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/plain", b"replaced")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/plain", b"again")
+            .await;
+        assert_eq!(status, StatusCode::GONE, "upload url reused");
+
+        let url = presign(&app, obj.id, br#"{"action":"download"}"#).await.url;
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/plain", b"other")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "wrong action accepted");
+
+        let (status, body) = app
+            .request_with(Method::GET, &url, None, "text/plain", b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"replaced");
+        let (status, _) = app
+            .request_with(Method::GET, &url, None, "text/plain", b"")
+            .await;
+        assert_eq!(status, StatusCode::GONE, "download url reused");
+    }
+
+    #[test(tokio::test)]
+    async fn test_presign_policy() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        let body = br#"{
+            "action": "upload",
+            "policy": { "max_size": 4, "mime_types": ["text/*"] }
+        }"#;
+        let url = presign(&app, obj.id, body).await.url;
+
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "image/png", b"png")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "mime type not enforced");
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/plain", b"replaced")
+            .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/plain", b"abc")
+            .await;
+        assert_eq!(status, StatusCode::GONE);
+
+        let url = presign(&app, obj.id, body).await.url;
+        let (status, _) = app
+            .request_with(Method::PUT, &url, None, "text/csv", b"a,b")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Dropping the policy must invalidate the signature
+        let start = url.find("&policy=").unwrap();
+        let end = url.find("&signature=").unwrap();
+        let tampered = format!("{}{}", &url[..start], &url[end..]);
+        let (status, _) = app
+            .request_with(Method::PUT, &tampered, None, "text/csv", b"a,b")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "policy was not signed");
+    }
+
+    #[test(tokio::test)]
+    async fn test_presign_create() {
+        let app = test_app().await;
+
+        let token = Some(app.token.as_str());
+        let body = br#"{
+            "duration": 60,
+            "policy": {
+                "max_size": 64,
+                "mime_types": ["text/plain"],
+                "folder": "/uploads/"
+            }
+        }"#;
+        let (status, res) = app
+            .request_with(
+                Method::POST,
+                "/api/file/presign",
+                token,
+                "application/json",
+                body,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let res: PresignCreateResponseData =
+            serde_json::from_slice(&res).unwrap();
+        assert_eq!(res.policy.folder.as_deref(), Some("uploads"));
+
+        let form = |name: &str, mime: &str| {
+            format!(
+                "--boundary\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+                Content-Type: {mime}\r\n\r\n\
+                hello\r\n\
+                --boundary--\r\n"
+            )
+        };
+        let content_type = "multipart/form-data; boundary=boundary";
+
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                &res.url,
+                None,
+                content_type,
+                form("a.png", "image/png"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "mime type not enforced");
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                &res.url,
+                None,
+                content_type,
+                form("../a", "text/plain"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "invalid name accepted");
+
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                &res.url,
+                None,
+                content_type,
+                form("a.txt", "text/plain"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+        assert_eq!(obj.id.to_string(), res.id);
+        assert_eq!(obj.data.name, "uploads/a.txt");
+        assert_eq!(obj.data.size, 5);
+
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                &res.url,
+                None,
+                content_type,
+                form("a.txt", "text/plain"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::GONE, "create url reused");
+
+        let body = br#"{"policy":{"folder":"a/../b"}}"#;
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                "/api/file/presign",
+                token,
+                "application/json",
+                body,
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_transfer_progress() {
+        let app = test_app().await;
+
+        let req = Request::get("/api/file/transfer/upload-1234/progress")
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .body(Body::empty())
+            .unwrap();
+        let events = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(events.status(), StatusCode::OK);
+
+        let req = Request::post("/api/file?name=fox.txt")
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .header(TRANSFER_ID_HEADER, "upload-1234")
+            .header(header::CONTENT_LENGTH, CONTENT.len())
+            .body(Body::from(CONTENT))
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let events = tokio::time::timeout(
+            Duration::from_secs(5),
+            to_bytes(events.into_body(), usize::MAX),
+        )
+        .await
+        .expect("progress events did not end with the transfer")
+        .unwrap();
+        let events = String::from_utf8(events.to_vec()).unwrap();
+
+        let last = events
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let last: TransferProgress = serde_json::from_str(last).unwrap();
+        assert_eq!(
+            last,
+            TransferProgress {
+                bytes: CONTENT.len() as u64,
+                total: Some(CONTENT.len() as u64),
+                done: true,
+            },
+        );
+
+        let req = Request::post("/api/file?name=fox.txt")
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .header(TRANSFER_ID_HEADER, "bad id")
+            .body(Body::from(CONTENT))
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_provenance() {
+        let app = test_app().await;
+
+        let req = Request::post("/api/file?name=fox.txt")
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .header(header::USER_AGENT, "downloader-cli/1.0")
+            .body(Body::from(CONTENT))
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let obj = parse_object(&body);
+
+        let provenance = ProvenanceRepository::new(app.db.clone())
+            .get(obj.id)
+            .await
+            .unwrap();
+        assert_eq!(provenance.token_type, TokenType::User);
+        assert_eq!(provenance.token_id, None);
+        assert_eq!(
+            provenance.user_agent.as_deref(),
+            Some("downloader-cli/1.0"),
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_checksum() {
+        use sha2::{Digest, Sha256};
+
+        let app = test_app().await;
+        let sha256 = hex::encode(Sha256::digest(CONTENT));
+        let other = hex::encode(Sha256::digest(b"other"));
+
+        let upload = |uri: String, checksum: Option<&str>| {
+            let mut req = Request::post(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token));
+            if let Some(checksum) = checksum {
+                req = req.header(CONTENT_SHA256_HEADER, checksum);
+            }
+            let req = req.body(Body::from(CONTENT)).unwrap();
+            app.router.clone().oneshot(req)
+        };
+
+        let res = upload("/api/file?name=fox.txt".into(), Some(&other)).await;
+        assert_eq!(res.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res =
+            upload(format!("/api/file?name=fox.txt&sha256={other}"), None)
+                .await;
+        assert_eq!(res.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res =
+            upload("/api/file?name=fox.txt&sha256=xyz".into(), None).await;
+        assert_eq!(res.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(count_files(&app.temp_dir.path().join(INCOMPLETE_DIR)), 0);
+
+        let res =
+            upload(format!("/api/file?name=fox.txt&sha256={sha256}"), None)
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let obj = parse_object(&body);
+        assert_eq!(hex::encode(obj.data.checksum_256), sha256);
+
+        // Only reused when asked to
+        let uri = "/api/file?name=copy.txt&reuse_existing=true";
+        let res = upload(uri.into(), Some(&sha256)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(parse_object(&body), obj);
+
+        let res = upload("/api/file?name=copy.txt".into(), Some(&sha256))
+            .await
+            .unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_ne!(parse_object(&body).id, obj.id);
+        let objects = app.obj_repo.get_all(10, 0).await.unwrap();
+        assert_eq!(objects.len(), 2);
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_checksum_trailer() {
+        use http_body::Frame;
+        use sha2::{Digest, Sha256};
+
+        use crate::storage::trailer::CHECKSUM_TRAILER;
+
+        let app = test_app().await;
+        let upload = |checksum: Option<&[u8]>| {
+            let mut frames =
+                vec![Ok::<_, io::Error>(Frame::data(Bytes::from(CONTENT)))];
+            if let Some(checksum) = checksum {
+                let mut trailers = axum::http::HeaderMap::new();
+                trailers.insert(
+                    CHECKSUM_TRAILER,
+                    hex::encode(checksum).parse().unwrap(),
+                );
+                frames.push(Ok(Frame::trailers(trailers)));
+            }
+            let body = http_body_util::StreamBody::new(stream::iter(frames));
+
+            let req = Request::post("/api/file?name=fox.txt")
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+                .header(header::TRAILER, CHECKSUM_TRAILER)
+                .body(Body::new(body))
+                .unwrap();
+            app.router.clone().oneshot(req)
+        };
+
+        let res = upload(Some(&Sha256::digest(b"other"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res = upload(None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(count_files(&app.temp_dir.path().join(INCOMPLETE_DIR)), 0);
+        assert!(app.obj_repo.get_all(10, 0).await.unwrap().is_empty());
+
+        let sha256 = Sha256::digest(CONTENT);
+        let res = upload(Some(&sha256)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(parse_object(&body).data.checksum_256, sha256.as_slice());
+    }
+
+    #[test(tokio::test)]
+    async fn test_digest_headers() {
+        use sha2::{Digest, Sha256};
+
+        use crate::storage::{
+            format_digest, CONTENT_DIGEST_HEADER, REPR_DIGEST_HEADER,
+        };
+
+        let app = test_app().await;
+        let sha256: [u8; 32] = Sha256::digest(CONTENT).into();
+        let digest = format_digest(&sha256);
+        let other = format_digest(&Sha256::digest(b"other").into());
+
+        let upload = |headers: &[(&str, &str)]| {
+            let mut req = Request::post("/api/file?name=fox.txt")
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token));
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            let req = req.body(Body::from(CONTENT)).unwrap();
+            app.router.clone().oneshot(req)
+        };
+
+        let res = upload(&[(CONTENT_DIGEST_HEADER, &other)]).await;
+        assert_eq!(res.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res = upload(&[(CONTENT_DIGEST_HEADER, "sha-256=:xyz:")]).await;
+        assert_eq!(res.unwrap().status(), StatusCode::BAD_REQUEST);
+        // Digests disagreeing with each other are rejected before storing
+        let res = upload(&[
+            (REPR_DIGEST_HEADER, &digest),
+            (CONTENT_DIGEST_HEADER, &other),
+        ])
+        .await;
+        assert_eq!(res.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(app.obj_repo.get_all(10, 0).await.unwrap().is_empty());
+
+        let value = format!("sha-512=:AAAA:, {digest}");
+        let res = upload(&[(CONTENT_DIGEST_HEADER, &value)]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let obj = parse_object(&body);
+
+        let req = Request::get(format!("/api/file/{}/data", obj.id))
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[REPR_DIGEST_HEADER], digest);
+    }
+
+    #[test(tokio::test)]
+    async fn test_object_names() {
+        let app = test_app().await;
+        let long = "a".repeat(256);
+        for name in ["", "a%0Ab.txt", &long] {
+            let uri = format!("/api/file?name={name}");
+            let (status, body) = app
+                .request(Method::POST, &uri, Some(&app.token), CONTENT)
+                .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let body: serde_json::Value =
+                serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error_code"].as_u64().unwrap() / 1000, 11);
+        }
+        assert!(app.obj_repo.get_all(10, 0).await.unwrap().is_empty());
+
+        let app = test_app_with(|cfg| {
+            cfg.names = NameConfig {
+                max_length: 255,
+                strictness: NameStrictness::Strict,
+                normalize_separators: true,
+            }
+        })
+        .await;
+
+        let uri = "/api/file?name=%5Cdocs%2F%2Ffox.txt";
+        let (status, body) = app
+            .request(Method::POST, uri, Some(&app.token), CONTENT)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+        assert_eq!(obj.data.name, "docs/fox.txt");
+
+        let uri = "/api/file?name=fox%3F.txt";
+        let (status, _) = app
+            .request(Method::POST, uri, Some(&app.token), CONTENT)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let token = Some(app.token.as_str());
+        let uri = format!("/api/file/{}", obj.id);
+        for (name, status) in [
+            ("docs/../fox.txt", StatusCode::BAD_REQUEST),
+            ("docs\\\\dog.txt", StatusCode::OK),
+        ] {
+            let body =
+                format!(r#"{{"name":"{name}","mime_type":"text/plain"}}"#);
+            let (res, _) = app
+                .request_with(
+                    Method::PUT,
+                    &uri,
+                    token,
+                    "application/json",
+                    body,
+                )
+                .await;
+            assert_eq!(res, status);
+        }
+        let obj = app.obj_repo.get(obj.id).await.unwrap();
+        assert_eq!(obj.data.name, "docs/dog.txt");
+
+        let uri = format!("/api/file/{}/data?name=fox.", obj.id);
+        let (status, _) = app
+            .request(Method::PUT, &uri, Some(&app.token), CONTENT)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_multipart_metadata() {
+        let app = test_app().await;
+        let groups = GroupRepository::new(app.db.clone());
+        let group = groups
+            .create(&GroupData {
+                name: "readers".into(),
+                permission: Permission::empty(),
+            })
+            .await
+            .unwrap();
+
+        let form = |metadata: &str| {
+            format!(
+                "--boundary\r\n\
+                Content-Disposition: form-data; name=\"metadata\"\r\n\
+                Content-Type: application/json\r\n\r\n\
+                {metadata}\r\n\
+                --boundary\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                Content-Type: text/plain\r\n\r\n\
+                hello\r\n\
+                --boundary--\r\n"
+            )
+        };
+        let upload = |body: String| {
+            let token = Some(app.token.as_str());
+            let content_type = "multipart/form-data; boundary=boundary";
+            app.request_with(
+                Method::POST,
+                "/api/file/multipart",
+                token,
+                content_type,
+                body,
+            )
+        };
+
+        let metadata = format!(
+            r#"{{"name":"b.txt","folder":"/docs/","groups":["{}"],
+            "available_from":"2100-01-01T00:00:00Z"}}"#,
+            group.id,
+        );
+        let (status, body) = upload(form(&metadata)).await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+        assert_eq!(obj.data.name, "docs/b.txt");
+        assert_eq!(obj.data.size, 5);
+
+        let (status, body) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{}/embargo", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let embargo: EmbargoData = serde_json::from_slice(&body).unwrap();
+        assert!(embargo.available_from.is_some());
+        let shares = groups.get_shares(obj.id).await.unwrap();
+        assert_eq!(shares, vec![group.clone()]);
+
+        // Nothing is stored with invalid metadata
+        for metadata in [
+            r#"{"tags":["a"]}"#.to_owned(),
+            r#"{"folder":"a/../b"}"#.to_owned(),
+            format!(r#"{{"groups":["{}"]}}"#, Uuid::new_v4()),
+            "not json".to_owned(),
+        ] {
+            let (status, _) = upload(form(&metadata)).await;
+            assert!(status.is_client_error(), "{metadata}: {status}");
+        }
+        assert_eq!(app.obj_repo.get_all(10, 0).await.unwrap().len(), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_batch() {
+        let app = test_app().await;
+        let token = Some(app.token.as_str());
+
+        let ndjson = format!(
+            "{}\n\n{}\n",
+            r#"{"name":"a.txt","mime_type":"text/plain","data":"aGVsbG8="}"#,
+            r#"{"name":"b.bin","data":""}"#,
+        );
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file/batch",
+                token,
+                "application/x-ndjson",
+                ndjson,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let objects: Vec<PublicObject> = serde_json::from_slice(&body).unwrap();
+        let objects: Vec<Object> =
+            objects.into_iter().map(into_object).collect();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].data.name, "a.txt");
+        assert_eq!(objects[0].data.mime_type, "text/plain");
+        assert_eq!(objects[0].data.size, 5);
+        assert_eq!(objects[1].data.mime_type, "application/octet-stream");
+        assert_eq!(objects[1].data.size, 0);
+
+        let uri = format!("/api/file/{}/data", objects[0].id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"hello");
+
+        let form = "--boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"c.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            one\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"d.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            two\r\n\
+            --boundary--\r\n";
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file/batch",
+                token,
+                "multipart/form-data; boundary=boundary",
+                form,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let objects: Vec<PublicObject> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(app.obj_repo.get_all(10, 0).await.unwrap().len(), 4);
+        assert!(app.obj_repo.create_intents().await.unwrap().is_empty());
+
+        // Nothing is created if any file is invalid
+        let too_large = format!(
+            r#"{{"name":"e.bin","data":"{}"}}"#,
+            BASE64_STANDARD.encode(vec![0; MAX_BATCH_FILE_SIZE + 1]),
+        );
+        for (content_type, body) in [
+            ("application/x-ndjson", String::new()),
+            ("application/x-ndjson", "not json".to_owned()),
+            (
+                "application/x-ndjson",
+                r#"{"name":"e.txt","data":"not base64"}"#.to_owned(),
+            ),
+            ("application/x-ndjson", too_large),
+            ("text/plain", "hello".to_owned()),
+        ] {
+            let (status, _) = app
+                .request_with(
+                    Method::POST,
+                    "/api/file/batch",
+                    token,
+                    content_type,
+                    body,
+                )
+                .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{content_type}");
+        }
+
+        app.manager.set_faults(Faults {
+            fail_store: true,
+            ..Default::default()
+        });
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                "/api/file/batch",
+                token,
+                "application/x-ndjson",
+                r#"{"name":"f.txt","data":"aGVsbG8="}"#,
+            )
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(app.obj_repo.get_all(10, 0).await.unwrap().len(), 4);
+        assert!(app.obj_repo.create_intents().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        for (name, inline, expected) in [
+            ("fox.txt", false, r#"attachment; filename="fox.txt""#),
+            ("docs/fox.txt", true, r#"inline; filename="fox.txt""#),
+            ("", false, r#"attachment; filename="download""#),
+            (
+                "a \"b\".txt",
+                false,
+                r#"attachment; filename="a _b_.txt"; filename*=UTF-8''a%20%22b%22.txt"#,
+            ),
+            (
+                "line\nbreak",
+                false,
+                r#"attachment; filename="line_break"; filename*=UTF-8''line%0Abreak"#,
+            ),
+            (
+                "résumé.pdf",
+                false,
+                r#"attachment; filename="r_sum_.pdf"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"#,
+            ),
+        ] {
+            assert_eq!(content_disposition(name, inline), expected);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_download_unicode_name() {
+        let app = test_app().await;
+        let (status, body) = app
+            .request(
+                Method::POST,
+                "/api/file?name=%C3%A9t%C3%A9%22.txt",
+                Some(&app.token),
+                CONTENT,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+        assert_eq!(obj.data.name, "été\".txt");
+
+        for (query, disposition) in
+            [("", "attachment"), ("?inline=true", "inline")]
+        {
+            let req = Request::get(format!("/api/file/{}/data{query}", obj.id))
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+                .body(Body::empty())
+                .unwrap();
+            let res = app.router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let value =
+                res.headers()[header::CONTENT_DISPOSITION].to_str().unwrap();
+            assert_eq!(
+                value,
+                format!(
+                    "{disposition}; filename=\"_t__.txt\"; \
+                    filename*=UTF-8''%C3%A9t%C3%A9%22.txt"
+                ),
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_download_inline_html() {
+        let app = test_app().await;
+        let html = b"<script>alert(document.cookie)</script>";
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file?name=xss.html",
+                Some(&app.token),
+                "text/html",
+                html,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+
+        let req =
+            Request::get(format!("/api/file/{}/data?inline=true", obj.id))
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+                .body(Body::empty())
+                .unwrap();
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            r#"attachment; filename="xss.html""#,
+            "html must not be rendered inline",
+        );
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::CONTENT_SECURITY_POLICY], "sandbox");
+
+        for (mime_type, passive) in [
+            ("text/plain; charset=utf-8", true),
+            ("image/png", true),
+            ("IMAGE/SVG+XML", false),
+            ("application/pdf", true),
+            ("video/mp4", true),
+            ("text/html", false),
+            ("application/xhtml+xml", false),
+            ("text/xml", false),
+            ("", false),
+        ] {
+            assert_eq!(is_passive(mime_type), passive, "{mime_type}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_preview() {
+        use crate::storage::preview::Preview;
+
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        let uri = format!("/api/file/{}/preview", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let preview: Preview = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            preview,
+            Preview::Text {
+                charset: "UTF-8".into(),
+                text: String::from_utf8(CONTENT.to_vec()).unwrap(),
+                truncated: false,
+            },
+        );
+
+        let uri = format!("/api/file/{}/preview?kib=0", obj.id);
+        let (status, _) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let token = Some(app.token.as_str());
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR";
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file?name=a.png",
+                token,
+                "image/png",
+                png,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let obj = parse_object(&body);
+
+        let uri = format!("/api/file/{}/preview", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let preview: Preview = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(preview, Preview::Stub { size: 16, .. }));
+    }
+
+    #[test(tokio::test)]
+    async fn test_download_fetch_failure() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        app.manager.set_faults(Faults {
+            fail_fetch: true,
+            ..Default::default()
+        });
+
+        let uri = format!("/api/file/{}/data", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body: serde_json::Value = serde_json::from_slice(&body)
+            .expect("expected json error response");
+        assert!(body["error_code"].is_number());
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_failure() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        app.manager.set_faults(Faults {
+            fail_delete: true,
+            ..Default::default()
+        });
+
+        let uri = format!("/api/file/{}", obj.id);
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&app.token), b"")
+            .await;
+        assert_eq!(
+            status,
+            StatusCode::OK,
+            "data deletion happens in background and must not fail the request",
+        );
+        assert!(app.obj_repo.get(obj.id).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_files_page_envelope() {
+        let app = test_app().await;
+        let objs = [upload(&app).await, upload(&app).await, upload(&app).await];
+        let user_id = objs[0].user_id;
+
+        let get = |uri: String, envelope: bool| {
+            let mut req = Request::get(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token));
+            if envelope {
+                req = req.header(header::ACCEPT, PAGE_MEDIA_TYPE);
+            }
+            let res =
+                app.router.clone().oneshot(req.body(Body::empty()).unwrap());
+            async move {
+                let res = res.await.unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                to_bytes(res.into_body(), usize::MAX).await.unwrap()
+            }
+        };
+
+        // Bare arrays unless opted in
+        let body =
+            get(format!("/api/file/user/{user_id}?limit=2"), false).await;
+        let items: Vec<PublicObject> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(items.len(), 2);
+
+        let body = get(format!("/api/file/user/{user_id}?limit=2"), true).await;
+        let page: Page<PublicObject> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page.items, items);
+        assert_eq!(page.total, Some(3));
+        let cursor = page.next_cursor.unwrap();
+
+        let uri = format!("/api/file/user/{user_id}?limit=2&offset={cursor}");
+        let page: Page<PublicObject> =
+            serde_json::from_slice(&get(uri, true).await).unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(into_object(page.items[0].clone()).id, objs[2].id);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test(tokio::test)]
+    async fn test_file_history() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let token = Some(app.token.as_str());
+
+        let body = r#"{"name":"dog.txt","mime_type":"text/plain"}"#;
+        let uri = format!("/api/file/{}", obj.id);
+        let (status, _) = app
+            .request_with(Method::PUT, &uri, token, "application/json", body)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = format!("/api/file/{}/history", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let history: ObjectHistory = serde_json::from_slice(&body).unwrap();
+        assert_eq!(history.updated_by, Some(obj.user_id));
+        assert_eq!(history.entries.len(), 1);
+
+        let entry = &history.entries[0];
+        assert_eq!(entry.user_id, Some(obj.user_id));
+        assert_eq!(entry.token_type, TokenType::User);
+        assert_eq!(entry.previous_name, "fox.txt");
+        assert_eq!(entry.name, "dog.txt");
+        assert_eq!(entry.fields, ["name"]);
+
+        let other = app
+            .token_repo
+            .generate_user_token(
+                Uuid::new_v4(),
+                Permission::UNPRIVILEGED,
+                "other".into(),
+            )
+            .unwrap();
+        let (status, _) = app
+            .request_with(Method::GET, &uri, Some(&other), "", b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_files_by_user() {
+        let app = test_app().await;
+        let objs = [upload(&app).await, upload(&app).await];
+        let uri = format!("/api/file/user/{}", objs[0].user_id);
+
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let admin = app
+            .token_repo
+            .generate_user_token(
+                Uuid::new_v4(),
+                Permission::WRITE_ALL,
+                "admin".into(),
+            )
+            .unwrap();
+        let (status, body) = app
+            .request_with(Method::DELETE, &uri, Some(&admin), "", b"")
+            .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let job: Job = serde_json::from_slice(&body).unwrap();
+        assert_eq!(job.kind, JobKind::DeleteUserObjects);
+
+        for _ in 0..100 {
+            let job = app.jobs.get(job.id).await.unwrap();
+            if !job.state.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+
+            assert_eq!(job.state, JobState::Succeeded, "{:?}", job.error);
+            assert_eq!(job.progress, 2);
+            assert_eq!(
+                job.result.unwrap(),
+                serde_json::json!({ "deleted": 2, "failed": 0 }),
+            );
+            for obj in &objs {
+                assert!(app.obj_repo.get(obj.id).await.is_err());
+            }
+            assert_eq!(count_files(app.data_dir.path()), 0);
+            return;
+        }
+        panic!("job did not finish");
+    }
+
+    #[test(tokio::test)]
+    async fn test_files_version() {
+        let app = test_app().await;
+
+        let get_version = |etag: Option<String>| {
+            let mut req = Request::builder()
+                .uri("/api/file/version")
+                .header(header::AUTHORIZATION, format!("Bearer {}", app.token));
+            if let Some(etag) = etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+
+            let res =
+                app.router.clone().oneshot(req.body(Body::empty()).unwrap());
+            async move {
+                let res = res.await.unwrap();
+                let etag =
+                    res.headers()[header::ETAG].to_str().unwrap().to_owned();
+                (res.status(), etag)
+            }
+        };
+
+        let (status, initial) = get_version(None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let obj = upload(&app).await;
+        let (status, etag) = get_version(Some(initial.clone())).await;
+        assert_eq!(status, StatusCode::OK, "version did not change on upload");
+        assert_ne!(etag, initial);
+
+        let (status, _) = get_version(Some(format!("W/{etag}"))).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let uri = format!("/api/file/version?user_id={}", Uuid::new_v4());
+        let (status, _) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let uri = format!("/api/file/version?user_id={}", obj.user_id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let version: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(version["version"], 1);
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_stalled() {
+        let app = test_app_with(|cfg| {
+            cfg.upload_timeout = Duration::from_secs(60);
+            cfg.upload_min_rate = 1024;
+            cfg.upload_rate_window = Duration::from_millis(100);
+        })
+        .await;
+
+        let body =
+            stream::once(async { Ok::<_, io::Error>(Bytes::from(CONTENT)) })
+                .chain(stream::pending());
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/file?name=fox.txt")
+            .header(header::AUTHORIZATION, format!("Bearer {}", app.token))
+            .body(Body::from_stream(body))
+            .unwrap();
+
+        let res = app.router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+
+        assert_eq!(
+            count_files(&app.temp_dir.path().join(INCOMPLETE_DIR)),
+            0,
+            "temp file of stalled upload was not cleaned up",
+        );
+        assert!(app.obj_repo.get_all(10, 0).await.unwrap().is_empty());
+    }
+
+    async fn remote_file() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/fox.txt", listener.local_addr().unwrap());
+        let remote = Router::new().route(
+            "/fox.txt",
+            routing::get(|| async {
+                ([(header::CONTENT_TYPE, "text/plain")], CONTENT)
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, remote).await });
+
+        url
+    }
+
+    fn fetch_config() -> FetchConfig {
+        FetchConfig {
+            allowed_hosts: vec![],
+            allow_private: true,
+            max_size: 1024,
+            max_redirects: 0,
+            connect_timeout: Duration::from_secs(5),
+            max_jobs_per_user: 0,
+            daily_max_bytes_per_user: 0,
+            allowed_folders: vec![],
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_file() {
+        let app = test_app().await;
+        let url = remote_file().await;
+
+        let body = format!(r#"{{"url":"{url}"}}"#);
+        let token = Some(app.token.as_str());
+        let (status, _) = app
+            .request_with(
+                Method::POST,
+                "/api/file/fetch",
+                token,
+                "application/json",
+                body.clone(),
+            )
+            .await;
+        assert_eq!(
+            status,
+            StatusCode::NOT_FOUND,
+            "fetch must be disabled by default"
+        );
+
+        let app = test_app_with(|cfg| cfg.fetch = Some(fetch_config())).await;
+        let token = Some(app.token.as_str());
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file/fetch",
+                token,
+                "application/json",
+                body,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let obj = parse_object(&body);
+        assert_eq!(obj.data.name, "fox.txt");
+        assert_eq!(obj.data.mime_type, "text/plain");
+        assert_eq!(obj.data.size, CONTENT.len() as u64);
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_file_background() {
+        let app = test_app_with(|cfg| cfg.fetch = Some(fetch_config())).await;
+        let url = remote_file().await;
+
+        let body = format!(r#"{{"url":"{url}","background":true}}"#);
+        let token = Some(app.token.as_str());
+        let (status, body) = app
+            .request_with(
+                Method::POST,
+                "/api/file/fetch",
+                token,
+                "application/json",
+                body,
+            )
+            .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let job: Job = serde_json::from_slice(&body).unwrap();
+
+        for _ in 0..100 {
+            let job = app.jobs.get(job.id).await.unwrap();
+            if !job.state.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+
+            assert_eq!(job.state, JobState::Succeeded, "{:?}", job.error);
+            assert_eq!(job.progress, CONTENT.len() as u64);
+            assert_eq!(job.total, Some(CONTENT.len() as u64));
+
+            let obj = into_object(
+                serde_json::from_value(job.result.unwrap()).unwrap(),
+            );
+            assert_eq!(obj.data.name, "fox.txt");
+            assert_eq!(app.obj_repo.get(obj.id).await.unwrap(), obj);
+            assert_eq!(count_files(&app.temp_dir.path().join("fetch")), 0);
+            return;
+        }
+        panic!("fetch job did not finish");
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_file_limits() {
+        let app = test_app_with(|cfg| {
+            cfg.fetch = Some(FetchConfig {
+                max_jobs_per_user: 1,
+                daily_max_bytes_per_user: CONTENT.len() as u64,
+                allowed_folders: vec!["users/{username}".into()],
+                ..fetch_config()
+            })
+        })
+        .await;
+        let url = remote_file().await;
+
+        let fetch = |body: String| {
+            let token = Some(app.token.as_str());
+            app.request_with(
+                Method::POST,
+                "/api/file/fetch",
+                token,
+                "application/json",
+                body,
+            )
+        };
+
+        for body in [
+            format!(r#"{{"url":"{url}"}}"#),
+            format!(r#"{{"url":"{url}","folder":"users/other"}}"#),
+            format!(r#"{{"url":"{url}","name":"../other/fox.txt"}}"#),
+        ] {
+            let (status, _) = fetch(body).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        let body = format!(r#"{{"url":"{url}","folder":"a//b"}}"#);
+        assert_eq!(fetch(body).await.0, StatusCode::BAD_REQUEST);
+
+        let body = format!(r#"{{"url":"{url}","folder":"/users/test/"}}"#);
+        let (status, res) = fetch(body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parse_object(&res).data.name, "users/test/fox.txt");
+
+        // The whole daily limit was fetched
+        let (status, _) = fetch(body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let Token::User(user) =
+            app.token_repo.decode_token(&app.token).unwrap()
+        else {
+            unreachable!()
+        };
+        sqlx::query("DELETE FROM fetch_usage")
+            .execute(&app.db)
+            .await
+            .unwrap();
+        let states = FetchStateRepository::new(app.db.clone());
+        states
+            .create(&FetchState {
+                job_id: Uuid::new_v4(),
+                user_id: user.user_id,
+                namespace: default_namespace(),
+                url: url.clone(),
+                name: None,
+                folder: Some("users/test".into()),
+                mime_type: None,
+                provenance: Uploader::detached(None).provenance(None),
+                fetched: 0,
+                total: None,
+                etag: None,
+                last_modified: None,
+            })
+            .await
+            .unwrap();
+
+        let body = format!(
+            r#"{{"url":"{url}","folder":"users/test","background":true}}"#
+        );
+        let (status, _) = fetch(body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test(tokio::test)]
+    async fn test_resume_fetch_file() {
+        let app = test_app().await;
+        let url = format!("{}/ranged", fetch::tests::server().await);
+        let Token::User(user) =
+            app.token_repo.decode_token(&app.token).unwrap()
+        else {
+            unreachable!()
+        };
+
+        // What a restart leaves behind after fetching the first bytes
+        let job = app
+            .jobs
+            .create(JobKind::FetchFile, Some(user.user_id))
+            .await
+            .unwrap();
+        let state = FetchState {
+            job_id: job.id,
+            user_id: user.user_id,
+            namespace: default_namespace(),
+            url,
+            name: Some("digits.txt".into()),
+            folder: None,
+            mime_type: None,
+            provenance: Uploader::detached(None).provenance(None),
+            fetched: 4,
+            total: Some(10),
+            etag: Some(fetch::tests::ETAG.into()),
+            last_modified: None,
+        };
+        let states = FetchStateRepository::new(app.db.clone());
+        states.create(&state).await.unwrap();
+        states.update(&state).await.unwrap();
+        let partial = app.temp_dir.path().join("fetch");
+        std::fs::create_dir(&partial).unwrap();
+        std::fs::write(partial.join(job.id.to_string()), b"abcd").unwrap();
+
+        let fetches = FetchJobs::new(app.db.clone(), app.temp_dir.path());
+        let ctx = FetchContext {
+            fetcher: Arc::new(fetch::tests::fetcher(1024)),
+            repo: app.obj_repo.clone(),
+            manager: app.manager.clone(),
+            limits: UploadLimits {
+                timeout: Duration::ZERO,
+                min_rate: 0,
+                rate_window: Duration::ZERO,
+            },
+            ids: ObjectIds::default(),
+            uploader: Uploader::detached(None),
+            quota: FetchQuota::new(app.db.clone(), &fetch_config()),
+        };
+        assert_eq!(fetches.resume(&app.jobs, Some(ctx)).await.unwrap(), 1);
+
+        for _ in 0..100 {
+            let job = app.jobs.get(job.id).await.unwrap();
+            if !job.state.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+
+            assert_eq!(job.state, JobState::Succeeded, "{:?}", job.error);
+            assert_eq!(job.progress, 10);
+            assert_eq!(job.total, Some(10));
+
+            // Only the rest of the file was fetched
+            let obj = into_object(
+                serde_json::from_value(job.result.unwrap()).unwrap(),
+            );
+            assert_eq!(obj.data.name, "digits.txt");
+            let (status, body) = app
+                .request(
+                    Method::GET,
+                    &format!("/api/file/{}/data", obj.id),
+                    Some(&app.token),
+                    b"",
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, b"abcd456789");
+
+            assert!(states.get_all().await.unwrap().is_empty());
+            assert_eq!(count_files(&partial), 0);
+            return;
+        }
+        panic!("fetch job did not finish");
+    }
+
+    #[test(tokio::test)]
+    async fn test_file_group_share() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+
+        let users =
+            UserRepository::new(app.db.clone(), PasswordHasher::bcrypt(4));
+        let member = users
+            .create(
+                Permission::UNPRIVILEGED,
+                UserData {
+                    username: "member".into(),
+                    password: "password".into(),
+                },
+            )
+            .await
+            .unwrap();
+        let token = app
+            .token_repo
+            .generate_user_token(member.id, member.permission, member.username)
+            .unwrap();
+
+        let groups = GroupRepository::new(app.db.clone());
+        let group = groups
+            .create(&GroupData {
+                name: "readers".into(),
+                permission: Permission::empty(),
+            })
+            .await
+            .unwrap();
+        groups.add_member(group.id, member.id).await.unwrap();
+
+        let json = "application/json";
+        let data = format!("/api/file/{}/data", obj.id);
+        let share = format!("/api/file/{}/groups/{}", obj.id, group.id);
+        let token = Some(token.as_str());
+
+        let (status, _) =
+            app.request_with(Method::GET, &data, token, json, b"").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Only the owner may share the file
+        let (status, _) = app
+            .request_with(Method::PUT, &share, token, json, b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = app
+            .request(Method::PUT, &share, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let shares: Vec<Group> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(shares, vec![group.clone()]);
+
+        let (status, body) =
+            app.request_with(Method::GET, &data, token, json, b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+        let file = format!("/api/file/{}", obj.id);
+        let (status, _) =
+            app.request_with(Method::GET, &file, token, json, b"").await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Members are held by embargoes like any other share
+        let available_from = (Utc::now() + TimeDelta::hours(1)).to_rfc3339();
+        let embargo = format!(r#"{{"available_from":"{available_from}"}}"#);
+        let embargo_uri = format!("/api/file/{}/embargo", obj.id);
+        let (status, _) = app
+            .request_with(
+                Method::PUT,
+                &embargo_uri,
+                Some(&app.token),
+                json,
+                &embargo,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let preview = format!("/api/file/{}/preview", obj.id);
+        for uri in [&data, &preview] {
+            let (status, _) =
+                app.request_with(Method::GET, uri, token, json, b"").await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{uri}");
+            let (status, _) =
+                app.request(Method::GET, uri, Some(&app.token), b"").await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+        }
+        let (status, _) = app
+            .request_with(
+                Method::PUT,
+                &embargo_uri,
+                Some(&app.token),
+                json,
+                r#"{"available_from":null}"#,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app
+            .request_with(Method::GET, &preview, token, json, b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = app
+            .request(Method::DELETE, &share, Some(&app.token), b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let shares: Vec<Group> = serde_json::from_slice(&body).unwrap();
+        assert!(shares.is_empty());
+
+        let (status, _) =
+            app.request_with(Method::GET, &data, token, json, b"").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test(tokio::test)]
+    async fn test_file_stats() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let json = "application/json";
+
+        let data = format!("/api/file/{}/data", obj.id);
+        for _ in 0..2 {
+            let (status, _) =
+                app.request(Method::GET, &data, Some(&app.token), b"").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let url = presign(&app, obj.id, br#"{"action":"download"}"#).await.url;
+        let (status, _) =
+            app.request_with(Method::GET, &url, None, json, b"").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = app
+            .request(
+                Method::GET,
+                &format!("/api/file/{}", obj.id),
+                Some(&app.token),
+                b"",
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let exposed: PublicObject = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exposed.downloads, 3);
+        assert!(exposed.last_accessed_at.is_some());
+
+        let uri = format!("/api/file/{}/stats?days=7", obj.id);
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+        let stats: FileStatsData = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats.downloads, 3);
+        assert_eq!(stats.last_accessed_at, exposed.last_accessed_at);
+        assert_eq!(stats.daily.len(), 7);
+        assert_eq!(stats.daily[6].date, Utc::now().date_naive());
+        assert_eq!(stats.daily[6].downloads, 3);
+
+        let file_token = app
+            .token_repo
+            .generate_file_token(
+                obj.id,
+                Duration::from_secs(60),
+                "test".into(),
+                Permission::SINGLE_FILE_R,
+                FileScope::DOWNLOAD,
+            )
+            .unwrap();
+        let (status, _) = app
+            .request_with(Method::GET, &uri, Some(&file_token), json, b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let uri = format!("/api/file/{}/stats?days=0", obj.id);
+        let (status, _) =
+            app.request(Method::GET, &uri, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_embargo() {
+        let app = test_app().await;
+        let obj = upload(&app).await;
+        let json = "application/json";
+        let token = Some(app.token.as_str());
+
+        let file_token = app
+            .token_repo
+            .generate_file_token(
+                obj.id,
+                Duration::from_secs(60),
+                "test".into(),
+                Permission::SINGLE_FILE_R,
+                FileScope::DOWNLOAD,
+            )
+            .unwrap();
+        let url = presign(&app, obj.id, br#"{"action":"download"}"#).await.url;
+
+        let available_from = (Utc::now() + TimeDelta::hours(1)).to_rfc3339();
+        let embargo = format!(r#"{{"available_from":"{available_from}"}}"#);
+        let uri = format!("/api/file/{}/embargo", obj.id);
+        let (status, _) = app
+            .request_with(Method::PUT, &uri, token, json, &embargo)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app
+            .request_with(Method::PUT, &uri, Some(&file_token), json, &embargo)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let data = format!("/api/file/{}/data", obj.id);
+        let (status, body) = app
+            .request_with(Method::GET, &data, Some(&file_token), json, b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(String::from_utf8(body).unwrap().contains("not available"));
+        let (status, _) =
+            app.request_with(Method::GET, &url, None, json, b"").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Owners are not restricted
+        let (status, _) =
+            app.request(Method::GET, &data, Some(&app.token), b"").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app
+            .request_with(
+                Method::PUT,
+                &uri,
+                token,
+                json,
+                r#"{"available_from":null}"#,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app
+            .request_with(Method::GET, &data, Some(&file_token), json, b"")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        // Rejected urls are not used up
+        let (status, body) =
+            app.request_with(Method::GET, &url, None, json, b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+
+        // Urls must become available before they expire
+        let available_from = (Utc::now() + TimeDelta::minutes(10)).to_rfc3339();
+        let presign_uri = format!("/api/file/{}/presign", obj.id);
+        let body = format!(
+            r#"{{"action":"download","available_from":"{available_from}"}}"#,
+        );
+        let (status, body) = app
+            .request_with(Method::POST, &presign_uri, token, json, body)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let res: PresignResponseData = serde_json::from_slice(&body).unwrap();
+        let (status, _) = app
+            .request_with(Method::GET, &res.url, None, json, b"")
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let body = format!(
+            r#"{{"action":"upload","available_from":"{available_from}"}}"#,
+        );
+        let (status, _) = app
+            .request_with(Method::POST, &presign_uri, token, json, body)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,703 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{async_trait, http::StatusCode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    Database, Encode, Executor, FromRow, IntoArguments, Pool, Sqlite, Type,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use uuid::Uuid;
+
+use crate::{
+    config::ScanningConfig, errors::DownloaderError, utils::retry::retry_busy,
+};
+
+use super::{
+    manager::{Manager, ObjectError},
+    pipeline::{DataStore, Step, StepContext},
+    repository::{ObjectRepository, RepositoryError},
+    Object,
+};
+
+/// Objects scanned by each pass of [`run_scanner`].
+const SCAN_BATCH_SIZE: i64 = 16;
+/// Size of the chunks the data is streamed to clamd in.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error("the file was not scanned for malware yet")]
+    Unscanned,
+    #[error("the file is infected with `{0}`")]
+    Infected(String),
+}
+
+impl ScanError {
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ScanError::Unscanned => StatusCode::LOCKED,
+            ScanError::Infected(..) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+crate::errors::error_codes! {
+    ScanError {
+        Unscanned => 1, "the file was not scanned for malware yet";
+        Infected(..) => 2, "the file is infected";
+    }
+}
+
+/// What is done to infected objects.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScanAction {
+    /// Kept, but can not be downloaded anymore.
+    #[default]
+    Quarantine,
+    /// Deleted right away, without going through the trash.
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    Clean = 0,
+    Infected = 1,
+    /// Could not be scanned, retried on the next start.
+    Failed = 2,
+}
+
+impl ScanStatus {
+    fn from_i64(status: i64) -> Option<Self> {
+        match status {
+            0 => Some(ScanStatus::Clean),
+            1 => Some(ScanStatus::Infected),
+            2 => Some(ScanStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The last scan of the data of an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scan {
+    /// Checksum of the scanned data, the scan is outdated once the object
+    /// has other data.
+    pub checksum_256: [u8; 32],
+    pub status: ScanStatus,
+    /// Name of the malware found, if infected.
+    pub signature: Option<String>,
+}
+
+pub struct ScanRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for ScanRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> ScanRepository<DB> {
+    pub fn new(db: Pool<DB>) -> ScanRepository<DB> {
+        ScanRepository { db }
+    }
+}
+
+impl<DB> ScanRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> (Vec<u8>, i64, Option<String>): FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>, Vec<u8>): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> Option<&'e str>: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<Option<Scan>, RepositoryError> {
+        let row: Option<(Vec<u8>, i64, Option<String>)> = sqlx::query_as(
+            "SELECT checksum_256, status, signature FROM object_scan \
+            WHERE object_id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        row.map(|(checksum_256, status, signature)| {
+            let decode_error = |msg: &str| {
+                RepositoryError::Sqlx(sqlx::Error::Decode(msg.into()))
+            };
+            Ok(Scan {
+                checksum_256: checksum_256.try_into().map_err(|_| {
+                    decode_error("parse `checksum_256` array out of range")
+                })?,
+                status: ScanStatus::from_i64(status).ok_or_else(|| {
+                    decode_error("parse `status` out of range")
+                })?,
+                signature,
+            })
+        })
+        .transpose()
+    }
+
+    pub async fn set(
+        &self,
+        id: Uuid,
+        scan: &Scan,
+    ) -> Result<(), RepositoryError> {
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query(
+                "INSERT OR REPLACE INTO object_scan \
+                (object_id, checksum_256, status, signature, scanned_at) \
+                VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(scan.checksum_256.as_slice())
+            .bind(scan.status as i64)
+            .bind(scan.signature.as_deref())
+            .bind(Utc::now().timestamp_millis())
+            .execute(&self.db)
+        })
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(())
+    }
+
+    /// Objects never scanned, whose data changed since scanned, or whose
+    /// scan failed before `failed_before`, oldest first.
+    pub async fn unscanned(
+        &self,
+        failed_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, [u8; 32])>, RepositoryError> {
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = sqlx::query_as(
+            "SELECT o.id, o.checksum_256 FROM object o \
+            LEFT JOIN object_scan s ON s.object_id = o.id \
+            WHERE s.object_id IS NULL \
+            OR s.checksum_256 != o.checksum_256 \
+            OR (s.status = $1 AND s.scanned_at < $2) \
+            ORDER BY o.created_at LIMIT $3",
+        )
+        .bind(ScanStatus::Failed as i64)
+        .bind(failed_before.timestamp_millis())
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        rows.into_iter()
+            .map(|(id, checksum_256)| {
+                let id = Uuid::from_slice(&id).ok();
+                let checksum_256 = checksum_256.try_into().ok();
+                id.zip(checksum_256).ok_or_else(|| {
+                    RepositoryError::Sqlx(sqlx::Error::Decode(
+                        "parse unscanned object out of range".into(),
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object scans");
+    RepositoryError::Sqlx(error)
+}
+
+/// What clamd found in the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClamdReply {
+    Clean,
+    Infected(String),
+    /// Clamd could not scan the data, like when larger than it accepts.
+    Error(String),
+}
+
+/// Client of the `INSTREAM` command of clamd.
+#[derive(Debug, Clone)]
+pub struct Clamd {
+    address: String,
+    timeout: Duration,
+}
+
+impl Clamd {
+    /// `address` is either the path of a unix socket or `host:port`.
+    pub fn new(address: String, timeout: Duration) -> Self {
+        Self { address, timeout }
+    }
+
+    pub async fn scan(
+        &self,
+        data: impl AsyncRead + Unpin,
+    ) -> io::Result<ClamdReply> {
+        let scan = async {
+            if self.address.starts_with('/') {
+                #[cfg(unix)]
+                {
+                    let conn =
+                        tokio::net::UnixStream::connect(&self.address).await?;
+                    instream(conn, data).await
+                }
+                #[cfg(not(unix))]
+                Err(io::Error::from(ErrorKind::Unsupported))
+            } else {
+                let conn = TcpStream::connect(&self.address).await?;
+                instream(conn, data).await
+            }
+        };
+
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| io::Error::from(ErrorKind::TimedOut))?
+    }
+}
+
+async fn instream<C>(
+    mut conn: C,
+    mut data: impl AsyncRead + Unpin,
+) -> io::Result<ClamdReply>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let sent = async {
+        conn.write_all(b"zINSTREAM\0").await?;
+        let mut buf = vec![0; CLAMD_CHUNK_SIZE];
+        loop {
+            let n = data.read(&mut buf).await?;
+            conn.write_all(&(n as u32).to_be_bytes()).await?;
+            if n == 0 {
+                return io::Result::Ok(());
+            }
+            conn.write_all(&buf[..n]).await?;
+        }
+    }
+    .await;
+
+    // Clamd replies before closing the connection when the data is larger
+    // than it accepts, failing the rest of the writes
+    let mut reply = Vec::new();
+    let read = conn.read_to_end(&mut reply).await;
+    match (parse_reply(&reply), sent) {
+        (Some(reply), _) => Ok(reply),
+        (None, Err(error)) => Err(error),
+        (None, Ok(())) => Err(read.err().unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unexpected clamd reply `{}`",
+                    String::from_utf8_lossy(&reply),
+                ),
+            )
+        })),
+    }
+}
+
+fn parse_reply(reply: &[u8]) -> Option<ClamdReply> {
+    let reply = std::str::from_utf8(reply).ok()?;
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let reply = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if reply == "OK" {
+        Some(ClamdReply::Clean)
+    } else if let Some(signature) = reply.strip_suffix(" FOUND") {
+        Some(ClamdReply::Infected(signature.into()))
+    } else {
+        let error = reply.strip_suffix(" ERROR")?;
+        Some(ClamdReply::Error(error.into()))
+    }
+}
+
+/// Scans the data of objects with clamd, blocking the downloads of the ones
+/// not scanned yet or infected.
+#[derive(Clone)]
+pub struct Scanner {
+    clamd: Arc<Clamd>,
+    action: ScanAction,
+    scans: ScanRepository<Sqlite>,
+}
+
+impl Scanner {
+    pub fn new(cfg: &ScanningConfig, scans: ScanRepository<Sqlite>) -> Self {
+        Self {
+            clamd: Arc::new(Clamd::new(cfg.clamd.clone(), cfg.timeout)),
+            action: cfg.action,
+            scans,
+        }
+    }
+
+    /// Fails unless the current data of the object was scanned and found
+    /// clean.
+    pub async fn check(&self, object: &Object) -> Result<(), DownloaderError> {
+        match self.scans.get(object.id).await? {
+            Some(scan) if scan.checksum_256 != object.data.checksum_256 => {
+                Err(ScanError::Unscanned.into())
+            }
+            Some(Scan {
+                status: ScanStatus::Clean,
+                ..
+            }) => Ok(()),
+            Some(Scan {
+                status: ScanStatus::Infected,
+                signature,
+                ..
+            }) => {
+                Err(ScanError::Infected(signature.unwrap_or_default()).into())
+            }
+            Some(..) | None => Err(ScanError::Unscanned.into()),
+        }
+    }
+
+    /// Scans a batch of the objects not scanned yet, returning how many.
+    /// Fails without recording anything if clamd can not be reached.
+    pub async fn scan_unscanned<M: Manager>(
+        &self,
+        repo: &ObjectRepository<Sqlite>,
+        manager: &M,
+        failed_before: DateTime<Utc>,
+    ) -> Result<usize, DownloaderError> {
+        let objects =
+            self.scans.unscanned(failed_before, SCAN_BATCH_SIZE).await?;
+
+        for &(id, checksum_256) in &objects {
+            self.scan_object(repo, manager, id, checksum_256).await?;
+        }
+        Ok(objects.len())
+    }
+
+    /// Scans the data of the object `id`, recording the outcome unless
+    /// clamd can not be reached.
+    async fn scan_object(
+        &self,
+        repo: &ObjectRepository<Sqlite>,
+        store: &dyn DataStore,
+        id: Uuid,
+        checksum_256: [u8; 32],
+    ) -> Result<Scan, DownloaderError> {
+        let reply = match store.open(id).await {
+            Ok(reader) => self.clamd.scan(reader).await.map_err(|error| {
+                tracing::warn!(%error, %id, "failed to scan object");
+                ObjectError::from(error)
+            })?,
+            Err(ObjectError::NotFound) => {
+                ClamdReply::Error("the data was not found".into())
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let (status, signature) = match reply {
+            ClamdReply::Clean => (ScanStatus::Clean, None),
+            ClamdReply::Infected(signature) => {
+                (ScanStatus::Infected, Some(signature))
+            }
+            ClamdReply::Error(error) => {
+                tracing::error!(%error, %id, "clamd could not scan object");
+                (ScanStatus::Failed, None)
+            }
+        };
+        let scan = Scan {
+            checksum_256,
+            status,
+            signature,
+        };
+        self.scans.set(id, &scan).await?;
+
+        if let Some(signature) = &scan.signature {
+            tracing::warn!(
+                %id,
+                %signature,
+                action = ?self.action,
+                "found infected object",
+            );
+            if self.action == ScanAction::Delete {
+                self.delete(repo, store, id).await?;
+            }
+        }
+        Ok(scan)
+    }
+
+    async fn delete(
+        &self,
+        repo: &ObjectRepository<Sqlite>,
+        store: &dyn DataStore,
+        id: Uuid,
+    ) -> Result<(), DownloaderError> {
+        match repo.delete(id).await {
+            Ok(..) | Err(RepositoryError::NotFound(..)) => {}
+            Err(error) => return Err(error.into()),
+        }
+        match store.remove(id).await {
+            Ok(()) | Err(ObjectError::NotFound) => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Scanning as a post-processing step, failing unless the data is clean.
+#[async_trait]
+impl Step for Scanner {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    async fn run(
+        &self,
+        ctx: &StepContext<'_>,
+    ) -> Result<serde_json::Value, String> {
+        let object = ctx.object;
+        let scan = self
+            .scan_object(
+                ctx.repo,
+                ctx.store,
+                object.id,
+                object.data.checksum_256,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match scan.status {
+            ScanStatus::Clean => Ok(serde_json::json!({ "status": "clean" })),
+            ScanStatus::Infected => {
+                Err(ScanError::Infected(scan.signature.unwrap_or_default())
+                    .to_string())
+            }
+            ScanStatus::Failed => Err("clamd could not scan the file".into()),
+        }
+    }
+}
+
+/// Scans the objects not scanned yet every `interval`, retrying the ones
+/// whose scans failed before the start once.
+pub async fn run_scanner<M: Manager>(
+    scanner: Scanner,
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    interval: Duration,
+) {
+    let started_at = Utc::now();
+    loop {
+        match scanner
+            .scan_unscanned(&repo, manager.as_ref(), started_at)
+            .await
+        {
+            Ok(0) => {}
+            Ok(count) => {
+                tracing::debug!(count, "scanned objects");
+                continue;
+            }
+            Err(error) => tracing::error!(%error, "failed to scan objects"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::stream;
+    use sqlx::{migrate, SqlitePool};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::storage::{memory::MemoryManager, ObjectData};
+
+    const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR";
+
+    /// Replies like clamd, finding the start of the EICAR test file.
+    async fn fake_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut command = [0; 10];
+                conn.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+
+                let mut data = Vec::new();
+                loop {
+                    let len = conn.read_u32().await.unwrap() as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let start = data.len();
+                    data.resize(start + len, 0);
+                    conn.read_exact(&mut data[start..]).await.unwrap();
+                }
+
+                let reply: &[u8] = if data.starts_with(EICAR) {
+                    b"stream: Eicar-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                conn.write_all(reply).await.unwrap();
+            }
+        });
+        address
+    }
+
+    async fn create(
+        repo: &ObjectRepository<Sqlite>,
+        manager: &MemoryManager,
+        data: &'static [u8],
+    ) -> Object {
+        let id = Uuid::new_v4();
+        let stream = stream::iter([Ok(Bytes::from_static(data))]);
+        let (size, checksum_256) =
+            manager.store(id, stream, None, None).await.unwrap();
+        let data = ObjectData {
+            name: "file.txt".into(),
+            mime_type: "text/plain".into(),
+            size,
+            checksum_256,
+        };
+        repo.create(id, Uuid::new_v4(), data).await.unwrap()
+    }
+
+    fn scanner(
+        db: &SqlitePool,
+        address: String,
+        action: ScanAction,
+    ) -> Scanner {
+        let cfg: ScanningConfig =
+            toml::from_str(&format!("clamd = {address:?}")).unwrap();
+        Scanner::new(
+            &ScanningConfig { action, ..cfg },
+            ScanRepository::new(db.clone()),
+        )
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply(b"stream: OK\0"), Some(ClamdReply::Clean));
+        assert_eq!(
+            parse_reply(b"stream: Eicar-Signature FOUND\0"),
+            Some(ClamdReply::Infected("Eicar-Signature".into())),
+        );
+        assert_eq!(
+            parse_reply(b"INSTREAM size limit exceeded. ERROR\0"),
+            Some(ClamdReply::Error("INSTREAM size limit exceeded.".into())),
+        );
+        assert_eq!(parse_reply(b"UNKNOWN COMMAND\0"), None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_scan_objects() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ObjectRepository::new(db.clone());
+        let manager = MemoryManager::new();
+        let scanner = scanner(&db, fake_clamd().await, ScanAction::Quarantine);
+
+        let clean = create(&repo, &manager, b"hello world").await;
+        let infected = create(&repo, &manager, EICAR).await;
+        for obj in [&clean, &infected] {
+            assert!(matches!(
+                scanner.check(obj).await,
+                Err(DownloaderError::Scan(ScanError::Unscanned)),
+            ));
+        }
+
+        let count = scanner
+            .scan_unscanned(&repo, &manager, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        scanner.check(&clean).await.unwrap();
+        assert!(matches!(
+            scanner.check(&infected).await,
+            Err(DownloaderError::Scan(ScanError::Infected(signature)))
+                if signature == "Eicar-Signature",
+        ));
+        // Quarantined objects are kept
+        repo.get(infected.id).await.unwrap();
+        manager.fetch(infected.id).await.unwrap();
+
+        // Scans are outdated once the data changes
+        let mut updated = clean.clone();
+        updated.data.checksum_256 = [0; 32];
+        assert!(matches!(
+            scanner.check(&updated).await,
+            Err(DownloaderError::Scan(ScanError::Unscanned)),
+        ));
+
+        let count = scanner
+            .scan_unscanned(&repo, &manager, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_scan_delete_infected() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ObjectRepository::new(db.clone());
+        let manager = MemoryManager::new();
+        let scanner = scanner(&db, fake_clamd().await, ScanAction::Delete);
+
+        let infected = create(&repo, &manager, EICAR).await;
+        scanner
+            .scan_unscanned(&repo, &manager, Utc::now())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            repo.get(infected.id).await,
+            Err(RepositoryError::NotFound(..)),
+        ));
+        assert!(matches!(
+            manager.fetch(infected.id).await,
+            Err(ObjectError::NotFound),
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_scan_clamd_unreachable() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = ObjectRepository::new(db.clone());
+        let manager = MemoryManager::new();
+
+        // Bound and dropped, so nothing listens on it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let scanner = scanner(&db, address, ScanAction::Quarantine);
+
+        let obj = create(&repo, &manager, b"hello world").await;
+        let res = scanner.scan_unscanned(&repo, &manager, Utc::now()).await;
+        assert!(res.is_err());
+
+        // Nothing is recorded, so it is scanned once clamd is back
+        let scans = ScanRepository::new(db.clone());
+        assert_eq!(scans.get(obj.id).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, RawQuery},
+    http::{header, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing, Extension, Router,
+};
+use chrono::DateTime;
+use sqlx::Sqlite;
+
+use crate::{
+    auth::{
+        presign::{PresignAction, PresignRepository, PresignedQuery},
+        AuthError,
+    },
+    branding::{Branding, SharePage},
+    config::BrandingConfig,
+    errors::DownloaderError,
+    namespace::DEFAULT_NAMESPACE,
+    user::repository::UserRepository,
+};
+
+use super::{
+    embargo::EmbargoRepository, repository::ObjectRepository, slug::ObjectId,
+};
+
+pub fn share_routes<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.route("/:id", routing::get(get_share))
+}
+
+/// Shows the details of the file of a presigned download url, with the url
+/// itself in the query, linking to it without using it up. Redirects to it
+/// instead with `raw=true`.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_share(
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(presign_repo): Extension<PresignRepository<Sqlite>>,
+    Extension(embargoes): Extension<EmbargoRepository<Sqlite>>,
+    users: Option<Extension<UserRepository<Sqlite>>>,
+    branding: Option<Extension<Arc<Branding>>>,
+    Path(public_id): Path<String>,
+    ObjectId(id): ObjectId,
+    RawQuery(query): RawQuery,
+) -> Result<Response, DownloaderError> {
+    let (raw, signed): (Vec<_>, Vec<_>) = query
+        .as_deref()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .partition(|pair| pair.split('=').next() == Some("raw"));
+    let raw = raw.iter().any(|pair| matches!(*pair, "raw" | "raw=true"));
+    let signed = signed.join("&");
+
+    let Query(presigned) = format!("/?{signed}")
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| Query::<PresignedQuery>::try_from_uri(&uri).ok())
+        .ok_or(AuthError::InvalidPresignedUrl)?;
+    presign_repo.verify(id, PresignAction::Download, &presigned)?;
+
+    // Share links are global, the download must be made in the namespace
+    // of the object
+    let object = repo.get(id).await?;
+    let download_url = if object.namespace == DEFAULT_NAMESPACE {
+        format!("/api/file/{public_id}/presigned?{signed}")
+    } else {
+        format!(
+            "/api/ns/{}/file/{public_id}/presigned?{signed}",
+            object.namespace,
+        )
+    };
+    if raw {
+        return Ok(Redirect::to(&download_url).into_response());
+    }
+    embargoes.check(id).await?;
+
+    let uploader = match &users {
+        Some(Extension(users)) => users.get(object.user_id).await.ok(),
+        None => None,
+    };
+
+    let page = SharePage {
+        name: &object.data.name,
+        size: object.data.size,
+        mime_type: &object.data.mime_type,
+        uploader: uploader.as_ref().map(|user| user.username.as_str()),
+        download_url: &download_url,
+        expires_at: DateTime::from_timestamp(presigned.expires, 0)
+            .unwrap_or_default(),
+    };
+    let html = match branding {
+        Some(Extension(branding)) => branding.render_share(&page),
+        None => Branding::new(&BrandingConfig::default())
+            .expect("default branding needs no templates")
+            .render_share(&page),
+    };
+
+    // The page holds a working url, which must not leak to other sites
+    let headers = [
+        (header::CACHE_CONTROL, "no-store"),
+        (header::REFERRER_POLICY, "no-referrer"),
+    ];
+    Ok((headers, Html(html)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use sqlx::{migrate, SqlitePool};
+    use test_log::test;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::storage::ObjectData;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_share_page() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let repo = ObjectRepository::new(db.clone());
+        let presign_repo = PresignRepository::new(
+            db.clone(),
+            b"secret",
+            Duration::from_secs(60),
+        );
+        let router = share_routes(Router::new())
+            .layer(Extension(repo.clone()))
+            .layer(Extension(presign_repo.clone()))
+            .layer(Extension(EmbargoRepository::new(db)));
+
+        let id = Uuid::new_v4();
+        let data = ObjectData {
+            name: "<report>.pdf".into(),
+            mime_type: "application/pdf".into(),
+            size: 3 * 1024 * 1024,
+            checksum_256: [0; 32],
+        };
+        repo.create(id, Uuid::new_v4(), data).await.unwrap();
+
+        let query = presign_repo
+            .create(
+                id,
+                PresignAction::Download,
+                Duration::from_secs(60),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap()
+            .to_query_string();
+
+        let get = |uri: String| {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let res = get(format!("/{id}?{query}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::REFERRER_POLICY], "no-referrer");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<h1>&lt;report&gt;.pdf</h1>"), "{body}");
+        assert!(body.contains("3.0 MiB"), "{body}");
+        assert!(body.contains(&format!(
+            r#"href="/api/file/{id}/presigned?{}""#,
+            query.replace('&', "&amp;"),
+        )));
+
+        // Showing the page does not use the url up
+        let res = get(format!("/{id}?{query}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = get(format!("/{id}?raw=true&{query}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers()[header::LOCATION],
+            format!("/api/file/{id}/presigned?{query}"),
+        );
+
+        let tampered = query.replace("action=download", "action=upload");
+        let res = get(format!("/{id}?{tampered}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = get(format!("/{id}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let id = Uuid::new_v4();
+        let data = ObjectData {
+            name: "team.txt".into(),
+            mime_type: "text/plain".into(),
+            size: 1,
+            checksum_256: [0; 32],
+        };
+        repo.in_namespace("team-a")
+            .create(id, Uuid::new_v4(), data)
+            .await
+            .unwrap();
+        let query = presign_repo
+            .create(
+                id,
+                PresignAction::Download,
+                Duration::from_secs(60),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap()
+            .to_query_string();
+
+        let res = get(format!("/{id}?raw&{query}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers()[header::LOCATION],
+            format!("/api/ns/team-a/file/{id}/presigned?{query}"),
+        );
+    }
+}
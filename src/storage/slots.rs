@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{timeout_at, Instant},
+};
+use uuid::Uuid;
+
+use crate::{auth::Token, config::UploadConcurrencyConfig};
+
+use super::manager::ObjectError;
+
+/// Limits how many uploads are stored at once, in total and by each user.
+#[derive(Debug)]
+pub struct UploadSlots {
+    global: Option<Arc<Semaphore>>,
+    per_user: usize,
+    users: Mutex<HashMap<Uuid, Arc<Semaphore>>>,
+    queue_timeout: Duration,
+}
+
+impl UploadSlots {
+    pub fn new(cfg: &UploadConcurrencyConfig) -> Self {
+        Self {
+            global: (cfg.global > 0)
+                .then(|| Arc::new(Semaphore::new(cfg.global))),
+            per_user: cfg.per_user,
+            users: Mutex::new(HashMap::new()),
+            queue_timeout: cfg.queue_timeout,
+        }
+    }
+
+    /// Takes a slot for an upload authorized by `token`, see
+    /// [`UploadSlots::acquire`].
+    pub async fn acquire_for(
+        self: &Arc<Self>,
+        token: &Token,
+    ) -> Result<UploadPermit, ObjectError> {
+        let user_id = match token {
+            Token::User(user_token) => Some(user_token.user_id),
+            Token::File(..) | Token::Server => None,
+        };
+        self.acquire(user_id).await
+    }
+
+    /// Takes a slot for an upload of the user, if any, kept until the
+    /// permit is dropped. Waits up to the queue timeout for one to be free,
+    /// failing with [`ObjectError::TooManyUploads`] after it.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        user_id: Option<Uuid>,
+    ) -> Result<UploadPermit, ObjectError> {
+        let deadline = Instant::now() + self.queue_timeout;
+
+        // The slot of the user is taken first, so uploads of users over
+        // their own limit do not hold global slots while waiting
+        let user = match user_id {
+            Some(id) if self.per_user > 0 => {
+                let semaphore = self
+                    .users
+                    .lock()
+                    .unwrap()
+                    .entry(id)
+                    .or_insert_with(|| Arc::new(Semaphore::new(self.per_user)))
+                    .clone();
+                let mut slot = UserSlot {
+                    slots: self.clone(),
+                    id,
+                    semaphore,
+                    permit: None,
+                };
+                let acquire = slot.semaphore.clone().acquire_owned();
+                let Ok(Ok(permit)) = timeout_at(deadline, acquire).await else {
+                    return Err(ObjectError::TooManyUploads);
+                };
+                slot.permit = Some(permit);
+                Some(slot)
+            }
+            _ => None,
+        };
+
+        let global = match &self.global {
+            Some(global) => {
+                let acquire = global.clone().acquire_owned();
+                let Ok(Ok(permit)) = timeout_at(deadline, acquire).await else {
+                    return Err(ObjectError::TooManyUploads);
+                };
+                Some(permit)
+            }
+            None => None,
+        };
+
+        Ok(UploadPermit {
+            _user: user,
+            _global: global,
+        })
+    }
+
+    #[cfg(test)]
+    fn tracked_users(&self) -> usize {
+        self.users.lock().unwrap().len()
+    }
+}
+
+/// Slot of an upload, freed once dropped.
+#[derive(Debug)]
+pub struct UploadPermit {
+    _user: Option<UserSlot>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+/// Slot of an upload among those of its user, whose semaphore is dropped
+/// along with the last one.
+#[derive(Debug)]
+struct UserSlot {
+    slots: Arc<UploadSlots>,
+    id: Uuid,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+
+        // Only referenced by the map and this slot if no other upload of
+        // the user holds or waits for a slot
+        let mut users = self.slots.users.lock().unwrap();
+        if Arc::strong_count(&self.semaphore) == 2 {
+            users.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::auth::{Permission, UserToken};
+
+    use super::*;
+
+    fn user_token(user_id: Uuid) -> Token {
+        Token::User(UserToken {
+            user_id,
+            created_at: Utc::now(),
+            expiration: Utc::now(),
+            issuer: "test".into(),
+            permission: Permission::UNPRIVILEGED,
+            username: "test".into(),
+            namespace: "default".into(),
+        })
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_upload_slots() {
+        let slots = Arc::new(UploadSlots::new(&UploadConcurrencyConfig {
+            global: 3,
+            per_user: 2,
+            queue_timeout: Duration::ZERO,
+        }));
+        let (alice, bob) =
+            (user_token(Uuid::new_v4()), user_token(Uuid::new_v4()));
+
+        let a1 = slots.acquire_for(&alice).await.unwrap();
+        let _a2 = slots.acquire_for(&alice).await.unwrap();
+        assert!(matches!(
+            slots.acquire_for(&alice).await,
+            Err(ObjectError::TooManyUploads),
+        ));
+
+        let _b1 = slots.acquire_for(&bob).await.unwrap();
+        // Global limit reached
+        assert!(matches!(
+            slots.acquire_for(&bob).await,
+            Err(ObjectError::TooManyUploads),
+        ));
+        assert!(matches!(
+            slots.acquire_for(&Token::Server).await,
+            Err(ObjectError::TooManyUploads),
+        ));
+
+        drop(a1);
+        let _s1 = slots.acquire_for(&Token::Server).await.unwrap();
+        assert_eq!(slots.tracked_users(), 2);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_upload_slots_queue() {
+        let slots = Arc::new(UploadSlots::new(&UploadConcurrencyConfig {
+            global: 0,
+            per_user: 1,
+            queue_timeout: Duration::from_secs(5),
+        }));
+        let alice = user_token(Uuid::new_v4());
+
+        let a1 = slots.acquire_for(&alice).await.unwrap();
+        let waiting = tokio::spawn({
+            let slots = slots.clone();
+            let alice = alice.clone();
+            async move { slots.acquire_for(&alice).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(a1);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(slots.tracked_users(), 0);
+
+        // Unlimited
+        let slots = Arc::new(UploadSlots::new(&Default::default()));
+        let _permits = futures_util::future::join_all(
+            (0..16).map(|_| slots.acquire_for(&alice)),
+        )
+        .await;
+        assert_eq!(slots.tracked_users(), 0);
+    }
+}
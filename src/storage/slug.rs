@@ -0,0 +1,389 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    Database, Encode, Executor, FromRow, IntoArguments, Pool, Sqlite, Type,
+};
+use tracing::field::display;
+use uuid::Uuid;
+
+use crate::{errors::DownloaderError, utils::retry::retry_busy};
+
+use super::{
+    meta::{MetaRepository, Metadata},
+    repository::RepositoryError,
+    stats::DownloadStats,
+    Object, ObjectData,
+};
+
+const SLUG_LEN: usize = 8;
+
+/// How the ids of objects are exposed through the api.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum IdExposure {
+    /// The internal uuids.
+    #[default]
+    Uuid,
+    /// Random short ids mapped to the internal uuids, which are never
+    /// accepted nor returned.
+    Slug,
+}
+
+/// An [`Object`] with its id as exposed by [`ObjectIds`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublicObject {
+    pub id: String,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub data: ObjectData,
+    #[serde(default)]
+    pub downloads: u64,
+    #[serde(default)]
+    pub last_accessed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// Sent back in the `version` of conditional updates, like the `ETag`
+    /// of the file in `If-Match`.
+    #[serde(default)]
+    pub version: u64,
+}
+
+pub struct SlugRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for SlugRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> SlugRepository<DB> {
+    pub fn new(db: Pool<DB>) -> SlugRepository<DB> {
+        SlugRepository { db }
+    }
+}
+
+impl<DB> SlugRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+
+    for<'r> (String,): FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> &'e str: Encode<'e, DB>,
+    for<'e> &'e str: Type<DB>,
+{
+    /// Returns the slug of the object `id`, creating it on first use.
+    pub async fn get_or_create(
+        &self,
+        id: Uuid,
+    ) -> Result<String, RepositoryError> {
+        loop {
+            let slug: Option<(String,)> = sqlx::query_as(
+                "SELECT slug FROM object_slug WHERE object_id = $1",
+            )
+            .bind(id.into_bytes().as_slice())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(sqlx_error)?;
+
+            if let Some((slug,)) = slug {
+                return Ok(slug);
+            }
+
+            let mut slug = [0u8; SLUG_LEN];
+            rand::thread_rng().fill_bytes(&mut slug);
+            let slug = BASE64_URL_SAFE_NO_PAD.encode(slug);
+
+            // Either a concurrent request created the slug of the object or
+            // the random one is taken, both solved by trying again
+            let id_bytes = id.into_bytes();
+            retry_busy(|| {
+                sqlx::query(
+                    "INSERT INTO object_slug (slug, object_id) VALUES ($1, $2) \
+                    ON CONFLICT DO NOTHING",
+                )
+                .bind(slug.as_str())
+                .bind(id_bytes.as_slice())
+                .execute(&self.db)
+            })
+            .await
+            .map_err(sqlx_error)?;
+        }
+    }
+
+    /// Returns the id of the object of `slug`, which must exist or be
+    /// deleted but still restorable.
+    pub async fn resolve(&self, slug: &str) -> Result<Uuid, RepositoryError> {
+        self.query_id(
+            "SELECT object_id FROM object_slug s WHERE slug = $1 \
+            AND (EXISTS (SELECT 1 FROM object WHERE id = s.object_id) \
+            OR EXISTS (SELECT 1 FROM deleted_object WHERE id = s.object_id))",
+            slug,
+        )
+        .await
+    }
+
+    /// Returns the id `slug` maps to, even if no object has it yet.
+    pub async fn resolve_pending(
+        &self,
+        slug: &str,
+    ) -> Result<Uuid, RepositoryError> {
+        self.query_id("SELECT object_id FROM object_slug WHERE slug = $1", slug)
+            .await
+    }
+
+    async fn query_id(
+        &self,
+        query: &'static str,
+        slug: &str,
+    ) -> Result<Uuid, RepositoryError> {
+        let (id,): (Vec<u8>,) = sqlx::query_as(query)
+            .bind(slug)
+            .fetch_optional(&self.db)
+            .await
+            .map_err(sqlx_error)?
+            .ok_or_else(|| RepositoryError::SlugNotFound(slug.to_owned()))?;
+
+        Uuid::from_slice(&id).map_err(|_| {
+            RepositoryError::Sqlx(sqlx::Error::Decode(
+                "parse `object_id` uuid out of range".into(),
+            ))
+        })
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object slugs");
+    RepositoryError::Sqlx(error)
+}
+
+/// Translates between the internal ids of objects and the ones exposed
+/// through the api. Extracting it without the extension exposes uuids.
+#[derive(Clone, Default)]
+pub struct ObjectIds {
+    slugs: Option<SlugRepository<Sqlite>>,
+    stats: Option<DownloadStats>,
+    metas: Option<MetaRepository<Sqlite>>,
+}
+
+impl ObjectIds {
+    pub fn new(exposure: IdExposure, db: Pool<Sqlite>) -> Self {
+        let slugs = match exposure {
+            IdExposure::Uuid => None,
+            IdExposure::Slug => Some(SlugRepository::new(db)),
+        };
+        Self {
+            slugs,
+            stats: None,
+            metas: None,
+        }
+    }
+
+    /// Includes the download stats in the exposed objects.
+    pub fn with_stats(mut self, stats: DownloadStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Includes the custom metadata in the exposed objects.
+    pub fn with_metadata(mut self, metas: MetaRepository<Sqlite>) -> Self {
+        self.metas = Some(metas);
+        self
+    }
+
+    /// Returns the internal id of an existing object.
+    pub async fn resolve(&self, id: &str) -> Result<Uuid, DownloaderError> {
+        match &self.slugs {
+            Some(slugs) => Ok(slugs.resolve(id).await?),
+            None => parse_uuid(id),
+        }
+    }
+
+    /// Like [`ObjectIds::resolve`], for objects that may not be created yet.
+    pub async fn resolve_pending(
+        &self,
+        id: &str,
+    ) -> Result<Uuid, DownloaderError> {
+        match &self.slugs {
+            Some(slugs) => Ok(slugs.resolve_pending(id).await?),
+            None => parse_uuid(id),
+        }
+    }
+
+    pub async fn expose_id(&self, id: Uuid) -> Result<String, RepositoryError> {
+        match &self.slugs {
+            Some(slugs) => slugs.get_or_create(id).await,
+            None => Ok(id.to_string()),
+        }
+    }
+
+    pub async fn expose(
+        &self,
+        obj: Object,
+    ) -> Result<PublicObject, RepositoryError> {
+        let stats = match &self.stats {
+            Some(stats) => stats.get(obj.id).await?,
+            None => Default::default(),
+        };
+        let metadata = match &self.metas {
+            Some(metas) => metas.get(obj.id).await?,
+            None => Metadata::new(),
+        };
+
+        Ok(PublicObject {
+            id: self.expose_id(obj.id).await?,
+            user_id: obj.user_id,
+            created_at: obj.created_at,
+            updated_at: obj.updated_at,
+            data: obj.data,
+            downloads: stats.downloads,
+            last_accessed_at: stats.last_accessed_at,
+            description: obj.description,
+            metadata,
+            version: obj.version,
+        })
+    }
+
+    pub async fn expose_all(
+        &self,
+        objects: Vec<Object>,
+    ) -> Result<Vec<PublicObject>, RepositoryError> {
+        let mut exposed = Vec::with_capacity(objects.len());
+        for obj in objects {
+            exposed.push(self.expose(obj).await?);
+        }
+        Ok(exposed)
+    }
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, DownloaderError> {
+    id.parse().map_err(|_| {
+        DownloaderError::Other(
+            format!("invalid object id `{id}`"),
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ObjectIds {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ObjectIds>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Internal id of the existing object in the `id` path parameter.
+pub struct ObjectId(pub Uuid);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ObjectId {
+    type Rejection = DownloaderError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(id) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+            DownloaderError::Other(e.body_text(), e.status())
+        })?;
+
+        let Ok(ids) = ObjectIds::from_request_parts(parts, state).await;
+        let id = ids.resolve(&id).await?;
+
+        tracing::Span::current().record("object_id", display(id));
+        Ok(ObjectId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{migrate, Pool, Sqlite};
+
+    use super::*;
+
+    async fn repository() -> (Pool<Sqlite>, SlugRepository<Sqlite>) {
+        let db = Pool::<Sqlite>::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        (db.clone(), SlugRepository::new(db))
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_slugs() {
+        let (db, repo) = repository().await;
+        let id = Uuid::new_v4();
+
+        let slug = repo.get_or_create(id).await.unwrap();
+        assert_eq!(slug.len(), 11);
+        assert_eq!(repo.get_or_create(id).await.unwrap(), slug);
+        assert_ne!(repo.get_or_create(Uuid::new_v4()).await.unwrap(), slug);
+
+        assert_eq!(repo.resolve_pending(&slug).await.unwrap(), id);
+        assert!(matches!(
+            repo.resolve(&slug).await,
+            Err(RepositoryError::SlugNotFound(..)),
+        ));
+        assert!(matches!(
+            repo.resolve_pending(&id.to_string()).await,
+            Err(RepositoryError::SlugNotFound(..)),
+        ));
+
+        sqlx::query(
+            "INSERT INTO object \
+            (id, user_id, created_at, updated_at, name, mime_type, size, checksum_256) \
+            VALUES ($1, $2, 0, 0, 'a', 'text/plain', 0, x'00')",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(Uuid::new_v4().into_bytes().as_slice())
+        .execute(&db)
+        .await
+        .unwrap();
+
+        assert_eq!(repo.resolve(&slug).await.unwrap(), id);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_uuid_exposure() {
+        let ids = ObjectIds::default();
+        let id = Uuid::new_v4();
+
+        assert_eq!(ids.expose_id(id).await.unwrap(), id.to_string());
+        assert_eq!(ids.resolve(&id.to_string()).await.unwrap(), id);
+        assert!(ids.resolve("abc").await.is_err());
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    Database, Encode, Executor, FromRow, IntoArguments, Pool, Sqlite, Type,
+};
+use uuid::Uuid;
+
+use crate::utils::retry::retry_busy;
+
+use super::repository::RepositoryError;
+
+/// Days of daily downloads kept for each object.
+pub const MAX_HISTORY_DAYS: u32 = 366;
+/// Hours of the downloads of all the objects kept.
+const MAX_HISTORY_HOURS: i64 = 7 * 24;
+
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_ROLLUP_INTERVAL: Duration = Duration::from_secs(1);
+const SECS_PER_HOUR: i64 = 60 * 60;
+const SECS_PER_DAY: i64 = 24 * SECS_PER_HOUR;
+
+/// Downloads of an object since its stats started being recorded.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ObjectStats {
+    pub downloads: u64,
+    pub last_accessed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyDownloads {
+    pub date: NaiveDate,
+    pub downloads: u64,
+}
+
+/// Downloads of an object not written to the database yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingDownloads {
+    pub downloads: u64,
+    pub last_accessed_at: DateTime<Utc>,
+    /// When each download happened, written as raw events.
+    pub times: Vec<DateTime<Utc>>,
+}
+
+impl PendingDownloads {
+    fn merge(&mut self, other: PendingDownloads) {
+        self.downloads += other.downloads;
+        self.last_accessed_at =
+            self.last_accessed_at.max(other.last_accessed_at);
+        self.times.extend(other.times);
+    }
+}
+
+fn hour_of(at: DateTime<Utc>) -> i64 {
+    at.timestamp().div_euclid(SECS_PER_HOUR)
+}
+
+pub(super) fn day_of(at: DateTime<Utc>) -> i64 {
+    at.timestamp().div_euclid(SECS_PER_DAY)
+}
+
+fn date_of(day: i64) -> NaiveDate {
+    DateTime::from_timestamp(day * SECS_PER_DAY, 0)
+        .unwrap_or_default()
+        .date_naive()
+}
+
+pub struct StatsRepository<DB: Database> {
+    db: Pool<DB>,
+}
+
+impl<DB: Database> Clone for StatsRepository<DB> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<DB: Database> StatsRepository<DB> {
+    pub fn new(db: Pool<DB>) -> StatsRepository<DB> {
+        StatsRepository { db }
+    }
+}
+
+impl<DB> StatsRepository<DB>
+where
+    DB: Database,
+    for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
+    for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> (i64, i64): FromRow<'r, DB::Row>,
+
+    for<'e> &'e [u8]: Encode<'e, DB>,
+    for<'e> &'e [u8]: Type<DB>,
+
+    for<'e> i64: Encode<'e, DB>,
+    i64: Type<DB>,
+{
+    pub async fn get(&self, id: Uuid) -> Result<ObjectStats, RepositoryError> {
+        let stats: Option<(i64, i64)> = sqlx::query_as(
+            "SELECT downloads, last_accessed_at FROM object_stats \
+            WHERE object_id = $1",
+        )
+        .bind(id.into_bytes().as_slice())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        let Some((downloads, last_accessed_at)) = stats else {
+            return Ok(ObjectStats::default());
+        };
+        let last_accessed_at = DateTime::from_timestamp_millis(
+            last_accessed_at,
+        )
+        .ok_or_else(|| {
+            RepositoryError::Sqlx(sqlx::Error::Decode(
+                "parse `last_accessed_at` field gone wrong".into(),
+            ))
+        })?;
+
+        Ok(ObjectStats {
+            downloads: downloads as u64,
+            last_accessed_at: Some(last_accessed_at),
+        })
+    }
+
+    /// Returns the downloads per day of the object `id` since the day
+    /// `since`, counted since the unix epoch, skipping the days without any.
+    /// The events not rolled up yet are counted too.
+    pub async fn get_daily(
+        &self,
+        id: Uuid,
+        since: i64,
+    ) -> Result<Vec<(i64, i64)>, RepositoryError> {
+        sqlx::query_as(
+            "SELECT day, SUM(downloads) FROM ( \
+                SELECT day, downloads FROM object_download_day \
+                WHERE object_id = $1 AND day >= $2 \
+                UNION ALL \
+                SELECT downloaded_at / $3, 1 FROM download_event \
+                WHERE object_id = $1 AND downloaded_at >= $2 * $3 \
+                AND id > (SELECT last_event_id FROM download_rollup) \
+            ) GROUP BY day ORDER BY day",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(since)
+        .bind(SECS_PER_DAY * 1000)
+        .fetch_all(&self.db)
+        .await
+        .map_err(sqlx_error)
+    }
+
+    /// Returns the downloads of all the objects since the hour `since`,
+    /// counted since the unix epoch. The events not rolled up yet are
+    /// counted too.
+    pub async fn get_downloads_since(
+        &self,
+        since: i64,
+    ) -> Result<u64, RepositoryError> {
+        let (downloads,): (i64,) = sqlx::query_as(
+            "SELECT ( \
+                SELECT COALESCE(SUM(downloads), 0) FROM download_hour \
+                WHERE hour >= $1 \
+            ) + ( \
+                SELECT COUNT(*) FROM download_event \
+                WHERE downloaded_at >= $1 * $2 \
+                AND id > (SELECT last_event_id FROM download_rollup) \
+            )",
+        )
+        .bind(since)
+        .bind(SECS_PER_HOUR * 1000)
+        .fetch_one(&self.db)
+        .await
+        .map_err(sqlx_error)?;
+
+        Ok(downloads as u64)
+    }
+
+    /// Adds the downloads of each object, and an event for each of them, in
+    /// a single transaction.
+    pub async fn add(
+        &self,
+        batch: &[(Uuid, PendingDownloads)],
+    ) -> Result<(), RepositoryError> {
+        retry_busy(|| self.add_once(batch))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn add_once(
+        &self,
+        batch: &[(Uuid, PendingDownloads)],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        for (id, pending) in batch {
+            let id_bytes = id.into_bytes();
+
+            sqlx::query(
+                "INSERT INTO object_stats \
+                (object_id, downloads, last_accessed_at) VALUES ($1, $2, $3) \
+                ON CONFLICT (object_id) DO UPDATE SET \
+                downloads = downloads + excluded.downloads, \
+                last_accessed_at = \
+                MAX(last_accessed_at, excluded.last_accessed_at)",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(pending.downloads as i64)
+            .bind(pending.last_accessed_at.timestamp_millis())
+            .execute(&mut *tx)
+            .await?;
+
+            for at in &pending.times {
+                sqlx::query(
+                    "INSERT INTO download_event (object_id, downloaded_at) \
+                    VALUES ($1, $2)",
+                )
+                .bind(id_bytes.as_slice())
+                .bind(at.timestamp_millis())
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await
+    }
+
+    /// Adds the events written since the last rollup to the downloads per
+    /// day and per hour, returning how many. The events rolled up before
+    /// `retain_since` are removed, as are the days beyond
+    /// [`MAX_HISTORY_DAYS`] and the hours beyond a week.
+    pub async fn rollup(
+        &self,
+        retain_since: DateTime<Utc>,
+    ) -> Result<u64, RepositoryError> {
+        retry_busy(|| self.rollup_once(retain_since))
+            .await
+            .map_err(sqlx_error)
+    }
+
+    async fn rollup_once(
+        &self,
+        retain_since: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+
+        let (rolled_up,): (i64,) =
+            sqlx::query_as("SELECT last_event_id FROM download_rollup")
+                .fetch_one(&mut *tx)
+                .await?;
+        let (count, last_id): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(MAX(id), $1) FROM download_event \
+            WHERE id > $1",
+        )
+        .bind(rolled_up)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if count > 0 {
+            sqlx::query(
+                "INSERT INTO object_download_day (object_id, day, downloads) \
+                SELECT object_id, downloaded_at / $2, COUNT(*) \
+                FROM download_event \
+                WHERE id > $3 AND id <= $1 \
+                GROUP BY 1, 2 \
+                ON CONFLICT (object_id, day) DO UPDATE SET \
+                downloads = downloads + excluded.downloads",
+            )
+            .bind(last_id)
+            .bind(SECS_PER_DAY * 1000)
+            .bind(rolled_up)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query(
+                "INSERT INTO download_hour (hour, downloads) \
+                SELECT downloaded_at / $2, COUNT(*) FROM download_event \
+                WHERE id > $3 AND id <= $1 \
+                GROUP BY 1 \
+                ON CONFLICT (hour) DO UPDATE SET \
+                downloads = downloads + excluded.downloads",
+            )
+            .bind(last_id)
+            .bind(SECS_PER_HOUR * 1000)
+            .bind(rolled_up)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query("UPDATE download_rollup SET last_event_id = $1")
+                .bind(last_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query(
+            "DELETE FROM download_event \
+            WHERE id <= $1 AND downloaded_at < $2",
+        )
+        .bind(last_id)
+        .bind(retain_since.timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+
+        let now = Utc::now();
+        sqlx::query("DELETE FROM object_download_day WHERE day <= $1")
+            .bind(day_of(now) - MAX_HISTORY_DAYS as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM download_hour WHERE hour <= $1")
+            .bind(hour_of(now) - MAX_HISTORY_HOURS)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(count as u64)
+    }
+}
+
+fn sqlx_error(error: sqlx::Error) -> RepositoryError {
+    tracing::error!(%error, "got sqlx error while querying object stats");
+    RepositoryError::Sqlx(error)
+}
+
+/// Counts the downloads of objects in memory, writing them in batches with
+/// [`DownloadStats::flush`] so each download does not cost a write.
+#[derive(Clone)]
+pub struct DownloadStats {
+    repo: StatsRepository<Sqlite>,
+    pending: Arc<Mutex<HashMap<Uuid, PendingDownloads>>>,
+}
+
+impl DownloadStats {
+    pub fn new(repo: StatsRepository<Sqlite>) -> Self {
+        Self {
+            repo,
+            pending: Default::default(),
+        }
+    }
+
+    pub fn record(&self, id: Uuid) {
+        let now = Utc::now();
+        let mut pending = self.pending.lock().unwrap();
+
+        let entry = pending.entry(id).or_default();
+        entry.downloads += 1;
+        entry.last_accessed_at = now;
+        entry.times.push(now);
+    }
+
+    /// Returns the stats of the object `id`, including the downloads not
+    /// written yet.
+    pub async fn get(&self, id: Uuid) -> Result<ObjectStats, RepositoryError> {
+        let mut stats = self.repo.get(id).await?;
+
+        if let Some(pending) = self.pending.lock().unwrap().get(&id) {
+            stats.downloads += pending.downloads;
+            stats.last_accessed_at =
+                stats.last_accessed_at.max(Some(pending.last_accessed_at));
+        }
+        Ok(stats)
+    }
+
+    /// Returns the downloads of the object `id` in each of the last `days`
+    /// days, today included, the oldest first.
+    pub async fn get_daily(
+        &self,
+        id: Uuid,
+        days: u32,
+    ) -> Result<Vec<DailyDownloads>, RepositoryError> {
+        let today = day_of(Utc::now());
+        let since = today - days as i64 + 1;
+
+        let mut counts: BTreeMap<i64, u64> =
+            (since..=today).map(|day| (day, 0)).collect();
+        let mut add = |day: i64, downloads: u64| {
+            if let Some(count) = counts.get_mut(&day) {
+                *count += downloads;
+            }
+        };
+
+        for (day, downloads) in self.repo.get_daily(id, since).await? {
+            add(day, downloads as u64);
+        }
+        if let Some(pending) = self.pending.lock().unwrap().get(&id) {
+            for &at in &pending.times {
+                add(day_of(at), 1);
+            }
+        }
+
+        Ok(counts
+            .into_iter()
+            .map(|(day, downloads)| DailyDownloads {
+                date: date_of(day),
+                downloads,
+            })
+            .collect())
+    }
+
+    /// Returns the downloads of all the objects since the start of the hour
+    /// of `since`, including the ones not written yet.
+    pub async fn get_downloads_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<u64, RepositoryError> {
+        let since = hour_of(since);
+        let mut downloads = self.repo.get_downloads_since(since).await?;
+
+        for pending in self.pending.lock().unwrap().values() {
+            let recent =
+                pending.times.iter().filter(|&&at| hour_of(at) >= since);
+            downloads += recent.count() as u64;
+        }
+        Ok(downloads)
+    }
+
+    /// Writes the pending downloads, returning of how many objects. They
+    /// are kept for the next flush if writing them fails.
+    pub async fn flush(&self) -> Result<usize, RepositoryError> {
+        let batch: Vec<_> = std::mem::take(&mut *self.pending.lock().unwrap())
+            .into_iter()
+            .collect();
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        if let Err(error) = self.repo.add(&batch).await {
+            let mut pending = self.pending.lock().unwrap();
+            for (id, downloads) in batch {
+                pending.entry(id).or_default().merge(downloads);
+            }
+            return Err(error);
+        }
+        Ok(batch.len())
+    }
+}
+
+/// Periodically writes the downloads counted by `stats`.
+pub async fn run_flush(stats: DownloadStats, interval: Duration) {
+    let mut interval = tokio::time::interval(interval.max(MIN_FLUSH_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(error) = stats.flush().await {
+            tracing::error!(%error, "failed to write download stats");
+        }
+    }
+}
+
+/// Periodically rolls up the download events written by [`run_flush`],
+/// keeping the rolled up ones for `retention`.
+pub async fn run_rollup(
+    repo: StatsRepository<Sqlite>,
+    interval: Duration,
+    retention: Duration,
+) {
+    let mut interval = tokio::time::interval(interval.max(MIN_ROLLUP_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let retain_since = TimeDelta::from_std(retention)
+            .ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+            .unwrap_or_default();
+
+        match repo.rollup(retain_since).await {
+            Ok(0) => {}
+            Ok(count) => tracing::debug!(count, "rolled up download events"),
+            Err(error) => {
+                tracing::error!(%error, "failed to roll up download events")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use sqlx::{migrate, SqlitePool};
+
+    use super::*;
+
+    async fn count_events(db: &SqlitePool) -> i64 {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM download_event")
+                .fetch_one(db)
+                .await
+                .unwrap();
+        count
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_download_stats() {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+        let repo = StatsRepository::new(db.clone());
+        let stats = DownloadStats::new(repo.clone());
+        let id = Uuid::new_v4();
+
+        assert_eq!(stats.get(id).await.unwrap(), ObjectStats::default());
+        assert_eq!(stats.flush().await.unwrap(), 0);
+
+        stats.record(id);
+        stats.record(id);
+        stats.record(Uuid::new_v4());
+
+        // Counted before being written
+        let pending = stats.get(id).await.unwrap();
+        assert_eq!(pending.downloads, 2);
+        assert_eq!(repo.get(id).await.unwrap().downloads, 0);
+
+        assert_eq!(stats.flush().await.unwrap(), 2);
+        stats.record(id);
+        let written = repo.get(id).await.unwrap();
+        assert_eq!(written.downloads, 2);
+        assert_eq!(stats.get(id).await.unwrap().downloads, 3);
+
+        let daily = stats.get_daily(id, 7).await.unwrap();
+        assert_eq!(daily.len(), 7);
+        assert_eq!(daily[6].date, Utc::now().date_naive());
+        assert_eq!(daily[6].downloads, 3);
+        assert!(daily[..6].iter().all(|day| day.downloads == 0));
+
+        // Events are counted the same before and after being rolled up
+        let today = day_of(Utc::now());
+        assert_eq!(repo.get_daily(id, 0).await.unwrap(), vec![(today, 2)]);
+        assert_eq!(repo.rollup(DateTime::UNIX_EPOCH).await.unwrap(), 3);
+        assert_eq!(repo.rollup(DateTime::UNIX_EPOCH).await.unwrap(), 0);
+        assert_eq!(repo.get_daily(id, 0).await.unwrap(), vec![(today, 2)]);
+        stats.flush().await.unwrap();
+        assert_eq!(repo.get_daily(id, 0).await.unwrap(), vec![(today, 3)]);
+
+        // Days beyond the history are removed once rolled up
+        let old_at = Utc::now() - TimeDelta::days(400);
+        let old = PendingDownloads {
+            downloads: 1,
+            last_accessed_at: old_at,
+            times: vec![old_at],
+        };
+        repo.add(&[(id, old)]).await.unwrap();
+        assert_eq!(repo.get_daily(id, 0).await.unwrap().len(), 2);
+        assert_eq!(repo.rollup(DateTime::UNIX_EPOCH).await.unwrap(), 2);
+        assert_eq!(repo.get_daily(id, 0).await.unwrap(), vec![(today, 3)]);
+
+        let day_ago = Utc::now() - TimeDelta::days(1);
+        assert_eq!(repo.get_downloads_since(0).await.unwrap(), 4);
+        stats.record(id);
+        assert_eq!(stats.get_downloads_since(day_ago).await.unwrap(), 5);
+        stats.flush().await.unwrap();
+        assert_eq!(stats.get_downloads_since(day_ago).await.unwrap(), 5);
+
+        let stored = repo.get(id).await.unwrap();
+        assert_eq!(stored.downloads, 5);
+        assert!(stored.last_accessed_at >= written.last_accessed_at);
+
+        // Only the events rolled up before the retention are removed
+        assert_eq!(count_events(&db).await, 6);
+        repo.rollup(Utc::now() - TimeDelta::hours(1)).await.unwrap();
+        assert_eq!(count_events(&db).await, 5);
+        stats.record(id);
+        stats.flush().await.unwrap();
+        assert_eq!(
+            repo.rollup(Utc::now() + TimeDelta::seconds(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(count_events(&db).await, 0);
+        assert_eq!(stats.get_downloads_since(day_ago).await.unwrap(), 6);
+    }
+}
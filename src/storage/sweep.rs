@@ -0,0 +1,91 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::manager::{DirUsage, Manager, ObjectError};
+
+/// Counters of the sweeps of incomplete objects since startup.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct TempSweeps {
+    pub runs: u64,
+    /// Incomplete objects removed.
+    pub swept: DirUsage,
+}
+
+/// Counts the sweeps of an instance, shared through an `Extension` with
+/// the storage report.
+#[derive(Debug, Default)]
+pub struct SweepCounters {
+    runs: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl SweepCounters {
+    pub fn get(&self) -> TempSweeps {
+        TempSweeps {
+            runs: self.runs.load(Ordering::Relaxed),
+            swept: DirUsage {
+                files: self.files.load(Ordering::Relaxed),
+                bytes: self.bytes.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+/// Removes the incomplete objects of crashed or interrupted stores older
+/// than `max_age`, counting them in `counters`.
+pub async fn sweep_incomplete<M: Manager>(
+    manager: &M,
+    counters: &SweepCounters,
+    max_age: Duration,
+) -> Result<DirUsage, ObjectError> {
+    let swept = manager.sweep(max_age).await?;
+
+    counters.runs.fetch_add(1, Ordering::Relaxed);
+    counters.files.fetch_add(swept.files, Ordering::Relaxed);
+    counters.bytes.fetch_add(swept.bytes, Ordering::Relaxed);
+
+    Ok(swept)
+}
+
+/// Sweeps the incomplete objects right away and then periodically, only on
+/// startup when `interval` is zero.
+pub async fn run_sweep<M: Manager>(
+    manager: Arc<M>,
+    counters: Arc<SweepCounters>,
+    interval: Duration,
+    max_age: Duration,
+) {
+    let mut interval = (!interval.is_zero()).then(|| {
+        let start = tokio::time::Instant::now() + interval;
+        tokio::time::interval_at(start, interval)
+    });
+
+    loop {
+        match sweep_incomplete(manager.as_ref(), &counters, max_age).await {
+            Ok(DirUsage { files: 0, .. }) => {}
+            Ok(swept) => tracing::info!(
+                files = swept.files,
+                bytes = swept.bytes,
+                "swept incomplete objects",
+            ),
+            Err(error) => {
+                tracing::error!(%error, "failed to sweep incomplete objects")
+            }
+        }
+
+        let Some(interval) = &mut interval else {
+            return;
+        };
+        interval.tick().await;
+    }
+}
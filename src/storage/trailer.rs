@@ -0,0 +1,223 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, HeaderMap},
+};
+use bytes::Bytes;
+use futures_util::Stream;
+use pin_project_lite::pin_project;
+use sha2::{Digest, Sha256};
+
+use super::{manager::ObjectError, parse_checksum};
+
+/// Trailer with the sha256 checksum of the uploaded data, in hex, for
+/// clients that only know it once the data is sent.
+pub const CHECKSUM_TRAILER: &str = "x-checksum-sha256";
+
+/// Whether the `Trailer` header of the request announces the
+/// [`CHECKSUM_TRAILER`].
+pub fn announces_checksum(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TRAILER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case(CHECKSUM_TRAILER))
+}
+
+/// The trailers of a [`TrailerBody`], set once it ends.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(Arc<Mutex<Option<HeaderMap>>>);
+
+impl Trailers {
+    /// The checksum in the [`CHECKSUM_TRAILER`], if sent.
+    pub fn checksum(&self) -> Result<Option<[u8; 32]>, ObjectError> {
+        let trailers = self.0.lock().unwrap();
+        let Some(checksum) =
+            trailers.as_ref().and_then(|t| t.get(CHECKSUM_TRAILER))
+        else {
+            return Ok(None);
+        };
+        let checksum = checksum
+            .to_str()
+            .map_err(|_| ObjectError::InvalidChecksum)?;
+        parse_checksum(checksum).map(Some)
+    }
+}
+
+/// The data of a request body, keeping the trailers that follow it in a
+/// [`Trailers`] instead of dropping them.
+pub struct TrailerBody {
+    body: Body,
+    trailers: Trailers,
+}
+
+impl TrailerBody {
+    pub fn new(body: Body) -> (Self, Trailers) {
+        let trailers = Trailers::default();
+        let body = Self {
+            body,
+            trailers: trailers.clone(),
+        };
+        (body, trailers)
+    }
+}
+
+impl Stream for TrailerBody {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            let frame = match Pin::new(&mut self.body).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(error))) => {
+                    return Poll::Ready(Some(Err(io::Error::other(error))));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match frame.into_data() {
+                Ok(data) => return Poll::Ready(Some(Ok(data))),
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        *self.trailers.0.lock().unwrap() = Some(trailers);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pin_project! {
+    /// Hashes the data of the stream, failing it at the end unless the hash
+    /// matches the checksum of the [`Trailers`]. The error wraps an
+    /// [`ObjectError`], so stores fail with it.
+    pub struct VerifyTrailer<S> {
+        #[pin]
+        stream: S,
+        trailers: Trailers,
+        hasher: Option<Sha256>,
+    }
+}
+
+impl<S> VerifyTrailer<S> {
+    pub fn new(stream: S, trailers: Trailers) -> Self {
+        Self {
+            stream,
+            trailers,
+            hasher: Some(Sha256::new()),
+        }
+    }
+}
+
+impl<S> Stream for VerifyTrailer<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>>,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let Some(hasher) = this.hasher else {
+            return Poll::Ready(None);
+        };
+
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                hasher.update(&data);
+                Poll::Ready(Some(Ok(data)))
+            }
+            Poll::Ready(None) => {
+                let hash: [u8; 32] =
+                    this.hasher.take().unwrap().finalize().into();
+                let res = match this.trailers.checksum() {
+                    Ok(Some(checksum)) if checksum == hash => {
+                        return Poll::Ready(None)
+                    }
+                    Ok(Some(_)) => ObjectError::ChecksumMismatch,
+                    Ok(None) => ObjectError::MissingChecksumTrailer,
+                    Err(error) => error,
+                };
+                Poll::Ready(Some(Err(io::Error::other(res))))
+            }
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use futures_util::{stream, StreamExt, TryStreamExt};
+    use http_body::Frame;
+
+    use super::*;
+
+    fn body(data: &'static [u8], checksum: Option<&[u8]>) -> Body {
+        let mut frames =
+            vec![Ok::<_, io::Error>(Frame::data(Bytes::from_static(data)))];
+        if let Some(checksum) = checksum {
+            let mut trailers = HeaderMap::new();
+            trailers.insert(
+                CHECKSUM_TRAILER,
+                HeaderValue::from_str(&hex::encode(checksum)).unwrap(),
+            );
+            frames.push(Ok(Frame::trailers(trailers)));
+        }
+        Body::new(http_body_util::StreamBody::new(stream::iter(frames)))
+    }
+
+    async fn verify(body: Body) -> Result<Vec<u8>, ObjectError> {
+        let (stream, trailers) = TrailerBody::new(body);
+        let chunks: Vec<Bytes> = VerifyTrailer::new(stream, trailers)
+            .try_collect()
+            .await
+            .map_err(ObjectError::from)?;
+        Ok(chunks.concat())
+    }
+
+    #[test]
+    fn test_announces_checksum() {
+        let mut headers = HeaderMap::new();
+        assert!(!announces_checksum(&headers));
+
+        headers.insert(header::TRAILER, HeaderValue::from_static("expires"));
+        assert!(!announces_checksum(&headers));
+
+        headers.append(
+            header::TRAILER,
+            HeaderValue::from_static("server-timing, X-Checksum-SHA256"),
+        );
+        assert!(announces_checksum(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_verify_trailer() {
+        let hash = Sha256::digest(b"hello world");
+        let data = verify(body(b"hello world", Some(&hash))).await.unwrap();
+        assert_eq!(data, b"hello world");
+
+        let res = verify(body(b"hello", Some(&hash))).await;
+        assert!(matches!(res, Err(ObjectError::ChecksumMismatch)));
+
+        let res = verify(body(b"hello world", None)).await;
+        assert!(matches!(res, Err(ObjectError::MissingChecksumTrailer)));
+
+        // Bodies without trailers are still read as usual
+        let (stream, _) = TrailerBody::new(body(b"hello world", None));
+        assert_eq!(stream.count().await, 1);
+    }
+}
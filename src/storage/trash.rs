@@ -0,0 +1,51 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::Sqlite;
+
+use super::{
+    manager::Manager,
+    repository::{ObjectRepository, RepositoryError},
+    UndeleteWindow,
+};
+
+/// Upper bound of the time deleted objects outlive the undelete window.
+const MAX_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Removes the entries and data of the objects that can no longer be
+/// restored, returning how many were purged.
+pub async fn purge_expired<M: Manager>(
+    repo: &ObjectRepository<Sqlite>,
+    manager: &M,
+    window: UndeleteWindow,
+) -> Result<usize, RepositoryError> {
+    let ids = repo.purge_deleted(window.cutoff()).await?;
+
+    for &id in &ids {
+        if let Err(error) = manager.delete(id).await {
+            tracing::error!(%error, %id, "failed to delete purged object data");
+        }
+    }
+
+    Ok(ids.len())
+}
+
+/// Periodically purges the objects deleted before the undelete window.
+pub async fn run_purge<M: Manager>(
+    repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    window: UndeleteWindow,
+) {
+    let mut interval = tokio::time::interval(window.0.min(MAX_PURGE_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        match purge_expired(&repo, manager.as_ref(), window).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "purged deleted objects"),
+            Err(error) => {
+                tracing::error!(%error, "failed to purge deleted objects")
+            }
+        }
+    }
+}
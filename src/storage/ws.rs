@@ -0,0 +1,658 @@
+use std::{future, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path,
+    },
+    http::StatusCode,
+    response::Response,
+    routing, Extension, Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    stream::{self, BoxStream, SelectAll},
+    StreamExt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::Sqlite;
+use tokio::time::{self, MissedTickBehavior};
+use uuid::Uuid;
+
+use crate::{
+    auth::{axum::Authorization, AuthError, Token},
+    errors::DownloaderError,
+    group::repository::GroupRepository,
+    utils::extractors::{Body, Envelope, Query},
+};
+
+use super::{
+    manager::Manager,
+    progress::{validate_transfer_id, Transfers},
+    repository::ObjectRepository,
+    routes::{
+        default_pagination_limit, default_pagination_offset, delete_file,
+        get_file, get_files_by_user, FilesVersion, PaginationData,
+    },
+    slug::{ObjectId, ObjectIds},
+    UndeleteWindow,
+};
+
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Active subscriptions of a connection, further ones are rejected.
+const MAX_SUBSCRIPTIONS: usize = 16;
+/// How often the change counter of subscribed listings is checked.
+const FILES_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub fn ws_routes<S, M>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    M: Manager,
+{
+    router.route("/", routing::get(upgrade::<M>))
+}
+
+/// A command sent by the client, answered with a [`WsResponse`] with the
+/// same `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WsRequest {
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsResponse {
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<WsError>,
+}
+
+/// The error the same command would get through the http api.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WsError {
+    pub error: String,
+    pub error_code: u32,
+    pub status: u16,
+}
+
+impl From<DownloaderError> for WsError {
+    fn from(e: DownloaderError) -> Self {
+        WsError {
+            error: e.to_string(),
+            error_code: e.custom_code(),
+            status: e.status_code().as_u16(),
+        }
+    }
+}
+
+/// Sent without being requested for each event of a subscription.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsNotification {
+    pub subscription: u64,
+    pub event: String,
+    pub data: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListParams {
+    /// Defaults to the owner of the token.
+    pub user_id: Option<Uuid>,
+    #[serde(default = "default_pagination_limit")]
+    pub limit: u32,
+    #[serde(default = "default_pagination_offset")]
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectParams {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SubscribeParams {
+    /// Changes of the files owned by an user, as their [`FilesVersion`].
+    Files { user_id: Option<Uuid> },
+    /// Progress of a transfer of the user, see `progress::Transfers`.
+    Progress { transfer_id: String },
+}
+
+type Events = BoxStream<'static, (&'static str, Value)>;
+
+enum Outcome {
+    Result(Value),
+    Subscribe(Events),
+}
+
+/// Upgrades to a websocket answering the commands of [`Session`] as the
+/// user of the token the connection was authorized with.
+#[allow(clippy::too_many_arguments)]
+pub async fn upgrade<M: Manager>(
+    Authorization(token): Authorization,
+    Extension(repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(groups): Extension<GroupRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(window): Extension<UndeleteWindow>,
+    transfers: Option<Extension<Arc<Transfers>>>,
+    ids: ObjectIds,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let session = Session {
+        token,
+        repo,
+        groups,
+        manager,
+        window,
+        transfers: transfers.map(|Extension(t)| t),
+        ids,
+    };
+
+    ws.max_message_size(MAX_MESSAGE_SIZE)
+        .on_upgrade(move |socket| serve(socket, session))
+}
+
+async fn serve<M: Manager>(mut socket: WebSocket, session: Session<M>) {
+    let mut subscriptions = Subscriptions::default();
+
+    // Tokens are only checked once, the connection ends when they expire
+    let expires_in = session
+        .expiration()
+        .map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+    let expired = async {
+        match expires_in {
+            Some(d) => time::sleep(d).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
+
+    loop {
+        let reply = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let (id, res) = session.handle(&text).await;
+                    let res = res.and_then(|outcome| match outcome {
+                        Outcome::Result(value) => Ok(value),
+                        Outcome::Subscribe(stream) => subscriptions.add(stream),
+                    });
+                    serde_json::to_string(&response(id, res))
+                }
+                Some(Ok(Message::Binary(..))) => {
+                    let e = DownloaderError::Other(
+                        "only text messages are supported".into(),
+                        StatusCode::BAD_REQUEST,
+                    );
+                    serde_json::to_string(&response(Value::Null, Err(e)))
+                }
+                Some(Ok(Message::Close(..))) | None => break,
+                Some(Ok(..)) => continue,
+                Some(Err(error)) => {
+                    tracing::debug!(%error, "websocket connection failed");
+                    break;
+                }
+            },
+            Some(notification) = subscriptions.streams.next() => {
+                serde_json::to_string(&notification)
+            }
+            _ = &mut expired => {
+                let frame = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: AuthError::ExpiredToken.to_string().into(),
+                };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+                break;
+            }
+        };
+
+        let reply = reply.expect("serialize websocket message");
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    streams: SelectAll<BoxStream<'static, WsNotification>>,
+    last_id: u64,
+}
+
+impl Subscriptions {
+    fn add(&mut self, events: Events) -> Result<Value, DownloaderError> {
+        if self.streams.len() >= MAX_SUBSCRIPTIONS {
+            return Err(DownloaderError::Other(
+                "too many subscriptions".into(),
+                StatusCode::TOO_MANY_REQUESTS,
+            ));
+        }
+
+        self.last_id += 1;
+        let subscription = self.last_id;
+        self.streams.push(
+            events
+                .map(move |(event, data)| WsNotification {
+                    subscription,
+                    event: event.to_owned(),
+                    data,
+                })
+                .boxed(),
+        );
+        Ok(serde_json::json!({ "subscription": subscription }))
+    }
+}
+
+fn response(id: Value, res: Result<Value, DownloaderError>) -> WsResponse {
+    match res {
+        Ok(result) => WsResponse {
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => WsResponse {
+            id,
+            result: None,
+            error: Some(e.into()),
+        },
+    }
+}
+
+/// Runs the commands of a connection with the same checks as the http
+/// routes they mirror.
+pub struct Session<M> {
+    token: Token,
+    repo: ObjectRepository<Sqlite>,
+    groups: GroupRepository<Sqlite>,
+    manager: Arc<M>,
+    window: UndeleteWindow,
+    transfers: Option<Arc<Transfers>>,
+    ids: ObjectIds,
+}
+
+impl<M: Manager> Session<M> {
+    fn expiration(&self) -> Option<DateTime<Utc>> {
+        match &self.token {
+            Token::User(user_token) => Some(user_token.expiration),
+            Token::File(file_token) => Some(file_token.expiration),
+            Token::Server => None,
+        }
+    }
+
+    async fn handle(
+        &self,
+        text: &str,
+    ) -> (Value, Result<Outcome, DownloaderError>) {
+        let req: WsRequest = match serde_json::from_str(text) {
+            Ok(req) => req,
+            Err(e) => {
+                let e = DownloaderError::Other(
+                    format!("invalid command: {e}"),
+                    StatusCode::BAD_REQUEST,
+                );
+                return (Value::Null, Err(e));
+            }
+        };
+
+        let res = match req.method.as_str() {
+            "subscribe" => match parse(req.params) {
+                Ok(params) => {
+                    self.subscribe(params).await.map(Outcome::Subscribe)
+                }
+                Err(e) => Err(e),
+            },
+            method => self.call(method, req.params).await.map(Outcome::Result),
+        };
+        (req.id, res)
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, DownloaderError> {
+        let result = match method {
+            "list" => {
+                let params: ListParams = parse(params)?;
+                let user_id = params
+                    .user_id
+                    .or(self.user_id())
+                    .ok_or(AuthError::AccessDenied)?;
+
+                let objects = get_files_by_user(
+                    Authorization(self.token.clone()),
+                    Extension(self.repo.clone()),
+                    self.ids.clone(),
+                    Envelope::default(),
+                    Path(user_id),
+                    Query(PaginationData {
+                        limit: params.limit,
+                        offset: params.offset,
+                    }),
+                )
+                .await?
+                .page
+                .items;
+                serde_json::to_value(objects)
+            }
+            "stat" => {
+                let ObjectParams { id } = parse(params)?;
+                let (_, Body(object)) = get_file(
+                    Authorization(self.token.clone()),
+                    Extension(self.repo.clone()),
+                    Extension(self.groups.clone()),
+                    self.ids.clone(),
+                    ObjectId(self.ids.resolve(&id).await?),
+                )
+                .await?;
+                serde_json::to_value(object)
+            }
+            "delete" => {
+                let ObjectParams { id } = parse(params)?;
+                let object = delete_file(
+                    Authorization(self.token.clone()),
+                    Extension(self.repo.clone()),
+                    Extension(self.manager.clone()),
+                    Extension(self.window),
+                    self.ids.clone(),
+                    ObjectId(self.ids.resolve(&id).await?),
+                )
+                .await?
+                .0;
+                serde_json::to_value(object)
+            }
+            method => {
+                return Err(DownloaderError::Other(
+                    format!("unknown method `{method}`"),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+        };
+
+        Ok(result.expect("serialize websocket result"))
+    }
+
+    async fn subscribe(
+        &self,
+        params: SubscribeParams,
+    ) -> Result<Events, DownloaderError> {
+        match params {
+            SubscribeParams::Files { user_id } => {
+                let user_id = user_id
+                    .or(self.user_id())
+                    .ok_or(AuthError::AccessDenied)?;
+                if !self.token.can_read_all() && self.user_id() != Some(user_id)
+                {
+                    return Err(AuthError::AccessDenied.into());
+                }
+
+                Ok(files_events(self.repo.clone(), user_id))
+            }
+            SubscribeParams::Progress { transfer_id } => {
+                let user_id = self.user_id().ok_or(AuthError::AccessDenied)?;
+                validate_transfer_id(&transfer_id)?;
+
+                let updates = self
+                    .transfers
+                    .as_ref()
+                    .and_then(|t| t.subscribe(user_id, transfer_id))
+                    .ok_or_else(|| {
+                        DownloaderError::Other(
+                            "too many transfers tracked".into(),
+                            StatusCode::SERVICE_UNAVAILABLE,
+                        )
+                    })?;
+
+                Ok(updates
+                    .map(|progress| {
+                        let data = serde_json::to_value(progress)
+                            .expect("serialize transfer progress");
+                        ("progress", data)
+                    })
+                    .boxed())
+            }
+        }
+    }
+
+    fn user_id(&self) -> Option<Uuid> {
+        match &self.token {
+            Token::User(user_token) => Some(user_token.user_id),
+            _ => None,
+        }
+    }
+}
+
+/// Yields the change counter of the files of the user, first the current
+/// one and then each new one.
+fn files_events(repo: ObjectRepository<Sqlite>, user_id: Uuid) -> Events {
+    let mut interval = time::interval(FILES_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    stream::unfold(
+        (repo, interval, None),
+        move |(repo, mut interval, mut last)| async move {
+            loop {
+                interval.tick().await;
+                // Errors are logged by the repository, the next poll retries
+                let Ok(version) = repo.get_version(user_id).await else {
+                    continue;
+                };
+                if last != Some(version) {
+                    last = Some(version);
+                    let data =
+                        serde_json::to_value(FilesVersion { user_id, version })
+                            .expect("serialize files version");
+                    return Some((("files", data), (repo, interval, last)));
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, DownloaderError> {
+    // Commands without parameters may omit them
+    let params = if params.is_null() {
+        Value::Object(Default::default())
+    } else {
+        params
+    };
+
+    serde_json::from_value(params).map_err(|e| {
+        DownloaderError::Other(
+            format!("invalid params: {e}"),
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use futures_util::StreamExt;
+    use sqlx::{migrate, SqlitePool};
+    use tempfile::TempDir;
+    use test_log::test;
+
+    use crate::{
+        auth::{Permission, UserToken},
+        config::StorageConfig,
+        namespace::default_namespace,
+        storage::{manager::ObjectManager, slug::PublicObject, ObjectData},
+        user::DeletePolicy,
+        utils::serde::ResolvedPath,
+    };
+
+    use super::*;
+
+    async fn session(dir: &TempDir, user_id: Uuid) -> Session<ObjectManager> {
+        let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate!().run(&db).await.unwrap();
+
+        let path = ResolvedPath::new(dir.path().to_string_lossy().into_owned())
+            .unwrap();
+        let manager = ObjectManager::new(&StorageConfig {
+            state_dir: path.clone(),
+            data_dir: path.clone(),
+            temp_dir: path,
+            on_user_delete: DeletePolicy::Block,
+            object_cache_size: 0,
+            object_cache_ttl: Duration::ZERO,
+            upload_timeout: Duration::ZERO,
+            upload_min_rate: 0,
+            upload_rate_window: Duration::ZERO,
+            wait_for_writes: false,
+            undelete_window: Duration::ZERO,
+            fetch: None,
+            expose_ids: Default::default(),
+            stats_flush_interval: Duration::ZERO,
+            stats_rollup_interval: Duration::ZERO,
+            download_retention: Duration::ZERO,
+            names: Default::default(),
+            temp_sweep_interval: Duration::ZERO,
+            temp_max_age: Duration::ZERO,
+            data_layout: Default::default(),
+            io: Default::default(),
+            cache: None,
+            backend: Default::default(),
+            post_process: Vec::new(),
+            upload_concurrency: Default::default(),
+        });
+
+        let now = Utc::now();
+        Session {
+            token: Token::User(UserToken {
+                user_id,
+                created_at: now,
+                expiration: now + TimeDelta::hours(1),
+                issuer: "test".into(),
+                permission: Permission::UNPRIVILEGED,
+                username: "ws".into(),
+                namespace: default_namespace(),
+            }),
+            repo: ObjectRepository::new(db.clone()),
+            groups: GroupRepository::new(db),
+            manager: Arc::new(manager),
+            window: UndeleteWindow(Duration::from_secs(60)),
+            transfers: Some(Arc::new(Transfers::new())),
+            ids: ObjectIds::default(),
+        }
+    }
+
+    async fn call(
+        session: &Session<ObjectManager>,
+        text: &str,
+    ) -> (Value, Result<Value, DownloaderError>) {
+        let (id, res) = session.handle(text).await;
+        let res = res.map(|outcome| match outcome {
+            Outcome::Result(value) => value,
+            Outcome::Subscribe(..) => panic!("unexpected subscription"),
+        });
+        (id, res)
+    }
+
+    #[test(tokio::test)]
+    async fn test_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_id = Uuid::new_v4();
+        let session = session(&dir, user_id).await;
+
+        let data = ObjectData {
+            name: "fox.txt".into(),
+            mime_type: "text/plain".into(),
+            size: 3,
+            checksum_256: [0; 32],
+        };
+        let id = Uuid::new_v4();
+        session
+            .repo
+            .create(id, user_id, data.clone())
+            .await
+            .unwrap();
+        session
+            .repo
+            .create(Uuid::new_v4(), Uuid::new_v4(), data)
+            .await
+            .unwrap();
+
+        let (req_id, res) = call(&session, r#"{"id":1,"method":"list"}"#).await;
+        assert_eq!(req_id, 1);
+        let objects: Vec<PublicObject> =
+            serde_json::from_value(res.unwrap()).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].id, id.to_string());
+
+        let stat =
+            format!(r#"{{"id":"a","method":"stat","params":{{"id":"{id}"}}}}"#);
+        let (req_id, res) = call(&session, &stat).await;
+        assert_eq!(req_id, "a");
+        assert_eq!(res.unwrap()["data"]["name"], "fox.txt");
+
+        let other = Uuid::new_v4();
+        let (_, res) = call(
+            &session,
+            &format!(r#"{{"method":"list","params":{{"user_id":"{other}"}}}}"#),
+        )
+        .await;
+        assert_eq!(res.unwrap_err().status_code(), StatusCode::FORBIDDEN);
+
+        let delete = stat.replace("stat", "delete");
+        call(&session, &delete).await.1.unwrap();
+        let (_, res) = call(&session, &stat).await;
+        assert_eq!(res.unwrap_err().status_code(), StatusCode::NOT_FOUND);
+
+        let (req_id, res) =
+            call(&session, r#"{"id":2,"method":"rename"}"#).await;
+        assert_eq!(req_id, 2);
+        assert_eq!(res.unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+
+        let (req_id, res) = call(&session, "not json").await;
+        assert_eq!(req_id, Value::Null);
+        assert_eq!(res.unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test(tokio::test)]
+    async fn test_subscribe() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_id = Uuid::new_v4();
+        let session = session(&dir, user_id).await;
+
+        let (_, res) = session
+            .handle(r#"{"method":"subscribe","params":{"event":"files"}}"#)
+            .await;
+        let Ok(Outcome::Subscribe(mut events)) = res else {
+            panic!("expected a subscription");
+        };
+        let (event, data) = events.next().await.unwrap();
+        assert_eq!(event, "files");
+        assert_eq!(data["version"], 0);
+
+        let (_, res) = session
+            .handle(
+                r#"{"method":"subscribe",
+                "params":{"event":"progress","transfer_id":"upload-1234"}}"#,
+            )
+            .await;
+        let Ok(Outcome::Subscribe(mut events)) = res else {
+            panic!("expected a subscription");
+        };
+        let (event, data) = events.next().await.unwrap();
+        assert_eq!(event, "progress");
+        assert_eq!(data["bytes"], 0);
+
+        let (_, res) = session
+            .handle(
+                r#"{"method":"subscribe",
+                "params":{"event":"progress","transfer_id":"bad id"}}"#,
+            )
+            .await;
+        assert!(res.is_err());
+    }
+}
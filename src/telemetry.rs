@@ -0,0 +1,29 @@
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+
+use crate::config::TelemetryConfig;
+
+/// Creates the provider of the tracers exporting spans to the configured
+/// endpoint in batches. Must be created outside of the async runtime and
+/// shut down before exiting, flushing the spans not sent yet.
+pub fn tracer_provider(
+    cfg: &TelemetryConfig,
+) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&cfg.endpoint)
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::TraceIdRatioBased(cfg.sample_ratio))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(cfg.service_name.clone())
+                .build(),
+        )
+        .build())
+}
@@ -0,0 +1,526 @@
+//! The whole router of the server over an in-memory database and temp
+//! dirs, for tests going through the routes like clients do.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sqlx::{migrate, Sqlite, SqlitePool};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+use crate::{
+    admin::stats::StatsCache,
+    app::{router, AppState},
+    auth::{
+        keys::ApiKeyRepository,
+        lockout::LoginLimiter,
+        presign::PresignRepository,
+        repository::{tests::repository as token_repository, TokenRepository},
+        totp::TotpRepository,
+        Permission,
+    },
+    client_log::{limiter::ReportLimiter, repository::ClientLogRepository},
+    config::{JobConfig, StorageConfig, TimeoutConfig},
+    group::repository::GroupRepository,
+    job::{queue::JobQueue, repository::JobRepository},
+    maintenance::Maintenance,
+    namespace::repository::NamespaceRepository,
+    proxy::TrustedProxies,
+    storage::{
+        embargo::EmbargoRepository,
+        faulty::{Faults, FaultyManager},
+        fetch::RemoteFetcher,
+        fetch_job::FetchJobs,
+        fetch_quota::FetchQuota,
+        history::HistoryRepository,
+        manager::ObjectManager,
+        meta::MetaRepository,
+        name::NamePolicy,
+        pipeline::StepRepository,
+        provenance::ProvenanceRepository,
+        repository::ObjectRepository,
+        slots::UploadSlots,
+        slug::ObjectIds,
+        stats::{DownloadStats, StatsRepository},
+        sweep::SweepCounters,
+        UndeleteWindow, UploadLimits, WriteLocks,
+    },
+    timeout::RequestTimeouts,
+    user::{
+        password::PasswordHasher, repository::UserRepository, User, UserData,
+    },
+    utils::serde::ResolvedPath,
+};
+
+/// The token in the responses of the login and file token routes.
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub token: String,
+}
+
+/// Manager of the test apps, which stores the data unchanged until faults
+/// are set.
+pub type TestManager = FaultyManager<ObjectManager>;
+
+pub struct TestApp {
+    /// Routes as served by the binary, the namespace prefixes included.
+    pub router: Router,
+    pub db: SqlitePool,
+    pub obj_repo: ObjectRepository<Sqlite>,
+    pub user_repo: UserRepository<Sqlite>,
+    pub token_repo: Arc<TokenRepository>,
+    pub manager: Arc<TestManager>,
+    pub jobs: Arc<JobQueue>,
+    pub write_locks: Arc<WriteLocks>,
+    /// Token of the unprivileged user `test`, created along with the app.
+    pub token: String,
+    pub data_dir: TempDir,
+    pub temp_dir: TempDir,
+}
+
+/// Builds the router with [`router`] like `main` does, with the default
+/// settings, leaving out the optional features.
+pub async fn test_app() -> TestApp {
+    test_app_with(|_| {}).await
+}
+
+/// Like [`test_app`], with the storage settings changed by `configure`.
+pub async fn test_app_with(
+    configure: impl FnOnce(&mut StorageConfig),
+) -> TestApp {
+    let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    migrate!().run(&db).await.unwrap();
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let temp_dir = tempfile::tempdir().unwrap();
+    let resolve = |dir: &Path| {
+        ResolvedPath::new(dir.to_string_lossy().into_owned()).unwrap()
+    };
+    let mut storage: StorageConfig = toml::from_str(&format!(
+        "state_dir = {0:?}\ndata_dir = {0:?}\ntemp_dir = {1:?}",
+        resolve(data_dir.path()).as_str(),
+        resolve(temp_dir.path()).as_str(),
+    ))
+    .unwrap();
+    configure(&mut storage);
+
+    let manager = Arc::new(FaultyManager::new(
+        ObjectManager::new(&storage),
+        Faults::default(),
+    ));
+    let obj_repo = ObjectRepository::new(db.clone())
+        .with_name_policy(NamePolicy::new(&storage.names));
+    let user_repo = UserRepository::new(db.clone(), PasswordHasher::bcrypt(4));
+    let token_repo = Arc::new(token_repository());
+    let write_locks = Arc::new(WriteLocks::new(storage.wait_for_writes));
+    let jobs =
+        JobQueue::start(JobRepository::new(db.clone()), &JobConfig::default())
+            .await
+            .unwrap();
+    let download_stats = DownloadStats::new(StatsRepository::new(db.clone()));
+    let object_ids = ObjectIds::new(storage.expose_ids, db.clone())
+        .with_stats(download_stats.clone())
+        .with_metadata(MetaRepository::new(db.clone()));
+
+    let fetcher = storage.fetch.as_ref().map(|fetch_cfg| {
+        let quota = FetchQuota::new(db.clone(), fetch_cfg);
+        (
+            Arc::new(RemoteFetcher::new(fetch_cfg.clone()).unwrap()),
+            quota,
+        )
+    });
+
+    let router = router(AppState {
+        obj_repo: obj_repo.clone(),
+        object_ids,
+        provenance_repo: ProvenanceRepository::new(db.clone()),
+        embargo_repo: EmbargoRepository::new(db.clone()),
+        step_repo: StepRepository::new(db.clone()),
+        meta_repo: MetaRepository::new(db.clone()),
+        history_repo: HistoryRepository::new(db.clone()),
+        download_stats,
+        manager: manager.clone(),
+        jobs: jobs.clone(),
+        fetch_jobs: FetchJobs::new(db.clone(), temp_dir.path()),
+        maintenance: Maintenance::new(db.clone(), 0),
+        sweeps: Arc::new(SweepCounters::default()),
+        undelete_window: UndeleteWindow(storage.undelete_window),
+        on_user_delete: storage.on_user_delete,
+        upload_limits: UploadLimits::new(&storage),
+        upload_slots: Arc::new(UploadSlots::new(&storage.upload_concurrency)),
+        write_locks: write_locks.clone(),
+        stats_cache: Arc::new(StatsCache::new(Duration::ZERO)),
+        user_repo: user_repo.clone(),
+        group_repo: GroupRepository::new(db.clone()),
+        key_repo: ApiKeyRepository::new(db.clone()),
+        client_log_repo: ClientLogRepository::new(db.clone()),
+        report_limiter: Arc::new(ReportLimiter::new(
+            64 * 1024,
+            60,
+            Duration::from_secs(60),
+        )),
+        presign_repo: PresignRepository::new(
+            db.clone(),
+            b"secret",
+            Duration::from_secs(3600),
+        ),
+        totp_repo: TotpRepository::new(db.clone(), "downloader".into(), false),
+        login_limiter: Arc::new(LoginLimiter::new(
+            0,
+            Duration::ZERO,
+            Duration::ZERO,
+        )),
+        token_repo: token_repo.clone(),
+        namespace_repo: NamespaceRepository::new(db.clone()),
+        trusted_proxies: TrustedProxies::new(Vec::new()),
+        timeouts: RequestTimeouts::new(&TimeoutConfig::default()),
+        frontend_dir: None,
+        oidc: None,
+        branding: None,
+        fetcher,
+        scanner: None,
+        pipeline: None,
+    });
+
+    let user = user_repo
+        .create(
+            Permission::UNPRIVILEGED,
+            UserData {
+                username: "test".into(),
+                password: "test".into(),
+            },
+        )
+        .await
+        .unwrap();
+    let token = token_repo
+        .generate_user_token(user.id, user.permission, user.username)
+        .unwrap();
+
+    TestApp {
+        router,
+        db,
+        obj_repo,
+        user_repo,
+        token_repo,
+        manager,
+        jobs,
+        write_locks,
+        token,
+        data_dir,
+        temp_dir,
+    }
+}
+
+impl TestApp {
+    /// Creates a user named `username` with `username` as the password.
+    pub async fn create_user(
+        &self,
+        username: &str,
+        permission: Permission,
+    ) -> User {
+        let data = UserData {
+            username: username.into(),
+            password: username.into(),
+        };
+        self.user_repo.create(permission, data).await.unwrap()
+    }
+
+    /// Logs in through the route, returning the token.
+    pub async fn login(&self, username: &str) -> String {
+        let body =
+            format!(r#"{{"username":"{username}","password":"{username}"}}"#);
+        let (status, body) = self
+            .request(Method::POST, "/api/auth/login", None, body)
+            .await;
+        assert_eq!(status, StatusCode::OK, "login as `{username}`");
+
+        serde_json::from_slice::<TokenResponse>(&body)
+            .unwrap()
+            .token
+    }
+
+    /// Creates a user like [`TestApp::create_user`], returning it along
+    /// with a token of it.
+    pub async fn create_user_token(
+        &self,
+        username: &str,
+        permission: Permission,
+    ) -> (User, String) {
+        let user = self.create_user(username, permission).await;
+        let token = self
+            .token_repo
+            .generate_user_token(user.id, permission, user.username.clone())
+            .unwrap();
+        (user, token)
+    }
+
+    /// Sends the request with `token` as the bearer token, if any. Bodies
+    /// starting with `{` are sent as JSON, other ones as plain text.
+    pub async fn request(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: impl AsRef<[u8]>,
+    ) -> (StatusCode, Vec<u8>) {
+        let content_type = match body.as_ref().starts_with(b"{") {
+            true => "application/json",
+            false => "text/plain",
+        };
+        self.request_with(method, uri, token, content_type, body)
+            .await
+    }
+
+    /// Like [`TestApp::request`], sending the body as `content_type`.
+    pub async fn request_with(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        content_type: &str,
+        body: impl AsRef<[u8]>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, content_type);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let req = req.body(Body::from(body.as_ref().to_vec())).unwrap();
+        let res = self.router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    /// Like [`TestApp::request`], parsing the response body, which must
+    /// be successful.
+    pub async fn request_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: impl AsRef<[u8]>,
+    ) -> T {
+        let (status, body) = self.request(method, uri, token, body).await;
+        assert!(
+            status.is_success(),
+            "{uri}: {status} {}",
+            String::from_utf8_lossy(&body),
+        );
+        serde_json::from_slice(&body).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::{history::ObjectHistory, slug::PublicObject};
+
+    #[test_log::test(tokio::test)]
+    async fn test_file_lifecycle() {
+        let app = test_app().await;
+        let owner = app.create_user("owner", Permission::UNPRIVILEGED).await;
+        app.create_user("other", Permission::UNPRIVILEGED).await;
+        let token = app.login("owner").await;
+        let other = app.login("other").await;
+
+        let file: PublicObject = app
+            .request_json(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&token),
+                "the quick brown fox",
+            )
+            .await;
+        let data_uri = format!("/api/file/{}/data", file.id);
+
+        let files: Vec<PublicObject> = app
+            .request_json(
+                Method::GET,
+                &format!("/api/file/user/{}", owner.id),
+                Some(&token),
+                "",
+            )
+            .await;
+        assert_eq!(files, vec![file.clone()]);
+
+        let (status, body) =
+            app.request(Method::GET, &data_uri, Some(&token), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"the quick brown fox");
+
+        // Only the owner can see the file until it is shared
+        let (status, _) =
+            app.request(Method::GET, &data_uri, Some(&other), "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app.request(Method::GET, &data_uri, None, "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let shared: TokenResponse = app
+            .request_json(
+                Method::POST,
+                &format!("/api/auth/token/{}", file.id),
+                Some(&token),
+                "{}",
+            )
+            .await;
+        let (status, body) = app
+            .request(Method::GET, &data_uri, Some(&shared.token), "")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"the quick brown fox");
+
+        // Read only shares can not delete it, nor other users
+        let file_uri = format!("/api/file/{}", file.id);
+        for token in [&shared.token, &other] {
+            let (status, _) = app
+                .request(Method::DELETE, &file_uri, Some(token), "")
+                .await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+
+        let (status, _) = app
+            .request(Method::DELETE, &file_uri, Some(&token), "")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) =
+            app.request(Method::GET, &data_uri, Some(&token), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_file_transfer() {
+        let app = test_app().await;
+        let owner = app.create_user("owner", Permission::UNPRIVILEGED).await;
+        let target = app.create_user("target", Permission::UNPRIVILEGED).await;
+        let token = app.login("owner").await;
+        let target_token = app.login("target").await;
+
+        let file: PublicObject = app
+            .request_json(
+                Method::POST,
+                "/api/file?name=fox.txt",
+                Some(&token),
+                "the quick brown fox",
+            )
+            .await;
+        let transfer_uri = format!("/api/file/{}/transfer", file.id);
+
+        // Only the owner can give the file away
+        let body = format!(r#"{{"user_id":"{}"}}"#, target.id);
+        let (status, _) = app
+            .request(
+                Method::POST,
+                &transfer_uri,
+                Some(&target_token),
+                body.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let missing = format!(r#"{{"user_id":"{}"}}"#, Uuid::new_v4());
+        let (status, _) = app
+            .request(Method::POST, &transfer_uri, Some(&token), missing)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Timestamps are stored in milliseconds
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let transferred: PublicObject = app
+            .request_json(Method::POST, &transfer_uri, Some(&token), body)
+            .await;
+        assert_eq!(transferred.user_id, target.id);
+        assert!(transferred.updated_at > file.updated_at);
+
+        // The previous owner lost access
+        let data_uri = format!("/api/file/{}/data", file.id);
+        let (status, _) =
+            app.request(Method::GET, &data_uri, Some(&token), "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app
+            .request(Method::GET, &data_uri, Some(&target_token), "")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let history: ObjectHistory = app
+            .request_json(
+                Method::GET,
+                &format!("/api/file/{}/history", file.id),
+                Some(&target_token),
+                "",
+            )
+            .await;
+        assert_eq!(history.updated_by, Some(owner.id));
+        assert_eq!(history.entries[0].fields, ["owner"]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_error_catalog() {
+        let app = test_app().await;
+        app.create_user("admin", Permission::ADMIN).await;
+        let token = app.login("admin").await;
+
+        // Public, so clients can fetch it before logging in
+        let catalog: Vec<serde_json::Value> =
+            app.request_json(Method::GET, "/api/errors", None, "").await;
+
+        let uri = format!("/api/file/{}", Uuid::new_v4());
+        let (status, body) =
+            app.request(Method::GET, &uri, Some(&token), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let entry = catalog
+            .iter()
+            .find(|entry| entry["code"] == body["error_code"])
+            .unwrap();
+        assert_eq!(entry["name"], "NotFound");
+        assert!(entry["description"].is_string());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_route_permissions() {
+        let app = test_app().await;
+        app.create_user("admin", Permission::ADMIN).await;
+        app.create_user("user", Permission::UNPRIVILEGED).await;
+        let admin = app.login("admin").await;
+        let user = app.login("user").await;
+
+        for uri in ["/api/file", "/api/admin/storage", "/api/admin/stats"] {
+            let (status, _) =
+                app.request(Method::GET, uri, Some(&user), "").await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{uri}");
+
+            let (status, _) =
+                app.request(Method::GET, uri, Some(&admin), "").await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+        }
+
+        let (status, _) = app
+            .request(Method::POST, "/api/file?name=a.txt", None, "data")
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = app
+            .request(
+                Method::POST,
+                "/api/auth/login",
+                None,
+                r#"{"username":"user","password":"wrong"}"#,
+            )
+            .await;
+        assert!(status.is_client_error(), "{status}");
+    }
+}
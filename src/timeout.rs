@@ -0,0 +1,157 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use tokio::time::{timeout, timeout_at, Instant};
+
+use crate::{
+    config::TimeoutConfig,
+    errors::{DownloaderError, HttpError},
+};
+
+/// Limits of the requests to api and transfer routes, `None` if unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestTimeouts {
+    pub api: Option<Duration>,
+    pub transfer: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    pub fn new(cfg: &TimeoutConfig) -> Self {
+        let limit = |d: Duration| (!d.is_zero()).then_some(d);
+        Self {
+            api: limit(cfg.api),
+            transfer: limit(cfg.transfer),
+        }
+    }
+}
+
+/// Set by [`transfer`] routes on the requests they handle.
+#[derive(Clone, Default)]
+struct TransferMarker(Arc<AtomicBool>);
+
+/// Marks the routes of `method_router` as streaming transfers, which get
+/// the transfer limit of [`timeout_requests`] instead of the api one.
+pub fn transfer<S>(method_router: MethodRouter<S>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    method_router.layer(middleware::from_fn(mark_transfer))
+}
+
+async fn mark_transfer(req: Request, next: Next) -> Response {
+    if let Some(TransferMarker(marker)) = req.extensions().get() {
+        marker.store(true, Ordering::Relaxed);
+    }
+    next.run(req).await
+}
+
+/// Fails the requests not responded to within their limit.
+///
+/// Whether a request is a transfer is only known once routed, so the
+/// response is first awaited for the shortest limit and then for the rest
+/// of the one of its route.
+pub async fn timeout_requests(
+    State(timeouts): State<RequestTimeouts>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let marker = TransferMarker::default();
+    req.extensions_mut().insert(marker.clone());
+
+    let started = Instant::now();
+    let res = next.run(req);
+    tokio::pin!(res);
+
+    let Some(first) = timeouts.api.into_iter().chain(timeouts.transfer).min()
+    else {
+        return res.await;
+    };
+    if let Ok(res) = timeout(first, &mut res).await {
+        return res;
+    }
+
+    let limit = if marker.0.load(Ordering::Relaxed) {
+        timeouts.transfer
+    } else {
+        timeouts.api
+    };
+    let Some(limit) = limit else {
+        return res.await;
+    };
+    match timeout_at(started + limit, &mut res).await {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!(?limit, "request timed out");
+            DownloaderError::Http(HttpError::RequestTimeout).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing, Router,
+    };
+    use test_log::test;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router(timeouts: RequestTimeouts) -> Router {
+        let sleep = |ms| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+        };
+        Router::new()
+            .route("/api", routing::get(move || sleep(100)))
+            .route("/transfer", transfer(routing::get(move || sleep(100))))
+            .layer(middleware::from_fn_with_state(timeouts, timeout_requests))
+    }
+
+    async fn status(router: &Router, uri: &str) -> StatusCode {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        router.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[test(tokio::test)]
+    async fn test_timeouts() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        let r = router(RequestTimeouts {
+            api: ms(20),
+            transfer: None,
+        });
+        assert_eq!(status(&r, "/api").await, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(status(&r, "/transfer").await, StatusCode::OK);
+
+        let r = router(RequestTimeouts {
+            api: ms(2000),
+            transfer: ms(20),
+        });
+        assert_eq!(status(&r, "/api").await, StatusCode::OK);
+        assert_eq!(status(&r, "/transfer").await, StatusCode::REQUEST_TIMEOUT);
+
+        let r = router(RequestTimeouts {
+            api: None,
+            transfer: ms(20),
+        });
+        assert_eq!(status(&r, "/api").await, StatusCode::OK);
+        assert_eq!(status(&r, "/transfer").await, StatusCode::REQUEST_TIMEOUT);
+
+        let r = router(RequestTimeouts::default());
+        assert_eq!(status(&r, "/api").await, StatusCode::OK);
+        assert_eq!(status(&r, "/transfer").await, StatusCode::OK);
+    }
+}
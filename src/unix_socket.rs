@@ -0,0 +1,195 @@
+use std::{
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::{extract::connect_info::Connected, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::net::{UnixListener, UnixStream};
+use tower::Service;
+
+/// Wait before accepting again after failing to, as when out of file
+/// descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Connection info of requests received on the unix socket. Peers are
+/// trusted to tell the client address, as only those allowed by the
+/// permissions of the socket can connect.
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+impl Connected<&UnixStream> for UnixPeer {
+    fn connect_info(_stream: &UnixStream) -> Self {
+        UnixPeer
+    }
+}
+
+/// A unix socket listening for http connections, removed once dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Creates the socket at `path` with the permissions `mode`, replacing
+    /// a socket left by a server that did not shut down cleanly.
+    pub fn bind(path: &Path, mode: u32) -> io::Result<Self> {
+        remove_stale(path)?;
+        let listener = UnixListener::bind(path)?;
+        let socket = Self {
+            listener,
+            path: path.to_owned(),
+        };
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(socket)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves `app` on the connections accepted, until dropped.
+    pub async fn serve(self, app: Router) -> io::Result<()> {
+        let mut make_service =
+            app.into_make_service_with_connect_info::<UnixPeer>();
+
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    tracing::warn!(
+                        %error,
+                        "failed to accept unix socket connection",
+                    );
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let service = match make_service.call(&stream).await {
+                Ok(service) => service,
+                Err(infallible) => match infallible {},
+            };
+
+            tokio::spawn(async move {
+                let result = Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(
+                        TokioIo::new(stream),
+                        TowerToHyperService::new(service),
+                    )
+                    .await;
+                if let Err(error) = result {
+                    tracing::debug!(%error, "unix socket connection failed");
+                }
+            });
+        }
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            tracing::warn!(
+                %error,
+                path = %self.path.display(),
+                "failed to remove unix socket",
+            );
+        }
+    }
+}
+
+/// Removes the socket at `path` if no server is listening on it. Fails for
+/// anything other than a socket, so no file is removed by mistake.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("`{}` exists and is not a socket", path.display()),
+        ));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("`{}` is in use by another server", path.display()),
+        )),
+        Err(_) => fs::remove_file(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn get(path: &Path) -> String {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloader.sock");
+
+        let listener = UnixSocketListener::bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let app = Router::new().route("/", routing::get(|| async { "hello" }));
+        let server = tokio::spawn(listener.serve(app));
+
+        let response = get(&path).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("hello"), "{response}");
+
+        // In use by the running server
+        assert_eq!(
+            UnixSocketListener::bind(&path, 0o600).err().unwrap().kind(),
+            io::ErrorKind::AddrInUse,
+        );
+
+        server.abort();
+        let _ = server.await;
+        assert!(!path.exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_unix_socket_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloader.sock");
+
+        // Left by a server that did not shut down
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = UnixSocketListener::bind(&path, 0o660).unwrap();
+        drop(listener);
+        assert!(!path.exists());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "data").unwrap();
+        assert_eq!(
+            UnixSocketListener::bind(&file, 0o660).err().unwrap().kind(),
+            io::ErrorKind::AlreadyExists,
+        );
+        assert!(file.exists());
+    }
+}
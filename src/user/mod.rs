@@ -4,8 +4,9 @@ use serde::{Deserialize, Serialize};
 use sqlx::{ColumnIndex, Decode, FromRow, Row, Type};
 use uuid::Uuid;
 
-use crate::auth::Permission;
+use crate::{auth::Permission, namespace::default_namespace};
 
+pub mod password;
 pub mod repository;
 pub mod routes;
 
@@ -17,12 +18,20 @@ pub enum UserError {
     AlreadyExists(String),
     #[error("incorrect password")]
     PasswordMismatch,
-    #[error("bcrypt hash failed")]
-    BcryptHashFailed,
-    #[error("bcrypt compare failed")]
-    BcryptCompareFailed,
+    #[error("password hash failed")]
+    PasswordHashFailed,
+    #[error("password compare failed")]
+    PasswordCompareFailed,
     #[error("sqlx error: {0}")]
     Sqlx(sqlx::Error),
+    #[error("user still owns {0} objects, delete them first")]
+    OwnsObjects(u64),
+    #[error("objects can not be transferred to the user being deleted")]
+    TransferToSelf,
+    #[error("user `{0}` designated to receive objects does not exist")]
+    TransferTargetNotFound(Uuid),
+    #[error("user was updated since version {0}")]
+    Conflict(u64),
 }
 
 impl UserError {
@@ -32,25 +41,51 @@ impl UserError {
             UserError::NotFound => StatusCode::NOT_FOUND,
             UserError::AlreadyExists(..) => StatusCode::CONFLICT,
             UserError::PasswordMismatch => StatusCode::UNAUTHORIZED,
-            UserError::BcryptHashFailed => StatusCode::INTERNAL_SERVER_ERROR,
-            UserError::BcryptCompareFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            UserError::PasswordHashFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            UserError::PasswordCompareFailed => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
             UserError::Sqlx(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            UserError::OwnsObjects(..) => StatusCode::CONFLICT,
+            UserError::TransferToSelf => StatusCode::CONFLICT,
+            UserError::TransferTargetNotFound(..) => StatusCode::CONFLICT,
+            UserError::Conflict(..) => StatusCode::CONFLICT,
         }
     }
+}
 
-    #[inline]
-    pub fn custom_code(&self) -> u8 {
-        match self {
-            UserError::NotFound => 1,
-            UserError::AlreadyExists(..) => 2,
-            UserError::PasswordMismatch => 3,
-            UserError::BcryptHashFailed => 4,
-            UserError::BcryptCompareFailed => 5,
-            UserError::Sqlx(..) => 6,
-        }
+crate::errors::error_codes! {
+    UserError {
+        NotFound => 1, "user not found";
+        AlreadyExists(..) => 2, "a user with the username already exists";
+        PasswordMismatch => 3, "incorrect password";
+        PasswordHashFailed => 4, "failed to hash the password";
+        PasswordCompareFailed => 5, "failed to compare the password";
+        Sqlx(..) => 6, "database error";
+        OwnsObjects(..) => 7, "the user still owns objects";
+        TransferToSelf => 8,
+            "objects can not be transferred to the user being deleted";
+        TransferTargetNotFound(..) => 9,
+            "the user designated to receive objects does not exist";
+        Conflict(..) => 10, "the user was updated since the given version";
     }
 }
 
+/// What happens to the objects of a user when it gets deleted.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePolicy {
+    /// Refuse to delete users that still own objects.
+    #[default]
+    Block,
+    /// Delete the objects along with the user.
+    Cascade,
+    /// Hand the objects over to another existing user.
+    Transfer(Uuid),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
@@ -58,6 +93,11 @@ pub struct User {
     pub updated_at: DateTime<Utc>,
     pub permission: Permission,
     pub username: String,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Incremented by every update of the user.
+    #[serde(default)]
+    pub version: u64,
 }
 
 impl<'r, R: Row> FromRow<'r, R> for User
@@ -108,6 +148,12 @@ where
             })?;
 
         let username: String = row.try_get("username")?;
+        let namespace: String = row.try_get("namespace")?;
+
+        let version: i64 = row.try_get("version")?;
+        let version: u64 = version.try_into().map_err(|_| {
+            sqlx::Error::Decode("parse `version` u64 out of range".into())
+        })?;
 
         Ok(Self {
             id,
@@ -115,6 +161,8 @@ where
             updated_at,
             permission,
             username,
+            namespace,
+            version,
         })
     }
 }
@@ -0,0 +1,202 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _,
+    PasswordVerifier, Version,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use super::UserError;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+}
+
+/// Hashes new passwords with the configured algorithm, while still being
+/// able to verify hashes produced by any of them.
+#[derive(Debug, Clone)]
+pub enum PasswordHasher {
+    Argon2id(Params),
+    Bcrypt(u32),
+}
+
+impl PasswordHasher {
+    /// Argon2id with `m_cost` in KiB, `t_cost` iterations and `p_cost`
+    /// degree of parallelism.
+    pub fn argon2id(
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    ) -> Result<Self, argon2::Error> {
+        Params::new(m_cost, t_cost, p_cost, None).map(Self::Argon2id)
+    }
+
+    #[inline]
+    pub fn bcrypt(cost: u32) -> Self {
+        Self::Bcrypt(cost)
+    }
+
+    pub async fn hash(&self, password: String) -> Result<String, UserError> {
+        let hasher = self.clone();
+        spawn_blocking(move || hasher.hash_blocking(password.as_bytes()))
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    %error,
+                    "got tokio error while handling password hash task",
+                );
+                UserError::PasswordHashFailed
+            })?
+    }
+
+    pub async fn verify(
+        &self,
+        password: String,
+        hash: String,
+    ) -> Result<bool, UserError> {
+        spawn_blocking(move || verify_blocking(password.as_bytes(), &hash))
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    %error,
+                    "got tokio error while handling password verify task",
+                );
+                UserError::PasswordCompareFailed
+            })?
+    }
+
+    /// Whether `hash` was produced by another algorithm or with different
+    /// parameters than the configured ones.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match self {
+            Self::Argon2id(params) => {
+                let Ok(hash) = PasswordHash::new(hash) else {
+                    return true;
+                };
+                if hash.algorithm != Algorithm::Argon2id.ident() {
+                    return true;
+                }
+
+                Params::try_from(&hash).map_or(true, |p| {
+                    p.m_cost() != params.m_cost()
+                        || p.t_cost() != params.t_cost()
+                        || p.p_cost() != params.p_cost()
+                })
+            }
+            Self::Bcrypt(cost) => hash
+                .parse::<bcrypt::HashParts>()
+                .map_or(true, |parts| parts.get_cost() != *cost),
+        }
+    }
+
+    fn hash_blocking(&self, password: &[u8]) -> Result<String, UserError> {
+        match self {
+            Self::Argon2id(params) => {
+                let salt = SaltString::generate(&mut OsRng);
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+                    .hash_password(password, &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|error| {
+                        tracing::error!(
+                            %error,
+                            "got argon2 error while hashing password",
+                        );
+                        UserError::PasswordHashFailed
+                    })
+            }
+            Self::Bcrypt(cost) => {
+                bcrypt::hash(password, *cost).map_err(|error| {
+                    tracing::error!(
+                        %error,
+                        "got bcrypt error while hashing password",
+                    );
+                    UserError::PasswordHashFailed
+                })
+            }
+        }
+    }
+}
+
+fn verify_blocking(password: &[u8], hash: &str) -> Result<bool, UserError> {
+    if !hash.starts_with("$argon2") {
+        return bcrypt::verify(password, hash).map_err(|error| {
+            tracing::error!(
+                %error,
+                "got bcrypt error while verifying password",
+            );
+            UserError::PasswordCompareFailed
+        });
+    }
+
+    let hash = PasswordHash::new(hash).map_err(|error| {
+        tracing::error!(%error, "got invalid argon2 password hash");
+        UserError::PasswordCompareFailed
+    })?;
+
+    // The parameters are taken from the hash itself
+    match Argon2::default().verify_password(password, &hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(error) => {
+            tracing::error!(
+                %error,
+                "got argon2 error while verifying password",
+            );
+            Err(UserError::PasswordCompareFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::PasswordHasher;
+
+    fn argon2id() -> PasswordHasher {
+        PasswordHasher::argon2id(64, 1, 1).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_verify_any_algorithm() {
+        for hasher in [argon2id(), PasswordHasher::bcrypt(4)] {
+            let hash = hasher.hash("password".into()).await.unwrap();
+            assert!(!hasher.needs_rehash(&hash));
+
+            for verifier in [argon2id(), PasswordHasher::bcrypt(4)] {
+                let ok = verifier
+                    .verify("password".into(), hash.clone())
+                    .await
+                    .unwrap();
+                assert!(ok, "{verifier:?} failed to verify {hash}");
+
+                let ok = verifier
+                    .verify("wrong".into(), hash.clone())
+                    .await
+                    .unwrap();
+                assert!(!ok, "{verifier:?} accepted wrong password");
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_needs_rehash() {
+        let bcrypt_hash =
+            PasswordHasher::bcrypt(4).hash("a".into()).await.unwrap();
+        let argon2_hash = argon2id().hash("a".into()).await.unwrap();
+        assert!(argon2_hash.starts_with("$argon2id$"));
+
+        assert!(argon2id().needs_rehash(&bcrypt_hash));
+        assert!(PasswordHasher::bcrypt(4).needs_rehash(&argon2_hash));
+        assert!(PasswordHasher::bcrypt(5).needs_rehash(&bcrypt_hash));
+
+        let stronger = PasswordHasher::argon2id(128, 1, 1).unwrap();
+        assert!(stronger.needs_rehash(&argon2_hash));
+    }
+}
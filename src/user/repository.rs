@@ -3,12 +3,20 @@ use sqlx::{
     ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments,
     Pool, Row, Type,
 };
-use tokio::task::spawn_blocking;
 use uuid::Uuid;
 
-use crate::auth::Permission;
+use std::sync::Arc;
 
-use super::{User, UserData, UserError};
+use crate::{
+    auth::Permission,
+    group::{union_permissions, MEMBER_PERMISSIONS_QUERY},
+    namespace::DEFAULT_NAMESPACE,
+    utils::retry::retry_busy,
+};
+
+use super::{
+    password::PasswordHasher, DeletePolicy, User, UserData, UserError,
+};
 
 struct UserWithPassword {
     pub user: User,
@@ -36,7 +44,9 @@ where
 
 pub struct UserRepository<DB: Database> {
     db: Pool<DB>,
-    hash_cost: u32,
+    hasher: PasswordHasher,
+    /// Only the users in it are visible if set.
+    namespace: Option<Arc<str>>,
 }
 
 impl<DB: Database> Clone for UserRepository<DB> {
@@ -44,14 +54,33 @@ impl<DB: Database> Clone for UserRepository<DB> {
     fn clone(&self) -> Self {
         Self {
             db: self.db.clone(),
-            hash_cost: self.hash_cost,
+            hasher: self.hasher.clone(),
+            namespace: self.namespace.clone(),
         }
     }
 }
 
 impl<DB: Database> UserRepository<DB> {
-    pub fn new(db: Pool<DB>, hash_cost: u32) -> UserRepository<DB> {
-        UserRepository { db, hash_cost }
+    pub fn new(db: Pool<DB>, hasher: PasswordHasher) -> UserRepository<DB> {
+        UserRepository {
+            db,
+            hasher,
+            namespace: None,
+        }
+    }
+
+    /// A repository of the users in `namespace` only, which the new users
+    /// are created in.
+    pub fn in_namespace(&self, namespace: &str) -> Self {
+        Self {
+            namespace: Some(namespace.into()),
+            ..self.clone()
+        }
+    }
+
+    /// The namespace new users are created in.
+    pub fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
     }
 }
 
@@ -60,8 +89,11 @@ where
     DB: Database,
     for<'a> <DB as sqlx::Database>::Arguments<'a>: IntoArguments<'a, DB>,
     for<'a> &'a Pool<DB>: Executor<'a, Database = DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
 
     for<'r> User: FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> (Vec<u8>,): FromRow<'r, DB::Row>,
 
     for<'r> &'r str: ColumnIndex<DB::Row>,
     for<'r> String: Decode<'r, DB>,
@@ -71,21 +103,45 @@ where
     for<'e> &'e [u8]: Type<DB>,
 
     for<'e> i64: Encode<'e, DB>,
+    for<'e> Option<i64>: Encode<'e, DB>,
     i64: Type<DB>,
 
     for<'e> &'e str: Encode<'e, DB>,
     for<'e> &'e str: Type<DB>,
+    for<'e> Option<&'e str>: Encode<'e, DB>,
 {
     pub async fn get(&self, id: Uuid) -> Result<User, UserError> {
-        sqlx::query_as("SELECT * FROM user WHERE id = $1")
-            .bind(id.into_bytes().as_slice())
-            .fetch_optional(&self.db)
-            .await
-            .map_err(|error| {
-                tracing::error!(%error, "got sqlx error while fetching user");
-                UserError::Sqlx(error)
-            })?
-            .ok_or(UserError::NotFound)
+        sqlx::query_as(
+            "SELECT * FROM user \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2)",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while fetching user");
+            UserError::Sqlx(error)
+        })?
+        .ok_or(UserError::NotFound)
+    }
+
+    pub async fn get_by_username(
+        &self,
+        username: &str,
+    ) -> Result<User, UserError> {
+        sqlx::query_as(
+            "SELECT * FROM user WHERE username = $1 AND namespace = $2",
+        )
+        .bind(username)
+        .bind(self.namespace())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "got sqlx error while fetching user");
+            UserError::Sqlx(error)
+        })?
+        .ok_or(UserError::NotFound)
     }
 
     pub async fn authenticate(
@@ -93,9 +149,10 @@ where
         data: UserData,
     ) -> Result<User, UserError> {
         let user: UserWithPassword = sqlx::query_as(
-            "SELECT * FROM user WHERE username = $1",
+            "SELECT * FROM user WHERE username = $1 AND namespace = $2",
         )
         .bind(data.username.as_str())
+        .bind(self.namespace())
         .fetch_optional(&self.db)
         .await
         .map_err(|error| {
@@ -104,14 +161,73 @@ where
         })?
         .ok_or(UserError::NotFound)?;
 
-        let ok = verify_password(data.password, user.password_hash).await?;
+        // Users provisioned by an external identity provider have no
+        // local password until they set one.
+        if user.password_hash.is_empty() {
+            return Err(UserError::PasswordMismatch);
+        }
+
+        let ok = self
+            .hasher
+            .verify(data.password.clone(), user.password_hash.clone())
+            .await?;
         if !ok {
             return Err(UserError::PasswordMismatch);
         }
 
+        if self.hasher.needs_rehash(&user.password_hash) {
+            self.rehash_password(&user, data.password).await;
+        }
+
         Ok(user.user)
     }
 
+    /// The permission of the user along with the ones of its groups, the
+    /// highest a token issued to it may have.
+    pub async fn get_effective_permission(
+        &self,
+        user: &User,
+    ) -> Result<Permission, UserError> {
+        let permissions = sqlx::query_as(MEMBER_PERMISSIONS_QUERY)
+            .bind(user.id.into_bytes().as_slice())
+            .fetch_all(&self.db)
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, "got sqlx error while fetching groups");
+                UserError::Sqlx(error)
+            })?;
+
+        Ok(user.permission | union_permissions(permissions))
+    }
+
+    /// Replaces a hash produced with outdated parameters or algorithm, so
+    /// the stored passwords migrate over time. Failures are only logged.
+    async fn rehash_password(&self, user: &UserWithPassword, password: String) {
+        let Ok(password_hash) = self.hasher.hash(password).await else {
+            return;
+        };
+
+        // Do not overwrite a password changed in the meantime
+        let res = sqlx::query(
+            "UPDATE user SET password = $1 WHERE id = $2 AND password = $3",
+        )
+        .bind(password_hash.as_str())
+        .bind(user.user.id.into_bytes().as_slice())
+        .bind(user.password_hash.as_str())
+        .execute(&self.db)
+        .await;
+
+        match res {
+            Ok(_) => {
+                tracing::info!(user_id = %user.user.id, "password rehashed")
+            }
+            Err(error) => tracing::error!(
+                %error,
+                "got sqlx error while rehashing user password",
+            ),
+        }
+    }
+
     pub async fn create(
         &self,
         permission: Permission,
@@ -120,138 +236,351 @@ where
         let id = Uuid::new_v4();
         let now_ms = Utc::now().timestamp_millis();
 
-        let password_hash =
-            hash_password(self.hash_cost, data.password).await?;
+        let password_hash = self.hasher.hash(data.password).await?;
+
+        let id_bytes = id.into_bytes();
+        retry_busy(|| {
+            sqlx::query_as(
+                "INSERT INTO user \
+                (id, created_at, updated_at, permission, username, password, \
+                namespace) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+            )
+            .bind(id_bytes.as_slice())
+            .bind(now_ms)
+            .bind(now_ms)
+            .bind(permission.bits() as i64)
+            .bind(data.username.as_str())
+            .bind(password_hash.as_str())
+            .bind(self.namespace())
+            .fetch_one(&self.db)
+        })
+        .await
+        .map_err(|error| {
+            if matches!(
+                &error,
+                sqlx::Error::Database(e) if e.is_unique_violation(),
+            ) {
+                return UserError::AlreadyExists(data.username);
+            }
+
+            tracing::error!(%error, "got sqlx error while creating user");
+            UserError::Sqlx(error)
+        })
+    }
+
+    /// Returns the user linked to the `subject` of an OpenID Connect
+    /// `issuer`, creating it without a local password when missing.
+    pub async fn get_or_create_oidc(
+        &self,
+        issuer: &str,
+        subject: &str,
+        username: String,
+        permission: Permission,
+    ) -> Result<User, UserError> {
+        let mut tx = self.db.begin().await.map_err(oidc_error)?;
 
-        sqlx::query_as(
+        let user = sqlx::query_as(
+            "SELECT user.* FROM user \
+            JOIN oidc_identity ON oidc_identity.user_id = user.id \
+            WHERE oidc_identity.issuer = $1 AND oidc_identity.subject = $2 \
+            AND user.namespace = $3",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(self.namespace())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(oidc_error)?;
+
+        if let Some(user) = user {
+            return Ok(user);
+        }
+
+        let id = Uuid::new_v4();
+        let now_ms = Utc::now().timestamp_millis();
+
+        let user = sqlx::query_as(
             "INSERT INTO user \
-            (id, created_at, updated_at, permission, username, password) \
-            VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            (id, created_at, updated_at, permission, username, password, \
+            namespace) VALUES ($1, $2, $3, $4, $5, '', $6) RETURNING *",
         )
         .bind(id.into_bytes().as_slice())
         .bind(now_ms)
         .bind(now_ms)
         .bind(permission.bits() as i64)
-        .bind(data.username.as_str())
-        .bind(password_hash.as_str())
-        .fetch_one(&self.db)
+        .bind(username.as_str())
+        .bind(self.namespace())
+        .fetch_one(&mut *tx)
         .await
         .map_err(|error| {
             if matches!(
                 &error,
                 sqlx::Error::Database(e) if e.is_unique_violation(),
             ) {
-                return UserError::AlreadyExists(data.username);
+                return UserError::AlreadyExists(username);
             }
+            oidc_error(error)
+        })?;
 
-            tracing::error!(%error, "got sqlx error while creating user");
-            UserError::Sqlx(error)
-        })
+        sqlx::query(
+            "INSERT INTO oidc_identity (issuer, subject, user_id, created_at) \
+            VALUES ($1, $2, $3, $4)",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(id.into_bytes().as_slice())
+        .bind(now_ms)
+        .execute(&mut *tx)
+        .await
+        .map_err(oidc_error)?;
+
+        tx.commit().await.map_err(oidc_error)?;
+
+        Ok(user)
     }
 
+    /// Updates the permission of the user, only if still at `version` if
+    /// set, failing with [`UserError::Conflict`] otherwise.
     pub async fn update_permission(
         &self,
         id: Uuid,
         permission: Permission,
+        version: Option<u64>,
     ) -> Result<User, UserError> {
         let now_ms = Utc::now().timestamp_millis();
 
-        sqlx::query_as(
-            "UPDATE user SET updated_at = $1, permission = $2 \
-            WHERE id = $3 RETURNING *",
-        )
-        .bind(now_ms)
-        .bind(permission.bits() as i64)
-        .bind(id.into_bytes().as_slice())
-        .fetch_optional(&self.db)
+        let id_bytes = id.into_bytes();
+        let user = retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE user SET updated_at = $1, permission = $2, \
+                version = version + 1 \
+                WHERE id = $3 AND ($4 IS NULL OR namespace = $4) \
+                AND ($5 IS NULL OR version = $5) RETURNING *",
+            )
+            .bind(now_ms)
+            .bind(permission.bits() as i64)
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .bind(version.map(|v| v as i64))
+            .fetch_optional(&self.db)
+        })
         .await
-        .map_err(|error| {
-            tracing::error!(%error, "got sqlx error while updating user");
-            UserError::Sqlx(error)
-        })?
-        .ok_or(UserError::NotFound)
+        .map_err(update_error)?;
+
+        self.updated_or_conflict(id, user, version).await
     }
 
+    /// Updates the password of the user, only if still at `version` if
+    /// set, failing with [`UserError::Conflict`] otherwise.
     pub async fn update_password(
         &self,
         id: Uuid,
         password: String,
+        version: Option<u64>,
     ) -> Result<User, UserError> {
         let now_ms = Utc::now().timestamp_millis();
 
-        let password_hash = hash_password(self.hash_cost, password).await?;
+        let password_hash = self.hasher.hash(password).await?;
+
+        let id_bytes = id.into_bytes();
+        let user = retry_busy(|| {
+            sqlx::query_as(
+                "UPDATE user SET updated_at = $1, password = $2, \
+                version = version + 1 \
+                WHERE id = $3 AND ($4 IS NULL OR namespace = $4) \
+                AND ($5 IS NULL OR version = $5) RETURNING *",
+            )
+            .bind(now_ms)
+            .bind(password_hash.as_str())
+            .bind(id_bytes.as_slice())
+            .bind(self.namespace.as_deref())
+            .bind(version.map(|v| v as i64))
+            .fetch_optional(&self.db)
+        })
+        .await
+        .map_err(update_error)?;
 
-        sqlx::query_as(
-            "UPDATE user SET updated_at = $1, password = $2 \
-            WHERE id = $3 RETURNING *",
+        self.updated_or_conflict(id, user, version).await
+    }
+
+    /// The `user` returned by a conditional update, telling whether it was
+    /// not found or at another version than `version` if none.
+    async fn updated_or_conflict(
+        &self,
+        id: Uuid,
+        user: Option<User>,
+        version: Option<u64>,
+    ) -> Result<User, UserError> {
+        if let Some(user) = user {
+            return Ok(user);
+        }
+        let Some(version) = version else {
+            return Err(UserError::NotFound);
+        };
+
+        let exists: Option<(i64,)> = sqlx::query_as(
+            "SELECT version FROM user \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2)",
         )
-        .bind(now_ms)
-        .bind(password_hash.as_str())
         .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
         .fetch_optional(&self.db)
         .await
-        .map_err(|error| {
-            tracing::error!(%error, "got sqlx error while updating user");
-            UserError::Sqlx(error)
-        })?
-        .ok_or(UserError::NotFound)
+        .map_err(update_error)?;
+
+        match exists {
+            Some(..) => Err(UserError::Conflict(version)),
+            None => Err(UserError::NotFound),
+        }
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<User, UserError> {
-        sqlx::query_as("DELETE FROM user WHERE id = $1 RETURNING *")
-            .bind(id.into_bytes().as_slice())
-            .fetch_optional(&self.db)
-            .await
-            .map_err(|error| {
-                tracing::error!(%error, "got sqlx error while deleting user");
-                UserError::Sqlx(error)
-            })?
-            .ok_or(UserError::NotFound)
+    /// Checks that `policy` can be applied, failing with
+    /// [`UserError::TransferTargetNotFound`] if it transfers the objects to
+    /// a user that does not exist.
+    pub async fn check_delete_policy(
+        &self,
+        policy: DeletePolicy,
+    ) -> Result<(), UserError> {
+        let DeletePolicy::Transfer(target) = policy else {
+            return Ok(());
+        };
+        match self.get(target).await {
+            Ok(..) => Ok(()),
+            Err(UserError::NotFound) => {
+                Err(UserError::TransferTargetNotFound(target))
+            }
+            Err(error) => Err(error),
+        }
     }
-}
 
-async fn hash_password(
-    cost: u32,
-    password: String,
-) -> Result<String, UserError> {
-    spawn_blocking(move || bcrypt::hash(password, cost))
+    /// Deletes the user, applying `policy` to the objects it owns in the
+    /// same transaction. Returns the deleted user and the ids of the objects
+    /// deleted along with it, whose data must be removed by the caller.
+    pub async fn delete(
+        &self,
+        id: Uuid,
+        policy: DeletePolicy,
+    ) -> Result<(User, Vec<Uuid>), UserError> {
+        let mut tx = self.db.begin().await.map_err(delete_error)?;
+
+        let deleted_objects = match policy {
+            DeletePolicy::Block => {
+                let (count,): (i64,) = sqlx::query_as(
+                    "SELECT COUNT(*) FROM object WHERE user_id = $1",
+                )
+                .bind(id.into_bytes().as_slice())
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(delete_error)?;
+
+                if count > 0 {
+                    return Err(UserError::OwnsObjects(count as u64));
+                }
+                Vec::new()
+            }
+            DeletePolicy::Cascade => {
+                let ids: Vec<(Vec<u8>,)> = sqlx::query_as(
+                    "DELETE FROM object WHERE user_id = $1 RETURNING id",
+                )
+                .bind(id.into_bytes().as_slice())
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(delete_error)?;
+
+                ids.into_iter()
+                    .map(|(id,)| {
+                        Uuid::from_slice(&id).map_err(|_| {
+                            delete_error(sqlx::Error::Decode(
+                                "parse `id` uuid out of range".into(),
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            DeletePolicy::Transfer(target) => {
+                if target == id {
+                    return Err(UserError::TransferToSelf);
+                }
+
+                // Objects are never moved across namespaces
+                sqlx::query_as::<_, (i64,)>(
+                    "SELECT COUNT(*) FROM user WHERE id = $1 AND namespace = \
+                    (SELECT namespace FROM user WHERE id = $2)",
+                )
+                .bind(target.into_bytes().as_slice())
+                .bind(id.into_bytes().as_slice())
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(delete_error)
+                .and_then(|(count,)| {
+                    if count == 0 {
+                        Err(UserError::TransferTargetNotFound(target))
+                    } else {
+                        Ok(())
+                    }
+                })?;
+
+                sqlx::query(
+                    "UPDATE object \
+                    SET user_id = $1, updated_at = $2, version = version + 1 \
+                    WHERE user_id = $3",
+                )
+                .bind(target.into_bytes().as_slice())
+                .bind(Utc::now().timestamp_millis())
+                .bind(id.into_bytes().as_slice())
+                .execute(&mut *tx)
+                .await
+                .map_err(delete_error)?;
+
+                Vec::new()
+            }
+        };
+
+        for table in [
+            "api_key",
+            "oidc_identity",
+            "user_totp",
+            "totp_recovery_code",
+            "object_version",
+            "group_member",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE user_id = $1"))
+                .bind(id.into_bytes().as_slice())
+                .execute(&mut *tx)
+                .await
+                .map_err(delete_error)?;
+        }
+
+        let user = sqlx::query_as(
+            "DELETE FROM user \
+            WHERE id = $1 AND ($2 IS NULL OR namespace = $2) RETURNING *",
+        )
+        .bind(id.into_bytes().as_slice())
+        .bind(self.namespace.as_deref())
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|error| {
-            tracing::error!(
-                %error,
-                "got tokio error while handling bcrypt hash task",
-            );
-            UserError::BcryptHashFailed
-        })?
-        .map_err(|error| {
-            tracing::error!(
-                %error,
-                "got bcrypt error while hashing password",
-            );
-            UserError::BcryptHashFailed
-        })
+        .map_err(delete_error)?
+        .ok_or(UserError::NotFound)?;
+
+        tx.commit().await.map_err(delete_error)?;
+
+        Ok((user, deleted_objects))
+    }
 }
 
-async fn verify_password(
-    password: String,
-    hash: String,
-) -> Result<bool, UserError> {
-    spawn_blocking(move || bcrypt::verify(password, &hash))
-        .await
-        .map_err(|error| {
-            tracing::error!(
-                %error,
-                "got tokio error while handling bcrypt verify task",
-            );
-            UserError::BcryptCompareFailed
-        })?
-        .map_err(|error| {
-            tracing::error!(
-                %error,
-                "got bcrypt error while verifying password",
-            );
-            UserError::BcryptCompareFailed
-        })
+fn update_error(error: sqlx::Error) -> UserError {
+    tracing::error!(%error, "got sqlx error while updating user");
+    UserError::Sqlx(error)
+}
+
+fn delete_error(error: sqlx::Error) -> UserError {
+    tracing::error!(%error, "got sqlx error while deleting user");
+    UserError::Sqlx(error)
+}
+
+fn oidc_error(error: sqlx::Error) -> UserError {
+    tracing::error!(%error, "got sqlx error while provisioning oidc user");
+    UserError::Sqlx(error)
 }
 
 #[cfg(test)]
@@ -262,7 +591,8 @@ mod tests {
 
     use crate::{
         auth::Permission,
-        user::{UserData, UserError},
+        storage::{repository::ObjectRepository, ObjectData},
+        user::{password::PasswordHasher, DeletePolicy, UserData, UserError},
     };
 
     use super::UserRepository;
@@ -278,11 +608,33 @@ mod tests {
         }
     }
 
+    async fn create_objects(
+        repo: &UserRepository<Sqlite>,
+        user_id: Uuid,
+        n: usize,
+    ) -> (ObjectRepository<Sqlite>, Vec<Uuid>) {
+        let obj_repo = ObjectRepository::new(repo.db.clone());
+        let mut ids = Vec::with_capacity(n);
+
+        for _ in 0..n {
+            let data = ObjectData {
+                name: rand_string(),
+                mime_type: mime::TEXT_PLAIN.to_string(),
+                size: 0,
+                checksum_256: [0; 32],
+            };
+            let obj = obj_repo.create(Uuid::new_v4(), user_id, data).await;
+            ids.push(obj.unwrap().id);
+        }
+
+        (obj_repo, ids)
+    }
+
     async fn repository() -> UserRepository<Sqlite> {
         let db = SqlitePool::connect("sqlite::memory:").await.unwrap();
         migrate!().run(&db).await.unwrap();
 
-        UserRepository::new(db, bcrypt::DEFAULT_COST)
+        UserRepository::new(db, PasswordHasher::bcrypt(bcrypt::DEFAULT_COST))
     }
 
     #[test(tokio::test)]
@@ -329,6 +681,35 @@ mod tests {
         )
     }
 
+    #[test(tokio::test)]
+    async fn test_authenticate_rehash() {
+        let repo = repository().await;
+        let password_hash = |id: Uuid| {
+            sqlx::query_as::<_, (String,)>(
+                "SELECT password FROM user WHERE id = $1",
+            )
+            .bind(id.into_bytes().to_vec())
+            .fetch_one(&repo.db)
+        };
+
+        let data = rand_data();
+        let user = repo.create(Permission::ADMIN, data.clone()).await.unwrap();
+        let (legacy_hash,) = password_hash(user.id).await.unwrap();
+        assert!(legacy_hash.starts_with("$2"));
+
+        let argon2_repo = UserRepository::new(
+            repo.db.clone(),
+            PasswordHasher::argon2id(64, 1, 1).unwrap(),
+        );
+        argon2_repo.authenticate(data.clone()).await.unwrap();
+
+        let (hash,) = password_hash(user.id).await.unwrap();
+        assert!(hash.starts_with("$argon2id$"), "password was not rehashed");
+
+        argon2_repo.authenticate(data.clone()).await.unwrap();
+        repo.authenticate(data).await.unwrap();
+    }
+
     #[test(tokio::test)]
     async fn test_update_permission() {
         let repo = repository().await;
@@ -337,8 +718,10 @@ mod tests {
         let user = repo.create(Permission::ADMIN, data.clone()).await.unwrap();
 
         let new_perm = Permission::UNPRIVILEGED.union(Permission::WRITE_USERS);
-        let fetched_user =
-            repo.update_permission(user.id, new_perm).await.unwrap();
+        let fetched_user = repo
+            .update_permission(user.id, new_perm, None)
+            .await
+            .unwrap();
 
         let mut old_user = user.clone();
         assert!(
@@ -348,6 +731,7 @@ mod tests {
 
         old_user.permission = new_perm;
         old_user.updated_at = fetched_user.updated_at;
+        old_user.version += 1;
 
         assert_eq!(
             fetched_user, old_user,
@@ -361,6 +745,40 @@ mod tests {
         );
     }
 
+    #[test(tokio::test)]
+    async fn test_conditional_update() {
+        let repo = repository().await;
+
+        let user = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+        assert_eq!(user.version, 0);
+
+        let updated = repo
+            .update_permission(user.id, Permission::SHARE, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 1);
+
+        // Another admin updating from the version both read
+        let res = repo.update_password(user.id, rand_string(), Some(0)).await;
+        assert!(matches!(res, Err(UserError::Conflict(0))));
+        let res = repo
+            .update_permission(user.id, Permission::ADMIN, Some(0))
+            .await;
+        assert!(matches!(res, Err(UserError::Conflict(0))));
+        assert_eq!(repo.get(user.id).await.unwrap(), updated);
+
+        let updated = repo
+            .update_password(user.id, rand_string(), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 2);
+
+        let res = repo
+            .update_permission(Uuid::new_v4(), Permission::ADMIN, Some(0))
+            .await;
+        assert!(matches!(res, Err(UserError::NotFound)));
+    }
+
     #[test(tokio::test)]
     async fn test_update_password() {
         let repo = repository().await;
@@ -370,7 +788,7 @@ mod tests {
 
         let new_passwd = rand_string();
         let fetched_user = repo
-            .update_password(user.id, new_passwd.clone())
+            .update_password(user.id, new_passwd.clone(), None)
             .await
             .unwrap();
 
@@ -380,6 +798,7 @@ mod tests {
             "updated_at field not changed",
         );
         old_user.updated_at = fetched_user.updated_at;
+        old_user.version += 1;
 
         assert_eq!(
             fetched_user, old_user,
@@ -410,7 +829,7 @@ mod tests {
     async fn test_delete() {
         let repo = repository().await;
 
-        let res = repo.delete(Uuid::new_v4()).await;
+        let res = repo.delete(Uuid::new_v4(), DeletePolicy::Block).await;
         assert!(
             matches!(res, Err(UserError::NotFound)),
             "expected not found error while deleting non existent user",
@@ -419,11 +838,13 @@ mod tests {
         let data = rand_data();
         let user = repo.create(Permission::ADMIN, data.clone()).await.unwrap();
 
-        let fetched_user = repo.delete(user.id).await.unwrap();
+        let (fetched_user, deleted) =
+            repo.delete(user.id, DeletePolicy::Block).await.unwrap();
         assert_eq!(
-            fetched_user, fetched_user,
-            "fetched data mismatches the created one",
+            fetched_user, user,
+            "fetched data mismatches the created one"
         );
+        assert!(deleted.is_empty());
 
         let res = repo.get(user.id).await;
         assert!(
@@ -431,4 +852,155 @@ mod tests {
             "expected not found error while fetching deleted user",
         );
     }
+
+    #[test(tokio::test)]
+    async fn test_delete_block() {
+        let repo = repository().await;
+
+        let user = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+        let (obj_repo, ids) = create_objects(&repo, user.id, 3).await;
+
+        let res = repo.delete(user.id, DeletePolicy::Block).await;
+        assert!(
+            matches!(res, Err(UserError::OwnsObjects(3))),
+            "expected owns objects error while deleting user with objects",
+        );
+
+        repo.get(user.id)
+            .await
+            .expect("blocked user must not be deleted");
+        for id in ids {
+            obj_repo.delete(id).await.unwrap();
+        }
+
+        repo.delete(user.id, DeletePolicy::Block)
+            .await
+            .expect("failed to delete user without objects");
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_cascade() {
+        let repo = repository().await;
+
+        let user = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+        let other = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+
+        let (obj_repo, mut ids) = create_objects(&repo, user.id, 5).await;
+        let (_, other_ids) = create_objects(&repo, other.id, 2).await;
+
+        let (_, mut deleted) =
+            repo.delete(user.id, DeletePolicy::Cascade).await.unwrap();
+
+        ids.sort();
+        deleted.sort();
+        assert_eq!(ids, deleted, "deleted objects mismatch the owned ones");
+
+        for id in ids {
+            assert!(obj_repo.get(id).await.is_err());
+        }
+        for id in other_ids {
+            obj_repo
+                .get(id)
+                .await
+                .expect("objects of other users must be kept");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_transfer() {
+        let repo = repository().await;
+
+        let user = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+        let target = repo.create(Permission::ADMIN, rand_data()).await.unwrap();
+        let (obj_repo, ids) = create_objects(&repo, user.id, 4).await;
+
+        let res = repo.delete(user.id, DeletePolicy::Transfer(user.id)).await;
+        assert!(matches!(res, Err(UserError::TransferToSelf)));
+
+        let missing = Uuid::new_v4();
+        let res = repo.delete(user.id, DeletePolicy::Transfer(missing)).await;
+        assert!(
+            matches!(res, Err(UserError::TransferTargetNotFound(id)) if id == missing),
+            "expected error while transferring objects to missing user",
+        );
+        let res = repo
+            .check_delete_policy(DeletePolicy::Transfer(missing))
+            .await;
+        assert!(matches!(res, Err(UserError::TransferTargetNotFound(..))));
+        repo.check_delete_policy(DeletePolicy::Transfer(target.id))
+            .await
+            .unwrap();
+        repo.check_delete_policy(DeletePolicy::Cascade)
+            .await
+            .unwrap();
+
+        let (_, deleted) = repo
+            .delete(user.id, DeletePolicy::Transfer(target.id))
+            .await
+            .unwrap();
+        assert!(deleted.is_empty());
+
+        for id in ids {
+            let obj = obj_repo.get(id).await.unwrap();
+            assert_eq!(obj.user_id, target.id, "object was not transferred");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_or_create_oidc() {
+        let repo = repository().await;
+        let (issuer, subject) = ("https://idp.example.com", rand_string());
+
+        let user = repo
+            .get_or_create_oidc(
+                issuer,
+                &subject,
+                rand_string(),
+                Permission::UNPRIVILEGED,
+            )
+            .await
+            .unwrap();
+        assert_eq!(user.permission, Permission::UNPRIVILEGED);
+
+        let same = repo
+            .get_or_create_oidc(
+                issuer,
+                &subject,
+                rand_string(),
+                Permission::ADMIN,
+            )
+            .await
+            .unwrap();
+        assert_eq!(user, same, "identity must map to the provisioned user");
+
+        let res = repo
+            .authenticate(UserData {
+                username: user.username.clone(),
+                password: String::new(),
+            })
+            .await;
+        assert!(matches!(res, Err(UserError::PasswordMismatch)));
+
+        let res = repo
+            .get_or_create_oidc(
+                issuer,
+                &rand_string(),
+                user.username.clone(),
+                Permission::UNPRIVILEGED,
+            )
+            .await;
+        assert!(matches!(res, Err(UserError::AlreadyExists(..))));
+
+        repo.delete(user.id, DeletePolicy::Block).await.unwrap();
+        let recreated = repo
+            .get_or_create_oidc(
+                issuer,
+                &subject,
+                rand_string(),
+                Permission::UNPRIVILEGED,
+            )
+            .await
+            .unwrap();
+        assert_ne!(recreated.id, user.id, "identity was not deleted");
+    }
 }
@@ -1,43 +1,55 @@
+use std::sync::Arc;
+
 use axum::{extract::Path, routing, Extension, Router};
 use serde::Deserialize;
 use sqlx::Sqlite;
+use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::{
     auth::{axum::Authorization, AuthError, Permission, Token},
     errors::DownloaderError,
-    utils::extractors::Json,
+    job::{queue::JobQueue, JobKind},
+    storage::{manager::Manager, repository::ObjectRepository},
+    utils::extractors::Body,
 };
 
-use super::{repository::UserRepository, User};
+use super::{repository::UserRepository, DeletePolicy, User};
 
-pub fn user_routes<S>(router: Router<S>) -> Router<S>
+pub fn user_routes<S, M>(router: Router<S>) -> Router<S>
 where
     S: Clone + Send + Sync + 'static,
+    M: Manager,
 {
     router
         .route("/self", routing::get(get_self))
         .route("/:id", routing::get(get_user))
         .route("/:id/password", routing::put(update_user_password))
         .route("/:id/permission", routing::put(update_user_permission))
-        .route("/self", routing::delete(delete_self))
-        .route("/:id", routing::delete(delete_user))
+        .route("/self", routing::delete(delete_self::<M>))
+        .route("/:id", routing::delete(delete_user::<M>))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct UpdatePasswordRequestData {
     pub password: String,
+    /// Only updates the user if still at this version.
+    #[serde(default)]
+    pub version: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct UpdatePermissionRequestData {
     pub permission: Permission,
+    /// Only updates the user if still at this version.
+    #[serde(default)]
+    pub version: Option<u64>,
 }
 
 pub async fn get_self(
     Authorization(token): Authorization,
     ext: Extension<UserRepository<Sqlite>>,
-) -> Result<Json<User>, DownloaderError> {
+) -> Result<Body<User>, DownloaderError> {
     let id = match token {
         Token::User(user_token) => user_token.user_id,
         _ => return Err(AuthError::AccessDenied.into()),
@@ -50,7 +62,7 @@ pub async fn get_user(
     Authorization(token): Authorization,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
     Path(id): Path<Uuid>,
-) -> Result<Json<User>, DownloaderError> {
+) -> Result<Body<User>, DownloaderError> {
     let can_access = match &token {
         Token::User(user_token) => {
             user_token.user_id == id || token.can_read_users()
@@ -64,59 +76,349 @@ pub async fn get_user(
     }
 
     let user = user_repo.get(id).await?;
-    Ok(Json(user))
+    Ok(Body(user))
 }
 
 pub async fn update_user_password(
     Authorization(token): Authorization,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
     Path(id): Path<Uuid>,
-    Json(data): Json<UpdatePasswordRequestData>,
-) -> Result<Json<User>, DownloaderError> {
+    Body(data): Body<UpdatePasswordRequestData>,
+) -> Result<Body<User>, DownloaderError> {
     if !token.can_write_users() {
         return Err(AuthError::AccessDenied.into());
     }
 
-    let user = user_repo.update_password(id, data.password).await?;
-    Ok(Json(user))
+    let user = user_repo
+        .update_password(id, data.password, data.version)
+        .await?;
+    Ok(Body(user))
 }
 
 pub async fn update_user_permission(
     Authorization(token): Authorization,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
     Path(id): Path<Uuid>,
-    Json(data): Json<UpdatePermissionRequestData>,
-) -> Result<Json<User>, DownloaderError> {
+    Body(data): Body<UpdatePermissionRequestData>,
+) -> Result<Body<User>, DownloaderError> {
     if !token.can_write_users() {
         return Err(AuthError::AccessDenied.into());
     }
 
-    let user = user_repo.update_permission(id, data.permission).await?;
-    Ok(Json(user))
+    let user = user_repo
+        .update_permission(id, data.permission, data.version)
+        .await?;
+    Ok(Body(user))
 }
 
-pub async fn delete_self(
+pub async fn delete_self<M: Manager>(
     Authorization(token): Authorization,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
-) -> Result<Json<User>, DownloaderError> {
+    Extension(obj_repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(policy): Extension<DeletePolicy>,
+    Extension(jobs): Extension<Arc<JobQueue>>,
+) -> Result<Body<User>, DownloaderError> {
     let id = match token {
         Token::User(user_token) => user_token.user_id,
         _ => return Err(AuthError::AccessDenied.into()),
     };
 
-    let user = user_repo.delete(id).await?;
-    Ok(Json(user))
+    delete_user_internal(user_repo, obj_repo, manager, jobs, policy, id)
+        .await
+        .map(Body)
 }
 
-pub async fn delete_user(
+pub async fn delete_user<M: Manager>(
     Authorization(token): Authorization,
     Extension(user_repo): Extension<UserRepository<Sqlite>>,
+    Extension(obj_repo): Extension<ObjectRepository<Sqlite>>,
+    Extension(manager): Extension<Arc<M>>,
+    Extension(policy): Extension<DeletePolicy>,
+    Extension(jobs): Extension<Arc<JobQueue>>,
     Path(id): Path<Uuid>,
-) -> Result<Json<User>, DownloaderError> {
+) -> Result<Body<User>, DownloaderError> {
     if !token.can_write_users() {
         return Err(AuthError::AccessDenied.into());
     }
 
-    let user = user_repo.delete(id).await?;
-    Ok(Json(user))
+    delete_user_internal(user_repo, obj_repo, manager, jobs, policy, id)
+        .await
+        .map(Body)
+}
+
+async fn delete_user_internal<M: Manager>(
+    user_repo: UserRepository<Sqlite>,
+    obj_repo: ObjectRepository<Sqlite>,
+    manager: Arc<M>,
+    jobs: Arc<JobQueue>,
+    policy: DeletePolicy,
+    id: Uuid,
+) -> Result<User, DownloaderError> {
+    let (user, deleted_objects) = user_repo.delete(id, policy).await?;
+    // Objects were either deleted or handed over to another owner
+    obj_repo.invalidate_cache();
+
+    if !deleted_objects.is_empty() {
+        let res = jobs
+            .enqueue(JobKind::DeleteUserObjects, None, |progress| {
+                progress.set_total(deleted_objects.len() as u64);
+
+                async move {
+                    let mut failed = 0;
+                    for id in deleted_objects {
+                        // Errors are already logged by the manager
+                        if manager.delete(id).await.is_err() {
+                            failed += 1;
+                        }
+                        progress.add(1);
+                    }
+                    Ok(serde_json::json!({ "failed": failed }))
+                }
+                .instrument(tracing::span!(
+                    tracing::Level::WARN,
+                    "delete_user_objects_background",
+                    user_id = %id,
+                ))
+            })
+            .await;
+
+        // The user is already gone, so the request must not fail
+        if let Err(error) = res {
+            tracing::error!(
+                %error,
+                user_id = %id,
+                "failed to queue the deletion of the user objects",
+            );
+        }
+    }
+
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use bytes::Bytes;
+    use futures_util::stream;
+    use test_log::test;
+    use uuid::Uuid;
+
+    use crate::{
+        auth::Permission,
+        storage::{
+            manager::{Manager, ObjectError},
+            ObjectData,
+        },
+        testing::{test_app, test_app_with, TestApp},
+        user::{DeletePolicy, User},
+    };
+
+    async fn create_user(
+        app: &TestApp,
+        permission: Permission,
+    ) -> (User, String) {
+        let username = Uuid::new_v4().to_string();
+        app.create_user_token(&username, permission).await
+    }
+
+    async fn create_object(app: &TestApp, user_id: Uuid) -> Uuid {
+        let id = Uuid::new_v4();
+        let content = Bytes::from_static(b"hello world");
+
+        let (size, checksum_256) = app
+            .manager
+            .store(id, stream::iter([Ok(content)]), None, None)
+            .await
+            .unwrap();
+
+        let data = ObjectData {
+            name: Uuid::new_v4().to_string(),
+            mime_type: mime::APPLICATION_OCTET_STREAM.to_string(),
+            size,
+            checksum_256,
+        };
+
+        app.obj_repo.create(id, user_id, data).await.unwrap();
+        id
+    }
+
+    async fn request(
+        app: &TestApp,
+        method: Method,
+        uri: &str,
+        token: &str,
+        body: Option<serde_json::Value>,
+    ) -> StatusCode {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        app.request(method, uri, Some(token), body).await.0
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_user_permission() {
+        let app = test_app().await;
+
+        let (user, token) = create_user(&app, Permission::SHARE).await;
+        let (other, _) = create_user(&app, Permission::UNPRIVILEGED).await;
+        let (_, reader_token) =
+            create_user(&app, Permission::UNPRIVILEGED).await;
+
+        let uri = "/api/user/self";
+        let status = request(&app, Method::GET, uri, &token, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = format!("/api/user/{}", user.id);
+        let status = request(&app, Method::GET, &uri, &token, None).await;
+        assert_eq!(status, StatusCode::OK, "user must be able to read itself");
+
+        let uri = format!("/api/user/{}", other.id);
+        let status = request(&app, Method::GET, &uri, &token, None).await;
+        assert_eq!(
+            status,
+            StatusCode::FORBIDDEN,
+            "expected access denied without `READ_USERS`",
+        );
+
+        let status =
+            request(&app, Method::GET, &uri, &reader_token, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_user_permission() {
+        let app = test_app().await;
+
+        let (_, token) = create_user(&app, Permission::UNPRIVILEGED).await;
+        let (_, admin_token) = create_user(&app, Permission::ADMIN).await;
+        let (target, _) = create_user(&app, Permission::UNPRIVILEGED).await;
+
+        let uri = format!("/api/user/{}/permission", target.id);
+        let body = serde_json::json!({ "permission": Permission::ADMIN });
+
+        let status =
+            request(&app, Method::PUT, &uri, &token, Some(body.clone())).await;
+        assert_eq!(
+            status,
+            StatusCode::FORBIDDEN,
+            "expected access denied without `WRITE_USERS`",
+        );
+
+        let status =
+            request(&app, Method::PUT, &uri, &admin_token, Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let target = app.user_repo.get(target.id).await.unwrap();
+        assert_eq!(target.permission, Permission::ADMIN);
+
+        // Made from the version read before the last update
+        let body = serde_json::json!({
+            "permission": Permission::UNPRIVILEGED,
+            "version": target.version - 1,
+        });
+        let status =
+            request(&app, Method::PUT, &uri, &admin_token, Some(body)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let body = serde_json::json!({
+            "permission": Permission::UNPRIVILEGED,
+            "version": target.version,
+        });
+        let status =
+            request(&app, Method::PUT, &uri, &admin_token, Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_user_permission() {
+        let app = test_app().await;
+
+        let (_, token) = create_user(&app, Permission::UNPRIVILEGED).await;
+        let (_, admin_token) = create_user(&app, Permission::ADMIN).await;
+        let (target, _) = create_user(&app, Permission::UNPRIVILEGED).await;
+
+        let uri = format!("/api/user/{}", target.id);
+
+        let status = request(&app, Method::DELETE, &uri, &token, None).await;
+        assert_eq!(
+            status,
+            StatusCode::FORBIDDEN,
+            "expected access denied without `WRITE_USERS`",
+        );
+
+        let status =
+            request(&app, Method::DELETE, &uri, &admin_token, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let status =
+            request(&app, Method::DELETE, &uri, &admin_token, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_user_owning_objects() {
+        let app = test_app().await;
+
+        let (_, admin_token) = create_user(&app, Permission::ADMIN).await;
+        let (target, target_token) =
+            create_user(&app, Permission::UNPRIVILEGED).await;
+
+        let obj_id = create_object(&app, target.id).await;
+
+        let uri = format!("/api/user/{}", target.id);
+        let status =
+            request(&app, Method::DELETE, &uri, &admin_token, None).await;
+        assert_eq!(
+            status,
+            StatusCode::CONFLICT,
+            "expected conflict while deleting user that owns objects",
+        );
+
+        let uri = "/api/user/self";
+        let status =
+            request(&app, Method::DELETE, uri, &target_token, None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        app.user_repo
+            .get(target.id)
+            .await
+            .expect("user must not be deleted while owning objects");
+
+        app.obj_repo.delete(obj_id).await.unwrap();
+
+        let status =
+            request(&app, Method::DELETE, uri, &target_token, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test(tokio::test)]
+    async fn test_delete_user_cascade() {
+        let app =
+            test_app_with(|cfg| cfg.on_user_delete = DeletePolicy::Cascade)
+                .await;
+
+        let (_, admin_token) = create_user(&app, Permission::ADMIN).await;
+        let (target, _) = create_user(&app, Permission::UNPRIVILEGED).await;
+
+        let obj_id = create_object(&app, target.id).await;
+
+        let uri = format!("/api/user/{}", target.id);
+        let status =
+            request(&app, Method::DELETE, &uri, &admin_token, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert!(
+            app.obj_repo.get(obj_id).await.is_err(),
+            "expected object to be deleted along with its owner",
+        );
+
+        // Object data is removed in background
+        for _ in 0..50 {
+            if let Err(ObjectError::NotFound) = app.manager.fetch(obj_id).await
+            {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("object data was not removed after owner deletion");
+    }
 }
@@ -1,15 +1,20 @@
 use std::{
+    io,
     pin::Pin,
     task::{Context, Poll},
 };
 
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
 use bytes::Bytes;
 use futures_util::Stream;
-use jsonwebtoken::{DecodingKey, EncodingKey};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
 use pin_project_lite::pin_project;
-use sha2::{digest::Output, Digest};
+use sha2::{digest::Output, Digest, Sha256};
 use sqlx::error::BoxDynError;
-use tokio::io::AsyncRead;
+use tokio::{io::AsyncRead, sync::mpsc, task::JoinHandle};
 
 pin_project! {
     pub struct HashRead<T, H> {
@@ -102,21 +107,259 @@ where
         let this = self.project();
         let poll = this.stream.poll_next(cx);
         if let Poll::Ready(Some(Ok(v))) = &poll {
-            this.hasher.update(&v);
+            this.hasher.update(v);
         }
         poll
     }
 }
 
+/// Bytes sent at once to the thread of a [`ConcurrentHasher`], as waking it
+/// for every chunk costs more than hashing small ones.
+const HASH_BATCH_SIZE: usize = 1024 * 1024;
+/// Batches waiting to be hashed by a [`ConcurrentHasher`] before the task
+/// feeding it waits too.
+const HASH_QUEUE_LEN: usize = 4;
+
+/// Hashes data on a blocking thread, so the task feeding it can do IO while
+/// the previous chunks are hashed.
+pub struct ConcurrentHasher<H: Digest> {
+    tx: mpsc::Sender<Vec<Bytes>>,
+    handle: JoinHandle<H>,
+    batch: Vec<Bytes>,
+    batch_size: usize,
+}
+
+impl<H: Digest + Send + 'static> ConcurrentHasher<H> {
+    pub fn new() -> Self {
+        let (tx, mut rx) = mpsc::channel::<Vec<Bytes>>(HASH_QUEUE_LEN);
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut hasher = H::new();
+            while let Some(batch) = rx.blocking_recv() {
+                batch.iter().for_each(|chunk| hasher.update(chunk));
+            }
+            hasher
+        });
+
+        Self {
+            tx,
+            handle,
+            batch: Vec::new(),
+            batch_size: 0,
+        }
+    }
+
+    /// Queues the chunk to be hashed, waiting if too many are queued.
+    pub async fn update(&mut self, chunk: Bytes) {
+        self.batch_size += chunk.len();
+        self.batch.push(chunk);
+
+        if self.batch_size >= HASH_BATCH_SIZE {
+            self.batch_size = 0;
+            // Only fails if the thread panicked, reported by `finish`
+            let _ = self.tx.send(std::mem::take(&mut self.batch)).await;
+        }
+    }
+
+    /// Waits for the queued chunks to be hashed.
+    pub async fn finish(self) -> io::Result<Output<H>> {
+        if !self.batch.is_empty() {
+            let _ = self.tx.send(self.batch).await;
+        }
+        drop(self.tx);
+
+        match self.handle.await {
+            Ok(hasher) => Ok(hasher.finalize()),
+            Err(error) => Err(io::Error::other(error)),
+        }
+    }
+}
+
+impl<H: Digest + Send + 'static> Default for ConcurrentHasher<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the signing key and its certificate, returning them along with the
+/// key id of the certificate. HMAC algorithms use the contents of the key
+/// file as the secret and need no certificate.
 pub async fn fetch_jwt_key_files(
-    public_key: &str,
+    algo: Algorithm,
+    public_key: Option<&str>,
     private_key: &str,
-) -> Result<(EncodingKey, DecodingKey), BoxDynError> {
+) -> Result<(EncodingKey, DecodingKey, String), BoxDynError> {
+    let private_key = tokio::fs::read(private_key).await?;
+
+    if is_hmac(algo) {
+        let secret = private_key.trim_ascii();
+        return Ok((
+            EncodingKey::from_secret(secret),
+            DecodingKey::from_secret(secret),
+            secret_key_id(secret),
+        ));
+    }
+
+    let public_key = public_key.ok_or_else(|| {
+        format!("a certificate is required by the {algo:?} algorithm")
+    })?;
+    let (kid, public_key) = fetch_jwt_cert_file(algo, public_key).await?;
+
+    let private_key = match algo {
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(&private_key)?,
+        Algorithm::ES256 | Algorithm::ES384 => {
+            EncodingKey::from_ec_pem(&private_key)?
+        }
+        _ => EncodingKey::from_rsa_pem(&private_key)?,
+    };
+
+    Ok((private_key, public_key, kid))
+}
+
+/// Reads a certificate, or a secret for HMAC algorithms, returning it along
+/// with its key id.
+pub async fn fetch_jwt_cert_file(
+    algo: Algorithm,
+    public_key: &str,
+) -> Result<(String, DecodingKey), BoxDynError> {
     let public_key = tokio::fs::read(public_key).await?;
-    let public_key = DecodingKey::from_ed_pem(&public_key)?;
 
-    let private_key = tokio::fs::read(private_key).await?;
-    let private_key = EncodingKey::from_ed_pem(&private_key)?;
+    if is_hmac(algo) {
+        let secret = public_key.trim_ascii();
+        return Ok((secret_key_id(secret), DecodingKey::from_secret(secret)));
+    }
 
-    Ok((private_key, public_key))
+    let kid = jwt_key_id(&public_key)?;
+    let public_key = match algo {
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(&public_key)?,
+        Algorithm::ES256 | Algorithm::ES384 => {
+            DecodingKey::from_ec_pem(&public_key)?
+        }
+        _ => DecodingKey::from_rsa_pem(&public_key)?,
+    };
+
+    Ok((kid, public_key))
+}
+
+#[inline]
+fn is_hmac(algo: Algorithm) -> bool {
+    matches!(algo, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/// Key id of an HMAC secret, a truncated hash so it does not disclose it.
+fn secret_key_id(secret: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(&Sha256::digest(secret)[..12])
+}
+
+/// Derives a stable key id from the DER contents of a PEM certificate, so
+/// it does not depend on how the file is formatted.
+pub fn jwt_key_id(pem: &[u8]) -> Result<String, BoxDynError> {
+    let der: String = std::str::from_utf8(pem)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = BASE64_STANDARD.decode(der)?;
+
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(&Sha256::digest(der)[..12]))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use jsonwebtoken::{Algorithm, Header, Validation};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    use super::{
+        fetch_jwt_cert_file, fetch_jwt_key_files, jwt_key_id, ConcurrentHasher,
+    };
+
+    const CERT: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAGb9ECWmEzf6FQbrBZ9w7lshQhqowtrbLDFw4rXAxZuE=
+-----END PUBLIC KEY-----
+";
+
+    #[test]
+    fn test_jwt_key_id() {
+        let kid = jwt_key_id(CERT.as_bytes()).unwrap();
+        assert_eq!(kid.len(), 16);
+
+        let reformatted = CERT.replace('\n', "\r\n  ");
+        assert_eq!(jwt_key_id(reformatted.as_bytes()).unwrap(), kid);
+
+        let other = CERT.replace("Gb9E", "GB9E");
+        assert_ne!(jwt_key_id(other.as_bytes()).unwrap(), kid);
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let body = BASE64_STANDARD.encode(der);
+        format!("-----BEGIN {label}-----\n{body}\n-----END {label}-----\n")
+    }
+
+    async fn roundtrip(dir: &TempDir, algo: Algorithm, cert: Option<&str>) {
+        let key = dir.path().join("key");
+        let key = key.to_str().unwrap();
+
+        let (enc_key, dec_key, kid) =
+            fetch_jwt_key_files(algo, cert, key).await.unwrap();
+        let (cert_kid, _) = fetch_jwt_cert_file(algo, cert.unwrap_or(key))
+            .await
+            .unwrap();
+        assert_eq!(kid, cert_kid);
+
+        let claims = json!({ "exp": chrono::Utc::now().timestamp() + 60 });
+        let token = jsonwebtoken::encode(&Header::new(algo), &claims, &enc_key)
+            .unwrap();
+        jsonwebtoken::decode::<Value>(&token, &dec_key, &Validation::new(algo))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_jwt_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::write(dir.path().join("key"), "secret\n").unwrap();
+        roundtrip(&dir, Algorithm::HS256, None).await;
+
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let mut spki = hex::decode("302a300506032b6570032100").unwrap();
+        spki.extend_from_slice(pair.public_key().as_ref());
+
+        let cert = dir.path().join("cert");
+        std::fs::write(&cert, pem("PUBLIC KEY", &spki)).unwrap();
+        std::fs::write(
+            dir.path().join("key"),
+            pem("PRIVATE KEY", pkcs8.as_ref()),
+        )
+        .unwrap();
+        roundtrip(&dir, Algorithm::EdDSA, cert.to_str()).await;
+
+        let res = fetch_jwt_key_files(
+            Algorithm::EdDSA,
+            None,
+            dir.path().join("key").to_str().unwrap(),
+        )
+        .await;
+        assert!(res.is_err(), "asymmetric keys require a certificate");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_hasher() {
+        use bytes::Bytes;
+        use sha2::{Digest, Sha256};
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        let mut hasher = ConcurrentHasher::<Sha256>::new();
+        for chunk in data.chunks(1000) {
+            hasher.update(Bytes::copy_from_slice(chunk)).await;
+        }
+        assert_eq!(hasher.finish().await.unwrap(), Sha256::digest(&data));
+    }
 }
@@ -1,13 +1,20 @@
+use std::convert::Infallible;
+
 use axum::{
     async_trait,
     extract::{FromRequest, FromRequestParts, Request},
-    http::request::Parts,
-    response::IntoResponse,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::errors::DownloaderError;
 
+/// Accepted by clients wanting list responses wrapped in a [`Page`].
+pub const PAGE_MEDIA_TYPE: &str = "application/vnd.downloader.page+json";
+
 pub struct Query<T>(pub T);
 
 #[async_trait]
@@ -30,12 +37,123 @@ where
     }
 }
 
-pub struct Json<T>(pub T);
+tokio::task_local! {
+    static FORMAT: Format;
+}
+
+/// Formats of the bodies of [`Body`], told apart by their media type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl Format {
+    #[inline]
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn parse(essence: &str) -> Option<Self> {
+        let essence = essence.split(';').next().unwrap_or("").trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack"
+            | "application/x-msgpack"
+            | "application/vnd.msgpack" => Some(Format::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// The most preferred of the formats accepted by the request, json if
+    /// none is listed.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let mut best: Option<(f32, Format)> = None;
+        let ranges = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for range in ranges {
+            let Some(format) = Format::parse(range) else {
+                continue;
+            };
+            let q = range
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            // The first of equally preferred formats wins
+            if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                best = Some((q, format));
+            }
+        }
+        best.map(|(_, format)| format).unwrap_or_default()
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => {
+                serde_json::to_vec(value).map_err(|e| e.to_string())
+            }
+            Format::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf)
+                    .map_err(|e| e.to_string())?;
+                Ok(buf)
+            }
+            // Structs as maps, so fields are named like in json
+            Format::MsgPack => {
+                rmp_serde::to_vec_named(value).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => {
+                serde_json::from_slice(bytes).map_err(|e| e.to_string())
+            }
+            Format::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| e.to_string())
+            }
+            Format::MsgPack => {
+                rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// Format of the responses to the request being handled, set by
+/// [`scope_format`].
+pub fn current_format() -> Format {
+    FORMAT.try_with(|format| *format).unwrap_or_default()
+}
+
+/// Negotiates the format of the [`Body`] responses to the request.
+pub async fn scope_format(req: Request, next: Next) -> Response {
+    let format = Format::negotiate(req.headers());
+    FORMAT.scope(format, next.run(req)).await
+}
+
+/// Request or response body in one of the [`Format`]s, json unless the
+/// `Content-Type` of the request or its `Accept` header tell otherwise.
+pub struct Body<T>(pub T);
 
 #[async_trait]
-impl<T, S> FromRequest<S> for Json<T>
+impl<T, S> FromRequest<S> for Body<T>
 where
-    T: for<'de> Deserialize<'de>,
+    T: DeserializeOwned,
     S: Send + Sync,
     T: Send + Sync,
 {
@@ -45,16 +163,264 @@ where
         req: Request,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
-        axum::Json::from_request(req, state)
+        let format = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Format::parse);
+
+        // Json or any other type is rejected as axum always did
+        let format = match format {
+            Some(format @ (Format::Cbor | Format::MsgPack)) => format,
+            Some(Format::Json) | None => {
+                return axum::Json::from_request(req, state)
+                    .await
+                    .map(|v| Body(v.0))
+                    .map_err(|e| {
+                        DownloaderError::Other(e.body_text(), e.status())
+                    });
+            }
+        };
+
+        let bytes = Bytes::from_request(req, state)
             .await
-            .map(|v| Json(v.0))
-            .map_err(|e| DownloaderError::Other(e.body_text(), e.status()))
+            .map_err(|e| DownloaderError::Other(e.body_text(), e.status()))?;
+        format.decode(&bytes).map(Body).map_err(|e| {
+            DownloaderError::Other(
+                format!(
+                    "Failed to deserialize the {} body: {e}",
+                    format.media_type(),
+                ),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            )
+        })
     }
 }
 
-impl<T: Serialize> IntoResponse for Json<T> {
-    #[inline]
-    fn into_response(self) -> axum::response::Response {
-        axum::Json(self.0).into_response()
+impl<T: Serialize> IntoResponse for Body<T> {
+    fn into_response(self) -> Response {
+        let format = current_format();
+        if format == Format::Json {
+            return axum::Json(self.0).into_response();
+        }
+
+        match format.encode(&self.0) {
+            Ok(body) => ([(header::CONTENT_TYPE, format.media_type())], body)
+                .into_response(),
+            Err(err) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, err).into_response()
+            }
+        }
+    }
+}
+
+/// Whether the `Accept` headers list the media type `essence`, whatever
+/// its parameters.
+pub fn accepts(headers: &HeaderMap, essence: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|ty| {
+            let ty = ty.split(';').next().unwrap_or("").trim();
+            ty.eq_ignore_ascii_case(essence)
+        })
+}
+
+/// A page of a list, with what to pass as the `offset` of the request for
+/// the next one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// `None` once no more items follow.
+    pub next_cursor: Option<String>,
+    /// Items in the whole list, when cheap to tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+impl<T> Page<T> {
+    /// A page of `limit` items at most starting at `offset`, more items
+    /// possibly following if it is full.
+    pub fn new(items: Vec<T>, limit: u32, offset: u32) -> Self {
+        let next_cursor = (items.len() >= limit as usize && limit > 0)
+            .then(|| (offset as usize + items.len()).to_string());
+        Self {
+            items,
+            next_cursor,
+            total: None,
+        }
+    }
+
+    pub fn with_total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+}
+
+/// Whether the client opted in to [`Page`] envelopes by accepting
+/// [`PAGE_MEDIA_TYPE`]. List routes respond with bare arrays otherwise, as
+/// they always did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Envelope(pub bool);
+
+impl Envelope {
+    pub fn of(headers: &HeaderMap) -> Self {
+        Self(accepts(headers, PAGE_MEDIA_TYPE))
+    }
+
+    /// Responds with `page`, or only its items if not opted in.
+    pub fn wrap<T>(self, page: Page<T>) -> Paged<T> {
+        Paged {
+            page,
+            envelope: self,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Envelope {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::of(&parts.headers))
+    }
+}
+
+/// A list response, see [`Envelope`].
+#[derive(Debug, Clone)]
+pub struct Paged<T> {
+    pub page: Page<T>,
+    pub envelope: Envelope,
+}
+
+impl<T: Serialize> IntoResponse for Paged<T> {
+    fn into_response(self) -> Response {
+        if self.envelope.0 {
+            Body(self.page).into_response()
+        } else {
+            Body(self.page.items).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body as RawBody},
+        http::HeaderValue,
+        middleware, routing, Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Fox {
+        name: String,
+        size: u64,
+    }
+
+    fn fox() -> Fox {
+        Fox {
+            name: "fox.txt".into(),
+            size: 19,
+        }
+    }
+
+    async fn post(content_type: &str, accept: &str, body: Vec<u8>) -> Response {
+        let router = Router::new()
+            .route(
+                "/",
+                routing::post(|Body(fox): Body<Fox>| async { Body(fox) }),
+            )
+            .layer(middleware::from_fn(scope_format));
+
+        let req = axum::http::Request::post("/")
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ACCEPT, accept)
+            .body(RawBody::from(body))
+            .unwrap();
+        router.oneshot(req).await.unwrap()
+    }
+
+    async fn read(res: Response) -> (StatusCode, String, Bytes) {
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_owned())
+            .unwrap_or_default();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, body)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_body_formats() {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&fox(), &mut cbor).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&fox()).unwrap();
+        let json = serde_json::to_vec(&fox()).unwrap();
+
+        let res = post("application/cbor", "application/cbor", cbor).await;
+        let (status, content_type, body) = read(res).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/cbor");
+        assert_eq!(ciborium::from_reader::<Fox, _>(&body[..]).unwrap(), fox());
+
+        let res = post("application/x-msgpack", "*/*", msgpack).await;
+        let (_, content_type, body) = read(res).await;
+        assert_eq!(content_type, "application/json");
+        assert_eq!(serde_json::from_slice::<Fox>(&body).unwrap(), fox());
+
+        let accept = "application/json;q=0.5, application/msgpack";
+        let res = post("application/json", accept, json).await;
+        let (_, content_type, body) = read(res).await;
+        assert_eq!(content_type, "application/msgpack");
+        assert_eq!(rmp_serde::from_slice::<Fox>(&body).unwrap(), fox());
+
+        let res = post("application/cbor", "*/*", b"not cbor".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res = post("text/plain", "*/*", b"{}".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Envelope::of(&headers), Envelope(false));
+
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        assert_eq!(Envelope::of(&headers), Envelope(false));
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static(
+                "application/json, application/vnd.downloader.page+json; q=0.9",
+            ),
+        );
+        assert_eq!(Envelope::of(&headers), Envelope(true));
+    }
+
+    #[test]
+    fn test_page() {
+        let page = Page::new(vec![1, 2], 2, 4);
+        assert_eq!(page.next_cursor.as_deref(), Some("6"));
+
+        // Not full, so the last one
+        let page = Page::new(vec![1], 2, 4).with_total(5);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(
+            serde_json::to_value(&page).unwrap(),
+            serde_json::json!({ "items": [1], "next_cursor": null, "total": 5 }),
+        );
+        assert_eq!(
+            serde_json::to_value(Page::new(Vec::<u8>::new(), 0, 0)).unwrap(),
+            serde_json::json!({ "items": [], "next_cursor": null }),
+        );
     }
 }
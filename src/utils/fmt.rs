@@ -21,6 +21,21 @@ pub fn fmt_duration(latency: Duration) -> String {
     }
 }
 
+pub fn fmt_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
 #[inline]
 pub fn fmt_hex(buf: &[u8]) -> String {
     hex::encode(buf)
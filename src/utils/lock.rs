@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Entries of released locks are pruned once the map grows past this size.
+const MIN_PRUNE_THRESHOLD: usize = 64;
+
+struct Locks<K> {
+    map: HashMap<K, Weak<AsyncMutex<()>>>,
+    prune_at: usize,
+}
+
+/// A map of async locks created on demand for each key, so operations on
+/// the same key are serialized while different keys proceed concurrently.
+pub struct KeyedLock<K> {
+    locks: Mutex<Locks<K>>,
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyedLock<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedLock<K> {
+    pub fn new() -> Self {
+        Self {
+            locks: Mutex::new(Locks {
+                map: HashMap::new(),
+                prune_at: MIN_PRUNE_THRESHOLD,
+            }),
+        }
+    }
+
+    /// Waits until the lock of `key` is released by its current holder.
+    pub async fn lock(&self, key: &K) -> OwnedMutexGuard<()> {
+        self.entry(key).lock_owned().await
+    }
+
+    /// Returns [`None`] if the lock of `key` is currently held.
+    pub fn try_lock(&self, key: &K) -> Option<OwnedMutexGuard<()>> {
+        self.entry(key).try_lock_owned().ok()
+    }
+
+    fn entry(&self, key: &K) -> Arc<AsyncMutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+
+        if let Some(lock) = locks.map.get(key).and_then(Weak::upgrade) {
+            return lock;
+        }
+
+        if locks.map.len() >= locks.prune_at {
+            locks.map.retain(|_, lock| lock.strong_count() > 0);
+            locks.prune_at = (locks.map.len() * 2).max(MIN_PRUNE_THRESHOLD);
+        }
+
+        let lock = Arc::new(AsyncMutex::new(()));
+        locks.map.insert(key.clone(), Arc::downgrade(&lock));
+        lock
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().map.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{KeyedLock, MIN_PRUNE_THRESHOLD};
+
+    #[tokio::test]
+    async fn test_keyed_lock() {
+        let locks = Arc::new(KeyedLock::new());
+
+        let guard = locks.lock(&1).await;
+        assert!(locks.try_lock(&1).is_none());
+        assert!(locks.try_lock(&2).is_some(), "keys must not share locks");
+
+        let task = tokio::spawn({
+            let locks = locks.clone();
+            async move { drop(locks.lock(&1).await) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!task.is_finished(), "lock acquired while held");
+
+        drop(guard);
+        task.await.unwrap();
+        assert!(locks.try_lock(&1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let locks = KeyedLock::new();
+
+        for i in 0..(MIN_PRUNE_THRESHOLD * 4) {
+            drop(locks.lock(&i).await);
+        }
+        assert!(locks.len() <= MIN_PRUNE_THRESHOLD, "released locks leaked");
+    }
+}
@@ -1,5 +1,8 @@
 pub mod crypto;
 pub mod extractors;
 pub mod fmt;
+pub mod lock;
+pub mod retry;
 pub mod serde;
+pub mod stream;
 pub mod sys;
@@ -0,0 +1,155 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Attempts of an operation failing with lock contention, the first one
+/// included.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled for each of the next ones.
+const BASE_DELAY: Duration = Duration::from_millis(10);
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+static RETRIED: AtomicU64 = AtomicU64::new(0);
+static EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the operations retried by [`retry_busy`] since startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusyRetries {
+    /// Retries after the database was busy or locked.
+    pub retried: u64,
+    /// Operations that failed after the last attempt.
+    pub exhausted: u64,
+}
+
+pub fn busy_retries() -> BusyRetries {
+    BusyRetries {
+        retried: RETRIED.load(Ordering::Relaxed),
+        exhausted: EXHAUSTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether `error` is a `SQLITE_BUSY` or `SQLITE_LOCKED` error, including
+/// their extended codes.
+pub fn is_busy(error: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(error) = error else {
+        return false;
+    };
+
+    error
+        .code()
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+}
+
+/// Runs `f` until it does not fail with a busy database, waiting a growing
+/// and jittered delay between attempts. Gives up after [`MAX_ATTEMPTS`].
+///
+/// Transactions must be run entirely inside `f`, so they are rolled back
+/// before being retried.
+pub async fn retry_busy<T, F, Fut>(mut f: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(error) if is_busy(&error) => {
+                if attempt >= MAX_ATTEMPTS {
+                    EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        %error,
+                        attempts = attempt,
+                        "database still busy, giving up",
+                    );
+                    return Err(error);
+                }
+
+                // Half of the delay is random, so concurrent retries spread
+                let half = BASE_DELAY * 2u32.pow(attempt - 1) / 2;
+                let delay =
+                    half + rand::thread_rng().gen_range(Duration::ZERO..=half);
+
+                RETRIED.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!(
+                    %error,
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "database busy, retrying",
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+        Connection, SqliteConnection,
+    };
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_retry_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = SqliteConnectOptions::from_str(&format!(
+            "sqlite://{}",
+            dir.path().join("db.sqlite").display(),
+        ))
+        .unwrap()
+        .create_if_missing(true)
+        .busy_timeout(Duration::ZERO);
+
+        let db = SqlitePoolOptions::new()
+            .connect_with(opts.clone())
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE t (v INTEGER)")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let mut conn = SqliteConnection::connect_with(&opts).await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let insert =
+            || sqlx::query("INSERT INTO t (v) VALUES (1)").execute(&db);
+        let error = insert().await.unwrap_err();
+        assert!(is_busy(&error), "{error}");
+
+        let before = busy_retries();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            sqlx::query("COMMIT").execute(&mut conn).await.unwrap();
+        });
+
+        retry_busy(insert).await.unwrap();
+        release.await.unwrap();
+
+        let after = busy_retries();
+        assert!(after.retried > before.retried);
+        assert_eq!(after.exhausted, before.exhausted);
+    }
+
+    #[test]
+    fn test_not_busy() {
+        assert!(!is_busy(&sqlx::Error::RowNotFound));
+    }
+}
@@ -6,6 +6,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use ipnet::IpNet;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Clone, Serialize)]
@@ -119,9 +120,9 @@ where
     E: serde::de::Error,
 {
     let v = v.try_into().map_err(|_| {
-        serde::de::Error::custom(format!(
-            "must be a string-formated socket address or a number"
-        ))
+        serde::de::Error::custom(
+            "must be a string-formated socket address or a number",
+        )
     })?;
 
     Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), v))
@@ -160,6 +161,13 @@ impl<'de> Visitor<'de> for NumberSocketAddrVisitor {
     {
         visit_any_n(v)
     }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 pub fn deserialize_socket_addr<'de, D: Deserializer<'de>>(
@@ -168,6 +176,25 @@ pub fn deserialize_socket_addr<'de, D: Deserializer<'de>>(
     deserializer.deserialize_any(NumberSocketAddrVisitor)
 }
 
+/// Parses a list of networks, where a plain address is the network of only
+/// that address.
+pub fn deserialize_ip_nets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    serde::de::Error::custom(format!(
+                        "`{net}` is not a valid ip address or network"
+                    ))
+                })
+        })
+        .collect()
+}
+
 pub mod duration_secs {
     use std::time::Duration;
 
@@ -187,6 +214,27 @@ pub mod duration_secs {
         let secs = u64::deserialize(deserializer)?;
         Ok(Duration::from_secs(secs))
     }
+
+    pub mod option {
+        use std::time::Duration;
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        #[inline]
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            duration.map(|d| d.as_secs()).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            let secs = Option::<u64>::deserialize(deserializer)?;
+            Ok(secs.map(Duration::from_secs))
+        }
+    }
 }
 
 pub mod base64 {
@@ -212,3 +260,57 @@ pub mod base64 {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use ipnet::IpNet;
+    use serde::Deserialize;
+
+    use super::{deserialize_ip_nets, deserialize_socket_addr};
+
+    #[derive(Deserialize)]
+    struct Addr {
+        #[serde(deserialize_with = "deserialize_socket_addr")]
+        addr: SocketAddr,
+    }
+
+    #[test]
+    fn test_socket_addr() {
+        let addr: Addr = serde_json::from_str(r#"{"addr":8080}"#).unwrap();
+        assert_eq!(addr.addr, "0.0.0.0:8080".parse().unwrap());
+
+        let addr: Addr =
+            serde_json::from_str(r#"{"addr":"127.0.0.1:7777"}"#).unwrap();
+        assert_eq!(addr.addr, "127.0.0.1:7777".parse().unwrap());
+
+        assert!(serde_json::from_str::<Addr>(r#"{"addr":70000}"#).is_err());
+        assert!(serde_json::from_str::<Addr>(r#"{"addr":-1}"#).is_err());
+        assert!(serde_json::from_str::<Addr>(r#"{"addr":"nope"}"#).is_err());
+    }
+
+    #[derive(Deserialize)]
+    struct Nets {
+        #[serde(deserialize_with = "deserialize_ip_nets")]
+        nets: Vec<IpNet>,
+    }
+
+    #[test]
+    fn test_ip_nets() {
+        let nets: Nets = serde_json::from_str(
+            r#"{"nets":["10.0.0.0/8","127.0.0.1","::1","fd00::/8"]}"#,
+        )
+        .unwrap();
+        let expected: Vec<IpNet> =
+            ["10.0.0.0/8", "127.0.0.1/32", "::1/128", "fd00::/8"]
+                .iter()
+                .map(|net| net.parse().unwrap())
+                .collect();
+        assert_eq!(nets.nets, expected);
+
+        assert!(serde_json::from_str::<Nets>(r#"{"nets":["10.0.0.0/33"]}"#)
+            .is_err());
+        assert!(serde_json::from_str::<Nets>(r#"{"nets":["nope"]}"#).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream::Fuse, Stream, StreamExt};
+use pin_project_lite::pin_project;
+use tokio::time::{Instant, Sleep};
+
+pin_project! {
+    /// Fails the stream with [`io::ErrorKind::TimedOut`] once it takes
+    /// longer than `timeout` as a whole or yields less than `min_rate`
+    /// bytes per second over a `window`, so stalled clients can not hold
+    /// the resources of a transfer indefinitely.
+    pub struct DeadlineStream<S> {
+        #[pin]
+        stream: S,
+        deadline: Option<Pin<Box<Sleep>>>,
+        window: Option<Pin<Box<Sleep>>>,
+        window_duration: Duration,
+        window_min_bytes: u64,
+        window_bytes: u64,
+    }
+}
+
+impl<S> DeadlineStream<S> {
+    /// A zero `timeout` or `min_rate` disables the respective check.
+    pub fn new(
+        stream: S,
+        timeout: Duration,
+        min_rate: u64,
+        window: Duration,
+    ) -> Self {
+        let deadline =
+            (!timeout.is_zero()).then(|| Box::pin(tokio::time::sleep(timeout)));
+        let window_sleep = (min_rate > 0 && !window.is_zero())
+            .then(|| Box::pin(tokio::time::sleep(window)));
+
+        Self {
+            stream,
+            deadline,
+            window: window_sleep,
+            window_duration: window,
+            window_min_bytes: (min_rate as f64 * window.as_secs_f64()).ceil()
+                as u64,
+            window_bytes: 0,
+        }
+    }
+}
+
+impl<S> Stream for DeadlineStream<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>>,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if let Some(deadline) = this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "transfer took too long",
+                ))));
+            }
+        }
+
+        if let Some(window) = this.window {
+            if window.as_mut().poll(cx).is_ready() {
+                if *this.window_bytes < *this.window_min_bytes {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "transfer rate is too low",
+                    ))));
+                }
+
+                *this.window_bytes = 0;
+                window
+                    .as_mut()
+                    .reset(Instant::now() + *this.window_duration);
+                // Registers the waker for the new window
+                let _ = window.as_mut().poll(cx);
+            }
+        }
+
+        let poll = this.stream.poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            *this.window_bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+pin_project! {
+    /// Fails the stream with [`io::ErrorKind::FileTooLarge`] once it yields
+    /// more than `limit` bytes.
+    pub struct LimitStream<S> {
+        #[pin]
+        stream: S,
+        remaining: u64,
+    }
+}
+
+impl<S> LimitStream<S> {
+    pub fn new(stream: S, limit: u64) -> Self {
+        Self {
+            stream,
+            remaining: limit,
+        }
+    }
+}
+
+impl<S> Stream for LimitStream<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>>,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        let poll = this.stream.poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let len = chunk.len() as u64;
+            if len > *this.remaining {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "transfer exceeds the size limit",
+                ))));
+            }
+            *this.remaining -= len;
+        }
+        poll
+    }
+}
+
+pin_project! {
+    /// Merges the small chunks that are ready at once into chunks of up to
+    /// about `size` bytes, so clients sending many tiny frames cost fewer
+    /// writes. Larger chunks are passed as they are, and pending data is
+    /// yielded as soon as the stream has no more ready, so slow clients are
+    /// not delayed.
+    pub struct CoalesceStream<S> {
+        #[pin]
+        stream: Fuse<S>,
+        size: usize,
+        pending: BytesMut,
+        next: Option<Bytes>,
+    }
+}
+
+impl<S: Stream> CoalesceStream<S> {
+    /// A zero `size` disables the merging.
+    pub fn new(stream: S, size: usize) -> Self {
+        Self {
+            stream: stream.fuse(),
+            size,
+            pending: BytesMut::new(),
+            next: None,
+        }
+    }
+}
+
+impl<S> Stream for CoalesceStream<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>>,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        if let Some(chunk) = this.next.take() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        loop {
+            let chunk = match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => chunk,
+                Poll::Ready(Some(Err(error))) => {
+                    return Poll::Ready(Some(Err(error)))
+                }
+                Poll::Ready(None) | Poll::Pending
+                    if !this.pending.is_empty() =>
+                {
+                    return Poll::Ready(Some(Ok(this
+                        .pending
+                        .split()
+                        .freeze())));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if chunk.len() >= *this.size {
+                if this.pending.is_empty() {
+                    return Poll::Ready(Some(Ok(chunk)));
+                }
+                *this.next = Some(chunk);
+                return Poll::Ready(Some(Ok(this.pending.split().freeze())));
+            }
+
+            this.pending.extend_from_slice(&chunk);
+            if this.pending.len() >= *this.size {
+                return Poll::Ready(Some(Ok(this.pending.split().freeze())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use bytes::Bytes;
+    use futures_util::{stream, StreamExt, TryStreamExt};
+
+    use super::{CoalesceStream, DeadlineStream, LimitStream};
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    fn chunks(
+        n: usize,
+        interval: Duration,
+    ) -> impl futures_util::Stream<Item = Result<Bytes, io::Error>> {
+        stream::iter(0..n).then(move |_| async move {
+            tokio::time::sleep(interval).await;
+            Ok(Bytes::from_static(&[0; 64]))
+        })
+    }
+
+    async fn collect(
+        stream: impl futures_util::Stream<Item = Result<Bytes, io::Error>>,
+    ) -> io::Result<usize> {
+        stream
+            .try_fold(0, |n, b| async move { Ok(n + b.len()) })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream() {
+        let stream =
+            DeadlineStream::new(stream::pending(), Duration::ZERO, 1, WINDOW);
+        let err = collect(stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream() {
+        // 64 bytes every 20ms is 3200 bytes/s
+        let stream = chunks(10, Duration::from_millis(20));
+        let stream = DeadlineStream::new(stream, Duration::ZERO, 1000, WINDOW);
+        assert_eq!(collect(stream).await.unwrap(), 640);
+
+        let stream = chunks(10, Duration::from_millis(20));
+        let stream = DeadlineStream::new(stream, Duration::ZERO, 5000, WINDOW);
+        let err = collect(stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let stream = chunks(10, Duration::from_millis(20));
+        let stream =
+            DeadlineStream::new(stream, Duration::from_millis(100), 0, WINDOW);
+        let err = collect(stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let stream = LimitStream::new(chunks(10, Duration::ZERO), 640);
+        assert_eq!(collect(stream).await.unwrap(), 640);
+
+        let stream = LimitStream::new(chunks(10, Duration::ZERO), 639);
+        let err = collect(stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let sizes = [10, 10, 10, 50, 200, 10, 100, 10];
+        let frames = sizes.map(|len| Ok(Bytes::from(vec![0; len])));
+
+        let stream = CoalesceStream::new(stream::iter(frames), 64);
+        let lens: Vec<usize> =
+            stream.map_ok(|b| b.len()).try_collect().await.unwrap();
+        assert_eq!(lens, [80, 200, 10, 100, 10]);
+
+        // Pending data is not held while waiting for more
+        let stream =
+            CoalesceStream::new(chunks(3, Duration::from_millis(5)), 1024);
+        let lens: Vec<usize> =
+            stream.map_ok(|b| b.len()).try_collect().await.unwrap();
+        assert_eq!(lens, [64, 64, 64]);
+    }
+}
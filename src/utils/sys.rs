@@ -1,4 +1,4 @@
-use std::future::Future;
+use std::{future::Future, io};
 
 #[macro_export]
 macro_rules! fatal {
@@ -67,3 +67,82 @@ pub fn shutdown_signal(
         }
     }))
 }
+
+/// How a file is going to be accessed, so the kernel can plan its caching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// Read from start to end, so it reads ahead more aggressively.
+    Sequential,
+    /// Not accessed again soon, so its cached pages can be dropped.
+    DontNeed,
+}
+
+/// Advises the kernel about the access pattern of the whole file, does
+/// nothing where unsupported.
+#[cfg(target_os = "linux")]
+pub fn fadvise(
+    file: &impl std::os::fd::AsRawFd,
+    advice: Advice,
+) -> io::Result<()> {
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    // SAFETY: the fd is valid while borrowed
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fadvise<F>(_file: &F, _advice: Advice) -> io::Result<()> {
+    Ok(())
+}
+
+/// Allocates the first `len` bytes of the file upfront, growing it to `len`
+/// if smaller, so it is less fragmented and fails early when there is not
+/// enough space. Fails with [`io::ErrorKind::Unsupported`] where the
+/// filesystem can not do so without writing the data.
+#[cfg(target_os = "linux")]
+pub fn preallocate(
+    file: &impl std::os::fd::AsRawFd,
+    len: u64,
+) -> io::Result<()> {
+    let len = i64::try_from(len).map_err(io::Error::other)?;
+    // SAFETY: the fd is valid while borrowed
+    match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } {
+        0 => Ok(()),
+        _ => {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EOPNOTSUPP) => {
+                    Err(io::ErrorKind::Unsupported.into())
+                }
+                _ => Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate<F>(_file: &F, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Writes all the modified data of the filesystem holding the file to the
+/// disk, which is faster than syncing many files one by one. Fails with
+/// [`io::ErrorKind::Unsupported`] where not available.
+#[cfg(target_os = "linux")]
+pub fn sync_filesystem(file: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+    // SAFETY: the fd is valid while borrowed
+    match unsafe { libc::syncfs(file.as_raw_fd()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sync_filesystem<F>(_file: &F) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}